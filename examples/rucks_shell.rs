@@ -0,0 +1,312 @@
+//! An interactive shell for poking at a database:
+//!
+//!     cargo run --example rucks_shell -- /path/to/db
+//!
+//! Keys and values are typed as slice::parse_escaped() reads them ("0x"
+//! and hex digits, or text with "\xNN" escapes) and printed as
+//! slice::escape_bytes() writes them.  "help" lists the commands.
+
+use std::{io::{self, BufRead, Write}, process, sync::Arc};
+
+use rucksdb::{db::{inspect_db, FileType, DB}, env::Env, options::{Options, ReadOptions, WriteOptions}, slice::{escape_bytes, parse_escaped, Slice}, status::Status};
+
+const HELP: &str = "\
+get KEY                     print the value of KEY
+put KEY VALUE               set KEY to VALUE
+delete KEY                  remove KEY
+scan [START [LIMIT]]        print up to LIMIT (default 10) entries from START
+more                        print the page after the last scan
+props [NAME]                list the property names, or print one property
+files                       print the tables of each level
+compact [BEGIN [END]]       compact the key range, or everything
+flush                       write the memtable out to a table
+options read|write [OPTS]   show or set the options used, as name=value;...
+help                        print this
+quit                        leave the shell";
+
+/// Number of entries a scan prints if not told otherwise.
+const DEFAULT_LIMIT: usize = 10;
+
+struct Shell {
+    db: Box<DB>,
+    env: Arc<dyn Env>,
+    dbname: String,
+    read_options: ReadOptions,
+    write_options: WriteOptions,
+    // Where "more" continues the last scan, and how many entries it prints
+    next_page: Option<(Vec<u8>, usize)>,
+}
+
+impl Shell {
+    fn new(db: Box<DB>, env: Arc<dyn Env>, dbname: &str) -> Self {
+        Self { db, env, dbname: dbname.to_string(), read_options: ReadOptions::new(), write_options: WriteOptions::default(), next_page: None }
+    }
+
+    /// Run one command line and return what it prints.
+    fn execute(&mut self, line: &str) -> Result<String, Status> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(String::new());
+        };
+        match (command, args) {
+            ("get", [key]) => {
+                let value = self.db.get(&self.read_options, &Slice::new(&parse_escaped(key)?))?;
+                Ok(escape_bytes(&value))
+            },
+            ("put", [key, value]) => {
+                check(self.db.put(&self.write_options, &Slice::new(&parse_escaped(key)?), &Slice::new(&parse_escaped(value)?)))
+            },
+            ("delete", [key]) => check(self.db.delete(&self.write_options, &Slice::new(&parse_escaped(key)?))),
+            ("scan", []) => self.scan(None, DEFAULT_LIMIT),
+            ("scan", [start]) => self.scan(Some(&parse_escaped(start)?), DEFAULT_LIMIT),
+            ("scan", [start, limit]) => {
+                let limit = limit.parse().map_err(|_| Status::invalid_argument("bad limit", limit))?;
+                self.scan(Some(&parse_escaped(start)?), limit)
+            },
+            ("more", []) => match self.next_page.take() {
+                Some((start, limit)) => self.scan(Some(&start), limit),
+                None => Ok("(end)".to_string()),
+            },
+            ("props", []) => Ok(DB::property_names().join("\n")),
+            ("props", [name]) => self.db.get_property(name).ok_or_else(|| Status::not_found("no such property", name)),
+            ("files", []) => self.files(),
+            ("compact", []) => check(self.db.compact_range(None, None)),
+            ("compact", [begin]) => check(self.db.compact_range(Some(&Slice::new(&parse_escaped(begin)?)), None)),
+            ("compact", [begin, end]) => {
+                let (begin, end) = (parse_escaped(begin)?, parse_escaped(end)?);
+                check(self.db.compact_range(Some(&Slice::new(&begin)), Some(&Slice::new(&end))))
+            },
+            ("flush", []) => check(self.db.flush()),
+            ("options", ["read"]) => Ok(self.read_options.to_option_string()),
+            ("options", ["read", options]) => {
+                self.read_options = ReadOptions::parse(options)?;
+                Ok(self.read_options.to_option_string())
+            },
+            ("options", ["write"]) => Ok(self.write_options.to_option_string()),
+            ("options", ["write", options]) => {
+                self.write_options = WriteOptions::parse(options)?;
+                Ok(self.write_options.to_option_string())
+            },
+            ("help", []) => Ok(HELP.to_string()),
+            _ => Err(Status::invalid_argument("bad command (try \"help\")", line.trim())),
+        }
+    }
+
+    fn scan(&mut self, start: Option<&[u8]>, limit: usize) -> Result<String, Status> {
+        let (entries, next) = self.db.scan_page(&self.read_options, start, limit)?;
+        let mut lines: Vec<String> = entries.iter().map(|(k, v)| format!("{} => {}", escape_bytes(k), escape_bytes(v))).collect();
+        lines.push(match &next {
+            Some(key) => format!("(more from {})", escape_bytes(key)),
+            None => "(end)".to_string(),
+        });
+        self.next_page = next.map(|key| (key, limit));
+        Ok(lines.join("\n"))
+    }
+
+    fn files(&self) -> Result<String, Status> {
+        let inspection = inspect_db(self.env.clone(), &self.dbname)?;
+        let mut lines = Vec::new();
+        for level in inspection.levels.iter().filter(|level| level.files > 0) {
+            lines.push(format!("level {}: {} files, {} bytes, {} entries", level.level, level.files, level.bytes, level.entries));
+        }
+        for file in inspection.files.iter().filter(|file| file.file_type == FileType::TableFile) {
+            match file.table.as_ref().and_then(|table| table.entries) {
+                Some(entries) => lines.push(format!("  {} {} bytes, {} entries", file.name, file.size, entries)),
+                None => lines.push(format!("  {} {} bytes", file.name, file.size)),
+            }
+        }
+        if lines.is_empty() {
+            lines.push("(no tables)".to_string());
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+fn check(s: Status) -> Result<String, Status> {
+    if s.ok() {
+        Ok("OK".to_string())
+    } else {
+        Err(s)
+    }
+}
+
+fn main() {
+    let Some(dbname) = std::env::args().nth(1) else {
+        eprintln!("usage: rucks_shell DBNAME");
+        process::exit(1);
+    };
+    let mut options = Options::new();
+    options.create_if_missing = true;
+    let db = DB::open(&options, &dbname).unwrap_or_else(|s| {
+        eprintln!("cannot open {}: {}", dbname, s.to_string());
+        process::exit(1);
+    });
+    let mut shell = Shell::new(db, options.env.clone(), &dbname);
+
+    let prompt = || {
+        print!("rucks> ");
+        let _ = io::stdout().flush();
+    };
+    prompt();
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim() == "quit" {
+            break;
+        }
+        match shell.execute(&line) {
+            Ok(output) if output.is_empty() => {},
+            Ok(output) => println!("{}", output),
+            Err(s) => println!("error: {}", s.to_string()),
+        }
+        prompt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rucksdb::helpers::memenv::new_mem_env;
+
+    use super::*;
+
+    const DBNAME: &str = "/shell";
+
+    fn new_shell() -> Shell {
+        let env = new_mem_env();
+        let mut options = Options::new();
+        options.env = env.clone();
+        options.create_if_missing = true;
+        Shell::new(DB::open(&options, DBNAME).unwrap(), env, DBNAME)
+    }
+
+    /// Run "script", one command per line, checking each one's output
+    /// against the lines after it that start with "| ", or its error
+    /// against a line starting with "! ".
+    fn run(shell: &mut Shell, script: &str) {
+        let mut lines = script.lines().map(str::trim).filter(|line| !line.is_empty()).peekable();
+        while let Some(command) = lines.next() {
+            let mut expected = Vec::new();
+            while let Some(line) = lines.next_if(|line| line.starts_with('|') || line.starts_with('!')) {
+                expected.push(line);
+            }
+            let actual: Vec<String> = match shell.execute(command) {
+                Ok(output) => output.lines().map(|line| format!("| {}", line).trim_end().to_string()).collect(),
+                Err(s) => vec![format!("! {}", s.to_string()).trim_end().to_string()],
+            };
+            assert_eq!(expected, actual, "{}", command);
+        }
+    }
+
+    #[test]
+    fn get_put_delete_test() {
+        let mut shell = new_shell();
+        run(&mut shell, r#"
+            put a 1
+            | OK
+            put b\x20c 0x00ff
+            | OK
+            get b\x20c
+            | \x00\xff
+            get 0x622063
+            | \x00\xff
+            delete a
+            | OK
+            get a
+            ! NotFound:
+            get a b
+            ! Invalid argument: bad command (try "help"): get a b
+            put \q 1
+            ! Invalid argument: bad escape sequence: \q
+        "#);
+    }
+
+    #[test]
+    fn scan_test() {
+        let mut shell = new_shell();
+        for i in 0..5 {
+            run(&mut shell, &format!("put k{0} v{0}\n| OK", i));
+        }
+        run(&mut shell, "
+            flush
+            | OK
+            delete k1
+            | OK
+            scan
+            | k0 => v0
+            | k2 => v2
+            | k3 => v3
+            | k4 => v4
+            | (end)
+            scan k 2
+            | k0 => v0
+            | k2 => v2
+            | (more from k3)
+            more
+            | k3 => v3
+            | k4 => v4
+            | (end)
+            more
+            | (end)
+            scan k3
+            | k3 => v3
+            | k4 => v4
+            | (end)
+            scan k x
+            ! Invalid argument: bad limit: x
+        ");
+    }
+
+    #[test]
+    fn options_test() {
+        let mut shell = new_shell();
+        run(&mut shell, "
+            put a 1
+            | OK
+            put c 3
+            | OK
+            options read iterate_upper_bound=b
+            | verify_checksums=false;fill_cache=true;read_tier=default;iterate_upper_bound=b
+            scan
+            | a => 1
+            | (end)
+            options read
+            | verify_checksums=false;fill_cache=true;read_tier=default;iterate_upper_bound=b
+            options write sync=true
+            | sync=true;fail_on_locked_range=false;collect_timing=false;disable_wal=false
+            options read fill_cache=maybe
+            ! Invalid argument: fill_cache: cannot parse \"maybe\"
+            options read
+            | verify_checksums=false;fill_cache=true;read_tier=default;iterate_upper_bound=b
+        ");
+    }
+
+    #[test]
+    fn props_files_compact_test() {
+        let mut shell = new_shell();
+        let names = shell.execute("props").unwrap();
+        assert_eq!(DB::property_names(), names.lines().collect::<Vec<_>>());
+        for name in names.lines() {
+            assert!(shell.execute(&format!("props {}", name)).is_ok(), "{}", name);
+        }
+        run(&mut shell, "
+            props leveldb.num-files-at-level0
+            | 0
+            props leveldb.nothing
+            ! NotFound: no such property: leveldb.nothing
+            files
+            | (no tables)
+            put a 1
+            | OK
+            flush
+            | OK
+            compact a b
+            | OK
+            compact
+            | OK
+        ");
+        let files = shell.execute("files").unwrap();
+        assert!(files.starts_with("level "), "{}", files);
+        assert!(files.contains(".ldb"), "{}", files);
+    }
+}
@@ -0,0 +1,52 @@
+//! A database can be configured with a BatchTransformer that rewrites
+//! each batch given to DB::write() before it is committed, e.g. to add
+//! the rows of a secondary index kept in the same database.  What the
+//! transformer returns is logged and applied as one atomic batch, so
+//! recovery needs nothing special: the log holds the final batch.
+//!
+//! Batches that do not come through write() (or put(), delete() and
+//! delete_range()) are not transformed: DB::apply_replicated_batch()
+//! applies batches the primary has already transformed, and
+//! DB::ingest_external_file() adds whole tables.
+
+use std::cell::RefCell;
+
+use crate::{status::Status, write_batch::WriteBatch};
+
+pub trait BatchTransformer: Send + Sync {
+    /// Return the batch to commit in place of "batch", usually a copy of
+    /// it with derived updates appended (see WriteBatch::iterate() and
+    /// WriteBatch::append()).  An error fails the write with it, and
+    /// nothing is written.
+    ///
+    /// Called on the writing thread before the write is queued, with no
+    /// lock of the database held, so it may read from the database.
+    /// Writes it makes to the same database fail with InvalidArgument.
+    fn transform(&self, batch: &WriteBatch) -> Result<WriteBatch, Status>;
+}
+
+thread_local! {
+    // Ids of the databases whose transformer is running on this thread.
+    static TRANSFORMING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Pops the id pushed by transform(), even if the transformer panics.
+struct Transforming;
+
+impl Drop for Transforming {
+    fn drop(&mut self) {
+        TRANSFORMING.with(|ids| ids.borrow_mut().pop());
+    }
+}
+
+/// Run "transformer" on "batch" for the database identified by "db",
+/// failing instead if that database's transformer is already running on
+/// this thread.
+pub(crate) fn transform(db: usize, transformer: &dyn BatchTransformer, batch: &WriteBatch) -> Result<WriteBatch, Status> {
+    if TRANSFORMING.with(|ids| ids.borrow().contains(&db)) {
+        return Err(Status::invalid_argument("write", "called from the database's BatchTransformer"));
+    }
+    TRANSFORMING.with(|ids| ids.borrow_mut().push(db));
+    let _transforming = Transforming;
+    transformer.transform(batch)
+}
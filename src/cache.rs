@@ -5,12 +5,442 @@
 //! capacity.  For example, a cache where the values are variable
 //! length strings, may use the length of the string as the charge for
 //! the string.
-//! 
+//!
 //! A builtin cache implementation with a least-recently-used eviction
 //! policy is provided.  Clients may use their own implementations if
 //! they want something more sophisticated (like scan-resistance, a
 //! custom eviction policy, variable cache sizing, etc.)
+//!
+//! A second builtin implementation splits an LRU cache between groups
+//! of levels, so that blocks read by scans of the bottom levels do not
+//! push out the blocks of the small, hot upper levels.
 
-pub trait Cache {
-    
+use std::{any::Any, collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use crate::db::dbformat::NUM_LEVELS;
+
+/// A value held by a Cache.  Users of a cache downcast the values they
+/// inserted back to their own type.
+pub type CacheValue = Arc<dyn Any + Send + Sync>;
+
+pub trait Cache: Send + Sync {
+    /// Insert a mapping from key->value into the cache and assign it
+    /// the specified charge against the total cache capacity, replacing
+    /// any existing mapping for "key".
+    fn insert(&self, key: &[u8], value: CacheValue, charge: usize);
+
+    /// If the cache has no mapping for "key", returns None.  Otherwise
+    /// returns the value, which stays valid even if the entry is evicted.
+    fn lookup(&self, key: &[u8]) -> Option<CacheValue>;
+
+    /// If the cache contains an entry for key, erase it.
+    fn erase(&self, key: &[u8]);
+
+    /// Return a new numeric id.  May be used by multiple clients who are
+    /// sharing the same cache to partition the key space.  Typically the
+    /// client will allocate a new id at startup and prepend the id to
+    /// its cache keys.
+    fn new_id(&self) -> u64;
+
+    /// Return an estimate of the combined charges of all elements stored in the
+    /// cache.
+    fn total_charge(&self) -> usize;
+
+    /// Return the capacity, usage, hits and misses of each partition of
+    /// the cache, or a single entry if it is not partitioned.  Caches that
+    /// keep no statistics return nothing.
+    fn stats(&self) -> Vec<CacheStats> {
+        Vec::new()
+    }
+}
+
+/// Capacity, usage and lookup counts of a cache or of one of its partitions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub usage: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Level byte ending the block cache keys of blocks read outside of any
+/// level, e.g. while repairing or verifying a new table.
+pub(crate) const UNKNOWN_LEVEL: u8 = 0xff;
+
+/// Create a new cache with a fixed size capacity.  This implementation
+/// of Cache uses a least-recently-used eviction policy.
+pub fn new_lru_cache(capacity: usize) -> Arc<dyn Cache> {
+    Arc::new(LruCache { capacity_: capacity, state_: Mutex::new(LruState::default()), hits_: AtomicU64::new(0), misses_: AtomicU64::new(0) })
+}
+
+/// Create a block cache of "capacity" bytes split between groups of
+/// levels: partition i holds the blocks of levels 2*i and 2*i+1, and the
+/// last partition those of every deeper level too, along with blocks read
+/// outside of any level.  Each partition gets the share of the capacity
+/// its entry of "fractions" has of their sum; entries that are not
+/// positive get nothing and entries past the number of level groups are
+/// ignored.  Each partition evicts its own least-recently-used blocks.
+///
+/// If "borrowing" is set, a partition may grow past its share while the
+/// whole cache is under capacity, and gives the borrowed room back first
+/// when another partition needs it.  Otherwise no partition ever holds
+/// more than its share.
+///
+/// The cache routes on the last byte of each key, which must be the
+/// level as the table reader's block keys have it.
+pub fn new_partitioned_lru_cache(capacity: usize, fractions: &[f32], borrowing: bool) -> Arc<dyn Cache> {
+    Arc::new(CachePartitioner::new(capacity, fractions, borrowing))
+}
+
+struct LruEntry {
+    value: CacheValue,
+    charge: usize,
+    last_use: u64,      // Key of the entry in LruState::lru_
+}
+
+#[derive(Default)]
+struct LruState {
+    entries_: HashMap<Vec<u8>, LruEntry>,
+    // Keys by last use, oldest first
+    lru_: BTreeMap<u64, Vec<u8>>,
+    clock_: u64,
+    usage_: usize,
+    last_id_: u64,
+}
+
+impl LruState {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries_.remove(key) {
+            self.lru_.remove(&entry.last_use);
+            self.usage_ -= entry.charge;
+        }
+    }
+
+    /// Add or replace the entry for "key" as the most recently used one,
+    /// without evicting anything.
+    fn add(&mut self, key: &[u8], value: CacheValue, charge: usize) {
+        self.remove(key);
+        self.clock_ += 1;
+        let last_use = self.clock_;
+        self.entries_.insert(key.to_vec(), LruEntry { value, charge, last_use });
+        self.lru_.insert(last_use, key.to_vec());
+        self.usage_ += charge;
+    }
+
+    /// Remove the least recently used entry.  Returns false if the state
+    /// is empty.
+    fn evict_oldest(&mut self) -> bool {
+        let Some((_, oldest)) = self.lru_.pop_first() else {
+            return false;
+        };
+        let entry = self.entries_.remove(&oldest).expect("lru key without entry");
+        self.usage_ -= entry.charge;
+        true
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Option<CacheValue> {
+        self.clock_ += 1;
+        let last_use = self.clock_;
+        let entry = self.entries_.get_mut(key)?;
+        let previous_use = std::mem::replace(&mut entry.last_use, last_use);
+        let value = entry.value.clone();
+        let key = self.lru_.remove(&previous_use).expect("entry not in lru list");
+        self.lru_.insert(last_use, key);
+        Some(value)
+    }
+}
+
+/// Count a lookup as a hit or a miss.
+fn record_lookup(value: Option<CacheValue>, hits: &AtomicU64, misses: &AtomicU64) -> Option<CacheValue> {
+    match value {
+        Some(_) => hits.fetch_add(1, Ordering::Relaxed),
+        None => misses.fetch_add(1, Ordering::Relaxed),
+    };
+    value
+}
+
+struct LruCache {
+    capacity_: usize,
+    state_: Mutex<LruState>,
+    hits_: AtomicU64,
+    misses_: AtomicU64,
+}
+
+impl Cache for LruCache {
+    fn insert(&self, key: &[u8], value: CacheValue, charge: usize) {
+        let mut state = self.state_.lock().unwrap();
+        state.add(key, value, charge);
+        while state.usage_ > self.capacity_ && state.evict_oldest() {}
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<CacheValue> {
+        let value = self.state_.lock().unwrap().lookup(key);
+        record_lookup(value, &self.hits_, &self.misses_)
+    }
+
+    fn erase(&self, key: &[u8]) {
+        self.state_.lock().unwrap().remove(key);
+    }
+
+    fn new_id(&self) -> u64 {
+        let mut state = self.state_.lock().unwrap();
+        state.last_id_ += 1;
+        state.last_id_
+    }
+
+    fn total_charge(&self) -> usize {
+        self.state_.lock().unwrap().usage_
+    }
+
+    fn stats(&self) -> Vec<CacheStats> {
+        vec![CacheStats {
+            capacity: self.capacity_,
+            usage: self.total_charge(),
+            hits: self.hits_.load(Ordering::Relaxed),
+            misses: self.misses_.load(Ordering::Relaxed),
+        }]
+    }
+}
+
+/// One LRU list per group of levels, under a single lock so that a
+/// partition can borrow capacity from the others.
+struct CachePartitioner {
+    capacities_: Vec<usize>,
+    capacity_: usize,
+    borrowing_: bool,
+    state_: Mutex<Vec<LruState>>,
+    last_id_: AtomicU64,
+    hits_: Vec<AtomicU64>,
+    misses_: Vec<AtomicU64>,
+}
+
+impl CachePartitioner {
+    fn new(capacity: usize, fractions: &[f32], borrowing: bool) -> Self {
+        let groups = (NUM_LEVELS as usize).div_ceil(2);
+        let mut fractions: Vec<f64> = fractions.iter().take(groups)
+            .map(|&f| if f > 0.0 && f.is_finite() { f as f64 } else { 0.0 })
+            .collect();
+        if fractions.is_empty() {
+            fractions.push(1.0);
+        }
+        let sum: f64 = fractions.iter().sum();
+        // Cut the capacity at the running sums of the fractions, so that
+        // the shares add up to exactly the whole of it
+        let mut capacities = Vec::with_capacity(fractions.len());
+        let (mut running, mut cut) = (0.0, 0);
+        for f in fractions {
+            running += f;
+            let next = if sum > 0.0 { ((capacity as f64 * running / sum) as usize).min(capacity) } else { 0 };
+            capacities.push(next - cut);
+            cut = next;
+        }
+        if let Some(last) = capacities.iter_mut().rev().find(|c| **c > 0) {
+            *last += capacity - cut;
+        }
+        let n = capacities.len();
+        Self {
+            capacity_: capacities.iter().sum(),
+            capacities_: capacities,
+            borrowing_: borrowing,
+            state_: Mutex::new((0..n).map(|_| LruState::default()).collect()),
+            last_id_: AtomicU64::new(0),
+            hits_: (0..n).map(|_| AtomicU64::new(0)).collect(),
+            misses_: (0..n).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// The partition holding "key", from the level byte ending it.
+    fn partition_of(&self, key: &[u8]) -> usize {
+        let last = self.capacities_.len() - 1;
+        match key.last() {
+            Some(&level) if level != UNKNOWN_LEVEL => (level as usize / 2).min(last),
+            _ => last,
+        }
+    }
+}
+
+impl Cache for CachePartitioner {
+    fn insert(&self, key: &[u8], value: CacheValue, charge: usize) {
+        let p = self.partition_of(key);
+        let mut state = self.state_.lock().unwrap();
+        state[p].add(key, value, charge);
+        let total = |state: &Vec<LruState>| state.iter().map(|s| s.usage_).sum::<usize>();
+        // Shrink the partition back to its share, unless it may keep
+        // what it borrowed because the whole cache still has room
+        while state[p].usage_ > self.capacities_[p] && (!self.borrowing_ || total(&state) > self.capacity_) {
+            state[p].evict_oldest();
+        }
+        // Take back room lent out, from the partition furthest past its share
+        while total(&state) > self.capacity_ {
+            let borrower = (0..state.len())
+                .filter(|&i| state[i].usage_ > self.capacities_[i])
+                .max_by_key(|&i| state[i].usage_ - self.capacities_[i]);
+            match borrower {
+                Some(i) => { state[i].evict_oldest(); },
+                None => break,
+            }
+        }
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<CacheValue> {
+        let p = self.partition_of(key);
+        let value = self.state_.lock().unwrap()[p].lookup(key);
+        record_lookup(value, &self.hits_[p], &self.misses_[p])
+    }
+
+    fn erase(&self, key: &[u8]) {
+        let p = self.partition_of(key);
+        self.state_.lock().unwrap()[p].remove(key);
+    }
+
+    fn new_id(&self) -> u64 {
+        self.last_id_.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn total_charge(&self) -> usize {
+        self.state_.lock().unwrap().iter().map(|s| s.usage_).sum()
+    }
+
+    fn stats(&self) -> Vec<CacheStats> {
+        let state = self.state_.lock().unwrap();
+        (0..state.len()).map(|i| CacheStats {
+            capacity: self.capacities_[i],
+            usage: state[i].usage_,
+            hits: self.hits_[i].load(Ordering::Relaxed),
+            misses: self.misses_[i].load(Ordering::Relaxed),
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::random::Random;
+
+    use super::*;
+
+    fn lookup(cache: &Arc<dyn Cache>, key: &str) -> Option<usize> {
+        cache.lookup(key.as_bytes()).map(|value| *value.downcast::<usize>().unwrap())
+    }
+
+    fn insert(cache: &Arc<dyn Cache>, key: &str, value: usize, charge: usize) {
+        cache.insert(key.as_bytes(), Arc::new(value), charge);
+    }
+
+    #[test]
+    fn hit_and_miss_test() {
+        let cache = new_lru_cache(1000);
+        assert_eq!(None, lookup(&cache, "100"));
+        insert(&cache, "100", 101, 1);
+        assert_eq!(Some(101), lookup(&cache, "100"));
+        assert_eq!(None, lookup(&cache, "200"));
+
+        insert(&cache, "100", 102, 3);
+        assert_eq!(Some(102), lookup(&cache, "100"));
+        assert_eq!(3, cache.total_charge());
+
+        cache.erase(b"100");
+        cache.erase(b"300");
+        assert_eq!(None, lookup(&cache, "100"));
+        assert_eq!(0, cache.total_charge());
+        assert_ne!(cache.new_id(), cache.new_id());
+    }
+
+    #[test]
+    fn eviction_policy_test() {
+        let cache = new_lru_cache(10);
+        for i in 0..10 {
+            insert(&cache, &i.to_string(), i, 1);
+        }
+        // Keep "0" in use while the others are pushed out one by one
+        for i in 10..19 {
+            assert_eq!(Some(0), lookup(&cache, "0"));
+            insert(&cache, &i.to_string(), i, 1);
+            assert_eq!(None, lookup(&cache, &(i - 9).to_string()));
+        }
+        assert_eq!(Some(0), lookup(&cache, "0"));
+        assert_eq!(10, cache.total_charge());
+
+        // A heavy entry evicts as many as it has to, and one larger than
+        // the whole cache is not kept at all
+        insert(&cache, "heavy", 100, 5);
+        assert_eq!(10, cache.total_charge());
+        assert_eq!(Some(0), lookup(&cache, "0"));
+        assert_eq!(None, lookup(&cache, "10"));
+        insert(&cache, "huge", 100, 11);
+        assert_eq!((0, None), (cache.total_charge(), lookup(&cache, "huge")));
+    }
+
+    fn level_key(i: usize, level: u8) -> Vec<u8> {
+        let mut key = i.to_string().into_bytes();
+        key.push(level);
+        key
+    }
+
+    fn usages(cache: &Arc<dyn Cache>) -> Vec<usize> {
+        cache.stats().iter().map(|s| s.usage).collect()
+    }
+
+    #[test]
+    fn partition_routing_test() {
+        let cache = new_partitioned_lru_cache(100, &[0.4, 0.4, 0.2], false);
+        assert_eq!(vec![40, 40, 20], cache.stats().iter().map(|s| s.capacity).collect::<Vec<_>>());
+        for (i, level) in [0, 1, 2, 3, 4, 6, UNKNOWN_LEVEL].into_iter().enumerate() {
+            cache.insert(&level_key(i, level), Arc::new(i), 1);
+        }
+        assert_eq!(vec![2, 2, 3], usages(&cache));
+        assert!(cache.lookup(&level_key(0, 0)).is_some());
+        assert!(cache.lookup(&level_key(0, 2)).is_none());
+        assert!(cache.lookup(&level_key(6, UNKNOWN_LEVEL)).is_some());
+        let stats = cache.stats();
+        assert_eq!(vec![(1, 0), (0, 1), (1, 0)], stats.iter().map(|s| (s.hits, s.misses)).collect::<Vec<_>>());
+
+        // Shares are fractions of the sum, and unusable fractions get nothing
+        let cache = new_partitioned_lru_cache(100, &[1.0, f32::NAN, -1.0, 3.0, 5.0], false);
+        assert_eq!(vec![25, 0, 0, 75], cache.stats().iter().map(|s| s.capacity).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn partition_capacity_test() {
+        for borrowing in [false, true] {
+            let cache = new_partitioned_lru_cache(1000, &[0.5, 0.3, 0.2], borrowing);
+            let mut rnd = Random::new(301);
+            for i in 0..5000 {
+                let level = rnd.uniform(8) as u8;
+                let level = if level == 7 { UNKNOWN_LEVEL } else { level };
+                cache.insert(&level_key(i, level), Arc::new(i), 1 + rnd.uniform(50) as usize);
+                assert!(cache.total_charge() <= 1000);
+                if !borrowing {
+                    assert!(cache.stats().iter().all(|s| s.usage <= s.capacity));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partition_borrowing_test() {
+        let cache = new_partitioned_lru_cache(10, &[0.5, 0.5], true);
+        // The bottom partition borrows the room the top one leaves unused
+        for i in 0..10 {
+            cache.insert(&level_key(i, 2), Arc::new(i), 1);
+        }
+        assert_eq!(vec![0, 10], usages(&cache));
+        // And gives it back, oldest first, as the top one fills up
+        for i in 0..3 {
+            cache.insert(&level_key(i, 0), Arc::new(i), 1);
+        }
+        assert_eq!(vec![3, 7], usages(&cache));
+        assert!(cache.lookup(&level_key(2, 2)).is_none());
+        assert!(cache.lookup(&level_key(3, 2)).is_some());
+        // Back within its share, it only evicts its own blocks
+        for i in 3..12 {
+            cache.insert(&level_key(i, 0), Arc::new(i), 1);
+        }
+        assert_eq!(vec![5, 5], usages(&cache));
+
+        let strict = new_partitioned_lru_cache(10, &[0.5, 0.5], false);
+        for i in 0..10 {
+            strict.insert(&level_key(i, 2), Arc::new(i), 1);
+        }
+        assert_eq!(vec![0, 5], usages(&strict));
+    }
 }
@@ -0,0 +1,18 @@
+//! A database can be configured with a CompactionFilter that compaction
+//! consults for each value it rewrites, e.g. to drop entries that have
+//! expired (see utilities::ttl).  Only the newest value of each key that
+//! no snapshot predates is offered to the filter; memtable flushes do not
+//! consult it.
+
+use crate::slice::Slice;
+
+pub trait CompactionFilter: Send + Sync {
+    /// Return the name of this filter, for diagnostic messages.
+    fn name(&self) -> &str;
+
+    /// Return true if the entry "key" -> "value", being compacted into
+    /// "level" + 1, is to be removed.  Compaction then drops it, or, if
+    /// older entries for the key may exist in deeper levels, replaces it
+    /// with a deletion marker so that they do not come back.
+    fn filter(&self, level: i32, key: &Slice, value: &Slice) -> bool;
+}
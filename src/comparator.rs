@@ -7,7 +7,7 @@ use crate::{slice::Slice, util::comparator::BYTEWISE_COMPARATOR};
 /// used as keys in an sstable or a database.  A Comparator implementation
 /// must be thread-safe since leveldb may invoke its methods concurrently
 /// from multiple threads.
-pub trait Comparator {
+pub trait Comparator: Send + Sync {
     /// The name of the comparator.  Used to check for comparator
     /// mismatches (i.e., a DB created with one comparator is
     /// accessed using a different comparator.
@@ -29,7 +29,15 @@ pub trait Comparator {
     // Advanced functions: these are used to reduce the space requirements
     // for internal data structures like index blocks.
 
+    /// If *start < limit, changes *start to a short string in [start,limit).
+    /// Simple comparator implementations may return with *start unchanged,
+    /// i.e., an implementation of this method that does nothing is correct.
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &Slice);
 
+    /// Changes *key to a short string >= *key.
+    /// Simple comparator implementations may return with *key unchanged,
+    /// i.e., an implementation of this method that does nothing is correct.
+    fn find_short_successor(&self, key: &mut Vec<u8>);
 }
 
 /// Return a builtin comparator that uses lexicographic byte-wise
@@ -1,163 +1,9767 @@
-use std::{cell::RefCell, rc::Rc, sync::Mutex};
+use std::{cell::{Cell, RefCell}, cmp::Ordering, collections::{BTreeMap, BTreeSet, VecDeque}, ops::{Bound, Deref, RangeBounds}, panic::{self, AssertUnwindSafe}, rc::Rc, sync::{atomic::{self, AtomicBool, AtomicU64, AtomicUsize}, Arc, Condvar, Mutex, MutexGuard, OnceLock, Weak}};
 
-use crate::{comparator::Comparator, db::{filename::{current_file_name, descriptor_file_name, lock_file_name, log_file_name, set_current_file}, log_writer::Writer, version_edit::VersionEdit}, env::{log, Env, FileLock, WritableFile}, filter_policy::FilterPolicy, options::Options, slice::Slice, status::Status};
+use crate::{batch_transformer, cache::{new_lru_cache, new_partitioned_lru_cache}, comparator::Comparator, db::{filename::{current_file_name, descriptor_file_name, info_log_file_name, lock_file_name, log_file_name, old_info_log_file_name, parse_file_name, read_fence_file, set_current_file, set_fence_file, table_file_name}, log_reader::{Reader, Reporter}, log_writer::Writer, version_edit::{FileMetaData, SequenceNumber, VersionEdit}}, env::{log, Env, FileLock, Logger, PrefixLogger, WritableFile}, filter_policy::FilterPolicy, iterator::{new_error_iterator, Iterator, RawBlock}, options::{GetSnapshotOptions, MutableOptions, Options, ReadOptions, ReadTier, RetryPolicy, SnapshotExpiry, WalRecoveryMode, WriteOptions, DEFAULT_BLOCK_CACHE_SIZE, MAX_BLOCK_SIZE, MAX_MAX_OPEN_FILES, MAX_WRITE_BUFFER_SIZE, MIN_BLOCK_SIZE, MIN_MAX_OPEN_FILES, MIN_WRITE_BUFFER_SIZE}, slice::Slice, status::{Status, SubCode}, table::{merger::new_internal_merging_iterator, KeyValue, properties::{SizeHistogram, SIZE_HISTOGRAM_BUCKETS}, table_builder::TableBuilder}, util::rate_limiter::RateLimiter, write_batch::{self, TransactionId, TransactionRecord, WriteBatch}};
 
-use self::{dbformat::InternalKeyComparator, memtable::MemTable, version_set::VersionSet};
+use self::{builder::build_table, db_iter::DBIter, idempotency::TokenWindow, iter_pool::IterPool, dbformat::{extract_user_key, parse_internal_key, InternalFilterPolicy, InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType, MAX_SEQUENCE_NUMBER, NUM_LEVELS, VALUE_TYPE_FOR_SEEK}, memtable::MemTable, range_del::new_flush_iterator, range_iter::prefix_successor, range_lock::RangeLockTable, read_amp::{GetSample, ReadAmpWindow}, registry::Instance, snapshot::SnapshotList, stats::StatsCounters, two_phase::PreparedBatch, table_cache::TableCache, version_set::{Compaction, GetStats, Retained, Version, VersionSet}, write_timing::{WriteTimingWindow, LAST_WRITE_TIMING, WRITE_TIMING_WINDOW}};
 
 pub(crate) mod version_edit;
 pub(crate) mod version_set;
 pub(crate) mod dbformat;
+pub(crate) mod builder;
+pub(crate) mod db_iter;
 pub(crate) mod filename;
 pub(crate) mod log_writer;
+pub(crate) mod log_reader;
 pub(crate) mod log_format;
 pub(crate) mod memtable;
 pub(crate) mod skiplist;
+pub(crate) mod inspect;
+pub(crate) mod snapshot;
+pub(crate) mod table_cache;
+pub(crate) mod range_lock;
+pub(crate) mod repair;
+pub(crate) mod idempotency;
+pub(crate) mod range_iter;
+pub(crate) mod health;
+pub(crate) mod read_amp;
+pub(crate) mod features;
+pub(crate) mod checkpoint;
+pub(crate) mod ingest;
+pub(crate) mod registry;
+pub(crate) mod space_amp;
+pub(crate) mod iter_pool;
+pub(crate) mod range_del;
+pub(crate) mod sst_file_writer;
+pub(crate) mod migrate;
+pub(crate) mod write_timing;
+pub(crate) mod stats;
+pub(crate) mod close;
+pub(crate) mod table_checksum;
+pub(crate) mod write_controller;
+pub(crate) mod value_handle;
+pub(crate) mod live_iter;
+pub(crate) mod two_phase;
+
+pub use self::{close::CloseReport, db_iter::TombstoneIter, filename::FileType, health::{DbHealth, HealthState, ReadinessThresholds}, inspect::{inspect_db, DbInspection, FileSummary, LevelSummary, ManifestSummary, TableSummary, WalSummary}, live_iter::{DbIterator, OwnedDbIterator}, migrate::{migrate_comparator, migrate_comparator_with, KeyTransform, MigrateOptions, MigrationReport}, range_iter::{RangeIter, RangeKeys}, range_lock::RangeLockGuard, read_amp::ReadAmpReport, registry::{list_instances, InstanceInfo}, repair::repair_db, snapshot::Snapshot, space_amp::SpaceAmpReport, sst_file_writer::SstFileWriter, stats::{BloomBenefit, DbStats, GroupStats, Histogram, LevelStats, LookupStats, ReadStats, StatsDelta, StatsGroup, TableCacheStats, WriteStats}, table_checksum::TableChecksumReport, value_handle::ValueHandle, version_set::RetainedVersion, write_controller::WriteController, write_timing::{StepLatency, WriteTiming, WriteTimingReport}};
+pub use crate::table::properties::ValueThresholdAdvice;
 
 
 /// A DB is a persistent ordered map from keys to values.
 /// A DB is safe for concurrent access from multiple threads without
 /// any external synchronization.
+/// 
+/// Its methods are those of the state it shares with its background
+/// work, which it dereferences to.
 pub struct DB {
+    inner_: Arc<DbInner>,
+}
+
+impl Deref for DB {
+    type Target = DbInner;
+
+    fn deref(&self) -> &DbInner {
+        &self.inner_
+    }
+}
+
+mod inner {
+    use super::*;
+
+    /// The state of a DB.  Work handed to Env::schedule() holds an
+    /// Arc on it, so the state outlives the DB until that work is done;
+    /// it is the DB's drop that stops the work, not this one's.
+    /// 
+    /// Not nameable outside the crate: it is reached only through DB.
+    pub struct DbInner {
+        // This DB's own Arc, from which background work gets one of its
+        // own (see background_handle()).
+        pub(super) self_: Weak<DbInner>,
+
+        // Opened by open_read_only(): nothing is written to the directory.
+        pub(super) read_only_: bool,
+
+        // Set by close_with_deadline(), after which every call fails.
+        pub(super) closed_: AtomicBool,
+
+        // Env::now_micros() at which compactions give up; u64::MAX unless
+        // close_with_deadline() is running.
+        pub(super) close_deadline_: AtomicU64,
+
+        // Set once the DB is being dropped or closed; no background work is
+        // started after that.
+        pub(super) shutting_down_: AtomicBool,
+
+        // Whether imm_ is set, so that a compaction can check for a memtable
+        // to flush without taking mutex_.
+        pub(super) has_imm_: AtomicBool,
+
+        pub(super) env_: Arc<dyn Env>,
+        pub(super) internal_comparator_: InternalKeyComparator,
+        pub(super) internal_filter_policy_: Option<Arc<dyn FilterPolicy>>,
+        pub(super) options_: Options,  // options_.comparator == &internal_comparator_
+        pub(super) dbname_: String,
+
+        // Open tables, shared with versions_
+        pub(super) table_cache_: Arc<TableCache>,
+
+        // Advisory key-range locks; see lock_range().  Not protected by mutex_.
+        pub(super) range_locks_: Arc<RangeLockTable>,
+
+        // Costs of recent gets; see read_amplification_report().  Not
+        // protected by mutex_, so that recording a get does not take it.
+        pub(super) read_amp_: Mutex<ReadAmpWindow>,
+
+        // Step timings of recent writes made with WriteOptions::collect_timing;
+        // see write_timing_report().  Not protected by mutex_.
+        pub(super) write_timing_: Mutex<WriteTimingWindow>,
+
+        // Counters and dirty flags behind stats_snapshot().  Not protected
+        // by mutex_.
+        pub(super) stats_counters_: Arc<StatsCounters>,
+
+        // Dropped iterators kept for reuse; see Options::iterator_pool_size.
+        // Emptied under mutex_ whenever the memtables or the current version
+        // change.
+        pub(super) iter_pool_: Arc<IterPool>,
+
+        // Paces the output of compactions; see
+        // Options::compaction_rate_limit_bytes_per_sec.  Its rate is changed
+        // under mutex_ and read without it.
+        pub(super) compaction_rate_limiter_: RateLimiter,
+
+        // Delays writes by the compaction debt; see write_controller().
+        pub(super) write_controller_: Arc<WriteController>,
+
+        // The DbIterators and OwnedDbIterators that are alive.
+        pub(super) live_iterators_: AtomicUsize,
+
+        // This DB's entry in list_instances(), if options_.instance_name is
+        // set.  Set once by open().
+        pub(super) instance_: OnceLock<Arc<Instance>>,
+
+        pub(super) mutex_: Mutex<DbState>,
+
+        // Signalled under mutex_ when a round of background work finishes,
+        // and when a manual compaction is taken off manual_compaction_.
+        pub(super) background_work_finished_signal_: Condvar,
+    }
+}
+
+use self::inner::DbInner;
+
+/// The state of a DB protected by its mutex_.
+struct DbState {
     // Lock over the persistent DB state.  Non-null iff successfully acquired.
-    db_lock_: RefCell<Option<FileLock>>,
-
-    env_: Rc<dyn Env>,
-    internal_comparator_: InternalKeyComparator,
-    internal_filter_policy_: Option<Rc<dyn FilterPolicy>>,
-    options_: Options,  // options_.comparator == &internal_comparator_
-    dbname_: String,
-
-    // State below is protected by mutex_
-    mutex_: Mutex<()>,
-    mem_: Option<Rc<MemTable>>,
-    imm_: Option<Rc<MemTable>>,
-    logfile_: Option<Rc<dyn WritableFile>>,
+    db_lock_: Option<FileLock>,
+
+    mem_: Option<Arc<MemTable>>,
+    imm_: Option<Arc<MemTable>>,
+    logfile_: Option<Arc<dyn WritableFile>>,
     logfile_number_: u64,
+    // Taken by the writer at the front of writers_ while it logs with
+    // mutex_ released.
     log_: Option<Writer>,
+    log_records_: u64,    // Records added to log_, for its trailer
+
+    // Last sequence as of the last switch to a new mem_: every write up
+    // to it is in imm_ or the tables.  See ReadTier::PersistedAndImmutable.
+    switch_sequence_: SequenceNumber,
 
-    versions_: RefCell<VersionSet>,
+    // Env::now_micros() at the first write into mem_ and imm_, if they
+    // hold any.
+    mem_first_write_micros_: Option<u64>,
+    imm_first_write_micros_: Option<u64>,
+
+    // Whether mem_ and imm_ hold writes made with WriteOptions::disable_wal.
+    mem_unlogged_: bool,
+    imm_unlogged_: bool,
+
+    // Queue of writers.
+    writers_: VecDeque<Arc<QueuedWrite>>,
+
+    snapshots_: SnapshotList,
+
+    // Set of table files to protect from deletion because they are
+    // part of ongoing compactions.
+    pending_outputs_: BTreeSet<u64>,
+
+    versions_: VersionSet,
+
+    // Options that may change at runtime; see set_options().
+    mutable_options_: MutableOptions,
+
+    // Has a background compaction been scheduled (or is one running)?
+    background_compaction_scheduled_: bool,
+
+    // Manual compaction for the background work to run, if any.
+    manual_compaction_: Option<ManualCompaction>,
+
+    // Have we encountered a background error?  Once set, all writes fail.
+    bg_error_: Status,
+
+    // Failures of background work retried since it last succeeded; see
+    // Options::max_background_error_retries.
+    background_error_retries_: usize,
+
+    // Writes delayed and stopped because of too many level-0 files
+    delayed_writes_: u64,
+    wal_recovery_dropped_records_: u64,   // Log records DB::open() did not replay
+    recovery_flushes_: u64,   // Level-0 tables DB::open() wrote while replaying logs
+    possible_unlogged_data_loss_: bool,   // DB::open() found unflushed unlogged writes recorded
+    stopped_writes_: u64,
+
+    // Per level compaction stats.  stats_[level] stores the stats for
+    // compactions that produced data for the specified "level".
+    stats_: Vec<CompactionStats>,
+
+    // Tokens of recent writes; see WriteOptions::idempotency_token.
+    idempotency_tokens_: TokenWindow,
+
+    // Seeds the read sampling of each new iterator.
+    read_sampling_seed_: u32,
+
+    // The version last searched for tables with many deletion markers;
+    // see Options::tombstone_compaction_ratio.
+    tombstone_scanned_: Weak<Version>,
+
+    // Share of a checksum verification owed to the reads so far; see
+    // Options::checksum_verification_sample_rate.
+    checksum_sample_credit_: f64,
+
+    // Transactions prepared but not yet released (see two_phase.rs), and
+    // the number of the log holding the prepare record of each: those
+    // logs must outlive the flush of the memtables logged into them.
+    prepared_: BTreeMap<TransactionId, u64>,
+
+    // Prepared transactions DB::open() resolved, with the batch of each
+    // one to commit, for it to record in the new log.
+    resolved_: Vec<(TransactionId, Option<WriteBatch>)>,
 }
 
 impl DB {
+    fn new(raw_options: &Options, dbname: &str, read_only: bool) -> DB {
+        DB { inner_: Arc::new_cyclic(|me| DbInner::new(raw_options, dbname, read_only, me.clone())) }
+    }
+
     /// Open the database with the specified "name".
     /// Returns boxed DB on success and a non-OK status on error.
     pub fn open(options: &Options, name: &str) -> Result<Box<DB>, Status> {
-        let mut db = Box::new(Self::new(options, name));
+        let db = Box::new(Self::new(options, name, false));
+        let mut s;
+        let mut schedule = false;
         {
-            let _unused = db.mutex_.lock().expect("failed to acquire lock");
+            let mut state = db.mutex_.lock().expect("failed to acquire lock");
+            if let Some(instance_name) = &options.instance_name {
+                // Registered before recovering, so that a second DB with
+                // the name fails without touching the database.
+                match Instance::register(instance_name, name, db.current_health(&state)) {
+                    Ok(instance) => { let _ = db.instance_.set(instance); },
+                    Err(s) => { return Err(s); },
+                }
+            }
             let mut edit = VersionEdit::new();
             // Recover handles create_if_missing, error_if_exists
             let mut save_manifest = false;
-            let mut s = db.recover(&mut edit, &mut save_manifest);
-            if s.ok() && db.mem_.is_none() {
+            s = db.recover(&mut state, &mut edit, &mut save_manifest);
+            sync_point!("db:open:after-recover", s);
+            if s.ok() && state.mem_.is_none() {
                 // Create new log and a corresponding memtable.
-                let new_log_number = db.versions_.borrow_mut().new_file_number();
+                let new_log_number = state.versions_.new_file_number();
                 match options.env.new_writable_file(&log_file_name(name, new_log_number)) {
                     Ok(file) => {
-                        edit.set_log_number(new_log_number);
-                        db.logfile_ = Some(file.clone());
-                        db.logfile_number_ = new_log_number;
-                        db.log_ = Some(Writer::new(file));
-                        db.mem_ = Some(Rc::new(MemTable::new(&db.internal_comparator_)));
+                        state.logfile_ = Some(file.clone());
+                        state.logfile_number_ = new_log_number;
+                        state.log_ = Some(db.new_log_writer(&state, file));
+                        state.log_records_ = 0;
+                        state.mem_ = Some(Arc::new(db.new_memtable()));
+                        state.switch_sequence_ = state.versions_.last_sequence();
                     },
                     Err(s_) => { s = s_; },
                 }
+                if s.ok() {
+                    // Before the logs holding the prepare records become
+                    // obsolete below.
+                    s = db.log_resolved_transactions(&mut state);
+                }
             }
+            if s.ok() && save_manifest {
+                edit.set_prev_log_number(0);    // No older logs needed after recovery.
+                edit.set_log_number(state.logfile_number_);
+                // The version replaced here lacks the data replayed from
+                // the logs, so it is not retained.
+                s = db.log_and_apply(&mut state, &mut edit, None);
+            }
+            if s.ok() {
+                db.remove_obsolete_files(&mut state);
+                schedule = db.maybe_schedule_compaction(&mut state);
+                db.publish_health(&state);
+            }
+            debug_assert!(!s.ok() || state.mem_.is_some());
+        }
+        if schedule {
+            db.schedule_background_call();
+        }
+        if s.ok() {
+            Ok(db)
+        } else {
+            Err(s)
         }
-        
-        
-        todo!()
     }
 
-    fn new(raw_options: &Options, dbname: &str) -> DB {
-        let icmp = InternalKeyComparator::new(raw_options.comparator.clone());
-        Self {
-            db_lock_: RefCell::new(None),
-            env_: raw_options.env.clone(),
-            internal_comparator_: icmp.clone(),
-            internal_filter_policy_: raw_options.filter_policy.clone(),
-            options_: sanitize_options(dbname, &icmp, raw_options.filter_policy.clone(), raw_options),
-            dbname_: dbname.to_string(),
-            mutex_: Mutex::new(()),
-            mem_: None,
-            imm_: None,
-            logfile_: None,
-            logfile_number_: 0,
-            log_: None,
-            versions_: RefCell::new(VersionSet::new()),
+    /// Like open(), but if the database is locked by someone else (e.g. a
+    /// previous process that is still shutting down), wait and try again
+    /// as "retry" says.  Waits go through options.env's
+    /// sleep_for_microseconds().  Any other error is returned right away.
+    /// 
+    /// Gives up with the last error, annotated with the number of
+    /// attempts and the time spent waiting, once retry.max_wait_micros
+    /// have been spent or retry.cancel is set.
+    pub fn open_with_retry(options: &Options, name: &str, retry: RetryPolicy) -> Result<Box<DB>, Status> {
+        let mut waited = 0;
+        let mut backoff = retry.backoff_start_micros;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let s = match DB::open(options, name) {
+                Ok(db) => return Ok(db),
+                Err(s) if s.is_busy() => s,
+                Err(s) => return Err(s),
+            };
+            if waited >= retry.max_wait_micros {
+                return Err(s.annotate(&format!("gave up after {} attempts, waited {} micros", attempts, waited)));
+            }
+
+            // Never wait for nothing, or a zero backoff would spin forever.
+            let wait = backoff.clamp(1, retry.max_wait_micros - waited);
+            log(options.info_log.clone(), &format!("{} is locked; retrying in {} micros", name, wait));
+            options.env.sleep_for_microseconds(wait);
+            waited += wait;
+            backoff = (backoff as f64 * retry.backoff_multiplier) as u64;
+            if retry.cancelled() {
+                return Err(s.annotate(&format!("cancelled after {} attempts, waited {} micros", attempts, waited)));
+            }
         }
     }
 
-    fn new_db(&self) -> Status {
-        let mut new_db = VersionEdit::new();
-        new_db.set_comparator_name(self.internal_comparator_.name());
-        new_db.set_log_number(0);
-        new_db.set_next_file(2);
-        new_db.set_last_sequence(0);
+    /// Open the database with the specified "name" for reading only.  The
+    /// directory is not written to: the LOCK file is not taken, and the
+    /// log files are replayed into memory rather than written out to
+    /// tables.  So another process may have the database open for writing
+    /// meanwhile.
+    ///
+    /// The DB sees the state as of its open.  Writes, compactions and
+    /// flushes fail with NotSupported.  Files it needs may still be
+    /// deleted by the writer's compactions, after which reads of them fail.
+    pub fn open_read_only(options: &Options, name: &str) -> Result<Box<DB>, Status> {
+        // Neither MANIFEST nor fence may be written to.
+        let options = Options { reuse_logs: false, fencing_token: None, ..options.clone() };
+        let db = Box::new(Self::new(&options, name, true));
+        let s = {
+            let mut state = db.mutex_.lock().expect("failed to acquire lock");
+            let mut edit = VersionEdit::new();
+            let mut save_manifest = false;
+            let s = db.recover(&mut state, &mut edit, &mut save_manifest);
+            if s.ok() && state.mem_.is_none() {
+                state.mem_ = Some(Arc::new(db.new_memtable()));
+            }
+            s
+        };
+        if s.ok() { Ok(db) } else { Err(s) }
+    }
 
-        let manifest = descriptor_file_name(&self.dbname_, 1);
-        let mut s = Status::new_ok();
-        match self.env_.new_writable_file(&manifest) {
-            Ok(file) => {
-                let mut log = Writer::new(file.clone());
-                let mut record = Vec::new();
-                new_db.encode_to(&mut record);
-                s = log.add_record(&Slice::new(&record));
+    /// Like new_iterator(), but the iterator holds on to the DB instead
+    /// of borrowing it, so it may be kept after the other handles on the
+    /// DB are dropped, or moved to another thread.  The DB is dropped, and
+    /// its background work stopped, only once the iterator is.
+    pub fn new_owned_iterator(self: &Arc<Self>, options: &ReadOptions) -> OwnedDbIterator {
+        OwnedDbIterator::new(self.new_bounded_iterator(options, None).0, self.clone())
+    }
+
+    /// Return how long the last write made on the calling thread with
+    /// WriteOptions::collect_timing spent in each step of its commit, or
+    /// None if there was none.
+    pub fn last_write_timing() -> Option<WriteTiming> {
+        LAST_WRITE_TIMING.with(|last| last.get())
+    }
+
+    /// Return every property name get_property() understands, with
+    /// "leveldb.num-files-at-level<N>" listed for each level.
+    pub fn property_names() -> Vec<&'static str> {
+        PROPERTY_NAMES.to_vec()
+    }
+}
+
+impl DbInner {
+    /// Set the database entry for "key" to "value".  Returns OK on success,
+    /// and a non-OK status on error.
+    /// Note: consider setting options.sync = true.
+    pub fn put(&self, options: &WriteOptions, key: &Slice, value: &Slice) -> Status {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(options, batch)
+    }
+
+    /// Remove the database entry (if any) for "key".  Returns OK on
+    /// success, and a non-OK status on error.  It is not an error if "key"
+    /// did not exist in the database.
+    /// Note: consider setting options.sync = true.
+    pub fn delete(&self, options: &WriteOptions, key: &Slice) -> Status {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(options, batch)
+    }
+
+    /// Remove the database entries (if any) for the keys in [begin, end).
+    /// Returns OK on success, and a non-OK status on error.  Fails with
+    /// InvalidArgument if "begin" does not come before "end".
+    /// Note: consider setting options.sync = true.
+    pub fn delete_range(&self, options: &WriteOptions, begin: &Slice, end: &Slice) -> Status {
+        if let Err(s) = self.check_range(begin.data(), end.data()) {
+            return s;
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete_range(begin, end);
+        self.write(options, batch)
+    }
+
+    /// Apply the specified updates to the database, as rewritten by
+    /// Options::batch_transformer if one is set.
+    /// Returns OK on success, non-OK on failure.
+    /// Note: consider setting options.sync = true.
+    pub fn write(&self, options: &WriteOptions, updates: WriteBatch) -> Status {
+        self.write_impl(options, updates, None)
+    }
+
+    /// Like write(), but keys in the range held by "guard" are not
+    /// considered locked.  Keys in ranges locked by others still are.
+    pub fn write_locked(&self, guard: &RangeLockGuard, options: &WriteOptions, updates: WriteBatch) -> Status {
+        self.write_impl(options, updates, Some(guard))
+    }
+
+    /// Lock the user key range [begin, end) for the life of the returned
+    /// guard, waiting for any overlapping lock to be released first.
+    /// While the guard is held, writes touching the range block (or fail
+    /// with a Busy status if WriteOptions::fail_on_locked_range is set)
+    /// unless they are made through write_locked() with this guard.
+    ///
+    /// The lock is advisory and local to this process: reads are not
+    /// affected and other processes cannot see it.
+    pub fn lock_range(&self, begin: &[u8], end: &[u8]) -> Result<RangeLockGuard, Status> {
+        self.check_open()?;
+        self.check_range(begin, end)?;
+        self.range_locks_.lock(begin, end, true)
+    }
+
+    /// Like lock_range(), but returns a Busy status instead of waiting
+    /// when the range overlaps a locked one.
+    pub fn try_lock_range(&self, begin: &[u8], end: &[u8]) -> Result<RangeLockGuard, Status> {
+        self.check_open()?;
+        self.check_range(begin, end)?;
+        self.range_locks_.lock(begin, end, false)
+    }
+
+    /// Fail with InvalidArgument once close_with_deadline() has run.
+    fn check_open(&self) -> Result<(), Status> {
+        if self.closed_.load(atomic::Ordering::Acquire) {
+            return Err(Status::invalid_argument("db closed", ""));
+        }
+        Ok(())
+    }
+
+    /// Fails a read through a snapshot that has expired; see
+    /// Options::max_snapshot_age_seconds.
+    fn check_snapshot(options: &ReadOptions) -> Result<(), Status> {
+        match &options.snapshot {
+            Some(snapshot) if snapshot.expired() => Err(Status::invalid_argument("snapshot expired", "")),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_range(&self, begin: &[u8], end: &[u8]) -> Result<(), Status> {
+        let ucmp = self.internal_comparator_.user_comparator();
+        if ucmp.compare(&Slice::new(begin), &Slice::new(end)) != Ordering::Less {
+            return Err(Status::invalid_argument("empty key range", ""));
+        }
+        Ok(())
+    }
+
+    fn write_impl(&self, options: &WriteOptions, updates: WriteBatch, owner: Option<&RangeLockGuard>) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.options_.replica_mode {
+            return Status::not_supported("write", "database is opened in replica mode");
+        }
+        if self.read_only_ {
+            return Status::not_supported("write", "database is opened read-only");
+        }
+        let updates = match &self.options_.batch_transformer {
+            Some(transformer) => match batch_transformer::transform(self as *const DbInner as usize, transformer.as_ref(), &updates) {
+                Ok(transformed) => transformed,
+                Err(s) => return s,
+            },
+            None => updates,
+        };
+        let start_micros = options.collect_timing.then(|| self.env_.now_micros());
+        let (entries, bytes) = (updates.count() as u64, updates.byte_size() as u64);
+        let _ticket = match self.range_locks_.begin_write(&updates, !options.fail_on_locked_range, owner) {
+            Ok(ticket) => ticket,
+            Err(s) => return s,
+        };
+        let w = Arc::new(QueuedWrite::new(updates, options));
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let state = self.wait_for_turn(state, &w);
+        let mut timing = start_micros.map(|start| WriteTiming { queue_wait_micros: self.env_.now_micros() - start, ..Default::default() });
+        let s = if w.done.load(atomic::Ordering::Acquire) {
+            drop(state);
+            if let Some(timing) = &mut timing {
+                timing.grouped = true;
+            }
+            w.status.lock().unwrap().clone()
+        } else {
+            self.lead_write_group(state, &w, timing.as_mut())
+        };
+        if let (Some(start), Some(mut timing)) = (start_micros, timing) {
+            timing.total_micros = self.env_.now_micros() - start;
+            self.record_write_timing(timing);
+        }
+        if s.ok() {
+            self.stats_counters_.record_write(entries, bytes);
+        }
+        s
+    }
+
+    /// Make "timing" this thread's last_write_timing(), and add it to
+    /// write_timing_.
+    fn record_write_timing(&self, timing: WriteTiming) {
+        LAST_WRITE_TIMING.with(|last| last.set(Some(timing)));
+        self.write_timing_.lock().unwrap().record(timing);
+    }
+
+    /// Report the median and 99th percentile time of each commit step
+    /// over the last writes made with WriteOptions::collect_timing.
+    pub fn write_timing_report(&self) -> WriteTimingReport {
+        self.write_timing_.lock().unwrap().report()
+    }
+
+    /// Queue "w" behind the writers already in writers_, and wait until it
+    /// is at the front or has been committed by the leader of its group.
+    fn wait_for_turn<'a>(&'a self, mut state: MutexGuard<'a, DbState>, w: &Arc<QueuedWrite>) -> MutexGuard<'a, DbState> {
+        state.writers_.push_back(w.clone());
+        while !w.done.load(atomic::Ordering::Acquire) && !state.writers_.front().is_some_and(|front| Arc::ptr_eq(front, w)) {
+            state = w.cv.wait(state).expect("failed to acquire lock");
+        }
+        state
+    }
+
+    /// Wait for the writers queued before the call to finish, and keep
+    /// later ones waiting until end_exclusive_write() is called with the
+    /// returned entry.  This lets flushes and compactions switch the
+    /// memtable and log while no writer is logging with mutex_ released.
+    fn begin_exclusive_write(&self) -> (MutexGuard<'_, DbState>, Arc<QueuedWrite>) {
+        let w = Arc::new(QueuedWrite::exclusive());
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let state = self.wait_for_turn(state, &w);
+        (state, w)
+    }
+
+    /// Run "f" on the state between begin_exclusive_write() and
+    /// end_exclusive_write().
+    #[cfg(test)]
+    fn with_exclusive_write<R>(&self, f: impl FnOnce(&mut DbState) -> R) -> R {
+        let (mut state, w) = self.begin_exclusive_write();
+        let result = f(&mut state);
+        self.end_exclusive_write(&mut state, &w);
+        result
+    }
+
+    /// Let the writers queued behind "w" go ahead.
+    /// REQUIRES: "w" was returned by begin_exclusive_write(), and "state"
+    /// is mutex_'s
+    fn end_exclusive_write(&self, state: &mut DbState, w: &Arc<QueuedWrite>) {
+        let front = state.writers_.pop_front().expect("exclusive write is not queued");
+        debug_assert!(Arc::ptr_eq(&front, w));
+        if let Some(front) = state.writers_.front() {
+            front.cv.notify_one();
+        }
+    }
+
+    /// Commit the batch of "w" together with those of the writers queued
+    /// behind it that can join it, as one log record, and wake up the
+    /// writers whose batches went in.  If "timing" is set, the time spent
+    /// logging and inserting is added to it.
+    /// REQUIRES: "state" is mutex_'s, and "w" is at the front of writers_
+    fn lead_write_group<'a>(&'a self, state: MutexGuard<'a, DbState>, w: &Arc<QueuedWrite>, timing: Option<&mut WriteTiming>) -> Status {
+        // May temporarily unlock and wait.
+        let (mut state, mut s) = self.make_room_for_write(state, w.batch.approximate_size() as u64);
+        let mut last_writer = w.clone();
+        if s.ok() {
+            let applied = w.token.and_then(|token| state.idempotency_tokens_.get(&token));
+            if let Some(sequence) = applied {
+                s = Status::already_applied("committed at sequence", &sequence.to_string());
+            } else if w.token.is_some() {
+                // The first token logged makes the database unreadable by builds
+                // without token support, so record that in the MANIFEST first.
+                s = self.require_features(&mut state, features::IDEMPOTENCY_TOKENS);
+            }
+            if s.ok() && applied.is_none() && state.writers_.iter().any(|w| w.batch.has_range_deletions()) {
+                // Likewise for the first range deletion (the writers queued
+                // behind "w" may join its group).
+                s = self.require_features(&mut state, features::RANGE_DELETIONS);
+            }
+            if s.ok() && applied.is_none() && !state.mem_unlogged_ && state.writers_.iter().any(|w| w.disable_wal) {
+                // Likewise for the first write into mem_ that skips the log.
+                s = self.note_unlogged_writes(&mut state);
+            }
+            if s.ok() && applied.is_none() {
+                let (mut updates, last, mut logged_runs) = Self::build_batch_group(&state);
+                last_writer = last;
+                let mut last_sequence = state.versions_.last_sequence();
+                updates.set_sequence(last_sequence + 1);
+                for (offset, run) in logged_runs.iter_mut().flatten() {
+                    run.set_sequence(last_sequence + 1 + *offset);
+                }
+                last_sequence += updates.count() as u64;
+
+                // Add to log and apply to memtable.  We can release the lock
+                // during this phase since "w" is currently responsible for
+                // logging and protects against concurrent loggers and
+                // concurrent writes into mem_.
+                let mut log = state.log_.take().expect("log is in use");
+                let logfile = state.logfile_.clone().unwrap();
+                let mem = state.mem_.clone().unwrap();
+                drop(state);
+                let now = || if timing.is_some() { self.env_.now_micros() } else { 0 };
+                sync_point!("db:write:before-log", s);
+                let append_start = now();
+                let mut records = 0;
+                match &logged_runs {
+                    None => {
+                        if s.ok() {
+                            s = log.add_record(&updates.contents());
+                            records += s.ok() as u64;
+                        }
+                    },
+                    Some(runs) => {
+                        for (_, run) in runs {
+                            if !s.ok() {
+                                break;
+                            }
+                            s = log.add_record(&run.contents());
+                            records += s.ok() as u64;
+                        }
+                    },
+                }
+                let mut sync_error = false;
+                let sync_start = now();
+                if s.ok() && w.sync && records > 0 {
+                    s = logfile.sync();
+                    sync_error = !s.ok();
+                }
+                sync_point!("db:write:after-log", s);
+                let insert_start = now();
                 if s.ok() {
-                    s = file.sync();
+                    s = updates.insert_into(&mem);
+                }
+                let insert_end = now();
+                if let Some(timing) = timing {
+                    timing.wal_append_micros = sync_start - append_start;
+                    timing.wal_sync_micros = insert_start - sync_start;
+                    timing.memtable_insert_micros = insert_end - insert_start;
+                }
+                state = self.mutex_.lock().expect("failed to acquire lock");
+                state.log_ = Some(log);
+                state.log_records_ += records;
+                if sync_error || (self.options_.paranoid_checks && !s.ok()) {
+                    // The state of the log file is indeterminate: the log record we
+                    // just added may or may not show up when the DB is re-opened.
+                    // So we force the DB into a mode where all future writes fail.
+                    self.record_background_error(&mut state, &s);
+                }
+                if sync_error {
+                    // Whatever the log holds past its last good sync may be
+                    // gone even if a later sync succeeds, so it is never
+                    // written or synced again, not even for its trailer.
+                    state.log_ = None;
+                    state.logfile_ = None;
                 }
                 if s.ok() {
-                    s = file.close();
+                    self.note_memtable_write(&mut state);
+                    if let Some(token) = w.token {
+                        let logfile_number = state.logfile_number_;
+                        state.idempotency_tokens_.insert(token, updates.sequence(), logfile_number);
+                    }
                 }
-            },
-            Err(s) => { return s; },
+                state.versions_.set_last_sequence(last_sequence);
+            }
         }
-        if s.ok() {
-            // Make "CURRENT" file that points to the new manifest file.
-            s = set_current_file(self.env_.clone(), &self.dbname_, 1);
-        } else {
-            self.env_.remove_file(&manifest);
+
+        loop {
+            let ready = state.writers_.pop_front().expect("write group is not queued");
+            if !Arc::ptr_eq(&ready, w) {
+                *ready.status.lock().unwrap() = s.clone();
+                ready.done.store(true, atomic::Ordering::Release);
+                ready.cv.notify_one();
+            }
+            if Arc::ptr_eq(&ready, &last_writer) {
+                break;
+            }
+        }
+
+        // Notify new head of write queue
+        if let Some(front) = state.writers_.front() {
+            front.cv.notify_one();
         }
+        drop(state);
         s
     }
 
-    /// The mutex should be acquired before calling it.
-    fn recover(&self, edit: &mut VersionEdit, save_manifest: &mut bool) -> Status {
-        // Ignore error from CreateDir since the creation of the DB is
-        // committed only when the descriptor is created, and this directory
-        // may already exist from a previous failed creation attempt.
-        let _ = self.env_.create_dir(&self.dbname_);
-        debug_assert!(self.db_lock_.borrow().is_none());
-        match self.env_.lock_file(&lock_file_name(&self.dbname_)) {
-            Ok(f) => { self.db_lock_.borrow_mut().replace(f); },
-            Err(s) => { return s; },
-        };
+    /// Record in the MANIFEST that the database uses the optional features
+    /// in "features", unless it already is.
+    /// REQUIRES: "state" is mutex_'s
+    fn require_features(&self, state: &mut DbState, features: u64) -> Status {
+        let required = state.versions_.required_features();
+        if required & features == features {
+            return Status::new_ok();
+        }
+        let mut edit = VersionEdit::new();
+        edit.set_required_features(required | features);
+        self.log_and_apply(state, &mut edit, None)
+    }
 
-        if !self.env_.file_exists(&current_file_name(&self.dbname_)) {
-            if self.options_.create_if_missing {
-                log(self.options_.info_log.clone(), &format!("Creating DB {} since it was missing.", &self.dbname_));
-                let s = self.new_db();
-                if !s.ok() {
-                    return s;
+    /// Record in the MANIFEST that mem_ holds writes made with
+    /// WriteOptions::disable_wal, so that DB::open() after a crash knows
+    /// they may be lost.  Flushing mem_ clears the record again.
+    /// REQUIRES: "state" is mutex_'s
+    fn note_unlogged_writes(&self, state: &mut DbState) -> Status {
+        if !state.versions_.unlogged_writes() {
+            let mut edit = VersionEdit::new();
+            edit.set_unlogged_writes(true);
+            let s = self.log_and_apply(state, &mut edit, None);
+            if !s.ok() {
+                return s;
+            }
+        }
+        state.mem_unlogged_ = true;
+        Status::new_ok()
+    }
+
+    /// Merge the batch at the front of writers_ with those of the writers
+    /// behind it that can join it.  Returns the merged batch, the last
+    /// writer whose batch it holds, and, if some of the batches are not to
+    /// be logged (see WriteOptions::disable_wal), the runs of consecutive
+    /// batches to log instead, each with the number of entries before it
+    /// in the merged batch.
+    /// REQUIRES: "state" is mutex_'s, and writers_ is not empty
+    fn build_batch_group(state: &DbState) -> (WriteBatch, Arc<QueuedWrite>, Option<LoggedRuns>) {
+        let writers = &state.writers_;
+        let first = writers.front().expect("no queued writer");
+        let mut result = first.batch.clone();
+        let mut size = result.byte_size();
+
+        // Allow the group to grow up to a maximum size, but if the
+        // original write is small, limit the growth so we do not slow
+        // down the small write too much.
+        let max_size = if size <= (128 << 10) { size + (128 << 10) } else { 1 << 20 };
+
+        let mut last_writer = first.clone();
+        for w in writers.iter().skip(1) {
+            if w.exclusive {
+                // A flush or compaction waits for the writes before it.
+                break;
+            }
+            if w.sync && !first.sync {
+                // Do not include a sync write into a batch handled by a non-sync write.
+                break;
+            }
+            if first.token.is_some() || w.token.is_some() {
+                // A batch carrying an idempotency token is logged on its
+                // own, so recovery finds the token in the record header.
+                break;
+            }
+            size += w.batch.byte_size();
+            if size > max_size {
+                // Do not make batch too big
+                break;
+            }
+            result.append(&w.batch);
+            last_writer = w.clone();
+        }
+
+        let group = writers.iter().take_while(|w| !Arc::ptr_eq(w, &last_writer)).chain([&last_writer]);
+        let logged_runs = group.clone().any(|w| w.disable_wal).then(|| {
+            let mut runs = LoggedRuns::new();
+            let mut offset = 0;
+            for w in group {
+                if !w.disable_wal {
+                    match runs.last_mut() {
+                        Some((start, run)) if *start + run.count() as u64 == offset => run.append(&w.batch),
+                        _ => runs.push((offset, w.batch.clone())),
+                    }
+                }
+                offset += w.batch.count() as u64;
+            }
+            runs
+        });
+        (result, last_writer, logged_runs)
+    }
+
+    /// Make sure the memtable has room for a write of "bytes" bytes,
+    /// flushing it once it outgrows the write buffer.  The write is first
+    /// delayed by write_controller() while there is compaction debt, and
+    /// stopped at l0_stop_writes_trigger level-0 files until level-0 has
+    /// been compacted.  Fails with the background error once one has been
+    /// recorded.  Returns mutex_'s guard again, as the delay and the waits
+    /// release it.
+    /// REQUIRES: "state" is mutex_'s, and the caller is at the front of
+    /// writers_
+    fn make_room_for_write<'a>(&'a self, mut state: MutexGuard<'a, DbState>, bytes: u64) -> (MutexGuard<'a, DbState>, Status) {
+        let mut allow_delay = true;
+        let mut stopped = false;
+        loop {
+            let level0_files = state.versions_.num_level_files(0) as i32;
+            let mutable_options = state.mutable_options_.clone();
+            if !state.bg_error_.ok() {
+                // Yield previous error
+                let s = state.bg_error_.clone();
+                return (state, s);
+            } else if allow_delay && bytes > 0 && self.write_controller_.estimated_delay_per_write() > 0 {
+                // We are falling behind on compactions, or getting close
+                // to hitting a hard limit on the number of L0 files.
+                // Rather than delaying a single write by several seconds
+                // when we hit the hard limit, start delaying each
+                // individual write to reduce latency variance.  Also, this
+                // delay hands over some CPU to the compaction thread in
+                // case it is sharing the same core as the writer.
+                state.delayed_writes_ += 1;
+                drop(state);
+                self.write_controller_.acquire(bytes);
+                allow_delay = false;  // Do not delay a single write more than once
+                state = self.mutex_.lock().expect("failed to acquire lock");
+            } else if state.mem_.as_ref().unwrap().approximate_memory_usage() <= mutable_options.write_buffer_size {
+                // There is room in current memtable
+                break;
+            } else if state.imm_.is_some() {
+                // We have filled up the current memtable, but the previous
+                // one is still being compacted, so we wait.
+                log(self.options_.info_log.clone(), "Current memtable full; waiting...");
+                state = self.wait_for_background_work(state);
+            } else if level0_files >= mutable_options.l0_stop_writes_trigger {
+                // There are too many level-0 files.
+                if !stopped {
+                    log(self.options_.info_log.clone(), "Too many L0 files; waiting...");
+                    state.stopped_writes_ += 1;
+                    stopped = true;
+                }
+                if state.background_compaction_scheduled_ {
+                    state = self.background_work_finished_signal_.wait(state).expect("failed to acquire lock");
+                } else {
+                    // No compaction is due when l0_stop_writes_trigger is
+                    // set below the level-0 compaction trigger, so ask for
+                    // one of level-0.
+                    let s;
+                    (state, s) = self.compact_level_range(state, 0, None, None);
+                    if !s.ok() {
+                        return (state, s);
+                    }
                 }
             } else {
-                return Status::invalid_argument(&self.dbname_, "does not exist (create_if_missing is false)");
+                // Attempt to switch to a new memtable and trigger flush of old
+                sync_point!("db:make-room:before-switch");
+                let s = self.switch_memtable(&mut state);
+                if !s.ok() {
+                    return (state, s);
+                }
+                if self.maybe_schedule_compaction(&mut state) {
+                    drop(state);
+                    self.schedule_background_call();
+                    state = self.mutex_.lock().expect("failed to acquire lock");
+                }
             }
-        } else {
-            return Status::invalid_argument(&self.dbname_, "exists (error_if_exists is true)");
         }
+        (state, Status::new_ok())
+    }
 
-        let mut save_manifest = false;
-        match self.versions_.borrow_mut().recover() {
-            Ok(save) => { save_manifest = save; },
-            Err(s) => { return s; },
+    /// Apply a batch shipped from a primary database, keeping the sequence
+    /// numbers it was assigned there: its first entry gets
+    /// "first_sequence", which must be exactly one past
+    /// last_applied_sequence().  Otherwise returns a status for which
+    /// Status::is_gap() returns true, naming the expected sequence, so the
+    /// caller can refetch from there.
+    ///
+    /// The batch is added to this database's own log (without a sync)
+    /// before it is applied, so it survives a restart like any write.
+    /// Options::batch_transformer is not run on it: the primary already
+    /// committed the transformed batch.
+    /// REQUIRES: options.replica_mode was set when the database was opened.
+    pub fn apply_replicated_batch(&self, batch: &WriteBatch, first_sequence: SequenceNumber) -> Status {
+        if !self.options_.replica_mode {
+            return Status::not_supported("apply_replicated_batch", "database is not opened in replica mode");
+        }
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.read_only_ {
+            return Status::not_supported("apply_replicated_batch", "database is opened read-only");
+        }
+        let (state, w) = self.begin_exclusive_write();
+        let (mut state, s) = self.make_room_for_write(state, batch.approximate_size() as u64);
+        let s = if s.ok() { self.apply_replicated_batch_locked(&mut state, batch, first_sequence) } else { s };
+        self.end_exclusive_write(&mut state, &w);
+        s
+    }
+
+    /// REQUIRES: "state" is mutex_'s, and no writer is logging
+    fn apply_replicated_batch_locked(&self, state: &mut DbState, batch: &WriteBatch, first_sequence: SequenceNumber) -> Status {
+        if batch.has_range_deletions() {
+            let s = self.require_features(state, features::RANGE_DELETIONS);
+            if !s.ok() {
+                return s;
+            }
+        }
+        let expected = state.versions_.last_sequence() + 1;
+        if first_sequence != expected {
+            return Status::gap("expected sequence", &expected.to_string());
         }
+        let mut updates = batch.clone();
+        updates.set_sequence(first_sequence);
 
-        todo!()
+        let mut s = state.log_.as_mut().unwrap().add_record(&updates.contents());
+        if s.ok() {
+            state.log_records_ += 1;
+            s = updates.insert_into(state.mem_.as_ref().unwrap());
+            self.note_memtable_write(state);
+        }
+        if s.ok() {
+            state.versions_.set_last_sequence(first_sequence + updates.count() as u64 - 1);
+        }
+        s
     }
-}
 
-fn sanitize_options(dbname: &str, icmp: &InternalKeyComparator, ipolicy: Option<Rc<dyn FilterPolicy>>, src: &Options) -> Options {
+    /// Remember when mem_ got its first write, for DB::health().
+    /// REQUIRES: "state" is mutex_'s
+    fn note_memtable_write(&self, state: &mut DbState) {
+        if state.mem_first_write_micros_.is_none() {
+            state.mem_first_write_micros_ = Some(self.env_.now_micros());
+        }
+    }
+
+    /// Return the sequence number of the last update applied to the
+    /// database, whether written locally or replicated.
+    pub fn last_applied_sequence(&self) -> SequenceNumber {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        state.versions_.last_sequence()
+    }
+
+    /// If the database contains an entry for "key" returns the
+    /// corresponding value.
+    /// 
+    /// If there is no entry for "key" returns a status for which
+    /// Status::is_not_found() returns true.
+    /// 
+    /// May return some other Status on an error.
+    pub fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Vec<u8>, Status> {
+        self.get_with_sequence(options, key).0
+    }
+
+    /// Like get(), but also return the sequence number the read saw: it
+    /// reflects every write up to that sequence and none after it.  For
+    /// a read at ReadTier::PersistedAndImmutable, this bounds how stale
+    /// the result may be.
+    pub fn get_with_sequence(&self, options: &ReadOptions, key: &Slice) -> (Result<Vec<u8>, Status>, SequenceNumber) {
+        self.get_impl(options, key, false)
+    }
+
+    /// Check whether "key" may be in the database without reading from
+    /// disk: only the memtables, and the filters and indexes of tables
+    /// that are already open, are consulted.  Returns false only if the
+    /// key is definitely absent, and the value too if it was found in
+    /// memory.
+    pub fn key_may_exist(&self, options: &ReadOptions, key: &Slice) -> (bool, Option<Vec<u8>>) {
+        match self.get_impl(options, key, true).0 {
+            Ok(value) => (true, Some(value)),
+            Err(s) if s.is_not_found() => (false, None),
+            Err(_) => (true, None),     // Needs a read to tell, or failed
+        }
+    }
+
+    /// Look "key" up as get_with_sequence() does.  With "no_io", gives
+    /// up with an Incomplete status where a file would have to be read.
+    fn get_impl(&self, options: &ReadOptions, key: &Slice, no_io: bool) -> (Result<Vec<u8>, Status>, SequenceNumber) {
+        if let Err(s) = self.check_open().and_then(|_| Self::check_snapshot(options)) {
+            return (Err(s), 0);
+        }
+        let (snapshot, mem, imm, current, verify) = {
+            let mut state = self.mutex_.lock().expect("failed to acquire lock");
+            let (snapshot, mem) = self.read_view(&state, options);
+            let verify = !no_io && !options.verify_checksums && Self::sample_checksum_verification(&mut state);
+            (snapshot, mem, state.imm_.clone(), state.versions_.current(), verify)
+        };
+        let sampled;
+        let options = if verify {
+            sampled = ReadOptions { verify_checksums: true, ..options.clone() };
+            &sampled
+        } else {
+            options
+        };
+
+        // First look in the memtable, then in the immutable memtable (if any).
+        let lkey = LookupKey::new(key, snapshot);
+        for table in [mem, imm].into_iter().flatten() {
+            let result = match table.get(&lkey) {
+                (Some(value), _, true) => Ok(value),
+                (_, Some(s), true) => Err(s),   // Deleted
+                _ => continue,
+            };
+            if !no_io {
+                self.record_get(GetSample { memtable_hit: true, ..Default::default() });
+            }
+            return (result, snapshot);
+        }
+        let mut stats = GetStats::new();
+        let result = current.get(options, &lkey, no_io, &mut stats);
+        if no_io {
+            return (result, snapshot);
+        }
+        if stats.seek_file_level >= 0 {
+            let mut state = self.mutex_.lock().expect("failed to acquire lock");
+            if current.update_stats(&stats) && self.maybe_schedule_compaction(&mut state) {
+                drop(state);
+                self.schedule_background_call();
+            }
+        }
+        self.record_get(GetSample { files_probed: stats.files_probed, blocks_read: stats.blocks_read, memtable_hit: false,
+                                    probe_misses: stats.probe_misses, miss_blocks_read: stats.miss_blocks_read });
+        (result, snapshot)
+    }
 
-    todo!()
+    /// Add the cost of a get to read_amp_, and warn if the gets in the
+    /// window, this one included, probe too many level-0 files.  Gets
+    /// made after level-0 was compacted do not warn, even while older
+    /// ones keep the average up.
+    fn record_get(&self, sample: GetSample) {
+        self.stats_counters_.record_get(sample.memtable_hit, sample.blocks_read as u64);
+        if self.options_.lookup_statistics {
+            self.stats_counters_.record_lookup(&sample);
+        }
+        let threshold = self.options_.read_amp_warning_l0_files;
+        let mut window = self.read_amp_.lock().unwrap();
+        window.record(sample);
+        let l0_average = window.l0_average();
+        if window.is_full() && l0_average > threshold as f64 && sample.files_probed[0] as usize > threshold &&
+            window.warning_due(self.env_.now_micros(), self.options_.read_amp_warning_interval_secs) {
+            log(self.options_.info_log.clone(), &format!(
+                "Read amplification: the last {} gets probed {:.1} level-0 files on average (warning above {}); \
+                 consider a lower l0_slowdown_writes_trigger or a larger write_buffer_size, \
+                 or compact level-0 with compact_range()",
+                self.options_.read_amp_window, l0_average, threshold));
+        }
+    }
+
+    /// Estimate how many data block reads a bloom filter with
+    /// "bits_per_key" would save each get, from the gets made since the
+    /// DB was opened.  Needs Options::lookup_statistics; without it, or
+    /// before any get, the estimate is of no saving.
+    pub fn estimate_bloom_benefit(&self, bits_per_key: usize) -> BloomBenefit {
+        self.stats_counters_.reads().lookups.estimate_bloom_benefit(bits_per_key)
+    }
+
+    /// Report what the recent gets cost: the files of each level they
+    /// probed and the blocks they read, averaged over the last
+    /// Options::read_amp_window gets.
+    pub fn read_amplification_report(&self) -> ReadAmpReport {
+        self.read_amp_.lock().unwrap().report()
+    }
+
+    /// Whether a read should verify checksums although its ReadOptions
+    /// do not ask it to, so that checksum_verification_sample_rate of
+    /// the reads do.  Spreads the sampled reads out evenly.
+    /// REQUIRES: mutex_ is held
+    fn sample_checksum_verification(state: &mut DbState) -> bool {
+        let rate = state.mutable_options_.checksum_verification_sample_rate;
+        if rate <= 0.0 {
+            return false;
+        }
+        state.checksum_sample_credit_ += rate;
+        if state.checksum_sample_credit_ < 1.0 {
+            return false;
+        }
+        state.checksum_sample_credit_ -= 1.0;
+        true
+    }
+
+    /// Return the sequence number a read with "options" sees, and the
+    /// memtable it looks at besides imm_, if any.
+    /// REQUIRES: mutex_ is held
+    fn read_view(&self, state: &DbState, options: &ReadOptions) -> (SequenceNumber, Option<Arc<MemTable>>) {
+        let sequence = match &options.snapshot {
+            Some(snapshot) => snapshot.sequence_number(),
+            None => state.versions_.last_sequence(),
+        };
+        match options.read_tier {
+            ReadTier::Default => (sequence, state.mem_.clone()),
+            ReadTier::PersistedAndImmutable => (sequence.min(state.switch_sequence_), None),
+        }
+    }
+
+    /// Like read_view(), but return every memtable the read looks at,
+    /// newest first.
+    /// REQUIRES: mutex_ is held
+    fn read_memtables(&self, state: &DbState, options: &ReadOptions) -> (SequenceNumber, Vec<Arc<MemTable>>) {
+        let (sequence, mem) = self.read_view(state, options);
+        (sequence, mem.into_iter().chain(state.imm_.clone()).collect())
+    }
+
+    /// Describe the superseded versions kept because of
+    /// Options::keep_old_versions, oldest first.
+    pub fn list_retained_versions(&self) -> Vec<RetainedVersion> {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        state.versions_.retained_versions()
+    }
+
+    /// Like get(), but read the database as it was when the retained
+    /// version "version_id" was replaced: its files, plus the memtable
+    /// entries written up to its last sequence.  options.snapshot is
+    /// ignored.
+    pub fn get_at_version(&self, version_id: u64, options: &ReadOptions, key: &Slice) -> Result<Vec<u8>, Status> {
+        let retained = self.find_retained_version(version_id)?;
+        let lkey = LookupKey::new(key, retained.last_sequence);
+        for table in retained.mems {
+            match table.get(&lkey) {
+                (Some(value), _, true) => { return Ok(value); },
+                (_, Some(s), true) => { return Err(s); },   // Deleted
+                _ => {},
+            }
+        }
+        retained.version.get(options, &lkey, false, &mut GetStats::new())
+    }
+
+    /// Like new_iterator(), but iterate over the database as it was when
+    /// the retained version "version_id" was replaced.  options.snapshot
+    /// is ignored.
+    pub fn new_iterator_at_version(&self, version_id: u64, options: &ReadOptions) -> Result<DbIterator<'_>, Status> {
+        let retained = self.find_retained_version(version_id)?;
+        let mut list: Vec<Box<dyn Iterator>> = retained.mems.iter().map(|mem| mem.new_iterator()).collect();
+        retained.version.add_iterators(options, &mut list);
+        let internal_iter = new_internal_merging_iterator(&self.internal_comparator_, list);
+        let tombstones = retained.mems.iter().map(|mem| mem.range_tombstones().clone()).collect();
+        let mut db_iter = DBIter::new(self.internal_comparator_.user_comparator(), internal_iter, retained.last_sequence, options.deadline.is_some(), tombstones);
+        db_iter.set_version_pin(retained.version);
+        Ok(DbIterator::new(Box::new(db_iter), &self.live_iterators_))
+    }
+
+    fn find_retained_version(&self, version_id: u64) -> Result<Retained, Status> {
+        self.check_open()?;
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let versions = &state.versions_;
+        match versions.retained_version(version_id) {
+            Some(r) => Ok(r.clone()),
+            None => Err(Status::invalid_argument("no retained version", &version_id.to_string())),
+        }
+    }
+
+    /// Return an iterator over the contents of the database.
+    /// The result of new_iterator() is initially invalid (caller must
+    /// call one of the seek methods on the iterator before using it).
+    /// The iterator borrows the DB; see new_owned_iterator() for one that
+    /// does not.
+    pub fn new_iterator(&self, options: &ReadOptions) -> DbIterator<'_> {
+        self.new_iterator_with_sequence(options).0
+    }
+
+    /// Like new_iterator(), but also return the sequence number the
+    /// iterator sees; see get_with_sequence().
+    pub fn new_iterator_with_sequence(&self, options: &ReadOptions) -> (DbIterator<'_>, SequenceNumber) {
+        let (iter, sequence) = self.new_bounded_iterator(options, None);
+        (DbIterator::new(iter, &self.live_iterators_), sequence)
+    }
+
+    /// Returns the number of iterators over the DB that are alive, owned
+    /// ones included.
+    pub fn live_iterators(&self) -> usize {
+        self.live_iterators_.load(atomic::Ordering::Acquire)
+    }
+
+    /// Return an iterator over the user keys deleted as of
+    /// options.snapshot (or of this call, without one) whose deletion
+    /// still hides older data: keys deleted in memtables or files above
+    /// an older value that has not been compacted away yet.  Keys whose
+    /// deletion and values have all been dropped by compactions are not
+    /// listed.  Range deletions still in memtables are listed by the
+    /// iterator's range_tombstones().  Like new_iterator(), the result is
+    /// initially invalid.
+    pub fn new_tombstone_iterator(&self, options: &ReadOptions) -> Result<DbIterator<'_, TombstoneIter>, Status> {
+        self.check_open()?;
+        Self::check_snapshot(options)?;
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let (iter, sequence) = self.build_internal_iterator(&state, options);
+        let tombstones = self.read_memtables(&state, options).1.iter().map(|mem| mem.range_tombstones().clone()).collect();
+        drop(state);
+        let iter = TombstoneIter::new(self.internal_comparator_.user_comparator(), iter, sequence, tombstones);
+        Ok(DbIterator::new(Box::new(iter), &self.live_iterators_))
+    }
+
+    /// Like new_iterator_with_sequence(), but if "upper_bound" is set, the
+    /// iterator stops before the first key at or past it without looking
+    /// at the entries beyond, as it does at options.iterate_upper_bound.
+    fn new_bounded_iterator(&self, options: &ReadOptions, upper_bound: Option<&[u8]>) -> (Box<dyn Iterator>, SequenceNumber) {
+        let ucmp = self.internal_comparator_.user_comparator();
+        let upper_bound = match (upper_bound, options.iterate_upper_bound.as_deref()) {
+            (Some(a), Some(b)) if ucmp.compare(&Slice::new(b), &Slice::new(a)) == Ordering::Less => Some(b),
+            (a, b) => a.or(b),
+        };
+        if let Err(s) = self.check_open().and_then(|_| Self::check_snapshot(options)) {
+            return (new_error_iterator(s), 0);
+        }
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        let sampled;
+        let options = if !options.verify_checksums && Self::sample_checksum_verification(&mut state) {
+            sampled = ReadOptions { verify_checksums: true, ..options.clone() };
+            &sampled
+        } else {
+            options
+        };
+        // Read samples of earlier iterators may have marked a file for
+        // compaction.
+        let schedule = state.versions_.current().file_to_compact().is_some() && self.maybe_schedule_compaction(&mut state);
+        let pool_key = self.iter_pool_.key_for(options);
+        let (mut iter, sequence) = match pool_key.as_ref().and_then(|key| self.iter_pool_.take(key)) {
+            Some(mut iter) => {
+                let (sequence, _) = self.read_view(&state, options);
+                iter.reset(sequence);
+                (iter, sequence)
+            },
+            None => {
+                let (iter, sequence) = self.build_internal_iterator(&state, options);
+                let tombstones = self.read_memtables(&state, options).1.iter().map(|mem| mem.range_tombstones().clone()).collect();
+                let mut db_iter = DBIter::new(self.internal_comparator_.user_comparator(), iter, sequence, options.deadline.is_some(), tombstones);
+                state.read_sampling_seed_ = state.read_sampling_seed_.wrapping_add(1);
+                db_iter.set_read_sampling(state.versions_.current(), state.read_sampling_seed_);
+                db_iter.set_version_pin(state.versions_.current());
+                if self.options_.lookup_statistics {
+                    db_iter.set_seek_statistics(self.stats_counters_.clone());
+                }
+                (Box::new(db_iter), sequence)
+            },
+        };
+        drop(state);
+        if schedule {
+            self.schedule_background_call();
+        }
+        if let Some(bound) = upper_bound {
+            iter.set_upper_bound(bound);
+        }
+        self.stats_counters_.record_iterator();
+        match pool_key {
+            Some(key) => (self.iter_pool_.wrap(key, iter), sequence),
+            None => (iter, sequence),
+        }
+    }
+
+    /// Return copies of at most "limit" entries with keys in [start,end),
+    /// in key order, as of options.snapshot (or of this call, without
+    /// one).  Entries past "end" are not looked at, so a scan does not
+    /// read on into the tables beyond it.  Fails with the iterator's
+    /// error if iteration fails part way.
+    pub fn scan(&self, options: &ReadOptions, start: &Slice, end: &Slice, limit: usize) -> Result<Vec<KeyValue>, Status> {
+        let mut result = Vec::new();
+        if limit == 0 {
+            return Ok(result);
+        }
+        let (mut iter, _) = self.new_bounded_iterator(options, Some(end.data()));
+        iter.seek(start);
+        while iter.valid() && result.len() < limit {
+            result.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+            iter.next();
+        }
+        let s = iter.status();
+        if !s.ok() {
+            return Err(s);
+        }
+        Ok(result)
+    }
+
+    /// Return copies of at most "limit" entries in key order, starting at
+    /// "start" (or at the first key, without one), and the key to start
+    /// the next page at, or None after the last entry.  Each page reads as
+    /// of options.snapshot, or of its own call without one, in which case
+    /// later pages see the writes made after earlier ones.
+    pub fn scan_page(&self, options: &ReadOptions, start: Option<&[u8]>, limit: usize) -> Result<(Vec<KeyValue>, Option<Vec<u8>>), Status> {
+        let mut iter = self.new_iterator(options);
+        match start {
+            Some(start) => iter.seek(&Slice::new(start)),
+            None => iter.seek_to_first(),
+        }
+        let mut result = Vec::new();
+        while iter.valid() && result.len() < limit {
+            result.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+            iter.next();
+        }
+        let next = iter.valid().then(|| iter.key().data().to_vec());
+        let s = iter.status();
+        if !s.ok() {
+            return Err(s);
+        }
+        Ok((result, next))
+    }
+
+    /// Return copies of the entries whose keys fall in "range", in key
+    /// order, as of options.snapshot (or of this call, without one).
+    /// E.g. db.range(&options, &b"a"[..]..&b"c"[..])
+    pub fn range<R: RangeBounds<[u8]>>(&self, options: &ReadOptions, range: R) -> RangeIter<'_> {
+        RangeIter::new(self.new_iterator(options), self.internal_comparator_.user_comparator(), range, false)
+    }
+
+    /// Like range(), but in reverse key order: starts at the largest key
+    /// inside the upper bound and stops at the lower bound.
+    pub fn range_rev<R: RangeBounds<[u8]>>(&self, options: &ReadOptions, range: R) -> RangeIter<'_> {
+        RangeIter::new(self.new_iterator(options), self.internal_comparator_.user_comparator(), range, true)
+    }
+
+    /// Return copies of the entries whose keys start with "prefix", in
+    /// key order.  REQUIRES: keys sharing a prefix are ordered together,
+    /// as they are by the bytewise comparator.
+    pub fn prefix(&self, options: &ReadOptions, prefix: &[u8]) -> RangeIter<'_> {
+        let successor = prefix_successor(prefix);
+        self.range(options, (Bound::Included(prefix), successor.as_deref().map_or(Bound::Unbounded, Bound::Excluded)))
+    }
+
+    /// Like prefix(), but in reverse key order.
+    pub fn prefix_rev(&self, options: &ReadOptions, prefix: &[u8]) -> RangeIter<'_> {
+        let successor = prefix_successor(prefix);
+        self.range_rev(options, (Bound::Included(prefix), successor.as_deref().map_or(Bound::Unbounded, Bound::Excluded)))
+    }
+
+    /// Return an iterator positioned at the first entry whose key starts
+    /// with "prefix", with its iterate_upper_bound set past the last
+    /// such key: moving forward stops at the end of the prefix,
+    /// seek_to_last() starts from there, and the tables past it are never
+    /// opened.  There is no lower bound, so prev(), seek_to_first() and
+    /// seek_to_last() may move before the prefix.
+    /// REQUIRES: as for prefix()
+    pub fn prefix_iterator(&self, options: &ReadOptions, prefix: &[u8]) -> DbIterator<'_> {
+        let mut options = options.clone();
+        if let Some(successor) = prefix_successor(prefix) {
+            let ucmp = self.internal_comparator_.user_comparator();
+            if options.iterate_upper_bound.as_ref().is_none_or(|b| ucmp.compare(&Slice::new(&successor), &Slice::new(b)) == Ordering::Less) {
+                options.iterate_upper_bound = Some(successor);
+            }
+        }
+        let mut iter = self.new_iterator(&options);
+        iter.seek(&Slice::new(prefix));
+        iter
+    }
+
+    /// Return a handle to the current DB state.  Iterators created with
+    /// this handle will all observe a stable snapshot of the current DB
+    /// state.  The caller must call release_snapshot(result) when the
+    /// snapshot is no longer needed.
+    pub fn get_snapshot(&self) -> Arc<Snapshot> {
+        self.get_snapshot_with(&GetSnapshotOptions::default())
+    }
+
+    /// Like get_snapshot(), but "options" says what happens once the
+    /// snapshot is older than Options::max_snapshot_age_seconds.
+    pub fn get_snapshot_with(&self, options: &GetSnapshotOptions) -> Arc<Snapshot> {
+        let now = self.env_.now_micros();
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        let last_sequence = state.versions_.last_sequence();
+        state.snapshots_.new_snapshot(last_sequence, now, options.on_expiry)
+    }
+
+    /// Release a previously acquired snapshot.  The caller must not
+    /// use "snapshot" after this call.
+    pub fn release_snapshot(&self, snapshot: Arc<Snapshot>) {
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        state.snapshots_.delete(&snapshot);
+    }
+
+    /// DB implementations can export properties about their state
+    /// via this method.  If "property" is a valid property understood by this
+    /// DB implementation, returns its current value.  Otherwise returns None.
+    ///
+    /// Valid property names include:
+    ///
+    ///  "leveldb.num-files-at-level<N>" - return the number of files at level <N>,
+    ///     where <N> is an ASCII representation of a level number (e.g. "0").
+    ///  "leveldb.filter-coverage" - return how many of the live tables (and
+    ///     bytes) carry a filter usable with the configured filter policy,
+    ///     and how many lookups had to bypass an unusable one.
+    ///  "leveldb.stats" - returns a multi-line string with the number and
+    ///     size of the files at each level, the time spent and bytes read
+    ///     and written by the compactions into each level (see
+    ///     compaction_stats()), whether writes are currently
+    ///     delayed ("slowdown") or stopped ("stop") because of too many
+    ///     level-0 files, and how many writes have been delayed or stopped
+    ///     so far.
+    ///  "leveldb.value-size-histogram" - return, for each level, the number
+    ///     and total size of its values in log2 size buckets.  Tables
+    ///     written before value sizes were recorded are counted separately
+    ///     and left out of the histogram.
+    ///  "leveldb.wal-recovery-dropped-records" - return the number of log
+    ///     records DB::open() skipped or discarded as damaged, or because
+    ///     they came after a damaged one (see Options::wal_recovery_mode).
+    ///  "leveldb.recovery-flushes" - return the number of level-0 tables
+    ///     DB::open() wrote while replaying logs: one for each time the
+    ///     memtable outgrew the write buffer, so that replaying a long log
+    ///     never holds more than about a write buffer in memory, and one
+    ///     for what was left at the end of each log that was not reused.
+    ///  "leveldb.possible-unlogged-data-loss" - return "true" if DB::open()
+    ///     found that writes made with WriteOptions::disable_wal had not
+    ///     been flushed when the DB last shut down, so they may be lost,
+    ///     and "false" otherwise.
+    ///  "leveldb.pinned-bytes" - return the total size of the table files
+    ///     that are no longer current but are kept by open iterators or
+    ///     retained versions (see Options::keep_old_versions).
+    ///  "leveldb.approximate-memory-usage" - return the approximate number
+    ///     of bytes of memory in use by the DB (see approximate_memory_usage()).
+    ///  "leveldb.space-amp" - return the size of the table files, the
+    ///     estimated size of the live data in them, and the size of obsolete
+    ///     files not deleted yet (see space_amp_report()).
+    ///  "leveldb.snapshots" - return the number of live snapshots, how many
+    ///     of them have expired or are stale but kept (see
+    ///     Options::max_snapshot_age_seconds), the oldest sequence number
+    ///     a snapshot keeps compactions from dropping, and the age of each
+    ///     snapshot, oldest first.
+    ///  "leveldb.block-cache-stats" - return the capacity, usage, hits and
+    ///     misses of the block cache, one line per partition if it is
+    ///     split between groups of levels (see
+    ///     Options::block_cache_level_partitions).
+    ///  "leveldb.lookup-efficiency" - return how many gets were answered
+    ///     from the memtable, how many table probes found nothing and the
+    ///     data blocks those misses read, how many seeks opened more than
+    ///     one table, and histograms of the data blocks read and level-0
+    ///     files probed per get (see estimate_bloom_benefit()).
+    ///
+    /// property_names() lists them all.
+    pub fn get_property(&self, property: &str) -> Option<String> {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let versions = &state.versions_;
+        let rest = property.strip_prefix("leveldb.")?;
+        if let Some(level) = rest.strip_prefix("num-files-at-level") {
+            match level.parse::<i32>() {
+                Ok(level) if (0..NUM_LEVELS).contains(&level) => Some(versions.num_level_files(level).to_string()),
+                _ => None,
+            }
+        } else if rest == "stats" {
+            let current = versions.current();
+            let mut value = String::from("                               Compactions\n\
+                                          Level  Files Size(MB) Time(sec) Read(MB) Write(MB)\n\
+                                          --------------------------------------------------\n");
+            let stats = &state.stats_;
+            for level in 0..NUM_LEVELS {
+                let files = current.files(level);
+                let stats = &stats[level as usize];
+                if stats.micros > 0 || !files.is_empty() {
+                    let bytes: u64 = files.iter().map(|f| f.file_size).sum();
+                    value.push_str(&format!("{:>3} {:>8} {:>8.0} {:>9.0} {:>8.0} {:>9.0}\n", level, files.len(),
+                                            bytes as f64 / 1048576.0, stats.micros as f64 / 1e6,
+                                            stats.bytes_read as f64 / 1048576.0, stats.bytes_written as f64 / 1048576.0));
+                }
+            }
+            let mutable_options = &state.mutable_options_;
+            let level0_files = versions.num_level_files(0) as i32;
+            let stall = if level0_files >= mutable_options.l0_stop_writes_trigger {
+                "stop"
+            } else if level0_files >= mutable_options.l0_slowdown_writes_trigger {
+                "slowdown"
+            } else {
+                "none"
+            };
+            value.push_str(&format!("write stall: {} (level-0 files: {}, slowdown at {}, stop at {})\n",
+                                    stall, level0_files, mutable_options.l0_slowdown_writes_trigger,
+                                    mutable_options.l0_stop_writes_trigger));
+            value.push_str(&format!("delayed writes: {}\nstopped writes: {}\n",
+                                    state.delayed_writes_, state.stopped_writes_));
+            Some(value)
+        } else if rest == "value-size-histogram" {
+            let mut value = String::new();
+            for (level, (histogram, tables, unknown)) in self.value_size_histograms(&state).iter().enumerate() {
+                if *tables == 0 {
+                    continue;
+                }
+                value.push_str(&format!("level {}: tables: {} (without histogram: {}), values: {}, bytes: {}\n",
+                                        level, tables, unknown, histogram.num(), histogram.sum()));
+                for bucket in (0..SIZE_HISTOGRAM_BUCKETS).filter(|&b| histogram.count(b) > 0) {
+                    let limit = if bucket + 1 < SIZE_HISTOGRAM_BUCKETS {
+                        SizeHistogram::bucket_start(bucket + 1).to_string()
+                    } else {
+                        "inf".to_string()
+                    };
+                    value.push_str(&format!("  [{}, {}): {} values, {} bytes\n", SizeHistogram::bucket_start(bucket),
+                                            limit, histogram.count(bucket), histogram.bytes(bucket)));
+                }
+            }
+            Some(value)
+        } else if rest == "wal-recovery-dropped-records" {
+            Some(state.wal_recovery_dropped_records_.to_string())
+        } else if rest == "recovery-flushes" {
+            Some(state.recovery_flushes_.to_string())
+        } else if rest == "possible-unlogged-data-loss" {
+            Some(state.possible_unlogged_data_loss_.to_string())
+        } else if rest == "pinned-bytes" {
+            Some(versions.pinned_bytes().to_string())
+        } else if rest == "approximate-memory-usage" {
+            Some(self.memory_usage_with(&self.live_memtables(&state)).to_string())
+        } else if rest == "space-amp" {
+            let report = self.space_amp_report_for(&versions.current(), versions.pinned_bytes());
+            Some(format!("total file bytes: {}\nestimated live bytes: {}\nobsolete file bytes pending deletion: {}\nspace amplification: {:.2}\n",
+                         report.total_file_bytes, report.estimated_live_bytes,
+                         report.obsolete_file_bytes_pending_deletion, report.space_amplification_ratio))
+        } else if rest == "snapshots" {
+            let now = self.env_.now_micros();
+            let snapshots = &state.snapshots_;
+            let expired = snapshots.iter().filter(|s| s.expired()).count();
+            let stale = snapshots.iter().filter(|s| s.stale() && !s.expired()).count();
+            let mut value = format!("snapshots: {} ({} expired, {} stale)\noldest pinning sequence: {}\n",
+                                    snapshots.iter().count(), expired, stale,
+                                    snapshots.oldest_pinning().map_or("none".to_string(), |seq| seq.to_string()));
+            for snapshot in snapshots.iter() {
+                value.push_str(&format!("sequence {}: age {}s", snapshot.sequence_number(),
+                                        now.saturating_sub(snapshot.created_micros()) / 1_000_000));
+                if snapshot.expired() {
+                    value.push_str(", expired");
+                } else if snapshot.stale() {
+                    value.push_str(", stale");
+                }
+                value.push('\n');
+            }
+            Some(value)
+        } else if rest == "block-cache-stats" {
+            let stats = self.options_.block_cache.as_ref().filter(|_| !self.options_.no_block_cache).map(|cache| cache.stats());
+            Some(stats.unwrap_or_default().iter().enumerate().map(|(i, stats)| {
+                format!("partition {}: capacity {}, usage {}, hits {}, misses {}\n", i, stats.capacity, stats.usage, stats.hits, stats.misses)
+            }).collect())
+        } else if rest == "filter-coverage" {
+            let current = versions.current();
+            let (mut tables, mut usable_tables, mut bytes, mut usable_bytes) = (0u64, 0u64, 0u64, 0u64);
+            for level in 0..NUM_LEVELS {
+                for f in current.files(level) {
+                    tables += 1;
+                    bytes += f.file_size;
+                    // A table that cannot be opened has no usable filter
+                    if self.table_cache_.find_table(&ReadOptions::new(), f.number, f.file_size).is_ok_and(|t| t.filter_usable()) {
+                        usable_tables += 1;
+                        usable_bytes += f.file_size;
+                    }
+                }
+            }
+            let percent = |part: u64, total: u64| if total == 0 { 100.0 } else { part as f64 * 100.0 / total as f64 };
+            Some(format!("tables: {}/{} ({:.1}%)\nbytes: {}/{} ({:.1}%)\nbypassed lookups: {}\n",
+                         usable_tables, tables, percent(usable_tables, tables),
+                         usable_bytes, bytes, percent(usable_bytes, bytes),
+                         self.table_cache_.filter_bypasses()))
+        } else if rest == "lookup-efficiency" {
+            let lookups = self.stats_counters_.reads().lookups;
+            let percent = |part: u64, total: u64| if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 };
+            Some(format!("gets: {} (memtable hits: {:.1}%)\n\
+                          table probes: {} (missed: {}, blocks read by misses: {})\n\
+                          seeks: {} (more than one table: {:.1}%)\n\
+                          data blocks per get: {}\
+                          level-0 files per get: {}",
+                         lookups.gets, percent(lookups.memtable_hits, lookups.gets),
+                         lookups.table_probes, lookups.probe_misses, lookups.miss_blocks_read,
+                         lookups.seeks, percent(lookups.multi_table_seeks, lookups.seeks),
+                         lookups.blocks_per_get.render(), lookups.level0_files_per_get.render()))
+        } else {
+            None
+        }
+    }
+
+    /// Returns, for each level, the totals of the memtable flushes and
+    /// compactions that produced data for that level since the DB was
+    /// opened.
+    pub fn compaction_stats(&self) -> Vec<CompactionStats> {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        state.stats_.clone()
+    }
+
+    /// Return the stats epoch and all the counters of the DB.  The epoch
+    /// moves on only if some group of counters changed since the last
+    /// snapshot, so an agent polling an idle DB sees the same epoch
+    /// again.  With "reset_histograms", the histograms returned cover the
+    /// interval since the last reset, and a new interval starts.
+    pub fn stats_snapshot(&self, reset_histograms: bool) -> (u64, DbStats) {
+        let epoch = self.advance_stats_epoch();
+        let stats = DbStats {
+            instance_name: self.options_.instance_name.clone(),
+            writes: self.stats_counters_.writes(reset_histograms),
+            reads: self.stats_counters_.reads(),
+            levels: self.level_stats(),
+            table_cache: self.table_cache_stats(),
+        };
+        (epoch, stats)
+    }
+
+    /// Return the stats epoch and the counters of only the groups that
+    /// changed after "since_epoch", e.g. the epoch of an earlier
+    /// snapshot.  Histograms are not reset.
+    pub fn stats_delta(&self, since_epoch: u64) -> (u64, StatsDelta) {
+        let epoch = self.advance_stats_epoch();
+        let delta = self.stats_counters_.changed_since(since_epoch).into_iter().map(|group| {
+            let stats = match group {
+                StatsGroup::Writes => GroupStats::Writes(self.stats_counters_.writes(false)),
+                StatsGroup::Reads => GroupStats::Reads(self.stats_counters_.reads()),
+                StatsGroup::Compaction => GroupStats::Compaction(self.level_stats()),
+                StatsGroup::TableCache => GroupStats::TableCache(self.table_cache_stats()),
+            };
+            (group, stats)
+        }).collect();
+        (epoch, delta)
+    }
+
+    fn advance_stats_epoch(&self) -> u64 {
+        // The table cache keeps its own flag, so that opening a table
+        // does not need to know about stats_counters_.
+        if self.table_cache_.take_changed() {
+            self.stats_counters_.mark(StatsGroup::TableCache);
+        }
+        self.stats_counters_.advance()
+    }
+
+    fn level_stats(&self) -> LevelStats {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let current = state.versions_.current();
+        let levels = 0..NUM_LEVELS;
+        LevelStats {
+            compactions: state.stats_.clone(),
+            files: levels.clone().map(|level| current.files(level).len()).collect(),
+            bytes: levels.map(|level| current.files(level).iter().map(|f| f.file_size).sum()).collect(),
+        }
+    }
+
+    fn table_cache_stats(&self) -> TableCacheStats {
+        TableCacheStats {
+            opens: self.table_cache_.opens(),
+            filter_bypasses: self.table_cache_.filter_bypasses(),
+            filter_cache_hits: self.table_cache_.filter_cache_counters().hits(),
+            filter_cache_misses: self.table_cache_.filter_cache_counters().misses(),
+            filter_cache_loads: self.table_cache_.filter_cache_counters().loads(),
+            capacity: self.table_cache_.capacity(),
+            shrinks: self.table_cache_.shrinks(),
+        }
+    }
+
+    /// Estimate how many bytes compactions still have to rewrite before
+    /// every level is within its size limit.
+    pub fn estimate_compaction_backlog(&self) -> u64 {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        state.versions_.compaction_backlog_bytes()
+    }
+
+    /// Return the approximate number of bytes of memory in use by the DB:
+    /// the memtables, Options::block_cache and Options::filter_cache (which
+    /// may be shared with other DBs), and the index blocks and filters of
+    /// the open tables.  Takes
+    /// mutex_ only to pick up the memtables, so it is cheap enough to poll
+    /// before admitting work.
+    pub fn approximate_memory_usage(&self) -> usize {
+        let mems = {
+            let state = self.mutex_.lock().expect("failed to acquire lock");
+            self.live_memtables(&state)
+        };
+        self.memory_usage_with(&mems)
+    }
+
+    /// approximate_memory_usage() with "mems" as the memtables.
+    fn memory_usage_with(&self, mems: &[Arc<MemTable>]) -> usize {
+        let memtables: usize = mems.iter().map(|mem| mem.approximate_memory_usage()).sum();
+        let block_cache = self.options_.block_cache.as_ref().map_or(0, |cache| cache.total_charge());
+        let filter_cache = match (&self.options_.filter_cache, &self.options_.block_cache) {
+            (Some(filters), Some(blocks)) if Arc::ptr_eq(filters, blocks) => 0,
+            (filters, _) => filters.as_ref().map_or(0, |cache| cache.total_charge()),
+        };
+        memtables + block_cache + filter_cache + self.table_cache_.approximate_memory_usage()
+    }
+
+    /// Returns the handle on the delays this DB puts on writes while
+    /// compactions fall behind, for callers that want to pace their
+    /// writes themselves.  Writes through the DB wait in its acquire()
+    /// whether or not the caller does; see write_controller.rs.
+    pub fn write_controller(&self) -> Arc<WriteController> {
+        self.write_controller_.clone()
+    }
+
+    /// Summarize whether the database keeps up with its writes.  Cheap
+    /// enough to call from a readiness probe: it does no IO, and only
+    /// allocates to copy a background error.
+    pub fn health(&self) -> DbHealth {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        self.current_health(&state)
+    }
+
+    /// REQUIRES: mutex_ is held
+    fn current_health(&self, state: &DbState) -> DbHealth {
+        let versions = &state.versions_;
+        let mutable_options = &state.mutable_options_;
+        let bg_error = &state.bg_error_;
+        let l0_files = versions.num_level_files(0);
+        let compaction_debt_bytes = versions.compaction_backlog_bytes();
+        let imm_pending = state.imm_.is_some();
+        let mem_full = state.mem_.as_ref().is_some_and(|mem| mem.approximate_memory_usage() > mutable_options.write_buffer_size);
+
+        let health = if !bg_error.ok() {
+            HealthState::Failed
+        } else if l0_files as i32 >= mutable_options.l0_stop_writes_trigger || (imm_pending && mem_full) {
+            HealthState::Stalled
+        } else if l0_files as i32 >= mutable_options.l0_slowdown_writes_trigger || compaction_debt_bytes > 0 {
+            HealthState::Degraded
+        } else {
+            HealthState::Healthy
+        };
+        let oldest_write = state.imm_first_write_micros_.or(state.mem_first_write_micros_);
+        DbHealth {
+            state: health,
+            background_error: if bg_error.ok() { None } else { Some(bg_error.to_string()) },
+            compaction_debt_bytes,
+            l0_files,
+            imm_pending,
+            oldest_unflushed_age_secs: oldest_write.map(|micros| self.env_.now_micros().saturating_sub(micros) / 1_000_000),
+            stalled_writers: state.writers_.len().saturating_sub(1),
+        }
+    }
+
+    /// Update the health list_instances() reports for this DB, and the
+    /// delay write_controller() puts on writes.
+    /// REQUIRES: mutex_ is held
+    fn publish_health(&self, state: &DbState) {
+        let mutable_options = &state.mutable_options_;
+        let imm_bytes = state.imm_.as_ref().map_or(0, |imm| imm.approximate_memory_usage() as u64);
+        self.write_controller_.update(state.versions_.compaction_backlog_bytes() + imm_bytes, state.versions_.num_level_files(0) as i32,
+                                      mutable_options.l0_slowdown_writes_trigger, mutable_options.l0_stop_writes_trigger);
+        if let Some(instance) = self.instance_.get() {
+            instance.set_health(self.current_health(state));
+        }
+    }
+
+    /// Suggest a value size above which values are worth handling
+    /// separately, from the value sizes recorded in the live tables: the
+    /// smallest size above which values hold more than half of the value
+    /// bytes but make up less than 5% of the values.  Values still in the
+    /// memtable and tables without recorded sizes are not considered.
+    pub fn advise_value_threshold(&self) -> ValueThresholdAdvice {
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let mut histogram = SizeHistogram::new();
+        let mut unknown = 0;
+        for (level_histogram, _, level_unknown) in self.value_size_histograms(&state) {
+            histogram.merge(&level_histogram);
+            unknown += level_unknown;
+        }
+        ValueThresholdAdvice::from_histogram(&histogram, unknown)
+    }
+
+    /// Return, per level, the value sizes recorded in its tables, the
+    /// number of tables, and how many of them recorded no sizes (or could
+    /// not be opened).
+    /// REQUIRES: mutex_ is held
+    fn value_size_histograms(&self, state: &DbState) -> Vec<(SizeHistogram, u64, u64)> {
+        let current = state.versions_.current();
+        (0..NUM_LEVELS).map(|level| {
+            let mut histogram = SizeHistogram::new();
+            let mut unknown = 0;
+            for f in current.files(level) {
+                match self.table_cache_.find_table(&ReadOptions::new(), f.number, f.file_size) {
+                    Ok(table) if table.properties().is_some() => histogram.merge(&table.properties().unwrap().value_sizes),
+                    _ => unknown += 1,
+                }
+            }
+            (histogram, current.files(level).len() as u64, unknown)
+        }).collect()
+    }
+
+    /// Change options of the running DB.  Only the following names are
+    /// supported: write_buffer_size, l0_slowdown_writes_trigger,
+    /// l0_stop_writes_trigger, compaction_rate_limit_bytes_per_sec,
+    /// max_background_error_retries, tombstone_compaction_ratio and
+    /// checksum_verification_sample_rate.  Values are validated with the
+    /// same rules that apply when the DB is opened, except that values
+    /// out of range are rejected rather than clamped.  A change applies
+    /// to the reads, writes and compactions that start after it, and to
+    /// the output a running compaction writes from then on.
+    /// 
+    /// The change is all-or-nothing: if any entry is unknown or invalid,
+    /// a non-OK status naming it is returned and no option is changed.
+    pub fn set_options(&self, changes: &[(&str, &str)]) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        let ratio = state.mutable_options_.tombstone_compaction_ratio;
+        match state.mutable_options_.apply(changes) {
+            Ok(applied) => {
+                for (name, old, new) in applied {
+                    log(self.options_.info_log.clone(), &format!("SetOptions: {} changed from {} to {}", name, old, new));
+                }
+                self.compaction_rate_limiter_.set_bytes_per_second(state.mutable_options_.compaction_rate_limit_bytes_per_sec);
+                self.publish_health(&state);
+                if state.mutable_options_.tombstone_compaction_ratio != ratio {
+                    // Tables passed over before may qualify now.
+                    state.tombstone_scanned_ = Weak::new();
+                }
+                if self.maybe_schedule_compaction(&mut state) {
+                    drop(state);
+                    self.schedule_background_call();
+                }
+                Status::new_ok()
+            },
+            Err(s) => {
+                log(self.options_.info_log.clone(), &format!("SetOptions failed: {}", s.to_string()));
+                s
+            },
+        }
+    }
+
+    /// Write the memtable out to a level-0 table, so that every write made
+    /// so far is in a table file (e.g. before backing those up).  Returns
+    /// the background error instead if there is one.  Does nothing if the
+    /// memtable is empty.
+    pub fn flush(&self) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.read_only_ {
+            return Status::not_supported("flush", "database is opened read-only");
+        }
+        let (state, w) = self.begin_exclusive_write();
+        let (mut state, mut s) = self.wait_for_imm_flush(state);
+        let mut schedule = false;
+        if s.ok() {
+            let mut iter = state.mem_.as_ref().unwrap().new_iterator();
+            iter.seek_to_first();
+            if iter.valid() {
+                s = self.switch_memtable(&mut state);
+                if s.ok() {
+                    s = self.compact_mem_table(&mut state, false);
+                    if !s.ok() {
+                        self.record_background_error(&mut state, &s);
+                    }
+                }
+                schedule = s.ok() && self.maybe_schedule_compaction(&mut state);
+            }
+        }
+        self.end_exclusive_write(&mut state, &w);
+        drop(state);
+        if schedule {
+            self.schedule_background_call();
+        }
+        s
+    }
+
+    /// Wait for the background work to write imm_ out, if there is one.
+    /// Fails with the background error once one has been recorded.
+    /// Returns mutex_'s guard again, as the wait releases it.
+    fn wait_for_imm_flush<'a>(&'a self, mut state: MutexGuard<'a, DbState>) -> (MutexGuard<'a, DbState>, Status) {
+        loop {
+            if !state.bg_error_.ok() {
+                let s = state.bg_error_.clone();
+                return (state, s);
+            }
+            if state.imm_.is_none() {
+                return (state, Status::new_ok());
+            }
+            state = self.wait_for_background_work(state);
+        }
+    }
+
+    /// Compact the underlying storage for the key range [*begin,*end].
+    /// In particular, deleted and overwritten versions are discarded,
+    /// and the data is rearranged to reduce the cost of operations
+    /// needed to access the data.  This operation should typically only
+    /// be invoked by users who understand the underlying implementation.
+    /// 
+    /// begin==None is treated as a key before all keys in the database.
+    /// end==None is treated as a key after all keys in the database.
+    /// Therefore the following call will compact the entire database:
+    ///    db.compact_range(None, None);
+    pub fn compact_range(&self, begin: Option<&Slice>, end: Option<&Slice>) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.read_only_ {
+            return Status::not_supported("compact_range", "database is opened read-only");
+        }
+        let (state, w) = self.begin_exclusive_write();
+        let (mut state, mut s) = self.wait_for_imm_flush(state);
+        let mut max_level_with_files = 1;
+        {
+            let base = state.versions_.current();
+            for level in 1..NUM_LEVELS {
+                if base.overlap_in_level(level, begin, end) {
+                    max_level_with_files = level;
+                }
+            }
+        }
+        if s.ok() && self.mem_overlaps_range(&state, begin, end) {
+            s = self.flush_memtable(&mut state);
+        }
+        for level in 0..max_level_with_files {
+            if !s.ok() {
+                break;
+            }
+            (state, s) = self.compact_level_range(state, level, begin, end);
+        }
+        self.end_exclusive_write(&mut state, &w);
+        s
+    }
+
+    /// Rewrite the tables overlapping [*begin,*end] whose filter can not
+    /// be used with the configured filter policy, because it was built by
+    /// another policy or the table has none.  Bounds are treated as in
+    /// compact_range().
+    /// 
+    /// Tables are rewritten by compacting them into the next level, so
+    /// tables in the last level are left as they are.  Returns an
+    /// InvalidArgument status if no filter policy is configured.
+    pub fn rebuild_filters(&self, begin: Option<&Slice>, end: Option<&Slice>) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.read_only_ {
+            return Status::not_supported("rebuild_filters", "database is opened read-only");
+        }
+        if self.internal_filter_policy_.is_none() {
+            return Status::invalid_argument("no filter policy configured", "");
+        }
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        // Every round compacts away at least the file that was found, and
+        // the outputs all carry usable filters.
+        while let Some((level, f)) = self.find_file_without_filter(&state, begin, end) {
+            let s;
+            (state, s) = self.compact_level_range(state, level, Some(&f.smallest.user_key()), Some(&f.largest.user_key()));
+            if !s.ok() {
+                return s;
+            }
+        }
+        Status::new_ok()
+    }
+
+    /// Merge the memtables that options.read_tier allows and the current
+    /// version into one iterator over internal keys.  Also returns the
+    /// sequence number the read sees.
+    /// REQUIRES: mutex_ is held
+    fn build_internal_iterator(&self, state: &DbState, options: &ReadOptions) -> (Box<dyn Iterator>, SequenceNumber) {
+        let (sequence, mems) = self.read_memtables(state, options);
+
+        // Collect together all needed child iterators
+        let mut list: Vec<Box<dyn Iterator>> = mems.iter().map(|mem| mem.new_iterator()).collect();
+        state.versions_.current().add_iterators(options, &mut list);
+        let internal_iter = new_internal_merging_iterator(&self.internal_comparator_, list);
+        (internal_iter, sequence)
+    }
+
+    fn new_memtable(&self) -> MemTable {
+        MemTable::with_prefix_compression(&self.internal_comparator_, self.options_.memtable_prefix_compression)
+    }
+
+    /// A DB opened "read_only" does not create a LOG file.
+    fn new(raw_options: &Options, dbname: &str, read_only: bool, self_: Weak<DbInner>) -> Self {
+        let icmp = InternalKeyComparator::new(raw_options.comparator.clone());
+        let ipolicy = raw_options.filter_policy.clone().map(|p| Arc::new(InternalFilterPolicy::new(p)) as Arc<dyn FilterPolicy>);
+        let options = sanitize_options(dbname, &icmp, ipolicy.clone(), raw_options, !read_only);
+        let table_cache = Arc::new(TableCache::new(dbname, &options));
+        let state = DbState {
+            db_lock_: None,
+            mem_: None,
+            imm_: None,
+            logfile_: None,
+            logfile_number_: 0,
+            log_: None,
+            log_records_: 0,
+            switch_sequence_: 0,
+            mem_first_write_micros_: None,
+            imm_first_write_micros_: None,
+            mem_unlogged_: false,
+            imm_unlogged_: false,
+            writers_: VecDeque::new(),
+            snapshots_: SnapshotList::new(),
+            pending_outputs_: BTreeSet::new(),
+            versions_: VersionSet::new(dbname, &options, &table_cache, &icmp),
+            mutable_options_: MutableOptions::new(&options),
+            idempotency_tokens_: TokenWindow::new(raw_options.idempotency_window),
+            background_compaction_scheduled_: false,
+            manual_compaction_: None,
+            bg_error_: Status::new_ok(),
+            background_error_retries_: 0,
+            delayed_writes_: 0,
+            wal_recovery_dropped_records_: 0,
+            recovery_flushes_: 0,
+            possible_unlogged_data_loss_: false,
+            stopped_writes_: 0,
+            stats_: vec![CompactionStats::default(); NUM_LEVELS as usize],
+            read_sampling_seed_: 0,
+            tombstone_scanned_: Weak::new(),
+            checksum_sample_credit_: 0.0,
+            prepared_: BTreeMap::new(),
+            resolved_: Vec::new(),
+        };
+        Self {
+            self_,
+            read_only_: read_only,
+            closed_: AtomicBool::new(false),
+            close_deadline_: AtomicU64::new(u64::MAX),
+            shutting_down_: AtomicBool::new(false),
+            has_imm_: AtomicBool::new(false),
+            env_: raw_options.env.clone(),
+            internal_comparator_: icmp.clone(),
+            internal_filter_policy_: ipolicy,
+            dbname_: dbname.to_string(),
+            mutex_: Mutex::new(state),
+            background_work_finished_signal_: Condvar::new(),
+            table_cache_: table_cache,
+            range_locks_: Arc::new(RangeLockTable::new(raw_options.comparator.clone())),
+            read_amp_: Mutex::new(ReadAmpWindow::new(raw_options.read_amp_window)),
+            write_timing_: Mutex::new(WriteTimingWindow::new(WRITE_TIMING_WINDOW)),
+            stats_counters_: Arc::new(StatsCounters::new()),
+            instance_: OnceLock::new(),
+            iter_pool_: IterPool::new(raw_options.iterator_pool_size),
+            compaction_rate_limiter_: RateLimiter::new(options.compaction_rate_limit_bytes_per_sec),
+            live_iterators_: AtomicUsize::new(0),
+            write_controller_: Arc::new(WriteController::new(options.env.clone(), options.write_delay_start, options.write_delay_full)),
+            options_: options,
+        }
+    }
+
+    /// A handle on this DB's state for background work to hold while it
+    /// runs on another thread.
+    fn background_handle(&self) -> Arc<DbInner> {
+        self.self_.upgrade().expect("DB state is gone")
+    }
+
+    fn new_db(&self) -> Status {
+        let mut new_db = VersionEdit::new();
+        new_db.set_comparator_name(self.internal_comparator_.user_comparator().name());
+        new_db.set_log_number(0);
+        new_db.set_next_file(2);
+        new_db.set_last_sequence(0);
+
+        let manifest = descriptor_file_name(&self.dbname_, 1);
+        let mut s = Status::new_ok();
+        match self.env_.new_writable_file(&manifest) {
+            Ok(file) => {
+                let mut log = Writer::new(file.clone());
+                let mut record = Vec::new();
+                new_db.encode_to(&mut record);
+                s = log.add_record(&Slice::new(&record));
+                if s.ok() {
+                    s = file.sync();
+                }
+                if s.ok() {
+                    s = file.close();
+                }
+            },
+            Err(s) => { return s; },
+        }
+        if s.ok() {
+            // Make "CURRENT" file that points to the new manifest file.
+            s = set_current_file(self.env_.clone(), &self.dbname_, 1);
+        } else {
+            self.env_.remove_file(&manifest);
+        }
+        s
+    }
+
+    /// The mutex should be acquired before calling it.
+    fn recover(&self, state: &mut DbState, edit: &mut VersionEdit, save_manifest: &mut bool) -> Status {
+        // Ignore error from CreateDir since the creation of the DB is
+        // committed only when the descriptor is created, and this directory
+        // may already exist from a previous failed creation attempt.
+        debug_assert!(state.db_lock_.is_none());
+        if !self.read_only_ {
+            let _ = self.env_.create_dir(&self.dbname_);
+            match self.env_.lock_file(&lock_file_name(&self.dbname_)) {
+                Ok(f) => { state.db_lock_.replace(f); },
+                Err(s) => { return s; },
+            };
+        }
+
+        if !self.env_.file_exists(&current_file_name(&self.dbname_)) {
+            if self.options_.create_if_missing && !self.read_only_ {
+                log(self.options_.info_log.clone(), &format!("Creating DB {} since it was missing.", &self.dbname_));
+                let s = self.new_db();
+                if !s.ok() {
+                    return s;
+                }
+            } else {
+                return Status::invalid_argument(&self.dbname_, "does not exist (create_if_missing is false)")
+                    .with_subcode(SubCode::NoDatabase).with_filename(&current_file_name(&self.dbname_));
+            }
+        } else if self.options_.error_if_exists {
+            return Status::invalid_argument(&self.dbname_, "exists (error_if_exists is true)")
+                .with_filename(&current_file_name(&self.dbname_));
+        }
+
+        match state.versions_.recover() {
+            Ok(save) => { *save_manifest = save; },
+            Err(s) => { return s; },
+        }
+        if self.read_only_ {
+            // The logs are replayed into mem_, over the tables
+            state.switch_sequence_ = state.versions_.last_sequence();
+        }
+        if state.versions_.unlogged_writes() {
+            log(self.options_.info_log.clone(),
+                "WARNING: writes made with WriteOptions::disable_wal were not flushed before the last shutdown and may be lost");
+            state.possible_unlogged_data_loss_ = true;
+            if !self.read_only_ {
+                edit.set_unlogged_writes(false);
+                *save_manifest = true;
+            }
+        }
+        if let Some(token) = self.options_.fencing_token {
+            let s = self.claim_fence(state, token, edit, save_manifest);
+            if !s.ok() {
+                return s;
+            }
+        }
+
+        // Recover from all newer log files than the ones named in the
+        // descriptor (new log files may have been added by the previous
+        // incarnation without registering them in the descriptor).
+        //
+        // Note that prev_log_number() is no longer used, but we pay
+        // attention to it in case we are recovering a database
+        // produced by an older version of leveldb.
+        let min_log = state.versions_.log_number();
+        let prev_log = state.versions_.prev_log_number();
+        let filenames = match self.env_.get_children(&self.dbname_) {
+            Ok(filenames) => filenames,
+            Err(s) => { return s; },
+        };
+        let mut expected = BTreeSet::new();
+        state.versions_.add_live_files(&mut expected);
+        let mut logs = Vec::new();
+        let mut old_logs = Vec::new();   // Kept only for their idempotency tokens
+        for filename in &filenames {
+            if let Some((number, type_)) = parse_file_name(filename) {
+                expected.remove(&number);
+                if type_ == FileType::LogFile {
+                    if number >= min_log || number == prev_log {
+                        logs.push(number);
+                    } else {
+                        old_logs.push(number);
+                    }
+                }
+            }
+        }
+        if let Some(&missing) = expected.first() {
+            let fname = table_file_name(&self.dbname_, missing);
+            return Status::corruption(&format!("{} missing files; e.g.", expected.len()), &fname).with_filename(&fname);
+        }
+
+        // The previous incarnation may not have written any MANIFEST
+        // records after allocating these log numbers.  So we manually
+        // update the file number allocation counter in VersionSet, before
+        // replaying allocates numbers for new tables.
+        for &log_number in &logs {
+            state.versions_.mark_file_number_used(log_number);
+        }
+
+        // The contents of older logs are already in tables, but the tokens
+        // they hold are still remembered.
+        old_logs.sort();
+        for log_number in old_logs {
+            state.versions_.mark_file_number_used(log_number);
+            self.recover_log_tokens(state, log_number);
+        }
+
+        // Recover in the order in which the logs were generated
+        logs.sort();
+        log(self.options_.info_log.clone(), &format!("Recovering {} log files with wal_recovery_mode {:?}",
+                                                     logs.len(), self.options_.wal_recovery_mode));
+        let mut replay = LogReplay::default();
+        for (i, &log_number) in logs.iter().enumerate() {
+            let last_log = i == logs.len() - 1;
+            let s = self.recover_log_file(state, log_number, last_log, save_manifest, edit, &mut replay);
+            if !s.ok() {
+                return s;
+            }
+        }
+
+        let versions = &mut state.versions_;
+        if versions.last_sequence() < replay.max_sequence {
+            versions.set_last_sequence(replay.max_sequence);
+        }
+
+        self.resolve_prepared_transactions(state, std::mem::take(&mut replay.prepared))
+    }
+
+    /// Replay the log file into a fresh memtable and write its contents
+    /// out to level-0 tables recorded in "edit", flushing whenever the
+    /// memtable grows larger than the write buffer.
+    /// 
+    /// Damaged records are handled as Options::wal_recovery_mode says.
+    /// Sets replay.stop_replay once point-in-time recovery reaches one; from
+    /// then on the records read are discarded, not replayed.
+    /// 
+    /// With options.reuse_logs, the last log is kept as the current one,
+    /// its memtable as mem_, if it could be replayed whole without
+    /// writing a table and left no transaction prepared.
+    ///
+    /// Prepared batches are set aside in replay.prepared until a commit
+    /// or rollback record of their transaction is read.
+    ///
+    /// A read-only DB replays every log into mem_ instead, and writes
+    /// nothing.
+    fn recover_log_file(&self, state: &mut DbState, log_number: u64, last_log: bool, save_manifest: &mut bool, edit: &mut VersionEdit,
+                        replay: &mut LogReplay) -> Status {
+        // Open the log file
+        let fname = log_file_name(&self.dbname_, log_number);
+        let file = match self.env_.new_sequential_file(&fname) {
+            Ok(file) => file,
+            Err(s) => { return s; },
+        };
+
+        // Create the log reader.
+        let reporter = Rc::new(DBLogReporter::new(self.options_.info_log.clone(), fname.clone()));
+        // We intentionally make log::Reader do checksumming even if
+        // paranoid_checks==false so that corruptions cause entire commits
+        // to be skipped instead of propagating bad information (like overly
+        // large sequence numbers).
+        let mut reader = Reader::new(file, Some(reporter.clone()), true, 0);
+        // A record cut short at the end counts as damage too, so that it
+        // is logged and counted, and fails absolute consistency.
+        reader.set_report_eof_inconsistency(true);
+        log(self.options_.info_log.clone(), &format!("Recovering log #{}", log_number));
+
+        // Read all the records and add to a memtable
+        let mode = self.options_.wal_recovery_mode;
+        let mut batch = WriteBatch::new();
+        let mut mem: Option<MemTable> = if self.read_only_ {
+            state.mem_.take().map(|mem| Arc::into_inner(mem).expect("memtable in use during recovery"))
+        } else {
+            None
+        };
+        let mut s = Status::new_ok();
+        let mut reported = 0;
+        let (mut discarded_records, mut discarded_bytes) = (0u64, 0u64);
+        let mut compactions = 0;
+        let mut first_sequence = None;
+        // Write batches read, and whether the last record read was a
+        // trailer that matches them (see DB::drop()).
+        let mut records = 0u64;
+        let mut verified = false;
+        loop {
+            let record = reader.read_record();
+
+            // Deal with the damage found since the last record, before
+            // (maybe) replaying this one.  Paranoid checks let only a
+            // record cut short at the end through.
+            if reporter.records() > reported {
+                let truncated_tail = record.is_none() && reader.truncated_tail() && reporter.records() == reported + 1;
+                reported = reporter.records();
+                match mode {
+                    _ if self.options_.paranoid_checks && !truncated_tail => {
+                        s = reporter.status().annotate(&fname);
+                        break;
+                    },
+                    WalRecoveryMode::AbsoluteConsistency => {
+                        s = reporter.status().annotate(&fname);
+                        break;
+                    },
+                    WalRecoveryMode::PointInTime if !replay.stop_replay => {
+                        log(self.options_.info_log.clone(),
+                            &format!("{}: point-in-time recovery stops here; the rest of the logs is discarded", fname));
+                        replay.stop_replay = true;
+                    },
+                    _ => {},
+                }
+            }
+
+            let Some(record) = record else { break };
+            if replay.stop_replay {
+                discarded_records += 1;
+                discarded_bytes += record.len() as u64;
+                continue;
+            }
+            if record.len() < write_batch::HEADER {
+                reporter.corruption(record.len(), &Status::corruption("log record too small", ""));
+                continue;
+            }
+            batch.set_contents(&Slice::new(&record));
+            match batch.wal_trailer() {
+                Ok(None) => {
+                    records += 1;
+                    verified = false;
+                },
+                Ok(Some(trailer)) => {
+                    let last_sequence = state.versions_.last_sequence().max(replay.max_sequence);
+                    verified = trailer.records == records && trailer.last_sequence == last_sequence;
+                    if !verified {
+                        let msg = format!("trailer says {} records up to sequence {}, log has {} up to {}",
+                                          trailer.records, trailer.last_sequence, records, last_sequence);
+                        reporter.corruption(record.len(), &Status::corruption("WAL trailer mismatch", &msg));
+                    }
+                    continue;
+                },
+                Err(s) => {
+                    reporter.corruption(record.len(), &s);
+                    verified = false;
+                    continue;
+                },
+            }
+
+            match batch.transaction_record() {
+                Some(TransactionRecord::Prepare { txn, participant, participants }) => {
+                    // Applied once the transaction commits
+                    replay.prepared.insert(txn, PreparedBatch { batch: batch.clone(), participant, participants });
+                    continue;
+                },
+                Some(TransactionRecord::Rollback(txn)) => {
+                    replay.prepared.remove(&txn);
+                    continue;
+                },
+                Some(TransactionRecord::Commit(txn)) => {
+                    replay.prepared.remove(&txn);
+                },
+                None => {},
+            }
+
+            let table = mem.get_or_insert_with(|| self.new_memtable());
+            let insert_status = batch.insert_into(table);
+            if !insert_status.ok() {
+                reporter.corruption(record.len(), &insert_status);
+                continue;
+            }
+            if let Some(token) = batch.idempotency_token() {
+                state.idempotency_tokens_.insert(token, batch.sequence(), log_number);
+            }
+            first_sequence.get_or_insert(batch.sequence());
+            let last_seq = batch.sequence() + batch.count() as u64 - 1;
+            if last_seq > replay.max_sequence {
+                replay.max_sequence = last_seq;
+            }
+            #[cfg(test)]
+            REPLAY_PEAK_MEMTABLE.with(|peak| peak.set(peak.get().max(table.approximate_memory_usage())));
+
+            if !self.read_only_ && table.approximate_memory_usage() > self.options_.write_buffer_size {
+                compactions += 1;
+                *save_manifest = true;
+                s = self.write_level0_table(state, &mem.take().unwrap(), edit, None);
+                if !s.ok() {
+                    // Reflect errors immediately so that conditions like full
+                    // file-systems cause the DB::open() to fail.
+                    break;
+                }
+                state.recovery_flushes_ += 1;
+            }
+        }
+
+        if s.ok() && verified {
+            log(self.options_.info_log.clone(), &format!("{}: clean shutdown verified", fname));
+        }
+
+        let dropped_records = reporter.records() + discarded_records;
+        if dropped_records > 0 {
+            log(self.options_.info_log.clone(),
+                &format!("WARNING: {}: recovery with wal_recovery_mode {:?} dropped {} records ({} damaged, {} bytes)",
+                         fname, mode, dropped_records, reporter.records(), reporter.bytes() + discarded_bytes));
+            state.wal_recovery_dropped_records_ += dropped_records;
+        }
+
+        // See if we should keep reusing the last log file.
+        if self.read_only_ {
+            if let Some(mem) = mem.take() {
+                state.mem_ = Some(Arc::new(mem));
+            }
+        } else if s.ok() && self.options_.reuse_logs && last_log && compactions == 0 && dropped_records == 0
+                  && replay.prepared.is_empty() {
+            debug_assert!(state.logfile_.is_none());
+            debug_assert!(state.mem_.is_none());
+            if let (Ok(size), Ok(file)) = (self.env_.get_file_size(&fname), self.env_.new_appendable_file(&fname)) {
+                log(self.options_.info_log.clone(), &format!("Reusing old log {}", fname));
+                state.logfile_ = Some(file.clone());
+                state.log_ = Some(Writer::new2(file, size));
+                state.log_records_ = records;
+                state.logfile_number_ = log_number;
+                // Writes before the ones in this log are all in tables
+                match first_sequence {
+                    Some(first) => {
+                        state.switch_sequence_ = first - 1;
+                        self.note_memtable_write(state);
+                    },
+                    None => state.switch_sequence_ = state.versions_.last_sequence().max(replay.max_sequence),
+                }
+                // mem can be None if the log exists but was empty.
+                let mem = mem.take().unwrap_or_else(|| self.new_memtable());
+                state.mem_ = Some(Arc::new(mem));
+            }
+        }
+
+        if let Some(mem) = mem {
+            if s.ok() {
+                *save_manifest = true;
+                s = self.write_level0_table(state, &mem, edit, None);
+                state.recovery_flushes_ += s.ok() as u64;
+            }
+        }
+        s
+    }
+
+    /// Remember the idempotency tokens of the batches in a log file whose
+    /// contents are already in tables.  Unreadable records are skipped.
+    fn recover_log_tokens(&self, state: &mut DbState, log_number: u64) {
+        let fname = log_file_name(&self.dbname_, log_number);
+        let file = match self.env_.new_sequential_file(&fname) {
+            Ok(file) => file,
+            Err(s) => {
+                log(self.options_.info_log.clone(), &format!("Ignoring log #{}: {}", log_number, s.to_string()));
+                return;
+            },
+        };
+        let reporter = Rc::new(DBLogReporter::new(self.options_.info_log.clone(), fname));
+        let mut reader = Reader::new(file, Some(reporter), true, 0);
+        let mut batch = WriteBatch::new();
+        while let Some(record) = reader.read_record() {
+            if record.len() < write_batch::HEADER {
+                continue;
+            }
+            batch.set_contents(&Slice::new(&record));
+            if let Some(token) = batch.idempotency_token() {
+                state.idempotency_tokens_.insert(token, batch.sequence(), log_number);
+            }
+        }
+    }
+
+    /// Returns true iff the memtable holds an entry or a range tombstone
+    /// for a user key in [*begin,*end].
+    fn mem_overlaps_range(&self, state: &DbState, begin: Option<&Slice>, end: Option<&Slice>) -> bool {
+        let mem = state.mem_.clone().unwrap();
+        if mem.range_tombstones().overlaps(begin.map(|k| k.data()), end.map(|k| k.data())) {
+            return true;
+        }
+        let mut iter = mem.new_iterator();
+        match begin {
+            Some(begin) => iter.seek(&InternalKey::new_from(begin, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK).encode()),
+            None => iter.seek_to_first(),
+        }
+        if !iter.valid() {
+            return false;
+        }
+        let key = iter.key();
+        let ucmp = self.internal_comparator_.user_comparator();
+        end.is_none_or(|end| ucmp.compare(&extract_user_key(key.data()), end) != Ordering::Greater)
+    }
+
+    /// Switch to a new log file and memtable and write the old memtable
+    /// out to a table.  The table goes to level-0 while background work
+    /// is in progress, as a deeper one could overlap the outputs of a
+    /// running compaction.
+    /// REQUIRES: imm_ is empty
+    fn flush_memtable(&self, state: &mut DbState) -> Status {
+        let s = self.switch_memtable(state);
+        if !s.ok() {
+            return s;
+        }
+        let push_down = !state.background_compaction_scheduled_;
+        self.compact_mem_table(state, push_down)
+    }
+
+    /// Mark background work as scheduled if the immutable memtable is
+    /// waiting to be flushed, a manual compaction is waiting to run, the
+    /// current version needs a compaction, or its tables are still to be
+    /// searched for deletion markers.  Returns true iff it was,
+    /// in which case the caller must pass it to schedule_background_call()
+    /// once it has released mutex_: an Env may run the work on the calling
+    /// thread, as the in-memory one does.
+    /// REQUIRES: mutex_ is held
+    #[must_use]
+    fn maybe_schedule_compaction(&self, state: &mut DbState) -> bool {
+        if state.background_compaction_scheduled_ {
+            // Already scheduled
+        } else if self.shutting_down_.load(atomic::Ordering::Acquire) {
+            // DB is being deleted; no more background compactions
+        } else if self.read_only_ {
+            // Compactions would write to the directory
+        } else if !state.bg_error_.ok() {
+            // Already got an error; no more changes
+        } else if state.imm_.is_none() && state.manual_compaction_.as_ref().is_none_or(|m| m.done) &&
+                  !state.versions_.needs_compaction() && !Self::tombstone_scan_due(state) && !state.versions_.housekeeping_due() {
+            // No work to be done
+        } else {
+            state.background_compaction_scheduled_ = true;
+            return true;
+        }
+        false
+    }
+
+    /// Hand background_call() to the Env, for the work that
+    /// maybe_schedule_compaction() marked as scheduled, labelled with
+    /// options_.instance_name so the Env can tell DBs' work apart.
+    /// REQUIRES: mutex_ is not held
+    fn schedule_background_call(&self) {
+        let db = self.background_handle();
+        self.env_.schedule(self.options_.instance_name.as_deref(), Box::new(move || db.background_call()));
+    }
+
+    /// Schedule the background work maybe_schedule_compaction() finds, or
+    /// else wait for the round of it in progress to finish.  Returns
+    /// mutex_'s guard again.
+    /// REQUIRES: "state" is mutex_'s, and background work is scheduled or
+    /// due, so that there is a round to wait for
+    fn wait_for_background_work<'a>(&'a self, mut state: MutexGuard<'a, DbState>) -> MutexGuard<'a, DbState> {
+        if self.maybe_schedule_compaction(&mut state) {
+            drop(state);
+            self.schedule_background_call();
+            self.mutex_.lock().expect("failed to acquire lock")
+        } else {
+            self.background_work_finished_signal_.wait(state).expect("failed to acquire lock")
+        }
+    }
+
+    fn background_call(&self) {
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        debug_assert!(state.background_compaction_scheduled_);
+        if self.shutting_down_.load(atomic::Ordering::Acquire) {
+            // No more background work when shutting down.
+        } else if !state.bg_error_.ok() {
+            // No more background work after a background error.
+        } else {
+            let s;
+            (state, s) = self.background_compaction(state);
+            if !s.ok() && state.bg_error_.ok() && state.background_error_retries_ > 0 {
+                // Give a failure that may pass, such as a full disk, time
+                // to clear before the work is retried.
+                let shift = (state.background_error_retries_ - 1).min(BACKGROUND_ERROR_RETRY_MAX_SHIFT);
+                drop(state);
+                self.env_.sleep_for_microseconds(BACKGROUND_ERROR_RETRY_MICROS << shift);
+                state = self.mutex_.lock().expect("failed to acquire lock");
+            }
+        }
+        state.background_compaction_scheduled_ = false;
+
+        // Previous compaction may have produced too many files in a level,
+        // so reschedule another compaction if needed.
+        let reschedule = self.maybe_schedule_compaction(&mut state);
+        self.background_work_finished_signal_.notify_all();
+        drop(state);
+        if reschedule {
+            self.schedule_background_call();
+        }
+    }
+
+    /// Flush imm_ if there is one, or else run the manual compaction
+    /// waiting in manual_compaction_, or the compaction the current
+    /// version needs, or one for a table with many deletion markers, if
+    /// any.  Returns its status: an Incomplete one if
+    /// close_with_deadline() made it give up, which is not a background
+    /// error.  The failure of a manual compaction is not one either: it
+    /// is left in manual_compaction_ for its caller.  Returns mutex_'s
+    /// guard again, as compactions run with it released.
+    fn background_compaction<'a>(&'a self, mut state: MutexGuard<'a, DbState>) -> (MutexGuard<'a, DbState>, Status) {
+        sync_point!("db:background-compaction:start");
+        if state.imm_.is_some() {
+            let s = self.compact_mem_table(&mut state, true);
+            if s.ok() {
+                state.background_error_retries_ = 0;
+            } else {
+                self.retry_or_record_background_error(&mut state, &s);
+            }
+            return (state, s);
+        }
+
+        let is_manual = state.manual_compaction_.as_ref().is_some_and(|m| !m.done);
+        let mut is_tombstone = false;
+        let mut manual_end = None;
+        let c = if let Some(m) = state.manual_compaction_.as_ref().filter(|_| is_manual) {
+            let (level, begin, end) = (m.level, m.begin.clone(), m.end.clone());
+            let c = state.versions_.compact_range(level, begin.as_ref(), end.as_ref());
+            if let Some(c) = &c {
+                manual_end = Some(c.input(0, c.num_input_files(0) - 1).largest.clone());
+                log(self.options_.info_log.clone(), &format!("Manual compaction at level-{}: {} files", 
+                    level, c.num_input_files(0)));
+            }
+            c
+        } else {
+            state.versions_.pick_compaction().or_else(|| {
+                let c = self.pick_tombstone_compaction(&mut state);
+                is_tombstone = c.is_some();
+                c
+            }).or_else(|| state.versions_.pick_housekeeping_compaction())
+        };
+
+        let mut s = Status::new_ok();
+        match c {
+            None => {
+                // Nothing to do
+            },
+            // Moving the table down would leave its deletion markers as
+            // they are.
+            Some(mut c) if !is_manual && !is_tombstone && c.is_trivial_move() => {
+                // Move file to next level
+                debug_assert!(c.num_input_files(0) == 1);
+                let f = c.input(0, 0).clone();
+                let level = c.level();
+                c.edit().remove_file(level, f.number);
+                c.edit().add_file_with_checksum(level + 1, f.number, f.file_size, &f.smallest, &f.largest, f.file_checksum);
+                let mems = self.live_memtables(&state);
+                s = self.log_and_apply(&mut state, c.edit(), Some(&mems));
+                log(self.options_.info_log.clone(), &format!("Moved #{} to level-{} {} bytes {}", 
+                    f.number, level + 1, f.file_size, s.to_string()));
+            },
+            Some(c) => {
+                let mut compact = CompactionState::new(c);
+                (state, s) = self.do_compaction_work(state, &mut compact);
+                self.cleanup_compaction(&mut state, compact);
+                self.remove_obsolete_files(&mut state);
+            },
+        }
+
+        if s.is_incomplete() {
+            log(self.options_.info_log.clone(), &format!("Compaction abandoned: {}", s.to_string()));
+        } else if s.ok() {
+            // Done
+            state.background_error_retries_ = 0;
+        } else if self.shutting_down_.load(atomic::Ordering::Acquire) {
+            // Ignore compaction errors found during shutting down
+        } else {
+            log(self.options_.info_log.clone(), &format!("Compaction error: {}", s.to_string()));
+            if !is_manual && self.retry_or_record_background_error(&mut state, &s) && is_tombstone {
+                // Look for the table again.
+                state.tombstone_scanned_ = Weak::new();
+            }
+        }
+
+        if is_manual {
+            let m = state.manual_compaction_.as_mut().unwrap();
+            if !s.ok() {
+                m.done = true;
+                m.status = s.clone();
+            }
+            match manual_end {
+                // We only compacted part of the requested range.  Update
+                // m to the range that is left to be compacted.
+                Some(manual_end) if s.ok() => { m.begin = Some(manual_end); },
+                Some(_) => {},
+                None => { m.done = true; },
+            }
+        }
+        (state, s)
+    }
+
+    /// True iff close_with_deadline() is running and its deadline has
+    /// passed.  Reads the clock only while closing.
+    fn past_close_deadline(&self) -> bool {
+        let deadline = self.close_deadline_.load(atomic::Ordering::Acquire);
+        deadline != u64::MAX && self.env_.now_micros() >= deadline
+    }
+
+    /// Remember the first background error; writes fail with it from now on.
+    fn record_background_error(&self, state: &mut DbState, s: &Status) {
+        if state.bg_error_.ok() {
+            state.bg_error_ = s.clone();
+            self.publish_health(state);
+        }
+    }
+
+    /// Record "s", the failure of background work, as the background
+    /// error, unless it is an IO error that may be retried as
+    /// max_background_error_retries allows.  A failed MANIFEST write is
+    /// never retried: the MANIFEST may not be written again.  Returns
+    /// true iff the work is to be retried.
+    fn retry_or_record_background_error(&self, state: &mut DbState, s: &Status) -> bool {
+        let max_retries = state.mutable_options_.max_background_error_retries;
+        if s.is_io_error() && !state.versions_.manifest_failed() && state.background_error_retries_ < max_retries {
+            state.background_error_retries_ += 1;
+            log(self.options_.info_log.clone(), &format!("Background error: {}; retry {} of {}",
+                s.to_string(), state.background_error_retries_, max_retries));
+            return true;
+        }
+        self.record_background_error(state, s);
+        false
+    }
+
+    /// Whether the current version is still to be searched for a table
+    /// with many deletion markers.
+    /// REQUIRES: mutex_ is held
+    fn tombstone_scan_due(state: &DbState) -> bool {
+        state.mutable_options_.tombstone_compaction_ratio > 0.0 &&
+            !state.tombstone_scanned_.upgrade().is_some_and(|v| Arc::ptr_eq(&v, &state.versions_.current()))
+    }
+
+    /// Pick a compaction of the table above the last level with the
+    /// largest share of deletion markers among its entries, if that share
+    /// reaches tombstone_compaction_ratio.  Each version is searched once,
+    /// and tables that do not record their deletion markers are passed
+    /// over.
+    /// REQUIRES: mutex_ is held
+    fn pick_tombstone_compaction(&self, state: &mut DbState) -> Option<Compaction> {
+        if !Self::tombstone_scan_due(state) {
+            return None;
+        }
+        let ratio = state.mutable_options_.tombstone_compaction_ratio;
+        let current = state.versions_.current();
+        state.tombstone_scanned_ = Arc::downgrade(&current);
+        let mut best: Option<(f64, i32, &FileMetaData)> = None;
+        for level in 0..NUM_LEVELS - 1 {
+            for f in current.files(level) {
+                let Ok(table) = self.table_cache_.find_table(&ReadOptions::new(), f.number, f.file_size) else {
+                    continue;
+                };
+                let Some(properties) = table.properties() else {
+                    continue;
+                };
+                let entries = properties.key_sizes.num();
+                let share = properties.num_deletions as f64 / entries.max(1) as f64;
+                if entries > 0 && share >= ratio && best.is_none_or(|(best_share, _, _)| share > best_share) {
+                    best = Some((share, level, f));
+                }
+            }
+        }
+        let (share, level, f) = best?;
+        log(self.options_.info_log.clone(), &format!("Compacting #{} at level-{}: {:.1}% of its entries are deletion markers",
+            f.number, level, share * 100.0));
+        state.versions_.compact_range(level, Some(&f.smallest), Some(&f.largest))
+    }
+
+    /// Fail unless "token" is at least as large as every fencing token the
+    /// DB was opened with before, then record it: in the FENCE file right
+    /// away, so older instances stop at their next check, and in the
+    /// MANIFEST through "edit".
+    /// REQUIRES: mutex_ is held, and versions_ has been recovered
+    fn claim_fence(&self, state: &mut DbState, token: u64, edit: &mut VersionEdit, save_manifest: &mut bool) -> Status {
+        let recorded = state.versions_.fencing_token();
+        let in_file = match read_fence_file(self.env_.clone(), &self.dbname_) {
+            Ok(in_file) => in_file,
+            Err(s) => { return s; },
+        };
+        let fenced = recorded.max(in_file);
+        if token < fenced {
+            return Status::invalid_argument(&format!("fencing token {} is older than {}", token, fenced),
+                                            "the database was opened by a newer instance");
+        }
+        if token > recorded {
+            edit.set_fencing_token(token);
+            *save_manifest = true;
+        }
+        if token > in_file {
+            log(self.options_.info_log.clone(), &format!("Fencing off instances with tokens below {}", token));
+            return set_fence_file(self.env_.clone(), &self.dbname_, token);
+        }
+        Status::new_ok()
+    }
+
+    /// With options.fencing_token set, fail once another instance has
+    /// opened the DB with a larger token, and record the failure as the
+    /// background error so that every later write fails too.
+    /// REQUIRES: mutex_ is held
+    fn check_fence(&self, state: &mut DbState) -> Status {
+        let Some(token) = self.options_.fencing_token else {
+            return Status::new_ok();
+        };
+        match read_fence_file(self.env_.clone(), &self.dbname_) {
+            Ok(fenced) if fenced > token => {
+                let s = Status::invalid_argument(&format!("fenced off by fencing token {}", fenced),
+                                                 &format!("this instance has {}", token));
+                log(self.options_.info_log.clone(), &format!("Stopping writes: {}", s.to_string()));
+                self.record_background_error(state, &s);
+                s
+            },
+            Ok(_) => Status::new_ok(),
+            Err(s) => s,
+        }
+    }
+
+    /// Apply "edit" to versions_ as VersionSet::log_and_apply() does,
+    /// unless the DB has been fenced off.
+    /// REQUIRES: mutex_ is held
+    fn log_and_apply(&self, state: &mut DbState, edit: &mut VersionEdit, live_mems: Option<&[Arc<MemTable>]>) -> Status {
+        let s = self.check_fence(state);
+        if !s.ok() {
+            return s;
+        }
+        let s = state.versions_.log_and_apply(edit, live_mems);
+        if state.versions_.manifest_failed() {
+            // The edit may or may not be in the MANIFEST, so the state on
+            // disk is unknown until the DB is reopened.
+            self.record_background_error(state, &s);
+        }
+        self.stats_counters_.mark(StatsGroup::Compaction);
+        // Also covers imm_, which is only dropped once its table is in
+        // the version.
+        self.iter_pool_.invalidate();
+        self.publish_health(state);
+        s
+    }
+
+    /// Start a new log file and memtable, retiring the current memtable
+    /// to imm_.
+    /// REQUIRES: imm_ is empty
+    fn switch_memtable(&self, state: &mut DbState) -> Status {
+        debug_assert!(state.imm_.is_none());
+        let s = self.check_fence(state);
+        if !s.ok() {
+            return s;
+        }
+        let new_log_number = state.versions_.new_file_number();
+        let file = match self.env_.new_writable_file(&log_file_name(&self.dbname_, new_log_number)) {
+            Ok(file) => file,
+            Err(s) => {
+                // Avoid chewing through file number space in a tight loop.
+                state.versions_.reuse_file_number(new_log_number);
+                return s;
+            },
+        };
+        if let Some(old) = state.logfile_.replace(file.clone()) {
+            // Everything in the old log is in the memtable about to be
+            // written out, so a failed close loses nothing.
+            let s = old.close();
+            if !s.ok() {
+                log(self.options_.info_log.clone(), &format!("Closing log #{}: {}", state.logfile_number_, s.to_string()));
+            }
+        }
+        state.logfile_number_ = new_log_number;
+        state.log_ = Some(self.new_log_writer(state, file));
+        state.log_records_ = 0;
+        state.imm_ = state.mem_.replace(Arc::new(self.new_memtable()));
+        self.has_imm_.store(true, atomic::Ordering::Release);
+        self.iter_pool_.invalidate();
+        state.switch_sequence_ = state.versions_.last_sequence();
+        state.imm_first_write_micros_ = state.mem_first_write_micros_.take();
+        state.imm_unlogged_ = std::mem::take(&mut state.mem_unlogged_);
+        self.publish_health(state);
+        Status::new_ok()
+    }
+
+    /// Wrap a new log file in a Writer that preallocates room for about
+    /// a memtable's worth of records.
+    fn new_log_writer(&self, state: &DbState, file: Arc<dyn WritableFile>) -> Writer {
+        let write_buffer_size = state.mutable_options_.write_buffer_size as u64;
+        Writer::with_preallocation(file, write_buffer_size, self.options_.preallocation_block_size as u64)
+    }
+
+    /// The memtables, newest first.
+    fn live_memtables(&self, state: &DbState) -> Vec<Arc<MemTable>> {
+        [state.mem_.clone(), state.imm_.clone()].into_iter().flatten().collect()
+    }
+
+    /// Write the immutable memtable out to a table, after which the logs
+    /// older than the current one are no longer needed.  The table goes
+    /// to level-0, or deeper if "push_down" and nothing overlaps it.
+    fn compact_mem_table(&self, state: &mut DbState, push_down: bool) -> Status {
+        let imm = state.imm_.clone().expect("no immutable memtable");
+
+        // Save the contents of the memtable as a new Table
+        let mut edit = VersionEdit::new();
+        let base = state.versions_.current();
+        let mut s = self.write_level0_table(state, &imm, &mut edit, if push_down { Some(&base) } else { None });
+        sync_point!("db:flush:after-build", s);
+
+        // Replace immutable memtable with the generated Table
+        if s.ok() {
+            edit.set_prev_log_number(0);
+            // Earlier logs no longer needed, but for those of unreleased
+            // prepared transactions
+            edit.set_log_number(Self::oldest_needed_log(state));
+            if state.imm_unlogged_ && !state.mem_unlogged_ {
+                // The unlogged writes are all in tables now
+                edit.set_unlogged_writes(false);
+            }
+            s = self.log_and_apply(state, &mut edit, Some(&self.live_memtables(state)));
+        }
+        if s.ok() {
+            // Commit to the new state
+            state.imm_ = None;
+            self.has_imm_.store(false, atomic::Ordering::Release);
+            state.imm_first_write_micros_ = None;
+            state.imm_unlogged_ = false;
+            self.remove_obsolete_files(state);
+            self.publish_health(state);
+        }
+        s
+    }
+
+    /// Write imm_ out from a compaction running with mutex_ released, if
+    /// it is still there.  The table goes to level-0: deeper, it could
+    /// overlap the outputs of the compaction.  Returns the status of the
+    /// flush.
+    fn compact_mem_table_during_compaction(&self) -> Status {
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        let mut s = Status::new_ok();
+        if state.imm_.is_some() {
+            s = self.compact_mem_table(&mut state, false);
+            if s.ok() {
+                state.background_error_retries_ = 0;
+            } else {
+                self.retry_or_record_background_error(&mut state, &s);
+            }
+            // Wake up make_room_for_write() if necessary.
+            self.background_work_finished_signal_.notify_all();
+        }
+        s
+    }
+
+    /// Build a table from the contents of "mem" and add it to "edit".  The
+    /// table goes to the level picked by "base", or to level-0 if no base
+    /// version is given.
+    fn write_level0_table(&self, state: &mut DbState, mem: &MemTable, edit: &mut VersionEdit, base: Option<&Version>) -> Status {
+        let start_micros = self.env_.now_micros();
+        let mut meta = FileMetaData::new();
+        meta.number = state.versions_.new_file_number();
+        state.pending_outputs_.insert(meta.number);
+        let mut iter = new_flush_iterator(&self.internal_comparator_, mem, || {
+            let mut options = ReadOptions::new();
+            options.fill_cache = false;
+            let mut older = Vec::new();
+            state.versions_.current().add_iterators(&options, &mut older);
+            // During recovery, the tables written from earlier logs are
+            // only in "edit" so far.
+            older.extend(edit.new_files_.iter().map(|(level, f)| self.table_cache_.new_iterator(&options, Some(*level), f.number, f.file_size)));
+            older
+        });
+        log(self.options_.info_log.clone(), &format!("Level-0 table #{}: started", meta.number));
+
+        let s = build_table(&self.dbname_, &self.env_, &self.options_, &self.table_cache_, iter.as_mut(), &mut meta);
+
+        log(self.options_.info_log.clone(), &format!("Level-0 table #{}: {} bytes {}", meta.number, meta.file_size, s.to_string()));
+        state.pending_outputs_.remove(&meta.number);
+
+        // Note that if file_size is zero, the file has been deleted and
+        // should not be added to the manifest.
+        let mut level = 0;
+        if s.ok() && meta.file_size > 0 {
+            if let Some(base) = base {
+                level = base.pick_level_for_mem_table_output(&self.options_, &meta.smallest.user_key(), &meta.largest.user_key());
+            }
+            edit.add_file_with_checksum(level, meta.number, meta.file_size, &meta.smallest, &meta.largest, meta.file_checksum);
+        }
+
+        let stats = CompactionStats {
+            micros: self.env_.now_micros().saturating_sub(start_micros),
+            bytes_read: 0,
+            bytes_written: meta.file_size,
+            subcompactions: 0,
+            blocks_copied: 0,
+            housekeeping_compactions: 0,
+        };
+        state.stats_[level as usize].add(&stats);
+        self.stats_counters_.mark(StatsGroup::Compaction);
+        s
+    }
+
+    /// Compact the files of "level" that overlap [*begin,*end] into the
+    /// next level, one manual compaction at a time, until none is left.
+    /// The compactions run as background work, after any manual
+    /// compaction requested before; this waits for them.  Returns mutex_'s
+    /// guard again, as the wait releases it.
+    fn compact_level_range<'a>(&'a self, mut state: MutexGuard<'a, DbState>, level: i32, begin: Option<&Slice>, end: Option<&Slice>) -> (MutexGuard<'a, DbState>, Status) {
+        debug_assert!(level >= 0);
+        debug_assert!(level + 1 < NUM_LEVELS);
+        while state.manual_compaction_.is_some() {
+            state = self.background_work_finished_signal_.wait(state).expect("failed to acquire lock");
+        }
+        state.manual_compaction_ = Some(ManualCompaction {
+            level,
+            done: false,
+            begin: begin.map(|k| InternalKey::new_from(k, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK)),
+            end: end.map(|k| InternalKey::new_from(k, 0, ValueType::type_deletion())),
+            status: Status::new_ok(),
+        });
+        // Once there is a background error, no more background work is
+        // scheduled: stop waiting when the round in progress is done.
+        while !state.manual_compaction_.as_ref().unwrap().done &&
+              (state.bg_error_.ok() || state.background_compaction_scheduled_) {
+            state = self.wait_for_background_work(state);
+        }
+        let manual = state.manual_compaction_.take().unwrap();
+        self.background_work_finished_signal_.notify_all();
+        let s = if !manual.status.ok() || state.bg_error_.ok() { manual.status } else { state.bg_error_.clone() };
+        (state, s)
+    }
+
+    /// Mark the snapshots older than Options::max_snapshot_age_seconds as
+    /// stale, which expires the ones taken with SnapshotExpiry::Error.
+    /// Runs before each compaction, as that is when an expired snapshot
+    /// stops costing space.
+    /// REQUIRES: mutex_ is held
+    fn mark_stale_snapshots(&self, state: &DbState, now_micros: u64) {
+        let cutoff = now_micros.saturating_sub(self.options_.max_snapshot_age_seconds.saturating_mul(1_000_000));
+        for snapshot in state.snapshots_.mark_stale(cutoff) {
+            let age = (now_micros - snapshot.created_micros()) / 1_000_000;
+            log(self.options_.info_log.clone(), &format!("Snapshot at sequence {} is {}s old; {}", snapshot.sequence_number(), age,
+                match snapshot.on_expiry() {
+                    SnapshotExpiry::Error => "expiring it",
+                    SnapshotExpiry::Ignore => "keeping it",
+                }));
+        }
+    }
+
+    /// Run the compaction in "compact" and install its outputs.  The
+    /// merge runs with mutex_ released; returns mutex_'s guard again.
+    fn do_compaction_work<'a>(&'a self, state: MutexGuard<'a, DbState>, compact: &mut CompactionState) -> (MutexGuard<'a, DbState>, Status) {
+        let start_micros = self.env_.now_micros();
+        let c = &compact.compaction;
+        log(self.options_.info_log.clone(), &format!("Compacting {}@{} + {}@{} files", 
+            c.num_input_files(0), c.level(), c.num_input_files(1), c.output_level()));
+
+        debug_assert!(state.versions_.num_level_files(c.level()) > 0);
+        debug_assert!(compact.builder.is_none());
+        debug_assert!(compact.outfile.is_none());
+        if self.options_.max_snapshot_age_seconds > 0 {
+            self.mark_stale_snapshots(&state, start_micros);
+        }
+        compact.smallest_snapshot = state.snapshots_.oldest_pinning().unwrap_or_else(|| state.versions_.last_sequence());
+
+        let split_keys = if self.options_.max_subcompactions > 1 &&
+                            compact.compaction.input_bytes() >= self.options_.subcompaction_threshold_bytes {
+            compact.compaction.split_keys(self.options_.max_subcompactions)
+        } else {
+            Vec::new()
+        };
+        let mut subcompactions = Vec::new();
+        let mut input = None;
+        if split_keys.is_empty() {
+            input = Some(state.versions_.make_input_iterator(&compact.compaction));
+        } else {
+            subcompactions = (0..=split_keys.len()).map(|_| {
+                let mut sub = CompactionState::new(compact.compaction.new_subcompaction());
+                sub.smallest_snapshot = compact.smallest_snapshot;
+                let input = state.versions_.make_input_iterator(&sub.compaction);
+                (sub, input)
+            }).collect();
+        }
+
+        // Release mutex while we're actually doing the compaction work
+        drop(state);
+
+        let mut s = match input {
+            Some(mut input) => {
+                input.seek_to_first();
+                self.compact_subrange(compact, input, None)
+            },
+            None => self.run_subcompactions(compact, subcompactions, &split_keys),
+        };
+
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        let c = &compact.compaction;
+        let stats = CompactionStats {
+            micros: self.env_.now_micros().saturating_sub(start_micros),
+            bytes_read: c.input_bytes(),
+            bytes_written: compact.outputs.iter().map(|out| out.file_size).sum(),
+            subcompactions: if split_keys.is_empty() { 0 } else { split_keys.len() as u64 + 1 },
+            blocks_copied: compact.blocks_copied,
+            housekeeping_compactions: c.is_housekeeping().into(),
+        };
+        state.stats_[c.output_level() as usize].add(&stats);
+        self.stats_counters_.mark(StatsGroup::Compaction);
+
+        sync_point!("compaction:before-install", s);
+        if s.ok() {
+            s = self.install_compaction_results(&mut state, compact);
+        }
+        (state, s)
+    }
+
+    /// Run the compaction in "compact" as "subcompactions", the states and
+    /// inputs of the subranges between "split_keys", and gather their
+    /// outputs into "compact".  Helpers scheduled on the Env and this
+    /// thread each take the next subrange left, so the subranges all get
+    /// run even if the Env runs the helpers late, or one at a time.
+    /// Fails if any of them fails or panics, and then starts no more.
+    /// REQUIRES: mutex_ is not held
+    fn run_subcompactions(&self, compact: &mut CompactionState, subcompactions: Vec<(CompactionState, Box<dyn Iterator>)>, split_keys: &[Vec<u8>]) -> Status {
+        log(self.options_.info_log.clone(), &format!("Compacting {} bytes in {} subcompactions",
+            compact.compaction.input_bytes(), split_keys.len() + 1));
+        let bounds: Vec<InternalKey> = split_keys.iter()
+            .map(|k| InternalKey::new_from(&Slice::new(k), MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK))
+            .collect();
+        let helpers = subcompactions.len() - 1;
+        let queue = Arc::new(SubcompactionQueue::default());
+        queue.state.lock().unwrap().pending = subcompactions.into_iter().enumerate().map(|(i, (state, input))| SubcompactionTask {
+            index: i,
+            state,
+            input,
+            begin: i.checked_sub(1).map(|b| bounds[b].clone()),
+            end: bounds.get(i).cloned(),
+        }).collect();
+
+        // Each helper gets a label of its own, so an Env that runs a label's
+        // work in order can still run the helpers side by side.
+        for i in 1..=helpers {
+            let label = match &self.options_.instance_name {
+                Some(name) => format!("{}-subcompaction-{}", name, i),
+                None => format!("subcompaction-{}", i),
+            };
+            let (db, queue) = (self.background_handle(), queue.clone());
+            self.env_.schedule(Some(&label), Box::new(move || { db.run_subcompaction(&queue); }));
+        }
+        while self.run_subcompaction(&queue) {}
+        let mut done = {
+            let mut state = queue.state.lock().unwrap();
+            while state.running > 0 {
+                state = queue.cv.wait(state).unwrap();
+            }
+            std::mem::take(&mut state.done)
+        };
+
+        done.sort_by_key(|(i, _, _)| *i);
+        let mut s = Status::new_ok();
+        for (_, mut sub, sub_s) in done {
+            if let Some(mut builder) = sub.builder.take() {
+                builder.abandon();
+            }
+            compact.outputs.append(&mut sub.outputs);
+            compact.total_bytes += sub.total_bytes;
+            compact.blocks_copied += sub.blocks_copied;
+            if s.ok() {
+                s = sub_s;
+            }
+        }
+        s
+    }
+
+    /// Run the next subrange left in "queue", unless a subrange has
+    /// failed.  Returns false if there was none to run.
+    fn run_subcompaction(&self, queue: &SubcompactionQueue) -> bool {
+        let task = {
+            let mut state = queue.state.lock().unwrap();
+            if state.failed {
+                return false;
+            }
+            let Some(task) = state.pending.pop_front() else {
+                return false;
+            };
+            state.running += 1;
+            task
+        };
+        let SubcompactionTask { index, state: mut sub, mut input, begin, end } = task;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            match &begin {
+                Some(begin) => input.seek(&begin.encode()),
+                None => input.seek_to_first(),
+            }
+            self.compact_subrange(&mut sub, input, end.as_ref())
+        }));
+        let s = result.unwrap_or_else(|payload| {
+            let message = payload.downcast_ref::<&str>().map(|m| m.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Status::corruption("subcompaction panicked", &message)
+        });
+
+        let mut state = queue.state.lock().unwrap();
+        state.running -= 1;
+        state.failed |= !s.ok();
+        state.done.push((index, sub, s));
+        queue.cv.notify_all();
+        true
+    }
+
+    /// Merge the entries "input" yields before "end" (all of them if None)
+    /// into output files of "compact", dropping the ones no snapshot can
+    /// see.  Flushes imm_ first whenever one is waiting, so that writers
+    /// are not held up for the whole compaction.
+    /// REQUIRES: mutex_ is not held
+    fn compact_subrange(&self, compact: &mut CompactionState, mut input: Box<dyn Iterator>, end: Option<&InternalKey>) -> Status {
+        let ucmp = self.internal_comparator_.user_comparator();
+        let copy_blocks = self.can_copy_blocks();
+        let mut s = Status::new_ok();
+        let mut current_user_key: Option<Vec<u8>> = None;
+        let mut last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+        let mut flush_imm = true;
+        while input.valid() {
+            if self.past_close_deadline() {
+                s = Status::incomplete("compaction abandoned", "close deadline passed");
+                break;
+            }
+            if self.shutting_down_.load(atomic::Ordering::Acquire) {
+                s = Status::io_error("Deleting DB during compaction", "");
+                break;
+            }
+            // Prioritize immutable compaction work.  A flush that failed
+            // is left to be retried by the background work.
+            if flush_imm && self.has_imm_.load(atomic::Ordering::Relaxed) {
+                flush_imm = self.compact_mem_table_during_compaction().ok();
+            }
+            self.pace_compaction_output(compact);
+            let key = input.key();
+            if end.is_some_and(|end| self.internal_comparator_.compare(&key, &end.encode()) != Ordering::Less) {
+                break;
+            }
+            if compact.compaction.should_stop_before(&key) && compact.builder.is_some() {
+                s = self.finish_compaction_output_file(compact, input.as_ref());
+                if !s.ok() {
+                    break;
+                }
+            }
+
+            // Copy the data blocks the loop below would pass through
+            // unchanged into the output as they are.
+            let raw = if copy_blocks { input.raw_block() } else { None };
+            if let Some((raw, (first, last))) = raw.and_then(|raw| {
+                let bounds = self.copyable_block_bounds(compact, &raw, current_user_key.as_deref(), end)?;
+                Some((raw, bounds))
+            }) {
+                if compact.builder.is_none() {
+                    s = self.open_compaction_output_file(compact);
+                    if !s.ok() {
+                        break;
+                    }
+                }
+                let first_entry = compact.builder.as_ref().unwrap().num_entries() == 0;
+                let output = compact.outputs.last_mut().unwrap();
+                if first_entry {
+                    output.smallest = InternalKey::decode_from(&Slice::new(&first));
+                }
+                output.largest = InternalKey::decode_from(&Slice::new(&last));
+                let builder = compact.builder.as_mut().unwrap();
+                builder.add_raw_block(&raw);
+                compact.blocks_copied += 1;
+                let ikey = parse_internal_key(&Slice::new(&last)).unwrap();
+                current_user_key = Some(ikey.user_key.data().to_vec());
+                last_sequence_for_key = ikey.sequence;
+
+                if builder.file_size() >= compact.compaction.max_output_file_size() || !builder.status().ok() {
+                    s = self.finish_compaction_output_file(compact, input.as_ref());
+                    if !s.ok() {
+                        break;
+                    }
+                }
+                input.skip_block();
+                continue;
+            }
+
+            // Handle key/value, add to state, etc.
+            let mut drop = false;
+            let mut marker = None;  // Deletion marker written instead of the entry
+            let parsed = parse_internal_key(&key);
+            match &parsed {
+                None => {
+                    // Do not hide error keys
+                    current_user_key = None;
+                    last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+                },
+                Some(ikey) => {
+                    if current_user_key.as_ref().is_none_or(|k| ucmp.compare(&ikey.user_key, &Slice::new(k)) != Ordering::Equal) {
+                        // First occurrence of this user key
+                        current_user_key = Some(ikey.user_key.data().to_vec());
+                        last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+                    }
+
+                    if last_sequence_for_key <= compact.smallest_snapshot {
+                        // Hidden by an newer entry for same user key
+                        drop = true;    // (A)
+                    } else if ikey.type_ == ValueType::type_deletion() && 
+                              ikey.sequence <= compact.smallest_snapshot &&
+                              compact.compaction.is_base_level_for_key(&ikey.user_key) {
+                        // For this user key:
+                        // (1) there is no data in higher levels
+                        // (2) data in lower levels will have larger sequence numbers
+                        // (3) data in layers that are being compacted here and have
+                        //     smaller sequence numbers will be dropped in the next
+                        //     few iterations of this loop (by rule (A) above).
+                        // Therefore this deletion marker is obsolete and can be dropped.
+                        drop = true;
+                    } else if last_sequence_for_key == MAX_SEQUENCE_NUMBER &&
+                              ikey.type_ == ValueType::type_value() &&
+                              ikey.sequence <= compact.smallest_snapshot &&
+                              self.options_.compaction_filter.as_ref().is_some_and(|f| f.filter(compact.compaction.level(), &ikey.user_key, &input.value())) {
+                        // The newest value of the key is filtered out.  Older
+                        // entries in this compaction are dropped by rule (A);
+                        // deeper levels need a deletion marker to hide theirs.
+                        if compact.compaction.is_base_level_for_key(&ikey.user_key) {
+                            drop = true;
+                        } else {
+                            marker = Some(InternalKey::new_from(&ikey.user_key, ikey.sequence, ValueType::type_deletion()));
+                        }
+                    }
+
+                    last_sequence_for_key = ikey.sequence;
+                },
+            }
+
+            if !drop {
+                // Never let an output span a boundary of the split policy
+                if let Some(ikey) = &parsed {
+                    if compact.builder.is_some() && self.split_before(compact, ikey) {
+                        s = self.finish_compaction_output_file(compact, input.as_ref());
+                        if !s.ok() {
+                            break;
+                        }
+                    }
+                }
+
+                // Open output file if necessary
+                if compact.builder.is_none() {
+                    s = self.open_compaction_output_file(compact);
+                    if !s.ok() {
+                        break;
+                    }
+                }
+                let (key, value) = match &marker {
+                    Some(marker) => (marker.encode(), Slice::new(b"")),
+                    None => (key, input.value()),
+                };
+                let first_entry = compact.builder.as_ref().unwrap().num_entries() == 0;
+                let output = compact.outputs.last_mut().unwrap();
+                if first_entry {
+                    output.smallest = InternalKey::decode_from(&key);
+                }
+                output.largest = InternalKey::decode_from(&key);
+                let builder = compact.builder.as_mut().unwrap();
+                builder.add(&key, &value);
+
+                // Close output file if it is big enough
+                if builder.file_size() >= compact.compaction.max_output_file_size() {
+                    s = self.finish_compaction_output_file(compact, input.as_ref());
+                    if !s.ok() {
+                        break;
+                    }
+                }
+            }
+
+            input.next();
+        }
+
+        if s.ok() && compact.builder.is_some() {
+            s = self.finish_compaction_output_file(compact, input.as_ref());
+        }
+        if s.ok() {
+            s = input.status();
+        }
+        if s.ok() {
+            self.pace_compaction_output(compact);
+        }
+        s
+    }
+
+    /// Wait as long as the compaction rate limit asks for the output
+    /// "compact" wrote since the last call.
+    /// REQUIRES: mutex_ is not held
+    fn pace_compaction_output(&self, compact: &mut CompactionState) {
+        let written = compact.total_bytes + compact.builder.as_ref().map_or(0, TableBuilder::file_size);
+        let bytes = written.saturating_sub(compact.paced_bytes);
+        compact.paced_bytes = written;
+        if bytes > 0 {
+            let wait = self.compaction_rate_limiter_.reserve(bytes, self.env_.now_micros());
+            if wait > 0 {
+                self.env_.sleep_for_microseconds(wait);
+            }
+        }
+    }
+
+    /// Returns true iff compactions may copy whole data blocks of their
+    /// inputs into their outputs.  Not when every entry has to be looked
+    /// at: to be filtered, to be checked against its block's checksum
+    /// once more, or to be split on by the split policy.
+    fn can_copy_blocks(&self) -> bool {
+        self.options_.compaction_filter.is_none() && !self.options_.paranoid_checks && self.options_.output_split_key_policy.is_none()
+    }
+
+    /// If compact_subrange() would write each entry of "raw" to its
+    /// output unchanged, and in the same output, return the first and
+    /// last keys of the block.  "current_user_key" is the user key of
+    /// the entry before it.
+    fn copyable_block_bounds(&self, compact: &CompactionState, raw: &RawBlock, current_user_key: Option<&[u8]>, end: Option<&InternalKey>) -> Option<(Vec<u8>, Vec<u8>)> {
+        // Every entry must be the only one of its user key in the
+        // compaction, and no deletion marker may be droppable.
+        let ucmp = self.internal_comparator_.user_comparator();
+        let mut iter = raw.new_iterator(Arc::new(self.internal_comparator_.clone()));
+        let mut prev_user_key = current_user_key.map(|k| k.to_vec());
+        let (mut first, mut last) = (None, Vec::new());
+        iter.seek_to_first();
+        while iter.valid() {
+            let ikey = parse_internal_key(&iter.key())?;
+            if prev_user_key.as_ref().is_some_and(|k| ucmp.compare(&ikey.user_key, &Slice::new(k)) != Ordering::Greater) ||
+               (ikey.type_ == ValueType::type_deletion() && ikey.sequence <= compact.smallest_snapshot) {
+                return None;
+            }
+            prev_user_key = Some(ikey.user_key.data().to_vec());
+            first.get_or_insert_with(|| iter.key().data().to_vec());
+            last = iter.key().data().to_vec();
+            iter.next();
+        }
+        let first = first.filter(|_| iter.status().ok())?;
+
+        // The block must end before the subrange does, and within the
+        // output should_stop_before() would keep it in.
+        let last_key = Slice::new(&last);
+        if end.is_some_and(|end| self.internal_comparator_.compare(&last_key, &end.encode()) != Ordering::Less) ||
+           compact.compaction.grandparent_boundary_before(&last_key) {
+            return None;
+        }
+        Some((first, last))
+    }
+
+    /// Returns true iff the split policy puts a boundary between the last
+    /// user key of the current output and "ikey".
+    fn split_before(&self, compact: &CompactionState, ikey: &ParsedInternalKey) -> bool {
+        let Some(policy) = &self.options_.output_split_key_policy else {
+            return false;
+        };
+        let prev = compact.outputs.last().unwrap().largest.user_key();
+        let ucmp = self.internal_comparator_.user_comparator();
+        ucmp.compare(&prev, &ikey.user_key) == Ordering::Less && policy.should_split_before(&prev, &ikey.user_key)
+    }
+
+    fn open_compaction_output_file(&self, compact: &mut CompactionState) -> Status {
+        debug_assert!(compact.builder.is_none());
+        let file_number = {
+            let mut state = self.mutex_.lock().expect("failed to acquire lock");
+            let file_number = state.versions_.new_file_number();
+            state.pending_outputs_.insert(file_number);
+            file_number
+        };
+        compact.outputs.push(CompactionOutput { 
+            number: file_number, 
+            file_size: 0, 
+            smallest: InternalKey::new(), 
+            largest: InternalKey::new(),
+            file_checksum: 0,
+        });
+
+        // Make the output file
+        match self.env_.new_writable_file(&table_file_name(&self.dbname_, file_number)) {
+            Ok(file) => {
+                compact.builder = Some(TableBuilder::new(&self.options_, file.clone()));
+                compact.outfile = Some(file);
+                Status::new_ok()
+            },
+            Err(s) => s,
+        }
+    }
+
+    fn finish_compaction_output_file(&self, compact: &mut CompactionState, input: &dyn Iterator) -> Status {
+        let mut builder = compact.builder.take().unwrap();
+        let output_number = compact.outputs.last().unwrap().number;
+        debug_assert!(output_number != 0);
+
+        // Check for iterator errors
+        let mut s = input.status();
+        let current_entries = builder.num_entries();
+        if s.ok() {
+            s = builder.finish();
+        } else {
+            builder.abandon();
+        }
+        let current_bytes = builder.file_size();
+        compact.outputs.last_mut().unwrap().file_size = current_bytes;
+        compact.outputs.last_mut().unwrap().file_checksum = builder.file_checksum();
+        compact.total_bytes += current_bytes;
+
+        // Finish and check for file errors
+        let outfile = compact.outfile.take().unwrap();
+        if s.ok() {
+            s = outfile.sync();
+        }
+        sync_point!("compaction:after-output-sync", s);
+        if s.ok() {
+            s = outfile.close();
+        }
+
+        if s.ok() && current_entries > 0 {
+            // Verify that the table is usable
+            let iter = self.table_cache_.new_iterator(&ReadOptions::new(), None, output_number, current_bytes);
+            s = iter.status();
+            if s.ok() {
+                log(self.options_.info_log.clone(), &format!("Generated table #{}@{}: {} keys, {} bytes", 
+                    output_number, compact.compaction.level(), current_entries, current_bytes));
+            }
+        }
+        s
+    }
+
+    fn install_compaction_results(&self, state: &mut DbState, compact: &mut CompactionState) -> Status {
+        let c = &mut compact.compaction;
+        log(self.options_.info_log.clone(), &format!("Compacted {}@{} + {}@{} files => {} bytes", 
+            c.num_input_files(0), c.level(), c.num_input_files(1), c.output_level(), compact.total_bytes));
+
+        // Add compaction outputs
+        c.add_input_deletions();
+        let level = c.output_level();
+        for out in &compact.outputs {
+            c.edit().add_file_with_checksum(level, out.number, out.file_size, &out.smallest, &out.largest, Some(out.file_checksum));
+        }
+        let mems = self.live_memtables(state);
+        self.log_and_apply(state, c.edit(), Some(&mems))
+    }
+
+    fn cleanup_compaction(&self, state: &mut DbState, mut compact: CompactionState) {
+        if let Some(mut builder) = compact.builder.take() {
+            // May happen if the compaction failed with an output open
+            builder.abandon();
+        }
+        let pending_outputs = &mut state.pending_outputs_;
+        for out in &compact.outputs {
+            pending_outputs.remove(&out.number);
+        }
+    }
+
+    /// Return a file above the last level that overlaps [*begin,*end] and
+    /// whose filter is not usable, if any.
+    fn find_file_without_filter(&self, state: &DbState, begin: Option<&Slice>, end: Option<&Slice>) -> Option<(i32, FileMetaData)> {
+        let current = state.versions_.current();
+        let ucmp = self.internal_comparator_.user_comparator();
+        for level in 0..NUM_LEVELS - 1 {
+            for f in current.files(level) {
+                if begin.is_some_and(|b| ucmp.compare(&f.largest.user_key(), b) == Ordering::Less) ||
+                    end.is_some_and(|e| ucmp.compare(&f.smallest.user_key(), e) == Ordering::Greater) {
+                    continue;
+                }
+                if !self.table_cache_.find_table(&ReadOptions::new(), f.number, f.file_size).is_ok_and(|t| t.filter_usable()) {
+                    return Some((level, f.clone()));
+                }
+            }
+        }
+        None
+    }
+
+    /// Delete any unneeded files and stale in-memory entries.
+    fn remove_obsolete_files(&self, state: &mut DbState) {
+        if !state.bg_error_.ok() {
+            // After a background error, we don't know whether a new version may
+            // or may not have been committed, so we cannot safely garbage collect.
+            return;
+        }
+
+        // Make a set of all of the live files
+        let mut live = state.pending_outputs_.clone();
+        let versions = &state.versions_;
+        versions.add_live_files(&mut live);
+
+        let filenames = match self.env_.get_children(&self.dbname_) {
+            Ok(filenames) => filenames,
+            Err(_) => { return; },  // Ignoring errors on purpose
+        };
+
+        // Keep the newest options_.keep_log_file_num logs that are no
+        // longer needed for recovery, and forget the idempotency tokens
+        // of the ones deleted.
+        let mut old_logs: Vec<u64> = filenames.iter()
+            .filter_map(|filename| parse_file_name(filename))
+            .filter(|&(number, type_)| type_ == FileType::LogFile
+                    && number < versions.log_number() && number != versions.prev_log_number())
+            .map(|(number, _)| number)
+            .collect();
+        old_logs.sort();
+        let kept_logs = old_logs.split_off(old_logs.len().saturating_sub(self.options_.keep_log_file_num));
+        let oldest_kept_log = kept_logs.first().copied().unwrap_or(versions.log_number());
+        state.idempotency_tokens_.expire_logs_before(oldest_kept_log);
+
+        sync_point!("db:remove-obsolete-files:before-delete");
+        for filename in filenames {
+            if let Some((number, type_)) = parse_file_name(&filename) {
+                let keep = match type_ {
+                    FileType::LogFile => {
+                        number >= oldest_kept_log || number == versions.prev_log_number()
+                    },
+                    FileType::DescriptorFile => {
+                        // Keep my manifest file, and any newer incarnations'
+                        // (in case there is a race that allows other incarnations)
+                        number >= versions.manifest_file_number()
+                    },
+                    FileType::TableFile => live.contains(&number),
+                    FileType::TempFile => {
+                        // Any temp files that are currently being written to must
+                        // be recorded in pending_outputs_, which is inserted into "live"
+                        live.contains(&number)
+                    },
+                    FileType::CurrentFile | FileType::DBLockFile | FileType::InfoLogFile | FileType::FenceFile => true,
+                };
+
+                if !keep {
+                    if type_ == FileType::TableFile {
+                        self.table_cache_.evict(number);
+                    }
+                    log(self.options_.info_log.clone(), &format!("Delete type={:?} #{}", type_, number));
+                    self.env_.remove_file(&format!("{}/{}", self.dbname_, filename));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        // Borrowing iterators cannot outlive the DB, and owned ones hold it.
+        debug_assert_eq!(0, self.live_iterators());
+        // Wait for background work to finish.
+        self.shutting_down_.store(true, atomic::Ordering::Release);
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        while state.background_compaction_scheduled_ {
+            state = self.background_work_finished_signal_.wait(state).expect("failed to acquire lock");
+        }
+        self.release_files(&mut state);
+    }
+}
+
+/// Destroy the contents of the specified database.
+/// Be very careful using this method.
+pub fn destroy_db(dbname: &str, options: &Options) -> Status {
+    let env = options.env.clone();
+    let filenames = match env.get_children(dbname) {
+        Ok(filenames) => filenames,
+        // Ignore error in case directory does not exist
+        Err(_) => { return Status::new_ok(); },
+    };
+
+    let lockname = lock_file_name(dbname);
+    let lock = match env.lock_file(&lockname) {
+        Ok(lock) => lock,
+        Err(s) => { return s; },
+    };
+    let mut result = Status::new_ok();
+    for filename in &filenames {
+        match parse_file_name(filename) {
+            // Lock file will be deleted at end
+            Some((_, type_)) if type_ != FileType::DBLockFile => {
+                let del = env.remove_file(&format!("{}/{}", dbname, filename));
+                if result.ok() && !del.ok() {
+                    result = del;
+                }
+            },
+            _ => {},
+        }
+    }
+    env.unlock_file(lock);  // Ignore error since state is already gone
+    env.remove_file(&lockname);
+    env.remove_dir(dbname);  // Ignore error in case dir contains other files
+    result
+}
+
+/// Time spent and bytes read and written by the memtable flushes and
+/// compactions that produced data for one level.  See
+/// DB::compaction_stats().
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub micros: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Subcompactions run by the compactions that were split; see
+    /// Options::max_subcompactions.
+    pub subcompactions: u64,
+    /// Data blocks copied from the inputs to the outputs as they were,
+    /// without merging their entries one by one.
+    pub blocks_copied: u64,
+    /// Housekeeping compactions, which merged small files of the last
+    /// level into the same level; see Options::max_bottommost_files.
+    pub housekeeping_compactions: u64,
+}
+
+impl CompactionStats {
+    fn add(&mut self, c: &CompactionStats) {
+        self.micros += c.micros;
+        self.bytes_read += c.bytes_read;
+        self.bytes_written += c.bytes_written;
+        self.subcompactions += c.subcompactions;
+        self.blocks_copied += c.blocks_copied;
+        self.housekeeping_compactions += c.housekeeping_compactions;
+    }
+}
+
+/// A write waiting in DB::writers_ for its turn to be committed.
+struct QueuedWrite {
+    batch: WriteBatch,
+    sync: bool,
+    token: Option<[u8; 16]>,
+    disable_wal: bool,
+    // Queued by begin_exclusive_write(): never joins a group.
+    exclusive: bool,
+
+    // Set, with the status, by the writer that committed this batch as
+    // part of its group.
+    done: AtomicBool,
+    status: Mutex<Status>,
+    cv: Condvar,     // Waited on with mutex_
+}
+
+impl QueuedWrite {
+    fn new(mut batch: WriteBatch, options: &WriteOptions) -> Self {
+        if let Some(token) = &options.idempotency_token {
+            batch.set_idempotency_token(token);
+        }
+        Self {
+            batch,
+            sync: options.sync,
+            token: options.idempotency_token,
+            disable_wal: options.disable_wal,
+            exclusive: false,
+            done: AtomicBool::new(false),
+            status: Mutex::new(Status::new_ok()),
+            cv: Condvar::new(),
+        }
+    }
+
+    fn exclusive() -> Self {
+        Self { exclusive: true, ..Self::new(WriteBatch::new(), &WriteOptions::default()) }
+    }
+}
+
+/// Batches of a write group to log, each with the number of entries
+/// before it in the group; see DbInner::build_batch_group().
+type LoggedRuns = Vec<(u64, WriteBatch)>;
+
+/// Information for a manual compaction
+struct ManualCompaction {
+    level: i32,
+    done: bool,
+    begin: Option<InternalKey>,     // None means beginning of key range
+    end: Option<InternalKey>,       // None means end of key range
+    status: Status,                 // Why it stopped early, if it failed
+}
+
+/// Files produced by compaction
+struct CompactionOutput {
+    number: u64,
+    file_size: u64,
+    smallest: InternalKey,
+    largest: InternalKey,
+    file_checksum: u32,
+}
+
+struct CompactionState {
+    compaction: Compaction,
+
+    // Sequence numbers < smallest_snapshot are not significant since we
+    // will never have to service a snapshot below smallest_snapshot.
+    // Therefore if we have seen a sequence number S <= smallest_snapshot,
+    // we can drop all entries for the same key with sequence numbers < S.
+    smallest_snapshot: SequenceNumber,
+
+    outputs: Vec<CompactionOutput>,
+
+    // State kept for output being generated
+    outfile: Option<Arc<dyn WritableFile>>,
+    builder: Option<TableBuilder>,
+
+    total_bytes: u64,
+    blocks_copied: u64,
+    paced_bytes: u64,   // Output already charged to the compaction rate limit
+}
+
+impl CompactionState {
+    fn new(compaction: Compaction) -> Self {
+        Self { compaction, smallest_snapshot: 0, outputs: Vec::new(), outfile: None, builder: None, total_bytes: 0, blocks_copied: 0,
+               paced_bytes: 0 }
+    }
+}
+
+/// A subrange of a compaction, waiting in a SubcompactionQueue.
+struct SubcompactionTask {
+    index: usize,
+    state: CompactionState,
+    input: Box<dyn Iterator>,
+    begin: Option<InternalKey>,     // None means beginning of key range
+    end: Option<InternalKey>,       // None means end of key range
+}
+
+/// The subranges of a compaction, shared by the thread running it and
+/// the helpers it schedules.
+#[derive(Default)]
+struct SubcompactionQueue {
+    state: Mutex<SubcompactionQueueState>,
+    cv: Condvar,    // Signalled when a subrange is done
+}
+
+#[derive(Default)]
+struct SubcompactionQueueState {
+    pending: VecDeque<SubcompactionTask>,
+    running: usize,
+    failed: bool,
+    done: Vec<(usize, CompactionState, Status)>,
+}
+
+/// How far recover() has got replaying the logs.
+#[derive(Default)]
+struct LogReplay {
+    max_sequence: SequenceNumber,   // Largest sequence number replayed
+    stop_replay: bool,              // Reached a damaged record in point-in-time recovery
+    // Batches of the transactions prepared but not yet ended
+    prepared: BTreeMap<TransactionId, PreparedBatch>,
+}
+
+/// Logs and counts the corruption found while replaying a log.  What
+/// recovery then does is up to Options::wal_recovery_mode.
+struct DBLogReporter {
+    info_log: Option<Arc<dyn Logger>>,
+    fname: String,
+    records: Cell<u64>,
+    bytes: Cell<u64>,
+    status: RefCell<Status>,   // The first corruption reported
+}
+
+impl DBLogReporter {
+    fn new(info_log: Option<Arc<dyn Logger>>, fname: String) -> Self {
+        Self { info_log, fname, records: Cell::new(0), bytes: Cell::new(0), status: RefCell::new(Status::new_ok()) }
+    }
+
+    /// Number of corruptions reported so far.
+    fn records(&self) -> u64 {
+        self.records.get()
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.get()
+    }
+
+    fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+}
+
+impl Reporter for DBLogReporter {
+    fn corruption(&self, bytes: usize, status: &Status) {
+        log(self.info_log.clone(), &format!("{}: dropping {} bytes; {}", self.fname, bytes, status.to_string()));
+        self.records.set(self.records.get() + 1);
+        self.bytes.set(self.bytes.get() + bytes as u64);
+        if self.status.borrow().ok() {
+            *self.status.borrow_mut() = status.clone();
+        }
+    }
+}
+
+/// Sanitize db options: clamp sizes to sane ranges, and fill in an 8MB
+/// block cache if "src" has none.  If "create_info_log", a missing
+/// info_log is filled in with a logger writing to dbname/LOG; the one
+/// left by an earlier open is renamed to LOG.old.  With an
+/// instance_name, messages to the info_log start with "[<name>] ".
+/// "src" is left as it is.
+fn sanitize_options(dbname: &str, icmp: &InternalKeyComparator, ipolicy: Option<Arc<dyn FilterPolicy>>, src: &Options, 
+                    create_info_log: bool) -> Options {
+    let mut result = src.clone();
+    result.comparator = Arc::new(icmp.clone());
+    result.filter_policy = if src.filter_policy.is_some() { ipolicy } else { None };
+    result.write_buffer_size = result.write_buffer_size.clamp(MIN_WRITE_BUFFER_SIZE, MAX_WRITE_BUFFER_SIZE);
+    if result.max_open_files != 0 {
+        result.max_open_files = result.max_open_files.clamp(MIN_MAX_OPEN_FILES, MAX_MAX_OPEN_FILES);
+    }
+    result.block_size = result.block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+    let fraction = |v: f64| if v.is_nan() { 0.0 } else { v.clamp(0.0, 1.0) };
+    result.tombstone_compaction_ratio = fraction(result.tombstone_compaction_ratio);
+    result.checksum_verification_sample_rate = fraction(result.checksum_verification_sample_rate);
+    // The delay curve only rises: write_delay_full is moved up to
+    // write_delay_start where it would lie below or at it.
+    result.write_delay_full.debt_bytes = result.write_delay_full.debt_bytes.max(result.write_delay_start.debt_bytes.saturating_add(1));
+    result.write_delay_full.delay_micros = result.write_delay_full.delay_micros.max(result.write_delay_start.delay_micros);
+    match (&src.instance_name, &src.info_log) {
+        (Some(name), Some(info_log)) => {
+            result.info_log = Some(Arc::new(PrefixLogger::new(format!("[{}] ", name), info_log.clone())));
+        },
+        (name, None) if create_info_log => {
+            let _ = src.env.create_dir(dbname);  // In case it does not exist
+            src.env.rename_file(&info_log_file_name(dbname), &old_info_log_file_name(dbname));
+            // Without a LOG file, the DB runs without logging.
+            result.info_log = src.env.new_logger(&info_log_file_name(dbname)).ok().map(|info_log| match name {
+                Some(name) => Arc::new(PrefixLogger::new(format!("[{}] ", name), info_log)) as Arc<dyn Logger>,
+                None => info_log,
+            });
+        },
+        _ => {},
+    }
+    if result.block_cache.is_none() && !result.no_block_cache {
+        result.block_cache = Some(match &result.block_cache_level_partitions {
+            Some(fractions) => new_partitioned_lru_cache(DEFAULT_BLOCK_CACHE_SIZE, fractions, result.block_cache_partition_borrowing),
+            None => new_lru_cache(DEFAULT_BLOCK_CACHE_SIZE),
+        });
+    }
+    // Keep sizes derived from this (table offsets, buffers sized to a
+    // whole table) far from usize::MAX.
+    #[cfg(target_pointer_width = "32")]
+    {
+        result.max_file_size = result.max_file_size.min(MAX_FILE_SIZE_32BIT);
+    }
+    result
+}
+
+/// The properties get_property() understands; see property_names().
+const PROPERTY_NAMES: [&str; NUM_LEVELS as usize + 12] = [
+    "leveldb.num-files-at-level0",
+    "leveldb.num-files-at-level1",
+    "leveldb.num-files-at-level2",
+    "leveldb.num-files-at-level3",
+    "leveldb.num-files-at-level4",
+    "leveldb.num-files-at-level5",
+    "leveldb.num-files-at-level6",
+    "leveldb.filter-coverage",
+    "leveldb.stats",
+    "leveldb.value-size-histogram",
+    "leveldb.wal-recovery-dropped-records",
+    "leveldb.recovery-flushes",
+    "leveldb.possible-unlogged-data-loss",
+    "leveldb.pinned-bytes",
+    "leveldb.approximate-memory-usage",
+    "leveldb.space-amp",
+    "leveldb.snapshots",
+    "leveldb.block-cache-stats",
+    "leveldb.lookup-efficiency",
+];
+
+/// Pause before the first retry of failed background work; each further
+/// retry in a row doubles it, up to 2^BACKGROUND_ERROR_RETRY_MAX_SHIFT
+/// times as long.  See Options::max_background_error_retries.
+const BACKGROUND_ERROR_RETRY_MICROS: u64 = 10_000;
+const BACKGROUND_ERROR_RETRY_MAX_SHIFT: usize = 7;
+
+/// Cap applied by sanitize_options() on 32-bit targets.
+#[cfg(target_pointer_width = "32")]
+const MAX_FILE_SIZE_32BIT: usize = 1 << 30;
+
+#[cfg(test)]
+thread_local! {
+    /// The most memory a memtable has used while logs were replayed
+    /// into it on this thread.
+    static REPLAY_PEAK_MEMTABLE: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::atomic::{AtomicBool, AtomicU32, AtomicU64}};
+
+    use crate::{batch_transformer::BatchTransformer, options::{CompressionType, DiagnosticRedaction, WriteDelayPoint}, cache::{new_lru_cache, Cache, CacheValue}, compaction_filter::CompactionFilter, comparator::{bytewise_comparator, Comparator}, db::{filename::fence_file_name, log_format::BLOCK_SIZE}, env::{RandomAccessFile, SequentialFile}, filter_policy::new_bloom_filter_policy, helpers::memenv::new_mem_env, split_policy::FixedPrefixSplitPolicy, status::Code, sync_point, util::{coding::{decode_fixed64_bytes, put_fixed64}, env::{copy_file, read_file_to_string, write_string_to_file_sync, StickyErrorFile}, random::Random}};
+
+    use super::*;
+
+    const DBNAME: &str = "/db";
+
+    fn options_with_env(env: Arc<dyn Env>) -> Options {
+        let mut options = Options::new();
+        options.env = env;
+        options.create_if_missing = true;
+        options
+    }
+
+    fn mem_get(db: &DB, key: &str) -> Option<Vec<u8>> {
+        let state = db.mutex_.lock().unwrap();
+        let lkey = LookupKey::new(&Slice::new(key.as_bytes()), state.versions_.last_sequence());
+        state.mem_.as_ref().unwrap().get(&lkey).0
+    }
+
+    #[test]
+    fn open_missing_test() {
+        let mut options = options_with_env(new_mem_env());
+        options.create_if_missing = false;
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        assert!(s.is_invalid_argument());
+        assert!(s.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn open_creates_files_test() {
+        let env = new_mem_env();
+        let _db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        let mut children = env.get_children(DBNAME).unwrap();
+        children.sort();
+        // MANIFEST-000001 written by new_db is superseded by the manifest
+        // written when the new log is recorded.  (The in-memory Env does
+        // not create a LOCK file.)
+        assert_eq!(vec!["000003.log", "CURRENT", "LOG", "MANIFEST-000002"], children);
+    }
+
+    #[test]
+    fn put_test() {
+        let env = new_mem_env();
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        const N: usize = 3000;
+        let wo = WriteOptions::default();
+        for i in 0..N {
+            let key = format!("key{:06}", i);
+            let value = format!("value{}", i);
+            assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes())).ok());
+        }
+        // Overwrite a key; the newest value wins.
+        assert!(db.put(&wo, &Slice::new(b"key000007"), &Slice::new(b"new")).ok());
+        assert_eq!(N as u64 + 1, db.mutex_.lock().unwrap().versions_.last_sequence());
+
+        for i in 0..N {
+            let expected = if i == 7 { "new".to_string() } else { format!("value{}", i) };
+            assert_eq!(Some(expected.into_bytes()), mem_get(&db, &format!("key{:06}", i)));
+        }
+        assert_eq!(None, mem_get(&db, "missing"));
+
+        // Every put is in the log, tagged with its sequence number.
+        let file = env.new_sequential_file(&log_file_name(DBNAME, db.mutex_.lock().unwrap().logfile_number_)).unwrap();
+        let mut reader = Reader::new(file, None, true, 0);
+        let mut records = 0;
+        while let Some(record) = reader.read_record() {
+            records += 1;
+            assert_eq!(records, decode_fixed64_bytes(&record[..8]));
+        }
+        assert_eq!(N as u64 + 1, records);
+    }
+
+    #[test]
+    fn put_sync_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions { sync: true, ..Default::default() };
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        assert!(db.put(&wo, &Slice::new(b""), &Slice::new(b"")).ok());
+        assert_eq!(Some(b"v1".to_vec()), mem_get(&db, "foo"));
+        assert_eq!(Some(Vec::new()), mem_get(&db, ""));
+    }
+
+    #[test]
+    fn put_default_env_test() {
+        let dir = std::env::temp_dir().join(format!("rucksdb-db-put-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dbname = dir.to_string_lossy().into_owned();
+        let mut options = Options::new();
+        options.create_if_missing = true;
+        {
+            let db = DB::open(&options, &dbname).unwrap();
+            assert!(db.put(&WriteOptions { sync: true, ..Default::default() }, &Slice::new(b"foo"), &Slice::new(b"bar")).ok());
+            assert_eq!(Some(b"bar".to_vec()), mem_get(&db, "foo"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_default_env_test() {
+        let dir = std::env::temp_dir().join(format!("rucksdb-db-reopen-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dbname = dir.to_string_lossy().into_owned();
+        let mut options = Options::new();
+        options.create_if_missing = true;
+        {
+            let db = DB::open(&options, &dbname).unwrap();
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"foo"), &Slice::new(b"bar")).ok());
+            // The DB holds the lock while it is open.
+            assert!(DB::open(&options, &dbname).err().unwrap().is_busy());
+        }
+        // Dropping the DB released the lock and kept the unsynced write.
+        for _ in 0..2 {
+            let db = DB::open(&options, &dbname).unwrap();
+            assert_eq!(b"bar".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"foo")).unwrap());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn get_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        assert!(db.get(&ro, &Slice::new(b"foo")).unwrap_err().is_not_found());
+
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        assert_eq!(b"v1".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v2")).ok());
+        assert_eq!(b"v2".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+
+        assert!(db.get(&ro, &Slice::new(b"fo")).unwrap_err().is_not_found());
+        assert!(db.get(&ro, &Slice::new(b"foo1")).unwrap_err().is_not_found());
+        assert!(db.get(&ro, &Slice::new(b"")).unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn get_deleted_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        assert!(db.put(&wo, &Slice::new(b"bar"), &Slice::new(b"v2")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"foo")).ok());
+        assert!(db.get(&ro, &Slice::new(b"foo")).unwrap_err().is_not_found());
+        assert_eq!(b"v2".to_vec(), db.get(&ro, &Slice::new(b"bar")).unwrap());
+
+        // A later put makes the key visible again.
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v3")).ok());
+        assert_eq!(b"v3".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+    }
+
+    #[test]
+    fn get_from_imm_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"old")).ok());
+        assert!(db.put(&wo, &Slice::new(b"bar"), &Slice::new(b"old")).ok());
+
+        // Retire the memtable as if a compaction were pending.
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            state.imm_ = state.mem_.replace(Arc::new(db.new_memtable()));
+        }
+        assert_eq!(b"old".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+
+        // Entries in mem_ shadow imm_, including tombstones.
+        assert!(db.put(&wo, &Slice::new(b"bar"), &Slice::new(b"new")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"foo")).ok());
+        assert_eq!(b"new".to_vec(), db.get(&ro, &Slice::new(b"bar")).unwrap());
+        assert!(db.get(&ro, &Slice::new(b"foo")).unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn delete_test() {
+        let env = new_mem_env();
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        // Deleting a key that was never written succeeds.
+        assert!(db.delete(&wo, &Slice::new(b"missing")).ok());
+        assert!(db.get(&ro, &Slice::new(b"missing")).unwrap_err().is_not_found());
+
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        assert!(db.delete(&WriteOptions { sync: true, ..Default::default() }, &Slice::new(b"foo")).ok());
+        assert!(db.get(&ro, &Slice::new(b"foo")).unwrap_err().is_not_found());
+        assert_eq!(3, db.mutex_.lock().unwrap().versions_.last_sequence());
+
+        // Deletions are logged with their own tag and no value.
+        let file = env.new_sequential_file(&log_file_name(DBNAME, db.mutex_.lock().unwrap().logfile_number_)).unwrap();
+        let mut reader = Reader::new(file, None, true, 0);
+        let mut types = Vec::new();
+        while let Some(record) = reader.read_record() {
+            types.push(record[12]);
+            if record[12] == ValueType::type_deletion().value() {
+                assert_eq!(12 + 1 + 1 + record[13] as usize, record.len());
+            }
+        }
+        let (v, d) = (ValueType::type_value().value(), ValueType::type_deletion().value());
+        assert_eq!(vec![d, v, d], types);
+    }
+
+    #[test]
+    fn write_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v0")).ok());
+
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::new(b"a"), &Slice::new(b"v1"));
+        batch.put(&Slice::new(b"b"), &Slice::new(b"v2"));
+        batch.delete(&Slice::new(b"a"));
+        batch.put(&Slice::new(b"c"), &Slice::new(b"v3"));
+        assert!(db.write(&wo, batch).ok());
+        // One sequence number per entry, applied in order.
+        assert_eq!(5, db.mutex_.lock().unwrap().versions_.last_sequence());
+        assert!(db.get(&ro, &Slice::new(b"a")).unwrap_err().is_not_found());
+        assert_eq!(b"v2".to_vec(), db.get(&ro, &Slice::new(b"b")).unwrap());
+        assert_eq!(b"v3".to_vec(), db.get(&ro, &Slice::new(b"c")).unwrap());
+
+        // An empty batch is logged but consumes no sequence numbers.
+        assert!(db.write(&wo, WriteBatch::new()).ok());
+        assert_eq!(5, db.mutex_.lock().unwrap().versions_.last_sequence());
+    }
+
+    #[test]
+    fn group_commit_test() {
+        let env = new_mem_env();
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        let queued = |key: &str, size: usize, sync: bool, token: Option<u8>| {
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::new(key.as_bytes()), &Slice::new(&vec![b'v'; size]));
+            let options = WriteOptions { sync, idempotency_token: token.map(|t| [t; 16]), ..Default::default() };
+            Arc::new(QueuedWrite::new(batch, &options))
+        };
+
+        // Writers that queued up while an earlier write was being logged.
+        let writers = [
+            queued("a", 10, false, None),
+            queued("b", 10, false, None),
+            queued("c", 10, false, None),
+            queued("d", 10, true, None),        // Does not join a non-sync group
+            queued("e", 10, false, None),       // Joins a sync group
+            queued("f", 10, false, Some(1)),    // Carries a token: logged alone
+            queued("g", 10, false, None),
+            queued("h", 200 << 10, false, None),    // Too big to join a small write
+        ];
+        db.mutex_.lock().unwrap().writers_.extend(writers.iter().cloned());
+        let lead = |i: usize| {
+            let state = db.mutex_.lock().unwrap();
+            assert!(Arc::ptr_eq(state.writers_.front().unwrap(), &writers[i]));
+            assert!(db.lead_write_group(state, &writers[i], None).ok());
+            writers.iter().map(|w| w.done.load(atomic::Ordering::Acquire)).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![false, true, true, false, false, false, false, false], lead(0));
+        assert_eq!(vec![false, true, true, false, true, false, false, false], lead(3));
+        assert_eq!(vec![false, true, true, false, true, false, false, false], lead(5));
+        assert_eq!(vec![false, true, true, false, true, false, false, false], lead(6));
+        assert_eq!(vec![false, true, true, false, true, false, false, false], lead(7));
+        assert!(db.mutex_.lock().unwrap().writers_.is_empty());
+
+        // One log record per group, with contiguous sequence numbers.
+        let groups: Vec<_> = logged_batches(&env, &db, DBNAME).iter().map(|b| (b.sequence(), b.count())).collect();
+        assert_eq!(vec![(1, 3), (4, 2), (6, 1), (7, 1), (8, 1)], groups);
+        assert_eq!(8, db.last_applied_sequence());
+        let keys: Vec<_> = full_scan(&db).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(vec!["a", "b", "c", "d", "e", "f", "g", "h"], keys);
+        assert!(put_with_token(&db, 1, "f", "v").is_already_applied());
+    }
+
+    #[test]
+    fn db_is_send_and_sync_test() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DB>();
+        assert_send_sync::<Arc<DB>>();
+    }
+
+    #[test]
+    fn concurrent_put_get_test() {
+        let mut options = options_with_env(new_mem_env());
+        // Small enough for the writers to switch memtables and compact
+        // level-0 while the others are writing.
+        options.write_buffer_size = 16 << 10;
+        let db: Arc<DB> = DB::open(&options, DBNAME).unwrap().into();
+        let threads: Vec<_> = (0..4).map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+                for i in 0..500 {
+                    let key = format!("t{}-{:04}", t, i);
+                    assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(format!("v{}", i).as_bytes())).ok());
+                    assert_eq!(format!("v{}", i).into_bytes(), db.get(&ro, &Slice::new(key.as_bytes())).unwrap());
+                    if i % 50 == 0 {
+                        // Also read a key of another thread, which may or
+                        // may not have been written yet.
+                        let other = format!("t{}-{:04}", (t + 1) % 4, i / 2);
+                        let _ = db.get(&ro, &Slice::new(other.as_bytes()));
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(4 * 500, db.last_applied_sequence());
+        assert_eq!(4 * 500, full_scan(&db).len());
+        for t in 0..4 {
+            let key = format!("t{}-{:04}", t, 499);
+            assert_eq!(b"v499".to_vec(), db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).unwrap());
+        }
+        assert!(files_per_level(&db).iter().sum::<usize>() > 0);
+    }
+
+    #[test]
+    fn recover_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+            assert!(db.put(&wo, &Slice::new(b"bar"), &Slice::new(b"v2")).ok());
+            assert!(db.delete(&wo, &Slice::new(b"bar")).ok());
+
+            // Crash after the log append but before the memtable apply.
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::new(b"baz"), &Slice::new(b"v3"));
+            batch.put(&Slice::new(b"foo"), &Slice::new(b"v4"));
+            batch.set_sequence(4);
+            assert!(db.mutex_.lock().unwrap().log_.as_mut().unwrap().add_record(&batch.contents()).ok());
+            assert!(db.get(&ro, &Slice::new(b"baz")).unwrap_err().is_not_found());
+        }
+
+        for _ in 0..2 {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert_eq!(5, db.mutex_.lock().unwrap().versions_.last_sequence());
+            assert_eq!(b"v4".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+            assert!(db.get(&ro, &Slice::new(b"bar")).unwrap_err().is_not_found());
+            assert_eq!(b"v3".to_vec(), db.get(&ro, &Slice::new(b"baz")).unwrap());
+        }
+
+        // Writes made after a recovery survive the next one as well.
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&wo, &Slice::new(b"bar"), &Slice::new(b"v5")).ok());
+        }
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(6, db.mutex_.lock().unwrap().versions_.last_sequence());
+        assert_eq!(b"v5".to_vec(), db.get(&ro, &Slice::new(b"bar")).unwrap());
+        assert_eq!(b"v4".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+    }
+
+    #[test]
+    fn wal_trailer_test() {
+        #[derive(Clone, Copy)]
+        enum Damage { None, TruncatedTrailer, MissingRecord, NoTrailer }
+
+        // Returns the keys visible after reopening, the number of records
+        // dropped and whether a clean shutdown was verified, or the status
+        // the reopen failed with.
+        let recover = |damage: Damage, mode: WalRecoveryMode| -> Result<(Vec<String>, String, bool), Status> {
+            let env = new_mem_env();
+            let mut options = options_with_env(env.clone());
+            let fname = {
+                let db = DB::open(&options, DBNAME).unwrap();
+                for i in 0..5 {
+                    assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(b"value")).ok());
+                }
+                let logfile_number = db.mutex_.lock().unwrap().logfile_number_;
+            log_file_name(DBNAME, logfile_number)
+            };
+
+            let mut records = Vec::new();
+            let mut reader = Reader::new(env.new_sequential_file(&fname).unwrap(), None, true, 0);
+            while let Some(record) = reader.read_record() {
+                records.push(record);
+            }
+            assert_eq!(6, records.len());
+            match damage {
+                Damage::None => {},
+                Damage::TruncatedTrailer => {
+                    let mut contents = env.new_sequential_file(&fname).unwrap().read(usize::MAX).unwrap();
+                    contents.truncate(contents.len() - 3);
+                    assert!(write_string_to_file_sync(env.clone(), &Slice::new(&contents), &fname).ok());
+                },
+                Damage::MissingRecord | Damage::NoTrailer => {
+                    let file = env.new_writable_file(&fname).unwrap();
+                    let mut writer = Writer::new(file.clone());
+                    for (i, record) in records.iter().enumerate() {
+                        let skip = if matches!(damage, Damage::MissingRecord) { i == 2 } else { i == 5 };
+                        if !skip {
+                            assert!(writer.add_record(&Slice::new(record)).ok());
+                        }
+                    }
+                    assert!(file.close().ok());
+                },
+            }
+
+            let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+            options.info_log = Some(logger.clone());
+            options.wal_recovery_mode = mode;
+            let db = DB::open(&options, DBNAME)?;
+            let keys = full_scan(&db).into_iter().map(|(k, _)| k).collect();
+            let verified = logger.messages_.lock().unwrap().iter().any(|m| m.ends_with("clean shutdown verified"));
+            Ok((keys, db.get_property("leveldb.wal-recovery-dropped-records").unwrap(), verified))
+        };
+        let visible = |keys: &[usize], dropped: usize, verified: bool| {
+            (keys.iter().map(|i| format!("key{}", i)).collect::<Vec<_>>(), dropped.to_string(), verified)
+        };
+
+        use WalRecoveryMode::*;
+        for mode in [TolerateCorruptedTail, AbsoluteConsistency, PointInTime] {
+            assert_eq!(visible(&[0, 1, 2, 3, 4], 0, true), recover(Damage::None, mode).unwrap());
+            // An unclean shutdown leaves no trailer, which is not damage
+            assert_eq!(visible(&[0, 1, 2, 3, 4], 0, false), recover(Damage::NoTrailer, mode).unwrap());
+        }
+
+        // Cut short after the close: only the trailer is lost
+        assert_eq!(visible(&[0, 1, 2, 3, 4], 1, false), recover(Damage::TruncatedTrailer, TolerateCorruptedTail).unwrap());
+        assert!(recover(Damage::TruncatedTrailer, AbsoluteConsistency).unwrap_err().is_corruption());
+        assert_eq!(visible(&[0, 1, 2, 3, 4], 1, false), recover(Damage::TruncatedTrailer, PointInTime).unwrap());
+
+        // A whole record went missing, which only the trailer shows
+        assert_eq!(visible(&[0, 1, 3, 4], 1, false), recover(Damage::MissingRecord, TolerateCorruptedTail).unwrap());
+        let s = recover(Damage::MissingRecord, AbsoluteConsistency).unwrap_err();
+        assert!(s.is_corruption() && s.to_string().contains("WAL trailer mismatch"), "{}", s.to_string());
+        assert_eq!(visible(&[0, 1, 3, 4], 1, false), recover(Damage::MissingRecord, PointInTime).unwrap());
+
+        // A reused log goes on after its trailer, and gets a new one
+        let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+        let options = Options { reuse_logs: true, info_log: Some(logger.clone()), wal_recovery_mode: AbsoluteConsistency,
+                                ..options_with_env(new_mem_env()) };
+        for i in 0..3 {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert_eq!(i, full_scan(&db).len());
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(b"v")).ok());
+        }
+        assert_eq!(2, logger.messages_.lock().unwrap().iter().filter(|m| m.ends_with("clean shutdown verified")).count());
+    }
+
+    #[test]
+    fn wal_recovery_mode_test() {
+        #[derive(Clone, Copy)]
+        enum Damage { None, TruncatedTail, CorruptRecord }
+
+        // Returns the keys visible after reopening, with the number of
+        // records dropped, or the status the reopen failed with.
+        let recover = |damage: Damage, mode: WalRecoveryMode| -> Result<(Vec<String>, String), Status> {
+            let env = new_mem_env();
+            let mut options = options_with_env(env.clone());
+            let (batches, fname) = {
+                let db = DB::open(&options, DBNAME).unwrap();
+                for i in 0..5 {
+                    assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(b"value")).ok());
+                }
+                let logfile_number = db.mutex_.lock().unwrap().logfile_number_;
+                (logged_batches(&env, &db, DBNAME), log_file_name(DBNAME, logfile_number))
+            };
+
+            // Rewrite the log, damaging the third record's contents
+            let file = env.new_writable_file(&fname).unwrap();
+            let mut writer = Writer::new(file.clone());
+            for (i, batch) in batches.iter().enumerate() {
+                let mut contents = batch.contents().data().to_vec();
+                if matches!(damage, Damage::CorruptRecord) && i == 2 {
+                    contents.pop();
+                }
+                assert!(writer.add_record(&Slice::new(&contents)).ok());
+            }
+            assert!(file.close().ok());
+            if matches!(damage, Damage::TruncatedTail) {
+                let mut contents = env.new_sequential_file(&fname).unwrap().read(usize::MAX).unwrap();
+                contents.truncate(contents.len() - 3);
+                assert!(write_string_to_file_sync(env.clone(), &Slice::new(&contents), &fname).ok());
+            }
+
+            options.wal_recovery_mode = mode;
+            let db = DB::open(&options, DBNAME)?;
+            let keys = full_scan(&db).into_iter().map(|(k, _)| k).collect();
+            Ok((keys, db.get_property("leveldb.wal-recovery-dropped-records").unwrap()))
+        };
+        let visible = |keys: &[usize], dropped: usize| {
+            (keys.iter().map(|i| format!("key{}", i)).collect::<Vec<_>>(), dropped.to_string())
+        };
+
+        use WalRecoveryMode::*;
+        for mode in [TolerateCorruptedTail, AbsoluteConsistency, PointInTime] {
+            assert_eq!(visible(&[0, 1, 2, 3, 4], 0), recover(Damage::None, mode).unwrap());
+        }
+
+        // The last write was cut short by a crash
+        assert_eq!(visible(&[0, 1, 2, 3], 1), recover(Damage::TruncatedTail, TolerateCorruptedTail).unwrap());
+        assert!(recover(Damage::TruncatedTail, AbsoluteConsistency).unwrap_err().is_corruption());
+        assert_eq!(visible(&[0, 1, 2, 3], 1), recover(Damage::TruncatedTail, PointInTime).unwrap());
+
+        // A damaged record with intact ones after it
+        assert_eq!(visible(&[0, 1, 3, 4], 1), recover(Damage::CorruptRecord, TolerateCorruptedTail).unwrap());
+        assert!(recover(Damage::CorruptRecord, AbsoluteConsistency).unwrap_err().is_corruption());
+        assert_eq!(visible(&[0, 1], 3), recover(Damage::CorruptRecord, PointInTime).unwrap());
+    }
+
+    #[test]
+    fn open_read_only_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let wo = WriteOptions::default();
+        let put = |db: &DB, key: &str, value: &str| db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes()));
+
+        assert!(DB::open_read_only(&options, DBNAME).err().unwrap().is_invalid_argument());
+        assert!(!env.file_exists(DBNAME));
+
+        let writer = DB::open(&options, DBNAME).unwrap();
+        for i in 0..20 {
+            assert!(put(&writer, &format!("key{:02}", i), "v1").ok());
+        }
+        assert!(writer.flush().ok());
+        for i in 10..30 {
+            assert!(put(&writer, &format!("key{:02}", i), "v2").ok());
+        }
+        assert!(writer.delete(&wo, &Slice::new(b"key00")).ok());
+
+        // Opened alongside the writer, without touching the directory
+        let files = env.get_children(DBNAME).unwrap();
+        let reader = DB::open_read_only(&options, DBNAME).unwrap();
+        assert_eq!(files, env.get_children(DBNAME).unwrap());
+        let expected: Vec<(String, String)> = (1..30).map(|i| (format!("key{:02}", i), if i < 10 { "v1" } else { "v2" }.to_string())).collect();
+        assert_eq!(expected, full_scan(&reader));
+
+        // The writer goes on; the reader keeps the state as of its open
+        assert!(put(&writer, "key01", "v3").ok());
+        assert!(put(&writer, "key99", "v3").ok());
+        assert!(writer.flush().ok());
+        assert_eq!(expected, full_scan(&reader));
+        assert_eq!(b"v1".to_vec(), reader.get(&ReadOptions::new(), &Slice::new(b"key01")).unwrap());
+        assert_eq!(b"v3".to_vec(), writer.get(&ReadOptions::new(), &Slice::new(b"key01")).unwrap());
+
+        for s in [put(&reader, "key01", "v4"),
+                  reader.delete(&wo, &Slice::new(b"key01")),
+                  reader.delete_range(&wo, &Slice::new(b"a"), &Slice::new(b"z")),
+                  reader.write(&wo, WriteBatch::new()),
+                  reader.compact_range(None, None),
+                  reader.flush()] {
+            assert!(s.is_not_supported_error(), "{}", s.to_string());
+        }
+        drop(writer);
+        let files_after = env.get_children(DBNAME).unwrap();
+        drop(reader);
+        assert_eq!(files_after, env.get_children(DBNAME).unwrap());
+
+        // A second reader sees what the writer left
+        let reader = DB::open_read_only(&options, DBNAME).unwrap();
+        assert_eq!(b"v3".to_vec(), reader.get(&ReadOptions::new(), &Slice::new(b"key99")).unwrap());
+        assert_eq!(30, full_scan(&reader).len());
+    }
+
+    #[test]
+    fn reuse_logs_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.reuse_logs = true;
+        let files = |db: &DB| {
+            let state = db.mutex_.lock().unwrap();
+            (state.logfile_number_, state.versions_.manifest_file_number())
+        };
+        let first = {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"key0"), &Slice::new(b"v")).ok());
+            files(&db)
+        };
+        for i in 1..10 {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert_eq!(first, files(&db));
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(b"v")).ok());
+            assert_eq!(i + 1, full_scan(&db).len());
+            // Nothing was flushed: the writes are all in the memtable
+            assert_eq!(0, db.mutex_.lock().unwrap().versions_.num_level_files(2));
+        }
+
+        // Without it, every open starts a new log and MANIFEST
+        options.reuse_logs = false;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let second = files(&db);
+        assert!(second.0 > first.0 && second.1 > first.1);
+        assert_eq!(10, full_scan(&db).len());
+    }
+
+    #[test]
+    fn flush_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.flush().ok());
+            assert_eq!(Some("0".to_string()), db.get_property("leveldb.num-files-at-level0"));
+            for i in 0..100 {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(b"v")).ok());
+            }
+            assert!(db.flush().ok());
+            assert_eq!(Some("1".to_string()), db.get_property("leveldb.num-files-at-level0"));
+
+            // Nothing left to flush
+            assert!(db.flush().ok());
+            assert_eq!(Some("1".to_string()), db.get_property("leveldb.num-files-at-level0"));
+        }
+
+        // The tables alone hold the data
+        for child in env.get_children(DBNAME).unwrap() {
+            if let Some((number, FileType::LogFile)) = parse_file_name(&child) {
+                assert!(env.remove_file(&log_file_name(DBNAME, number)).ok());
+            }
+        }
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(100, full_scan(&db).len());
+    }
+
+    /// Fill "db" with keys in a few tables, some of them compacted down
+    /// and the rest left in the memtable.
+    fn fill_for_checkpoint(db: &DB) {
+        for i in 0..200 {
+            let key = format!("key{:03}", i);
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(b"v1")).ok());
+            if i % 50 == 49 {
+                assert!(db.flush().ok());
+            }
+            if i == 99 {
+                assert!(db.compact_range(None, None).ok());
+            }
+        }
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"unflushed"), &Slice::new(b"v1")).ok());
+    }
+
+    #[test]
+    fn checkpoint_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        fill_for_checkpoint(&db);
+        let before = full_scan(&db);
+        assert_eq!(201, before.len());
+        assert!(db.create_checkpoint("/checkpoint").ok());
+        assert!(db.create_checkpoint("/checkpoint").is_invalid_argument());
+        assert!(db.create_checkpoint(DBNAME).is_invalid_argument());
+
+        // Later writes, and the compactions that delete the linked tables
+        // from the DB, leave the checkpoint alone.
+        for i in 0..200 {
+            let key = format!("key{:03}", i);
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(b"v2")).ok());
+        }
+        assert!(db.delete(&WriteOptions::default(), &Slice::new(b"unflushed")).ok());
+        assert!(db.compact_range(None, None).ok());
+        let after = full_scan(&db);
+        assert_ne!(before, after);
+
+        let checkpoint = DB::open(&options, "/checkpoint").unwrap();
+        assert_eq!(before, full_scan(&checkpoint));
+        assert!(checkpoint.put(&WriteOptions::default(), &Slice::new(b"new"), &Slice::new(b"v3")).ok());
+        drop(checkpoint);
+        assert_eq!(after, full_scan(&db));
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"new")).unwrap_err().is_not_found());
+    }
+
+    /// Write the keys "{prefix}{i:04}" for i in "range" to a new external
+    /// table file "fname" with SstFileWriter.
+    fn write_external_file(options: &Options, fname: &str, prefix: &str, range: std::ops::Range<usize>) {
+        let mut writer = SstFileWriter::create(options, fname).unwrap();
+        for i in range {
+            let key = format!("{}{:04}", prefix, i);
+            assert!(writer.put(&Slice::new(key.as_bytes()), &Slice::new(format!("value{}", i).as_bytes())).ok());
+        }
+        assert!(writer.finish().ok());
+    }
+
+    #[test]
+    fn ingest_external_file_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        write_external_file(&options, "/external/a.sst", "key", 0..1000);
+        write_external_file(&options, "/external/b.sst", "other", 0..10);
+
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"before")).ok());
+        assert!(db.ingest_external_file(&["/external/a.sst", "/external/b.sst"], false).ok());
+        assert!(env.file_exists("/external/a.sst"));
+        let check = |db: &DB| {
+            for i in [0, 1, 500, 999] {
+                let key = format!("key{:04}", i);
+                assert_eq!(format!("value{}", i).into_bytes(), db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).unwrap());
+            }
+            let all = full_scan(db);
+            assert_eq!(1011, all.len());
+            assert_eq!(("a".to_string(), "before".to_string()), all[0]);
+        };
+        check(&db);
+
+        // Ingested keys can be overwritten like any others, and the files
+        // survive a reopen and compactions.
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"key0500"), &Slice::new(b"after")).ok());
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(b"after".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"key0500")).unwrap());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"key0500"), &Slice::new(b"value500")).ok());
+        assert!(db.compact_range(None, None).ok());
+        check(&db);
+
+        // Moving takes the file away from its old place.
+        write_external_file(&options, "/external/c.sst", "zzz", 0..10);
+        assert!(db.ingest_external_file(&["/external/c.sst"], true).ok());
+        assert!(!env.file_exists("/external/c.sst"));
+        assert_eq!(b"value3".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"zzz0003")).unwrap());
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn name(&self) -> &'static str { "test.ReverseComparator" }
+        fn compare(&self, a: &Slice, b: &Slice) -> Ordering { b.data().cmp(a.data()) }
+        fn find_shortest_separator(&self, _start: &mut Vec<u8>, _limit: &Slice) {}
+        fn find_short_successor(&self, _key: &mut Vec<u8>) {}
+    }
+
+    #[test]
+    fn ingest_external_file_rejects_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"key0010"), &Slice::new(b"v")).ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"other0010"), &Slice::new(b"v")).ok());
+        assert!(db.flush().ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"mem0010"), &Slice::new(b"v")).ok());
+
+        // SstFileWriter wants keys in order, and at least one.
+        let mut writer = SstFileWriter::create(&options, "/external/bad.sst").unwrap();
+        assert!(writer.put(&Slice::new(b"b"), &Slice::new(b"v")).ok());
+        assert!(writer.put(&Slice::new(b"a"), &Slice::new(b"v")).is_invalid_argument());
+        assert!(writer.put(&Slice::new(b"b"), &Slice::new(b"v")).is_invalid_argument());
+        drop(writer);
+        let mut writer = SstFileWriter::create(&options, "/external/empty.sst").unwrap();
+        assert!(writer.finish().is_invalid_argument());
+
+        let expect_rejected = |paths: &[&str], msg: &str| {
+            let s = db.ingest_external_file(paths, true);
+            assert!(s.is_invalid_argument(), "{}", s.to_string());
+            assert!(s.to_string().contains(msg), "{}", s.to_string());
+            for path in paths {
+                assert!(env.file_exists(path));
+            }
+        };
+        write_external_file(&options, "/external/tables.sst", "key", 0..20);
+        expect_rejected(&["/external/tables.sst"], "overlaps keys in the database");
+        write_external_file(&options, "/external/mem.sst", "mem", 5..6);
+        expect_rejected(&["/external/mem.sst"], "overlaps keys in the database");
+        write_external_file(&options, "/external/x1.sst", "x", 0..10);
+        write_external_file(&options, "/external/x2.sst", "x", 9..20);
+        expect_rejected(&["/external/x1.sst", "/external/x2.sst"], "overlaps /external/x1.sst");
+        let reverse = Options { comparator: Arc::new(ReverseComparator), ..options.clone() };
+        write_external_file(&reverse, "/external/reverse.sst", "y", 0..1);
+        expect_rejected(&["/external/reverse.sst"], "was built with comparator test.ReverseComparator");
+
+        // A file of the DB itself has no comparator name.
+        let table = env.get_children(DBNAME).unwrap().into_iter()
+            .find(|name| matches!(parse_file_name(name), Some((_, FileType::TableFile)))).unwrap();
+        let table = format!("{}/{}", DBNAME, table);
+        assert!(copy_file(env.clone(), &table, "/external/table.ldb", env.get_file_size(&table).unwrap()).ok());
+        expect_rejected(&["/external/table.ldb"], "does not name its comparator");
+
+        // A failed ingest puts moved files back.
+        {
+            let _failure = sync_point::fail("db:ingest:before-install", Status::io_error("injected", ""));
+            assert!(db.ingest_external_file(&["/external/x1.sst"], true).is_io_error());
+        }
+        assert!(env.file_exists("/external/x1.sst"));
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"x0001")).unwrap_err().is_not_found());
+        assert!(db.ingest_external_file(&["/external/x1.sst"], true).ok());
+        assert_eq!(b"value1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"x0001")).unwrap());
+    }
+
+    /// Shares the files of "base_" but not its locks, like a second machine
+    /// attached to the same storage.
+    struct SharedStorageEnv {
+        base_: Arc<dyn Env>,
+    }
+
+    impl Env for SharedStorageEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { Ok(FileLock::new(fname)) }
+        fn unlock_file(&self, _lock: FileLock) -> Status { Status::new_ok() }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    #[test]
+    fn fencing_token_test() {
+        let env = new_mem_env();
+        let options_with_token = |env: Arc<dyn Env>, token| Options { fencing_token: Some(token), ..options_with_env(env) };
+        let old = DB::open(&options_with_token(env.clone(), 5), DBNAME).unwrap();
+        assert_eq!(features::FENCING_TOKENS, old.mutex_.lock().unwrap().versions_.required_features() & features::FENCING_TOKENS);
+        assert!(old.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+
+        // A newer instance takes over; the old one stops writing at its
+        // next flush, but can still be read from.
+        let new = DB::open(&options_with_token(Arc::new(SharedStorageEnv { base_: env.clone() }), 6), DBNAME).unwrap();
+        assert_eq!(b"v1".to_vec(), new.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        let s = old.flush();
+        assert!(s.is_invalid_argument(), "{}", s.to_string());
+        assert_eq!("Invalid argument: fenced off by fencing token 6: this instance has 5", s.to_string());
+        assert!(old.put(&WriteOptions::default(), &Slice::new(b"b"), &Slice::new(b"v2")).is_invalid_argument());
+        assert!(old.compact_range(None, None).is_invalid_argument());
+        assert_eq!(b"v1".to_vec(), old.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        assert!(new.put(&WriteOptions::default(), &Slice::new(b"b"), &Slice::new(b"v3")).ok());
+        assert!(new.flush().ok());
+
+        // An open with an older token fails right away.
+        let stale = Arc::new(SharedStorageEnv { base_: env.clone() });
+        let s = DB::open(&options_with_token(stale.clone(), 4), DBNAME).err().unwrap();
+        assert_eq!("Invalid argument: fencing token 4 is older than 6: the database was opened by a newer instance", s.to_string());
+        drop(old);
+        drop(new);
+
+        // The token is also kept in the MANIFEST.
+        assert!(env.remove_file(&fence_file_name(DBNAME)).ok());
+        assert!(DB::open(&options_with_token(env.clone(), 5), DBNAME).err().unwrap().is_invalid_argument());
+        let db = DB::open(&options_with_token(env.clone(), 6), DBNAME).unwrap();
+        assert_eq!(6, read_fence_file(env.clone(), DBNAME).unwrap());
+        assert_eq!(b"v3".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"b")).unwrap());
+        drop(db);
+
+        // Builds that cannot check the token refuse the database.
+        features::SUPPORTED_OVERRIDE.with(|o| o.set(Some(features::supported_features() & !features::FENCING_TOKENS)));
+        let s = DB::open(&options_with_env(env.clone()), DBNAME).err().unwrap();
+        features::SUPPORTED_OVERRIDE.with(|o| o.set(None));
+        assert!(s.is_not_supported_error(), "{}", s.to_string());
+        assert!(s.to_string().starts_with("Not implemented: this database requires: fencing token support"), "{}", s.to_string());
+
+        // Without a token there is no fencing at all.
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"c"), &Slice::new(b"v4")).ok());
+        assert!(db.flush().ok());
+
+        // And nothing to require of a fresh database.
+        drop(db);
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        assert_eq!(0, db.mutex_.lock().unwrap().versions_.required_features() & features::FENCING_TOKENS);
+    }
+
+    #[test]
+    fn checkpoint_copy_test() {
+        // FailSyncEnv can not link files, so tables are copied.
+        let env = FailSyncEnv::new(".log");
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        fill_for_checkpoint(&db);
+        let before = full_scan(&db);
+        assert!(db.create_checkpoint("/checkpoint").ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"key000"), &Slice::new(b"v2")).ok());
+        assert!(db.compact_range(None, None).ok());
+        drop(db);
+
+        let checkpoint = DB::open(&options, "/checkpoint").unwrap();
+        assert_eq!(before, full_scan(&checkpoint));
+    }
+
+    #[test]
+    fn checkpoint_failure_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        fill_for_checkpoint(&db);
+        {
+            let _failure = sync_point::fail("checkpoint:before-current", Status::io_error("injected", ""));
+            assert!(db.create_checkpoint("/checkpoint").is_io_error());
+        }
+
+        // Nothing is left behind, so the directory is not mistaken for a DB.
+        assert!(env.get_children("/checkpoint").unwrap().is_empty());
+        options.create_if_missing = false;
+        assert!(DB::open(&options, "/checkpoint").is_err());
+
+        // The DB is unharmed and can still be checkpointed.
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"later"), &Slice::new(b"v")).ok());
+        assert!(db.create_checkpoint("/checkpoint").ok());
+        let checkpoint = DB::open(&options, "/checkpoint").unwrap();
+        assert_eq!(full_scan(&db), full_scan(&checkpoint));
+    }
+
+    #[test]
+    fn paranoid_checks_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        let wo = WriteOptions::default();
+        let fname = {
+            let db = DB::open(&options, DBNAME).unwrap();
+            // The second record fills the first log block exactly, so that
+            // the third one is left intact when the second is dropped.
+            assert!(db.put(&wo, &Slice::new(b"key0"), &Slice::new(b"v")).ok());
+            assert!(db.put(&wo, &Slice::new(b"key1"), &Slice::new(&[b'v'; 32713])).ok());
+            assert!(db.put(&wo, &Slice::new(b"key2"), &Slice::new(b"v")).ok());
+            let logfile_number = db.mutex_.lock().unwrap().logfile_number_;
+            log_file_name(DBNAME, logfile_number)
+        };
+        let mut contents = env.new_sequential_file(&fname).unwrap().read(usize::MAX).unwrap();
+        assert_eq!(BLOCK_SIZE + 27 + 41, contents.len());     // With the trailer
+        contents[100] ^= 0x80;
+        assert!(write_string_to_file_sync(env.clone(), &Slice::new(&contents), &fname).ok());
+
+        options.paranoid_checks = true;
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        assert!(s.is_corruption() && s.to_string().contains("checksum mismatch"), "{}", s.to_string());
+
+        options.paranoid_checks = false;
+        options.wal_recovery_mode = WalRecoveryMode::TolerateCorruptedTail;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let keys: Vec<_> = full_scan(&db).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(vec!["key0", "key2"], keys);
+
+        // A failed log write fails every later one
+        for paranoid_checks in [false, true] {
+            let db = DB::open(&Options { paranoid_checks, ..options_with_env(new_mem_env()) }, DBNAME).unwrap();
+            {
+                let _fail = sync_point::fail("db:write:before-log", Status::io_error("injected", ""));
+                assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v")).is_io_error());
+            }
+            assert_eq!(!paranoid_checks, db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v")).ok());
+        }
+    }
+
+    #[test]
+    fn verify_checksums_test() {
+        let env = new_mem_env();
+        // Cached blocks are not verified again.
+        let options = Options { no_block_cache: true, ..options_with_env(env.clone()) };
+        let value = vec![b'v'; 100];
+        let fname = {
+            let db = DB::open(&options, DBNAME).unwrap();
+            for key in ["a", "b", "c"] {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(&value)).ok());
+            }
+            assert!(db.flush().ok());
+            let current = db.mutex_.lock().unwrap().versions_.current();
+            let f = (0..NUM_LEVELS).flat_map(|level| current.files(level)).next().unwrap().clone();
+            table_file_name(DBNAME, f.number)
+        };
+
+        // Flip a byte in the value of "a", in the first data block
+        let size = env.get_file_size(&fname).unwrap() as usize;
+        let mut contents = env.new_random_access_file(&fname).unwrap().read(0, size).unwrap();
+        contents[50] ^= 0x01;
+        assert!(write_string_to_file_sync(env.clone(), &Slice::new(&contents), &fname).ok());
+
+        let db = DB::open(&options, DBNAME).unwrap();
+        let corrupted = db.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap();
+        assert_eq!(value.len(), corrupted.len());
+        assert_ne!(value, corrupted);
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        assert!(iter.valid() && iter.status().ok());
+
+        let verify = ReadOptions { verify_checksums: true, ..ReadOptions::new() };
+        for key in ["a", "c"] {
+            let s = db.get(&verify, &Slice::new(key.as_bytes())).err().unwrap();
+            assert!(s.is_corruption() && s.to_string().contains("checksum mismatch"), "{}", s.to_string());
+        }
+        iter = db.new_iterator(&verify);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().is_corruption());
+        drop(iter);
+        drop(db);
+
+        // A sample of the reads verifies checksums unasked, spread evenly.
+        let db = DB::open(&Options { checksum_verification_sample_rate: 0.25, ..options.clone() }, DBNAME).unwrap();
+        let verified: Vec<bool> = (0..8).map(|_| db.get(&ReadOptions::new(), &Slice::new(b"a")).is_err()).collect();
+        assert_eq!(vec![false, false, false, true, false, false, false, true], verified);
+        assert!(db.set_options(&[("checksum_verification_sample_rate", "1")]).ok());
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        assert!(iter.status().is_corruption());
+        assert!(db.set_options(&[("checksum_verification_sample_rate", "0")]).ok());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"a")).is_ok());
+    }
+
+    #[test]
+    fn verify_table_checksums_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        put_values(&db, "a", 100, 100);
+        assert!(db.flush().ok());
+        put_values(&db, "b", 100, 100);
+        assert!(db.flush().ok());
+        assert!(db.compact_range(Some(&Slice::new(b"b")), None).ok());
+        write_external_file(&options, "/external/c.sst", "c", 0..100);
+        assert!(db.ingest_external_file(&["/external/c.sst"], false).ok());
+        let table_numbers = || {
+            let current = db.mutex_.lock().unwrap().versions_.current();
+            let mut numbers: Vec<u64> = (0..NUM_LEVELS).flat_map(|level| current.files(level).iter().map(|f| f.number).collect::<Vec<_>>()).collect();
+            numbers.sort_unstable();
+            numbers
+        };
+        let written = table_numbers();
+        assert_eq!(3, written.len());
+
+        // Tables recorded without a checksum, as older versions did, are
+        // reported but not failed.
+        add_table_without_properties(&db, 4, "old", 10, 100);
+        let old: Vec<u64> = table_numbers().into_iter().filter(|n| !written.contains(n)).collect();
+        let mut report = db.verify_table_checksums().unwrap();
+        report.verified.sort_unstable();
+        assert_eq!(TableChecksumReport { verified: written.clone(), unchecked: old.clone(), mismatched: vec![] }, report);
+        drop(db);
+
+        // The checksums survive a reopen.  Bytes appended to a table do not
+        // disturb reads, but no longer match its checksum.
+        let db = DB::open(&options, DBNAME).unwrap();
+        let fname = table_file_name(DBNAME, written[0]);
+        let size = env.get_file_size(&fname).unwrap() as usize;
+        let mut contents = env.new_random_access_file(&fname).unwrap().read(0, size).unwrap();
+        contents.extend_from_slice(b"garbage");
+        assert!(write_string_to_file_sync(env.clone(), &Slice::new(&contents), &fname).ok());
+        for key in ["a00000", "b00099", "c0099", "old00000"] {
+            assert!(db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).is_ok(), "{}", key);
+        }
+        let mut report = db.verify_table_checksums().unwrap();
+        report.verified.sort_unstable();
+        assert_eq!(TableChecksumReport { verified: written[1..].to_vec(), unchecked: old, mismatched: vec![written[0]] }, report);
+    }
+
+    #[test]
+    fn set_options_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:06}", i);
+        let value = vec![b'v'; 1000];
+
+        // Lowered, write_buffer_size cuts the memtable at the new size.
+        assert!(db.set_options(&[("write_buffer_size", &MIN_WRITE_BUFFER_SIZE.to_string())]).ok());
+        let mut writes = 0;
+        while files_per_level(&db).iter().sum::<usize>() == 0 {
+            assert!(db.put(&wo, &Slice::new(key(writes).as_bytes()), &Slice::new(&value)).ok());
+            writes += 1;
+        }
+        assert!((MIN_WRITE_BUFFER_SIZE / 2000..2 * MIN_WRITE_BUFFER_SIZE / 1000).contains(&writes), "{}", writes);
+
+        // One bad entry among good ones changes nothing.
+        let before = db.mutex_.lock().unwrap().mutable_options_.clone();
+        let s = db.set_options(&[("l0_stop_writes_trigger", "30"), ("tombstone_compaction_ratio", "2"),
+                                 ("max_background_error_retries", "1")]);
+        assert!(s.is_invalid_argument() && s.to_string().contains("tombstone_compaction_ratio"), "{}", s.to_string());
+        assert_eq!(before, db.mutex_.lock().unwrap().mutable_options_);
+
+        // Readers and writers running alongside set_options() see each
+        // change whole.
+        let a = [("l0_slowdown_writes_trigger", "4"), ("l0_stop_writes_trigger", "8"),
+                 ("compaction_rate_limit_bytes_per_sec", "0"), ("checksum_verification_sample_rate", "0")];
+        let b = [("l0_slowdown_writes_trigger", "16"), ("l0_stop_writes_trigger", "32"),
+                 ("compaction_rate_limit_bytes_per_sec", "1000000000"), ("checksum_verification_sample_rate", "1")];
+        assert!(db.set_options(&a).ok());
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..200 {
+                    assert!(db.set_options(if i % 2 == 0 { &b } else { &a }).ok());
+                }
+                done.store(true, atomic::Ordering::SeqCst);
+            });
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mut i = 0;
+                    while !done.load(atomic::Ordering::SeqCst) {
+                        let seen = {
+                            let state = db.mutex_.lock().unwrap();
+                            let opts = &state.mutable_options_;
+                            (opts.l0_slowdown_writes_trigger, opts.l0_stop_writes_trigger,
+                             opts.compaction_rate_limit_bytes_per_sec, opts.checksum_verification_sample_rate)
+                        };
+                        assert!(seen == (4, 8, 0, 0.0) || seen == (16, 32, 1_000_000_000, 1.0), "{:?}", seen);
+                        assert_eq!(value, db.get(&ReadOptions::new(), &Slice::new(key(i % writes).as_bytes())).unwrap());
+                        i += 1;
+                    }
+                });
+            }
+            scope.spawn(|| {
+                let mut i = writes;
+                while !done.load(atomic::Ordering::SeqCst) {
+                    assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(&value)).ok());
+                    i += 1;
+                }
+            });
+        });
+        // The last change made was back to "a".
+        let state = db.mutex_.lock().unwrap();
+        assert_eq!((4, 8), (state.mutable_options_.l0_slowdown_writes_trigger, state.mutable_options_.l0_stop_writes_trigger));
+    }
+
+    #[test]
+    fn compaction_rate_limit_test() {
+        let (env, _, db) = open_slow_db(2000);
+        // Rewrite every key, and time the compaction that merges the new
+        // table with the old ones on the mock clock.
+        let compaction = |db: &DB, round: usize| {
+            for i in 0..2000 {
+                let key = format!("key{:04}", i);
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(format!("value{}-{}", i, round).as_bytes())).ok());
+            }
+            assert!(db.flush().ok());
+            let written = db.compaction_stats().iter().map(|stats| stats.bytes_written).sum::<u64>();
+            let start = env.clock_.load(atomic::Ordering::SeqCst);
+            assert!(db.compact_range(None, None).ok());
+            (db.compaction_stats().iter().map(|stats| stats.bytes_written).sum::<u64>() - written,
+             env.clock_.load(atomic::Ordering::SeqCst) - start)
+        };
+        let (unlimited_bytes, unlimited_micros) = compaction(&db, 0);
+        assert!(unlimited_bytes > 0);
+
+        assert!(db.set_options(&[("compaction_rate_limit_bytes_per_sec", "100000")]).ok());
+        let (bytes, micros) = compaction(&db, 1);
+        assert!(micros as f64 >= bytes as f64 * 10.0 * 0.99, "{} bytes in {} micros", bytes, micros);
+        assert!(micros > unlimited_micros + 100_000, "{} vs {}", micros, unlimited_micros);
+    }
+
+    #[test]
+    fn tombstone_compaction_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:04}", i);
+        for i in 0..1000 {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(b"value")).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        // A table of 60% deletion markers over the data, and 40% new keys
+        for i in 0..600 {
+            assert!(db.delete(&wo, &Slice::new(key(i).as_bytes())).ok());
+        }
+        for i in 1000..1400 {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(b"value")).ok());
+        }
+        assert!(db.flush().ok());
+        let deletions = |db: &DB| -> u64 {
+            let current = db.mutex_.lock().unwrap().versions_.current();
+            (0..NUM_LEVELS).flat_map(|level| current.files(level))
+                .map(|f| db.table_cache_.find_table(&ReadOptions::new(), f.number, f.file_size).unwrap().properties().unwrap().num_deletions)
+                .sum()
+        };
+        let levels = files_per_level(&db);
+        assert_eq!(600, deletions(&db));
+
+        // Below the ratio, the table is left alone.
+        assert!(db.set_options(&[("tombstone_compaction_ratio", "0.7")]).ok());
+        assert_eq!((levels.clone(), 600), (files_per_level(&db), deletions(&db)));
+
+        // At it, the deletion markers are compacted down until they meet
+        // the data they hide, and both are dropped.
+        assert!(db.set_options(&[("tombstone_compaction_ratio", "0.5")]).ok());
+        assert_eq!(0, deletions(&db));
+        assert_eq!(1, files_per_level(&db).iter().sum::<usize>(), "{:?}", files_per_level(&db));
+        for i in 0..1400 {
+            let result = db.get(&ReadOptions::new(), &Slice::new(key(i).as_bytes()));
+            assert_eq!(i >= 600, result.is_ok(), "{}", i);
+        }
+    }
+
+    #[test]
+    fn housekeeping_compaction_test() {
+        let env = new_mem_env();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:04}", i);
+        let value = |i: usize| format!("{:0>200}", i);
+        // The last non-empty level, its file count and its bytes
+        let bottom = |db: &DB| -> (i32, usize, u64) {
+            let current = db.mutex_.lock().unwrap().versions_.current();
+            let level = (0..NUM_LEVELS).rev().find(|&level| !current.files(level).is_empty()).unwrap();
+            let files = current.files(level);
+            (level, files.len(), files.iter().map(|f| f.file_size).sum())
+        };
+        let (level, files, bytes, expected) = {
+            // Many small files in the last level, then level-0 files over
+            // a few of their keys, which no compaction has picked up yet.
+            let options = Options { max_file_size: 4096, ..options_with_env(env.clone()) };
+            let db = DB::open(&options, DBNAME).unwrap();
+            for round in 0..2 {
+                for i in 0..400 {
+                    assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value(i + round).as_bytes())).ok());
+                }
+                assert!(db.flush().ok());
+            }
+            assert!(db.compact_range(None, None).ok());
+            while files_per_level(&db)[0] < 4 {
+                assert!(db.put(&wo, &Slice::new(key(0).as_bytes()), &Slice::new(b"new")).ok());
+                let mut state = db.mutex_.lock().unwrap();
+                assert!(db.flush_memtable(&mut state).ok());
+            }
+            let mut iter = db.new_iterator(&ReadOptions::new());
+            iter.seek_to_first();
+            let (level, files, bytes) = bottom(&db);
+            (level, files, bytes, scan(iter.as_mut(), true))
+        };
+        assert!(level > 0 && files > 10, "{} {}", level, files);
+
+        let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+        let options = Options { max_bottommost_files: 4, info_log: Some(logger.clone()), ..options_with_env(env.clone()) };
+        let db = DB::open(&options, DBNAME).unwrap();
+
+        // The level-0 files are compacted first, then the small files are
+        // merged in place.  The bytes only shrink by the index blocks and
+        // footers of the files merged away.
+        let messages: Vec<String> = logger.messages_.lock().unwrap().iter().filter(|m| m.starts_with("Compacting ")).cloned().collect();
+        let in_place = format!("@{} + 0@{} files", level, level);
+        assert!(messages[0].starts_with("Compacting 4@0 +"), "{:?}", messages);
+        assert!(messages[1..].iter().all(|m| m.ends_with(&in_place)), "{:?}", messages);
+        assert_eq!(messages.len() as u64 - 1, db.compaction_stats()[level as usize].housekeeping_compactions);
+        let (new_level, new_files, new_bytes) = bottom(&db);
+        assert_eq!((level, 1), (new_level, new_files));
+        assert!(new_bytes <= bytes && new_bytes > bytes * 9 / 10, "{} {}", new_bytes, bytes);
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        assert_eq!(expected, scan(iter.as_mut(), true));
+    }
+
+    #[test]
+    fn background_error_retries_test() {
+        let env = FailSyncEnv::new(".ldb");
+        let mut options = options_with_env(env.clone());
+        options.write_buffer_size = MIN_WRITE_BUFFER_SIZE;
+        options.max_background_error_retries = 2;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let value = vec![b'v'; 1000];
+        let fill_memtable = |db: &DB, round: usize| {
+            (0..MIN_WRITE_BUFFER_SIZE / 1000 + 10).map(|i| db.put(&WriteOptions::default(), &Slice::new(format!("key{}-{:04}", round, i).as_bytes()), &Slice::new(&value)))
+                .find(|s| !s.ok()).unwrap_or_else(Status::new_ok)
+        };
+
+        // The background flush fails twice, then goes through on its
+        // second retry.
+        env.fail_.store(true, atomic::Ordering::SeqCst);
+        let attempts = Arc::new(AtomicU32::new(0));
+        {
+            let (env, attempts) = (env.clone(), attempts.clone());
+            let _recover = sync_point::activate("db:background-compaction:start", move || {
+                if attempts.fetch_add(1, atomic::Ordering::SeqCst) == 2 {
+                    env.fail_.store(false, atomic::Ordering::SeqCst);
+                }
+                None
+            });
+            assert!(fill_memtable(&db, 0).ok());
+        }
+        assert_eq!(3, attempts.load(atomic::Ordering::SeqCst));
+        assert!(db.mutex_.lock().unwrap().bg_error_.ok());
+        assert_eq!(1, files_per_level(&db).iter().sum::<usize>());
+        assert_eq!(value, db.get(&ReadOptions::new(), &Slice::new(b"key0-0000")).unwrap());
+
+        // The count starts over after a success; out of retries, the
+        // error sticks.
+        env.fail_.store(true, atomic::Ordering::SeqCst);
+        let s = fill_memtable(&db, 1);
+        assert!(s.is_io_error(), "{}", s.to_string());
+        assert_eq!(s.to_string(), db.mutex_.lock().unwrap().bg_error_.to_string());
+        assert_eq!(2, db.mutex_.lock().unwrap().background_error_retries_);
+        env.fail_.store(false, atomic::Ordering::SeqCst);
+        assert_eq!(s.to_string(), db.put(&WriteOptions::default(), &Slice::new(b"k"), &Slice::new(b"v")).to_string());
+    }
+
+    /// Counts the lookups into a cache, and how many of them hit.
+    struct CountingCache {
+        base_: Arc<dyn Cache>,
+        lookups_: AtomicU64,
+        hits_: AtomicU64,
+    }
+
+    impl CountingCache {
+        /// Return the fraction of the lookups since the last call that hit.
+        fn take_hit_rate(&self) -> f64 {
+            let lookups = self.lookups_.swap(0, atomic::Ordering::SeqCst);
+            self.hits_.swap(0, atomic::Ordering::SeqCst) as f64 / lookups as f64
+        }
+    }
+
+    impl Cache for CountingCache {
+        fn insert(&self, key: &[u8], value: CacheValue, charge: usize) { self.base_.insert(key, value, charge) }
+        fn lookup(&self, key: &[u8]) -> Option<CacheValue> {
+            self.lookups_.fetch_add(1, atomic::Ordering::SeqCst);
+            let value = self.base_.lookup(key);
+            if value.is_some() {
+                self.hits_.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            value
+        }
+        fn erase(&self, key: &[u8]) { self.base_.erase(key) }
+        fn new_id(&self) -> u64 { self.base_.new_id() }
+        fn total_charge(&self) -> usize { self.base_.total_charge() }
+    }
+
+    #[test]
+    fn fill_cache_test() {
+        let cache = Arc::new(CountingCache { base_: new_lru_cache(4096), lookups_: AtomicU64::new(0), hits_: AtomicU64::new(0) });
+        let mut options = options_with_env(new_mem_env());
+        options.block_size = MIN_BLOCK_SIZE;
+        options.block_cache = Some(cache.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..1000 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:04}", i).as_bytes()), &Slice::new(&[b'v'; 100])).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        let hot_workload = || {
+            cache.take_hit_rate();
+            for _ in 0..10 {
+                for i in 0..10 {
+                    assert!(db.get(&ReadOptions::new(), &Slice::new(format!("key{:04}", i).as_bytes())).is_ok());
+                }
+            }
+            cache.take_hit_rate()
+        };
+        let scan = |fill_cache: bool| {
+            let mut iter = db.new_iterator(&ReadOptions { fill_cache, ..ReadOptions::new() });
+            iter.seek_to_first();
+            assert_eq!(1000, scan(iter.as_mut(), true).len());
+        };
+
+        // The hot keys fit in the cache
+        hot_workload();
+        let hot_hit_rate = hot_workload();
+        assert_eq!(1.0, hot_hit_rate);
+        let charge = cache.total_charge();
+
+        // A scan that does not fill the cache leaves the hot blocks alone
+        scan(false);
+        assert_eq!(charge, cache.total_charge());
+        assert_eq!(hot_hit_rate, hot_workload());
+        let cold = ReadOptions { fill_cache: false, ..ReadOptions::new() };
+        assert!(db.get(&cold, &Slice::new(b"key0500")).is_ok());
+        assert_eq!(charge, cache.total_charge());
+        assert_eq!(hot_hit_rate, hot_workload());
+
+        // Filling it evicts them
+        scan(true);
+        assert!(hot_workload() < hot_hit_rate);
+    }
+
+    #[test]
+    fn recover_flushes_logs_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        let key = |i: usize| format!("key{:06}", i);
+        let value = |i: usize| format!("{:0>1000}", i);
+        {
+            // Everything stays in the memtable, and so in one log.
+            let db = DB::open(&options, DBNAME).unwrap();
+            for i in 0..500 {
+                assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+            }
+            assert!(db.delete(&wo, &Slice::new(key(7).as_bytes())).ok());
+            assert!(files_per_level(&db).iter().all(|&n| n == 0));
+        }
+
+        options.write_buffer_size = 64 * 1024;
+        let logs = || env.get_children(DBNAME).unwrap().iter()
+            .filter(|name| matches!(parse_file_name(name), Some((_, FileType::LogFile))))
+            .count();
+        for round in 0..2 {
+            REPLAY_PEAK_MEMTABLE.with(|peak| peak.set(0));
+            let db = DB::open(&options, DBNAME).unwrap();
+            // The log was replayed into level-0 tables and deleted.  It
+            // holds more than a write buffer's worth, so there were enough
+            // tables for them to be compacted out of level-0 right away.
+            assert_eq!(0, files_per_level(&db)[0], "{:?}", files_per_level(&db));
+            assert!(files_per_level(&db).iter().sum::<usize>() > 0, "{:?}", files_per_level(&db));
+            assert_eq!(1, logs());
+            assert_eq!(501, db.mutex_.lock().unwrap().versions_.last_sequence());
+            // The second time round, only the empty log left by the first.
+            let flushes: usize = db.get_property("leveldb.recovery-flushes").unwrap().parse().unwrap();
+            let peak = REPLAY_PEAK_MEMTABLE.with(|peak| peak.get());
+            if round == 0 {
+                assert!(flushes >= 500 * 1000 / options.write_buffer_size, "{}", flushes);
+                // No memtable grew past a write buffer by more than the
+                // one record that filled it.
+                assert!(peak > options.write_buffer_size && peak < options.write_buffer_size + 4096, "{}", peak);
+                // Each flush added a level-0 table.
+                let manifest = descriptor_file_name(DBNAME, db.mutex_.lock().unwrap().versions_.manifest_file_number());
+                let mut reader = Reader::new(env.new_sequential_file(&manifest).unwrap(), None, true, 0);
+                let mut level0_files = 0;
+                while let Some(record) = reader.read_record() {
+                    let edit = VersionEdit::decode_from(&Slice::new(&record)).unwrap();
+                    level0_files += edit.new_files_.iter().filter(|(level, _)| *level == 0).count();
+                }
+                assert_eq!(flushes, level0_files);
+                assert!(level0_files > 1);
+            } else {
+                assert_eq!((0, 0), (flushes, peak));
+            }
+            for i in 0..500 {
+                match db.get(&ro, &Slice::new(key(i).as_bytes())) {
+                    Ok(v) => assert_eq!(value(i).into_bytes(), v),
+                    Err(s) => assert!(i == 7 && s.is_not_found()),
+                }
+            }
+        }
+    }
+
+    /// Stand-in for a log shipper: read back every batch "db" has logged
+    /// since it was opened, in order.
+    fn logged_batches(env: &Arc<dyn Env>, db: &DB, dbname: &str) -> Vec<WriteBatch> {
+        let file = env.new_sequential_file(&log_file_name(dbname, db.mutex_.lock().unwrap().logfile_number_)).unwrap();
+        let mut reader = Reader::new(file, None, true, 0);
+        let mut batches = Vec::new();
+        while let Some(record) = reader.read_record() {
+            let mut batch = WriteBatch::new();
+            batch.set_contents(&Slice::new(&record));
+            batches.push(batch);
+        }
+        batches
+    }
+
+    fn write_primary(db: &DB) {
+        let wo = WriteOptions::default();
+        for i in 0..100 {
+            let key = format!("key{:03}", i % 40);
+            let value = format!("value{}", i);
+            assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes())).ok());
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete(&Slice::new(b"key003"));
+        batch.put(&Slice::new(b"key100"), &Slice::new(b"batched"));
+        batch.delete(&Slice::new(b"key017"));
+        assert!(db.write(&wo, batch).ok());
+        assert!(db.write(&wo, WriteBatch::new()).ok());
+        assert!(db.delete(&wo, &Slice::new(b"key021")).ok());
+    }
+
+    fn full_scan(db: &DB) -> Vec<(String, String)> {
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        scan(iter.as_mut(), true)
+    }
+
+    #[test]
+    fn apply_replicated_batch_test() {
+        let primary_env = new_mem_env();
+        let primary = DB::open(&options_with_env(primary_env.clone()), DBNAME).unwrap();
+        write_primary(&primary);
+        let batches = logged_batches(&primary_env, &primary, DBNAME);
+        assert_eq!(103, batches.len());
+
+        let mut options = options_with_env(new_mem_env());
+        options.replica_mode = true;
+        let replica = DB::open(&options, DBNAME).unwrap();
+        // Local writes could fork the sequence, so they are rejected.
+        let wo = WriteOptions::default();
+        assert!(replica.put(&wo, &Slice::new(b"foo"), &Slice::new(b"bar")).is_not_supported_error());
+        assert!(replica.delete(&wo, &Slice::new(b"foo")).is_not_supported_error());
+        assert!(primary.apply_replicated_batch(&batches[0], 1).is_not_supported_error());
+
+        for (i, batch) in batches.iter().enumerate() {
+            assert!(replica.apply_replicated_batch(batch, batch.sequence()).ok());
+            if i == 50 {
+                // Neither a batch from the past nor one from the future
+                // is applied; the error names the sequence to refetch from.
+                let expected = replica.last_applied_sequence() + 1;
+                for bad in [&batches[i], &batches[i + 2]] {
+                    let s = replica.apply_replicated_batch(bad, bad.sequence());
+                    assert!(s.is_gap(), "{}", s.to_string());
+                    assert_eq!(format!("Gap: expected sequence: {}", expected), s.to_string());
+                }
+                assert_eq!(expected, batches[i + 1].sequence());
+            }
+        }
+        assert_eq!(primary.last_applied_sequence(), replica.last_applied_sequence());
+        assert_eq!(full_scan(&primary), full_scan(&replica));
+    }
+
+    #[test]
+    fn apply_replicated_batch_recover_test() {
+        let primary_env = new_mem_env();
+        let primary = DB::open(&options_with_env(primary_env.clone()), DBNAME).unwrap();
+        write_primary(&primary);
+        let batches = logged_batches(&primary_env, &primary, DBNAME);
+
+        let mut options = options_with_env(new_mem_env());
+        options.replica_mode = true;
+        {
+            let replica = DB::open(&options, DBNAME).unwrap();
+            for batch in &batches[..60] {
+                assert!(replica.apply_replicated_batch(batch, batch.sequence()).ok());
+            }
+            // Crash without any further cleanup.
+        }
+
+        let replica = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(batches[60].sequence() - 1, replica.last_applied_sequence());
+        let s = replica.apply_replicated_batch(&batches[0], 1);
+        assert_eq!(format!("Gap: expected sequence: {}", batches[60].sequence()), s.to_string());
+        // Resume shipping from the first batch the replica has not seen.
+        let next = replica.last_applied_sequence() + 1;
+        for batch in batches.iter().skip_while(|b| b.sequence() < next) {
+            assert!(replica.apply_replicated_batch(batch, batch.sequence()).ok());
+        }
+        assert_eq!(primary.last_applied_sequence(), replica.last_applied_sequence());
+        assert_eq!(full_scan(&primary), full_scan(&replica));
+    }
+
+    #[test]
+    fn error_if_exists_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        drop(DB::open(&options, DBNAME).unwrap());
+        options.error_if_exists = true;
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        assert!(s.is_invalid_argument());
+        assert!(s.to_string().contains("exists"));
+    }
+
+    #[test]
+    fn open_existence_options_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        let wo = WriteOptions::default();
+
+        // Missing, create_if_missing: created.
+        options.create_if_missing = true;
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        }
+        assert!(env.file_exists(&current_file_name(DBNAME)));
+
+        // Exists, !error_if_exists: opened with its contents.
+        options.create_if_missing = false;
+        options.error_if_exists = false;
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert_eq!(b"v1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"foo")).unwrap());
+        }
+
+        // Exists, error_if_exists: refused, and left intact.
+        options.error_if_exists = true;
+        assert!(DB::open(&options, DBNAME).err().unwrap().is_invalid_argument());
+        options.error_if_exists = false;
+        assert!(DB::open(&options, DBNAME).is_ok());
+
+        // Missing, !create_if_missing: refused without creating anything.
+        let s = DB::open(&options, "/other").err().unwrap();
+        assert!(s.is_invalid_argument());
+        assert!(!env.file_exists(&current_file_name("/other")));
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        let s1 = db.get_snapshot();
+        assert!(db.put(&wo, &Slice::new(b"foo"), &Slice::new(b"v2")).ok());
+        assert!(db.put(&wo, &Slice::new(b"bar"), &Slice::new(b"v1")).ok());
+        let s2 = db.get_snapshot();
+        assert!(db.delete(&wo, &Slice::new(b"foo")).ok());
+
+        let mut ro = ReadOptions::new();
+        assert!(db.get(&ro, &Slice::new(b"foo")).unwrap_err().is_not_found());
+        ro.snapshot = Some(s1.clone());
+        assert_eq!(b"v1".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+        assert!(db.get(&ro, &Slice::new(b"bar")).unwrap_err().is_not_found());
+        ro.snapshot = Some(s2.clone());
+        assert_eq!(b"v2".to_vec(), db.get(&ro, &Slice::new(b"foo")).unwrap());
+        assert_eq!(b"v1".to_vec(), db.get(&ro, &Slice::new(b"bar")).unwrap());
+
+        assert!(Arc::ptr_eq(&s1, &db.mutex_.lock().unwrap().snapshots_.oldest()));
+        db.release_snapshot(s1);
+        assert!(Arc::ptr_eq(&s2, &db.mutex_.lock().unwrap().snapshots_.oldest()));
+        ro.snapshot = None;
+        db.release_snapshot(s2);
+        assert!(db.mutex_.lock().unwrap().snapshots_.empty());
+    }
+
+    fn scan(iter: &mut dyn Iterator, forward: bool) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        while iter.valid() {
+            result.push((iter.key().to_utf8_string().unwrap(), iter.value().to_utf8_string().unwrap()));
+            if forward { iter.next(); } else { iter.prev(); }
+        }
+        assert!(iter.status().ok());
+        result
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn read_tier_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let tiered = ReadOptions { read_tier: ReadTier::PersistedAndImmutable, ..ReadOptions::new() };
+        let get = |options: &ReadOptions, key: &str| {
+            let (result, sequence) = db.get_with_sequence(options, &Slice::new(key.as_bytes()));
+            (result.map(|v| String::from_utf8(v).unwrap()).map_err(|s| s.is_not_found()), sequence)
+        };
+        let tiered_scan = || {
+            let (mut iter, sequence) = db.new_iterator_with_sequence(&tiered);
+            iter.seek_to_first();
+            (scan(iter.as_mut(), true), sequence)
+        };
+
+        // Writes still in the memtable are invisible to tiered reads
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+        let s1 = db.get_snapshot();
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v1")).ok());
+        assert_eq!((Ok("v1".to_string()), 2), get(&ReadOptions::new(), "b"));
+        assert_eq!((Err(true), 0), get(&tiered, "a"));
+        assert_eq!((pairs(&[]), 0), tiered_scan());
+
+        // Once the memtable is switched they are read from imm_, while
+        // later writes are not seen
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.switch_memtable(&mut state).ok());
+        }
+        assert_eq!(2, db.mutex_.lock().unwrap().switch_sequence_);
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v2")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"b")).ok());
+        assert_eq!((Ok("v1".to_string()), 2), get(&tiered, "a"));
+        assert_eq!((Ok("v1".to_string()), 2), get(&tiered, "b"));
+        assert_eq!((pairs(&[("a", "v1"), ("b", "v1")]), 2), tiered_scan());
+        assert_eq!((Ok("v2".to_string()), 4), get(&ReadOptions::new(), "a"));
+        assert_eq!((Err(true), 4), get(&ReadOptions::new(), "b"));
+
+        // An older snapshot bounds the view further
+        let old = ReadOptions { snapshot: Some(s1.clone()), ..tiered.clone() };
+        assert_eq!((Err(true), 1), get(&old, "b"));
+
+        // After a flush, tiered reads see the tables, up to the switch
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.compact_mem_table(&mut state, true).ok());
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        assert!(db.mutex_.lock().unwrap().imm_.is_none());
+        assert_eq!((Ok("v2".to_string()), 4), get(&tiered, "a"));
+        assert_eq!((Err(true), 4), get(&tiered, "b"));
+
+        // Tiered reads never touch the active memtable: they work while it
+        // is taken out of the DB.
+        assert!(db.put(&wo, &Slice::new(b"c"), &Slice::new(b"v1")).ok());
+        let busy = db.mutex_.lock().unwrap().mem_.take();
+        assert_eq!((Err(true), 4), get(&tiered, "c"));
+        assert_eq!((pairs(&[("a", "v2")]), 4), tiered_scan());
+        db.mutex_.lock().unwrap().mem_ = busy;
+        assert_eq!((Ok("v1".to_string()), 5), get(&ReadOptions::new(), "c"));
+        db.release_snapshot(s1);
+    }
+
+    #[test]
+    fn iterator_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let ro = ReadOptions::new();
+        let mut iter = db.new_iterator(&ro);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+
+        let wo = WriteOptions::default();
+        for (k, v) in [("b", "v1"), ("d", "v1"), ("a", "v1"), ("c", "v1"), ("e", "v1"), ("aa", "v1")] {
+            assert!(db.put(&wo, &Slice::new(k.as_bytes()), &Slice::new(v.as_bytes())).ok());
+        }
+        // Move the first writes to imm_ so entries come from both memtables.
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            state.imm_ = state.mem_.replace(Arc::new(db.new_memtable()));
+        }
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v2")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"c")).ok());
+        assert!(db.put(&wo, &Slice::new(b"e"), &Slice::new(b"v2")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"e")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"zz")).ok());
+        assert!(db.put(&wo, &Slice::new(b"f"), &Slice::new(b"v2")).ok());
+        let snapshot = db.get_snapshot();
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v3")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"d")).ok());
+
+        let expected = pairs(&[("a", "v3"), ("aa", "v1"), ("b", "v2"), ("f", "v2")]);
+        let mut iter = db.new_iterator(&ro);
+        iter.seek_to_first();
+        assert_eq!(expected, scan(iter.as_mut(), true));
+        iter.seek_to_last();
+        assert_eq!(expected.iter().rev().cloned().collect::<Vec<_>>(), scan(iter.as_mut(), false));
+
+        iter.seek(&Slice::new(b"c"));
+        assert_eq!(pairs(&[("f", "v2")]), scan(iter.as_mut(), true));
+        iter.seek(&Slice::new(b"g"));
+        assert!(!iter.valid());
+
+        // Change directions in the middle of the sequence.
+        iter.seek(&Slice::new(b"b"));
+        iter.prev();
+        assert_eq!("aa", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        assert_eq!("b", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        assert_eq!("f", iter.key().to_utf8_string().unwrap());
+        iter.prev();
+        iter.prev();
+        assert_eq!("aa", iter.key().to_utf8_string().unwrap());
+        assert_eq!("v1", iter.value().to_utf8_string().unwrap());
+
+        // Reading at a snapshot ignores later writes.
+        let mut ro = ReadOptions::new();
+        ro.snapshot = Some(snapshot.clone());
+        let mut iter = db.new_iterator(&ro);
+        iter.seek_to_first();
+        let expected = pairs(&[("a", "v1"), ("aa", "v1"), ("b", "v2"), ("d", "v1"), ("f", "v2")]);
+        assert_eq!(expected, scan(iter.as_mut(), true));
+        iter.seek_to_last();
+        assert_eq!(expected.iter().rev().cloned().collect::<Vec<_>>(), scan(iter.as_mut(), false));
+        db.release_snapshot(snapshot);
+    }
+
+    #[test]
+    fn owned_iterator_test() {
+        let options = options_with_env(new_mem_env());
+        let db: Arc<DB> = DB::open(&options, DBNAME).unwrap().into();
+        let wo = WriteOptions::default();
+        for k in ["a", "b", "c"] {
+            assert!(db.put(&wo, &Slice::new(k.as_bytes()), &Slice::new(b"v1")).ok());
+        }
+        assert!(db.flush().ok());
+        let borrowed = db.new_iterator(&ReadOptions::new());
+        let range = db.range(&ReadOptions::new(), ..);
+        assert_eq!(2, db.live_iterators());
+        drop((borrowed, range));
+        let mut iter = db.new_owned_iterator(&ReadOptions::new());
+        assert_eq!(1, db.live_iterators());
+        let state = Arc::downgrade(&db.inner_);
+        drop(db);
+
+        // The iterator keeps the DB up after the last other handle on it
+        // is gone: the DB stays locked, and can still be written and
+        // compacted through the iterator.
+        assert!(DB::open(&options, DBNAME).err().unwrap().is_busy());
+        for k in ["a", "b", "c", "d"] {
+            assert!(iter.db().put(&wo, &Slice::new(k.as_bytes()), &Slice::new(b"v2")).ok());
+        }
+        assert!(iter.db().compact_range(None, None).ok());
+        let expected = pairs(&[("a", "v1"), ("b", "v1"), ("c", "v1")]);
+        iter.seek_to_first();
+        assert_eq!(expected, scan(iter.as_mut(), true));
+
+        // It can be moved to another thread, and the DB with it.
+        let iter = std::thread::spawn(move || {
+            iter.seek_to_last();
+            assert_eq!(expected.iter().rev().cloned().collect::<Vec<_>>(), scan(iter.as_mut(), false));
+            iter
+        }).join().unwrap();
+        assert!(!state.upgrade().unwrap().shutting_down_.load(atomic::Ordering::Acquire));
+        drop(iter);
+        assert!(state.upgrade().is_none());
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(b"v2".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"d")).unwrap());
+    }
+
+    #[test]
+    fn scan_page_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let put = |k: &str, v: &str| assert!(db.put(&wo, &Slice::new(k.as_bytes()), &Slice::new(v.as_bytes())).ok());
+        let delete = |k: &str| assert!(db.delete(&wo, &Slice::new(k.as_bytes())).ok());
+
+        // Tables under the memtable, each hiding some of the other's keys
+        for i in 0..20 {
+            put(&format!("k{:02}", i), "old");
+        }
+        assert!(db.flush().ok());
+        for i in (0..20).step_by(3) {
+            delete(&format!("k{:02}", i));
+        }
+        put("k05", "new");
+        assert!(db.flush().ok());
+        for i in (1..20).step_by(4) {
+            delete(&format!("k{:02}", i));
+        }
+        put("k00", "mem");
+        put("k99", "mem");
+        let expected = full_scan(&db);
+        assert!(expected.len() > 8);
+
+        let page = |start: Option<&[u8]>, limit: usize| {
+            let (entries, next) = db.scan_page(&ReadOptions::new(), start, limit).unwrap();
+            let entries: Vec<_> = entries.into_iter().map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())).collect();
+            (entries, next)
+        };
+        for limit in 1..=expected.len() + 1 {
+            let mut paged = Vec::new();
+            let (mut entries, mut next) = page(None, limit);
+            loop {
+                assert!(!entries.is_empty() && entries.len() <= limit);
+                paged.append(&mut entries);
+                match next {
+                    Some(start) => {
+                        assert_eq!(Some(start.clone()), expected.get(paged.len()).map(|(k, _)| k.clone().into_bytes()));
+                        (entries, next) = page(Some(&start), limit);
+                    },
+                    None => break,
+                }
+            }
+            assert_eq!(expected, paged);
+        }
+
+        // Starting at a deleted key, past the end, and with nothing to take
+        assert_eq!((pairs(&[("k04", "old")]), Some(b"k07".to_vec())), page(Some(b"k03"), 1));
+        assert_eq!((pairs(&[]), None), page(Some(b"z"), 5));
+        assert_eq!((pairs(&[]), Some(b"k00".to_vec())), page(None, 0));
+    }
+
+    #[test]
+    fn property_names_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let names = DB::property_names();
+        assert_eq!(NUM_LEVELS as usize + 12, names.len());
+        for name in &names {
+            assert!(db.get_property(name).is_some(), "{}", name);
+        }
+        for level in 0..NUM_LEVELS {
+            assert!(names.contains(&format!("leveldb.num-files-at-level{}", level).as_str()));
+        }
+        assert!(db.get_property(&format!("leveldb.num-files-at-level{}", NUM_LEVELS)).is_none());
+        assert!(db.get_property("leveldb.unknown").is_none());
+    }
+
+    #[test]
+    fn scan_test() {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let ro = ReadOptions::new();
+        let scan = |options: &ReadOptions, start: &str, end: &str, limit: usize| {
+            db.scan(options, &Slice::new(start.as_bytes()), &Slice::new(end.as_bytes()), limit)
+                .map(|entries| entries.into_iter().map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())).collect::<Vec<_>>())
+        };
+        let pairs = |entries: &[(&str, &str)]| entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+
+        for key in ["a", "b", "c", "d", "e"] {
+            assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(format!("v{}", key).as_bytes())).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        let snapshot = db.get_snapshot();
+        assert!(db.delete(&wo, &Slice::new(b"b")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"c")).ok());
+
+        // Empty ranges
+        assert_eq!(pairs(&[]), scan(&ro, "c", "c", 10).unwrap());
+        assert_eq!(pairs(&[]), scan(&ro, "d", "a", 10).unwrap());
+        assert_eq!(pairs(&[]), scan(&ro, "b", "d", 10).unwrap());
+        assert_eq!(pairs(&[]), scan(&ro, "x", "z", 10).unwrap());
+        assert_eq!(pairs(&[]), scan(&ro, "a", "z", 0).unwrap());
+
+        // "end" is exclusive; the range spans the deleted keys
+        assert_eq!(pairs(&[("a", "va"), ("d", "vd")]), scan(&ro, "a", "e", 10).unwrap());
+        assert_eq!(pairs(&[("a", "va"), ("d", "vd"), ("e", "ve")]), scan(&ro, "", "z", 10).unwrap());
+        assert_eq!(pairs(&[("a", "va"), ("d", "vd")]), scan(&ro, "", "z", 2).unwrap());
+        assert_eq!(pairs(&[("d", "vd")]), scan(&ro, "b", "z", 1).unwrap());
+
+        // Snapshots see the entries deleted since
+        let at_snapshot = ReadOptions { snapshot: Some(snapshot.clone()), ..ReadOptions::new() };
+        assert_eq!(pairs(&[("b", "vb"), ("c", "vc"), ("d", "vd")]), scan(&at_snapshot, "aa", "e", 10).unwrap());
+        assert_eq!(pairs(&[("a", "va"), ("b", "vb")]), scan(&at_snapshot, "a", "z", 2).unwrap());
+        db.release_snapshot(snapshot);
+
+        // The scan does not read on past "end" through a run of deletions
+        (0..2000).for_each(|i| assert!(db.put(&wo, &Slice::new(format!("f{:04}", i).as_bytes()), &Slice::new(b"v")).ok()));
+        assert!(db.put(&wo, &Slice::new(b"g"), &Slice::new(b"vg")).ok());
+        assert!(db.compact_range(None, None).ok());
+        (0..2000).for_each(|i| assert!(db.delete(&wo, &Slice::new(format!("f{:04}", i).as_bytes())).ok()));
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        let reads = |f: &dyn Fn()| {
+            let before = env.reads_.load(atomic::Ordering::SeqCst);
+            f();
+            env.reads_.load(atomic::Ordering::SeqCst) - before
+        };
+        let unbounded = reads(&|| {
+            let mut iter = db.new_iterator(&ro);
+            iter.seek(&Slice::new(b"e"));
+            iter.next();
+            assert_eq!(b"g", iter.key().data());
+        });
+        let bounded = reads(&|| assert_eq!(pairs(&[("e", "ve")]), scan(&ro, "e", "f", 10).unwrap()));
+        assert!(bounded * 2 < unbounded, "{} reads, {} without a bound", bounded, unbounded);
+        assert_eq!(pairs(&[("e", "ve"), ("g", "vg")]), scan(&ro, "e", "z", 10).unwrap());
+
+        // A read that fails part way returns the error
+        let expired = ReadOptions { deadline: Some(1), ..ReadOptions::new() };
+        assert!(scan(&expired, "a", "z", 10).unwrap_err().is_timed_out());
+    }
+
+    #[test]
+    fn range_rev_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("k{:03}", i).into_bytes();
+        let mut model = std::collections::BTreeMap::new();
+        let put = |db: &DB, model: &mut std::collections::BTreeMap<Vec<u8>, Vec<u8>>, i: usize, round: usize| {
+            let value = format!("v{}.{}", i, round).into_bytes();
+            assert!(db.put(&wo, &Slice::new(&key(i)), &Slice::new(&value)).ok());
+            model.insert(key(i), value);
+        };
+
+        // Spread the keys over several levels and the memtable, with
+        // deletions shadowing older entries.
+        (0..200).step_by(2).for_each(|i| put(&db, &mut model, i, 0));
+        assert!(db.compact_range(None, None).ok());
+        (0..200).step_by(3).for_each(|i| put(&db, &mut model, i, 1));
+        for i in (0..200).step_by(5) {
+            assert!(db.delete(&wo, &Slice::new(&key(i))).ok());
+            model.remove(&key(i));
+        }
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        let snapshot = db.get_snapshot();
+        let snapshot_model = model.clone();
+        (0..200).step_by(7).for_each(|i| put(&db, &mut model, i, 2));
+        for i in (0..200).step_by(11) {
+            assert!(db.delete(&wo, &Slice::new(&key(i))).ok());
+            model.remove(&key(i));
+        }
+        assert!(files_per_level(&db).iter().filter(|&&n| n > 0).count() > 1, "{:?}", files_per_level(&db));
+
+        // Bounds on keys, between keys, and outside the keyspace.
+        let mut bounds: Vec<Vec<u8>> = (0..201).map(key).collect();
+        bounds.extend((0..200).map(|i| format!("k{:03}5", i).into_bytes()));
+        bounds.extend([b"".to_vec(), b"a".to_vec(), b"k".to_vec(), b"z".to_vec()]);
+        let mut rnd = Random::new(301);
+        let bound = |rnd: &mut Random| match rnd.uniform(5) {
+            0 => Bound::Unbounded,
+            1 | 2 => Bound::Included(bounds[rnd.uniform(bounds.len() as i32) as usize].clone()),
+            _ => Bound::Excluded(bounds[rnd.uniform(bounds.len() as i32) as usize].clone()),
+        };
+        let check = |options: &ReadOptions, model: &std::collections::BTreeMap<Vec<u8>, Vec<u8>>, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)| {
+            let bounds = (range.0.as_ref().map(|k| k.as_slice()), range.1.as_ref().map(|k| k.as_slice()));
+            let expected: Vec<_> = model.iter().filter(|(k, _)| bounds.contains(k.as_slice()))
+                .map(|(k, v)| (k.clone(), v.clone())).collect();
+            let mut forward = db.range(options, bounds);
+            assert_eq!(expected, forward.by_ref().collect::<Vec<_>>(), "{:?}", range);
+            assert!(forward.status().ok());
+            let mut reverse = db.range_rev(options, bounds);
+            assert_eq!(expected.iter().rev().cloned().collect::<Vec<_>>(), reverse.by_ref().collect::<Vec<_>>(), "{:?}", range);
+            assert!(reverse.status().ok());
+            let keys: Vec<_> = expected.iter().rev().map(|(k, _)| k.clone()).collect();
+            assert_eq!(keys, db.range_rev(options, bounds).keys_only().collect::<Vec<_>>());
+        };
+        let mut snapshot_options = ReadOptions::new();
+        snapshot_options.snapshot = Some(snapshot.clone());
+        for _ in 0..500 {
+            let range = (bound(&mut rnd), bound(&mut rnd));
+            check(&ReadOptions::new(), &model, range.clone());
+            check(&snapshot_options, &snapshot_model, range);
+        }
+        check(&ReadOptions::new(), &model, (Bound::Unbounded, Bound::Unbounded));
+        db.release_snapshot(snapshot);
+    }
+
+    #[test]
+    fn prefix_rev_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let keys: [&[u8]; 9] = [b"a", b"ab", b"ab\x00", b"abc", b"ab\xff", b"ac", b"b", b"\xff\xff", b"\xff\xff\x01"];
+        for key in keys {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key), &Slice::new(b"v")).ok());
+        }
+        let prefixed = |prefix: &[u8]| -> Vec<Vec<u8>> {
+            let forward: Vec<_> = db.prefix(&ReadOptions::new(), prefix).keys_only().collect();
+            let mut reverse: Vec<_> = db.prefix_rev(&ReadOptions::new(), prefix).keys_only().collect();
+            reverse.reverse();
+            assert_eq!(forward, reverse);
+            forward
+        };
+        assert_eq!(vec![b"ab".to_vec(), b"ab\x00".to_vec(), b"abc".to_vec(), b"ab\xff".to_vec()], prefixed(b"ab"));
+        assert_eq!(vec![b"ab\xff".to_vec()], prefixed(b"ab\xff"));
+        assert_eq!(vec![b"b".to_vec()], prefixed(b"b"));
+        assert!(prefixed(b"abd").is_empty());
+        assert!(prefixed(b"0").is_empty());
+        assert_eq!(vec![b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()], prefixed(b"\xff\xff"));
+        assert_eq!(keys.iter().map(|k| k.to_vec()).collect::<Vec<_>>(), prefixed(b""));
+        assert_eq!(vec![b"\xff\xff\x01".to_vec(), b"\xff\xff".to_vec()],
+                   db.prefix_rev(&ReadOptions::new(), b"\xff").keys_only().collect::<Vec<_>>());
+    }
+
+    /// Records the names of the files opened for random access, which
+    /// the table cache does for each table it opens.
+    struct OpenRecordEnv {
+        base_: Arc<dyn Env>,
+        opened_: Mutex<Vec<String>>,
+    }
+
+    impl OpenRecordEnv {
+        /// Numbers of the tables opened since the last call.
+        fn take_opened_tables(&self) -> BTreeSet<u64> {
+            self.opened_.lock().unwrap().drain(..)
+                .filter_map(|fname| match parse_file_name(fname.rsplit('/').next().unwrap()) {
+                    Some((number, FileType::TableFile)) => Some(number),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl Env for OpenRecordEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+            self.opened_.lock().unwrap().push(fname.to_string());
+            self.base_.new_random_access_file(fname)
+        }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_appendable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn link_file(&self, src: &str, target: &str) -> Status { self.base_.link_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    /// Numbers of the tables holding a key before "bound".
+    fn tables_below(db: &DB, bound: &[u8]) -> BTreeSet<u64> {
+        let current = db.mutex_.lock().unwrap().versions_.current();
+        (0..NUM_LEVELS).flat_map(|level| current.files(level).iter())
+            .filter(|f| f.smallest.user_key().data() < bound)
+            .map(|f| f.number)
+            .collect()
+    }
+
+    #[test]
+    fn iterate_upper_bound_test() {
+        let env = Arc::new(OpenRecordEnv { base_: new_mem_env(), opened_: Mutex::new(Vec::new()) });
+        let mut options = options_with_env(env.clone());
+        options.max_file_size = 4096;
+        let key = |i: usize| format!("k{:03}", i);
+        {
+            // Tables in level 1, then two flushed on top of them
+            let db = DB::open(&options, DBNAME).unwrap();
+            let mut rnd = Random::new(301);
+            for i in 0..60 {
+                let value: String = (0..500).map(|_| (b'a' + rnd.uniform(26) as u8) as char).collect();
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(value.as_bytes())).ok());
+            }
+            assert!(db.flush().ok());
+            assert!(db.compact_range(None, None).ok());
+            for i in [5, 55] {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(b"new")).ok());
+                assert!(db.flush().ok());
+            }
+        }
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(key(25).as_bytes()), &Slice::new(b"mem")).ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(key(45).as_bytes()), &Slice::new(b"mem")).ok());
+        let all = tables_below(&db, b"\xff");
+        let below = tables_below(&db, key(30).as_bytes());
+        assert!(all.len() >= 4 && !below.is_empty() && below.len() < all.len(), "{:?} {:?}", all, below);
+        env.take_opened_tables();
+
+        let bounded = ReadOptions { iterate_upper_bound: Some(key(30).into_bytes()), ..ReadOptions::new() };
+        let keys = |iter: &mut dyn Iterator, forward: bool| scan(iter, forward).into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let mut iter = db.new_iterator(&bounded);
+        iter.seek_to_first();
+        assert_eq!((0..30).map(key).collect::<Vec<_>>(), keys(iter.as_mut(), true));
+        iter.seek_to_last();
+        assert_eq!(key(29), iter.key().to_utf8_string().unwrap());
+        assert_eq!((0..30).rev().map(key).collect::<Vec<_>>(), keys(iter.as_mut(), false));
+        iter.seek(&Slice::new(key(25).as_bytes()));
+        assert_eq!("mem", iter.value().to_utf8_string().unwrap());
+        assert_eq!((25..30).map(key).collect::<Vec<_>>(), keys(iter.as_mut(), true));
+        iter.seek(&Slice::new(key(30).as_bytes()));
+        assert!(!iter.valid());
+        iter.seek(&Slice::new(key(45).as_bytes()));
+        assert!(!iter.valid());
+
+        // Turning around at the bound stays before it
+        iter.seek(&Slice::new(key(28).as_bytes()));
+        iter.next();
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        iter.prev();
+        iter.next();
+        iter.next();
+        assert!(!iter.valid() && iter.status().ok());
+        drop(iter);
+        let opened = env.take_opened_tables();
+        assert!(opened.is_subset(&below), "{:?} {:?}", opened, below);
+
+        // An explicit scan end tighter than the bound wins
+        let entries = db.scan(&bounded, &Slice::new(key(10).as_bytes()), &Slice::new(key(12).as_bytes()), 100).unwrap();
+        assert_eq!(vec![key(10), key(11)], entries.into_iter().map(|(k, _)| String::from_utf8(k).unwrap()).collect::<Vec<_>>());
+        let entries = db.scan(&bounded, &Slice::new(key(28).as_bytes()), &Slice::new(key(50).as_bytes()), 100).unwrap();
+        assert_eq!(2, entries.len());
+
+        // Without the bound the rest is read too
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        assert_eq!((0..60).map(key).collect::<Vec<_>>(), keys(iter.as_mut(), true));
+        assert_eq!(all, &opened | &env.take_opened_tables());
+    }
+
+    #[test]
+    fn prefix_iterator_test() {
+        let mut options = options_with_env(new_mem_env());
+        options.iterator_pool_size = 2;
+        let db = DB::open(&options, DBNAME).unwrap();
+        for key in ["a", "ab", "abc", "abd", "ac", "b", "\u{7f}"] {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(b"v")).ok());
+        }
+        assert!(db.flush().ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"ab\x00"), &Slice::new(b"v")).ok());
+        let prefixed = |prefix: &[u8], options: &ReadOptions| {
+            let mut iter = db.prefix_iterator(options, prefix);
+            let mut keys = Vec::new();
+            while iter.valid() {
+                keys.push(iter.key().data().to_vec());
+                iter.next();
+            }
+            if let Some(last) = keys.last() {
+                iter.seek_to_last();
+                assert_eq!(last.as_slice(), iter.key().data());
+            }
+            keys
+        };
+        let ro = ReadOptions::new();
+        assert_eq!(vec![b"ab".to_vec(), b"ab\x00".to_vec(), b"abc".to_vec(), b"abd".to_vec()], prefixed(b"ab", &ro));
+        assert_eq!(vec![b"abc".to_vec()], prefixed(b"abc", &ro));
+        assert!(prefixed(b"aa", &ro).is_empty());
+        assert!(prefixed(b"z", &ro).is_empty());
+        assert_eq!(8, prefixed(b"", &ro).len());
+
+        // A tighter bound in the options is kept
+        let bounded = ReadOptions { iterate_upper_bound: Some(b"abd".to_vec()), ..ReadOptions::new() };
+        assert_eq!(vec![b"ab".to_vec(), b"ab\x00".to_vec(), b"abc".to_vec()], prefixed(b"ab", &bounded));
+        assert_eq!(vec![b"a".to_vec(), b"ab".to_vec(), b"ab\x00".to_vec(), b"abc".to_vec()], prefixed(b"a", &bounded));
+
+        // Unbounded iterators taken from the pool afterwards see everything
+        let mut iter = db.new_iterator(&ro);
+        iter.seek_to_last();
+        assert_eq!(b"\x7f", iter.key().data());
+    }
+
+    #[test]
+    fn iterator_is_stable_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        // Writes after the iterator is created are not visible through it.
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v1")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"a")).ok());
+        iter.seek_to_first();
+        assert_eq!(pairs(&[("a", "v1")]), scan(iter.as_mut(), true));
+    }
+
+    fn files_per_level(db: &DB) -> Vec<usize> {
+        let state = db.mutex_.lock().unwrap();
+        (0..NUM_LEVELS).map(|level| state.versions_.num_level_files(level)).collect()
+    }
+
+    /// Number of entries for "key" left anywhere in the DB, including
+    /// overwritten values and deletion markers.
+    fn internal_entries(db: &DB, key: &str) -> usize {
+        let (mut iter, _) = {
+            let state = db.mutex_.lock().unwrap();
+            db.build_internal_iterator(&state, &ReadOptions::new())
+        };
+        iter.seek(&InternalKey::new_from(&Slice::new(key.as_bytes()), MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK).encode());
+        let mut n = 0;
+        while iter.valid() && extract_user_key(iter.key().data()).data() == key.as_bytes() {
+            n += 1;
+            iter.next();
+        }
+        n
+    }
+
+    #[test]
+    fn compact_range_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.max_file_size = 16 * 1024;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        let key = |i: usize| format!("key{:06}", i);
+        let value = "x".repeat(100);
+        for i in 0..1000 {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value.as_bytes())).ok());
+        }
+
+        // The memtable is only flushed if it overlaps the range
+        assert!(db.compact_range(Some(&Slice::new(b"x")), Some(&Slice::new(b"z"))).ok());
+        assert_eq!(vec![0; NUM_LEVELS as usize], files_per_level(&db));
+
+        assert!(db.compact_range(None, None).ok());
+        let files = files_per_level(&db);
+        assert_eq!(0, files[0]);
+        assert_eq!(1, files.iter().filter(|&&n| n > 0).count(), "{:?}", files);
+        assert_eq!(value.as_bytes(), db.get(&ro, &Slice::new(key(7).as_bytes())).unwrap());
+
+        // Delete the even keys and overwrite some of the odd ones.  A
+        // snapshot taken before keeps one deleted value alive.
+        assert!(db.delete(&wo, &Slice::new(key(0).as_bytes())).ok());
+        let snapshot = db.get_snapshot();
+        for i in (2..1000).step_by(2) {
+            assert!(db.delete(&wo, &Slice::new(key(i).as_bytes())).ok());
+        }
+        for i in (1..1000).step_by(10) {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(b"new")).ok());
+        }
+        assert_eq!(2, internal_entries(&db, &key(4)));
+        assert_eq!(2, internal_entries(&db, &key(11)));
+
+        assert!(db.compact_range(None, None).ok());
+        let files = files_per_level(&db);
+        assert_eq!(0, files[0]);
+        assert_eq!(1, files.iter().filter(|&&n| n > 0).count(), "{:?}", files);
+        // Outputs are cut at max_file_size
+        assert!(files.iter().sum::<usize>() > 1, "{:?}", files);
+
+        for i in 0..1000 {
+            let k = key(i);
+            if i % 2 == 0 {
+                assert!(db.get(&ro, &Slice::new(k.as_bytes())).unwrap_err().is_not_found());
+                // Only the entries the snapshot still needs are kept
+                let expected = if i == 0 { 0 } else { 2 };
+                assert_eq!(expected, internal_entries(&db, &k), "{}", k);
+            } else {
+                let expected = if i % 10 == 1 { b"new".to_vec() } else { value.as_bytes().to_vec() };
+                assert_eq!(expected, db.get(&ro, &Slice::new(k.as_bytes())).unwrap());
+                let expected = if i % 10 == 1 { 2 } else { 1 };
+                assert_eq!(expected, internal_entries(&db, &k), "{}", k);
+            }
+        }
+        let ro_snapshot = ReadOptions { snapshot: Some(snapshot.clone()), ..ReadOptions::new() };
+        assert_eq!(value.as_bytes(), db.get(&ro_snapshot, &Slice::new(key(2).as_bytes())).unwrap());
+
+        // Without the snapshot, deleted and overwritten entries are dropped
+        // once their files are compacted again.  (The last level with
+        // files is never compacted on its own.)
+        db.release_snapshot(snapshot);
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(2, internal_entries(&db, &key(2)));
+        for i in [1, 999] {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(b"newer")).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        for i in 0..1000 {
+            assert_eq!(i % 2, internal_entries(&db, &key(i)), "{}", key(i));
+        }
+
+        // Only the live tables are left on disk
+        let tables = env.get_children(DBNAME).unwrap().iter()
+            .filter(|name| matches!(parse_file_name(name), Some((_, FileType::TableFile))))
+            .count();
+        assert_eq!(files_per_level(&db).iter().sum::<usize>(), tables);
+    }
+
+    #[test]
+    fn compaction_split_policy_test() {
+        // Returns the user key range of every table after loading the
+        // same data with the given history.
+        let load = |shuffle: bool| -> Vec<(Vec<u8>, Vec<u8>)> {
+            let mut options = options_with_env(new_mem_env());
+            options.output_split_key_policy = Some(Arc::new(FixedPrefixSplitPolicy::new(4)));
+            let db = DB::open(&options, DBNAME).unwrap();
+            let wo = WriteOptions::default();
+            let mut keys: Vec<String> = (0..250).map(|i| format!("t{:03}-{:03}", i / 50, i % 50)).collect();
+            if shuffle {
+                let mut rnd = Random::new(301);
+                for i in (1..keys.len()).rev() {
+                    keys.swap(i, rnd.uniform(i as i32 + 1) as usize);
+                }
+            }
+            // Compact twice so that everything goes through a compaction;
+            // memtable flushes are not split.
+            let (first, second) = keys.split_at(if shuffle { 100 } else { 250 });
+            for k in first {
+                assert!(db.put(&wo, &Slice::new(k.as_bytes()), &Slice::new(b"old")).ok());
+            }
+            assert!(db.compact_range(None, None).ok());
+            if shuffle {
+                assert!(db.put(&wo, &Slice::new(b"t000-zzz"), &Slice::new(b"gone")).ok());
+                assert!(db.delete(&wo, &Slice::new(b"t000-zzz")).ok());
+            }
+            for k in first.iter().chain(second) {
+                assert!(db.put(&wo, &Slice::new(k.as_bytes()), &Slice::new(b"v")).ok());
+            }
+            assert!(db.compact_range(None, None).ok());
+
+            let current = db.mutex_.lock().unwrap().versions_.current();
+            (0..NUM_LEVELS).flat_map(|level| current.files(level).clone())
+                .map(|f| (f.smallest.user_key().data().to_vec(), f.largest.user_key().data().to_vec()))
+                .collect()
+        };
+
+        let files = load(false);
+        assert_eq!(5, files.len());
+        for (smallest, largest) in &files {
+            assert_eq!(smallest[..4], largest[..4]);
+        }
+        assert_eq!(files, load(true));
+    }
+
+    /// Removes the values equal to "drop".
+    struct DropFilter;
+
+    impl CompactionFilter for DropFilter {
+        fn name(&self) -> &str { "DropFilter" }
+        fn filter(&self, _level: i32, _key: &Slice, value: &Slice) -> bool { value.data() == b"drop" }
+    }
+
+    #[test]
+    fn compaction_filter_test() {
+        let options = Options { compaction_filter: Some(Arc::new(DropFilter)), ..options_with_env(new_mem_env()) };
+        let db = DB::open(&options, DBNAME).unwrap();
+        let (ro, wo) = (ReadOptions::new(), WriteOptions::default());
+        let compact_level = |level| db.compact_level_range(db.mutex_.lock().unwrap(), level, None, None).1;
+
+        // "a" -> "v1" goes down to level-2.
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v1")).ok());
+        assert!(db.flush().ok());
+        assert!(compact_level(0).ok());
+        assert!(compact_level(1).ok());
+        assert_eq!(vec![0, 0, 1, 0, 0, 0, 0], files_per_level(&db));
+
+        // Filtering the newer value of "a" leaves a deletion marker, or
+        // the old value would come back.
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"drop")).ok());
+        assert!(db.put(&wo, &Slice::new(b"c"), &Slice::new(b"drop")).ok());
+        let snapshot = db.get_snapshot();
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"drop")).ok());
+        assert!(db.flush().ok());
+        assert!(compact_level(0).ok());
+        assert!(db.get(&ro, &Slice::new(b"a")).unwrap_err().is_not_found());
+        assert_eq!(2, internal_entries(&db, "a"));
+        // Nothing older exists for "c", so it is dropped outright.
+        assert_eq!(0, internal_entries(&db, "c"));
+        // A value newer than a snapshot is not filtered.
+        assert_eq!(b"drop".to_vec(), db.get(&ro, &Slice::new(b"b")).unwrap());
+
+        // At the base level the marker goes too.
+        db.release_snapshot(snapshot);
+        assert!(compact_level(1).ok());
+        assert_eq!(0, internal_entries(&db, "a"));
+        assert!(db.get(&ro, &Slice::new(b"b")).unwrap_err().is_not_found());
+    }
+
+    /// A fault for ThreadWorkEnv to inject into a table sync.
+    #[derive(Clone, Copy)]
+    enum SyncFault {
+        Fail,
+        Panic,
+    }
+
+    /// An Env that runs each piece of scheduled work on a thread of its
+    /// own, named "scheduled-<n>:<label>", and waits for it.  It counts the random
+    /// access reads made by each thread, by thread name, and injects
+    /// "sync_fault_" into the table sync after the given number more.
+    struct ThreadWorkEnv {
+        base_: Arc<dyn Env>,
+        reads_: Arc<Mutex<HashMap<String, u64>>>,
+        sync_fault_: Arc<Mutex<Option<(usize, SyncFault)>>>,
+        threads_: AtomicU32,
+    }
+
+    struct ThreadWorkFile {
+        file_: Arc<dyn RandomAccessFile>,
+        reads_: Arc<Mutex<HashMap<String, u64>>>,
+    }
+
+    impl RandomAccessFile for ThreadWorkFile {
+        fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+            let thread = std::thread::current().name().unwrap_or_default().to_string();
+            *self.reads_.lock().unwrap().entry(thread).or_default() += 1;
+            self.file_.read(offset, n)
+        }
+    }
+
+    struct SyncFaultFile {
+        file_: Arc<dyn WritableFile>,
+        sync_fault_: Arc<Mutex<Option<(usize, SyncFault)>>>,
+    }
+
+    impl WritableFile for SyncFaultFile {
+        fn append(&self, data: &Slice) -> Status { self.file_.append(data) }
+        fn close(&self) -> Status { self.file_.close() }
+        fn flush(&self) -> Status { self.file_.flush() }
+        fn sync(&self) -> Status {
+            let fault = {
+                let mut sync_fault = self.sync_fault_.lock().unwrap();
+                match sync_fault.as_mut() {
+                    Some((0, fault)) => {
+                        let fault = *fault;
+                        *sync_fault = None;
+                        Some(fault)
+                    },
+                    Some((n, _)) => {
+                        *n -= 1;
+                        None
+                    },
+                    None => None,
+                }
+            };
+            match fault {
+                Some(SyncFault::Fail) => Status::io_error("sync", "injected failure"),
+                Some(SyncFault::Panic) => panic!("injected panic"),
+                None => self.file_.sync(),
+            }
+        }
+    }
+
+    impl Env for ThreadWorkEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+            Ok(Arc::new(ThreadWorkFile { file_: self.base_.new_random_access_file(fname)?, reads_: self.reads_.clone() }))
+        }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+            let file = self.base_.new_writable_file(fname)?;
+            if !fname.ends_with(".ldb") {
+                return Ok(file);
+            }
+            Ok(Arc::new(SyncFaultFile { file_: file, sync_fault_: self.sync_fault_.clone() }))
+        }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) {
+            let name = format!("scheduled-{}:{}", self.threads_.fetch_add(1, atomic::Ordering::SeqCst), label.unwrap_or(""));
+            std::thread::Builder::new().name(name).spawn(work).unwrap().join().unwrap();
+        }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    #[test]
+    fn subcompactions_test() {
+        let env = Arc::new(ThreadWorkEnv {
+            base_: new_mem_env(),
+            reads_: Arc::new(Mutex::new(HashMap::new())),
+            sync_fault_: Arc::new(Mutex::new(None)),
+            threads_: AtomicU32::new(0),
+        });
+        let mut options = options_with_env(env.clone());
+        options.max_file_size = 16 * 1024;
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:06}", i);
+        // Incompressible, so that the tables get cut at max_file_size
+        let value = |i: usize| {
+            let mut rnd = Random::new(i as u32 + 1);
+            (0..100).map(|_| (b'a' + rnd.uniform(26) as u8) as char).collect::<String>()
+        };
+
+        // Lay the keys out over many tables of one level, then flush
+        // overwrites and deletions spread over all of them into a table
+        // of the level above, which compact_range() merges down in one
+        // compaction.
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            for i in 0..2000 {
+                assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+            }
+            assert!(db.flush().ok());
+            assert!(db.compact_range(None, None).ok());
+            for i in (0..2000).step_by(3) {
+                assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(format!("new{}", i).as_bytes())).ok());
+            }
+            for i in (0..2000).step_by(5) {
+                assert!(db.delete(&wo, &Slice::new(key(i).as_bytes())).ok());
+            }
+            assert!(db.flush().ok());
+        }
+        options.max_subcompactions = 4;
+        options.subcompaction_threshold_bytes = 64 * 1024;
+        options.instance_name = Some("subcompactions_test".to_string());
+        let mut db = DB::open(&options, DBNAME).unwrap();
+        let before = full_scan(&db);
+        let levels = files_per_level(&db);
+        assert_eq!(2, levels.iter().filter(|&&n| n > 0).count(), "{:?}", levels);
+
+        // A failing or panicking subcompaction fails the whole compaction,
+        // and the outputs of the others are deleted.
+        for (fault, expected) in [(SyncFault::Fail, "IO error: sync: injected failure"),
+                                  (SyncFault::Panic, "Corruption: subcompaction panicked: injected panic")] {
+            *env.sync_fault_.lock().unwrap() = Some((4, fault));
+            let s = db.compact_range(None, None);
+            assert_eq!(expected, s.to_string());
+            assert!(env.sync_fault_.lock().unwrap().is_none());
+            assert_eq!(levels, files_per_level(&db));
+            assert_eq!(levels.iter().sum::<usize>(), table_files(&(env.clone() as Arc<dyn Env>)));
+            drop(db);
+            db = DB::open(&options, DBNAME).unwrap();
+        }
+
+        env.reads_.lock().unwrap().clear();
+        assert!(db.compact_range(None, None).ok());
+        let reads = env.reads_.lock().unwrap().clone();
+        assert_eq!(4, db.compaction_stats().iter().map(|stats| stats.subcompactions).sum::<u64>());
+        assert_eq!(before, full_scan(&db));
+
+        // The outputs do not overlap, and cover the whole input range.
+        let current = db.mutex_.lock().unwrap().versions_.current();
+        let level = (0..NUM_LEVELS).find(|&level| !current.files(level).is_empty()).unwrap();
+        assert!((level + 1..NUM_LEVELS).all(|level| current.files(level).is_empty()));
+        let ranges: Vec<(String, String)> = current.files(level).iter()
+            .map(|f| (String::from_utf8(f.smallest.user_key().data().to_vec()).unwrap(),
+                      String::from_utf8(f.largest.user_key().data().to_vec()).unwrap()))
+            .collect();
+        assert_eq!(key(1), ranges[0].0);
+        assert_eq!(key(1999), ranges[ranges.len() - 1].1);
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 < pair[1].0, "{:?}", ranges);
+        }
+
+        // The subranges ran on threads of their own, each doing a share
+        // of the reading: the compaction's own and one per labelled helper.
+        let workers: Vec<u64> = reads.iter().filter(|(name, _)| name.starts_with("scheduled-")).map(|(_, &n)| n).collect();
+        let total: u64 = workers.iter().sum();
+        assert_eq!(4, workers.len(), "{:?}", reads);
+        let mut labels: Vec<&str> = reads.keys().filter_map(|name| name.split_once(':')).map(|(_, label)| label).collect();
+        labels.sort();
+        assert_eq!(vec!["subcompactions_test", "subcompactions_test-subcompaction-1",
+                        "subcompactions_test-subcompaction-2", "subcompactions_test-subcompaction-3"], labels);
+        assert!(workers.iter().all(|&n| n * 8 >= total), "{:?}", reads);
+    }
+
+    /// Number of data blocks in the tables of the DB.
+    fn data_blocks(db: &DB) -> u64 {
+        let current = db.mutex_.lock().unwrap().versions_.current();
+        let mut blocks = 0;
+        for f in (0..NUM_LEVELS).flat_map(|level| current.files(level)) {
+            let mut iter = db.table_cache_.new_iterator(&ReadOptions::new(), None, f.number, f.file_size);
+            iter.seek_to_first();
+            while iter.valid() {
+                if iter.raw_block().is_some() {
+                    blocks += 1;
+                    iter.skip_block();
+                } else {
+                    iter.next();
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn compaction_block_copy_test() {
+        let key = |i: usize| format!("key{:06}", i);
+        let value = |i: usize| format!("{:0100}", i);
+        // Flush 4 tables of disjoint key ranges into level-0, compact them
+        // into level-1, and return the number of data blocks they had and
+        // the number the compaction copied.
+        let run = |options: Options| {
+            let options = Options { block_size: 1024, ..options };
+            let db = DB::open(&options, DBNAME).unwrap();
+            let wo = WriteOptions::default();
+            for i in 0..1000 {
+                assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+                if i % 250 == 249 {
+                    assert!(db.with_exclusive_write(|state| db.switch_memtable(state).ok() && db.compact_mem_table(state, false).ok()));
+                }
+            }
+            assert_eq!(vec![4, 0, 0, 0, 0, 0, 0], files_per_level(&db));
+            let blocks = data_blocks(&db);
+            assert!(db.compact_level_range(db.mutex_.lock().unwrap(), 0, None, None).1.ok());
+            assert_eq!(vec![0, 1, 0, 0, 0, 0, 0], files_per_level(&db));
+
+            let expected: Vec<(String, String)> = (0..1000).map(|i| (key(i), value(i))).collect();
+            assert_eq!(expected, full_scan(&db));
+            let ro = ReadOptions { verify_checksums: true, ..ReadOptions::new() };
+            for i in (0..1000).step_by(7) {
+                assert_eq!(value(i).into_bytes(), db.get(&ro, &Slice::new(key(i).as_bytes())).unwrap());
+            }
+            (blocks, db.compaction_stats()[1].blocks_copied)
+        };
+
+        // Nothing overlaps, so every block is copied.
+        let (blocks, copied) = run(options_with_env(new_mem_env()));
+        assert!(blocks > 50);
+        assert_eq!(blocks, copied);
+
+        // A filter, paranoid checks, or a split policy need to see each
+        // entry.
+        let (_, copied) = run(Options { compaction_filter: Some(Arc::new(DropFilter)), ..options_with_env(new_mem_env()) });
+        assert_eq!(0, copied);
+        let (_, copied) = run(Options { paranoid_checks: true, ..options_with_env(new_mem_env()) });
+        assert_eq!(0, copied);
+        let (_, copied) = run(Options { output_split_key_policy: Some(Arc::new(FixedPrefixSplitPolicy::new(4))), ..options_with_env(new_mem_env()) });
+        assert_eq!(0, copied);
+    }
+
+    #[test]
+    fn compaction_block_copy_overlap_test() {
+        let options = Options { block_size: 1024, ..options_with_env(new_mem_env()) };
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:06}", i);
+
+        // Two tables over the same keys, and a third over keys of their
+        // own, with a deletion marker the compaction drops in its middle.
+        // Only the blocks of the third without the marker are copied.
+        for round in 0..3 {
+            for i in 0..300 {
+                let k = if round < 2 { key(i) } else { key(1000 + i) };
+                assert!(db.put(&wo, &Slice::new(k.as_bytes()), &Slice::new(format!("{}-{:0100}", round, i).as_bytes())).ok());
+            }
+            if round == 2 {
+                assert!(db.delete(&wo, &Slice::new(key(1150).as_bytes())).ok());
+            }
+            assert!(db.with_exclusive_write(|state| db.switch_memtable(state).ok() && db.compact_mem_table(state, false).ok()));
+        }
+        assert_eq!(vec![3, 0, 0, 0, 0, 0, 0], files_per_level(&db));
+        let before = full_scan(&db);
+        assert_eq!(599, before.len());
+
+        assert!(db.compact_level_range(db.mutex_.lock().unwrap(), 0, None, None).1.ok());
+        assert_eq!(vec![0, 1, 0, 0, 0, 0, 0], files_per_level(&db));
+        let (copied, blocks) = (db.compaction_stats()[1].blocks_copied, data_blocks(&db));
+        assert!(copied > 10 && copied * 2 < blocks, "{} of {}", copied, blocks);
+        assert_eq!(before, full_scan(&db));
+        assert_eq!(0, internal_entries(&db, &key(1150)));
+    }
+
+    fn create_tables_with_policies(env: &Arc<dyn Env>, policies: &[Option<Arc<dyn FilterPolicy>>]) {
+        // One level-0 table per policy.  Until the memtable is flushed on
+        // its own, repair_db turns each round's log into a table.
+        for (i, policy) in policies.iter().enumerate() {
+            let mut options = options_with_env(env.clone());
+            options.filter_policy = policy.clone();
+            {
+                let db = DB::open(&options, DBNAME).unwrap();
+                for j in 0..100 {
+                    let key = format!("{}-{:03}", i, j);
+                    assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(b"v")).ok());
+                }
+            }
+            assert!(repair_db(DBNAME, &options).ok());
+        }
+    }
+
+    struct OtherBloom(Arc<dyn FilterPolicy>);
+
+    impl FilterPolicy for OtherBloom {
+        fn name(&self) -> &str { "test.OtherBloom" }
+        fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>) { self.0.create_filter(keys, dst) }
+        fn key_may_match(&self, key: &Slice, filter: &Slice) -> bool { self.0.key_may_match(key, filter) }
+    }
+
+    #[test]
+    fn filter_coverage_test() {
+        let env = new_mem_env();
+        let policies: [Option<Arc<dyn FilterPolicy>>; 3] = [
+            None,
+            Some(new_bloom_filter_policy(10)),
+            Some(Arc::new(OtherBloom(new_bloom_filter_policy(5)))),
+        ];
+        create_tables_with_policies(&env, &policies);
+
+        for (usable, policy) in [0, 1, 1].into_iter().zip(&policies) {
+            let mut options = options_with_env(env.clone());
+            options.filter_policy = policy.clone();
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert_eq!(Some("3".to_string()), db.get_property("leveldb.num-files-at-level0"));
+            for i in 0..3 {
+                for j in 0..100 {
+                    let key = format!("{}-{:03}", i, j);
+                    assert_eq!(b"v".to_vec(), db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).unwrap());
+                    let missing = format!("{}-{:03}x", i, j);
+                    assert!(db.get(&ReadOptions::new(), &Slice::new(missing.as_bytes())).err().unwrap().is_not_found());
+                }
+            }
+
+            let coverage = db.get_property("leveldb.filter-coverage").unwrap();
+            assert!(coverage.starts_with(&format!("tables: {}/3 (", usable)), "{}", coverage);
+            // Two of the tables carry filters; at most one is usable.
+            assert!(!coverage.contains("bypassed lookups: 0\n"), "{}", coverage);
+            assert_eq!(0, db.stats_snapshot(false).1.table_cache.filter_cache_loads);
+        }
+
+        // The usable filter is read into the filter cache by the first
+        // lookup, and found there by the others but the last, whose key
+        // is past the table's.
+        let mut options = options_with_env(env.clone());
+        options.filter_policy = policies[1].clone();
+        options.filter_cache = Some(new_lru_cache(1 << 20));
+        let db = DB::open(&options, DBNAME).unwrap();
+        for j in 0..100 {
+            let missing = format!("1-{:03}x", j);
+            assert!(db.get(&ReadOptions::new(), &Slice::new(missing.as_bytes())).err().unwrap().is_not_found());
+        }
+        let stats = db.stats_snapshot(false).1.table_cache;
+        assert_eq!((1, 1, 98), (stats.filter_cache_loads, stats.filter_cache_misses, stats.filter_cache_hits));
+        assert!(db.approximate_memory_usage() > options.filter_cache.as_ref().unwrap().total_charge());
+        drop(db);
+
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        assert_eq!(None, db.get_property("leveldb.filter-coverage-x"));
+        assert_eq!(None, db.get_property("leveldb.num-files-at-level7"));
+        assert_eq!(None, db.get_property("rocksdb.filter-coverage"));
+    }
+
+    #[test]
+    fn rebuild_filters_test() {
+        let env = new_mem_env();
+        let bloom = new_bloom_filter_policy(10);
+        create_tables_with_policies(&env, &[None, Some(bloom.clone()), Some(Arc::new(OtherBloom(new_bloom_filter_policy(5))))]);
+
+        assert!(DB::open(&options_with_env(env.clone()), DBNAME).unwrap().rebuild_filters(None, None).is_invalid_argument());
+
+        let mut options = options_with_env(env.clone());
+        options.filter_policy = Some(bloom);
+        let db = DB::open(&options, DBNAME).unwrap();
+        let coverage = || db.get_property("leveldb.filter-coverage").unwrap();
+        assert!(coverage().starts_with("tables: 1/3 ("), "{}", coverage());
+
+        // Only the table holding keys "0-..." is in the range
+        assert!(db.rebuild_filters(Some(&Slice::new(b"0-")), Some(&Slice::new(b"0-~"))).ok());
+        assert!(coverage().starts_with("tables: 2/3 ("), "{}", coverage());
+
+        assert!(db.rebuild_filters(None, None).ok());
+        assert!(coverage().starts_with("tables: 3/3 (100.0%)\nbytes: "), "{}", coverage());
+        assert!(coverage().contains("(100.0%)\nbypassed"), "{}", coverage());
+        for i in 0..3 {
+            for j in 0..100 {
+                let key = format!("{}-{:03}", i, j);
+                assert_eq!(b"v".to_vec(), db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).unwrap());
+            }
+        }
+
+        // Nothing left to rebuild
+        let files = files_per_level(&db);
+        assert!(db.rebuild_filters(None, None).ok());
+        assert_eq!(files, files_per_level(&db));
+    }
+
+    #[test]
+    fn lock_range_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let fail_fast = WriteOptions { fail_on_locked_range: true, ..Default::default() };
+        assert!(db.put(&fail_fast, &Slice::new(b"c"), &Slice::new(b"v1")).ok());
+
+        let guard = db.lock_range(b"b", b"d").unwrap();
+        assert!(db.put(&fail_fast, &Slice::new(b"c"), &Slice::new(b"v2")).is_busy());
+        assert!(db.delete(&fail_fast, &Slice::new(b"b")).is_busy());
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::new(b"a"), &Slice::new(b"v"));
+        batch.put(&Slice::new(b"c"), &Slice::new(b"v"));
+        assert!(db.write(&fail_fast, batch.clone()).is_busy());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"a")).err().unwrap().is_not_found());
+
+        // Keys outside the range, and readers, are unaffected
+        assert!(db.put(&fail_fast, &Slice::new(b"d"), &Slice::new(b"v")).ok());
+        assert_eq!(b"v1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"c")).unwrap());
+
+        // Overlapping locks conflict; empty ranges are rejected
+        assert!(db.try_lock_range(b"a", b"c").err().unwrap().is_busy());
+        assert!(db.try_lock_range(b"d", b"d").err().unwrap().is_invalid_argument());
+        assert!(db.try_lock_range(b"d", b"e").is_ok());
+
+        // The holder writes through its guard
+        assert!(db.write_locked(&guard, &fail_fast, batch).ok());
+        assert_eq!(b"v".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"c")).unwrap());
+
+        drop(guard);
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"c"), &Slice::new(b"v3")).ok());
+        assert_eq!(b"v3".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"c")).unwrap());
+    }
+
+    #[test]
+    fn lock_range_blocks_writers_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let ro = ReadOptions::new();
+        let guard = db.lock_range(b"b", b"d").unwrap();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| db.put(&WriteOptions::default(), &Slice::new(b"c"), &Slice::new(b"other")));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!writer.is_finished());
+            assert!(db.get(&ro, &Slice::new(b"c")).unwrap_err().is_not_found());
+
+            // The blocked writer goes ahead once the holder lets go, and
+            // its write lands after the holder's.
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::new(b"c"), &Slice::new(b"holder"));
+            assert!(db.write_locked(&guard, &WriteOptions::default(), batch).ok());
+            assert_eq!(b"holder".to_vec(), db.get(&ro, &Slice::new(b"c")).unwrap());
+            drop(guard);
+            assert!(writer.join().unwrap().ok());
+        });
+        assert_eq!(b"other".to_vec(), db.get(&ro, &Slice::new(b"c")).unwrap());
+    }
+
+    #[test]
+    fn destroy_db_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"foo"), &Slice::new(b"v1")).ok());
+        }
+        let unknown = format!("{}/notes.txt", DBNAME);
+        assert!(env.new_writable_file(&unknown).unwrap().append(&Slice::new(b"keep")).ok());
+        assert!(env.file_exists(&current_file_name(DBNAME)));
+
+        assert!(destroy_db(DBNAME, &options).ok());
+        assert!(!env.file_exists(&current_file_name(DBNAME)));
+        assert_eq!(vec!["notes.txt".to_string()], env.get_children(DBNAME).unwrap());
+
+        // The DB is gone; opening without create_if_missing fails.
+        let mut options = options;
+        options.create_if_missing = false;
+        assert!(DB::open(&options, DBNAME).is_err());
+    }
+
+    #[test]
+    fn destroy_db_default_env_test() {
+        let options = Options::new();
+        let dir = std::env::temp_dir().join(format!("rucksdb-db-destroy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dbname = dir.to_string_lossy().into_owned();
+
+        // A missing directory is not an error.
+        assert!(destroy_db(&dbname, &options).ok());
+
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["000003.log", "CURRENT", "MANIFEST-000002", "LOCK"] {
+            std::fs::write(dir.join(name), b"x").unwrap();
+        }
+        assert!(destroy_db(&dbname, &options).ok());
+        assert!(!dir.exists());
+    }
+
+    fn table_files(env: &Arc<dyn Env>) -> usize {
+        env.get_children(DBNAME).unwrap().iter()
+            .filter(|name| matches!(parse_file_name(name), Some((_, FileType::TableFile))))
+            .count()
+    }
+
+    /// Three flushes, each replacing a version, then a write left in the
+    /// memtable.
+    fn write_history(db: &DB) {
+        let wo = WriteOptions::default();
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v1")).ok());
+        assert!(db.flush_memtable(&mut db.mutex_.lock().unwrap()).ok());
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v2")).ok());
+        assert!(db.delete(&wo, &Slice::new(b"b")).ok());
+        assert!(db.flush_memtable(&mut db.mutex_.lock().unwrap()).ok());
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v3")).ok());
+        assert!(db.flush_memtable(&mut db.mutex_.lock().unwrap()).ok());
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v4")).ok());
+    }
+
+    #[test]
+    fn keep_old_versions_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.keep_old_versions = 3;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let ro = ReadOptions::new();
+        let get_at = |id: u64, key: &str| db.get_at_version(id, &ro, &Slice::new(key.as_bytes()))
+            .map(|v| String::from_utf8(v).unwrap());
+        let scan_at = |id: u64| {
+            let mut iter = db.new_iterator_at_version(id, &ro).unwrap();
+            iter.seek_to_first();
+            scan(iter.as_mut(), true)
+        };
+        let pinned_bytes = || db.get_property("leveldb.pinned-bytes").unwrap().parse::<u64>().unwrap();
+
+        assert!(db.list_retained_versions().is_empty());
+        write_history(&db);
+        let retained = db.list_retained_versions();
+        assert_eq!(vec![2, 4, 5], retained.iter().map(|r| r.last_sequence).collect::<Vec<_>>());
+        assert_eq!(vec![0, 1, 2], retained.iter().map(|r| r.num_files).collect::<Vec<_>>());
+        assert!(retained.windows(2).all(|w| w[0].replaced_at_micros <= w[1].replaced_at_micros));
+        let ids: Vec<u64> = retained.iter().map(|r| r.id).collect();
+
+        // Each version reads as the DB did when it was replaced, including
+        // what was still in a memtable then, but not later writes.
+        assert_eq!("v1", get_at(ids[0], "a").unwrap());
+        assert_eq!("v1", get_at(ids[0], "b").unwrap());
+        assert_eq!("v2", get_at(ids[1], "a").unwrap());
+        assert!(get_at(ids[1], "b").unwrap_err().is_not_found());
+        assert_eq!("v3", get_at(ids[2], "a").unwrap());
+        assert_eq!(b"v4".to_vec(), db.get(&ro, &Slice::new(b"a")).unwrap());
+        assert_eq!(pairs(&[("a", "v1"), ("b", "v1")]), scan_at(ids[0]));
+        assert_eq!(pairs(&[("a", "v2")]), scan_at(ids[1]));
+        assert_eq!(0, pinned_bytes());
+
+        // Compacting replaces the flushed tables, but the retained
+        // versions still need them.
+        assert!(db.compact_range(None, None).ok());
+        let current_files = || files_per_level(&db).iter().sum::<usize>();
+        assert!(table_files(&env) > current_files());
+        assert!(pinned_bytes() > 0);
+        assert!(db.get_at_version(ids[0], &ro, &Slice::new(b"a")).unwrap_err().is_invalid_argument());
+
+        // Once they age out, the tables are deleted.
+        let wo = WriteOptions::default();
+        for i in 0..3 {
+            assert!(db.put(&wo, &Slice::new(format!("c{}", i).as_bytes()), &Slice::new(b"v")).ok());
+            assert!(db.flush_memtable(&mut db.mutex_.lock().unwrap()).ok());
+        }
+        assert_eq!(3, db.list_retained_versions().len());
+        assert_eq!(table_files(&env), current_files());
+        assert_eq!(0, pinned_bytes());
+    }
+
+    #[test]
+    fn keep_no_old_versions_test() {
+        let env = new_mem_env();
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        write_history(&db);
+        assert!(db.list_retained_versions().is_empty());
+        assert!(db.get_at_version(1, &ReadOptions::new(), &Slice::new(b"a")).unwrap_err().is_invalid_argument());
+        assert!(db.new_iterator_at_version(1, &ReadOptions::new()).is_err());
+
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(table_files(&env), files_per_level(&db).iter().sum::<usize>());
+        assert_eq!("0", db.get_property("leveldb.pinned-bytes").unwrap());
+    }
+
+    /// Wraps an Env with a manual clock that every random access read
+    /// advances by "read_micros", standing in for a slow disk.  Counts
+    /// the reads and how often the clock is consulted.
+    struct SlowReadEnv {
+        base_: Arc<dyn Env>,
+        clock_: Arc<AtomicU64>,
+        read_micros_: Arc<AtomicU64>,
+        reads_: Arc<AtomicU64>,
+        clock_calls_: AtomicU64,
+    }
+
+    struct SlowRandomAccessFile {
+        file_: Arc<dyn RandomAccessFile>,
+        clock_: Arc<AtomicU64>,
+        read_micros_: Arc<AtomicU64>,
+        reads_: Arc<AtomicU64>,
+    }
+
+    impl RandomAccessFile for SlowRandomAccessFile {
+        fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+            self.clock_.fetch_add(self.read_micros_.load(atomic::Ordering::SeqCst), atomic::Ordering::SeqCst);
+            self.reads_.fetch_add(1, atomic::Ordering::SeqCst);
+            self.file_.read(offset, n)
+        }
+    }
+
+    impl Env for SlowReadEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+            Ok(Arc::new(SlowRandomAccessFile {
+                file_: self.base_.new_random_access_file(fname)?,
+                clock_: self.clock_.clone(),
+                read_micros_: self.read_micros_.clone(),
+                reads_: self.reads_.clone(),
+            }))
+        }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_appendable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn link_file(&self, src: &str, target: &str) -> Status { self.base_.link_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 {
+            self.clock_calls_.fetch_add(1, atomic::Ordering::SeqCst);
+            self.clock_.load(atomic::Ordering::SeqCst)
+        }
+        fn sleep_for_microseconds(&self, micros: u64) { self.clock_.fetch_add(micros, atomic::Ordering::SeqCst); }
+    }
+
+    /// Open a DB on a SlowReadEnv holding "n" keys, all in tables.
+    fn open_slow_db(n: usize) -> (Arc<SlowReadEnv>, Options, Box<DB>) {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        let mut options = options_with_env(env.clone());
+        options.block_size = MIN_BLOCK_SIZE;
+        options.no_block_cache = true;  // Every read is a slow one
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        for i in 0..n {
+            let key = format!("key{:04}", i);
+            assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(format!("value{}", i).as_bytes())).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        (env, options, db)
+    }
+
+    #[test]
+    fn value_lazy_test() {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        let mut options = options_with_env(env.clone());
+        options.no_block_cache = true;  // Every value read is a file read
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:04}", i);
+        let value = |i: usize| format!("{:0>1000}", i);
+        for i in 0..2000 {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        assert!(db.put(&wo, &Slice::new(b"mem"), &Slice::new(b"v")).ok());
+        let reads = || env.reads_.load(atomic::Ordering::SeqCst);
+
+        let before = reads();
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        let materialized = scan(iter.as_mut(), true);
+        let full_scan = reads() - before;
+
+        // Collect handles for 1% of the entries, and read them while the
+        // iterator lives.  Only their blocks are read again.
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        let mut handles = Vec::new();
+        for i in 0..2000 {
+            if i % 100 == 0 {
+                handles.push((i, iter.value_lazy().unwrap()));
+            }
+            iter.next();
+        }
+        assert!(handles.iter().all(|(_, handle)| !handle.is_inline() && handle.len() == 1000));
+        let before = reads();
+        for (i, handle) in &handles {
+            assert_eq!(materialized[*i].1.as_bytes(), db.read_value(handle).unwrap());
+        }
+        let lazy = reads() - before;
+        assert_eq!(20, lazy);
+        assert!(lazy * 10 < full_scan, "{} {}", lazy, full_scan);
+
+        // Memtable entries come inline
+        iter.seek(&Slice::new(b"mem"));
+        let inline = iter.value_lazy().unwrap();
+        assert!(inline.is_inline());
+        assert_eq!(b"v".to_vec(), db.read_value(&inline).unwrap());
+
+        // Handles lapse with their iterator, unless pinned before
+        let pinned = handles[0].1.pin().unwrap();
+        drop(iter);
+        assert!(db.read_value(&handles[0].1).unwrap_err().is_invalid_argument());
+        assert!(db.read_value(&inline).unwrap_err().is_invalid_argument());
+        assert!(handles[1].1.pin().err().unwrap().is_invalid_argument());
+
+        // A pinned handle keeps its table through compactions
+        for i in 0..2000 {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(b"new")).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(value(0).into_bytes(), db.read_value(&pinned).unwrap());
+    }
+
+    #[test]
+    fn key_may_exist_test() {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        let mut options = options_with_env(env.clone());
+        options.filter_policy = Some(new_bloom_filter_policy(10));
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let key = |i: usize| format!("key{:04}", i);
+        for i in (0..1000).step_by(2) {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(b"v")).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        assert!(db.put(&wo, &Slice::new(b"mem"), &Slice::new(b"v")).ok());
+        assert!(db.delete(&wo, &Slice::new(key(0).as_bytes())).ok());
+        let may_exist = |k: &str| db.key_may_exist(&ReadOptions::new(), &Slice::new(k.as_bytes()));
+        let reads = env.reads_.load(atomic::Ordering::SeqCst);
+
+        // The memtable answers for its keys, tombstones included
+        assert_eq!((true, Some(b"v".to_vec())), may_exist("mem"));
+        assert_eq!((false, None), may_exist(&key(0)));
+
+        // Keys in tables may exist, but their values are not read.  The
+        // filter rules out nearly every absent key.
+        assert!((2..1000).step_by(2).all(|i| may_exist(&key(i)) == (true, None)));
+        let absent = (1..1000).step_by(2).filter(|&i| !may_exist(&key(i)).0).count();
+        assert!(absent > 480, "{}", absent);
+        assert_eq!(reads, env.reads_.load(atomic::Ordering::SeqCst));
+
+        // Tables that are not open yet are not opened
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        let reads = env.reads_.load(atomic::Ordering::SeqCst);
+        assert_eq!((true, None), db.key_may_exist(&ReadOptions::new(), &Slice::new(key(1).as_bytes())));
+        assert_eq!(reads, env.reads_.load(atomic::Ordering::SeqCst));
+        assert!(db.get(&ReadOptions::new(), &Slice::new(key(1).as_bytes())).unwrap_err().is_not_found());
+        let reads = env.reads_.load(atomic::Ordering::SeqCst);
+        assert!((1..1000).step_by(2).filter(|&i| !db.key_may_exist(&ReadOptions::new(), &Slice::new(key(i).as_bytes())).0).count() > 480);
+        assert_eq!(reads, env.reads_.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn compaction_stats_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        assert_eq!(vec![CompactionStats::default(); NUM_LEVELS as usize], db.compaction_stats());
+        let flush = |value: &str| {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"key"), &Slice::new(value.as_bytes())).ok());
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        };
+
+        // The first flushes are pushed below level-0 as nothing overlaps
+        flush("v1");
+        flush("v2");
+        assert_eq!(vec![0, 1, 1], files_per_level(&db)[..3]);
+        let stats = db.compaction_stats();
+        assert!(stats[1].bytes_written > 0 && stats[2].bytes_written > 0, "{:?}", stats);
+        assert_eq!(CompactionStats::default(), stats[0]);
+
+        let mut written = 0;
+        for i in 3..5 {
+            flush(&format!("v{}", i));
+            let stats = db.compaction_stats()[0].clone();
+            assert!(stats.bytes_written > written, "{:?}", stats);
+            assert_eq!(0, stats.bytes_read);
+            written = stats.bytes_written;
+        }
+        let stats = db.get_property("leveldb.stats").unwrap();
+        assert!(stats.lines().any(|line| line.trim_start().starts_with("0        2 ")), "{}", stats);
+
+        // A compaction reads its inputs and writes into the next level
+        assert!(db.compact_range(None, None).ok());
+        let stats = db.compaction_stats();
+        assert!(stats[1].bytes_read > 0 && stats[1].bytes_written > 0, "{:?}", stats);
+        assert!(stats[2].bytes_read > 0 && stats[2].bytes_written > 0, "{:?}", stats);
+    }
+
+    #[test]
+    fn stats_snapshot_test() {
+        let options = Options { instance_name: Some("stats_snapshot_test".to_string()), ..options_with_env(new_mem_env()) };
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        for i in 0..100 {
+            assert!(db.put(&wo, &Slice::new(format!("key{:03}", i).as_bytes()), &Slice::new(b"value")).ok());
+        }
+        assert!(db.flush().ok());
+        let groups = |delta: &StatsDelta| delta.keys().copied().collect::<Vec<_>>();
+
+        // Nothing happens, nothing changes
+        let (epoch, stats) = db.stats_snapshot(false);
+        assert_eq!(Some("stats_snapshot_test"), stats.instance_name.as_deref());
+        assert_eq!((100, 100), (stats.writes.batches, stats.writes.entries));
+        assert_eq!(1, stats.levels.files.iter().sum::<usize>());
+        assert_eq!(stats.levels.compactions, db.compaction_stats());
+        assert_eq!((epoch, stats.clone()), db.stats_snapshot(false));
+        assert_eq!((epoch, StatsDelta::new()), db.stats_delta(epoch));
+        assert_eq!(vec![StatsGroup::Writes, StatsGroup::Compaction, StatsGroup::TableCache], groups(&db.stats_delta(0).1));
+
+        // Gets from a table already open only change the reads
+        let ro = ReadOptions::new();
+        assert!(db.get(&ro, &Slice::new(b"key000")).is_ok());
+        let (epoch, _) = db.stats_snapshot(false);
+        for i in 0..10 {
+            assert!(db.get(&ro, &Slice::new(format!("key{:03}", i).as_bytes())).is_ok());
+        }
+        let (read_epoch, delta) = db.stats_delta(epoch);
+        assert_eq!(epoch + 1, read_epoch);
+        assert_eq!(vec![StatsGroup::Reads], groups(&delta));
+        let GroupStats::Reads(reads) = &delta[&StatsGroup::Reads] else { panic!("{:?}", delta) };
+        assert_eq!((stats.reads.gets + 11, 0), (reads.gets, reads.memtable_hits));
+        assert!(reads.blocks_read >= 11, "{:?}", reads);
+
+        // A compaction changes the levels, and opens the table it writes
+        assert!(db.compact_range(None, None).ok());
+        let (compact_epoch, delta) = db.stats_delta(read_epoch);
+        assert_eq!(vec![StatsGroup::Compaction, StatsGroup::TableCache], groups(&delta));
+        let GroupStats::Compaction(levels) = &delta[&StatsGroup::Compaction] else { panic!("{:?}", delta) };
+        assert_eq!(db.compaction_stats(), levels.compactions);
+        assert_eq!(vec![1], levels.files.iter().copied().filter(|&n| n > 0).collect::<Vec<_>>());
+        assert_eq!(vec![StatsGroup::Reads, StatsGroup::Compaction, StatsGroup::TableCache], groups(&db.stats_delta(epoch).1));
+        assert_eq!((compact_epoch, StatsDelta::new()), db.stats_delta(compact_epoch));
+    }
+
+    #[test]
+    fn stats_histogram_reset_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let put = |value_size: usize| {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"key"), &Slice::new(&vec![b'v'; value_size])).ok());
+        };
+        for i in 0..20 {
+            put(10 + i);
+        }
+        let (epoch, stats) = db.stats_snapshot(true);
+        let first = stats.writes.batch_bytes;
+        assert_eq!(20, first.count());
+
+        // Only the batches since the reset are in the next interval
+        for i in 0..30 {
+            put(1000 + i);
+        }
+        let (next_epoch, stats) = db.stats_snapshot(true);
+        assert!(next_epoch > epoch);
+        let second = stats.writes.batch_bytes;
+        assert_eq!((50, 30), (stats.writes.batches, second.count()));
+        assert!(first.percentile(100.0) < second.percentile(0.0), "{:?} {:?}", first, second);
+        assert!(second.percentile(50.0) >= 1000);
+
+        // Merged, the intervals cover every batch once
+        let mut merged = first.clone();
+        merged.merge(&second);
+        assert_eq!((50, stats.writes.bytes), (merged.count(), merged.sum()));
+
+        // The reset itself is a change, but an empty interval is not reset
+        let (reset_epoch, stats) = db.stats_snapshot(true);
+        assert!(reset_epoch > next_epoch);
+        assert_eq!(0, stats.writes.batch_bytes.count());
+        assert_eq!(reset_epoch, db.stats_snapshot(true).0);
+    }
+
+    #[test]
+    fn lookup_statistics_test() {
+        let key = |i: usize| format!("key{:03}", i);
+        // A table of the even keys, gets of "hits" of them, then of
+        // "misses" odd keys in its range
+        let run = |filter_policy: Option<Arc<dyn FilterPolicy>>, hits: usize, misses: usize| {
+            let options = Options { lookup_statistics: true, filter_policy, ..options_with_env(new_mem_env()) };
+            let db = DB::open(&options, DBNAME).unwrap();
+            for i in (0..200).step_by(2) {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(b"v")).ok());
+            }
+            assert!(db.flush().ok());
+            let ro = ReadOptions::default();
+            for i in 0..hits {
+                assert!(db.get(&ro, &Slice::new(key(2 * i).as_bytes())).is_ok());
+            }
+            for i in 0..misses {
+                assert!(db.get(&ro, &Slice::new(key(2 * i + 1).as_bytes())).unwrap_err().is_not_found());
+            }
+            db
+        };
+        let lookups = |db: &DB| db.stats_snapshot(false).1.reads.lookups;
+
+        // Hits read a block each, none in vain
+        let db = run(None, 50, 0);
+        let stats = lookups(&db);
+        assert_eq!((50, 0, 50, 0, 0), (stats.gets, stats.memtable_hits, stats.table_probes, stats.probe_misses, stats.miss_blocks_read));
+        assert_eq!((50, 50), (stats.blocks_per_get.count(), stats.blocks_per_get.sum()));
+        let hit_heavy = db.estimate_bloom_benefit(10);
+        assert_eq!((50, 1.0, 0.0), (hit_heavy.gets, hit_heavy.blocks_per_get, hit_heavy.blocks_saved_per_get));
+
+        // Without a filter, misses read a block each in vain, which a
+        // filter would mostly save
+        let db = run(None, 10, 40);
+        let stats = lookups(&db);
+        assert_eq!((50, 40, 40, 50), (stats.table_probes, stats.probe_misses, stats.miss_blocks_read, stats.blocks_per_get.sum()));
+        let miss_heavy = db.estimate_bloom_benefit(10);
+        assert_eq!(0.8, miss_heavy.miss_blocks_per_get);
+        assert!(miss_heavy.false_positive_rate < 0.01, "{:?}", miss_heavy);
+        assert!(miss_heavy.blocks_saved_per_get > 0.79 && miss_heavy.fraction_saved > 0.79, "{:?}", miss_heavy);
+        assert!(miss_heavy.blocks_saved_per_get > hit_heavy.blocks_saved_per_get);
+        assert!(db.estimate_bloom_benefit(5).blocks_saved_per_get < miss_heavy.blocks_saved_per_get);
+        assert_eq!(0.0, db.estimate_bloom_benefit(0).blocks_saved_per_get);
+
+        // With a filter, the same misses rarely read a block, and little
+        // is left to save
+        let filtered = lookups(&run(Some(new_bloom_filter_policy(10)), 10, 40));
+        assert_eq!(40, filtered.probe_misses);
+        assert!(filtered.miss_blocks_read < 5, "{:?}", filtered);
+        assert!(filtered.blocks_per_get.sum() < stats.blocks_per_get.sum());
+        assert!(filtered.estimate_bloom_benefit(10).blocks_saved_per_get < miss_heavy.blocks_saved_per_get / 4.0);
+
+        // Memtable hits probe no table
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"key500"), &Slice::new(b"v")).ok());
+        assert!(db.get(&ReadOptions::default(), &Slice::new(b"key500")).is_ok());
+        let stats = lookups(&db);
+        assert_eq!((51, 1, 50), (stats.gets, stats.memtable_hits, stats.table_probes));
+        assert_eq!((51, 50), (stats.level0_files_per_get.count(), stats.level0_files_per_get.sum()));
+
+        // A seek positions in every table that ends at or after its target
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        iter.seek(&Slice::new(b"key100"));
+        assert_eq!((1, 0), (lookups(&db).seeks, lookups(&db).multi_table_seeks));
+        for i in (0..200).step_by(2) {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(b"v2")).ok());
+        }
+        assert!(db.flush().ok());
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        iter.seek(&Slice::new(b"key100"));
+        iter.seek(&Slice::new(b"key999"));
+        assert_eq!((3, 1), (lookups(&db).seeks, lookups(&db).multi_table_seeks));
+        let value = db.get_property("leveldb.lookup-efficiency").unwrap();
+        assert!(value.starts_with("gets: 51 (memtable hits: 2.0%)\n"), "{}", value);
+        assert!(value.contains("seeks: 3 (more than one table: 33.3%)\n"), "{}", value);
+        assert!(value.contains("data blocks per get: count 51, average 0.98, p50 1, p99 1, max 1\n  [0, 1): 1\n  [1, 2): 50\n"), "{}", value);
+
+        // Off by default
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v")).ok());
+        assert!(db.get(&ReadOptions::default(), &Slice::new(b"a")).is_ok());
+        db.new_iterator(&ReadOptions::default()).seek(&Slice::new(b"a"));
+        assert_eq!(LookupStats::default(), lookups(&db));
+        assert_eq!(0, db.estimate_bloom_benefit(10).gets);
+    }
+
+    #[test]
+    fn seek_compaction_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let get = |key: &[u8]| db.get(&ReadOptions::default(), &Slice::new(key));
+
+        // Two overlapping files: the newer one pushed to level-1 because
+        // the older one is in level-2
+        for value in ["v1", "v2"] {
+            for key in ["a", "z"] {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes())).ok());
+            }
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        assert_eq!(vec![0, 1, 1], files_per_level(&db)[..3]);
+
+        // Hits in the level-1 file cost a single seek
+        for _ in 0..200 {
+            assert_eq!(b"v2".to_vec(), get(b"a").unwrap());
+        }
+        assert_eq!(vec![0, 1, 1], files_per_level(&db)[..3]);
+
+        // Misses inside its range also probe level-2, until the level-1
+        // file runs out of its 100 allowed seeks and is compacted away.
+        for _ in 0..99 {
+            assert!(get(b"m").unwrap_err().is_not_found());
+        }
+        assert_eq!(vec![0, 1, 1], files_per_level(&db)[..3]);
+        assert!(get(b"m").unwrap_err().is_not_found());
+        assert_eq!(vec![0, 0, 1], files_per_level(&db)[..3]);
+        assert_eq!(b"v2".to_vec(), get(b"z").unwrap());
+    }
+
+    #[test]
+    fn read_sampling_compaction_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let value = vec![b'v'; 1000];
+
+        // Two overlapping files of about 1MB, as in seek_compaction_test
+        for _ in 0..2 {
+            for i in 0..1000 {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:04}", i).as_bytes()), &Slice::new(&value)).ok());
+            }
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        assert_eq!(vec![0, 1, 1], files_per_level(&db)[..3]);
+
+        // Every full scan reads about 2MB, sampled about once per 1MB,
+        // and each sample is charged to the level-1 file.  Once it has
+        // run out of its 100 allowed seeks, the next iterator compacts it.
+        let mut scans = 0;
+        while files_per_level(&db)[1] > 0 {
+            assert!(scans < 200, "no seek compaction after {} scans", scans);
+            assert_eq!(1000, full_scan(&db).len());
+            scans += 1;
+        }
+        assert!(scans > 20, "{}", scans);
+        assert_eq!(vec![0, 0, 1], files_per_level(&db)[..3]);
+        assert_eq!(1000, full_scan(&db).len());
+    }
+
+    /// Keeps the messages logged to it.
+    struct CaptureLogger {
+        messages_: Mutex<Vec<String>>,
+    }
+
+    impl Logger for CaptureLogger {
+        fn logv(&self, msg: &str) {
+            self.messages_.lock().unwrap().push(msg.to_string());
+        }
+    }
+
+    #[test]
+    fn read_amplification_report_test() {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+        let mut options = options_with_env(env.clone());
+        options.info_log = Some(logger.clone());
+        options.read_amp_window = 20;
+        options.read_amp_warning_l0_files = 4;
+        options.read_amp_warning_interval_secs = 60;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let warnings = || logger.messages_.lock().unwrap().iter().filter(|m| m.starts_with("Read amplification")).count();
+        let miss = || assert!(db.get(&ReadOptions::default(), &Slice::new(b"m")).unwrap_err().is_not_found());
+
+        // Six overlapping level-0 files over one in level-1 and one in
+        // level-2, all spanning "a".."z": a miss for "m" probes them all.
+        for i in 0..8 {
+            for key in ["a", "z"] {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(format!("v{}", i).as_bytes())).ok());
+            }
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        assert_eq!(vec![6, 1, 1], files_per_level(&db)[..3]);
+
+        // No warning until the window is full, then one per interval
+        for _ in 0..19 {
+            miss();
+        }
+        assert_eq!(0, warnings());
+        miss();
+        assert_eq!(1, warnings());
+        let report = db.read_amplification_report();
+        assert_eq!(20, report.gets);
+        assert_eq!(vec![6.0, 1.0, 1.0, 0.0], report.per_level_avg_files_probed[..4]);
+        assert_eq!(8.0, report.avg_blocks_per_get);
+        assert_eq!(0.0, report.memtable_hit_fraction);
+        assert_eq!(8, report.worst_recent_get_files);
+        env.clock_.fetch_add(59_000_000, atomic::Ordering::SeqCst);
+        for _ in 0..20 {
+            miss();
+        }
+        assert_eq!(1, warnings());
+
+        // Gets answered by the memtable probe no files
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"m"), &Slice::new(b"v")).ok());
+        for _ in 0..5 {
+            assert_eq!(b"v".to_vec(), db.get(&ReadOptions::default(), &Slice::new(b"m")).unwrap());
+        }
+        let report = db.read_amplification_report();
+        assert_eq!((0.25, 4.5), (report.memtable_hit_fraction, report.per_level_avg_files_probed[0]));
+        assert!(db.delete(&WriteOptions::default(), &Slice::new(b"m")).ok());
+
+        // Once level-0 is compacted away, the numbers drop and no further
+        // warning fires.
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(0, files_per_level(&db)[0]);
+        env.clock_.fetch_add(120_000_000, atomic::Ordering::SeqCst);
+        for _ in 0..20 {
+            miss();
+        }
+        let report = db.read_amplification_report();
+        assert_eq!(0.0, report.per_level_avg_files_probed[0]);
+        assert!(report.worst_recent_get_files <= 1 && report.avg_blocks_per_get <= 1.0, "{:?}", report);
+        assert_eq!(1, warnings());
+    }
+
+    /// Keeps the work passed to schedule() until run_work() runs it.
+    struct DeferredWorkEnv {
+        base_: Arc<dyn Env>,
+        work_: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+    }
+
+    impl DeferredWorkEnv {
+        /// Run the work scheduled so far, and return how much there was.
+        fn run_work(&self) -> usize {
+            let work = std::mem::take(&mut *self.work_.lock().unwrap());
+            let n = work.len();
+            work.into_iter().for_each(|work| work());
+            n
+        }
+    }
+
+    impl Env for DeferredWorkEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, _label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.work_.lock().unwrap().push(work) }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    #[test]
+    fn background_work_test() {
+        let env = Arc::new(DeferredWorkEnv { base_: new_mem_env(), work_: Mutex::new(Vec::new()) });
+        let options = Options { write_buffer_size: 64 << 10, ..options_with_env(env.clone()) };
+        let db = DB::open(&options, DBNAME).unwrap();
+        let put = |i: usize| {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:06}", i).as_bytes()), &Slice::new(&[b'v'; 100])).ok());
+        };
+        let total_files = |db: &DB| files_per_level(db).iter().sum::<usize>();
+
+        // The write that fills the memtable leaves the flush to the
+        // background work, and later writes go on into the new memtable.
+        let mut i = 0;
+        while db.mutex_.lock().unwrap().imm_.is_none() {
+            put(i);
+            i += 1;
+        }
+        put(i);
+        assert_eq!(0, total_files(&db));
+        assert!(db.mutex_.lock().unwrap().background_compaction_scheduled_);
+        // The work holds the DB's state, and lets go of it when done.
+        assert_eq!(2, Arc::strong_count(&db.inner_));
+        assert_eq!(1, env.run_work());
+        assert_eq!(1, Arc::strong_count(&db.inner_));
+        assert_eq!(1, total_files(&db));
+        assert!(db.mutex_.lock().unwrap().imm_.is_none());
+        assert_eq!(0, env.run_work());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"key000000")).is_ok());
+
+        // Dropping the DB waits for the work scheduled by then to run.
+        while db.mutex_.lock().unwrap().imm_.is_none() {
+            put(i);
+            i += 1;
+        }
+        let worker = {
+            let env = env.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                env.run_work()
+            })
+        };
+        let state = Arc::downgrade(&db.inner_);
+        drop(db);
+        assert!(env.work_.lock().unwrap().is_empty());
+        assert_eq!(1, worker.join().unwrap());
+        assert!(state.upgrade().is_none());
+    }
+
+    #[test]
+    fn owned_iterator_shutdown_test() {
+        let env = Arc::new(DeferredWorkEnv { base_: new_mem_env(), work_: Mutex::new(Vec::new()) });
+        let options = Options { write_buffer_size: 64 << 10, ..options_with_env(env.clone()) };
+        let db: Arc<DB> = DB::open(&options, DBNAME).unwrap().into();
+        let fill = |db: &DB, mut i: usize| {
+            while db.mutex_.lock().unwrap().imm_.is_none() {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:06}", i).as_bytes()), &Slice::new(&[b'v'; 100])).ok());
+                i += 1;
+            }
+            i
+        };
+        let i = fill(&db, 0);
+        let iter = db.new_owned_iterator(&ReadOptions::new());
+        let state = Arc::downgrade(&db.inner_);
+        drop(db);
+
+        // With the iterator the last handle on the DB, its background work
+        // still runs.
+        assert!(!state.upgrade().unwrap().shutting_down_.load(atomic::Ordering::Acquire));
+        assert_eq!(1, env.run_work());
+        assert_eq!(1, files_per_level(iter.db()).iter().sum::<usize>());
+        assert!(iter.db().mutex_.lock().unwrap().imm_.is_none());
+
+        // Dropping the iterator shuts the DB down, waiting for the work
+        // scheduled by then.
+        fill(iter.db(), i);
+        let worker = {
+            let env = env.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                env.run_work()
+            })
+        };
+        assert!(!state.upgrade().unwrap().shutting_down_.load(atomic::Ordering::Acquire));
+        drop(iter);
+        assert!(env.work_.lock().unwrap().is_empty());
+        assert_eq!(1, worker.join().unwrap());
+        assert!(state.upgrade().is_none());
+        assert!(DB::open(&options, DBNAME).is_ok());
+    }
+
+    #[test]
+    fn write_controller_test() {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        // Low enough breakpoints for a few level-0 files to put the debt
+        // on the curve.
+        let options = Options {
+            compression: CompressionType::NoCompression,
+            write_delay_start: WriteDelayPoint { debt_bytes: 1 << 20, delay_micros: 1000 },
+            write_delay_full: WriteDelayPoint { debt_bytes: 3 << 20, delay_micros: 5000 },
+            ..options_with_env(env.clone())
+        };
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.set_options(&[("l0_slowdown_writes_trigger", "6"), ("l0_stop_writes_trigger", "7")]).ok());
+        let controller = db.write_controller();
+        assert_eq!((0, 0), (controller.estimated_delay_per_write(), controller.compaction_debt_bytes()));
+        // The same keys over and over, so that flushes are not pushed
+        // down past level-0.
+        let value = vec![b'v'; 100_000];
+        let mut i = 0;
+        let mut put = || {
+            i += 1;
+            let before = env.clock_.load(atomic::Ordering::SeqCst);
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i % 3).as_bytes()), &Slice::new(&value)).ok());
+            env.clock_.load(atomic::Ordering::SeqCst) - before
+        };
+        let flush = || {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        };
+
+        // Level-0 files pile up while compactions are held off: flushes
+        // under the lock do not schedule any.  The delay rises with the
+        // debt, and each write waits it out once.
+        let mut last_delay = 0;
+        while files_per_level(&db)[0] < 6 {
+            for _ in 0..3 {
+                assert_eq!(controller.estimated_delay_per_write(), put());
+            }
+            flush();
+            let delay = controller.estimated_delay_per_write();
+            assert_eq!(db.estimate_compaction_backlog(), controller.compaction_debt_bytes());
+            assert_eq!(files_per_level(&db)[0] >= 4, delay > last_delay, "{} after {}", delay, last_delay);
+            assert!(delay <= 5000);
+            last_delay = delay;
+        }
+        assert!(last_delay > 1000);
+        let delayed_writes = || db.mutex_.lock().unwrap().delayed_writes_;
+        assert_eq!(6, delayed_writes());      // At 4 and 5 files
+        assert_eq!(last_delay, put());
+        assert_eq!(7, delayed_writes());
+        let before = env.clock_.load(atomic::Ordering::SeqCst);
+        assert_eq!(last_delay, controller.acquire(100));
+        assert_eq!((0, before + last_delay), (controller.acquire(0), env.clock_.load(atomic::Ordering::SeqCst)));
+
+        // Reaching the stop trigger puts the delay at its top.  Writes go
+        // on into the memtable, but the one that finds it full is stopped
+        // until level-0 is compacted, which pays off the debt.
+        flush();
+        assert_eq!(5000, controller.estimated_delay_per_write());
+        let stopped_writes = || db.mutex_.lock().unwrap().stopped_writes_;
+        while stopped_writes() == 0 {
+            assert_eq!(5000, put());
+        }
+        assert!(files_per_level(&db)[0] < 4, "{:?}", files_per_level(&db));
+        assert_eq!((0, 0), (controller.estimated_delay_per_write(), controller.compaction_debt_bytes()));
+        assert_eq!(0, put());
+    }
+
+    #[test]
+    fn health_test() {
+        let env = Arc::new(SlowReadEnv {
+            base_: new_mem_env(),
+            clock_: Arc::new(AtomicU64::new(1_000_000)),
+            read_micros_: Arc::new(AtomicU64::new(0)),
+            reads_: Arc::new(AtomicU64::new(0)),
+            clock_calls_: AtomicU64::new(0),
+        });
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.set_options(&[("l0_slowdown_writes_trigger", "5"), ("l0_stop_writes_trigger", "6")]).ok());
+        let lenient = ReadinessThresholds::new();
+        let strict = ReadinessThresholds { max_compaction_debt_bytes: 0, allow_degraded: false, max_unflushed_age_secs: Some(3) };
+        let put = |i: usize| assert!(db.put(&WriteOptions::default(), &Slice::new(b"key"), &Slice::new(format!("v{}", i).as_bytes())).ok());
+
+        let health = db.health();
+        assert_eq!(DbHealth {
+            state: HealthState::Healthy,
+            background_error: None,
+            compaction_debt_bytes: 0,
+            l0_files: 0,
+            imm_pending: false,
+            oldest_unflushed_age_secs: None,
+            stalled_writers: 0,
+        }, health);
+        assert!(health.is_ready(&lenient) && health.is_ready(&strict));
+
+        // Unflushed writes age by the Env's clock, also once in imm_
+        put(0);
+        assert_eq!(Some(0), db.health().oldest_unflushed_age_secs);
+        env.clock_.fetch_add(5_000_000, atomic::Ordering::SeqCst);
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.switch_memtable(&mut state).ok());
+        }
+        let health = db.health();
+        assert!(health.imm_pending);
+        assert_eq!((HealthState::Healthy, Some(5)), (health.state, health.oldest_unflushed_age_secs));
+        assert!(health.is_ready(&lenient) && !health.is_ready(&strict));
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.compact_mem_table(&mut state, true).ok());
+        }
+        assert_eq!((false, None), (db.health().imm_pending, db.health().oldest_unflushed_age_secs));
+
+        // Level-0 files pile up while compactions are held off: flushes
+        // under the lock do not schedule any.
+        let mut i = 1;
+        while files_per_level(&db)[0] < 6 {
+            put(i);
+            i += 1;
+            {
+                let mut state = db.mutex_.lock().unwrap();
+                assert!(db.flush_memtable(&mut state).ok());
+            }
+            let health = db.health();
+            let expected = match health.l0_files {
+                0..=3 => (HealthState::Healthy, true),
+                4 | 5 => (HealthState::Degraded, true),
+                _ => (HealthState::Stalled, false),
+            };
+            assert_eq!(expected, (health.state, health.is_ready(&lenient)), "{:?}", health);
+            assert_eq!(health.l0_files >= 4, health.compaction_debt_bytes > 0, "{:?}", health);
+            assert!(health.l0_files < 4 || !health.is_ready(&strict));
+            assert_eq!(health.compaction_debt_bytes, db.estimate_compaction_backlog());
+        }
+
+        // Compaction clears the backlog
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.maybe_schedule_compaction(&mut state));
+            drop(state);
+            db.schedule_background_call();
+        }
+        let health = db.health();
+        assert_eq!((HealthState::Healthy, 0, 0), (health.state, health.l0_files, health.compaction_debt_bytes));
+        assert!(health.is_ready(&strict));
+
+        // A failed flush leaves the DB failed until it is reopened
+        put(i);
+        let failure = sync_point::fail("build-table:before-sync", Status::io_error("table sync", "injected"));
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.switch_memtable(&mut state).ok());
+            assert!(db.maybe_schedule_compaction(&mut state));
+            drop(state);
+            db.schedule_background_call();
+        }
+        drop(failure);
+        let health = db.health();
+        assert_eq!(HealthState::Failed, health.state);
+        assert_eq!(Some("IO error: table sync: injected".to_string()), health.background_error);
+        assert!(!health.is_ready(&lenient));
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(HealthState::Healthy, db.health().state);
+        assert!(db.health().is_ready(&strict));
+    }
+
+    #[test]
+    fn read_deadline_get_test() {
+        let (env, options, db) = open_slow_db(200);
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        env.read_micros_.store(1000, atomic::Ordering::SeqCst);
+        let key = Slice::new(b"key0100");
+
+        // Opening the table takes several reads, after which there is no
+        // time left for the data block.
+        let now = env.clock_.load(atomic::Ordering::SeqCst);
+        let ro = ReadOptions { deadline: Some(now + 1500), ..ReadOptions::new() };
+        let s = db.get(&ro, &key).unwrap_err();
+        assert!(s.is_timed_out(), "{}", s.to_string());
+        let reads = env.reads_.load(atomic::Ordering::SeqCst);
+        assert!(env.clock_.load(atomic::Ordering::SeqCst) > now + 1500);
+
+        // A deadline that has already passed gives up before any read.
+        let ro = ReadOptions { deadline: Some(env.clock_.load(atomic::Ordering::SeqCst) - 1), ..ReadOptions::new() };
+        assert!(db.get(&ro, &key).unwrap_err().is_timed_out());
+        assert_eq!(reads, env.reads_.load(atomic::Ordering::SeqCst));
+
+        // Without a deadline, or with enough time, the same get succeeds.
+        assert_eq!(b"value100".to_vec(), db.get(&ReadOptions::new(), &key).unwrap());
+        let ro = ReadOptions { deadline: Some(env.clock_.load(atomic::Ordering::SeqCst) + 1000), ..ReadOptions::new() };
+        assert_eq!(b"value100".to_vec(), db.get(&ro, &key).unwrap());
+    }
+
+    #[test]
+    fn read_deadline_iterator_test() {
+        let (env, _options, db) = open_slow_db(500);
+        // Newer entries in the memtable must not leak past the timeout.
+        let wo = WriteOptions::default();
+        for i in (0..500).step_by(7) {
+            let key = format!("key{:04}", i);
+            assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(b"new")).ok());
+        }
+        let expected = full_scan(&db);
+        assert_eq!(500, expected.len());
+
+        env.read_micros_.store(1000, atomic::Ordering::SeqCst);
+        let ro = ReadOptions { deadline: Some(env.clock_.load(atomic::Ordering::SeqCst) + 5_000), ..ReadOptions::new() };
+        let mut iter = db.new_iterator(&ro);
+        iter.seek_to_first();
+        let mut seen = Vec::new();
+        while iter.valid() {
+            seen.push((iter.key().to_utf8_string().unwrap(), iter.value().to_utf8_string().unwrap()));
+            iter.next();
+        }
+        assert!(iter.status().is_timed_out(), "{}", iter.status().to_string());
+        // What was yielded is exactly the start of the full scan.
+        assert!(!seen.is_empty() && seen.len() < expected.len(), "{}", seen.len());
+        assert_eq!(expected[..seen.len()], seen[..]);
+    }
+
+    #[test]
+    fn read_deadline_clock_calls_test() {
+        let (env, _options, db) = open_slow_db(200);
+        let key = Slice::new(b"key0100");
+        // Warm the table cache.
+        assert!(db.get(&ReadOptions::new(), &key).is_ok());
+
+        // Reads without a deadline never consult the clock.
+        env.clock_calls_.store(0, atomic::Ordering::SeqCst);
+        assert!(db.get(&ReadOptions::new(), &key).is_ok());
+        assert_eq!(200, full_scan(&db).len());
+        assert_eq!(0, env.clock_calls_.load(atomic::Ordering::SeqCst));
+
+        // With one, a get served by a single block costs a single call.
+        let ro = ReadOptions { deadline: Some(u64::MAX), ..ReadOptions::new() };
+        assert!(db.get(&ro, &key).is_ok());
+        assert_eq!(1, env.clock_calls_.load(atomic::Ordering::SeqCst));
+    }
+
+    fn log_files(env: &Arc<dyn Env>) -> usize {
+        env.get_children(DBNAME).unwrap().iter()
+            .filter(|name| matches!(parse_file_name(name), Some((_, FileType::LogFile))))
+            .count()
+    }
+
+    /// Put "key" with "token", returning the write's status.
+    fn put_with_token(db: &DB, token: u8, key: &str, value: &str) -> Status {
+        let wo = WriteOptions { idempotency_token: Some([token; 16]), ..Default::default() };
+        db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes()))
+    }
+
+    #[test]
+    fn idempotency_token_test() {
+        let env = new_mem_env();
+        let db = DB::open(&options_with_env(env), DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v0")).ok());
+        assert!(put_with_token(&db, 1, "foo", "v1").ok());
+        let committed = db.last_applied_sequence();
+
+        let s = put_with_token(&db, 1, "foo", "v1");
+        assert!(s.is_already_applied(), "{}", s.to_string());
+        assert_eq!(format!("Already applied: committed at sequence: {}", committed), s.to_string());
+        assert_eq!(committed, db.last_applied_sequence());
+        assert_eq!(1, internal_entries(&db, "foo"));
+
+        // Only the token matters, not the contents of the batch.
+        assert!(put_with_token(&db, 1, "bar", "v2").is_already_applied());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"bar")).unwrap_err().is_not_found());
+        assert!(put_with_token(&db, 2, "foo", "v2").ok());
+        assert_eq!(b"v2".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"foo")).unwrap());
+    }
+
+    #[test]
+    fn idempotency_window_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env);
+        options.idempotency_window = 2;
+        let db = DB::open(&options, DBNAME).unwrap();
+        for token in 1..=3 {
+            assert!(put_with_token(&db, token, "foo", "v").ok());
+        }
+        assert!(put_with_token(&db, 1, "foo", "v").ok());
+        assert!(put_with_token(&db, 3, "foo", "v").is_already_applied());
+    }
+
+    #[test]
+    fn idempotency_token_recovery_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.keep_log_file_num = 2;
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(put_with_token(&db, 1, "foo", "v1").ok());
+        let committed = db.last_applied_sequence();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"bar"), &Slice::new(b"v")).ok());
+        drop(db);
+
+        // Recovery replays the log and remembers its tokens, then moves the
+        // data into a table; the log is kept for its tokens.
+        let db = DB::open(&options, DBNAME).unwrap();
+        let s = put_with_token(&db, 1, "foo", "v1");
+        assert!(s.is_already_applied());
+        assert_eq!(format!("Already applied: committed at sequence: {}", committed), s.to_string());
+        assert_eq!(1, internal_entries(&db, "foo"));
+        drop(db);
+
+        // Retained logs are only scanned for tokens.
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(put_with_token(&db, 1, "foo", "v1").is_already_applied());
+        assert_eq!(1, internal_entries(&db, "foo"));
+        assert_eq!(committed + 1, db.last_applied_sequence());
+    }
+
+    #[test]
+    fn required_features_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let without_tokens = features::supported_features() & !features::IDEMPOTENCY_TOKENS;
+        let set_supported = |mask| features::SUPPORTED_OVERRIDE.with(|o| o.set(mask));
+
+        // A baseline database opens in a build without the feature, and what
+        // such a build writes opens in one with it.
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v")).ok());
+        drop(db);
+        set_supported(Some(without_tokens));
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"b"), &Slice::new(b"v")).ok());
+        drop(db);
+        set_supported(None);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(0, db.mutex_.lock().unwrap().versions_.required_features() & features::IDEMPOTENCY_TOKENS);
+
+        // The first token is recorded in the MANIFEST before it is logged.
+        assert!(put_with_token(&db, 1, "foo", "v1").ok());
+        assert_eq!(features::IDEMPOTENCY_TOKENS, db.mutex_.lock().unwrap().versions_.required_features() & features::IDEMPOTENCY_TOKENS);
+        assert!(put_with_token(&db, 2, "bar", "v2").ok());
+        drop(db);
+
+        set_supported(Some(without_tokens));
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        set_supported(None);
+        assert!(s.is_not_supported_error(), "{}", s.to_string());
+        assert!(s.to_string().starts_with("Not implemented: this database requires: idempotency token support (file /db/MANIFEST-"), "{}", s.to_string());
+
+        // The record is carried over into each new MANIFEST.
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(b"v1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"foo")).unwrap());
+        assert_eq!(features::IDEMPOTENCY_TOKENS, db.mutex_.lock().unwrap().versions_.required_features() & features::IDEMPOTENCY_TOKENS);
+        drop(db);
+        set_supported(Some(without_tokens));
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        set_supported(None);
+        assert!(s.is_not_supported_error(), "{}", s.to_string());
+
+        // The edit adding the first table with a whole-file checksum is
+        // the one that records table checksums.
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let without_checksums = features::supported_features() & !features::TABLE_CHECKSUMS;
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v")).ok());
+        assert_eq!(0, db.mutex_.lock().unwrap().versions_.required_features());
+        assert!(db.flush().ok());
+        assert_eq!(features::TABLE_CHECKSUMS, db.mutex_.lock().unwrap().versions_.required_features());
+        let manifest = descriptor_file_name(DBNAME, db.mutex_.lock().unwrap().versions_.manifest_file_number());
+        drop(db);
+        let mut reader = Reader::new(env.new_sequential_file(&manifest).unwrap(), None, true, 0);
+        let mut checked = false;
+        while let Some(record) = reader.read_record() {
+            let edit = VersionEdit::decode_from(&Slice::new(&record)).unwrap();
+            if !checked && edit.new_files_.iter().any(|(_, f)| f.file_checksum.is_some()) {
+                assert!(edit.has_required_features_ && edit.required_features_ & features::TABLE_CHECKSUMS != 0);
+                checked = true;
+            }
+        }
+        assert!(checked);
+        set_supported(Some(without_checksums));
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        set_supported(None);
+        assert!(s.is_not_supported_error(), "{}", s.to_string());
+        assert!(s.to_string().starts_with("Not implemented: this database requires: table checksum support (file /db/MANIFEST-"), "{}", s.to_string());
+        assert!(DB::open(&options, DBNAME).is_ok());
+    }
+
+    #[test]
+    fn idempotency_token_expiry_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.keep_log_file_num = 1;
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(put_with_token(&db, 1, "foo", "v1").ok());
+
+        // A flush keeps the token's log around, and with it the token.
+        assert!(db.flush_memtable(&mut db.mutex_.lock().unwrap()).ok());
+        assert_eq!(2, log_files(&env));
+        assert!(put_with_token(&db, 1, "foo", "v1").is_already_applied());
+
+        // The next one deletes the log and forgets the token, in memory
+        // and across a reopen.
+        assert!(db.flush_memtable(&mut db.mutex_.lock().unwrap()).ok());
+        assert_eq!(2, log_files(&env));
+        assert!(put_with_token(&db, 2, "bar", "v1").ok());
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(put_with_token(&db, 2, "bar", "v1").is_already_applied());
+        assert!(put_with_token(&db, 1, "foo", "v2").ok());
+        assert_eq!(2, internal_entries(&db, "foo"));
+
+        // Without retained logs, the token goes with the first flush.
+        options.keep_log_file_num = 0;
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(1, log_files(&env));
+        assert!(put_with_token(&db, 1, "foo", "v3").ok());
+    }
+
+    fn stats_line(db: &DB, prefix: &str) -> String {
+        let stats = db.get_property("leveldb.stats").unwrap();
+        stats.lines().find(|line| line.starts_with(prefix)).unwrap().to_string()
+    }
+
+    #[test]
+    fn write_stall_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.write_buffer_size = MIN_WRITE_BUFFER_SIZE;     // Every write fills the memtable
+        let db = DB::open(&options, DBNAME).unwrap();
+        // Both triggers are below the level-0 compaction trigger, so
+        // background compaction leaves level-0 alone.
+        assert!(db.set_options(&[("l0_slowdown_writes_trigger", "2"), ("l0_stop_writes_trigger", "3")]).ok());
+        assert_eq!("write stall: none (level-0 files: 0, slowdown at 2, stop at 3)", stats_line(&db, "write stall"));
+
+        // Rewriting the same key makes every flush overlap level-0 once it
+        // has a file.
+        let wo = WriteOptions::default();
+        let put = |i: usize| {
+            let start = env.now_micros();
+            assert!(db.put(&wo, &Slice::new(b"key"), &Slice::new(format!("{:05}{}", i, "0".repeat(MIN_WRITE_BUFFER_SIZE)).as_bytes())).ok());
+            env.now_micros() - start
+        };
+        let mut i = 0;
+        while files_per_level(&db)[0] < 2 {
+            assert_eq!("delayed writes: 0", stats_line(&db, "delayed writes"));
+            put(i);
+            i += 1;
+        }
+        // The write that flushed the second file was delayed after it.
+        assert_eq!("delayed writes: 1", stats_line(&db, "delayed writes"));
+        assert_eq!("write stall: slowdown (level-0 files: 2, slowdown at 2, stop at 3)", stats_line(&db, "write stall"));
+
+        // From now on every write is delayed, once.
+        assert!(put(i) >= 1000);
+        i += 1;
+        assert_eq!("delayed writes: 2", stats_line(&db, "delayed writes"));
+        assert_eq!("write stall: stop (level-0 files: 3, slowdown at 2, stop at 3)", stats_line(&db, "write stall"));
+        assert_eq!("stopped writes: 0", stats_line(&db, "stopped writes"));
+
+        // The next write has to wait for level-0 to be compacted.
+        put(i);
+        assert_eq!("stopped writes: 1", stats_line(&db, "stopped writes"));
+        assert!(files_per_level(&db)[0] < 2, "{:?}", files_per_level(&db));
+        assert!(stats_line(&db, "write stall").starts_with("write stall: none"));
+        assert_eq!(format!("{:05}{}", i, "0".repeat(MIN_WRITE_BUFFER_SIZE)).into_bytes(), db.get(&ReadOptions::new(), &Slice::new(b"key")).unwrap());
+    }
+
+    #[test]
+    fn memtable_rotation_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.write_buffer_size = MIN_WRITE_BUFFER_SIZE;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let first_log = db.mutex_.lock().unwrap().logfile_number_;
+        let key = |i: usize| format!("key{:04}", i % 100);
+        let value = |i: usize| format!("{:0>1300}", i);
+
+        // Two rounds over the same keys, so later flushes overlap earlier
+        // ones and stay in level-0.
+        let wo = WriteOptions::default();
+        for i in 0..200 {
+            assert!(db.put(&wo, &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+            // The memtable outgrows the buffer by at most one write.
+            assert!(db.mutex_.lock().unwrap().mem_.as_ref().unwrap().approximate_memory_usage() <= MIN_WRITE_BUFFER_SIZE + 4096);
+        }
+        assert!(db.mutex_.lock().unwrap().imm_.is_none());
+        assert!(files_per_level(&db)[0] > 0, "{:?}", files_per_level(&db));
+        assert!(db.mutex_.lock().unwrap().logfile_number_ > first_log);
+        assert_eq!(1, log_files(&env));
+
+        let ro = ReadOptions::new();
+        for i in 100..200 {
+            assert_eq!(value(i).into_bytes(), db.get(&ro, &Slice::new(key(i).as_bytes())).unwrap());
+        }
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 100..200 {
+            assert_eq!(value(i).into_bytes(), db.get(&ro, &Slice::new(key(i).as_bytes())).unwrap());
+        }
+    }
+
+    #[test]
+    fn auto_compaction_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.write_buffer_size = MIN_WRITE_BUFFER_SIZE;     // Every write fills the memtable
+        let db = DB::open(&options, DBNAME).unwrap();
+
+        // Rewriting one key keeps every flush in level-0, until it holds
+        // enough files to be compacted into level-1.
+        let value = |i: usize| format!("{:05}{}", i, "0".repeat(MIN_WRITE_BUFFER_SIZE));
+        for i in 0..20 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"key"), &Slice::new(value(i).as_bytes())).ok());
+            assert!(files_per_level(&db)[0] < 4, "{:?}", files_per_level(&db));
+        }
+        assert_eq!(1, files_per_level(&db)[1], "{:?}", files_per_level(&db));
+        assert!(internal_entries(&db, "key") < 5);
+        assert!(!db.mutex_.lock().unwrap().versions_.needs_compaction());
+        assert_eq!(value(19).into_bytes(), db.get(&ReadOptions::new(), &Slice::new(b"key")).unwrap());
+        assert!(table_files(&env) < 5);
+    }
+
+    #[test]
+    fn background_error_test() {
+        let mut options = options_with_env(new_mem_env());
+        options.write_buffer_size = MIN_WRITE_BUFFER_SIZE;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let put = |key: &str| db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(&[b'x'; MIN_WRITE_BUFFER_SIZE]));
+        assert!(put("a").ok());
+
+        // The write that needs the memtable flushed fails with the error,
+        // and so does every later write, even once tables can be written.
+        let failure = sync_point::fail("build-table:before-sync", Status::io_error("table sync", "injected"));
+        let s = put("b");
+        assert!(s.is_io_error(), "{}", s.to_string());
+        drop(failure);
+        assert_eq!(s.to_string(), put("c").to_string());
+        assert!(db.delete(&wo, &Slice::new(b"a")).is_io_error());
+
+        // Reads still work, and reopening recovers what was written.
+        assert_eq!(vec![b'x'; MIN_WRITE_BUFFER_SIZE], db.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(vec![b'x'; MIN_WRITE_BUFFER_SIZE], db.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        assert!(db.put(&wo, &Slice::new(b"d"), &Slice::new(b"v")).ok());
+    }
+
+    /// An Env whose files with "pattern_" in their name fail to sync while
+    /// "fail_" is set, and sync fine again once it is cleared.  Like a real
+    /// Env it makes those failures sticky, and it keeps the files.
+    struct FailSyncEnv {
+        base_: Arc<dyn Env>,
+        fail_: Arc<AtomicBool>,
+        pattern_: &'static str,
+        files_: Mutex<Vec<Arc<dyn WritableFile>>>,
+    }
+
+    impl FailSyncEnv {
+        fn new(pattern: &'static str) -> Arc<Self> {
+            Arc::new(Self { base_: new_mem_env(), fail_: Arc::new(AtomicBool::new(false)), pattern_: pattern, files_: Mutex::new(Vec::new()) })
+        }
+    }
+
+    struct FailSyncFile {
+        file_: Arc<dyn WritableFile>,
+        fail_: Arc<AtomicBool>,
+    }
+
+    impl WritableFile for FailSyncFile {
+        fn append(&self, data: &Slice) -> Status { self.file_.append(data) }
+        fn close(&self) -> Status { self.file_.close() }
+        fn flush(&self) -> Status { self.file_.flush() }
+        fn sync(&self) -> Status {
+            if self.fail_.load(atomic::Ordering::SeqCst) {
+                return Status::io_error("sync", "injected failure");
+            }
+            self.file_.sync()
+        }
+    }
+
+    impl Env for FailSyncEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+            let file = self.base_.new_writable_file(fname)?;
+            if !fname.contains(self.pattern_) {
+                return Ok(file);
+            }
+            let file = StickyErrorFile::wrap(Arc::new(FailSyncFile { file_: file, fail_: self.fail_.clone() }));
+            self.files_.lock().unwrap().push(file.clone());
+            Ok(file)
+        }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    #[test]
+    fn sync_error_test() {
+        let env = FailSyncEnv::new(".log");
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        let sync = WriteOptions { sync: true, ..Default::default() };
+        assert!(db.put(&sync, &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+
+        // A write whose log sync fails fails, and so does every later
+        // write, synced or not, once the sync would succeed again.
+        env.fail_.store(true, atomic::Ordering::SeqCst);
+        let s = db.put(&sync, &Slice::new(b"b"), &Slice::new(b"v2"));
+        assert!(s.is_io_error(), "{}", s.to_string());
+        env.fail_.store(false, atomic::Ordering::SeqCst);
+        assert_eq!(s.to_string(), db.put(&sync, &Slice::new(b"c"), &Slice::new(b"v3")).to_string());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"c"), &Slice::new(b"v3")).is_io_error());
+        assert_eq!(b"v1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"c")).unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn sticky_sync_error_test() {
+        let env = FailSyncEnv::new(".log");
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        let sync = WriteOptions { sync: true, ..Default::default() };
+        let put = |db: &DB, wo: &WriteOptions, key: &str, value: &str| db.put(wo, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes()));
+        assert!(put(&db, &sync, "a", "v1").ok());
+        let logname = log_file_name(DBNAME, db.mutex_.lock().unwrap().logfile_number_);
+        let synced = env.get_file_size(&logname).unwrap();
+        assert!(put(&db, &WriteOptions::default(), "u", "v2").ok());
+
+        // The log fails to sync once, then would pretend to sync fine.
+        env.fail_.store(true, atomic::Ordering::SeqCst);
+        let s = put(&db, &sync, "b", "v3");
+        env.fail_.store(false, atomic::Ordering::SeqCst);
+        assert!(s.is_io_error(), "{}", s.to_string());
+
+        // The file keeps failing with the same error, the DB has recorded
+        // it and let go of the log, and nothing more is acknowledged.
+        let log = env.files_.lock().unwrap().last().unwrap().clone();
+        assert_eq!(s.to_string(), log.sync().to_string());
+        assert_eq!(s.to_string(), log.append(&Slice::new(b"x")).to_string());
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(s.to_string(), state.bg_error_.to_string());
+            assert!(state.log_.is_none() && state.logfile_.is_none());
+        }
+        assert_eq!(s.to_string(), put(&db, &sync, "c", "v4").to_string());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"b")).unwrap_err().is_not_found());
+        drop(db);
+
+        // What the log held past its last good sync is gone.
+        let data = env.new_random_access_file(&logname).unwrap().read(0, synced as usize).unwrap();
+        assert!(write_string_to_file_sync(env.clone(), &Slice::new(&data), &logname).ok());
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(b"v1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        for key in ["b", "c"] {
+            assert!(db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).unwrap_err().is_not_found(), "{}", key);
+        }
+        assert!(put(&db, &sync, "d", "v5").ok());
+    }
+
+    #[test]
+    fn manifest_sync_error_test() {
+        let env = FailSyncEnv::new("MANIFEST");
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+
+        // A flush whose MANIFEST sync fails is a background error at once,
+        // and the MANIFEST is never written again.
+        env.fail_.store(true, atomic::Ordering::SeqCst);
+        let s = db.flush();
+        env.fail_.store(false, atomic::Ordering::SeqCst);
+        assert!(s.is_io_error(), "{}", s.to_string());
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(s.to_string(), state.bg_error_.to_string());
+            assert!(state.versions_.manifest_failed());
+        }
+        let manifest = env.files_.lock().unwrap().last().unwrap().clone();
+        assert_eq!(s.to_string(), manifest.sync().to_string());
+        assert_eq!(s.to_string(), db.put(&WriteOptions::default(), &Slice::new(b"b"), &Slice::new(b"v2")).to_string());
+        drop(db);
+
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(b"v1".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"a")).unwrap());
+        assert!(db.flush().ok());
+    }
+
+    /// An Env with a mock clock that counts its reads, and that a log file
+    /// sync advances by "sync_micros_".
+    struct SlowSyncEnv {
+        base_: Arc<dyn Env>,
+        clock_: Arc<AtomicU64>,
+        clock_reads_: AtomicU64,
+        sync_micros_: u64,
+    }
+
+    struct SlowSyncFile {
+        file_: Arc<dyn WritableFile>,
+        clock_: Arc<AtomicU64>,
+        sync_micros_: u64,
+    }
+
+    impl WritableFile for SlowSyncFile {
+        fn append(&self, data: &Slice) -> Status { self.file_.append(data) }
+        fn close(&self) -> Status { self.file_.close() }
+        fn flush(&self) -> Status { self.file_.flush() }
+        fn sync(&self) -> Status {
+            self.clock_.fetch_add(self.sync_micros_, atomic::Ordering::SeqCst);
+            self.file_.sync()
+        }
+    }
+
+    impl Env for SlowSyncEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+            let file = self.base_.new_writable_file(fname)?;
+            if !fname.ends_with(".log") {
+                return Ok(file);
+            }
+            Ok(Arc::new(SlowSyncFile { file_: file, clock_: self.clock_.clone(), sync_micros_: self.sync_micros_ }))
+        }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 {
+            self.clock_reads_.fetch_add(1, atomic::Ordering::SeqCst);
+            self.clock_.load(atomic::Ordering::SeqCst)
+        }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    fn new_slow_sync_env(sync_micros: u64) -> Arc<SlowSyncEnv> {
+        Arc::new(SlowSyncEnv { base_: new_mem_env(), clock_: Arc::new(AtomicU64::new(0)), clock_reads_: AtomicU64::new(0), sync_micros_: sync_micros })
+    }
+
+    #[test]
+    fn write_timing_test() {
+        let env = new_slow_sync_env(5000);
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"a"), &Slice::new(b"v")).ok());
+        assert_eq!(None, DB::last_write_timing());
+
+        // Without collect_timing, a write does not read the clock.
+        let reads = env.clock_reads_.load(atomic::Ordering::SeqCst);
+        assert!(db.put(&WriteOptions { sync: true, ..Default::default() }, &Slice::new(b"b"), &Slice::new(b"v")).ok());
+        assert_eq!(reads, env.clock_reads_.load(atomic::Ordering::SeqCst));
+        assert_eq!(None, DB::last_write_timing());
+        assert_eq!(0, db.write_timing_report().writes);
+
+        // The slow sync dominates a synced write.
+        let timed_sync = WriteOptions { sync: true, collect_timing: true, ..Default::default() };
+        assert!(db.put(&timed_sync, &Slice::new(b"c"), &Slice::new(b"v")).ok());
+        let timing = DB::last_write_timing().unwrap();
+        assert_eq!(WriteTiming { wal_sync_micros: 5000, total_micros: 5000, ..Default::default() }, timing);
+
+        let timed = WriteOptions { collect_timing: true, ..Default::default() };
+        assert!(db.put(&timed, &Slice::new(b"d"), &Slice::new(b"v")).ok());
+        assert_eq!(Some(WriteTiming::default()), DB::last_write_timing());
+
+        // The timing is per thread.
+        std::thread::scope(|scope| scope.spawn(|| assert_eq!(None, DB::last_write_timing())).join().unwrap());
+
+        let report = db.write_timing_report();
+        assert_eq!((2, 0), (report.writes, report.grouped_writes));
+        assert_eq!(StepLatency { p50_micros: 0, p99_micros: 5000 }, report.wal_sync);
+        assert_eq!(StepLatency { p50_micros: 0, p99_micros: 5000 }, report.total);
+        assert_eq!(StepLatency::default(), report.queue_wait);
+    }
+
+    #[test]
+    fn write_timing_grouped_test() {
+        let env = new_slow_sync_env(0);
+        let db = DB::open(&options_with_env(env.clone()), DBNAME).unwrap();
+        let timed = WriteOptions { collect_timing: true, ..Default::default() };
+        let reached = Arc::new(std::sync::Barrier::new(2));
+        let release = Arc::new(std::sync::Barrier::new(2));
+        std::thread::scope(|scope| {
+            // The leader stops before logging, with its group already
+            // built, so the followers queue up behind it.
+            let leader = scope.spawn(|| {
+                let (reached, release) = (reached.clone(), release.clone());
+                let _pause = sync_point::activate("db:write:before-log", move || {
+                    reached.wait();
+                    release.wait();
+                    None
+                });
+                assert!(db.put(&timed, &Slice::new(b"leader"), &Slice::new(b"v")).ok());
+                DB::last_write_timing().unwrap()
+            });
+            reached.wait();
+            let followers: Vec<_> = (0..3).map(|i| scope.spawn({
+                let (db, timed) = (&db, &timed);
+                move || {
+                    let key = format!("follower{}", i);
+                    assert!(db.put(timed, &Slice::new(key.as_bytes()), &Slice::new(b"v")).ok());
+                    DB::last_write_timing().unwrap()
+                }
+            })).collect();
+            while db.mutex_.lock().unwrap().writers_.len() < 4 {
+                std::thread::yield_now();
+            }
+            env.clock_.fetch_add(1_000_000, atomic::Ordering::SeqCst);
+            release.wait();
+
+            // The pause came before the leader's steps were timed.
+            assert_eq!(WriteTiming { total_micros: 1_000_000, ..Default::default() }, leader.join().unwrap());
+
+            // One follower leads the next group, which the other two join.
+            let followers: Vec<WriteTiming> = followers.into_iter().map(|f| f.join().unwrap()).collect();
+            assert!(followers.iter().all(|t| t.queue_wait_micros >= 1_000_000), "{:?}", followers);
+            let grouped: Vec<&WriteTiming> = followers.iter().filter(|t| t.grouped).collect();
+            assert_eq!(2, grouped.len());
+            for t in grouped {
+                assert_eq!((0, 0, 0), (t.wal_append_micros, t.wal_sync_micros, t.memtable_insert_micros));
+            }
+        });
+        let report = db.write_timing_report();
+        assert_eq!((4, 2), (report.writes, report.grouped_writes));
+        assert_eq!(1_000_000, report.queue_wait.p99_micros);
+    }
+
+    /// An Env that records the preallocate() calls on the files it
+    /// creates, by file name.
+    struct PreallocEnv {
+        base_: Arc<dyn Env>,
+        calls_: Arc<Mutex<Vec<(String, u64, u64)>>>,
+    }
+
+    struct PreallocFile {
+        file_: Arc<dyn WritableFile>,
+        fname_: String,
+        calls_: Arc<Mutex<Vec<(String, u64, u64)>>>,
+    }
+
+    impl WritableFile for PreallocFile {
+        fn append(&self, data: &Slice) -> Status { self.file_.append(data) }
+        fn close(&self) -> Status { self.file_.close() }
+        fn flush(&self) -> Status { self.file_.flush() }
+        fn sync(&self) -> Status { self.file_.sync() }
+        fn preallocate(&self, offset: u64, len: u64) -> Status {
+            self.calls_.lock().unwrap().push((self.fname_.clone(), offset, len));
+            self.file_.preallocate(offset, len)
+        }
+    }
+
+    impl Env for PreallocEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+            let file = self.base_.new_writable_file(fname)?;
+            Ok(Arc::new(PreallocFile { file_: file, fname_: fname.to_string(), calls_: self.calls_.clone() }))
+        }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    #[test]
+    fn preallocation_test() {
+        let env = Arc::new(PreallocEnv { base_: new_mem_env(), calls_: Arc::new(Mutex::new(Vec::new())) });
+        let mut options = options_with_env(env.clone());
+        options.write_buffer_size = 100_000;
+        options.max_file_size = 400_000;
+        options.preallocation_block_size = 4096;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let calls_of = |type_: FileType| -> Vec<(u64, u64)> {
+            env.calls_.lock().unwrap().iter()
+                .filter(|(fname, _, _)| parse_file_name(fname.rsplit('/').next().unwrap()).is_some_and(|(_, t)| t == type_))
+                .map(|&(_, offset, len)| (offset, len))
+                .collect()
+        };
+        assert_eq!(vec![(0, 100_000)], calls_of(FileType::LogFile));
+        assert_eq!(vec![(0, 100_000)], calls_of(FileType::DescriptorFile));
+        assert!(calls_of(FileType::TableFile).is_empty());
+
+        // A new log is preallocated as write_buffer_size is now
+        assert!(db.set_options(&[("write_buffer_size", "200000")]).ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"k"), &Slice::new(b"v")).ok());
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(vec![(0, 100_000), (0, 200_000)], calls_of(FileType::LogFile));
+        drop(db);
+
+        // Turned off, nothing is preallocated
+        env.calls_.lock().unwrap().clear();
+        options.preallocation_block_size = 0;
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..1000 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(&[b'x'; 500])).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        assert!(env.calls_.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn preallocated_log_recovery_default_env_test() {
+        let dir = std::env::temp_dir().join(format!("rucksdb-db-prealloc-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dbname = dir.to_string_lossy().into_owned();
+        let mut options = Options::new();
+        options.create_if_missing = true;
+        let log_sizes = || -> Vec<u64> {
+            std::fs::read_dir(&dir).unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| entry.file_name().to_string_lossy().ends_with(".log"))
+                .map(|entry| entry.metadata().unwrap().len())
+                .collect()
+        };
+        {
+            let db = DB::open(&options, &dbname).unwrap();
+            for i in 0..100 {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(b"value")).ok());
+            }
+            // The log is preallocated well past what was written, but its
+            // length is only what was written.
+            let sizes = log_sizes();
+            assert_eq!(1, sizes.len());
+            assert!(sizes[0] > 0 && sizes[0] < 10_000, "{:?}", sizes);
+        }
+        {
+            let db = DB::open(&options, &dbname).unwrap();
+            for i in 0..100 {
+                assert_eq!(b"value".to_vec(), db.get(&ReadOptions::new(), &Slice::new(format!("key{}", i).as_bytes())).unwrap());
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crash_between_flush_build_and_manifest_write_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        for i in 0..100 {
+            assert!(db.put(&wo, &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(format!("value{}", i).as_bytes())).ok());
+        }
+
+        // The flush dies after building its table, before the MANIFEST
+        // records it, leaving the table behind.
+        let crash = sync_point::fail("db:flush:after-build", Status::io_error("crash", "injected"));
+        assert!(db.compact_range(None, None).is_io_error());
+        drop(crash);
+        assert_eq!(1, table_files(&env));
+        assert_eq!(0, files_per_level(&db).iter().sum::<usize>());
+        drop(db);
+
+        // Recovery replays the log, and the orphaned table is deleted or
+        // overwritten, since its file number was never recorded.
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..100 {
+            assert_eq!(format!("value{}", i).into_bytes(), db.get(&ReadOptions::new(), &Slice::new(format!("key{}", i).as_bytes())).unwrap());
+        }
+        assert_eq!(files_per_level(&db).iter().sum::<usize>(), table_files(&env));
+    }
+
+    /// An Env with a mock clock that counts lock attempts and calls
+    /// "on_sleep_" with the number of sleeps so far on every sleep.
+    struct SleepHookEnv {
+        base_: Arc<dyn Env>,
+        clock_: AtomicU64,
+        sleeps_: AtomicU32,
+        lock_attempts_: AtomicU32,
+        on_sleep_: Mutex<Box<dyn FnMut(u32) + Send>>,
+    }
+
+    impl Env for SleepHookEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_appendable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn link_file(&self, src: &str, target: &str) -> Status { self.base_.link_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> {
+            self.lock_attempts_.fetch_add(1, atomic::Ordering::SeqCst);
+            self.base_.lock_file(fname)
+        }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 { self.clock_.load(atomic::Ordering::SeqCst) }
+        fn sleep_for_microseconds(&self, micros: u64) {
+            self.clock_.fetch_add(micros, atomic::Ordering::SeqCst);
+            self.sleeps_.fetch_add(1, atomic::Ordering::SeqCst);
+            (self.on_sleep_.lock().unwrap())(self.sleeps_.load(atomic::Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn open_with_retry_test() {
+        let base = new_mem_env();
+        drop(DB::open(&options_with_env(base.clone()), DBNAME).unwrap());
+        let new_env = |on_sleep: Box<dyn FnMut(u32) + Send>| Arc::new(SleepHookEnv {
+            base_: base.clone(),
+            clock_: AtomicU64::new(0),
+            sleeps_: AtomicU32::new(0),
+            lock_attempts_: AtomicU32::new(0),
+            on_sleep_: Mutex::new(on_sleep),
+        });
+        let retry = RetryPolicy { max_wait_micros: 100_000, backoff_start_micros: 1000, backoff_multiplier: 2.0, cancel: None };
+
+        // The holder lets go during the third wait, so the next attempt
+        // gets the lock.
+        let held = Arc::new(Mutex::new(Some(base.lock_file(&lock_file_name(DBNAME)).unwrap())));
+        let (holder_env, holder) = (base.clone(), held.clone());
+        let env = new_env(Box::new(move |sleeps| if sleeps == 3 {
+            assert!(holder_env.unlock_file(holder.lock().unwrap().take().unwrap()).ok());
+        }));
+        let db = DB::open_with_retry(&options_with_env(env.clone()), DBNAME, retry.clone()).unwrap();
+        assert_eq!((3, 4), (env.sleeps_.load(atomic::Ordering::SeqCst), env.lock_attempts_.load(atomic::Ordering::SeqCst)));
+        assert_eq!(1000 + 2000 + 4000, env.clock_.load(atomic::Ordering::SeqCst));
+        assert!(held.lock().unwrap().is_none());
+        drop(db);
+
+        // Nobody lets go: the waits double until max_wait_micros is spent.
+        let lock = base.lock_file(&lock_file_name(DBNAME)).unwrap();
+        let env = new_env(Box::new(|_| {}));
+        let s = DB::open_with_retry(&options_with_env(env.clone()), DBNAME, retry.clone()).err().unwrap();
+        assert!(s.is_busy());
+        assert!(s.to_string().contains("gave up after 8 attempts, waited 100000 micros"), "{}", s.to_string());
+        assert_eq!((7, 8, 100_000), (env.sleeps_.load(atomic::Ordering::SeqCst), env.lock_attempts_.load(atomic::Ordering::SeqCst), env.clock_.load(atomic::Ordering::SeqCst)));
+
+        // Cancelling during a wait stops before the next attempt.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = cancel.clone();
+        let env = new_env(Box::new(move |sleeps| if sleeps == 2 {
+            canceller.store(true, std::sync::atomic::Ordering::Release);
+        }));
+        let policy = RetryPolicy { cancel: Some(cancel), ..retry.clone() };
+        let s = DB::open_with_retry(&options_with_env(env.clone()), DBNAME, policy).err().unwrap();
+        assert!(s.is_busy());
+        assert!(s.to_string().contains("cancelled after 2 attempts, waited 3000 micros"), "{}", s.to_string());
+        assert_eq!((2, 2), (env.sleeps_.load(atomic::Ordering::SeqCst), env.lock_attempts_.load(atomic::Ordering::SeqCst)));
+        assert!(base.unlock_file(lock).ok());
+
+        // Other errors are returned without waiting.
+        let current = base.new_writable_file(&current_file_name(DBNAME)).unwrap();
+        assert!(current.append(&Slice::new(b"MANIFEST-000001")).ok());
+        let env = new_env(Box::new(|_| {}));
+        let s = DB::open_with_retry(&options_with_env(env.clone()), DBNAME, retry).err().unwrap();
+        assert!(s.is_corruption(), "{}", s.to_string());
+        assert_eq!((0, 1), (env.sleeps_.load(atomic::Ordering::SeqCst), env.lock_attempts_.load(atomic::Ordering::SeqCst)));
+    }
+
+    /// Put "n" values of "size" bytes under keys starting with "prefix".
+    fn put_values(db: &DB, prefix: &str, n: usize, size: usize) {
+        for i in 0..n {
+            let key = format!("{}{:05}", prefix, i);
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(&vec![b'x'; size])).ok());
+        }
+    }
+
+    /// Add a table holding "n" values of "size" bytes to "level", written
+    /// without the properties block, as older versions did.
+    fn add_table_without_properties(db: &DB, level: i32, prefix: &str, n: usize, size: usize) {
+        let mut state = db.mutex_.lock().unwrap();
+        let number = state.versions_.new_file_number();
+        let file = db.env_.new_writable_file(&table_file_name(DBNAME, number)).unwrap();
+        let mut builder = TableBuilder::new(&db.options_, file.clone());
+        builder.skip_properties();
+        let key = |i: usize| InternalKey::new_from(&Slice::new(format!("{}{:05}", prefix, i).as_bytes()), 1, ValueType::type_value());
+        for i in 0..n {
+            builder.add(&key(i).encode(), &Slice::new(&vec![b'x'; size]));
+        }
+        assert!(builder.finish().ok());
+        assert!(file.close().ok());
+        let mut edit = VersionEdit::new();
+        edit.add_file(level, number, builder.file_size(), &key(0), &key(n - 1));
+        assert!(state.versions_.log_and_apply(&mut edit, None).ok());
+    }
+
+    #[test]
+    fn value_size_histogram_test() {
+        let env = new_mem_env();
+        let db = DB::open(&options_with_env(env), DBNAME).unwrap();
+        // Bimodal: many 20 byte values, a few 5000 byte ones.
+        put_values(&db, "small", 970, 20);
+        put_values(&db, "large", 30, 5000);
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(vec![0, 0, 1, 0, 0, 0, 0], files_per_level(&db));
+
+        let histogram = db.get_property("leveldb.value-size-histogram").unwrap();
+        assert_eq!("level 2: tables: 1 (without histogram: 0), values: 1000, bytes: 169400\n  \
+                    [16, 32): 970 values, 19400 bytes\n  \
+                    [4096, 8192): 30 values, 150000 bytes\n", histogram);
+        let advice = db.advise_value_threshold();
+        let threshold = advice.threshold.unwrap();
+        assert!(threshold > 20 && threshold <= 5000, "{}", threshold);
+        assert_eq!((1000, 169400, 30, 150000, 0),
+                   (advice.values, advice.value_bytes, advice.large_values, advice.large_value_bytes,
+                    advice.tables_without_histogram));
+
+        // Old tables are reported but do not skew the numbers, even if
+        // they are full of large values.
+        add_table_without_properties(&db, 4, "old", 500, 5000);
+        let histogram = db.get_property("leveldb.value-size-histogram").unwrap();
+        assert!(histogram.contains("level 4: tables: 1 (without histogram: 1), values: 0, bytes: 0\n"), "{}", histogram);
+        let mixed = db.advise_value_threshold();
+        assert_eq!(1, mixed.tables_without_histogram);
+        assert_eq!(ValueThresholdAdvice { tables_without_histogram: 0, ..mixed }, advice);
+    }
+
+    #[test]
+    fn instance_registry_test() {
+        // Other tests may open named DBs at the same time, so only the
+        // names used here are looked at.
+        let listed = || list_instances().into_iter()
+            .filter(|info| info.name.starts_with("registry_test:"))
+            .map(|info| (info.name, info.path, info.health.state))
+            .collect::<Vec<_>>();
+        let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+        let named = |name: &str| {
+            let mut options = options_with_env(new_mem_env());
+            options.instance_name = Some(format!("registry_test:{}", name));
+            options.info_log = Some(logger.clone());
+            options
+        };
+
+        let a = DB::open(&named("a"), "/a").unwrap();
+        let b = DB::open(&named("b"), "/b").unwrap();
+        let c = DB::open(&named("c"), "/c").unwrap();
+        {
+            let mut state = b.mutex_.lock().unwrap();
+            b.record_background_error(&mut state, &Status::io_error("injected", ""));
+        }
+        assert_eq!(vec![
+            ("registry_test:a".to_string(), "/a".to_string(), HealthState::Healthy),
+            ("registry_test:b".to_string(), "/b".to_string(), HealthState::Failed),
+            ("registry_test:c".to_string(), "/c".to_string(), HealthState::Healthy),
+        ], listed());
+
+        // Every line the DBs logged names them.
+        let messages = logger.messages_.lock().unwrap().clone();
+        assert!(messages.iter().any(|msg| msg.starts_with("[registry_test:a] ")));
+        assert!(messages.iter().all(|msg| ["a", "b", "c"].iter().any(|name| msg.starts_with(&format!("[registry_test:{}] ", name)))), "{:?}", messages);
+
+        // A second DB with a taken name fails before creating anything.
+        let options = Options { instance_name: Some("registry_test:a".to_string()), ..options_with_env(new_mem_env()) };
+        let s = DB::open(&options, "/d").err().unwrap();
+        assert!(s.is_invalid_argument());
+        assert_eq!("Invalid argument: registry_test:a: is already the instance name of an open DB", s.to_string());
+        assert!(!options.env.file_exists(&current_file_name("/d")));
+        assert_eq!(3, listed().len());
+
+        // A dropped DB is no longer listed, and its name is free again.
+        drop(b);
+        assert_eq!(vec!["registry_test:a", "registry_test:c"], listed().iter().map(|(name, _, _)| name.as_str()).collect::<Vec<_>>());
+        let b = DB::open(&Options { instance_name: Some("registry_test:b".to_string()), ..options_with_env(new_mem_env()) }, "/b2").unwrap();
+        assert_eq!(("registry_test:b".to_string(), "/b2".to_string(), HealthState::Healthy), listed()[1]);
+        drop((a, b, c));
+        assert!(listed().is_empty());
+    }
+
+    #[test]
+    fn approximate_memory_usage_test() {
+        struct FixedCache;
+        impl Cache for FixedCache {
+            fn insert(&self, _key: &[u8], _value: CacheValue, _charge: usize) {}
+            fn lookup(&self, _key: &[u8]) -> Option<CacheValue> { None }
+            fn erase(&self, _key: &[u8]) {}
+            fn new_id(&self) -> u64 { 0 }
+            fn total_charge(&self) -> usize {
+                1000
+            }
+        }
+
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let property = || db.get_property("leveldb.approximate-memory-usage").unwrap().parse::<usize>().unwrap();
+        let empty = db.approximate_memory_usage();
+        assert_eq!(empty, property());
+
+        for i in 0..1000 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:06}", i).as_bytes()), &Slice::new(&[b'v'; 100])).ok());
+        }
+        let written = db.approximate_memory_usage();
+        assert!(written > empty + 100_000, "{} {}", empty, written);
+        assert_eq!(written, property());
+
+        // The flushed memtable is dropped; the new table only keeps its
+        // index block in memory.
+        assert!(db.flush().ok());
+        assert_eq!(Some("1".to_string()), db.get_property("leveldb.num-files-at-level0"));
+        assert_eq!(b"v".repeat(100), db.get(&ReadOptions::new(), &Slice::new(b"key000500")).unwrap());
+        let flushed = db.approximate_memory_usage();
+        assert!(flushed < empty + 10_000, "{} {}", empty, flushed);
+        drop(db);
+
+        let mut options = options_with_env(new_mem_env());
+        options.block_cache = Some(Arc::new(FixedCache));
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(empty + 1000, db.approximate_memory_usage());
+    }
+
+    #[test]
+    fn space_amp_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let put = |i: usize, round: u8| {
+            let key = format!("key{:06}", i);
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(&[b'a' + round; 100])).ok());
+        };
+        // Flushes under the lock do not schedule compactions, so the
+        // overwrites pile up in level-0.
+        let flush = || {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        };
+        let report = db.space_amp_report();
+        assert_eq!(SpaceAmpReport::new(0, 0, 0), report);
+        assert_eq!(1.0, report.space_amplification_ratio);
+
+        const N: usize = 2000;
+        (0..N).for_each(|i| put(i, 0));
+        flush();
+        let loaded = db.space_amp_report();
+        assert!((loaded.space_amplification_ratio - 1.0).abs() < 0.05, "{:?}", loaded);
+
+        // Overwrite half of the keys three times
+        let mut previous = loaded.space_amplification_ratio;
+        for round in 1..=3 {
+            (0..N).step_by(2).for_each(|i| put(i, round));
+            flush();
+            let report = db.space_amp_report();
+            assert!(report.space_amplification_ratio > previous, "{:?}", report);
+            previous = report.space_amplification_ratio;
+        }
+        assert_eq!(4, files_per_level(&db).iter().sum::<usize>());
+        let overwritten = db.space_amp_report();
+        assert!(overwritten.space_amplification_ratio > 2.0, "{:?}", overwritten);
+        let live = overwritten.estimated_live_bytes as f64;
+        assert!((live / loaded.estimated_live_bytes as f64 - 1.0).abs() < 0.1, "{:?} {:?}", loaded, overwritten);
+        assert_eq!(overwritten.estimated_live_bytes, db.live_data_size_estimate());
+        assert!(db.get_property("leveldb.space-amp").unwrap().ends_with(&format!("space amplification: {:.2}\n", overwritten.space_amplification_ratio)));
+
+        // Deletions are not live either
+        (0..N / 2).for_each(|i| assert!(db.delete(&WriteOptions::default(), &Slice::new(format!("key{:06}", i).as_bytes())).ok()));
+        flush();
+        assert!(db.space_amp_report().space_amplification_ratio > overwritten.space_amplification_ratio);
+
+        // A full compaction leaves only live data
+        assert!(db.compact_range(None, None).ok());
+        let compacted = db.space_amp_report();
+        assert!((compacted.space_amplification_ratio - 1.0).abs() < 0.05, "{:?}", compacted);
+        assert_eq!(0, compacted.obsolete_file_bytes_pending_deletion);
+        let logical = (N / 2) * ("key000000".len() + 100);
+        assert!((compacted.estimated_live_bytes as f64 / logical as f64 - 1.0).abs() < 0.25, "{} {:?}", logical, compacted);
+    }
+
+    #[test]
+    fn iterator_pool_test() {
+        // The same operations against a DB with and without a pool must
+        // read the same.
+        let open = |pool_size| {
+            let mut options = options_with_env(new_mem_env());
+            options.iterator_pool_size = pool_size;
+            options.write_buffer_size = 16 << 10;
+            DB::open(&options, DBNAME).unwrap()
+        };
+        let (plain, pooled) = (open(0), open(4));
+        let scan = |db: &DB, snapshot: Option<Arc<Snapshot>>, start: &[u8]| {
+            let options = ReadOptions { snapshot, ..ReadOptions::new() };
+            let mut iter = db.new_iterator(&options);
+            let mut forward = Vec::new();
+            iter.seek(&Slice::new(start));
+            while iter.valid() && forward.len() < 20 {
+                forward.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+                iter.next();
+            }
+            let mut backward = Vec::new();
+            iter.seek_to_last();
+            while iter.valid() && backward.len() < 20 {
+                backward.push(iter.key().data().to_vec());
+                iter.prev();
+            }
+            assert!(iter.status().ok());
+            (forward, backward)
+        };
+
+        let mut rnd = Random::new(301);
+        let mut snapshots = Vec::new();
+        for step in 0..400 {
+            let key = format!("key{:04}", rnd.uniform(500));
+            let delete = rnd.one_in(4);
+            for db in [&plain, &pooled] {
+                if delete {
+                    assert!(db.delete(&WriteOptions::default(), &Slice::new(key.as_bytes())).ok());
+                } else {
+                    assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(format!("{}:{}", key, step).repeat(10).as_bytes())).ok());
+                }
+            }
+            match step % 100 {
+                37 => snapshots.push((plain.get_snapshot(), pooled.get_snapshot())),
+                50 => [&plain, &pooled].iter().for_each(|db| assert!(db.flush().ok())),
+                99 => [&plain, &pooled].iter().for_each(|db| assert!(db.compact_range(None, None).ok())),
+                _ => {},
+            }
+            let start = format!("key{:04}", rnd.uniform(500));
+            assert_eq!(scan(&plain, None, start.as_bytes()), scan(&pooled, None, start.as_bytes()), "step {}", step);
+            for (plain_snapshot, pooled_snapshot) in &snapshots {
+                assert_eq!(scan(&plain, Some(plain_snapshot.clone()), start.as_bytes()),
+                           scan(&pooled, Some(pooled_snapshot.clone()), start.as_bytes()), "step {}", step);
+            }
+        }
+        assert!(files_per_level(&pooled).iter().sum::<usize>() > 0);
+    }
+
+    #[test]
+    fn iterator_pool_allocations_test() {
+        let short_scan_allocations = |pool_size| {
+            let mut options = options_with_env(new_mem_env());
+            options.iterator_pool_size = pool_size;
+            let db = DB::open(&options, DBNAME).unwrap();
+            // A memtable, level-0 files and a deeper level to iterate over
+            for round in 0..4 {
+                for i in (round..1000).step_by(4) {
+                    assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:04}", i).as_bytes()), &Slice::new(b"value")).ok());
+                }
+                let mut state = db.mutex_.lock().unwrap();
+                assert!(db.flush_memtable(&mut state).ok());
+            }
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"key0500"), &Slice::new(b"new")).ok());
+
+            let before = crate::util::testutil::allocations();
+            for _ in 0..100 {
+                let mut iter = db.new_iterator(&ReadOptions::new());
+                iter.seek(&Slice::new(b"key0500"));
+                assert_eq!(b"new", iter.value().data());
+                iter.next();
+                assert_eq!(b"key0501", iter.key().data());
+            }
+            (crate::util::testutil::allocations() - before) / 100
+        };
+        let (plain, pooled) = (short_scan_allocations(0), short_scan_allocations(4));
+        assert!(pooled * 4 < plain, "{} allocations per scan with the pool, {} without", pooled, plain);
+    }
+
+    #[test]
+    fn delete_range_test() {
+        let env = new_mem_env();
+        let mut options = options_with_env(env.clone());
+        options.iterator_pool_size = 2;
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let ro = ReadOptions::new();
+        let get = |db: &DB, options: &ReadOptions, key: &str| db.get(options, &Slice::new(key.as_bytes())).ok().map(|v| String::from_utf8(v).unwrap());
+        let backward = |db: &DB, options: &ReadOptions| {
+            let mut iter = db.new_iterator(options);
+            iter.seek_to_last();
+            let mut entries = scan(iter.as_mut(), false);
+            entries.reverse();
+            entries
+        };
+        let forward = |db: &DB, options: &ReadOptions| {
+            let mut iter = db.new_iterator(options);
+            iter.seek_to_first();
+            let entries = scan(iter.as_mut(), true);
+            assert_eq!(entries, backward(db, options));
+            entries
+        };
+        let put = |db: &DB, key: &str, value: &str| assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes())).ok());
+        let delete_range = |db: &DB, begin: &str, end: &str| db.delete_range(&wo, &Slice::new(begin.as_bytes()), &Slice::new(end.as_bytes()));
+
+        assert!(delete_range(&db, "b", "b").is_invalid_argument());
+        assert!(delete_range(&db, "c", "b").is_invalid_argument());
+        assert_eq!(0, db.mutex_.lock().unwrap().versions_.required_features() & features::RANGE_DELETIONS);
+
+        // "a".."e" in a table at a deeper level, "f".."j" in the memtable
+        ["a", "b", "c", "d", "e"].iter().for_each(|k| put(&db, k, &format!("old-{}", k)));
+        assert!(db.compact_range(None, None).ok());
+        ["f", "g", "h", "i", "j"].iter().for_each(|k| put(&db, k, &format!("old-{}", k)));
+        drop(forward(&db, &ro));    // Leaves an iterator in the pool
+        let snapshot = db.get_snapshot();
+        assert!(delete_range(&db, "b", "d").ok());
+        assert!(delete_range(&db, "cc", "h").ok());
+        put(&db, "c", "new-c");
+        put(&db, "g", "new-g");
+        assert_eq!(features::RANGE_DELETIONS, db.mutex_.lock().unwrap().versions_.required_features() & features::RANGE_DELETIONS);
+
+        let expected = pairs(&[("a", "old-a"), ("c", "new-c"), ("g", "new-g"), ("h", "old-h"), ("i", "old-i"), ("j", "old-j")]);
+        let at_snapshot = ReadOptions { snapshot: Some(snapshot.clone()), ..ReadOptions::new() };
+        let check = |db: &DB| {
+            assert_eq!(expected, forward(db, &ro));
+            for (key, value) in [("a", Some("old-a")), ("b", None), ("c", Some("new-c")), ("cc", None), ("d", None),
+                                 ("e", None), ("f", None), ("g", Some("new-g")), ("h", Some("old-h"))] {
+                assert_eq!(value.map(str::to_string), get(db, &ro, key), "{}", key);
+            }
+            // A snapshot from before the deletions still sees the keys
+            assert_eq!(10, forward(db, &at_snapshot).len());
+            assert_eq!(Some("old-b".to_string()), get(db, &at_snapshot, "b"));
+            assert_eq!(Some("old-f".to_string()), get(db, &at_snapshot, "f"));
+        };
+        check(&db);
+        let mut iter = db.new_iterator(&ro);
+        iter.seek(&Slice::new(b"bb"));
+        assert_eq!("c", iter.key().to_utf8_string().unwrap());
+        iter.prev();
+        assert_eq!("a", iter.key().to_utf8_string().unwrap());
+        drop(iter);
+
+        // Written out, the deletions become point deletions of the keys
+        // they cover, at their own sequence numbers.
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        assert!(db.mutex_.lock().unwrap().mem_.as_ref().unwrap().range_tombstones().is_empty());
+        check(&db);
+        assert_eq!(2, internal_entries(&db, "b"));
+        assert_eq!(2, internal_entries(&db, "f"));
+
+        // Compactions drop the covered entries once no snapshot needs them
+        assert!(db.compact_range(None, None).ok());
+        check(&db);
+        db.release_snapshot(snapshot);
+        assert_eq!(vec![0, 0, 1, 0, 0, 0, 0], files_per_level(&db));
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert!(db.compact_level_range(state, 2, None, None).1.ok());
+        }
+        assert_eq!(expected, forward(&db, &ro));
+        assert_eq!(0, internal_entries(&db, "b"));
+        assert_eq!(0, internal_entries(&db, "f"));
+        assert_eq!(1, internal_entries(&db, "c"));
+        drop(db);
+
+        // A deletion replayed from the log covers the tables
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert!(delete_range(&db, "", "b").ok());
+        assert!(delete_range(&db, "i", "z").ok());
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(pairs(&[("c", "new-c"), ("g", "new-g"), ("h", "old-h")]), forward(&db, &ro));
+        assert_eq!(features::RANGE_DELETIONS, db.mutex_.lock().unwrap().versions_.required_features() & features::RANGE_DELETIONS);
+    }
+
+    #[test]
+    fn tombstone_iterator_test() {
+        let db = DB::open(&options_with_env(new_mem_env()), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        let ro = ReadOptions::new();
+        let put = |key: &str| assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(b"v")).ok());
+        let delete = |key: &str| assert!(db.delete(&wo, &Slice::new(key.as_bytes())).ok());
+        let tombstones = |options: &ReadOptions| {
+            let mut iter = db.new_tombstone_iterator(options).unwrap();
+            iter.seek_to_last();
+            let mut backward: Vec<String> = scan(&mut *iter, false).into_iter().map(|(k, _)| k).collect();
+            backward.reverse();
+            iter.seek_to_first();
+            let keys: Vec<String> = scan(&mut *iter, true).into_iter().map(|(k, _)| k).collect();
+            assert_eq!(keys, backward);
+            (keys, iter.range_tombstones())
+        };
+
+        ["a", "b", "c", "d", "e", "f", "g"].iter().for_each(|k| put(k));
+        assert!(db.compact_range(None, None).ok());
+        let before = db.get_snapshot();
+
+        // "b" deleted in a level-0 file, "d" and "x" in the memtable; "x"
+        // never existed, so its deletion hides nothing.  "f" is deleted
+        // and written again.
+        delete("b");
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        delete("d");
+        delete("x");
+        delete("f");
+        put("f");
+        assert!(db.delete_range(&wo, &Slice::new(b"e"), &Slice::new(b"f")).ok());
+        assert_eq!((vec!["b".to_string(), "d".to_string()], vec![(b"e".to_vec(), b"f".to_vec())]), tombstones(&ro));
+
+        // Positioning in the middle, and changing direction
+        let mut iter = db.new_tombstone_iterator(&ro).unwrap();
+        iter.seek(&Slice::new(b"c"));
+        assert_eq!("d", iter.key().to_utf8_string().unwrap());
+        iter.prev();
+        assert_eq!("b", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        assert_eq!("d", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        assert!(!iter.valid());
+        drop(iter);
+
+        // Nothing was deleted yet at the older snapshot
+        let at_before = ReadOptions { snapshot: Some(before.clone()), ..ReadOptions::new() };
+        assert_eq!((Vec::<String>::new(), Vec::new()), tombstones(&at_before));
+
+        // Flushed, the range deletion becomes a point deletion of "e"
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            assert!(db.flush_memtable(&mut state).ok());
+        }
+        assert_eq!((vec!["b".to_string(), "d".to_string(), "e".to_string()], Vec::new()), tombstones(&ro));
+
+        // A full compaction drops the deletions along with the values they
+        // hide once no snapshot needs them.
+        db.release_snapshot(before);
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!((Vec::<String>::new(), Vec::new()), tombstones(&ro));
+        let mut iter = db.new_iterator(&ro);
+        iter.seek_to_first();
+        assert_eq!(pairs(&[("a", "v"), ("c", "v"), ("f", "v"), ("g", "v")]), scan(iter.as_mut(), true));
+    }
+
+    #[test]
+    fn memtable_prefix_compression_test() {
+        // The same writes produce the same reads and the same table file
+        // with and without prefix compression.
+        let tables: Vec<Vec<u8>> = [0, 16].into_iter().map(|prefix_len| {
+            let env = new_mem_env();
+            let options = Options { memtable_prefix_compression: prefix_len, ..options_with_env(env.clone()) };
+            let db = DB::open(&options, DBNAME).unwrap();
+            let wo = WriteOptions::default();
+            for i in 0..2000 {
+                let key = format!("user/{:04}/profile/{:05}", i % 13, i);
+                assert!(db.put(&wo, &Slice::new(key.as_bytes()), &Slice::new(format!("v{}", i).as_bytes())).ok());
+                if i % 7 == 0 {
+                    assert!(db.delete(&wo, &Slice::new(key.as_bytes())).ok());
+                }
+            }
+            assert!(db.put(&wo, &Slice::new(b"u"), &Slice::new(b"short")).ok());
+            assert_eq!(b"v8".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"user/0008/profile/00008")).unwrap());
+            assert!(db.get(&ReadOptions::new(), &Slice::new(b"user/0007/profile/00007")).unwrap_err().is_not_found());
+            let mut iter = db.new_iterator(&ReadOptions::new());
+            iter.seek_to_last();
+            let backward = scan(iter.as_mut(), false);
+            assert_eq!(2000 - 286 + 1, backward.len());
+            assert_eq!(("u".to_string(), "short".to_string()), backward[backward.len() - 1]);
+
+            assert!(db.flush().ok());
+            let table = env.get_children(DBNAME).unwrap().into_iter()
+                .find(|name| matches!(parse_file_name(name), Some((_, FileType::TableFile)))).unwrap();
+            let fname = format!("{}/{}", DBNAME, table);
+            let size = env.get_file_size(&fname).unwrap() as usize;
+            env.new_random_access_file(&fname).unwrap().read(0, size).unwrap()
+        }).collect();
+        assert!(tables[0] == tables[1]);
+    }
+
+    #[test]
+    fn close_with_deadline_test() {
+        let env = new_slow_sync_env(0);
+        let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+        let options = Options { info_log: Some(logger.clone()), ..options_with_env(env.clone()) };
+        let mut db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..100 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:03}", i).as_bytes()), &Slice::new(b"v")).ok());
+        }
+        let report = db.close_with_deadline(u64::MAX).unwrap();
+        assert_eq!(CloseReport { memtable_flushed: true, last_sequence: 100, wal_trailer_written: true, ..Default::default() }, report);
+        drop(db);
+
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(100, full_scan(&db).len());
+        assert!(logger.messages_.lock().unwrap().iter().any(|m| m.ends_with("clean shutdown verified")));
+    }
+
+    #[test]
+    fn close_with_deadline_abandon_test() {
+        let env = new_slow_sync_env(0);
+        let options = Options { max_file_size: 4096, ..options_with_env(env.clone()) };
+        let mut db = DB::open(&options, DBNAME).unwrap();
+        let write_round = |db: &DB, round: usize| {
+            for i in 0..500 {
+                let value = format!("value{}-{}", round, i);
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:03}", i).as_bytes()), &Slice::new(value.as_bytes())).ok());
+            }
+        };
+        // Three level-0 files, one short of a compaction, and the writes
+        // for a fourth in the memtable
+        for round in 0..3 {
+            write_round(&db, round);
+            assert!(db.flush().ok());
+        }
+        write_round(&db, 3);
+        assert_eq!("3", db.get_property("leveldb.num-files-at-level0").unwrap());
+
+        // The compaction the flush starts takes a second per output file.
+        let report = {
+            let clock = env.clock_.clone();
+            let _slow = sync_point::activate("compaction:after-output-sync", move || {
+                clock.fetch_add(1_000_000, atomic::Ordering::SeqCst);
+                None
+            });
+            let deadline = env.clock_.load(atomic::Ordering::SeqCst) + 1000;
+            db.close_with_deadline(deadline).unwrap()
+        };
+        assert_eq!(CloseReport {
+            memtable_flushed: true,
+            compactions_abandoned: 1,
+            compaction_pending: true,
+            last_sequence: 2000,
+            wal_trailer_written: true,
+            deadline_exceeded: true,
+            ..Default::default()
+        }, report);
+
+        // The abandoned outputs are gone.
+        let tables = env.get_children(DBNAME).unwrap().into_iter()
+            .filter(|name| matches!(parse_file_name(name), Some((_, FileType::TableFile)))).count();
+        assert_eq!(4, tables);
+
+        // Every call fails from now on.
+        let closed = |s: Status| assert!(s.is_invalid_argument() && s.to_string().contains("db closed"), "{}", s.to_string());
+        closed(db.put(&WriteOptions::default(), &Slice::new(b"key000"), &Slice::new(b"v")));
+        closed(db.get(&ReadOptions::new(), &Slice::new(b"key000")).unwrap_err());
+        closed(db.new_iterator(&ReadOptions::new()).status());
+        closed(db.scan(&ReadOptions::new(), &Slice::new(b"a"), &Slice::new(b"z"), 10).unwrap_err());
+        closed(db.flush());
+        closed(db.compact_range(None, None));
+        closed(db.set_options(&[("l0_compaction_trigger", "8")]));
+        closed(db.lock_range(b"a", b"b").err().unwrap());
+        closed(db.close_with_deadline(u64::MAX).unwrap_err());
+        drop(db);
+
+        // The DB reopens with every write, and compacts on open.
+        let db = DB::open(&options, DBNAME).unwrap();
+        let contents = full_scan(&db);
+        assert_eq!(500, contents.len());
+        assert!(contents.iter().enumerate().all(|(i, (_, v))| *v == format!("value3-{}", i)));
+        assert_eq!("0", db.get_property("leveldb.num-files-at-level0").unwrap());
+    }
+
+    #[test]
+    fn open_error_codes_test() {
+        // Of two opens racing for the same directory, the loser fails
+        // with a lock conflict.
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let barrier = std::sync::Barrier::new(2);
+        let results: Vec<Result<Box<DB>, Status>> = std::thread::scope(|scope| {
+            let opens: Vec<_> = (0..2).map(|_| scope.spawn(|| {
+                barrier.wait();
+                DB::open(&options, DBNAME)
+            })).collect();
+            opens.into_iter().map(|open| open.join().unwrap()).collect()
+        });
+        assert_eq!(1, results.iter().filter(|r| r.is_ok()).count());
+        let s = results.into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!((Code::Busy, SubCode::LockHeld, Some(lock_file_name(DBNAME).as_str())), (s.code(), s.subcode(), s.filename()));
+
+        // A missing database
+        let s = DB::open(&Options { create_if_missing: false, ..options.clone() }, "/missing").err().unwrap();
+        assert_eq!((Code::InvalidArgument, SubCode::NoDatabase, Some(current_file_name("/missing").as_str())), (s.code(), s.subcode(), s.filename()));
+
+        // A corrupt MANIFEST is named in the error.
+        assert!(DB::open(&options, "/corrupt").is_ok());
+        let manifest = format!("/corrupt/{}", read_file_to_string(env.clone(), &current_file_name("/corrupt")).unwrap().trim_end());
+        assert!(write_string_to_file_sync(env.clone(), &Slice::new(b"garbage"), &manifest).ok());
+        let s = DB::open(&options, "/corrupt").err().unwrap();
+        assert_eq!((Code::Corruption, SubCode::None, Some(manifest.as_str())), (s.code(), s.subcode(), s.filename()));
+        assert!(s.to_string().contains(&manifest), "{}", s.to_string());
+    }
+
+    #[test]
+    fn adaptive_table_cache_test() {
+        let mut options = options_with_env(new_mem_env());
+        options.adaptive_table_cache = true;
+        options.max_open_files = 100;
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..8 {
+            put_values(&db, &format!("t{}", i), 10, 100);
+            assert!(db.flush().ok());
+        }
+        for i in 0..8 {
+            let key = format!("t{}{:05}", i, 0);
+            assert_eq!(vec![b'x'; 100], db.get(&ReadOptions::default(), &Slice::new(key.as_bytes())).unwrap());
+        }
+        let (_, stats) = db.stats_snapshot(false);
+        assert_eq!(Some(100), stats.table_cache.capacity);
+        assert_eq!(0, stats.table_cache.shrinks);
+    }
+
+    #[test]
+    fn sanitize_options_test() {
+        let env = new_mem_env();
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let mut src = options_with_env(env.clone());
+        let sanitize = |src: &Options| sanitize_options(DBNAME, &icmp, None, src, false);
+
+        src.write_buffer_size = 1000;
+        src.max_open_files = 10;
+        src.block_size = 256;
+        let result = sanitize(&src);
+        assert_eq!((MIN_WRITE_BUFFER_SIZE, MIN_MAX_OPEN_FILES, MIN_BLOCK_SIZE), (result.write_buffer_size, result.max_open_files, result.block_size));
+        src.write_buffer_size = 2 << 30;
+        src.max_open_files = 100_000;
+        src.block_size = 16 << 20;
+        let result = sanitize(&src);
+        assert_eq!((MAX_WRITE_BUFFER_SIZE, MAX_MAX_OPEN_FILES, MAX_BLOCK_SIZE), (result.write_buffer_size, result.max_open_files, result.block_size));
+        src.write_buffer_size = 1 << 20;
+        src.max_open_files = 1000;
+        src.block_size = 8 << 10;
+        let result = sanitize(&src);
+        assert_eq!((1 << 20, 1000, 8 << 10), (result.write_buffer_size, result.max_open_files, result.block_size));
+        // Zero keeps the table cache unbounded.
+        src.max_open_files = 0;
+        assert_eq!(0, sanitize(&src).max_open_files);
+
+        // Defaults are filled in on the copy only.
+        let result = sanitize_options(DBNAME, &icmp, Some(new_bloom_filter_policy(10)), &src, true);
+        assert_eq!(icmp.name(), result.comparator.name());
+        assert!(result.filter_policy.is_none());
+        assert!(result.block_cache.is_some());
+        assert!(result.info_log.is_some());
+        assert!(env.file_exists(&info_log_file_name(DBNAME)));
+        assert_eq!((8 << 10, 0), (src.block_size, src.max_open_files));
+        assert!(src.block_cache.is_none() && src.info_log.is_none() && src.filter_policy.is_none());
+        assert_eq!(bytewise_comparator().name(), src.comparator.name());
+        let partitioned = Options { block_cache_level_partitions: Some(vec![0.4, 0.4, 0.2]), ..src.clone() };
+        let stats = sanitize(&partitioned).block_cache.unwrap().stats();
+        assert_eq!(DEFAULT_BLOCK_CACHE_SIZE, stats.iter().map(|s| s.capacity).sum::<usize>());
+        assert_eq!(3, stats.len());
+        let src = Options { no_block_cache: true, ..src };
+        assert!(sanitize(&src).block_cache.is_none());
+
+        // An inverted write delay curve is raised to a flat one.
+        let inverted = Options {
+            write_delay_start: WriteDelayPoint { debt_bytes: 2 << 20, delay_micros: 5000 },
+            write_delay_full: WriteDelayPoint { debt_bytes: 1 << 20, delay_micros: 1000 },
+            ..src.clone()
+        };
+        let result = sanitize(&inverted);
+        assert_eq!(WriteDelayPoint { debt_bytes: (2 << 20) + 1, delay_micros: 5000 }, result.write_delay_full);
+        assert_eq!(inverted.write_delay_start, result.write_delay_start);
+        let start = WriteDelayPoint { debt_bytes: u64::MAX, delay_micros: 0 };
+        let result = sanitize(&Options { write_delay_start: start, write_delay_full: start, ..src.clone() });
+        assert_eq!(start, result.write_delay_full);
+        // A sound one is kept.
+        assert_eq!(src.write_delay_full, sanitize(&src).write_delay_full);
+    }
+
+    #[test]
+    fn info_log_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        drop(DB::open(&options, DBNAME).unwrap());
+        let first = read_file_to_string(env.clone(), &info_log_file_name(DBNAME)).unwrap();
+        assert!(first.contains("Creating DB /db since it was missing."), "{}", first);
+        // Each line starts with the time it was logged at.
+        let line = first.lines().next().unwrap();
+        assert_eq!((Some(b'/'), Some(b'-'), Some(b' ')), (line.as_bytes().get(4).copied(), line.as_bytes().get(10).copied(), line.as_bytes().get(26).copied()), "{}", line);
+
+        // Reopening keeps the previous LOG as LOG.old.
+        drop(DB::open(&options, DBNAME).unwrap());
+        assert_eq!(first, read_file_to_string(env.clone(), &old_info_log_file_name(DBNAME)).unwrap());
+        assert!(!read_file_to_string(env.clone(), &info_log_file_name(DBNAME)).unwrap().contains("Creating DB"));
+
+        // A read-only open leaves the directory alone.
+        let before = read_file_to_string(env.clone(), &info_log_file_name(DBNAME)).unwrap();
+        drop(DB::open_read_only(&options, DBNAME).unwrap());
+        assert_eq!(before, read_file_to_string(env.clone(), &info_log_file_name(DBNAME)).unwrap());
+        assert_eq!(first, read_file_to_string(env.clone(), &old_info_log_file_name(DBNAME)).unwrap());
+
+        // With an instance name, the LOG's messages carry it too.
+        let options = Options { instance_name: Some("info_log_test".to_string()), ..options };
+        drop(DB::open(&options, DBNAME).unwrap());
+        let named = read_file_to_string(env.clone(), &info_log_file_name(DBNAME)).unwrap();
+        assert!(!named.is_empty());
+        assert!(named.lines().all(|line| line.get(27..).is_some_and(|msg| msg.starts_with("[info_log_test] "))), "{}", named);
+    }
+
+    /// Keeps a "r:<reversed key>" -> "<key>" row for every "p:<key>" row,
+    /// and rejects batches that write "p:bad".
+    #[derive(Default)]
+    struct ReverseIndex {
+        calls_: AtomicU64,
+        // Set to make transform() write to the DB, as it must not.
+        db_: OnceLock<std::sync::Weak<DB>>,
+        nested_: Mutex<Option<Status>>,
+    }
+
+    #[derive(Default)]
+    struct IndexRows {
+        rows: WriteBatch,
+        rejected: bool,
+    }
+
+    fn index_key(key: &[u8]) -> Vec<u8> {
+        [b"r:".as_slice(), &key.iter().rev().copied().collect::<Vec<_>>()].concat()
+    }
+
+    impl write_batch::Handler for IndexRows {
+        fn put(&mut self, key: &Slice, _value: &Slice) {
+            self.rejected |= key.data() == b"p:bad";
+            if let Some(key) = key.data().strip_prefix(b"p:") {
+                self.rows.put(&Slice::new(&index_key(key)), &Slice::new(key));
+            }
+        }
+        fn delete(&mut self, key: &Slice) {
+            if let Some(key) = key.data().strip_prefix(b"p:") {
+                self.rows.delete(&Slice::new(&index_key(key)));
+            }
+        }
+        fn delete_range(&mut self, _begin: &Slice, _end: &Slice) {}
+    }
+
+    impl BatchTransformer for ReverseIndex {
+        fn transform(&self, batch: &WriteBatch) -> Result<WriteBatch, Status> {
+            self.calls_.fetch_add(1, atomic::Ordering::SeqCst);
+            if let Some(db) = self.db_.get().and_then(|db| db.upgrade()) {
+                *self.nested_.lock().unwrap() = Some(db.put(&WriteOptions::default(), &Slice::new(b"nested"), &Slice::new(b"v")));
+            }
+            let mut index = IndexRows::default();
+            let s = batch.iterate(&mut index);
+            if !s.ok() {
+                return Err(s);
+            }
+            if index.rejected {
+                return Err(Status::invalid_argument("p:bad", "rejected by the index"));
+            }
+            let mut result = batch.clone();
+            result.append(&index.rows);
+            Ok(result)
+        }
+    }
+
+    /// Check that the "p:" rows and the "r:" rows seen by "options" match,
+    /// and return the number of "p:" rows.
+    fn check_reverse_index(db: &DB, options: &ReadOptions) -> usize {
+        let mut iter = db.new_iterator(options);
+        iter.seek_to_first();
+        let rows = scan(iter.as_mut(), true);
+        let primary: BTreeSet<String> = rows.iter().filter_map(|(k, _)| k.strip_prefix("p:")).map(str::to_string).collect();
+        let index: BTreeSet<String> = rows.iter().filter(|(k, _)| k.starts_with("r:")).map(|(k, v)| {
+            assert_eq!(k.as_bytes(), index_key(v.as_bytes()));
+            v.clone()
+        }).collect();
+        assert_eq!(primary, index);
+        primary.len()
+    }
+
+    fn options_with_transformer(env: Arc<dyn Env>, transformer: &Arc<ReverseIndex>) -> Options {
+        Options { batch_transformer: Some(transformer.clone()), ..options_with_env(env) }
+    }
+
+    #[test]
+    fn batch_transformer_test() {
+        let env = new_mem_env();
+        let transformer = Arc::new(ReverseIndex::default());
+        let options = options_with_transformer(env.clone(), &transformer);
+        let db = DB::open(&options, DBNAME).unwrap();
+
+        // Primary and index rows appear and disappear together, as seen
+        // from any snapshot, while writers race.
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..4).map(|t| {
+                let db = &db;
+                scope.spawn(move || {
+                    let mut rnd = Random::new(301 + t);
+                    let wo = WriteOptions::default();
+                    for _ in 0..300 {
+                        let key = format!("p:key{:02}", rnd.uniform(30));
+                        if rnd.one_in(3) {
+                            assert!(db.delete(&wo, &Slice::new(key.as_bytes())).ok());
+                        } else {
+                            let mut batch = WriteBatch::new();
+                            batch.put(&Slice::new(key.as_bytes()), &Slice::new(b"v"));
+                            batch.put(&Slice::new(format!("p:key{:02}", rnd.uniform(30)).as_bytes()), &Slice::new(b"v"));
+                            assert!(db.write(&wo, batch).ok());
+                        }
+                    }
+                })
+            }).collect();
+            scope.spawn(|| {
+                while !done.load(atomic::Ordering::SeqCst) {
+                    let snapshot = db.get_snapshot();
+                    check_reverse_index(&db, &ReadOptions { snapshot: Some(snapshot.clone()), ..ReadOptions::new() });
+                    db.release_snapshot(snapshot);
+                }
+            });
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, atomic::Ordering::SeqCst);
+        });
+        assert_eq!(1200, transformer.calls_.load(atomic::Ordering::SeqCst));
+        let rows = check_reverse_index(&db, &ReadOptions::new());
+        assert!(rows > 0);
+
+        // A rejected batch fails the write, and writes nothing.
+        let sequence = db.last_applied_sequence();
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::new(b"p:good"), &Slice::new(b"v"));
+        batch.put(&Slice::new(b"p:bad"), &Slice::new(b"v"));
+        let s = db.write(&WriteOptions::default(), batch);
+        assert!(s.is_invalid_argument() && s.to_string().contains("rejected by the index"), "{}", s.to_string());
+        assert_eq!(sequence, db.last_applied_sequence());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"p:good")).unwrap_err().is_not_found());
+
+        // The log holds the transformed batches, so a crash at any point
+        // recovers both kinds of rows or neither.
+        let log = log_file_name(DBNAME, db.mutex_.lock().unwrap().logfile_number_);
+        let log_size = env.get_file_size(&log).unwrap();
+        let mut recovered = BTreeSet::new();
+        for cut in (0..log_size).step_by(997) {
+            let crash = format!("/crash{}", cut);
+            assert!(env.create_dir(&crash).is_ok());
+            for name in env.get_children(DBNAME).unwrap() {
+                let size = match parse_file_name(&name) {
+                    Some((_, FileType::LogFile)) => log_size - cut,
+                    Some((_, FileType::CurrentFile | FileType::DescriptorFile)) => env.get_file_size(&format!("{}/{}", DBNAME, name)).unwrap(),
+                    _ => continue,
+                };
+                assert!(copy_file(env.clone(), &format!("{}/{}", DBNAME, name), &format!("{}/{}", crash, name), size).ok());
+            }
+            let db = DB::open(&options, &crash).unwrap();
+            recovered.insert(check_reverse_index(&db, &ReadOptions::new()));
+        }
+        assert!(recovered.contains(&rows) && recovered.len() > 1, "{:?}", recovered);
+    }
+
+    #[test]
+    fn batch_transformer_bypass_test() {
+        // Writing to the DB from its transformer fails, and the outer
+        // write goes ahead.
+        let transformer = Arc::new(ReverseIndex::default());
+        let db: Arc<DB> = Arc::from(DB::open(&options_with_transformer(new_mem_env(), &transformer), DBNAME).unwrap());
+        assert!(transformer.db_.set(Arc::downgrade(&db)).is_ok());
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"p:key"), &Slice::new(b"v")).ok());
+        let nested = transformer.nested_.lock().unwrap().take().unwrap();
+        assert!(nested.is_invalid_argument(), "{}", nested.to_string());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"nested")).unwrap_err().is_not_found());
+        assert_eq!(1, check_reverse_index(&db, &ReadOptions::new()));
+
+        // Replicated batches were transformed on the primary, and are
+        // applied as they are.
+        let primary_env = new_mem_env();
+        let primary_transformer = Arc::new(ReverseIndex::default());
+        let primary = DB::open(&options_with_transformer(primary_env.clone(), &primary_transformer), DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        for i in 0..20 {
+            assert!(primary.put(&wo, &Slice::new(format!("p:key{}", i % 7).as_bytes()), &Slice::new(b"v")).ok());
+            if i % 3 == 0 {
+                assert!(primary.delete(&wo, &Slice::new(format!("p:key{}", i % 5).as_bytes())).ok());
+            }
+        }
+        let replica_transformer = Arc::new(ReverseIndex::default());
+        let options = Options { replica_mode: true, ..options_with_transformer(new_mem_env(), &replica_transformer) };
+        let replica = DB::open(&options, DBNAME).unwrap();
+        for batch in logged_batches(&primary_env, &primary, DBNAME) {
+            assert!(replica.apply_replicated_batch(&batch, batch.sequence()).ok());
+        }
+        assert_eq!(0, replica_transformer.calls_.load(atomic::Ordering::SeqCst));
+        assert_eq!(full_scan(&primary), full_scan(&replica));
+        check_reverse_index(&replica, &ReadOptions::new());
+    }
+
+    #[test]
+    fn max_snapshot_age_test() {
+        let env = new_slow_sync_env(0);
+        let options = Options { max_snapshot_age_seconds: 60, ..options_with_env(env.clone()) };
+        let db = DB::open(&options, DBNAME).unwrap();
+        let wo = WriteOptions::default();
+        // Compact the deepest table into the level below.
+        let compact_bottom = || {
+            let state = db.mutex_.lock().unwrap();
+            let level = (0..NUM_LEVELS).rev().find(|&level| state.versions_.num_level_files(level) > 0).unwrap();
+            db.compact_level_range(state, level, None, None).1
+        };
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"v1")).ok());
+        let expiring = db.get_snapshot();
+        assert!(db.delete(&wo, &Slice::new(b"a")).ok());
+        env.clock_.store(10_000_000, atomic::Ordering::SeqCst);
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"v1")).ok());
+        let kept = db.get_snapshot_with(&GetSnapshotOptions { on_expiry: SnapshotExpiry::Ignore });
+        assert!(db.delete(&wo, &Slice::new(b"b")).ok());
+        assert!(db.flush().ok());
+        assert!(compact_bottom().ok());
+        assert_eq!(2, internal_entries(&db, "a"));
+        assert_eq!(2, internal_entries(&db, "b"));
+
+        // Snapshots are only found stale by a compaction.
+        env.clock_.store(100_000_000, atomic::Ordering::SeqCst);
+        let expiring_ro = ReadOptions { snapshot: Some(expiring.clone()), ..ReadOptions::new() };
+        let kept_ro = ReadOptions { snapshot: Some(kept.clone()), ..ReadOptions::new() };
+        assert_eq!(b"v1".to_vec(), db.get(&expiring_ro, &Slice::new(b"a")).unwrap());
+        assert_eq!("snapshots: 2 (0 expired, 0 stale)\noldest pinning sequence: 1\n\
+                    sequence 1: age 100s\nsequence 3: age 90s\n", db.get_property("leveldb.snapshots").unwrap());
+
+        // The expired snapshot fails reads and no longer keeps "a" from
+        // being dropped; the ignored one keeps working and keeps "b".
+        assert!(compact_bottom().ok());
+        assert!(expiring.expired() && !kept.expired());
+        let s = db.get(&expiring_ro, &Slice::new(b"a")).unwrap_err();
+        assert!(s.is_invalid_argument() && s.to_string().contains("snapshot expired"), "{}", s.to_string());
+        assert!(db.new_iterator(&expiring_ro).status().is_invalid_argument());
+        assert_eq!(0, internal_entries(&db, "a"));
+        assert_eq!(2, internal_entries(&db, "b"));
+        assert_eq!(b"v1".to_vec(), db.get(&kept_ro, &Slice::new(b"b")).unwrap());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"b")).unwrap_err().is_not_found());
+        assert_eq!("snapshots: 2 (1 expired, 1 stale)\noldest pinning sequence: 3\n\
+                    sequence 1: age 100s, expired\nsequence 3: age 90s, stale\n", db.get_property("leveldb.snapshots").unwrap());
+
+        db.release_snapshot(expiring);
+        db.release_snapshot(kept);
+        assert!(compact_bottom().ok());
+        assert_eq!(0, internal_entries(&db, "b"));
+        assert_eq!("snapshots: 0 (0 expired, 0 stale)\noldest pinning sequence: none\n", db.get_property("leveldb.snapshots").unwrap());
+    }
+
+    #[test]
+    fn disable_wal_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        let unlogged = WriteOptions { disable_wal: true, ..Default::default() };
+        let key = |prefix: &str, i: i32| format!("{}{:03}", prefix, i);
+        assert_eq!(Some("false".to_string()), db.get_property("leveldb.possible-unlogged-data-loss"));
+        assert_eq!(0, db.mutex_.lock().unwrap().versions_.required_features() & features::UNLOGGED_WRITES);
+        for i in 0..100 {
+            assert!(db.put(&unlogged, &Slice::new(key("flushed", i).as_bytes()), &Slice::new(b"v")).ok());
+        }
+        assert!(db.flush().ok());
+        assert!(!db.mutex_.lock().unwrap().versions_.unlogged_writes());
+        for i in 0..20 {
+            assert!(db.put(&unlogged, &Slice::new(key("unflushed", i).as_bytes()), &Slice::new(b"v")).ok());
+        }
+        assert!(db.mutex_.lock().unwrap().versions_.unlogged_writes());
+        assert_eq!(b"v".to_vec(), db.get(&ReadOptions::new(), &Slice::new(key("unflushed", 0).as_bytes())).unwrap());
+        assert!(logged_batches(&env, &db, DBNAME).is_empty());
+        assert_eq!(features::UNLOGGED_WRITES, db.mutex_.lock().unwrap().versions_.required_features() & features::UNLOGGED_WRITES);
+
+        // Dropping the DB loses the memtable, as a crash would.  Builds
+        // that cannot tell refuse the database.
+        drop(db);
+        features::SUPPORTED_OVERRIDE.with(|o| o.set(Some(features::supported_features() & !features::UNLOGGED_WRITES)));
+        let s = DB::open(&options, DBNAME).err().unwrap();
+        features::SUPPORTED_OVERRIDE.with(|o| o.set(None));
+        assert!(s.is_not_supported_error(), "{}", s.to_string());
+        assert!(s.to_string().starts_with("Not implemented: this database requires: unlogged write support"), "{}", s.to_string());
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..100 {
+            assert_eq!(b"v".to_vec(), db.get(&ReadOptions::new(), &Slice::new(key("flushed", i).as_bytes())).unwrap());
+        }
+        for i in 0..20 {
+            assert!(db.get(&ReadOptions::new(), &Slice::new(key("unflushed", i).as_bytes())).unwrap_err().is_not_found());
+        }
+        assert_eq!(Some("true".to_string()), db.get_property("leveldb.possible-unlogged-data-loss"));
+        assert!(read_file_to_string(env.clone(), &info_log_file_name(DBNAME)).unwrap().contains("disable_wal"));
+
+        // The open cleared the record, so the next one has nothing to report.
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(Some("false".to_string()), db.get_property("leveldb.possible-unlogged-data-loss"));
+    }
+
+    #[test]
+    fn disable_wal_mixed_group_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        let db = DB::open(&options, DBNAME).unwrap();
+        let logged = WriteOptions::default();
+        let unlogged = WriteOptions { disable_wal: true, ..Default::default() };
+        let reached = Arc::new(std::sync::Barrier::new(2));
+        let release = Arc::new(std::sync::Barrier::new(2));
+        std::thread::scope(|scope| {
+            // The leader stops before logging, so that the followers queue
+            // up behind it in order and form the next group.
+            scope.spawn(|| {
+                let (reached, release) = (reached.clone(), release.clone());
+                let _pause = sync_point::activate("db:write:before-log", move || {
+                    reached.wait();
+                    release.wait();
+                    None
+                });
+                assert!(db.put(&logged, &Slice::new(b"leader"), &Slice::new(b"v")).ok());
+            });
+            reached.wait();
+            let followers = [(&logged, "a", "logged"), (&unlogged, "k", "unlogged"), (&unlogged, "u", "unlogged"),
+                             (&logged, "k", "logged"), (&logged, "z", "logged")];
+            for (i, &(options, key, value)) in followers.iter().enumerate() {
+                let db = &db;
+                scope.spawn(move || assert!(db.put(options, &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes())).ok()));
+                while db.mutex_.lock().unwrap().writers_.len() < i + 2 {
+                    std::thread::yield_now();
+                }
+            }
+            release.wait();
+        });
+
+        // Sequence numbers follow the queue, and only the logged writes
+        // are in the log, the last two in one record.
+        let (value, sequence) = db.get_with_sequence(&ReadOptions::new(), &Slice::new(b"k"));
+        assert_eq!((b"logged".to_vec(), 6), (value.unwrap(), sequence));
+        assert_eq!(b"unlogged".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"u")).unwrap());
+        let batches = logged_batches(&env, &db, DBNAME);
+        let records: Vec<(SequenceNumber, u32)> = batches.iter().map(|b| (b.sequence(), b.count())).collect();
+        assert_eq!(vec![(1, 1), (2, 1), (5, 2)], records);
+
+        drop(db);
+        let db = DB::open(&options, DBNAME).unwrap();
+        for key in ["leader", "a", "z"] {
+            assert!(db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).is_ok(), "{}", key);
+        }
+        assert_eq!(b"logged".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"k")).unwrap());
+        assert!(db.get(&ReadOptions::new(), &Slice::new(b"u")).unwrap_err().is_not_found());
+        assert_eq!(Some("true".to_string()), db.get_property("leveldb.possible-unlogged-data-loss"));
+    }
+
+    #[test]
+    fn block_cache_partition_test() {
+        const CAPACITY: usize = 32 << 10;
+        let hot_miss_rates = |cache: Arc<dyn Cache>| {
+            let mut options = options_with_env(new_mem_env());
+            options.block_size = MIN_BLOCK_SIZE;
+            options.block_cache = Some(cache.clone());
+            let db = DB::open(&options, DBNAME).unwrap();
+            let put = |key: &str| assert!(db.put(&WriteOptions::default(), &Slice::new(key.as_bytes()), &Slice::new(&[b'v'; 100])).ok());
+            // A large bottom level, and a few hot keys in a small table above it
+            for i in 0..2000 {
+                put(&format!("b{:04}", i));
+            }
+            assert!(db.compact_range(None, None).ok());
+            for level in 0..4 {
+                assert!(db.compact_level_range(db.mutex_.lock().unwrap(), level, None, None).1.ok());
+            }
+            for i in 0..20 {
+                put(&format!("h{:02}", i));
+            }
+            assert!(db.flush().ok());
+            let files = |level: i32| db.get_property(&format!("leveldb.num-files-at-level{}", level)).unwrap();
+            assert!((0..4).any(|level| files(level) == "1") && files(4) != "0");
+
+            let misses = || cache.stats().iter().map(|s| s.misses).sum::<u64>();
+            let hot_gets = || {
+                for i in 0..20 {
+                    assert!(db.get(&ReadOptions::new(), &Slice::new(format!("h{:02}", i).as_bytes())).is_ok());
+                }
+            };
+            hot_gets();
+            let mut rates = Vec::new();
+            for _ in 0..5 {
+                // The scan reads every block of the bottom level
+                let mut iter = db.new_iterator(&ReadOptions { iterate_upper_bound: Some(b"c".to_vec()), ..ReadOptions::new() });
+                iter.seek_to_first();
+                assert_eq!(2000, scan(iter.as_mut(), true).len());
+                drop(iter);
+                assert!(cache.total_charge() <= CAPACITY);
+                assert!(cache.stats().iter().map(|s| s.usage).sum::<usize>() <= CAPACITY);
+
+                let before = misses();
+                hot_gets();
+                rates.push(misses() - before);
+            }
+            let stats = db.get_property("leveldb.block-cache-stats").unwrap();
+            assert_eq!(cache.stats().len(), stats.lines().count());
+            rates
+        };
+
+        // Partitioned, the hot blocks stay cached through every scan
+        assert_eq!(vec![0; 5], hot_miss_rates(new_partitioned_lru_cache(CAPACITY, &[0.25, 0.5, 0.25], false)));
+        // Unpartitioned, each scan pushes them out
+        assert!(hot_miss_rates(new_lru_cache(CAPACITY)).iter().all(|&misses| misses > 0));
+    }
+
+    #[test]
+    fn diagnostics_redaction_test() {
+        const KEY: &str = "secret-user@example.com";
+        let run = |redaction: DiagnosticRedaction| {
+            let env = new_mem_env();
+            let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+            let mut options = options_with_env(env.clone());
+            options.info_log = Some(logger.clone());
+            options.diagnostics_redaction = redaction;
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"other"), &Slice::new(b"v")).ok());
+            // A level-0 table whose entry for KEY has an invalid type,
+            // followed by a valid entry so repair keeps the table.
+            db.with_exclusive_write(|state| {
+                let number = state.versions_.new_file_number();
+                let file = env.new_writable_file(&table_file_name(DBNAME, number)).unwrap();
+                let mut builder = TableBuilder::new(&db.options_, file.clone());
+                let mut ikey = KEY.as_bytes().to_vec();
+                put_fixed64(&mut ikey, 0x55);
+                let last = InternalKey::new_from(&Slice::new(b"zzz"), 1, ValueType::type_value());
+                builder.add(&Slice::new(&ikey), &Slice::new(b"v"));
+                builder.add(&last.encode(), &Slice::new(b"v"));
+                assert!(builder.finish().ok() && file.close().ok());
+                let ikey = InternalKey::decode_from(&Slice::new(&ikey));
+                let mut edit = VersionEdit::new();
+                edit.add_file(0, number, builder.file_size(), &ikey, &last);
+                assert!(db.log_and_apply(state, &mut edit, None).ok());
+            });
+            let s = db.get(&ReadOptions::new(), &Slice::new(KEY.as_bytes())).unwrap_err();
+            assert!(s.is_corruption(), "{}", s.to_string());
+            drop(db);
+            // Repair logs the entry it can not parse.
+            assert!(repair_db(DBNAME, &options).ok());
+            let logs = logger.messages_.lock().unwrap().join("\n");
+            assert!(logs.contains("unparsable key"), "{}", logs);
+            (s.to_string(), logs)
+        };
+
+        let (status, logs) = run(DiagnosticRedaction::Full);
+        assert!(status.contains(KEY) && logs.contains(KEY), "{}\n{}", status, logs);
+        let (status, logs) = run(DiagnosticRedaction::HashOnly);
+        for rendered in [&status, &logs] {
+            assert!(!rendered.contains("secret") && !rendered.contains("example"), "{}", rendered);
+        }
+        assert!(status.contains("<23 bytes, hash "), "{}", status);
+        let (status, logs) = run(DiagnosticRedaction::TruncateTo(6));
+        assert!(status.contains("secret...") && !status.contains("secret-"), "{}", status);
+        assert!(!logs.contains("secret-"), "{}", logs);
+    }
 }
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use crate::{env::Env, iterator::Iterator, options::{Options, ReadOptions}, slice::Slice, status::Status, table::table_builder::TableBuilder};
+
+use super::{dbformat::InternalKey, filename::table_file_name, table_cache::TableCache, version_edit::FileMetaData};
+
+/// Build a Table file from the contents of *iter.  The generated file
+/// will be named according to meta->number.  On success, the rest of
+/// *meta will be filled with metadata about the generated table.
+/// If no data is present in *iter, meta->file_size will be set to
+/// zero, and no Table file will be produced.
+pub(crate) fn build_table(dbname: &str, env: &Arc<dyn Env>, options: &Options, table_cache: &TableCache,
+                          iter: &mut dyn Iterator, meta: &mut FileMetaData) -> Status {
+    let mut s = Status::new_ok();
+    meta.file_size = 0;
+    iter.seek_to_first();
+
+    let fname = table_file_name(dbname, meta.number);
+    if iter.valid() {
+        let file = match env.new_writable_file(&fname) {
+            Ok(file) => file,
+            Err(s) => { return s; },
+        };
+
+        let mut builder = TableBuilder::new(options, file.clone());
+        meta.smallest = InternalKey::decode_from(&iter.key());
+        let mut largest = Vec::new();
+        while iter.valid() {
+            largest.clear();
+            largest.extend_from_slice(iter.key().data());
+            builder.add(&iter.key(), &iter.value());
+            iter.next();
+        }
+        if !largest.is_empty() {
+            meta.largest = InternalKey::decode_from(&Slice::new(&largest));
+        }
+
+        // Finish and check for builder errors
+        s = builder.finish();
+        if s.ok() {
+            meta.file_size = builder.file_size();
+            meta.file_checksum = Some(builder.file_checksum());
+            debug_assert!(meta.file_size > 0);
+        }
+
+        // Finish and check for file errors
+        sync_point!("build-table:before-sync", s);
+        if s.ok() {
+            s = file.sync();
+        }
+        sync_point!("build-table:after-sync", s);
+        if s.ok() {
+            s = file.close();
+        }
+
+        if s.ok() {
+            // Verify that the table is usable
+            let it = table_cache.new_iterator(&ReadOptions::new(), None, meta.number, meta.file_size);
+            s = it.status();
+        }
+    }
+
+    // Check for input iterator errors
+    if !iter.status().ok() {
+        s = iter.status();
+    }
+
+    if !s.ok() || meta.file_size == 0 {
+        env.remove_file(&fname);
+    }
+    s
+}
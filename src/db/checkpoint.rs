@@ -0,0 +1,85 @@
+//! Checkpoints: consistent copies of a DB in another directory that can
+//! be opened as DBs of their own.
+//!
+//! Table files never change once written, so they are hard-linked into
+//! the checkpoint (or copied when the Env can not link).  The MANIFEST is
+//! still being appended to, so the part written so far is copied, and a
+//! CURRENT pointing at it is written last: a checkpoint that fails part
+//! way has no CURRENT and is removed again.
+
+use crate::{db::{dbformat::NUM_LEVELS, filename::{current_file_name, descriptor_file_name, set_current_file, table_file_name}, DbInner}, env::log, status::Status, util::env::copy_file};
+
+impl DbInner {
+    /// Write a checkpoint of the DB to the directory "dir", which must not
+    /// hold a DB yet.  The memtable is flushed first, so the checkpoint
+    /// holds every write that completed before the call and needs no log.
+    /// "dir" should be on the same file system as the DB for its table
+    /// files to be linked rather than copied.
+    pub fn create_checkpoint(&self, dir: &str) -> Status {
+        if dir == self.dbname_ {
+            return Status::invalid_argument(dir, "is the database itself");
+        }
+        if self.env_.file_exists(&current_file_name(dir)) {
+            return Status::invalid_argument(dir, "exists (already holds a database)");
+        }
+        let mut s = self.flush();
+        if !s.ok() {
+            return s;
+        }
+
+        // Holding the lock keeps the files from being compacted away
+        // while they are linked.
+        let state = self.mutex_.lock().expect("failed to acquire lock");
+        let _ = self.env_.create_dir(dir);
+        let (version, manifest_number, manifest_size) = {
+            let versions = &state.versions_;
+            (versions.current(), versions.manifest_file_number(), versions.manifest_file_size())
+        };
+        sync_point!("checkpoint:after-live-files", s);
+
+        let mut created = Vec::new();
+        for level in 0..NUM_LEVELS {
+            for f in version.files(level) {
+                if !s.ok() {
+                    break;
+                }
+                let target = table_file_name(dir, f.number);
+                s = self.link_or_copy(&table_file_name(&self.dbname_, f.number), &target, f.file_size);
+                if s.ok() {
+                    created.push(target);
+                }
+            }
+        }
+        if s.ok() {
+            let target = descriptor_file_name(dir, manifest_number);
+            s = copy_file(self.env_.clone(), &descriptor_file_name(&self.dbname_, manifest_number), &target, manifest_size);
+            if s.ok() {
+                created.push(target);
+            }
+        }
+        sync_point!("checkpoint:before-current", s);
+        if s.ok() {
+            s = set_current_file(self.env_.clone(), dir, manifest_number);
+        }
+
+        if s.ok() {
+            log(self.options_.info_log.clone(), &format!("Checkpoint to {}: {} table files", dir, created.len() - 1));
+        } else {
+            log(self.options_.info_log.clone(), &format!("Checkpoint to {} failed: {}", dir, s.to_string()));
+            for fname in created {
+                self.env_.remove_file(&fname);
+            }
+        }
+        s
+    }
+
+    /// Make "target" a hard link to "src", or a copy of its first "size"
+    /// bytes if the Env can not link files.
+    fn link_or_copy(&self, src: &str, target: &str, size: u64) -> Status {
+        let s = self.env_.link_file(src, target);
+        if s.is_not_supported_error() {
+            return copy_file(self.env_.clone(), src, target, size);
+        }
+        s
+    }
+}
@@ -0,0 +1,158 @@
+//! Shutting a DB down on the caller's terms.  See DB::close_with_deadline().
+
+use std::sync::atomic::Ordering;
+
+use crate::{env::log, status::Status, write_batch::{WalTrailer, WriteBatch}};
+
+use super::{version_edit::SequenceNumber, DbState, DB};
+
+/// What DB::close_with_deadline() got done before the DB closed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CloseReport {
+    /// True iff every write is in a table file, so that reopening the DB
+    /// has no log to replay.
+    pub memtable_flushed: bool,
+
+    /// Compactions the close ran to the end.
+    pub compactions_completed: u64,
+
+    /// Compactions the deadline cut short.  Their outputs are deleted,
+    /// and the MANIFEST never mentions them.
+    pub compactions_abandoned: u64,
+
+    /// True iff the DB still needed a compaction when it closed.
+    pub compaction_pending: bool,
+
+    pub last_sequence: SequenceNumber,
+
+    /// True iff the log ends with the trailer of a clean shutdown, which
+    /// the next DB::open() verifies the log against.
+    pub wal_trailer_written: bool,
+
+    /// True iff the deadline passed before the close was done.
+    pub deadline_exceeded: bool,
+
+    /// The background error the DB closed with, if any.  Nothing is
+    /// flushed or compacted once there is one.
+    pub background_error: Option<String>,
+}
+
+impl DB {
+    /// Flush the memtable, run the compactions the DB needs, and close
+    /// the log and the DB directory.  Once Env::now_micros() reaches
+    /// "deadline_micros", what is left is abandoned: the compaction in
+    /// progress gives up without touching the MANIFEST, and none is
+    /// started.  The DB closes either way, leaving a state that reopens
+    /// consistently, and the report says how far it got.
+    ///
+    /// Every later call on this handle fails with InvalidArgument, as
+    /// does a second close.
+    pub fn close_with_deadline(&mut self, deadline_micros: u64) -> Result<CloseReport, Status> {
+        self.check_open()?;
+        self.close_deadline_.store(deadline_micros, Ordering::Release);
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        let mut report = CloseReport::default();
+
+        // Wait for the background work in progress, and keep any more from
+        // starting: the close does what is left itself.
+        while state.background_compaction_scheduled_ {
+            state = self.background_work_finished_signal_.wait(state).expect("failed to acquire lock");
+        }
+        state.background_compaction_scheduled_ = true;
+
+        if !self.read_only_ && state.bg_error_.ok() && !self.past_close_deadline() {
+            let s = self.flush_for_close(&mut state);
+            if !s.ok() {
+                self.record_background_error(&mut state, &s);
+            }
+        }
+        let mut iter = state.mem_.as_ref().unwrap().new_iterator();
+        iter.seek_to_first();
+        report.memtable_flushed = state.imm_.is_none() && !iter.valid();
+
+        while !self.read_only_ && state.bg_error_.ok() && (state.imm_.is_some() || state.versions_.needs_compaction()) {
+            if self.past_close_deadline() {
+                break;
+            }
+            let s;
+            (state, s) = self.background_compaction(state);
+            if s.ok() {
+                report.compactions_completed += 1;
+            } else if s.is_incomplete() {
+                report.compactions_abandoned += 1;
+            }
+        }
+        report.compaction_pending = state.imm_.is_some() || state.versions_.needs_compaction();
+        report.deadline_exceeded = self.past_close_deadline();
+        self.close_deadline_.store(u64::MAX, Ordering::Release);
+
+        self.shutting_down_.store(true, Ordering::Release);
+        state.background_compaction_scheduled_ = false;
+
+        report.last_sequence = state.versions_.last_sequence();
+        report.background_error = (!state.bg_error_.ok()).then(|| state.bg_error_.to_string());
+        report.wal_trailer_written = self.release_files(&mut state);
+        self.closed_.store(true, Ordering::Release);
+        log(self.options_.info_log.clone(), &format!("Closed: {:?}", report));
+        Ok(report)
+    }
+
+    /// Write imm_ and mem_ out to tables, as flush() does.
+    /// REQUIRES: "state" is mutex_'s
+    fn flush_for_close(&self, state: &mut DbState) -> Status {
+        if state.imm_.is_some() {
+            let s = self.compact_mem_table(state, true);
+            if !s.ok() {
+                return s;
+            }
+        }
+        let mut iter = state.mem_.as_ref().unwrap().new_iterator();
+        iter.seek_to_first();
+        if !iter.valid() {
+            return Status::new_ok();
+        }
+        let s = self.switch_memtable(state);
+        if !s.ok() {
+            return s;
+        }
+        self.compact_mem_table(state, false)
+    }
+
+    /// Close the log and release the lock on the DB directory, if that
+    /// has not been done yet.  Returns true iff the log got its trailer
+    /// and was synced.
+    pub(super) fn release_files(&self, state: &mut DbState) -> bool {
+        // Mark the log as complete, unless a failed write may have left
+        // it short of what it should hold, or the memtables hold writes
+        // that were never logged.
+        let mut trailer_written = false;
+        if let Some(mut writer) = state.log_.take() {
+            if state.bg_error_.ok() && !state.mem_unlogged_ && !state.imm_unlogged_ {
+                let trailer = WalTrailer { last_sequence: state.versions_.last_sequence(), records: state.log_records_ };
+                let s = writer.add_record(&WriteBatch::new_wal_trailer(&trailer).contents());
+                if !s.ok() {
+                    log(self.options_.info_log.clone(), &format!("Writing trailer of log #{}: {}", state.logfile_number_, s.to_string()));
+                }
+                trailer_written = s.ok();
+            }
+        }
+        if let Some(logfile) = state.logfile_.take() {
+            let mut s = logfile.sync();
+            if s.ok() {
+                s = logfile.close();
+            }
+            if !s.ok() {
+                log(self.options_.info_log.clone(), &format!("Closing log #{}: {}", state.logfile_number_, s.to_string()));
+                trailer_written = false;
+            }
+        }
+
+        if let Some(lock) = state.db_lock_.take() {
+            let s = self.env_.unlock_file(lock);
+            if !s.ok() {
+                log(self.options_.info_log.clone(), &format!("Unlocking {}: {}", self.dbname_, s.to_string()));
+            }
+        }
+        trailer_written
+    }
+}
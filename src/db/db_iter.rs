@@ -0,0 +1,801 @@
+use std::{cmp::Ordering, mem, sync::Arc};
+
+use crate::{comparator::Comparator, iterator::Iterator, slice::Slice, status::Status, util::random::Random};
+
+use super::{dbformat::{append_internal_key, parse_internal_key, ParsedInternalKey, ValueType, MAX_SEQUENCE_NUMBER, READ_BYTES_PERIOD, VALUE_TYPE_FOR_SEEK}, range_del::RangeTombstones, stats::StatsCounters, value_handle::{IteratorPin, ValueHandle}, version_edit::SequenceNumber, version_set::Version};
+
+/// Which direction is the iterator currently moving?
+/// (1) When moving forward, the internal iterator is positioned at
+///     the exact entry that yields this->key(), this->value()
+/// (2) When moving backwards, the internal iterator is positioned
+///     just before all entries whose user key == this->key().
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// Memtables and sstables that make the DB representation contain
+/// (userkey,seq,type) => uservalue entries.  DBIter
+/// combines multiple entries for the same userkey found in the DB
+/// representation into a single entry while accounting for sequence
+/// numbers, deletion markers, overwrites, etc.
+pub(crate) struct DBIter {
+    user_comparator_: Arc<dyn Comparator>,
+    iter_: Box<dyn Iterator>,
+    sequence_: SequenceNumber,
+    status_: Status,
+    saved_key_: Vec<u8>,    // == current key when direction_==Reverse
+    saved_value_: Vec<u8>,  // == current raw value when direction_==Reverse
+    direction_: Direction,
+    valid_: bool,
+    has_deadline_: bool,
+
+    // Of the memtables iter_ reads; they hide the older entries in their
+    // ranges.
+    range_tombstones_: Vec<Arc<RangeTombstones>>,
+
+    // If set, moving forward stops at the first entry whose user key is
+    // at or past it, without looking at the entries beyond, and
+    // seek_to_last() starts before it.
+    upper_bound_: Option<Vec<u8>>,
+
+    // The version whose files iter_ reads, if reads are to be sampled
+    // for seek compactions; see set_read_sampling().
+    version_: Option<Arc<Version>>,
+    rnd_: Random,
+    bytes_until_read_sampling_: usize,
+
+    // Where seeks are counted, if they are; see set_seek_statistics().
+    seek_stats_: Option<Arc<StatsCounters>>,
+
+    // The version iter_ reads, shared with the value handles handed out
+    // since the iterator was last reset, if it hands them out; see
+    // set_version_pin().
+    pin_: Option<Arc<IteratorPin>>,
+}
+
+impl DBIter {
+    /// Return a new iterator that converts internal keys (yielded by
+    /// "internal_iter") that were live at the specified "sequence" number
+    /// into appropriate user keys.  "range_tombstones" are those of the
+    /// memtables that "internal_iter" reads.
+    pub(crate) fn new(user_key_comparator: Arc<dyn Comparator>, internal_iter: Box<dyn Iterator>,
+                      sequence: SequenceNumber, has_deadline: bool, range_tombstones: Vec<Arc<RangeTombstones>>) -> Self {
+        Self {
+            user_comparator_: user_key_comparator,
+            iter_: internal_iter,
+            sequence_: sequence,
+            status_: Status::new_ok(),
+            saved_key_: Vec::new(),
+            saved_value_: Vec::new(),
+            direction_: Direction::Forward,
+            valid_: false,
+            has_deadline_: has_deadline,
+            range_tombstones_: range_tombstones,
+            upper_bound_: None,
+            version_: None,
+            rnd_: Random::new(0),
+            bytes_until_read_sampling_: 0,
+            seek_stats_: None,
+            pin_: None,
+        }
+    }
+
+    /// Sample the entries read, about one per READ_BYTES_PERIOD bytes,
+    /// into Version::record_read_sample() of "version", which should be
+    /// the one the internal iterator reads.  A file that many samples
+    /// find overlapped by another is marked for compaction, as files
+    /// that gets probe in vain are.  "seed" seeds the sampling.
+    pub(crate) fn set_read_sampling(&mut self, version: Arc<Version>, seed: u32) {
+        self.version_ = Some(version);
+        self.rnd_ = Random::new(seed);
+        self.bytes_until_read_sampling_ = self.rnd_.uniform(2 * READ_BYTES_PERIOD as i32) as usize;
+    }
+
+    /// Count each seek in "counters", with the number of tables it
+    /// positions in.  Needs the version of set_read_sampling().
+    pub(crate) fn set_seek_statistics(&mut self, counters: Arc<StatsCounters>) {
+        self.seek_stats_ = Some(counters);
+    }
+
+    /// Hand out value handles into the tables of "version", which should
+    /// be the one the internal iterator reads; see value_lazy().
+    pub(crate) fn set_version_pin(&mut self, version: Arc<Version>) {
+        self.pin_ = Some(IteratorPin::new(version));
+    }
+
+    /// Treat "bound" and the keys past it as the end of the iteration.
+    pub(crate) fn set_upper_bound(&mut self, bound: &[u8]) {
+        self.upper_bound_ = Some(bound.to_vec());
+    }
+
+    /// Make the iterator unpositioned again, reading as of "sequence"
+    /// without a deadline, so that it can be handed out anew.  The
+    /// internal iterator stays as it is: every seek repositions it, and
+    /// so does its read sampling.
+    pub(crate) fn reset(&mut self, sequence: SequenceNumber) {
+        self.sequence_ = sequence;
+        self.status_ = Status::new_ok();
+        self.saved_key_.clear();
+        self.saved_value_.clear();
+        self.direction_ = Direction::Forward;
+        self.valid_ = false;
+        self.has_deadline_ = false;
+        self.upper_bound_ = None;
+    }
+
+    /// Make the value handles handed out so far lapse, as if the
+    /// iterator had been dropped.
+    pub(crate) fn lapse_value_handles(&mut self) {
+        self.pin_ = self.pin_.as_ref().map(|pin| pin.renewed());
+    }
+
+    fn find_next_user_entry(&mut self, mut skipping: bool, skip: &mut Vec<u8>) {
+        // Loop until we hit an acceptable entry to yield
+        debug_assert!(self.iter_.valid());
+        debug_assert!(self.direction_ == Direction::Forward);
+        loop {
+            self.sample_read();
+            let key = self.iter_.key();
+            let parsed = parse_internal_key(&key);
+            if let (Some(ikey), Some(bound)) = (&parsed, &self.upper_bound_) {
+                if self.user_comparator_.compare(&ikey.user_key, &Slice::new(bound)) != Ordering::Less {
+                    break;
+                }
+            }
+            match parsed {
+                Some(ikey) if ikey.sequence <= self.sequence_ => {
+                    if ikey.type_ == ValueType::type_deletion() {
+                        // Arrange to skip all upcoming entries for this key since
+                        // they are hidden by this deletion.
+                        save_key(&ikey.user_key, skip);
+                        skipping = true;
+                    } else if skipping &&
+                        self.user_comparator_.compare(&ikey.user_key, &Slice::new(skip)) != Ordering::Greater {
+                        // Entry hidden
+                    } else if self.range_deleted(&ikey) {
+                        // Hidden, as are the older entries for this key
+                        save_key(&ikey.user_key, skip);
+                        skipping = true;
+                    } else if self.timed_out() {
+                        break;
+                    } else {
+                        self.valid_ = true;
+                        self.saved_key_.clear();
+                        return;
+                    }
+                },
+                Some(_) => {},  // Newer than the snapshot
+                None => { self.status_ = Status::corruption("corrupted internal key in DBIter", ""); },
+            }
+            self.iter_.next();
+            if !self.iter_.valid() {
+                break;
+            }
+        }
+        self.saved_key_.clear();
+        self.valid_ = false;
+    }
+
+    fn find_prev_user_entry(&mut self) {
+        debug_assert!(self.direction_ == Direction::Reverse);
+
+        let mut value_type = ValueType::type_deletion();
+        if self.iter_.valid() {
+            loop {
+                self.sample_read();
+                let key = self.iter_.key();
+                match parse_internal_key(&key) {
+                    Some(ikey) if ikey.sequence <= self.sequence_ => {
+                        if value_type != ValueType::type_deletion() &&
+                            self.user_comparator_.compare(&ikey.user_key, &Slice::new(&self.saved_key_)) == Ordering::Less {
+                            // We encountered a non-deleted value in entries for previous keys,
+                            break;
+                        }
+                        value_type = if self.range_deleted(&ikey) { ValueType::type_deletion() } else { ikey.type_ };
+                        if value_type == ValueType::type_deletion() {
+                            self.saved_key_.clear();
+                            self.saved_value_.clear();
+                        } else {
+                            save_key(&ikey.user_key, &mut self.saved_key_);
+                            self.saved_value_.clear();
+                            self.saved_value_.extend_from_slice(self.iter_.value().data());
+                        }
+                    },
+                    Some(_) => {},  // Newer than the snapshot
+                    None => { self.status_ = Status::corruption("corrupted internal key in DBIter", ""); },
+                }
+                self.iter_.prev();
+                if !self.iter_.valid() {
+                    break;
+                }
+            }
+        }
+
+        if value_type == ValueType::type_deletion() || self.timed_out() {
+            // End
+            self.valid_ = false;
+            self.saved_key_.clear();
+            self.saved_value_.clear();
+            self.direction_ = Direction::Forward;
+        } else {
+            self.valid_ = true;
+        }
+    }
+
+    /// Count the entry the internal iterator is at against the bytes left
+    /// until the next read sample, taking the samples that are due.
+    fn sample_read(&mut self) {
+        let Some(version) = &self.version_ else {
+            return;
+        };
+        let bytes_read = self.iter_.key().size() + self.iter_.value().size();
+        while self.bytes_until_read_sampling_ < bytes_read {
+            self.bytes_until_read_sampling_ += self.rnd_.uniform(2 * READ_BYTES_PERIOD as i32) as usize;
+            version.record_read_sample(&self.iter_.key());
+        }
+        self.bytes_until_read_sampling_ -= bytes_read;
+    }
+
+    /// Returns true iff a range tombstone the iterator sees hides "ikey".
+    fn range_deleted(&self, ikey: &ParsedInternalKey) -> bool {
+        self.range_tombstones_.iter().any(|tombstones| {
+            tombstones.covering_sequence(ikey.user_key.data(), self.sequence_).is_some_and(|s| s > ikey.sequence)
+        })
+    }
+
+    /// A child iterator that ran out of time skips the rest of its
+    /// entries while the others carry on, so once the read has timed out
+    /// nothing more may be yielded.  Only checked when the read has a
+    /// deadline.
+    fn timed_out(&mut self) -> bool {
+        if self.has_deadline_ && self.status_.ok() {
+            let s = self.iter_.status();
+            if s.is_timed_out() {
+                self.status_ = s;
+            }
+        }
+        self.status_.is_timed_out()
+    }
+
+    /// The user key of the entry the internal iterator is positioned at.
+    fn internal_user_key(&self) -> Slice<'_> {
+        let mut key = self.iter_.key();
+        let n = key.size();
+        debug_assert!(n >= 8);
+        key.advance(n.saturating_sub(8))
+    }
+}
+
+impl Iterator for DBIter {
+    fn valid(&self) -> bool {
+        self.valid_
+    }
+
+    fn seek_to_first(&mut self) {
+        self.direction_ = Direction::Forward;
+        self.saved_value_.clear();
+        self.iter_.seek_to_first();
+        if self.iter_.valid() {
+            let mut skip = mem::take(&mut self.saved_key_);
+            self.find_next_user_entry(false, &mut skip);
+        } else {
+            self.valid_ = false;
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        self.direction_ = Direction::Reverse;
+        self.saved_value_.clear();
+        match &self.upper_bound_ {
+            Some(bound) => {
+                // Start from the last entry before the bound
+                let mut target = Vec::new();
+                append_internal_key(&mut target, &ParsedInternalKey::new(&Slice::new(bound), &MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+                self.iter_.seek(&Slice::new(&target));
+                if self.iter_.valid() {
+                    self.iter_.prev();
+                } else {
+                    self.iter_.seek_to_last();
+                }
+            },
+            None => self.iter_.seek_to_last(),
+        }
+        self.find_prev_user_entry();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        if let (Some(counters), Some(version)) = (&self.seek_stats_, &self.version_) {
+            counters.record_seek(version.tables_for_seek(target));
+        }
+        self.direction_ = Direction::Forward;
+        self.saved_value_.clear();
+        self.saved_key_.clear();
+        append_internal_key(&mut self.saved_key_, &ParsedInternalKey::new(target, &self.sequence_, VALUE_TYPE_FOR_SEEK));
+        self.iter_.seek(&Slice::new(&self.saved_key_));
+        if self.iter_.valid() {
+            let mut skip = mem::take(&mut self.saved_key_);
+            self.find_next_user_entry(false, &mut skip);
+        } else {
+            self.valid_ = false;
+        }
+    }
+
+    fn next(&mut self) {
+        debug_assert!(self.valid_);
+
+        if self.direction_ == Direction::Reverse {    // Switch directions?
+            self.direction_ = Direction::Forward;
+            // iter_ is pointing just before the entries for this->key(),
+            // so advance into the range of entries for this->key() and then
+            // use the normal skipping code below.
+            if !self.iter_.valid() {
+                self.iter_.seek_to_first();
+            } else {
+                self.iter_.next();
+            }
+            if !self.iter_.valid() {
+                self.valid_ = false;
+                self.saved_key_.clear();
+                return;
+            }
+            // saved_key_ already contains the key to skip past.
+        } else {
+            // Store in saved_key_ the current key so we skip it below.
+            let mut key = mem::take(&mut self.saved_key_);
+            save_key(&self.internal_user_key(), &mut key);
+            self.saved_key_ = key;
+
+            // iter_ is pointing to current key. We can now safely move to the next to
+            // avoid checking current key.
+            self.iter_.next();
+            if !self.iter_.valid() {
+                self.valid_ = false;
+                self.saved_key_.clear();
+                return;
+            }
+        }
+
+        let mut skip = mem::take(&mut self.saved_key_);
+        self.find_next_user_entry(true, &mut skip);
+    }
+
+    fn prev(&mut self) {
+        debug_assert!(self.valid_);
+
+        if self.direction_ == Direction::Forward {    // Switch directions?
+            // iter_ is pointing at the current entry.  Scan backwards until
+            // the key changes so we can use the normal reverse scanning code.
+            debug_assert!(self.iter_.valid());  // Otherwise valid_ would have been false
+            let mut key = mem::take(&mut self.saved_key_);
+            save_key(&self.internal_user_key(), &mut key);
+            self.saved_key_ = key;
+            loop {
+                self.iter_.prev();
+                if !self.iter_.valid() {
+                    self.valid_ = false;
+                    self.saved_key_.clear();
+                    self.saved_value_.clear();
+                    return;
+                }
+                if self.user_comparator_.compare(&self.internal_user_key(), &Slice::new(&self.saved_key_)) == Ordering::Less {
+                    break;
+                }
+            }
+            self.direction_ = Direction::Reverse;
+        }
+
+        self.find_prev_user_entry();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        debug_assert!(self.valid_);
+        if self.direction_ == Direction::Forward { self.internal_user_key() } else { Slice::new(&self.saved_key_) }
+    }
+
+    fn value(&self) -> Slice<'_> {
+        debug_assert!(self.valid_);
+        if self.direction_ == Direction::Forward { self.iter_.value() } else { Slice::new(&self.saved_value_) }
+    }
+
+    fn value_lazy(&self) -> Option<ValueHandle> {
+        debug_assert!(self.valid_);
+        let pin = self.pin_.as_ref()?;
+        // Moving backwards, the value is only in saved_value_
+        let location = if self.direction_ == Direction::Forward { self.iter_.value_location() } else { None };
+        match location {
+            Some(location) if location.block.is_some() => Some(ValueHandle::in_table(location, pin)),
+            _ => Some(ValueHandle::inline(self.value().data().to_vec(), pin)),
+        }
+    }
+
+    fn status(&self) -> Status {
+        if self.status_.ok() {
+            self.iter_.status()
+        } else {
+            self.status_.clone()
+        }
+    }
+}
+
+fn save_key(k: &Slice, dst: &mut Vec<u8>) {
+    dst.clear();
+    dst.extend_from_slice(k.data());
+}
+
+/// The user keys whose newest entry visible at a sequence number is a
+/// point deletion that still hides an older value, in key order; see
+/// DB::new_tombstone_iterator().  Where DBIter yields the live keys and
+/// skips the deleted ones, this yields the deleted ones and skips the
+/// live keys, reading the same merged entries.  Each key comes with an
+/// empty value.
+///
+/// A key whose newest entry is hidden by a range deletion is not
+/// yielded; the ranges are listed by range_tombstones() instead.
+pub struct TombstoneIter {
+    user_comparator_: Arc<dyn Comparator>,
+    iter_: Box<dyn Iterator>,
+    sequence_: SequenceNumber,
+    status_: Status,
+    key_: Vec<u8>,
+    valid_: bool,
+
+    // Forward: iter_ is past the entries for key_.  Reverse: iter_ is
+    // just before them.
+    direction_: Direction,
+    range_tombstones_: Vec<Arc<RangeTombstones>>,
+}
+
+impl TombstoneIter {
+    /// "internal_iter" and "range_tombstones" are as for DBIter::new().
+    pub(crate) fn new(user_key_comparator: Arc<dyn Comparator>, internal_iter: Box<dyn Iterator>,
+                      sequence: SequenceNumber, range_tombstones: Vec<Arc<RangeTombstones>>) -> Self {
+        Self {
+            user_comparator_: user_key_comparator,
+            iter_: internal_iter,
+            sequence_: sequence,
+            status_: Status::new_ok(),
+            key_: Vec::new(),
+            valid_: false,
+            direction_: Direction::Forward,
+            range_tombstones_: range_tombstones,
+        }
+    }
+
+    /// Returns the [begin, end) ranges of the range deletions visible to
+    /// the iterator, newest memtable first.  They are only kept while in
+    /// memtables: a flush turns them into point deletions of the keys
+    /// they hide, which the iterator then yields.
+    pub fn range_tombstones(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.range_tombstones_.iter().flat_map(|tombstones| tombstones.visible(self.sequence_)).collect()
+    }
+
+    /// Read the entries of one user key after another, the newest first,
+    /// until one of them is a tombstone to yield.
+    fn find_next_tombstone(&mut self) {
+        debug_assert!(self.direction_ == Direction::Forward);
+        while self.iter_.valid() {
+            let mut key = mem::take(&mut self.key_);
+            save_key(&self.internal_user_key(), &mut key);
+            self.key_ = key;
+            let mut deleted = None;
+            let mut older_value = false;
+            while self.iter_.valid() && self.user_comparator_.compare(&self.internal_user_key(), &Slice::new(&self.key_)) == Ordering::Equal {
+                if let Some((type_, point_deleted)) = self.visible_entry() {
+                    match deleted {
+                        None => deleted = Some(point_deleted),
+                        Some(_) => older_value |= type_ == ValueType::type_value(),
+                    }
+                }
+                self.iter_.next();
+            }
+            if deleted == Some(true) && older_value {
+                self.valid_ = true;
+                return;
+            }
+        }
+        self.key_.clear();
+        self.valid_ = false;
+    }
+
+    /// Like find_next_tombstone(), but backwards, reading the entries of
+    /// each user key the oldest first.
+    fn find_prev_tombstone(&mut self) {
+        debug_assert!(self.direction_ == Direction::Reverse);
+        while self.iter_.valid() {
+            let mut key = mem::take(&mut self.key_);
+            save_key(&self.internal_user_key(), &mut key);
+            self.key_ = key;
+            let mut deleted = false;
+            let mut last_was_value = false;
+            let mut older_value = false;
+            while self.iter_.valid() && self.user_comparator_.compare(&self.internal_user_key(), &Slice::new(&self.key_)) == Ordering::Equal {
+                if let Some((type_, point_deleted)) = self.visible_entry() {
+                    older_value |= last_was_value;
+                    last_was_value = type_ == ValueType::type_value();
+                    deleted = point_deleted;
+                }
+                self.iter_.prev();
+            }
+            if deleted && older_value {
+                self.valid_ = true;
+                return;
+            }
+        }
+        self.key_.clear();
+        self.valid_ = false;
+    }
+
+    /// If the entry the internal iterator is at is visible, return its
+    /// type and whether it is a point deletion not itself hidden by a
+    /// newer range deletion.
+    fn visible_entry(&mut self) -> Option<(ValueType, bool)> {
+        let key = self.iter_.key();
+        match parse_internal_key(&key) {
+            Some(ikey) if ikey.sequence <= self.sequence_ => {
+                let range_deleted = self.range_tombstones_.iter().any(|tombstones| {
+                    tombstones.covering_sequence(ikey.user_key.data(), self.sequence_).is_some_and(|s| s > ikey.sequence)
+                });
+                Some((ikey.type_, ikey.type_ == ValueType::type_deletion() && !range_deleted))
+            },
+            Some(_) => None,    // Newer than the snapshot
+            None => {
+                self.status_ = Status::corruption("corrupted internal key in TombstoneIter", "");
+                None
+            },
+        }
+    }
+
+    /// Position the internal iterator at the newest entry for "user_key",
+    /// or at the first entry past it.
+    fn seek_internal(&mut self, user_key: &Slice) {
+        let mut target = Vec::new();
+        append_internal_key(&mut target, &ParsedInternalKey::new(user_key, &MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+        self.iter_.seek(&Slice::new(&target));
+    }
+
+    /// The user key of the entry the internal iterator is positioned at.
+    fn internal_user_key(&self) -> Slice<'_> {
+        let mut key = self.iter_.key();
+        let n = key.size();
+        debug_assert!(n >= 8);
+        key.advance(n.saturating_sub(8))
+    }
+}
+
+impl Iterator for TombstoneIter {
+    fn valid(&self) -> bool {
+        self.valid_
+    }
+
+    fn seek_to_first(&mut self) {
+        self.direction_ = Direction::Forward;
+        self.iter_.seek_to_first();
+        self.find_next_tombstone();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.direction_ = Direction::Reverse;
+        self.iter_.seek_to_last();
+        self.find_prev_tombstone();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.direction_ = Direction::Forward;
+        self.seek_internal(target);
+        self.find_next_tombstone();
+    }
+
+    fn next(&mut self) {
+        debug_assert!(self.valid_);
+        if self.direction_ == Direction::Reverse {
+            // Move past the entries for key_.
+            self.direction_ = Direction::Forward;
+            let key = mem::take(&mut self.key_);
+            self.seek_internal(&Slice::new(&key));
+            while self.iter_.valid() && self.user_comparator_.compare(&self.internal_user_key(), &Slice::new(&key)) == Ordering::Equal {
+                self.iter_.next();
+            }
+        }
+        self.find_next_tombstone();
+    }
+
+    fn prev(&mut self) {
+        debug_assert!(self.valid_);
+        if self.direction_ == Direction::Forward {
+            // Move to just before the entries for key_.
+            self.direction_ = Direction::Reverse;
+            let key = mem::take(&mut self.key_);
+            self.seek_internal(&Slice::new(&key));
+            if self.iter_.valid() {
+                self.iter_.prev();
+            } else {
+                self.iter_.seek_to_last();
+            }
+        }
+        self.find_prev_tombstone();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        debug_assert!(self.valid_);
+        Slice::new(&self.key_)
+    }
+
+    fn value(&self) -> Slice<'_> {
+        debug_assert!(self.valid_);
+        Slice::new(b"")
+    }
+
+    fn status(&self) -> Status {
+        if self.status_.ok() {
+            self.iter_.status()
+        } else {
+            self.status_.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{comparator::bytewise_comparator, db::{dbformat::InternalKeyComparator, memtable::MemTable}};
+
+    use super::*;
+
+    /// A DBIter reading "entries", each (sequence, key, value) with a
+    /// None value for a deletion, as of "sequence".
+    fn new_iter(entries: &[(SequenceNumber, &str, Option<&str>)], sequence: SequenceNumber) -> DBIter {
+        let mem = MemTable::new(&InternalKeyComparator::new(bytewise_comparator()));
+        for &(seq, key, value) in entries {
+            match value {
+                Some(value) => mem.add(seq, ValueType::type_value(), &Slice::new(key.as_bytes()), &Slice::new(value.as_bytes())),
+                None => mem.add(seq, ValueType::type_deletion(), &Slice::new(key.as_bytes()), &Slice::new(b"")),
+            }
+        }
+        DBIter::new(bytewise_comparator(), mem.new_iterator(), sequence, false, Vec::new())
+    }
+
+    fn entry(iter: &DBIter) -> Option<(String, String)> {
+        iter.valid().then(|| (String::from_utf8(iter.key().data().to_vec()).unwrap(), String::from_utf8(iter.value().data().to_vec()).unwrap()))
+    }
+
+    fn forward(iter: &mut DBIter) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        iter.seek_to_first();
+        while let Some(e) = entry(iter) {
+            result.push(e);
+            iter.next();
+        }
+        assert!(iter.status().ok());
+        result
+    }
+
+    fn backward(iter: &mut DBIter) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        iter.seek_to_last();
+        while let Some(e) = entry(iter) {
+            result.push(e);
+            iter.prev();
+        }
+        assert!(iter.status().ok());
+        result
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    const HISTORY: [(SequenceNumber, &str, Option<&str>); 9] = [
+        (1, "a", Some("a1")),
+        (2, "b", Some("b1")),
+        (3, "c", Some("c1")),
+        (4, "a", Some("a2")),   // Overwrites a1
+        (5, "b", None),         // Deletes b1
+        (6, "d", Some("d1")),
+        (7, "c", None),         // Deletes c1, then c is written again
+        (8, "c", Some("c2")),
+        (9, "d", None),         // Deletes the last key
+    ];
+
+    #[test]
+    fn overwrite_test() {
+        let mut iter = new_iter(&HISTORY, 9);
+        assert_eq!(pairs(&[("a", "a2"), ("c", "c2")]), forward(&mut iter));
+        iter.seek(&Slice::new(b"a"));
+        assert_eq!(Some(("a".to_string(), "a2".to_string())), entry(&iter));
+    }
+
+    #[test]
+    fn deletion_test() {
+        let mut iter = new_iter(&HISTORY, 9);
+        iter.seek(&Slice::new(b"b"));
+        assert_eq!(Some(("c".to_string(), "c2".to_string())), entry(&iter));
+        iter.seek(&Slice::new(b"d"));
+        assert!(!iter.valid());
+
+        // Nothing but deletions
+        let mut iter = new_iter(&[(1, "a", None), (2, "b", None)], 2);
+        assert!(forward(&mut iter).is_empty());
+        assert!(backward(&mut iter).is_empty());
+    }
+
+    #[test]
+    fn reverse_test() {
+        let mut iter = new_iter(&HISTORY, 9);
+        assert_eq!(pairs(&[("c", "c2"), ("a", "a2")]), backward(&mut iter));
+
+        // Switching direction across the deleted "b"
+        iter.seek_to_last();
+        iter.prev();
+        assert_eq!(Some(("a".to_string(), "a2".to_string())), entry(&iter));
+        iter.next();
+        assert_eq!(Some(("c".to_string(), "c2".to_string())), entry(&iter));
+        iter.prev();
+        assert_eq!(Some(("a".to_string(), "a2".to_string())), entry(&iter));
+        iter.prev();
+        assert!(!iter.valid());
+
+        iter.seek(&Slice::new(b"b"));
+        iter.prev();
+        assert_eq!(Some(("a".to_string(), "a2".to_string())), entry(&iter));
+        iter.next();
+        iter.next();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let expected: [&[(&str, &str)]; 10] = [
+            &[],
+            &[("a", "a1")],
+            &[("a", "a1"), ("b", "b1")],
+            &[("a", "a1"), ("b", "b1"), ("c", "c1")],
+            &[("a", "a2"), ("b", "b1"), ("c", "c1")],
+            &[("a", "a2"), ("c", "c1")],
+            &[("a", "a2"), ("c", "c1"), ("d", "d1")],
+            &[("a", "a2"), ("d", "d1")],
+            &[("a", "a2"), ("c", "c2"), ("d", "d1")],
+            &[("a", "a2"), ("c", "c2")],
+        ];
+        for (sequence, expected) in expected.iter().enumerate() {
+            let mut iter = new_iter(&HISTORY, sequence as SequenceNumber);
+            assert_eq!(pairs(expected), forward(&mut iter), "at {}", sequence);
+            let mut reversed = pairs(expected);
+            reversed.reverse();
+            assert_eq!(reversed, backward(&mut iter), "at {}", sequence);
+        }
+
+        // A reset iterator reads as of its new sequence
+        let mut iter = new_iter(&HISTORY, 9);
+        iter.reset(2);
+        assert_eq!(pairs(&[("a", "a1"), ("b", "b1")]), forward(&mut iter));
+    }
+
+    #[test]
+    fn tombstone_test() {
+        let tombstones = |entries: &[(SequenceNumber, &str, Option<&str>)], sequence: SequenceNumber| {
+            let mem = new_iter(entries, sequence).iter_;
+            let mut iter = TombstoneIter::new(bytewise_comparator(), mem, sequence, Vec::new());
+            let mut keys = Vec::new();
+            iter.seek_to_first();
+            while iter.valid() {
+                keys.push(String::from_utf8(iter.key().data().to_vec()).unwrap());
+                iter.next();
+            }
+            iter.seek_to_last();
+            for key in keys.iter().rev() {
+                assert_eq!(key.as_bytes(), iter.key().data());
+                iter.prev();
+            }
+            assert!(!iter.valid());
+            keys
+        };
+        assert_eq!(vec!["b", "d"], tombstones(&HISTORY, 9));
+        assert_eq!(vec!["b", "c"], tombstones(&HISTORY, 7));
+        assert_eq!(vec!["b"], tombstones(&HISTORY, 5));
+        assert!(tombstones(&HISTORY, 4).is_empty());
+
+        // Deletions that hide nothing, or only other deletions
+        assert!(tombstones(&[(1, "a", None), (2, "b", None), (3, "b", None)], 3).is_empty());
+    }
+}
@@ -1,12 +1,34 @@
+#[cfg(test)]
+use std::cell::Cell;
 use std::{cmp::Ordering, sync::Arc};
 
-use crate::{comparator::Comparator, slice::Slice, util::coding::{decode_fixed64, decode_fixed64_bytes, encode_fixed64, encode_varint32, encode_varint32_to, put_fixed64, varint_length}};
+use crate::{comparator::Comparator, filter_policy::FilterPolicy, slice::Slice, util::coding::{decode_fixed64, decode_fixed64_bytes, encode_fixed64, encode_varint32, encode_varint32_to, put_fixed64, varint_length}};
 
 use super::version_edit::SequenceNumber;
 
 // Grouping of constants.  We may want to make some of these
 // parameters set via options.
-pub(crate) static NUM_LEVELS: i32 = 7;
+pub(crate) const NUM_LEVELS: i32 = 7;
+
+// Level-0 compaction is started when we hit this many files.
+pub(crate) static L0_COMPACTION_TRIGGER: i32 = 4;
+
+// Soft limit on number of level-0 files.  We slow down writes at this point.
+pub(crate) static L0_SLOWDOWN_WRITES_TRIGGER: i32 = 8;
+
+// Maximum number of level-0 files.  We stop writes at this point.
+pub(crate) static L0_STOP_WRITES_TRIGGER: i32 = 12;
+
+// Maximum level to which a new compacted memtable is pushed if it
+// does not create overlap.  We try to push to level 2 to avoid the
+// relatively expensive level 0=>1 compactions and to avoid some
+// expensive manifest file operations.  We do not push all the way to
+// the largest level since that can generate a lot of wasted disk
+// space if the same key space is being repeatedly overwritten.
+pub(crate) static MAX_MEM_COMPACT_LEVEL: i32 = 2;
+
+// Approximate gap in bytes between samples of data read during iteration.
+pub(crate) const READ_BYTES_PERIOD: usize = 1048576;
 
 // We leave eight bits empty at the bottom so a type and sequence#
 // can be packed together into 64-bits.
@@ -31,6 +53,9 @@ pub(crate) struct ValueType(u8);
 impl ValueType {
     pub(crate) fn type_deletion() -> Self { Self(0) }
     pub(crate) const fn type_value() -> Self { Self(1) }
+    /// Tags range deletions in write batches.  Never part of an internal
+    /// key: see range_del.rs.
+    pub(crate) const fn type_range_deletion() -> Self { Self(2) }
     pub(crate) fn value(&self) -> u8 { self.0 }
 }
 // kValueTypeForSeek defines the ValueType that should be passed when
@@ -42,9 +67,9 @@ impl ValueType {
 pub(crate) static VALUE_TYPE_FOR_SEEK: ValueType = ValueType::type_value();
 
 pub(crate) struct ParsedInternalKey<'a> {
-    user_key: Slice<'a>,
-    sequence: SequenceNumber,
-    type_: ValueType,
+    pub(crate) user_key: Slice<'a>,
+    pub(crate) sequence: SequenceNumber,
+    pub(crate) type_: ValueType,
 }
 impl<'a> ParsedInternalKey<'a> {
     pub(crate) fn new(u: &'a Slice, seq: &SequenceNumber, t: ValueType) -> Self {
@@ -52,13 +77,46 @@ impl<'a> ParsedInternalKey<'a> {
     }
 }
 
+/// Attempt to parse an internal key from "internal_key".  On success,
+/// returns the parsed data.  On error, returns None.
+pub(crate) fn parse_internal_key<'a>(internal_key: &Slice<'a>) -> Option<ParsedInternalKey<'a>> {
+    let n = internal_key.size();
+    if n < 8 {
+        return None;
+    }
+    let data = internal_key.data();
+    let num = decode_fixed64_bytes(&data[n - 8..]);
+    let c = (num & 0xff) as u8;
+    if c > ValueType::type_value().0 {
+        return None;
+    }
+    let mut user_key = internal_key.clone();
+    let user_key = user_key.advance(n - 8);
+    Some(ParsedInternalKey { user_key, sequence: num >> 8, type_: ValueType(c) })
+}
+
 /// Returns the user key portion of an internal key.
 #[inline]
-fn extract_user_key<'a>(internal_key: &'a [u8]) -> Slice<'a> {
+pub(crate) fn extract_user_key<'a>(internal_key: &'a [u8]) -> Slice<'a> {
     debug_assert!(internal_key.len() >= 8);
     Slice::new_with_range(internal_key, 0, internal_key.len() - 8)
 }
 
+/// Returns the packed sequence number and type of an internal key.
+#[inline]
+pub(crate) fn extract_tag(internal_key: &[u8]) -> u64 {
+    debug_assert!(internal_key.len() >= 8);
+    #[cfg(test)]
+    TAG_DECODES.with(|n| n.set(n.get() + 1));
+    decode_fixed64_bytes(&internal_key[internal_key.len() - 8..])
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of extract_tag() calls made on this thread.
+    pub(crate) static TAG_DECODES: Cell<u64> = const { Cell::new(0) };
+}
+
 #[derive(Clone)]
 pub(crate) struct InternalKeyComparator {
     user_comparator_: Arc<dyn Comparator>,
@@ -73,6 +131,12 @@ impl InternalKeyComparator {
     pub(crate) fn compare2(&self, a: &InternalKey, b: &InternalKey) -> Ordering {
         self.compare(&a.encode(), &b.encode())
     }
+
+    /// Compare two internal keys given as their user keys and tags (see
+    /// extract_tag()), for callers that keep the tags decoded.
+    pub(crate) fn compare_parsed(&self, a_user: &[u8], a_tag: u64, b_user: &[u8], b_tag: u64) -> Ordering {
+        self.user_comparator_.compare(&Slice::new(a_user), &Slice::new(b_user)).then(b_tag.cmp(&a_tag))
+    }
 }
 impl Comparator for InternalKeyComparator {
     fn name(&self) -> &'static str {
@@ -86,8 +150,8 @@ impl Comparator for InternalKeyComparator {
         //    decreasing type (though sequence# should be enough to disambiguate)
         let mut r = self.user_comparator_.compare(&extract_user_key(a.data()), &extract_user_key(b.data()));
         if r == Ordering::Equal {
-            let anum = decode_fixed64_bytes(&a.data()[(a.size() - 8)..]);
-            let bnum = decode_fixed64_bytes(&b.data()[(b.size() - 8)..]);
+            let anum = extract_tag(a.data());
+            let bnum = extract_tag(b.data());
             if anum > bnum {
                 r = Ordering::Less;
             } else if anum < bnum {
@@ -96,6 +160,63 @@ impl Comparator for InternalKeyComparator {
         }
         r
     }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &Slice) {
+        // Attempt to shorten the user portion of the key
+        let user_start = extract_user_key(start);
+        let user_limit = extract_user_key(limit.data());
+        let mut tmp = user_start.data().to_vec();
+        self.user_comparator_.find_shortest_separator(&mut tmp, &user_limit);
+        if tmp.len() < user_start.size() &&
+            self.user_comparator_.compare(&user_start, &Slice::new(&tmp)) == Ordering::Less {
+            // User key has become shorter physically, but larger logically.
+            // Tack on the earliest possible number to the shortened user key.
+            put_fixed64(&mut tmp, pack_sequence_and_type(MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+            debug_assert!(self.compare(&Slice::new(start), &Slice::new(&tmp)) == Ordering::Less);
+            debug_assert!(self.compare(&Slice::new(&tmp), limit) == Ordering::Less);
+            *start = tmp;
+        }
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>) {
+        let user_key = extract_user_key(key);
+        let mut tmp = user_key.data().to_vec();
+        self.user_comparator_.find_short_successor(&mut tmp);
+        if tmp.len() < user_key.size() &&
+            self.user_comparator_.compare(&user_key, &Slice::new(&tmp)) == Ordering::Less {
+            // User key has become shorter physically, but larger logically.
+            // Tack on the earliest possible number to the shortened user key.
+            put_fixed64(&mut tmp, pack_sequence_and_type(MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+            debug_assert!(self.compare(&Slice::new(key), &Slice::new(&tmp)) == Ordering::Less);
+            *key = tmp;
+        }
+    }
+}
+
+/// Filter policy wrapper that converts from internal keys to user keys
+pub(crate) struct InternalFilterPolicy {
+    user_policy_: Arc<dyn FilterPolicy>,
+}
+
+impl InternalFilterPolicy {
+    pub(crate) fn new(p: Arc<dyn FilterPolicy>) -> Self {
+        Self { user_policy_: p }
+    }
+}
+
+impl FilterPolicy for InternalFilterPolicy {
+    fn name(&self) -> &str {
+        self.user_policy_.name()
+    }
+
+    fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>) {
+        let user_keys: Vec<Slice> = keys.iter().map(|k| extract_user_key(k.data())).collect();
+        self.user_policy_.create_filter(&user_keys, dst);
+    }
+
+    fn key_may_match(&self, key: &Slice, f: &Slice) -> bool {
+        self.user_policy_.key_may_match(&extract_user_key(key.data()), f)
+    }
 }
 
 /// Modules in this directory should keep internal keys wrapped inside
@@ -168,4 +289,9 @@ impl LookupKey {
     pub(crate) fn user_key(&self) -> Slice {
         Slice::new_with_range(&self.rep_, self.start_, self.rep_.len() - 8)
     }
+
+    /// Return the snapshot sequence number to look the key up at
+    pub(crate) fn sequence(&self) -> SequenceNumber {
+        decode_fixed64_bytes(&self.rep_[self.rep_.len() - 8..]) >> 8
+    }
 }
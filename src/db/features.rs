@@ -0,0 +1,108 @@
+//! Optional on-disk features a database can depend on.
+//!
+//! A database that uses none of them is "baseline" and can be opened by
+//! any build.  The first time one is used, its bit is recorded in the
+//! MANIFEST (see VersionEdit::set_required_features), and from then on
+//! VersionSet::recover refuses to open the database in a build that does
+//! not support it, instead of failing later on data it cannot read.
+
+use crate::status::Status;
+
+/// Write batches in the log may carry an idempotency token record
+/// (see WriteOptions::idempotency_token).
+pub(crate) const IDEMPOTENCY_TOKENS: u64 = 1 << 0;
+
+/// Write batches in the log may carry range deletion records (see
+/// WriteBatch::delete_range).
+pub(crate) const RANGE_DELETIONS: u64 = 1 << 1;
+
+/// MANIFEST file entries may carry the whole-file checksum of the table
+/// (see VersionEdit::add_file_with_checksum).
+pub(crate) const TABLE_CHECKSUMS: u64 = 1 << 2;
+
+/// The MANIFEST may record the largest Options::fencing_token the
+/// database has been opened with.
+pub(crate) const FENCING_TOKENS: u64 = 1 << 3;
+
+/// The MANIFEST may record that the memtables held writes made with
+/// WriteOptions::disable_wal.
+pub(crate) const UNLOGGED_WRITES: u64 = 1 << 4;
+
+/// The log may hold the prepared, committed and rolled back batches of
+/// transactions across several DBs (see MultiDbWriteBatch).
+pub(crate) const TWO_PHASE_COMMIT: u64 = 1 << 5;
+
+/// Every feature this build knows of, with the words used to name it
+/// in errors.
+const FEATURE_NAMES: [(u64, &str); 6] = [
+    (IDEMPOTENCY_TOKENS, "idempotency token"),
+    (RANGE_DELETIONS, "range deletion"),
+    (TABLE_CHECKSUMS, "table checksum"),
+    (FENCING_TOKENS, "fencing token"),
+    (UNLOGGED_WRITES, "unlogged write"),
+    (TWO_PHASE_COMMIT, "two-phase commit"),
+];
+
+/// The features this build can read.
+const SUPPORTED_FEATURES: u64 = IDEMPOTENCY_TOKENS | RANGE_DELETIONS | TABLE_CHECKSUMS | FENCING_TOKENS
+    | UNLOGGED_WRITES | TWO_PHASE_COMMIT;
+
+#[cfg(test)]
+thread_local! {
+    /// Replaces SUPPORTED_FEATURES on this thread when set, so tests can
+    /// act as a build that lacks some features.
+    pub(crate) static SUPPORTED_OVERRIDE: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+/// Return the features the running build supports.
+pub(crate) fn supported_features() -> u64 {
+    #[cfg(test)]
+    if let Some(mask) = SUPPORTED_OVERRIDE.with(|o| o.get()) {
+        return mask;
+    }
+    SUPPORTED_FEATURES
+}
+
+/// Return OK if the running build supports every feature in "required",
+/// else a NotSupported status naming each one it lacks.
+pub(crate) fn check_supported(required: u64) -> Status {
+    let missing = required & !supported_features();
+    if missing == 0 {
+        return Status::new_ok();
+    }
+    let mut names = Vec::new();
+    for bit in 0..64 {
+        let feature = 1u64 << bit;
+        if missing & feature == 0 {
+            continue;
+        }
+        match FEATURE_NAMES.iter().find(|(f, _)| *f == feature) {
+            Some((_, name)) => names.push(format!("{} support", name)),
+            // Recorded by a newer build.
+            None => names.push(format!("unknown feature {} support", bit)),
+        }
+    }
+    Status::not_supported("this database requires", &names.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_supported_test() {
+        assert!(check_supported(0).ok());
+        assert!(check_supported(IDEMPOTENCY_TOKENS).ok());
+
+        let s = check_supported(IDEMPOTENCY_TOKENS | 1 << 6);
+        assert!(s.is_not_supported_error());
+        assert_eq!("Not implemented: this database requires: unknown feature 6 support", s.to_string());
+
+        SUPPORTED_OVERRIDE.with(|o| o.set(Some(0)));
+        let s = check_supported(IDEMPOTENCY_TOKENS);
+        let both = check_supported(IDEMPOTENCY_TOKENS | RANGE_DELETIONS);
+        SUPPORTED_OVERRIDE.with(|o| o.set(None));
+        assert_eq!("Not implemented: this database requires: idempotency token support", s.to_string());
+        assert_eq!("Not implemented: this database requires: idempotency token support, range deletion support", both.to_string());
+    }
+}
@@ -1,6 +1,19 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::{env::Env, slice::Slice, status::Status, util::env::write_string_to_file_sync};
+use crate::{env::Env, slice::Slice, status::Status, util::env::{read_file_to_string, write_string_to_file_sync}};
+
+/// The kinds of files found in a database directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    LogFile,
+    DBLockFile,
+    TableFile,
+    DescriptorFile,
+    CurrentFile,
+    TempFile,
+    InfoLogFile,    // Either the current one, or an old one
+    FenceFile,
+}
 
 /// Return the name of the log file with the specified number
 /// in the db named by "dbname".  The result will be prefixed with
@@ -10,6 +23,22 @@ pub(crate) fn log_file_name(dbname: &str, number: u64) -> String {
     make_file_name(dbname, number, "log")
 }
 
+/// Return the name of the sstable with the specified number
+/// in the db named by "dbname".  The result will be prefixed with
+/// "dbname".
+pub(crate) fn table_file_name(dbname: &str, number: u64) -> String {
+    debug_assert!(number > 0);
+    make_file_name(dbname, number, "ldb")
+}
+
+/// Return the legacy file name for an sstable with the specified number
+/// in the db named by "dbname". The result will be prefixed with
+/// "dbname".
+pub(crate) fn sst_table_file_name(dbname: &str, number: u64) -> String {
+    debug_assert!(number > 0);
+    make_file_name(dbname, number, "sst")
+}
+
 fn make_file_name(dbname: &str, number: u64, suffix: &str) -> String {
     format!("{}/{:06}.{}", dbname, number, suffix)
 }
@@ -32,7 +61,72 @@ pub(crate) fn temp_file_name(dbname: &str, number: u64) -> String {
     make_file_name(dbname, number, "dbtmp")
 }
 
-pub(crate) fn set_current_file(env: Rc<dyn Env>, dbname: &str, descriptor_number: u64) -> Status {
+/// Return the name of the file holding the fencing token of "dbname"
+/// (see Options::fencing_token).
+pub(crate) fn fence_file_name(dbname: &str) -> String {
+    format!("{}/FENCE", dbname)
+}
+
+/// Return the name of the info log file for "dbname".
+pub(crate) fn info_log_file_name(dbname: &str) -> String {
+    format!("{}/LOG", dbname)
+}
+
+/// Return the name of the old info log file for "dbname".
+pub(crate) fn old_info_log_file_name(dbname: &str) -> String {
+    format!("{}/LOG.old", dbname)
+}
+
+/// If filename is a leveldb file, return the number encoded in the
+/// filename and the type of the file.  Owned filenames have the form:
+///    dbname/CURRENT
+///    dbname/LOCK
+///    dbname/LOG
+///    dbname/LOG.old
+///    dbname/FENCE
+///    dbname/MANIFEST-[0-9]+
+///    dbname/[0-9]+.(log|sst|ldb|dbtmp)
+/// 
+/// "filename" is the name relative to the db directory.
+pub(crate) fn parse_file_name(filename: &str) -> Option<(u64, FileType)> {
+    match filename {
+        "CURRENT" => Some((0, FileType::CurrentFile)),
+        "LOCK" => Some((0, FileType::DBLockFile)),
+        "LOG" | "LOG.old" => Some((0, FileType::InfoLogFile)),
+        "FENCE" => Some((0, FileType::FenceFile)),
+        _ => {
+            if let Some(rest) = filename.strip_prefix("MANIFEST-") {
+                match consume_decimal_number(rest) {
+                    Some((num, "")) => Some((num, FileType::DescriptorFile)),
+                    _ => None,
+                }
+            } else {
+                let (num, suffix) = consume_decimal_number(filename)?;
+                let type_ = match suffix {
+                    ".log" => FileType::LogFile,
+                    ".sst" | ".ldb" => FileType::TableFile,
+                    ".dbtmp" => FileType::TempFile,
+                    _ => { return None; },
+                };
+                Some((num, type_))
+            }
+        },
+    }
+}
+
+/// Parse a leading decimal number (at least one digit) from "input" and
+/// return it together with the unparsed remainder.  Returns None if there
+/// are no digits or the number overflows u64.
+fn consume_decimal_number(input: &str) -> Option<(u64, &str)> {
+    let digits = input.bytes().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let num = input[..digits].parse::<u64>().ok()?;
+    Some((num, &input[digits..]))
+}
+
+pub(crate) fn set_current_file(env: Arc<dyn Env>, dbname: &str, descriptor_number: u64) -> Status {
     // Remove leading "dbname/" and add newline to manifest file name
     let manifest = descriptor_file_name(dbname, descriptor_number);
     let prefix = format!("{}/", dbname);
@@ -49,10 +143,98 @@ pub(crate) fn set_current_file(env: Rc<dyn Env>, dbname: &str, descriptor_number
     s
 }
 
+/// Return the fencing token in the FENCE file of "dbname", or zero if
+/// there is no such file.
+pub(crate) fn read_fence_file(env: Arc<dyn Env>, dbname: &str) -> Result<u64, Status> {
+    let fname = fence_file_name(dbname);
+    if !env.file_exists(&fname) {
+        return Ok(0);
+    }
+    let contents = read_file_to_string(env, &fname)?;
+    match contents.strip_suffix('\n').and_then(|token| token.parse().ok()) {
+        Some(token) => Ok(token),
+        None => Err(Status::corruption("bad FENCE file", &contents)),
+    }
+}
+
+/// Make the FENCE file of "dbname" hold "token", replacing it atomically.
+pub(crate) fn set_fence_file(env: Arc<dyn Env>, dbname: &str, token: u64) -> Status {
+    let tmp = format!("{}.dbtmp", fence_file_name(dbname));
+    let contents = format!("{}\n", token);
+    let mut s = write_string_to_file_sync(env.clone(), &Slice::new(contents.as_bytes()), &tmp);
+    if s.ok() {
+        s = env.rename_file(&tmp, &fence_file_name(dbname));
+    }
+    if !s.ok() {
+        env.remove_file(&tmp);
+    }
+    s
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_test() {
+        // Successful parses
+        let cases = [
+            ("100.log", 100, FileType::LogFile),
+            ("0.log", 0, FileType::LogFile),
+            ("0.sst", 0, FileType::TableFile),
+            ("0.ldb", 0, FileType::TableFile),
+            ("CURRENT", 0, FileType::CurrentFile),
+            ("LOCK", 0, FileType::DBLockFile),
+            ("MANIFEST-2", 2, FileType::DescriptorFile),
+            ("MANIFEST-7", 7, FileType::DescriptorFile),
+            ("LOG", 0, FileType::InfoLogFile),
+            ("LOG.old", 0, FileType::InfoLogFile),
+            ("FENCE", 0, FileType::FenceFile),
+            ("18446744073709551615.log", 18446744073709551615u64, FileType::LogFile),
+        ];
+        for (fname, number, type_) in cases {
+            assert_eq!(Some((number, type_)), parse_file_name(fname), "{}", fname);
+        }
+
+        // Errors
+        let errors = [
+            "", "foo", "foo-dx-100.log", ".log", "", "manifest", "CURREN", "CURRENTX", "FENCE.dbtmp",
+            "MANIFES", "MANIFEST", "MANIFEST-", "XMANIFEST-3", "MANIFEST-3x", "LOC", "LOCKx",
+            "LO", "LOGx", "18446744073709551616.log", "184467440737095516150.log", "100",
+            "100.", "100.lop",
+        ];
+        for fname in errors {
+            assert_eq!(None, parse_file_name(fname), "{}", fname);
+        }
+    }
+
+    #[test]
+    fn construction_test() {
+        let fname = current_file_name("foo");
+        assert_eq!(Some((0, FileType::CurrentFile)), parse_file_name(fname.strip_prefix("foo/").unwrap()));
+
+        let fname = lock_file_name("foo");
+        assert_eq!(Some((0, FileType::DBLockFile)), parse_file_name(fname.strip_prefix("foo/").unwrap()));
+
+        let fname = log_file_name("foo", 192);
+        assert_eq!(Some((192, FileType::LogFile)), parse_file_name(fname.strip_prefix("foo/").unwrap()));
+
+        let fname = table_file_name("bar", 200);
+        assert_eq!(Some((200, FileType::TableFile)), parse_file_name(fname.strip_prefix("bar/").unwrap()));
+
+        let fname = descriptor_file_name("bar", 100);
+        assert_eq!(Some((100, FileType::DescriptorFile)), parse_file_name(fname.strip_prefix("bar/").unwrap()));
+
+        let fname = temp_file_name("tmp", 999);
+        assert_eq!(Some((999, FileType::TempFile)), parse_file_name(fname.strip_prefix("tmp/").unwrap()));
+
+        let fname = info_log_file_name("foo");
+        assert_eq!(Some((0, FileType::InfoLogFile)), parse_file_name(fname.strip_prefix("foo/").unwrap()));
+
+        let fname = old_info_log_file_name("foo");
+        assert_eq!(Some((0, FileType::InfoLogFile)), parse_file_name(fname.strip_prefix("foo/").unwrap()));
+    }
+
     #[test]
     fn descriptor_file_name_test() {
         assert_eq!(descriptor_file_name("test", 111), "test/MANIFEST-000111");
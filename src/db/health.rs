@@ -0,0 +1,94 @@
+//! A summary of whether a DB keeps up with its writes, cheap enough to
+//! poll from a readiness probe.  See DB::health().
+
+/// How well the DB is keeping up, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthState {
+    Healthy,
+
+    /// Writes go through, but level-0 has reached the slowdown trigger
+    /// or compactions are behind.
+    Degraded,
+
+    /// Writes wait: level-0 has reached the stop trigger, or the
+    /// memtable is full while the previous one is still being flushed.
+    Stalled,
+
+    /// A background error was recorded; every write fails until the DB
+    /// is reopened.
+    Failed,
+}
+
+/// State of a DB as of one DB::health() call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbHealth {
+    pub state: HealthState,
+
+    /// The background error that writes fail with, if any.
+    pub background_error: Option<String>,
+
+    /// See DB::estimate_compaction_backlog().
+    pub compaction_debt_bytes: u64,
+
+    pub l0_files: usize,
+
+    /// True iff a full memtable is waiting to be written to a table.
+    pub imm_pending: bool,
+
+    /// Seconds since the oldest write that is not in a table yet (by
+    /// Options::env's clock), or None if every write is in a table.
+    pub oldest_unflushed_age_secs: Option<u64>,
+
+    /// Writers waiting behind the write that is being logged.
+    pub stalled_writers: usize,
+}
+
+/// Limits DbHealth::is_ready() checks against.
+#[derive(Debug, Clone)]
+pub struct ReadinessThresholds {
+    /// Not ready while the compaction backlog is larger than this.
+    /// Default: 256MB
+    pub max_compaction_debt_bytes: u64,
+
+    /// If false, not ready while the state is Degraded.
+    /// Default: true
+    pub allow_degraded: bool,
+
+    /// If non-null, not ready while the oldest unflushed write is older
+    /// than this many seconds.
+    /// Default: NULL
+    pub max_unflushed_age_secs: Option<u64>,
+}
+
+impl ReadinessThresholds {
+    pub fn new() -> Self {
+        Self {
+            max_compaction_debt_bytes: 256 * 1048576,
+            allow_degraded: true,
+            max_unflushed_age_secs: None,
+        }
+    }
+}
+
+impl Default for ReadinessThresholds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DbHealth {
+    /// Returns true iff the DB should take traffic: it is neither stalled
+    /// nor failed, and within "thresholds".
+    pub fn is_ready(&self, thresholds: &ReadinessThresholds) -> bool {
+        let state_ok = match self.state {
+            HealthState::Healthy => true,
+            HealthState::Degraded => thresholds.allow_degraded,
+            HealthState::Stalled | HealthState::Failed => false,
+        };
+        let age_ok = match (self.oldest_unflushed_age_secs, thresholds.max_unflushed_age_secs) {
+            (Some(age), Some(max)) => age <= max,
+            _ => true,
+        };
+        state_ok && age_ok && self.compaction_debt_bytes <= thresholds.max_compaction_debt_bytes
+    }
+}
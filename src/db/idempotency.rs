@@ -0,0 +1,112 @@
+//! Remembers the idempotency tokens of recent writes (see
+//! WriteOptions::idempotency_token) so a retried write can be recognized.
+//!
+//! Each token is remembered together with the first sequence number of
+//! its batch and the number of the log file holding it.  Tokens are
+//! forgotten oldest first, once more than the configured number have been
+//! added or once their log file is deleted.
+//!
+//! Not thread-safe: callers must provide their own synchronization.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::version_edit::SequenceNumber;
+
+pub(crate) type IdempotencyToken = [u8; 16];
+
+pub(crate) struct TokenWindow {
+    capacity_: usize,
+    tokens_: HashMap<IdempotencyToken, SequenceNumber>,
+    // Tokens in the order they were added, with the sequence and log
+    // number they were added with.
+    order_: VecDeque<(IdempotencyToken, SequenceNumber, u64)>,
+}
+
+impl TokenWindow {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity_: capacity, tokens_: HashMap::new(), order_: VecDeque::new() }
+    }
+
+    /// Return the sequence number "token" was committed at, if it is
+    /// still remembered.
+    pub(crate) fn get(&self, token: &IdempotencyToken) -> Option<SequenceNumber> {
+        self.tokens_.get(token).copied()
+    }
+
+    /// Remember that the batch carrying "token" was committed at
+    /// "sequence" and logged in log file "log_number".
+    pub(crate) fn insert(&mut self, token: IdempotencyToken, sequence: SequenceNumber, log_number: u64) {
+        if self.capacity_ == 0 {
+            return;
+        }
+        self.tokens_.insert(token, sequence);
+        self.order_.push_back((token, sequence, log_number));
+        while self.order_.len() > self.capacity_ {
+            self.pop_oldest();
+        }
+    }
+
+    /// Forget every token logged in a log file numbered below "log_number".
+    pub(crate) fn expire_logs_before(&mut self, log_number: u64) {
+        while self.order_.front().is_some_and(|&(_, _, log)| log < log_number) {
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((token, sequence, _)) = self.order_.pop_front() {
+            // The token may have been added again since; keep the newer entry.
+            if self.tokens_.get(&token) == Some(&sequence) {
+                self.tokens_.remove(&token);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(n: u8) -> IdempotencyToken {
+        [n; 16]
+    }
+
+    #[test]
+    fn capacity_test() {
+        let mut window = TokenWindow::new(3);
+        for i in 1..=4 {
+            window.insert(token(i), 10 * i as u64, 1);
+        }
+        assert_eq!(None, window.get(&token(1)));
+        assert_eq!(Some(20), window.get(&token(2)));
+        assert_eq!(Some(40), window.get(&token(4)));
+
+        // Re-adding a token keeps only the newer sequence.
+        window.insert(token(2), 50, 1);
+        assert_eq!(Some(50), window.get(&token(2)));
+        window.insert(token(5), 60, 1);
+        assert_eq!(Some(50), window.get(&token(2)));
+        assert_eq!(None, window.get(&token(3)));
+
+        let mut disabled = TokenWindow::new(0);
+        disabled.insert(token(1), 1, 1);
+        assert_eq!(None, disabled.get(&token(1)));
+    }
+
+    #[test]
+    fn expire_logs_test() {
+        let mut window = TokenWindow::new(10);
+        window.insert(token(1), 1, 3);
+        window.insert(token(2), 2, 3);
+        window.insert(token(3), 3, 5);
+        window.expire_logs_before(3);
+        assert_eq!(Some(1), window.get(&token(1)));
+        window.expire_logs_before(4);
+        assert_eq!(None, window.get(&token(1)));
+        assert_eq!(None, window.get(&token(2)));
+        assert_eq!(Some(3), window.get(&token(3)));
+        window.expire_logs_before(6);
+        assert_eq!(None, window.get(&token(3)));
+        assert!(window.order_.is_empty());
+    }
+}
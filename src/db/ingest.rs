@@ -0,0 +1,188 @@
+//! Adding table files built outside the DB (see SstFileWriter) to it.
+//!
+//! Ingested entries carry sequence number zero, which makes them older
+//! than anything written to the DB.  So that they are not hidden by it, a
+//! file may only cover user keys the DB does not hold: it must not
+//! overlap the memtables, the tables of the DB, or the other files
+//! ingested with it.
+
+use std::cmp::Ordering;
+
+use crate::{db::{dbformat::{extract_user_key, parse_internal_key, InternalKey, MAX_SEQUENCE_NUMBER, NUM_LEVELS, VALUE_TYPE_FOR_SEEK}, filename::table_file_name, memtable::MemTable, version_edit::VersionEdit, DbInner}, env::log, options::ReadOptions, slice::Slice, status::Status, table::Table, util::env::{checksum_file, copy_file}};
+
+/// A file to ingest, checked by DB::inspect_external_file().
+struct ExternalFile {
+    path: String,
+    size: u64,
+    smallest: InternalKey,
+    largest: InternalKey,
+    checksum: u32,      // Of the whole file
+}
+
+impl DbInner {
+    /// Add the table files at "paths", built with SstFileWriter using the
+    /// DB's comparator, to the DB.  Each file is moved into the DB if
+    /// "move_files" is set (and must then be on the same file system),
+    /// and copied otherwise.  Either every file is added or none is.
+    ///
+    /// Fails with InvalidArgument if a file was built with another
+    /// comparator, or covers keys that the DB or another of the files
+    /// already covers: the entries of ingested files are older than
+    /// every write to the DB, so they could not be seen over them.
+    /// Options::batch_transformer does not see their entries.
+    pub fn ingest_external_file(&self, paths: &[&str], move_files: bool) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.read_only_ {
+            return Status::not_supported("ingest_external_file", "database is opened read-only");
+        }
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        if !state.bg_error_.ok() {
+            return state.bg_error_.clone();
+        }
+
+        let mut files = Vec::new();
+        for path in paths {
+            match self.inspect_external_file(path) {
+                Ok(file) => files.push(file),
+                Err(s) => { return s; },
+            }
+        }
+        let ucmp = self.internal_comparator_.user_comparator();
+        files.sort_by(|a, b| ucmp.compare(&a.smallest.user_key(), &b.smallest.user_key()));
+        for pair in files.windows(2) {
+            if ucmp.compare(&pair[0].largest.user_key(), &pair[1].smallest.user_key()) != Ordering::Less {
+                return Status::invalid_argument(&pair[1].path, &format!("overlaps {}", pair[0].path));
+            }
+        }
+        let version = state.versions_.current();
+        let mems = self.live_memtables(&state);
+        for file in &files {
+            let (smallest, largest) = (file.smallest.user_key(), file.largest.user_key());
+            if mems.iter().any(|mem| self.memtable_overlaps(mem, &smallest, &largest))
+                    || (0..NUM_LEVELS).any(|level| version.overlap_in_level(level, Some(&smallest), Some(&largest))) {
+                return Status::invalid_argument(&file.path, "overlaps keys in the database");
+            }
+        }
+
+        let mut s = Status::new_ok();
+        let mut edit = VersionEdit::new();
+        let mut added = Vec::new();
+        for file in &files {
+            let number = state.versions_.new_file_number();
+            state.pending_outputs_.insert(number);
+            let target = table_file_name(&self.dbname_, number);
+            s = if move_files {
+                self.env_.rename_file(&file.path, &target)
+            } else {
+                copy_file(self.env_.clone(), &file.path, &target, file.size)
+            };
+            if !s.ok() {
+                state.pending_outputs_.remove(&number);
+                break;
+            }
+            added.push((number, file));
+            // Deeper than level-0, a file could overlap the outputs of a
+            // compaction running meanwhile.
+            let level = if state.background_compaction_scheduled_ {
+                0
+            } else {
+                version.pick_level_for_mem_table_output(&self.options_, &file.smallest.user_key(), &file.largest.user_key())
+            };
+            edit.add_file_with_checksum(level, number, file.size, &file.smallest, &file.largest, Some(file.checksum));
+        }
+        sync_point!("db:ingest:before-install", s);
+        if s.ok() {
+            s = self.log_and_apply(&mut state, &mut edit, Some(&mems));
+        }
+
+        for (number, file) in &added {
+            state.pending_outputs_.remove(number);
+            if !s.ok() {
+                // Put the files back as they were.
+                let target = table_file_name(&self.dbname_, *number);
+                if move_files {
+                    self.env_.rename_file(&target, &file.path);
+                } else {
+                    self.env_.remove_file(&target);
+                }
+            }
+        }
+        if s.ok() {
+            log(self.options_.info_log.clone(), &format!("Ingested {} files", added.len()));
+            if self.maybe_schedule_compaction(&mut state) {
+                drop(state);
+                self.schedule_background_call();
+            }
+        } else {
+            log(self.options_.info_log.clone(), &format!("Ingesting {} files failed: {}", paths.len(), s.to_string()));
+        }
+        s
+    }
+
+    /// Check that the file at "path" is a table ordered by the DB's
+    /// comparator whose entries all have sequence number zero and distinct
+    /// user keys, and find its key range.
+    fn inspect_external_file(&self, path: &str) -> Result<ExternalFile, Status> {
+        let size = self.env_.get_file_size(path)?;
+        let table = Table::open(&self.options_, self.env_.new_random_access_file(path)?, size)?;
+        let ucmp = self.internal_comparator_.user_comparator();
+        match table.comparator_name() {
+            Some(name) if name == ucmp.name() => {},
+            Some(name) => {
+                return Err(Status::invalid_argument(path, &format!("was built with comparator {}, not {}", name, ucmp.name())));
+            },
+            None => {
+                return Err(Status::invalid_argument(path, "does not name its comparator (not built with SstFileWriter)"));
+            },
+        }
+
+        let mut options = ReadOptions::new();
+        options.verify_checksums = true;
+        let mut iter = table.new_iterator(&options, None);
+        iter.seek_to_first();
+        let mut smallest = None;
+        let mut largest = Vec::new();
+        while iter.valid() {
+            let key = iter.key();
+            let Some(parsed) = parse_internal_key(&key) else {
+                return Err(Status::corruption(path, "bad internal key"));
+            };
+            if parsed.sequence != 0 {
+                return Err(Status::invalid_argument(path, "holds entries with non-zero sequence numbers"));
+            }
+            if !largest.is_empty() && ucmp.compare(&parsed.user_key, &extract_user_key(&largest)) != Ordering::Greater {
+                return Err(Status::invalid_argument(path, "holds keys out of order or more than once"));
+            }
+            if smallest.is_none() {
+                smallest = Some(InternalKey::decode_from(&key));
+            }
+            largest = key.data().to_vec();
+            iter.next();
+        }
+        let s = iter.status();
+        if !s.ok() {
+            return Err(s);
+        }
+        let Some(smallest) = smallest else {
+            return Err(Status::invalid_argument(path, "holds no entries"));
+        };
+        let (checksum, checksummed) = checksum_file(self.env_.clone(), path)?;
+        if checksummed != size {
+            return Err(Status::io_error(path, "changed while being ingested"));
+        }
+        Ok(ExternalFile { path: path.to_string(), size, smallest, largest: InternalKey::decode_from(&Slice::new(&largest)), checksum })
+    }
+
+    /// Returns true iff "mem" holds an entry or a range tombstone for a
+    /// user key in [smallest,largest].
+    fn memtable_overlaps(&self, mem: &MemTable, smallest: &Slice, largest: &Slice) -> bool {
+        if mem.range_tombstones().overlaps(Some(smallest.data()), Some(largest.data())) {
+            return true;
+        }
+        let mut iter = mem.new_iterator();
+        iter.seek(&InternalKey::new_from(smallest, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK).encode());
+        iter.valid() && self.internal_comparator_.user_comparator().compare(&extract_user_key(iter.key().data()), largest) != Ordering::Greater
+    }
+}
@@ -0,0 +1,659 @@
+//! Structural, read-only inspection of a database directory.
+//!
+//! inspect_db() summarizes a directory without knowing the options or the
+//! comparator the database was created with.  It only looks at the on-disk
+//! formats: the CURRENT file, the MANIFEST files, the write-ahead logs, the
+//! footers and meta blocks of the live tables, and the file names
+//! themselves.  Nothing is ever written.
+
+use std::{cell::RefCell, collections::{BTreeMap, BTreeSet}, rc::Rc, sync::Arc};
+
+use crate::{env::Env, options::Options, slice::Slice, status::Status, table::Table, util::env::read_file_to_string};
+
+use super::{dbformat::NUM_LEVELS, filename::{current_file_name, parse_file_name, FileType}, log_reader::{Reader, Reporter}, version_edit::VersionEdit};
+
+/// Summary of a database directory produced by inspect_db().
+#[derive(Debug, Clone)]
+pub struct DbInspection {
+    /// Manifest named by the CURRENT file, if CURRENT could be parsed.
+    pub current_manifest: Option<String>,
+    /// Manifest whose edits were replayed to build the level summaries.
+    /// This is the CURRENT manifest when readable, else the newest readable one.
+    pub replayed_manifest: Option<String>,
+    /// Every MANIFEST-* file in the directory, ordered by file number.
+    pub manifests: Vec<ManifestSummary>,
+    /// Comparator name recorded in the replayed manifest.
+    pub comparator: Option<String>,
+    pub log_number: Option<u64>,
+    pub prev_log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+    pub last_sequence: Option<u64>,
+    /// Live tables per level according to the replayed manifest.
+    pub levels: Vec<LevelSummary>,
+    /// Every recognized file in the directory, ordered by name.
+    pub files: Vec<FileSummary>,
+    /// Write-ahead logs still needed by the replayed manifest.
+    pub wal_files: Vec<WalSummary>,
+    /// Recognized files that the replayed manifest no longer references.
+    pub orphan_files: Vec<String>,
+    /// Files whose names are not database files.
+    pub unknown_files: Vec<String>,
+    /// Size of all files in the directory, recognized or not.
+    pub total_bytes: u64,
+    /// Human readable descriptions of everything that looks wrong.
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestSummary {
+    pub name: String,
+    pub number: u64,
+    pub size: u64,
+    pub readable: bool,
+    /// Number of version edits decoded before the first error.
+    pub edits: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelSummary {
+    pub level: i32,
+    pub files: usize,
+    pub bytes: u64,
+    /// Entries in the tables of the level that record their count (see
+    /// TableSummary::entries).
+    pub entries: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSummary {
+    pub name: String,
+    pub number: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// What the file itself says, for live tables that could be opened.
+    pub table: Option<TableSummary>,
+}
+
+/// Details of a live table, read from its footer, index and meta blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSummary {
+    /// Number of data blocks the index lists.
+    pub data_blocks: usize,
+    /// Number of entries, deletion markers included, or None for tables
+    /// written before the properties block existed.
+    pub entries: Option<u64>,
+    /// How many of the entries are deletion markers; see "entries".
+    pub deletions: Option<u64>,
+    /// Name of the filter policy that built the table's filter, if it has
+    /// one.
+    pub filter_policy: Option<String>,
+    /// Name of the comparator of the user keys, if the table records it.
+    pub comparator: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalSummary {
+    pub name: String,
+    pub number: u64,
+    /// Number of records with a valid checksum.
+    pub records: u64,
+    /// Number of corruptions reported while reading.
+    pub corruptions: u64,
+    /// Approximate number of bytes dropped because of corruption.
+    pub dropped_bytes: u64,
+}
+
+/// Summarize the database in directory "path" using only the files found
+/// there.  Only fails if the directory itself cannot be listed; everything
+/// else that looks wrong is recorded in DbInspection::problems.
+pub fn inspect_db(env: Arc<dyn Env>, path: &str) -> Result<DbInspection, Status> {
+    let mut children = env.get_children(path)?;
+    children.sort();
+
+    let mut result = DbInspection {
+        current_manifest: None,
+        replayed_manifest: None,
+        manifests: Vec::new(),
+        comparator: None,
+        log_number: None,
+        prev_log_number: None,
+        next_file_number: None,
+        last_sequence: None,
+        levels: Vec::new(),
+        files: Vec::new(),
+        wal_files: Vec::new(),
+        orphan_files: Vec::new(),
+        unknown_files: Vec::new(),
+        total_bytes: 0,
+        problems: Vec::new(),
+    };
+
+    for name in children {
+        let fname = format!("{}/{}", path, name);
+        let size = match env.get_file_size(&fname) {
+            Ok(size) => size,
+            Err(s) => {
+                result.problems.push(format!("cannot stat {}: {}", name, s.to_string()));
+                0
+            },
+        };
+        result.total_bytes += size;
+        match parse_file_name(&name) {
+            Some((number, file_type)) => {
+                result.files.push(FileSummary { name, number, file_type, size, table: None });
+            },
+            None => { result.unknown_files.push(name); },
+        }
+    }
+
+    // Find the manifest named by CURRENT.
+    let current = current_file_name(path);
+    if env.file_exists(&current) {
+        match read_file_to_string(env.clone(), &current) {
+            Ok(contents) => {
+                match contents.strip_suffix('\n') {
+                    Some(name) if !name.is_empty() && !name.contains('/') => {
+                        if !result.files.iter().any(|f| f.name == name) {
+                            result.problems.push(format!("CURRENT names missing manifest {}", name));
+                        }
+                        result.current_manifest = Some(name.to_string());
+                    },
+                    _ => { result.problems.push("CURRENT file is malformed".to_string()); },
+                }
+            },
+            Err(s) => { result.problems.push(format!("cannot read CURRENT: {}", s.to_string())); },
+        }
+    } else {
+        result.problems.push("CURRENT file is missing".to_string());
+    }
+
+    // Read every manifest, remembering the state each one describes.
+    let mut states = BTreeMap::new();
+    for file in result.files.iter().filter(|f| f.file_type == FileType::DescriptorFile) {
+        let (summary, state) = read_manifest(env.clone(), path, file);
+        if let Some(error) = &summary.error {
+            result.problems.push(format!("manifest {} is unreadable: {}", summary.name, error));
+        }
+        if let Some(state) = state {
+            states.insert(summary.name.clone(), state);
+        }
+        result.manifests.push(summary);
+    }
+
+    let replayed = result.current_manifest.clone()
+        .filter(|name| states.contains_key(name))
+        .or_else(|| states.keys().max_by_key(|name| manifest_number(name)).cloned());
+    let state = match replayed.as_ref().and_then(|name| states.remove(name)) {
+        Some(state) => state,
+        None => {
+            if result.manifests.is_empty() {
+                result.problems.push("no manifest found".to_string());
+            } else {
+                result.problems.push("no readable manifest found".to_string());
+            }
+            return Ok(result);
+        },
+    };
+    if result.current_manifest.is_some() && result.current_manifest != replayed {
+        result.problems.push(format!("fell back to manifest {}", replayed.as_ref().unwrap()));
+    }
+    result.replayed_manifest = replayed;
+
+    result.comparator = state.comparator;
+    result.log_number = state.log_number;
+    result.prev_log_number = state.prev_log_number;
+    result.next_file_number = state.next_file_number;
+    result.last_sequence = state.last_sequence;
+    if result.comparator.is_none() {
+        result.problems.push("manifest has no comparator entry".to_string());
+    }
+    if result.log_number.is_none() {
+        result.problems.push("manifest has no log number entry".to_string());
+    }
+    if result.next_file_number.is_none() {
+        result.problems.push("manifest has no next file number entry".to_string());
+    }
+    if result.last_sequence.is_none() {
+        result.problems.push("manifest has no last sequence entry".to_string());
+    }
+
+    // Check the live tables against the directory listing, and read the
+    // details of those that are there.
+    let mut live_tables = BTreeSet::new();
+    for (level, files) in state.levels.iter().enumerate() {
+        let mut summary = LevelSummary {
+            level: level as i32,
+            files: files.len(),
+            bytes: files.values().sum(),
+            entries: 0,
+        };
+        for (&number, &expected_size) in files {
+            live_tables.insert(number);
+            match result.files.iter_mut().find(|f| f.file_type == FileType::TableFile && f.number == number) {
+                Some(file) => {
+                    if file.size != expected_size {
+                        result.problems.push(format!("table {} has {} bytes, manifest expects {}",
+                            file.name, file.size, expected_size));
+                    }
+                    match read_table(env.clone(), path, file) {
+                        Ok(table) => {
+                            summary.entries += table.entries.unwrap_or(0);
+                            file.table = Some(table);
+                        },
+                        Err(s) => { result.problems.push(format!("table {} is unreadable: {}", file.name, s.to_string())); },
+                    }
+                },
+                None => {
+                    result.problems.push(format!("missing table {} at level {}", number, level));
+                },
+            }
+        }
+        result.levels.push(summary);
+    }
+
+    // Classify the remaining files and scan the live logs.
+    let log_number = result.log_number.unwrap_or(0);
+    let prev_log_number = result.prev_log_number.unwrap_or(0);
+    for file in &result.files {
+        let live = match file.file_type {
+            FileType::LogFile => file.number >= log_number || file.number == prev_log_number,
+            FileType::DescriptorFile => result.replayed_manifest.as_ref() == Some(&file.name),
+            FileType::TableFile => live_tables.contains(&file.number),
+            FileType::TempFile => false,
+            FileType::CurrentFile | FileType::DBLockFile | FileType::InfoLogFile | FileType::FenceFile => true,
+        };
+        if !live {
+            result.orphan_files.push(file.name.clone());
+        } else if file.file_type == FileType::LogFile {
+            let wal = scan_log(env.clone(), path, file);
+            if wal.corruptions > 0 {
+                result.problems.push(format!("log {} has {} corruptions ({} bytes dropped)",
+                    wal.name, wal.corruptions, wal.dropped_bytes));
+            }
+            result.wal_files.push(wal);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Database state described by one manifest.
+struct ManifestState {
+    comparator: Option<String>,
+    log_number: Option<u64>,
+    prev_log_number: Option<u64>,
+    next_file_number: Option<u64>,
+    last_sequence: Option<u64>,
+    levels: Vec<BTreeMap<u64, u64>>,    // file number -> file size
+}
+
+impl ManifestState {
+    fn new() -> Self {
+        Self {
+            comparator: None,
+            log_number: None,
+            prev_log_number: None,
+            next_file_number: None,
+            last_sequence: None,
+            levels: vec![BTreeMap::new(); NUM_LEVELS as usize],
+        }
+    }
+
+    /// Unlike VersionSet::recover, any comparator name is accepted;
+    /// it is recorded rather than checked.
+    fn apply(&mut self, edit: &VersionEdit) {
+        if edit.has_comparator_ {
+            self.comparator = Some(edit.comparator_.clone());
+        }
+        if edit.has_log_number_ {
+            self.log_number = Some(edit.log_number_);
+        }
+        if edit.has_prev_log_number_ {
+            self.prev_log_number = Some(edit.prev_log_number_);
+        }
+        if edit.has_next_file_number_ {
+            self.next_file_number = Some(edit.next_file_number_);
+        }
+        if edit.has_last_sequence_ {
+            self.last_sequence = Some(edit.last_sequence_);
+        }
+        for &(level, number) in &edit.deleted_files_ {
+            self.levels[level as usize].remove(&number);
+        }
+        for (level, meta) in &edit.new_files_ {
+            self.levels[*level as usize].insert(meta.number, meta.file_size);
+        }
+    }
+}
+
+/// Collects corruptions reported by a log::Reader.
+struct CorruptionCounter {
+    count_: RefCell<u64>,
+    bytes_: RefCell<u64>,
+    first_error_: RefCell<Option<String>>,
+}
+
+impl CorruptionCounter {
+    fn new() -> Self {
+        Self { count_: RefCell::new(0), bytes_: RefCell::new(0), first_error_: RefCell::new(None) }
+    }
+}
+
+impl Reporter for CorruptionCounter {
+    fn corruption(&self, bytes: usize, status: &Status) {
+        *self.count_.borrow_mut() += 1;
+        *self.bytes_.borrow_mut() += bytes as u64;
+        self.first_error_.borrow_mut().get_or_insert_with(|| status.to_string());
+    }
+}
+
+fn manifest_number(name: &str) -> u64 {
+    parse_file_name(name).map(|(number, _)| number).unwrap_or(0)
+}
+
+fn read_manifest(env: Arc<dyn Env>, path: &str, file: &FileSummary) -> (ManifestSummary, Option<ManifestState>) {
+    let mut summary = ManifestSummary {
+        name: file.name.clone(),
+        number: file.number,
+        size: file.size,
+        readable: false,
+        edits: 0,
+        error: None,
+    };
+    let fname = format!("{}/{}", path, file.name);
+    let reader_file = match env.new_sequential_file(&fname) {
+        Ok(f) => f,
+        Err(s) => {
+            summary.error = Some(s.to_string());
+            return (summary, None);
+        },
+    };
+    let reporter = Rc::new(CorruptionCounter::new());
+    let mut reader = Reader::new(reader_file, Some(reporter.clone()), true, 0);
+    let mut state = ManifestState::new();
+    while let Some(record) = reader.read_record() {
+        match VersionEdit::decode_from(&Slice::new(&record)) {
+            Ok(edit) => {
+                state.apply(&edit);
+                summary.edits += 1;
+            },
+            Err(s) => {
+                summary.error = Some(s.to_string());
+                break;
+            },
+        }
+    }
+    if summary.error.is_none() {
+        summary.error = reporter.first_error_.borrow().clone();
+    }
+    if summary.error.is_none() && summary.edits == 0 {
+        summary.error = Some("no version edits".to_string());
+    }
+    summary.readable = summary.error.is_none();
+    let state = if summary.readable { Some(state) } else { None };
+    (summary, state)
+}
+
+/// Open the table "file" on its own, without a cache or a filter policy,
+/// and summarize what it records about itself.
+fn read_table(env: Arc<dyn Env>, path: &str, file: &FileSummary) -> Result<TableSummary, Status> {
+    let fname = format!("{}/{}", path, file.name);
+    let table = Table::open(&Options::new(), env.new_random_access_file(&fname)?, file.size)?;
+    let properties = table.properties();
+    Ok(TableSummary {
+        data_blocks: table.num_data_blocks(),
+        entries: properties.map(|p| p.key_sizes.num()),
+        deletions: properties.map(|p| p.num_deletions),
+        filter_policy: table.filter_name().map(str::to_string),
+        comparator: table.comparator_name().map(str::to_string),
+    })
+}
+
+/// Count the records of a log without interpreting their contents.
+fn scan_log(env: Arc<dyn Env>, path: &str, file: &FileSummary) -> WalSummary {
+    let mut wal = WalSummary {
+        name: file.name.clone(),
+        number: file.number,
+        records: 0,
+        corruptions: 0,
+        dropped_bytes: 0,
+    };
+    let fname = format!("{}/{}", path, file.name);
+    let reporter = Rc::new(CorruptionCounter::new());
+    match env.new_sequential_file(&fname) {
+        Ok(f) => {
+            let mut reader = Reader::new(f, Some(reporter.clone()), true, 0);
+            while reader.read_record().is_some() {
+                wal.records += 1;
+            }
+        },
+        Err(s) => { reporter.corruption(file.size as usize, &s); },
+    }
+    wal.corruptions = *reporter.count_.borrow();
+    wal.dropped_bytes = *reporter.bytes_.borrow();
+    wal
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::{dbformat::{InternalKey, ValueType}, filename::{descriptor_file_name, log_file_name, set_current_file, table_file_name}, log_writer::Writer, DB}, filter_policy::new_bloom_filter_policy, helpers::memenv::new_mem_env, options::WriteOptions, table::table_builder::TableBuilder};
+
+    use super::*;
+
+    const DBNAME: &str = "/db";
+
+    fn write_log(env: &Arc<dyn Env>, fname: &str, records: &[Vec<u8>]) {
+        let file = env.new_writable_file(fname).unwrap();
+        let mut writer = Writer::new(file);
+        for record in records {
+            assert!(writer.add_record(&Slice::new(record)).ok());
+        }
+    }
+
+    fn write_file(env: &Arc<dyn Env>, fname: &str, size: usize) {
+        let file = env.new_writable_file(fname).unwrap();
+        assert!(file.append(&Slice::new(&vec![b'x'; size])).ok());
+    }
+
+    fn key(k: &str, seq: u64) -> InternalKey {
+        InternalKey::new_from(&Slice::new(k.as_bytes()), seq, ValueType::type_value())
+    }
+
+    /// Write table "number" holding "keys", with a bloom filter if
+    /// "filter" is set, and return its size.
+    fn write_table(env: &Arc<dyn Env>, number: u64, keys: &[&str], filter: bool) -> u64 {
+        let fname = table_file_name(DBNAME, number);
+        let options = Options { filter_policy: filter.then(|| new_bloom_filter_policy(10)), ..Options::new() };
+        let mut builder = TableBuilder::new(&options, env.new_writable_file(&fname).unwrap());
+        for k in keys {
+            builder.add(&Slice::new(k.as_bytes()), &Slice::new(b"value"));
+        }
+        assert!(builder.finish().ok());
+        env.get_file_size(&fname).unwrap()
+    }
+
+    /// Builds a database with two tables (5 at level 0, 6 at level 1),
+    /// log 7 holding three records and MANIFEST-2 as the current manifest.
+    /// Returns the sizes of the tables.
+    fn build_healthy_db(env: &Arc<dyn Env>) -> (u64, u64) {
+        let sizes = (write_table(env, 5, &["a", "b", "m"], true), write_table(env, 6, &["n", "z"], false));
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name("custom.ReverseComparator");
+        edit.set_log_number(7);
+        edit.set_next_file(8);
+        edit.set_last_sequence(42);
+        edit.add_file(0, 5, sizes.0, &key("a", 1), &key("m", 2));
+        edit.add_file(1, 6, sizes.1, &key("n", 3), &key("z", 4));
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        write_log(env, &descriptor_file_name(DBNAME, 2), &[record]);
+        assert!(set_current_file(env.clone(), DBNAME, 2).ok());
+
+        write_log(env, &log_file_name(DBNAME, 7), &[b"foo".to_vec(), b"bar".to_vec(), b"baz".to_vec()]);
+        sizes
+    }
+
+    #[test]
+    fn healthy_test() {
+        let env = new_mem_env();
+        let sizes = build_healthy_db(&env);
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+
+        assert!(inspection.problems.is_empty(), "{:?}", inspection.problems);
+        assert_eq!(Some("MANIFEST-000002".to_string()), inspection.current_manifest);
+        assert_eq!(inspection.current_manifest, inspection.replayed_manifest);
+        assert_eq!(Some("custom.ReverseComparator".to_string()), inspection.comparator);
+        assert_eq!(Some(7), inspection.log_number);
+        assert_eq!(Some(8), inspection.next_file_number);
+        assert_eq!(Some(42), inspection.last_sequence);
+        assert_eq!(1, inspection.manifests.len());
+        assert!(inspection.manifests[0].readable);
+        assert_eq!(1, inspection.manifests[0].edits);
+        assert_eq!(NUM_LEVELS as usize, inspection.levels.len());
+        assert_eq!(LevelSummary { level: 0, files: 1, bytes: sizes.0, entries: 3 }, inspection.levels[0]);
+        assert_eq!(LevelSummary { level: 1, files: 1, bytes: sizes.1, entries: 2 }, inspection.levels[1]);
+        let table = |number| inspection.files.iter().find(|f| f.file_type == FileType::TableFile && f.number == number).unwrap().table.clone().unwrap();
+        assert_eq!(Some("leveldb.BuiltinBloomFilter2".to_string()), table(5).filter_policy);
+        assert_eq!((1, Some(3), Some(0)), (table(5).data_blocks, table(5).entries, table(5).deletions));
+        assert_eq!(None, table(6).filter_policy);
+        assert_eq!(1, inspection.wal_files.len());
+        assert_eq!(3, inspection.wal_files[0].records);
+        assert_eq!(0, inspection.wal_files[0].corruptions);
+        assert!(inspection.orphan_files.is_empty());
+        assert!(inspection.unknown_files.is_empty());
+        let total: u64 = inspection.files.iter().map(|f| f.size).sum();
+        assert_eq!(total, inspection.total_bytes);
+        assert!(inspection.total_bytes >= sizes.0 + sizes.1);
+    }
+
+    #[test]
+    fn table_details_test() {
+        let env = new_mem_env();
+        let options = Options {
+            env: env.clone(),
+            create_if_missing: true,
+            filter_policy: Some(new_bloom_filter_policy(10)),
+            block_size: 1024,
+            ..Options::new()
+        };
+        let db = DB::open(&options, DBNAME).unwrap();
+        for i in 0..1000 {
+            let k = format!("key{:04}", i);
+            assert!(db.put(&WriteOptions::default(), &Slice::new(k.as_bytes()), &Slice::new(b"value")).ok());
+        }
+        assert!(db.flush().ok());
+        for i in 0..100 {
+            let k = format!("key{:04}", i * 10);
+            assert!(db.delete(&WriteOptions::default(), &Slice::new(k.as_bytes())).ok());
+        }
+        assert!(db.flush().ok());
+        drop(db);
+
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+        assert!(inspection.problems.is_empty(), "{:?}", inspection.problems);
+        let mut tables: Vec<&FileSummary> = inspection.files.iter().filter(|f| f.table.is_some()).collect();
+        tables.sort_by_key(|f| f.number);
+        assert_eq!(2, tables.len());
+        let (puts, deletes) = (tables[0].table.as_ref().unwrap(), tables[1].table.as_ref().unwrap());
+        assert_eq!((Some(1000), Some(0)), (puts.entries, puts.deletions));
+        assert_eq!((Some(100), Some(100)), (deletes.entries, deletes.deletions));
+        assert!(puts.data_blocks > 10, "{}", puts.data_blocks);
+        for table in [puts, deletes] {
+            assert_eq!(Some("leveldb.BuiltinBloomFilter2".to_string()), table.filter_policy);
+        }
+        assert_eq!(1100, inspection.levels.iter().map(|l| l.entries).sum::<u64>());
+
+        // A table that is not one is a problem, not an error.
+        let fname = format!("{}/{}", DBNAME, tables[1].name);
+        let size = env.get_file_size(&fname).unwrap() as usize;
+        let file = env.new_writable_file(&fname).unwrap();
+        assert!(file.append(&Slice::new(&vec![b'x'; size])).ok());
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+        assert_eq!(1, inspection.problems.len(), "{:?}", inspection.problems);
+        assert!(inspection.problems[0].starts_with(&format!("table {} is unreadable", tables[1].name)), "{:?}", inspection.problems);
+        assert_eq!(1000, inspection.levels.iter().map(|l| l.entries).sum::<u64>());
+    }
+
+    #[test]
+    fn corrupted_manifest_test() {
+        let env = new_mem_env();
+        build_healthy_db(&env);
+        // A newer manifest that CURRENT points to, but whose record is garbage.
+        write_log(&env, &descriptor_file_name(DBNAME, 9), &[vec![0xff, 0xff, 0xff]]);
+        assert!(set_current_file(env.clone(), DBNAME, 9).ok());
+        // An old log that the manifest no longer needs.
+        write_log(&env, &log_file_name(DBNAME, 3), &[b"old".to_vec()]);
+
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+        assert_eq!(Some("MANIFEST-000009".to_string()), inspection.current_manifest);
+        assert_eq!(Some("MANIFEST-000002".to_string()), inspection.replayed_manifest);
+        assert_eq!(2, inspection.manifests.len());
+        assert!(inspection.manifests[0].readable);
+        assert!(!inspection.manifests[1].readable);
+        assert_eq!(2, inspection.problems.len(), "{:?}", inspection.problems);
+        assert!(inspection.problems[0].contains("MANIFEST-000009 is unreadable"));
+        assert!(inspection.problems[1].contains("fell back to manifest MANIFEST-000002"));
+        // The fallback manifest still describes both tables.
+        assert_eq!(1, inspection.levels[0].files);
+        assert_eq!(1, inspection.levels[1].files);
+        assert_eq!(vec!["000003.log".to_string(), "MANIFEST-000009".to_string()], inspection.orphan_files);
+    }
+
+    #[test]
+    fn missing_table_test() {
+        let env = new_mem_env();
+        let sizes = build_healthy_db(&env);
+        assert!(env.remove_file(&table_file_name(DBNAME, 6)).ok());
+
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+        assert_eq!(vec!["missing table 6 at level 1".to_string()], inspection.problems);
+        // The manifest still accounts for the missing table.
+        assert_eq!(LevelSummary { level: 1, files: 1, bytes: sizes.1, entries: 0 }, inspection.levels[1]);
+    }
+
+    #[test]
+    fn foreign_junk_file_test() {
+        let env = new_mem_env();
+        build_healthy_db(&env);
+        let before = inspect_db(env.clone(), DBNAME).unwrap().files.len();
+        write_file(&env, &format!("{}/notes.txt", DBNAME), 10);
+        write_file(&env, &format!("{}/000099.ldb.bak", DBNAME), 10);
+
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+        assert!(inspection.problems.is_empty(), "{:?}", inspection.problems);
+        assert_eq!(vec!["000099.ldb.bak".to_string(), "notes.txt".to_string()], inspection.unknown_files);
+        assert_eq!(before, inspection.files.len());
+        assert!(inspection.orphan_files.is_empty());
+        // Unknown files still count towards the directory size.
+        let recognized: u64 = inspection.files.iter().map(|f| f.size).sum();
+        assert_eq!(recognized + 20, inspection.total_bytes);
+    }
+
+    #[test]
+    fn missing_current_test() {
+        let env = new_mem_env();
+        build_healthy_db(&env);
+        assert!(env.remove_file(&current_file_name(DBNAME)).ok());
+
+        let inspection = inspect_db(env.clone(), DBNAME).unwrap();
+        assert_eq!(vec!["CURRENT file is missing".to_string()], inspection.problems);
+        assert_eq!(Some("MANIFEST-000002".to_string()), inspection.replayed_manifest);
+    }
+
+    #[test]
+    fn does_not_write_test() {
+        let env = new_mem_env();
+        build_healthy_db(&env);
+        let mut before = env.get_children(DBNAME).unwrap();
+        before.sort();
+        let sizes: Vec<u64> = before.iter().map(|f| env.get_file_size(&format!("{}/{}", DBNAME, f)).unwrap()).collect();
+
+        inspect_db(env.clone(), DBNAME).unwrap();
+        let mut after = env.get_children(DBNAME).unwrap();
+        after.sort();
+        assert_eq!(before, after);
+        let sizes_after: Vec<u64> = after.iter().map(|f| env.get_file_size(&format!("{}/{}", DBNAME, f)).unwrap()).collect();
+        assert_eq!(sizes, sizes_after);
+    }
+}
@@ -0,0 +1,159 @@
+//! Dropped DB iterators kept for reuse, so that short scans do not build
+//! the tree of iterators under a DBIter (the merging iterator and one
+//! iterator per memtable, level-0 file and level) from scratch each time.
+//! See Options::iterator_pool_size.
+//!
+//! That tree is bound to the memtables and the version that were current
+//! when it was built.  The pool only hands out iterators built since the
+//! last change to them: the DB calls IterPool::invalidate() on each
+//! change, which empties the pool and starts a new generation, and
+//! iterators of older generations are dropped instead of returned to it.
+
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, Weak};
+
+use crate::{iterator::Iterator, options::{ReadOptions, ReadTier}, slice::Slice, status::Status};
+
+use super::{db_iter::DBIter, value_handle::ValueHandle};
+
+/// What a pooled iterator was built for.  Only an iterator built for the
+/// same key can stand in for a new one.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct PoolKey {
+    generation: u64,
+    verify_checksums: bool,
+    fill_cache: bool,
+    read_tier: ReadTier,
+}
+
+pub(crate) struct IterPool {
+    capacity_: usize,
+    // Changed only with free_ locked, so that an iterator coming back
+    // is checked against the generation of the iterators it joins.
+    generation_: AtomicU64,
+    free_: Mutex<Vec<(PoolKey, Box<DBIter>)>>,
+}
+
+impl IterPool {
+    /// A pool that keeps up to "capacity" iterators.  Zero keeps none.
+    pub(crate) fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self { capacity_: capacity, generation_: AtomicU64::new(0), free_: Mutex::new(Vec::new()) })
+    }
+
+    /// Return the key of the iterators that can serve a read with
+    /// "options", or None if such reads are not pooled: the pool is
+    /// disabled, or the read has a deadline or an iterate_upper_bound
+    /// (whose iterators leave out the tables past the bound).
+    /// REQUIRES: the DB's mutex_ is held
+    pub(crate) fn key_for(&self, options: &ReadOptions) -> Option<PoolKey> {
+        if self.capacity_ == 0 || options.deadline.is_some() || options.iterate_upper_bound.is_some() {
+            return None;
+        }
+        Some(PoolKey {
+            generation: self.generation_.load(Ordering::Relaxed),
+            verify_checksums: options.verify_checksums,
+            fill_cache: options.fill_cache,
+            read_tier: options.read_tier,
+        })
+    }
+
+    /// Remove and return an iterator built for "key", if the pool has one.
+    pub(crate) fn take(&self, key: &PoolKey) -> Option<Box<DBIter>> {
+        let mut free = self.free_.lock().unwrap();
+        let i = free.iter().position(|(k, _)| k == key)?;
+        Some(free.swap_remove(i).1)
+    }
+
+    /// Hand out "iter", built for "key"; it comes back to the pool when
+    /// it is dropped.
+    pub(crate) fn wrap(self: &Arc<Self>, key: PoolKey, iter: Box<DBIter>) -> Box<dyn Iterator> {
+        Box::new(PooledIterator { iter_: Some(iter), key_: key, pool_: Arc::downgrade(self) })
+    }
+
+    /// Drop every pooled iterator, and those handed out so far once they
+    /// are dropped.
+    /// REQUIRES: the DB's mutex_ is held
+    pub(crate) fn invalidate(&self) {
+        let mut free = self.free_.lock().unwrap();
+        self.generation_.fetch_add(1, Ordering::Relaxed);
+        free.clear();
+    }
+
+    /// Keep "iter" for reuse if it is of the current generation, the pool
+    /// has room and the iterator has not failed.
+    fn put_back(&self, key: PoolKey, iter: Box<DBIter>) {
+        let mut free = self.free_.lock().unwrap();
+        if key.generation == self.generation_.load(Ordering::Relaxed) && free.len() < self.capacity_ && iter.status().ok() {
+            free.push((key, iter));
+        }
+    }
+}
+
+/// A DBIter that goes back to its pool when dropped.
+struct PooledIterator {
+    iter_: Option<Box<DBIter>>,     // Some until dropped
+    key_: PoolKey,
+    pool_: Weak<IterPool>,
+}
+
+impl PooledIterator {
+    fn iter(&self) -> &DBIter {
+        self.iter_.as_ref().unwrap()
+    }
+
+    fn iter_mut(&mut self) -> &mut DBIter {
+        self.iter_.as_mut().unwrap()
+    }
+}
+
+impl Iterator for PooledIterator {
+    fn valid(&self) -> bool {
+        self.iter().valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter_mut().seek_to_first();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter_mut().seek_to_last();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.iter_mut().seek(target);
+    }
+
+    fn next(&mut self) {
+        self.iter_mut().next();
+    }
+
+    fn prev(&mut self) {
+        self.iter_mut().prev();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        self.iter().key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        self.iter().value()
+    }
+
+    fn value_lazy(&self) -> Option<ValueHandle> {
+        self.iter().value_lazy()
+    }
+
+    fn status(&self) -> Status {
+        self.iter().status()
+    }
+}
+
+impl Drop for PooledIterator {
+    fn drop(&mut self) {
+        // The DB, and with it the pool, may be gone already
+        if let (Some(pool), Some(mut iter)) = (self.pool_.upgrade(), self.iter_.take()) {
+            // Its value handles go with the user's iterator
+            iter.lapse_value_handles();
+            pool.put_back(self.key_, iter);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! The iterators a DB hands out, and the count of those still alive.
+//!
+//! A DbIterator borrows the DB it came from, so the compiler keeps it from
+//! outliving the DB, and the DB's drop may assume that none is left.  An
+//! OwnedDbIterator is the way around that for iterators that must be
+//! 'static, e.g. to move them to another thread: it holds the DB itself,
+//! so the DB, and its background work, stay up until the last such
+//! iterator is dropped.  Both kinds count themselves in the DB's live
+//! iterators; see DB::live_iterators().
+//!
+//! A borrowing iterator cannot outlive its DB:
+//!
+//! ```compile_fail,E0505
+//! use rucksdb::{db::DB, helpers::memenv::new_mem_env, options::{Options, ReadOptions}};
+//!
+//! let options = Options { env: new_mem_env(), create_if_missing: true, ..Options::new() };
+//! let db = DB::open(&options, "/db").unwrap();
+//! let iter = db.new_iterator(&ReadOptions::new());
+//! drop(db);
+//! iter.valid();
+//! ```
+//!
+//! nor be returned from the scope that owns the DB:
+//!
+//! ```compile_fail,E0515
+//! use rucksdb::{db::{DbIterator, DB}, helpers::memenv::new_mem_env, options::{Options, ReadOptions}};
+//!
+//! fn scan() -> DbIterator<'static> {
+//!     let options = Options { env: new_mem_env(), create_if_missing: true, ..Options::new() };
+//!     let db = DB::open(&options, "/db").unwrap();
+//!     db.new_iterator(&ReadOptions::new())
+//! }
+//! ```
+//!
+//! nor be moved into a thread that may outlive it:
+//!
+//! ```compile_fail,E0597
+//! use rucksdb::{db::DB, helpers::memenv::new_mem_env, options::{Options, ReadOptions}};
+//!
+//! let options = Options { env: new_mem_env(), create_if_missing: true, ..Options::new() };
+//! let db = DB::open(&options, "/db").unwrap();
+//! let mut iter = db.range(&ReadOptions::new(), ..);
+//! std::thread::spawn(move || iter.next());
+//! ```
+//!
+//! whereas an owned one can:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use rucksdb::{db::DB, helpers::memenv::new_mem_env, options::{Options, ReadOptions}};
+//!
+//! let options = Options { env: new_mem_env(), create_if_missing: true, ..Options::new() };
+//! let db: Arc<DB> = DB::open(&options, "/db").unwrap().into();
+//! let mut iter = db.new_owned_iterator(&ReadOptions::new());
+//! drop(db);
+//! std::thread::spawn(move || { iter.seek_to_first(); iter.valid() }).join().unwrap();
+//! ```
+
+use std::{ops::{Deref, DerefMut}, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+
+use crate::iterator::Iterator;
+
+use super::DB;
+
+/// An iterator over a DB, borrowing the DB.  Derefs to the underlying
+/// iterator: "dyn Iterator" for new_iterator() and the like, TombstoneIter
+/// for new_tombstone_iterator().
+pub struct DbIterator<'db, I: Iterator + ?Sized = dyn Iterator> {
+    iter_: Box<I>,
+    live_: &'db AtomicUsize,
+}
+
+impl<'db, I: Iterator + ?Sized> DbIterator<'db, I> {
+    /// Count "iter" in "live" until it is dropped.
+    pub(crate) fn new(iter: Box<I>, live: &'db AtomicUsize) -> Self {
+        live.fetch_add(1, Ordering::Relaxed);
+        Self { iter_: iter, live_: live }
+    }
+}
+
+impl<I: Iterator + ?Sized> Drop for DbIterator<'_, I> {
+    fn drop(&mut self) {
+        self.live_.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<I: Iterator + ?Sized> Deref for DbIterator<'_, I> {
+    type Target = I;
+
+    fn deref(&self) -> &I {
+        &self.iter_
+    }
+}
+
+impl<I: Iterator + ?Sized> DerefMut for DbIterator<'_, I> {
+    fn deref_mut(&mut self) -> &mut I {
+        &mut self.iter_
+    }
+}
+
+impl<I: Iterator + ?Sized> AsRef<I> for DbIterator<'_, I> {
+    fn as_ref(&self) -> &I {
+        &self.iter_
+    }
+}
+
+impl<I: Iterator + ?Sized> AsMut<I> for DbIterator<'_, I> {
+    fn as_mut(&mut self) -> &mut I {
+        &mut self.iter_
+    }
+}
+
+/// An iterator over a DB that holds on to the DB, so that it is 'static.
+/// The DB is dropped, and its background work stopped, when the last of
+/// its handles and owned iterators is.  See DB::new_owned_iterator().
+pub struct OwnedDbIterator {
+    // Dropped before db_, so that the DB is still up when the iterator
+    // goes back to its pool.
+    iter_: Box<dyn Iterator>,
+    db_: Arc<DB>,
+}
+
+impl OwnedDbIterator {
+    /// Count "iter" in the live iterators of "db" until it is dropped.
+    pub(crate) fn new(iter: Box<dyn Iterator>, db: Arc<DB>) -> Self {
+        db.live_iterators_.fetch_add(1, Ordering::Relaxed);
+        Self { iter_: iter, db_: db }
+    }
+
+    /// Returns the DB the iterator reads.
+    pub fn db(&self) -> &Arc<DB> {
+        &self.db_
+    }
+}
+
+impl Drop for OwnedDbIterator {
+    fn drop(&mut self) {
+        self.db_.live_iterators_.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl Deref for OwnedDbIterator {
+    type Target = dyn Iterator;
+
+    fn deref(&self) -> &(dyn Iterator + 'static) {
+        self.iter_.as_ref()
+    }
+}
+
+impl DerefMut for OwnedDbIterator {
+    fn deref_mut(&mut self) -> &mut (dyn Iterator + 'static) {
+        self.iter_.as_mut()
+    }
+}
+
+impl AsRef<dyn Iterator> for OwnedDbIterator {
+    fn as_ref(&self) -> &(dyn Iterator + 'static) {
+        self.iter_.as_ref()
+    }
+}
+
+impl AsMut<dyn Iterator> for OwnedDbIterator {
+    fn as_mut(&mut self) -> &mut (dyn Iterator + 'static) {
+        self.iter_.as_mut()
+    }
+}
@@ -10,13 +10,13 @@ pub(crate) const HEADER_SIZE: usize = 4 + 2 + 1;
 pub(crate) struct RecordType(u8);
 impl RecordType {
     // Zero is reserved for preallocated files
-    pub(crate) fn zero_type() -> Self { Self(0) }
+    pub(crate) const fn zero_type() -> Self { Self(0) }
 
-    pub(crate) fn full_type() -> Self { Self(1) }
+    pub(crate) const fn full_type() -> Self { Self(1) }
 
     // For fragments
-    pub(crate) fn first_type() -> Self { Self(2) }
-    pub(crate) fn middle_type() -> Self { Self(3) }
+    pub(crate) const fn first_type() -> Self { Self(2) }
+    pub(crate) const fn middle_type() -> Self { Self(3) }
     pub(crate) const fn last_type() -> Self { Self(4) }
-    pub(crate) fn value(&self) -> u8 { self.0 }
+    pub(crate) const fn value(&self) -> u8 { self.0 }
 }
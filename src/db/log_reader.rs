@@ -0,0 +1,686 @@
+use std::rc::Rc;
+
+use crate::{env::SequentialFile, status::Status, util::{coding::decode_fixed32, crc32c::{unmask, value}}};
+
+use super::log_format::{RecordType, BLOCK_SIZE, HEADER_SIZE, MAX_RECORD_TYPE};
+
+const ZERO_TYPE: u32 = RecordType::zero_type().value() as u32;
+const FULL_TYPE: u32 = RecordType::full_type().value() as u32;
+const FIRST_TYPE: u32 = RecordType::first_type().value() as u32;
+const MIDDLE_TYPE: u32 = RecordType::middle_type().value() as u32;
+const LAST_TYPE: u32 = RecordType::last_type().value() as u32;
+
+// Extend record types with the following special values
+const EOF: u32 = MAX_RECORD_TYPE as u32 + 1;
+// Returned whenever we find an invalid physical record.
+// Currently there are three situations in which this happens:
+// * The record has an invalid CRC (read_physical_record reports a drop)
+// * The record is a 0-length record (No drop is reported)
+// * The record is below constructor's initial_offset (No drop is reported)
+const BAD_RECORD: u32 = MAX_RECORD_TYPE as u32 + 2;
+
+/// Interface for reporting errors.
+pub(crate) trait Reporter {
+    /// Some corruption was detected.  "bytes" is the approximate number
+    /// of bytes dropped due to the corruption.
+    fn corruption(&self, bytes: usize, status: &Status);
+}
+
+pub(crate) struct Reader {
+    file_: Box<dyn SequentialFile>,
+    reporter_: Option<Rc<dyn Reporter>>,
+    checksum_: bool,
+    buffer_: Vec<u8>,
+    buffer_start_: usize,   // Index of the first unconsumed byte of buffer_
+    eof_: bool,     // Last read() indicated EOF by returning < BLOCK_SIZE
+
+    // Offset of the last record returned by read_record.
+    last_record_offset_: u64,
+    // Offset of the first location past the end of buffer_.
+    end_of_buffer_offset_: u64,
+
+    // Offset at which to start looking for the first record to return
+    initial_offset_: u64,
+
+    // True if we are resynchronizing after a seek (initial_offset_ > 0). In
+    // particular, a run of MIDDLE_TYPE and LAST_TYPE records can be silently
+    // skipped in this mode
+    resyncing_: bool,
+
+    // Report a record cut short by the end of the file as corruption
+    // instead of taking it for a write the writer did not finish.
+    report_eof_inconsistency_: bool,
+    truncated_tail_: bool,   // Such a record was reported
+}
+
+impl Reader {
+    /// Create a reader that will return log records from "file".
+    /// 
+    /// If "reporter" is non-null, it is notified whenever some data is
+    /// dropped due to a detected corruption.
+    /// 
+    /// If "checksum" is true, verify checksums if available.
+    /// 
+    /// The Reader will start reading at the first record located at physical
+    /// position >= initial_offset within the file.
+    pub(crate) fn new(file: Box<dyn SequentialFile>, reporter: Option<Rc<dyn Reporter>>, 
+                        checksum: bool, initial_offset: u64) -> Self {
+        Self {
+            file_: file,
+            reporter_: reporter,
+            checksum_: checksum,
+            buffer_: Vec::new(),
+            buffer_start_: 0,
+            eof_: false,
+            last_record_offset_: 0,
+            end_of_buffer_offset_: 0,
+            initial_offset_: initial_offset,
+            resyncing_: initial_offset > 0,
+            report_eof_inconsistency_: false,
+            truncated_tail_: false,
+        }
+    }
+
+    /// Report a truncated record at the end of the file to the reporter,
+    /// rather than ignoring it as the trace of a writer that crashed.
+    pub(crate) fn set_report_eof_inconsistency(&mut self, report: bool) {
+        self.report_eof_inconsistency_ = report;
+    }
+
+    /// Returns true iff a record cut short by the end of the file was
+    /// reported (see set_report_eof_inconsistency()).
+    pub(crate) fn truncated_tail(&self) -> bool {
+        self.truncated_tail_
+    }
+
+    /// Read the next record.  Returns None if we hit the end of the input.
+    pub(crate) fn read_record(&mut self) -> Option<Vec<u8>> {
+        if self.last_record_offset_ < self.initial_offset_ && !self.skip_to_initial_block() {
+            return None;
+        }
+
+        let mut scratch: Vec<u8> = Vec::new();
+        let mut in_fragmented_record = false;
+        // Record offset of the logical record that we're reading
+        let mut prospective_record_offset = 0;
+
+        loop {
+            let (record_type, fragment) = self.read_physical_record();
+
+            // read_physical_record may have only had an empty trailer remaining in its
+            // internal buffer. Calculate the offset of the next physical record now
+            // that it has returned, properly accounting for its header size.
+            // The subtraction may wrap for EOF/BAD_RECORD, in which case the
+            // offset is never used.
+            let physical_record_offset = self.end_of_buffer_offset_
+                .wrapping_sub(self.buffer_size() as u64 + HEADER_SIZE as u64 + fragment.len() as u64);
+
+            if self.resyncing_ {
+                if record_type == MIDDLE_TYPE {
+                    continue;
+                } else if record_type == LAST_TYPE {
+                    self.resyncing_ = false;
+                    continue;
+                } else {
+                    self.resyncing_ = false;
+                }
+            }
+
+            match record_type {
+                FULL_TYPE => {
+                    // Handle bug in earlier versions of log::Writer where
+                    // it could emit an empty FIRST_TYPE record at the tail end
+                    // of a block followed by a FULL_TYPE or FIRST_TYPE record
+                    // at the beginning of the next block.
+                    if in_fragmented_record && !scratch.is_empty() {
+                        self.report_corruption(scratch.len() as u64, "partial record without end(1)");
+                    }
+                    prospective_record_offset = physical_record_offset;
+                    self.last_record_offset_ = prospective_record_offset;
+                    return Some(fragment);
+                },
+                FIRST_TYPE => {
+                    if in_fragmented_record && !scratch.is_empty() {
+                        self.report_corruption(scratch.len() as u64, "partial record without end(2)");
+                    }
+                    prospective_record_offset = physical_record_offset;
+                    scratch = fragment;
+                    in_fragmented_record = true;
+                },
+                MIDDLE_TYPE => {
+                    if !in_fragmented_record {
+                        self.report_corruption(fragment.len() as u64, "missing start of fragmented record(1)");
+                    } else {
+                        scratch.extend(fragment);
+                    }
+                },
+                LAST_TYPE => {
+                    if !in_fragmented_record {
+                        self.report_corruption(fragment.len() as u64, "missing start of fragmented record(2)");
+                    } else {
+                        scratch.extend(fragment);
+                        self.last_record_offset_ = prospective_record_offset;
+                        return Some(scratch);
+                    }
+                },
+                EOF => {
+                    // This can be caused by the writer dying immediately after
+                    // writing a physical record but before completing the next; don't
+                    // treat it as a corruption, just ignore the entire logical record.
+                    if in_fragmented_record {
+                        self.report_truncated_tail(scratch.len() as u64, "partial record without end(3)");
+                    }
+                    return None;
+                },
+                BAD_RECORD => {
+                    if in_fragmented_record {
+                        self.report_corruption(scratch.len() as u64, "error in middle of record");
+                        in_fragmented_record = false;
+                        scratch.clear();
+                    }
+                },
+                _ => {
+                    let dropped = fragment.len() + if in_fragmented_record { scratch.len() } else { 0 };
+                    self.report_corruption(dropped as u64, &format!("unknown record type {}", record_type));
+                    in_fragmented_record = false;
+                    scratch.clear();
+                },
+            }
+        }
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.buffer_.len() - self.buffer_start_
+    }
+
+    fn clear_buffer(&mut self) {
+        self.buffer_.clear();
+        self.buffer_start_ = 0;
+    }
+
+    /// Skips all blocks that are completely before "initial_offset_".
+    /// 
+    /// Returns true on success. Handles reporting.
+    fn skip_to_initial_block(&mut self) -> bool {
+        let offset_in_block = self.initial_offset_ % BLOCK_SIZE as u64;
+        let mut block_start_location = self.initial_offset_ - offset_in_block;
+
+        // Don't search a block if we'd be in the trailer
+        if offset_in_block > (BLOCK_SIZE - 6) as u64 {
+            block_start_location += BLOCK_SIZE as u64;
+        }
+
+        self.end_of_buffer_offset_ = block_start_location;
+
+        // Skip to start of first block that can contain the initial record
+        if block_start_location > 0 {
+            let skip_status = self.file_.skip(block_start_location);
+            if !skip_status.ok() {
+                self.report_drop(block_start_location, &skip_status);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return type, or one of the preceding special values
+    fn read_physical_record(&mut self) -> (u32, Vec<u8>) {
+        loop {
+            if self.buffer_size() < HEADER_SIZE {
+                if !self.eof_ {
+                    // Last read was a full read, so this is a trailer to skip
+                    self.clear_buffer();
+                    match self.file_.read(BLOCK_SIZE) {
+                        Ok(data) => {
+                            self.buffer_ = data;
+                            self.end_of_buffer_offset_ += self.buffer_.len() as u64;
+                            if self.buffer_.len() < BLOCK_SIZE {
+                                self.eof_ = true;
+                            }
+                        },
+                        Err(status) => {
+                            self.report_drop(BLOCK_SIZE as u64, &status);
+                            self.eof_ = true;
+                            return (EOF, Vec::new());
+                        },
+                    }
+                    continue;
+                } else {
+                    // Note that if buffer_ is non-empty, we have a truncated header at the
+                    // end of the file, which can be caused by the writer crashing in the
+                    // middle of writing the header. Instead of considering this an error,
+                    // just report EOF.  Zeros are space preallocated for the
+                    // writer, not a header.
+                    let drop_size = self.buffer_size();
+                    let zeros = self.buffer_[self.buffer_start_..].iter().all(|&b| b == 0);
+                    self.clear_buffer();
+                    if !zeros {
+                        self.report_truncated_tail(drop_size as u64, "truncated header");
+                    }
+                    return (EOF, Vec::new());
+                }
+            }
+
+            // Parse the header
+            let header = &self.buffer_[self.buffer_start_..];
+            let a = header[4] as usize;
+            let b = header[5] as usize;
+            let type_ = header[6] as u32;
+            let length = a | (b << 8);
+            if HEADER_SIZE + length > self.buffer_size() {
+                let drop_size = self.buffer_size();
+                self.clear_buffer();
+                if !self.eof_ {
+                    self.report_corruption(drop_size as u64, "bad record length");
+                    return (BAD_RECORD, Vec::new());
+                }
+                // If the end of the file has been reached without reading |length| bytes
+                // of payload, assume the writer died in the middle of writing the record.
+                // Don't report a corruption.
+                self.report_truncated_tail(drop_size as u64, "truncated record body");
+                return (EOF, Vec::new());
+            }
+
+            if type_ == ZERO_TYPE && length == 0 {
+                // Skip zero length record without reporting any drops since
+                // such records are produced by writers that preallocate
+                // file regions.
+                self.clear_buffer();
+                return (BAD_RECORD, Vec::new());
+            }
+
+            // Check crc
+            if self.checksum_ {
+                let expected_crc = unmask(decode_fixed32([header[0], header[1], header[2], header[3]]));
+                let actual_crc = value(&header[6..(7 + length)]);
+                if actual_crc != expected_crc {
+                    // Drop the rest of the buffer since "length" itself may have
+                    // been corrupted and if we trust it, we could find some
+                    // fragment of a real log record that just happens to look
+                    // like a valid log record.
+                    let drop_size = self.buffer_size();
+                    self.clear_buffer();
+                    self.report_corruption(drop_size as u64, "checksum mismatch");
+                    return (BAD_RECORD, Vec::new());
+                }
+            }
+
+            let fragment = header[HEADER_SIZE..(HEADER_SIZE + length)].to_vec();
+            self.buffer_start_ += HEADER_SIZE + length;
+
+            // Skip physical record that started before initial_offset_
+            if self.end_of_buffer_offset_ - ((self.buffer_size() + HEADER_SIZE + length) as u64) < self.initial_offset_ {
+                return (BAD_RECORD, Vec::new());
+            }
+
+            return (type_, fragment);
+        }
+    }
+
+    /// Reports dropped bytes to the reporter.
+    /// buffer_ must be updated to remove the dropped bytes prior to invocation.
+    fn report_corruption(&self, bytes: u64, reason: &str) {
+        self.report_drop(bytes, &Status::corruption(reason, ""));
+    }
+
+    fn report_truncated_tail(&mut self, bytes: u64, reason: &str) {
+        if self.report_eof_inconsistency_ {
+            self.truncated_tail_ = true;
+            self.report_corruption(bytes, reason);
+        }
+    }
+
+    fn report_drop(&self, bytes: u64, reason: &Status) {
+        if let Some(reporter) = self.reporter_.as_ref() {
+            if self.end_of_buffer_offset_ >= self.initial_offset_ + self.buffer_size() as u64 + bytes {
+                reporter.corruption(bytes as usize, reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::{Cell, RefCell}, sync::{Arc, Mutex}};
+
+    use crate::{db::log_writer::Writer, env::Env, helpers::memenv::new_mem_env, slice::Slice, util::random::Random};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct ReportCollector {
+        dropped_bytes_: Cell<usize>,
+        message_: RefCell<String>,
+    }
+    impl Reporter for ReportCollector {
+        fn corruption(&self, bytes: usize, status: &Status) {
+            self.dropped_bytes_.set(self.dropped_bytes_.get() + bytes);
+            self.message_.borrow_mut().push_str(&status.to_string());
+        }
+    }
+
+    // Construct a string of the specified length made out of the supplied
+    // partial string.
+    fn big_string(partial: &str, n: usize) -> String {
+        partial.repeat(n / partial.len() + 1)[..n].to_string()
+    }
+
+    // Construct a string from a number
+    fn number_string(n: i32) -> String {
+        format!("{}.", n)
+    }
+
+    // Return a skewed potentially long string
+    fn random_skewed_string(i: i32, rnd: &mut Random) -> String {
+        big_string(&number_string(i), rnd.skewed(17) as usize)
+    }
+
+    struct LogTest {
+        env_: Arc<dyn Env>,
+        writer_: Writer,
+        report_: Rc<ReportCollector>,
+        reader_: Option<Reader>,
+    }
+    impl LogTest {
+        const FNAME: &'static str = "/log/000001.log";
+
+        fn new() -> Self {
+            let env = new_mem_env();
+            let writer = Writer::new(env.new_writable_file(Self::FNAME).unwrap());
+            Self { env_: env, writer_: writer, report_: Rc::new(ReportCollector::default()), reader_: None }
+        }
+
+        /// Continue the log with a new writer, as a reopened DB would.
+        fn reopen_for_append(&mut self) {
+            let size = self.env_.get_file_size(Self::FNAME).unwrap();
+            self.writer_ = Writer::new2(self.env_.new_appendable_file(Self::FNAME).unwrap(), size);
+        }
+
+        fn write(&mut self, msg: &str) {
+            assert!(self.reader_.is_none(), "write() after starting to read");
+            assert!(self.writer_.add_record(&Slice::new(msg.as_bytes())).ok());
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            let size = self.env_.get_file_size(Self::FNAME).unwrap();
+            self.env_.new_random_access_file(Self::FNAME).unwrap().read(0, size as usize).unwrap()
+        }
+
+        fn set_contents(&self, contents: &[u8]) {
+            let file = self.env_.new_writable_file(Self::FNAME).unwrap();
+            assert!(file.append(&Slice::new(contents)).ok());
+        }
+
+        fn read(&mut self) -> String {
+            if self.reader_.is_none() {
+                let file = self.env_.new_sequential_file(Self::FNAME).unwrap();
+                let reporter: Rc<dyn Reporter> = self.report_.clone();
+                self.reader_ = Some(Reader::new(file, Some(reporter), true, 0));
+            }
+            match self.reader_.as_mut().unwrap().read_record() {
+                Some(record) => String::from_utf8(record).unwrap(),
+                None => "EOF".to_string(),
+            }
+        }
+
+        /// Start reading with truncated records at the end reported.
+        fn report_eof_inconsistency(&mut self) {
+            assert!(self.reader_.is_none());
+            let reporter: Rc<dyn Reporter> = self.report_.clone();
+            let mut reader = Reader::new(self.env_.new_sequential_file(Self::FNAME).unwrap(), Some(reporter), true, 0);
+            reader.set_report_eof_inconsistency(true);
+            self.reader_ = Some(reader);
+        }
+
+        fn increment_byte(&self, offset: usize, delta: u8) {
+            let mut contents = self.contents();
+            contents[offset] = contents[offset].wrapping_add(delta);
+            self.set_contents(&contents);
+        }
+
+        fn set_byte(&self, offset: usize, new_byte: u8) {
+            let mut contents = self.contents();
+            contents[offset] = new_byte;
+            self.set_contents(&contents);
+        }
+
+        fn shrink_size(&self, bytes: usize) {
+            let mut contents = self.contents();
+            contents.truncate(contents.len() - bytes);
+            self.set_contents(&contents);
+        }
+
+        fn fix_checksum(&self, header_offset: usize, len: usize) {
+            // Compute crc of type/len/data
+            let mut contents = self.contents();
+            let crc = crate::util::crc32c::mask(value(&contents[(header_offset + 6)..(header_offset + 7 + len)]));
+            contents[header_offset..(header_offset + 4)].copy_from_slice(&crc.to_le_bytes());
+            self.set_contents(&contents);
+        }
+
+        fn dropped_bytes(&self) -> usize {
+            self.report_.dropped_bytes_.get()
+        }
+
+        fn report_message(&self) -> String {
+            self.report_.message_.borrow().clone()
+        }
+    }
+
+    #[test]
+    fn empty_test() {
+        let mut t = LogTest::new();
+        assert_eq!("EOF", t.read());
+    }
+
+    #[test]
+    fn read_write_test() {
+        let mut t = LogTest::new();
+        t.write("foo");
+        t.write("bar");
+        t.write("");
+        t.write("xxxx");
+        assert_eq!("foo", t.read());
+        assert_eq!("bar", t.read());
+        assert_eq!("", t.read());
+        assert_eq!("xxxx", t.read());
+        assert_eq!("EOF", t.read());
+        assert_eq!("EOF", t.read());   // Make sure reads at eof work
+    }
+
+    #[test]
+    fn many_blocks_test() {
+        let mut t = LogTest::new();
+        for i in 0..100000 {
+            t.write(&number_string(i));
+        }
+        for i in 0..100000 {
+            assert_eq!(number_string(i), t.read());
+        }
+        assert_eq!("EOF", t.read());
+    }
+
+    #[test]
+    fn fragmentation_test() {
+        let mut t = LogTest::new();
+        t.write("small");
+        t.write(&big_string("medium", 50000));
+        t.write(&big_string("large", 100000));
+        assert_eq!("small", t.read());
+        assert_eq!(big_string("medium", 50000), t.read());
+        assert_eq!(big_string("large", 100000), t.read());
+        assert_eq!("EOF", t.read());
+    }
+
+    #[test]
+    fn random_read_test() {
+        let mut t = LogTest::new();
+        let n = 500;
+        let mut write_rnd = Random::new(301);
+        for i in 0..n {
+            t.write(&random_skewed_string(i, &mut write_rnd));
+        }
+        let mut read_rnd = Random::new(301);
+        for i in 0..n {
+            assert_eq!(random_skewed_string(i, &mut read_rnd), t.read());
+        }
+        assert_eq!("EOF", t.read());
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_ignored_test() {
+        let mut t = LogTest::new();
+        t.write("foo");
+        t.shrink_size(4);   // Drop all payload as well as a header byte
+        assert_eq!("EOF", t.read());
+        // Truncated last record is ignored, not treated as an error.
+        assert_eq!(0, t.dropped_bytes());
+        assert_eq!("", t.report_message());
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_reported_test() {
+        for (shrink, dropped, message) in [(4, 6, "truncated header"), (1, 9, "truncated record body")] {
+            let mut t = LogTest::new();
+            t.write("foo");
+            t.shrink_size(shrink);
+            t.report_eof_inconsistency();
+            assert_eq!("EOF", t.read());
+            assert!(t.reader_.as_ref().unwrap().truncated_tail());
+            assert_eq!(dropped, t.dropped_bytes());
+            assert!(t.report_message().contains(message), "{}", t.report_message());
+        }
+
+        // The fragments read before the end are reported too
+        let mut t = LogTest::new();
+        t.write("foo");
+        t.write(&big_string("bar", BLOCK_SIZE));
+        t.shrink_size(1);
+        t.report_eof_inconsistency();
+        assert_eq!("foo", t.read());
+        assert_eq!("EOF", t.read());
+        assert_eq!(BLOCK_SIZE + HEADER_SIZE - 1, t.dropped_bytes());
+        assert!(t.report_message().contains("truncated record body"));
+        assert!(t.report_message().contains("partial record without end(3)"));
+    }
+
+    #[test]
+    fn reopen_for_append_test() {
+        let mut t = LogTest::new();
+        t.write("hello");
+        t.reopen_for_append();
+        t.write(&big_string("x", BLOCK_SIZE));
+        t.reopen_for_append();
+        t.write("world");
+        assert_eq!("hello", t.read());
+        assert_eq!(big_string("x", BLOCK_SIZE), t.read());
+        assert_eq!("world", t.read());
+        assert_eq!("EOF", t.read());
+        assert_eq!(0, t.dropped_bytes());
+    }
+
+    #[test]
+    fn bad_length_test() {
+        let mut t = LogTest::new();
+        let payload_size = BLOCK_SIZE - HEADER_SIZE;
+        t.write(&big_string("bar", payload_size));
+        t.write("foo");
+        // Least significant size byte is stored in header[4].
+        t.increment_byte(4, 1);
+        assert_eq!("foo", t.read());
+        assert_eq!(BLOCK_SIZE, t.dropped_bytes());
+        assert!(t.report_message().contains("bad record length"));
+    }
+
+    #[test]
+    fn checksum_mismatch_test() {
+        let mut t = LogTest::new();
+        t.write("foo");
+        t.increment_byte(0, 10);
+        assert_eq!("EOF", t.read());
+        assert_eq!(10, t.dropped_bytes());
+        assert!(t.report_message().contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn unexpected_middle_type_test() {
+        let mut t = LogTest::new();
+        t.write("foo");
+        t.set_byte(6, MIDDLE_TYPE as u8);
+        t.fix_checksum(0, 3);
+        assert_eq!("EOF", t.read());
+        assert_eq!(3, t.dropped_bytes());
+        assert!(t.report_message().contains("missing start"));
+    }
+
+    #[test]
+    fn unexpected_first_type_test() {
+        let mut t = LogTest::new();
+        t.write("foo");
+        t.write(&big_string("bar", 100000));
+        t.set_byte(6, FIRST_TYPE as u8);
+        t.fix_checksum(0, 3);
+        assert_eq!(big_string("bar", 100000), t.read());
+        assert_eq!("EOF", t.read());
+        assert_eq!(3, t.dropped_bytes());
+        assert!(t.report_message().contains("partial record without end"));
+    }
+
+    #[test]
+    fn error_joins_records_test() {
+        // Consider two fragmented records:
+        //    first(R1) last(R1) first(R2) last(R2)
+        // where the middle two fragments disappear.  We do not want
+        // first(R1),last(R2) to get joined and returned as a valid record.
+        let mut t = LogTest::new();
+
+        // Write records that span two blocks
+        t.write(&big_string("foo", BLOCK_SIZE));
+        t.write(&big_string("bar", BLOCK_SIZE));
+        t.write("correct");
+
+        // Wipe the middle block
+        let mut contents = t.contents();
+        contents[BLOCK_SIZE..(2 * BLOCK_SIZE)].fill(b'x');
+        t.set_contents(&contents);
+
+        assert_eq!("correct", t.read());
+        assert_eq!("EOF", t.read());
+        let dropped = t.dropped_bytes();
+        assert!(dropped <= 2 * BLOCK_SIZE + 100);
+        assert!(dropped >= 2 * BLOCK_SIZE);
+    }
+
+    /// A WritableFile that records preallocate() calls and drops the data.
+    #[derive(Default)]
+    struct PreallocRecorder {
+        calls_: Mutex<Vec<(u64, u64)>>,
+    }
+    impl crate::env::WritableFile for PreallocRecorder {
+        fn append(&self, _data: &Slice) -> Status { Status::new_ok() }
+        fn close(&self) -> Status { Status::new_ok() }
+        fn flush(&self) -> Status { Status::new_ok() }
+        fn sync(&self) -> Status { Status::new_ok() }
+        fn preallocate(&self, offset: u64, len: u64) -> Status {
+            self.calls_.lock().unwrap().push((offset, len));
+            Status::new_ok()
+        }
+    }
+
+    #[test]
+    fn preallocation_test() {
+        let file = Arc::new(PreallocRecorder::default());
+        let mut writer = Writer::with_preallocation(file.clone(), 100, 50);
+        assert_eq!(vec![(0, 100)], *file.calls_.lock().unwrap());
+        assert!(writer.add_record(&Slice::new(&[b'x'; 30])).ok());    // Ends at 37
+        assert_eq!(1, file.calls_.lock().unwrap().len());
+        assert!(writer.add_record(&Slice::new(&[b'x'; 60])).ok());    // Ends at 104
+        assert!(writer.add_record(&Slice::new(&[b'x'; 200])).ok());   // Ends at 311
+        assert_eq!(vec![(0, 100), (100, 50), (150, 200)], *file.calls_.lock().unwrap());
+
+        let file = Arc::new(PreallocRecorder::default());
+        let mut writer = Writer::with_preallocation(file.clone(), 100, 0);
+        assert!(writer.add_record(&Slice::new(&[b'x'; 200])).ok());
+        assert!(file.calls_.lock().unwrap().is_empty());
+    }
+}
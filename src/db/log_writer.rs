@@ -1,12 +1,18 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::{db::log_format::{BLOCK_SIZE, HEADER_SIZE}, env::WritableFile, slice::Slice, status::Status, util::{coding::encode_fixed32, crc32c::{extend, mask, value}}};
 
 use super::log_format::{RecordType, MAX_RECORD_TYPE};
 
 pub(crate) struct Writer {
-    dest_: Rc<dyn WritableFile>,
+    dest_: Arc<dyn WritableFile>,
     block_offset_: i32, // Current offset in block
+    file_offset_: u64,
+
+    // End of the space preallocated in dest_, and the chunk size it grows
+    // by (zero if preallocation is off).
+    preallocated_: u64,
+    preallocation_block_size_: u64,
     
     // crc32c values for all supported record types.  These are
     // pre-computed to reduce the overhead of computing the crc of the
@@ -18,19 +24,49 @@ impl Writer {
     /// Create a writer that will append data to "*dest".
     /// "*dest" must be initially empty.
     /// "*dest" must remain live while this Writer is in use.
-    pub(crate) fn new(dest: Rc<dyn WritableFile>) -> Self {
+    pub(crate) fn new(dest: Arc<dyn WritableFile>) -> Self {
         Self {
             dest_: dest,
             block_offset_: 0,
+            file_offset_: 0,
+            preallocated_: 0,
+            preallocation_block_size_: 0,
             type_crc_: Self::init_type_crc(),
         }
     }
 
+    /// Like new(), but preallocate the first "initial_size" bytes of
+    /// "*dest" up front, and "block_size" more whenever the records
+    /// reach the end of the preallocated space.  Nothing is preallocated
+    /// if "block_size" is zero.
+    pub(crate) fn with_preallocation(dest: Arc<dyn WritableFile>, initial_size: u64, block_size: u64) -> Self {
+        let mut writer = Self::new(dest);
+        if block_size > 0 {
+            writer.preallocation_block_size_ = block_size;
+            if initial_size > 0 {
+                // Only a hint: appends work the same if it fails.
+                let _ = writer.dest_.preallocate(0, initial_size);
+                writer.preallocated_ = initial_size;
+            }
+        }
+        writer
+    }
+
     /// Create a writer that will append data to "*dest".
     /// "*dest" must have initial length "dest_length".
     /// "*dest" must remain live while this Writer is in use.
-    pub(crate) fn new2(dest: Rc<dyn WritableFile>, dest_length: u64) -> Self {
-        todo!()
+    pub(crate) fn new2(dest: Arc<dyn WritableFile>, dest_length: u64) -> Self {
+        let mut writer = Self::new(dest);
+        writer.block_offset_ = (dest_length % BLOCK_SIZE as u64) as i32;
+        writer.file_offset_ = dest_length;
+        writer.preallocated_ = dest_length;
+        writer
+    }
+
+    /// Return the number of bytes written to "*dest" so far, counting
+    /// the "dest_length" it started with but not preallocated space.
+    pub(crate) fn file_offset(&self) -> u64 {
+        self.file_offset_
     }
 
     pub(crate) fn add_record(&mut self, slice: &Slice) -> Status {
@@ -48,7 +84,9 @@ impl Writer {
                 if leftover > 0 {
                     // Fill the trailer (literal below relies on kHeaderSize being 7)
                     debug_assert!(HEADER_SIZE == 7);
+                    self.reserve(leftover as u64);
                     self.dest_.append(&Slice::new(&vec![0u8; leftover]));
+                    self.file_offset_ += leftover as u64;
                 }
                 self.block_offset_ = 0;
             }
@@ -98,6 +136,7 @@ impl Writer {
         buf[3] = crc_encoded[3];
 
         // Write the header and the payload
+        self.reserve((HEADER_SIZE + length) as u64);
         let mut s = self.dest_.append(&Slice::new(&buf));
         if s.ok() {
             let payload = slice.advance(length);
@@ -107,9 +146,23 @@ impl Writer {
             }
         }
         self.block_offset_ += (HEADER_SIZE + length) as i32;
+        self.file_offset_ += (HEADER_SIZE + length) as u64;
         s
     }
 
+    /// Preallocate more of dest_, if enabled, so that the next "n" bytes
+    /// fall inside the preallocated space.
+    fn reserve(&mut self, n: u64) {
+        let end = self.file_offset_ + n;
+        if self.preallocation_block_size_ == 0 || end <= self.preallocated_ {
+            return;
+        }
+        let len = (end - self.preallocated_).next_multiple_of(self.preallocation_block_size_);
+        // Only a hint: appends work the same if it fails.
+        let _ = self.dest_.preallocate(self.preallocated_, len);
+        self.preallocated_ += len;
+    }
+
     fn init_type_crc() -> [u32; MAX_RECORD_TYPE as usize + 1] {
         let mut type_crc = [0u32; MAX_RECORD_TYPE as usize + 1];
         for i in 0..type_crc.len() {
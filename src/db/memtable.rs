@@ -1,8 +1,10 @@
-use std::{cmp::Ordering, sync::Arc};
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, sync::{Arc, Mutex, OnceLock}};
 
-use crate::{comparator::Comparator, db::skiplist::Iter, slice::Slice, status::Status, util::{arena::Arena, coding::{decode_fixed64_bytes, encode_fixed64_to, encode_varint32_to, get_varint32_idx, varint_length}}};
+use crate::{comparator::Comparator, db::skiplist::Iter, iterator::Iterator, slice::Slice, status::Status, util::{arena::Arena, coding::{decode_fixed64_bytes, encode_fixed64_to, encode_varint32, encode_varint32_to, get_varint32_idx, varint_length}, hash::hash}};
+#[cfg(feature = "arena-canaries")]
+use crate::util::arena::is_poisoned;
 
-use super::{dbformat::{InternalKeyComparator, LookupKey, ValueType}, skiplist::{self, SkipList}, version_edit::SequenceNumber};
+use super::{dbformat::{InternalKeyComparator, LookupKey, ValueType}, range_del::RangeTombstones, skiplist::{self, SkipList}, version_edit::SequenceNumber};
 
 type Table = Arc<SkipList<Vec<u8, Arena>, KeyComparator>, Arena>;
 
@@ -11,46 +13,74 @@ pub(crate) struct MemTable {
     refs_: i32,
     arena_: Arena,
     table_: Table,
+    range_tombstones_: Arc<RangeTombstones>,
 }
 
 impl MemTable {
     /// MemTables are reference counted.  The initial reference count
     /// is zero and the caller must call Ref() at least once.
     pub(crate) fn new(comparator: &InternalKeyComparator) -> Self {
-        let cmp = KeyComparator { comparator: comparator.clone() };
+        Self::with_prefix_compression(comparator, 0)
+    }
+
+    /// Like new(), but if "prefix_len" is not zero, the first
+    /// "prefix_len" bytes of the user keys that share them with other
+    /// keys are stored once, in a dictionary, rather than in each entry.
+    /// See Options::memtable_prefix_compression.
+    pub(crate) fn with_prefix_compression(comparator: &InternalKeyComparator, prefix_len: usize) -> Self {
         let arena = Arena::new();
+        let prefixes = (prefix_len > 0).then(|| Arc::new(PrefixTable::new(prefix_len, arena.clone())));
+        let cmp = KeyComparator {
+            comparator: comparator.clone(),
+            bytewise: comparator.user_comparator().name() == "leveldb.BytewiseComparator",
+            prefixes,
+        };
         let key: Vec<u8, Arena> = Vec::new_in(arena.clone());
         Self {
             comparator_: cmp.clone(),
             refs_: 0,
             arena_: arena.clone(),
             table_: Arc::new_in(SkipList::new_in(key, cmp, arena.clone()), arena),
+            range_tombstones_: Arc::new(RangeTombstones::new(comparator.user_comparator())),
         }
     }
 
     /// Increase reference count.
     pub(crate) fn ref_(&mut self) {
         self.refs_ += 1;
-    } 
+    }
 
     /// Add an entry into memtable that maps key to value at the
     /// specified sequence number and with the specified type.
     /// Typically value will be empty if type==kTypeDeletion.
     pub(crate) fn add(&self, seq: SequenceNumber, type_: ValueType, key: &Slice, value: &Slice) {
         // Format of an entry is concatenation of:
-        //  key_size     : varint32 of internal_key.size()
-        //  key bytes    : char[internal_key.size()]
+        //  prefix_id    : varint32 id of the key's prefix in the prefix
+        //                 table, or 0 if it has none; only with prefix
+        //                 compression
+        //  key_size     : varint32 of internal_key.size(), less the prefix
+        //  key bytes    : char[internal_key.size()], less the prefix
         //  tag          : uint64((sequence << 8) | type)
         //  value_size   : varint32 of value.size()
         //  value bytes  : char[value.size()]
-        let key_size = key.size();
+        let (prefix_id, key) = match &self.comparator_.prefixes {
+            Some(prefixes) => match prefixes.intern(key.data()) {
+                0 => (Some(0), key.data()),
+                id => (Some(id), &key.data()[prefixes.prefix_len_..]),
+            },
+            None => (None, key.data()),
+        };
+        let key_size = key.len();
         let val_size = value.size();
         let internal_key_size = key_size + 8;
-        let encoded_len = varint_length(internal_key_size as u64) + 
+        let encoded_len = prefix_id.map_or(0, |id| varint_length(id as u64)) + varint_length(internal_key_size as u64) +
                                 internal_key_size + varint_length(val_size as u64) + val_size;
         let mut buf: Vec<u8, Arena> = Vec::with_capacity_in(encoded_len, self.arena_.clone());
+        if let Some(id) = prefix_id {
+            encode_varint32_to(&mut buf, id);
+        }
         encode_varint32_to(&mut buf, internal_key_size as u32);
-        buf.extend(key.data());
+        buf.extend(key);
         encode_fixed64_to(&mut buf, (seq << 8) | (type_.value() as u64));
         encode_varint32_to(&mut buf, val_size as u32);
         buf.extend(value.data());
@@ -58,52 +88,338 @@ impl MemTable {
         self.table_.insert(buf);
     }
 
+    /// Add a range tombstone deleting the keys in [begin, end) that were
+    /// written before "seq".
+    pub(crate) fn add_range_tombstone(&self, seq: SequenceNumber, begin: &Slice, end: &Slice) {
+        self.range_tombstones_.add(begin, end, seq);
+    }
+
+    /// The range tombstones added to the memtable.
+    pub(crate) fn range_tombstones(&self) -> &Arc<RangeTombstones> {
+        &self.range_tombstones_
+    }
+
     /// If memtable contains a value for key, store it in *value and return true.
-    /// If memtable contains a deletion for key, store a NotFound() error
-    /// in *status and return true.
+    /// If memtable contains a deletion for key, or a range tombstone
+    /// covering key that is newer than its value, store a NotFound()
+    /// error in *status and return true.
     /// Else, return false.
     pub(crate) fn get(&self, key: &LookupKey) -> (Option<Vec<u8>>, Option<Status>, bool) {
+        let covering = self.range_tombstones_.covering_sequence(key.user_key().data(), key.sequence());
         let memkey = key.memtable_key();
         let mut iter = Iter::new(self.table_.clone());
-        iter.seek(&memkey.data().to_vec_in(self.arena_.clone()));
+        iter.seek(&self.comparator_.seek_target(memkey.data(), &self.arena_));
         if iter.valid() {
             let entry = iter.key();
-            let (next, n) = get_varint32_idx(&entry, 0);
-            if self.comparator_.comparator.user_comparator()
-                    .compare(&Slice::new_with_range(&entry, next as usize, (next as usize) + (n as usize) - 8),
-                            &key.user_key()) == Ordering::Equal {
+            #[cfg(feature = "arena-canaries")]
+            debug_assert!(!is_poisoned(&entry), "memtable entry read from freed memory");
+            let decoded = self.comparator_.decode(&entry);
+            let (user_suffix, tag) = decoded.suffix.split_at(decoded.suffix.len() - 8);
+            if self.comparator_.compare_user_keys([decoded.prefix, user_suffix], [key.user_key().data(), &[]]) == Ordering::Equal {
                 // Correct user key
-                let tag = decode_fixed64_bytes(&entry[((next as usize) + (n as usize) - 8)..((next as usize) + (n as usize))]);
+                let tag = decode_fixed64_bytes(tag);
                 let vt = (tag & 0xff) as u8;
+                if covering.is_some_and(|s| s > tag >> 8) {
+                    return (None, Some(Status::not_found("", "")), true);
+                }
                 if vt == ValueType::type_value().value() {
-                    let v = get_length_prefixed_slice(&entry[((next as usize) + (n as usize))..]);
+                    let v = get_length_prefixed_slice(decoded.rest);
                     return (Some(v.data().to_vec()), None, true);
                 } else if vt == ValueType::type_deletion().value() {
                     return (None, Some(Status::not_found("", "")), true);
                 }
             }
         }
+        if covering.is_some() {
+            // Older memtables and tables only hold entries older than the
+            // tombstone.
+            return (None, Some(Status::not_found("", "")), true);
+        }
         (None, None, false)
     }
 
+    /// Return an iterator that yields the contents of the memtable.
+    ///
+    /// The keys returned by this iterator are internal keys encoded by
+    /// append_internal_key in the dbformat module, with their prefixes
+    /// restored.
+    pub(crate) fn new_iterator(&self) -> Box<dyn Iterator> {
+        Box::new(MemTableIterator {
+            iter_: Iter::new(self.table_.clone()),
+            comparator_: self.comparator_.clone(),
+            arena_: self.arena_.clone(),
+            entry_: None,
+            key_: Vec::new(),
+        })
+    }
+
     /// Returns an estimate of the number of bytes of data in use by this
     /// data structure. It is safe to call when MemTable is being modified.
     pub(crate) fn approximate_memory_usage(&self) -> usize {
-        return self.arena_.memory_usage()
+        self.arena_.memory_usage() + self.range_tombstones_.approximate_memory_usage() +
+            self.comparator_.prefixes.as_ref().map_or(0, |prefixes| prefixes.index_memory_usage())
     }
 }
 
+/// An entry split into its parts.
+struct DecodedEntry<'a> {
+    prefix: &'a [u8],   // Empty if the key has no interned prefix
+    suffix: &'a [u8],   // The rest of the internal key
+    rest: &'a [u8],     // The length-prefixed value
+}
+
 #[derive(Clone)]
 struct KeyComparator {
     comparator: InternalKeyComparator,
+    // Does the user comparator order keys bytewise?  Keys with a prefix
+    // are then compared in two parts instead of being reassembled.
+    bytewise: bool,
+    prefixes: Option<Arc<PrefixTable>>,
+}
+
+impl KeyComparator {
+    fn decode<'a>(&'a self, entry: &'a [u8]) -> DecodedEntry<'a> {
+        let Some(prefixes) = &self.prefixes else {
+            let (next, n) = get_varint32_idx(entry, 0);
+            let end = next as usize + n as usize;
+            return DecodedEntry { prefix: &[], suffix: &entry[next as usize..end], rest: &entry[end..] };
+        };
+        let (start, id) = get_varint32_idx(entry, 0);
+        let (next, n) = get_varint32_idx(entry, start);
+        let end = next as usize + n as usize;
+        DecodedEntry { prefix: prefixes.get(id), suffix: &entry[next as usize..end], rest: &entry[end..] }
+    }
+
+    /// Encode a memtable key (a length-prefixed internal key) as an entry
+    /// to seek to.
+    fn seek_target(&self, memkey: &[u8], arena: &Arena) -> Vec<u8, Arena> {
+        let mut target = Vec::with_capacity_in(memkey.len() + 1, arena.clone());
+        if self.prefixes.is_some() {
+            encode_varint32_to(&mut target, 0);
+        }
+        target.extend(memkey);
+        target
+    }
+
+    /// Compare the user keys made of the concatenated parts.
+    fn compare_user_keys(&self, a: [&[u8]; 2], b: [&[u8]; 2]) -> Ordering {
+        if a[0].is_empty() && b[0].is_empty() {
+            return self.comparator.user_comparator().compare(&Slice::new(a[1]), &Slice::new(b[1]));
+        }
+        if !self.bytewise {
+            return self.comparator.user_comparator().compare(&Slice::new(&[a[0], a[1]].concat()), &Slice::new(&[b[0], b[1]].concat()));
+        }
+        if a[0] == b[0] {
+            // Same prefix, and most often the same interned one
+            return a[1].cmp(b[1]);
+        }
+        compare_parts(a, b)
+    }
 }
 
 impl skiplist::Comparator<Vec<u8, Arena>> for KeyComparator {
     fn compare(&self, left: &Vec<u8, Arena>, right: &Vec<u8, Arena>) -> std::cmp::Ordering {
-        // Internal keys are encoded as length-prefixed strings.
-        let a = get_length_prefixed_slice(left);
-        let b = get_length_prefixed_slice(right);
-        self.comparator.compare(&a, &b)
+        #[cfg(feature = "arena-canaries")]
+        debug_assert!(!is_poisoned(left) && !is_poisoned(right), "memtable key read from freed memory");
+        if self.prefixes.is_none() {
+            // Internal keys are encoded as length-prefixed strings.
+            let a = get_length_prefixed_slice(left);
+            let b = get_length_prefixed_slice(right);
+            return self.comparator.compare(&a, &b);
+        }
+        let (a, b) = (self.decode(left), self.decode(right));
+        let (a_user, a_tag) = a.suffix.split_at(a.suffix.len() - 8);
+        let (b_user, b_tag) = b.suffix.split_at(b.suffix.len() - 8);
+        // Decreasing sequence number and type, as InternalKeyComparator
+        self.compare_user_keys([a.prefix, a_user], [b.prefix, b_user])
+            .then_with(|| decode_fixed64_bytes(b_tag).cmp(&decode_fixed64_bytes(a_tag)))
+    }
+}
+
+/// Compare the byte strings made of the concatenated parts, without
+/// concatenating them.
+fn compare_parts(a: [&[u8]; 2], b: [&[u8]; 2]) -> Ordering {
+    let (mut a_part, mut a_rest) = (a[0], a[1]);
+    let (mut b_part, mut b_rest) = (b[0], b[1]);
+    loop {
+        if a_part.is_empty() {
+            a_part = std::mem::take(&mut a_rest);
+        }
+        if b_part.is_empty() {
+            b_part = std::mem::take(&mut b_rest);
+        }
+        if a_part.is_empty() || b_part.is_empty() {
+            return a_part.len().cmp(&b_part.len());
+        }
+        let n = a_part.len().min(b_part.len());
+        match a_part[..n].cmp(&b_part[..n]) {
+            Ordering::Equal => {},
+            ordering => { return ordering; },
+        }
+        a_part = &a_part[n..];
+        b_part = &b_part[n..];
+    }
+}
+
+/// Number of prefix ids seen once that PrefixTable remembers.  Once
+/// there are more, they are forgotten, so that keys that share no
+/// prefix cost little.
+const MAX_PREFIX_CANDIDATES: usize = 4096;
+
+/// Number of segments of PrefixTable::segments_: enough for every u32 id.
+const PREFIX_SEGMENTS: usize = 32;
+
+/// The slots of one segment of a PrefixTable, each set once.
+type PrefixSegment = Box<[OnceLock<Vec<u8, Arena>>]>;
+
+/// The prefixes interned by a memtable with prefix compression.  Ids
+/// start at 1 and are never reused, and a prefix is never moved, so
+/// comparisons look prefixes up without taking a lock: prefix id n is
+/// slot n - 2^s of segment s = floor(log2(n)), which has 2^s slots.
+struct PrefixTable {
+    prefix_len_: usize,
+    arena_: Arena,
+    segments_: [OnceLock<PrefixSegment>; PREFIX_SEGMENTS],
+    index_: Mutex<PrefixIndex>,
+}
+
+#[derive(Default)]
+struct PrefixIndex {
+    ids_: HashMap<Vec<u8>, u32>,
+    // Hashes of prefixes seen once and not interned yet
+    candidates_: HashSet<u32>,
+}
+
+impl PrefixTable {
+    fn new(prefix_len: usize, arena: Arena) -> Self {
+        Self {
+            prefix_len_: prefix_len,
+            arena_: arena,
+            segments_: std::array::from_fn(|_| OnceLock::new()),
+            index_: Mutex::new(PrefixIndex::default()),
+        }
+    }
+
+    /// Return the id of the prefix of "user_key", or 0 if it has none: if
+    /// it is too short, or if it is the first key seen with its prefix.
+    fn intern(&self, user_key: &[u8]) -> u32 {
+        let Some(prefix) = user_key.get(..self.prefix_len_) else {
+            return 0;
+        };
+        let mut index = self.index_.lock().unwrap();
+        if let Some(&id) = index.ids_.get(prefix) {
+            return id;
+        }
+        if index.candidates_.len() >= MAX_PREFIX_CANDIDATES {
+            index.candidates_.clear();
+        }
+        if index.candidates_.insert(hash(prefix, 0)) || index.ids_.len() >= u32::MAX as usize - 1 {
+            return 0;
+        }
+        let id = index.ids_.len() as u32 + 1;
+        let (segment, slot) = Self::position(id);
+        let slots = self.segments_[segment].get_or_init(|| (0..1usize << segment).map(|_| OnceLock::new()).collect());
+        let mut stored = Vec::with_capacity_in(prefix.len(), self.arena_.clone());
+        stored.extend_from_slice(prefix);
+        assert!(slots[slot].set(stored).is_ok(), "prefix id {} interned twice", id);
+        index.ids_.insert(prefix.to_vec(), id);
+        id
+    }
+
+    /// Return prefix "id", or an empty one for id 0.
+    fn get(&self, id: u32) -> &[u8] {
+        if id == 0 {
+            return &[];
+        }
+        let (segment, slot) = Self::position(id);
+        self.segments_[segment].get().and_then(|slots| slots[slot].get()).expect("unknown prefix id")
+    }
+
+    fn position(id: u32) -> (usize, usize) {
+        let segment = (u32::BITS - 1 - id.leading_zeros()) as usize;
+        (segment, id as usize - (1 << segment))
+    }
+
+    /// Bytes used by the index from prefixes to ids, which is not in the
+    /// arena.  The segments and prefixes are.
+    fn index_memory_usage(&self) -> usize {
+        let index = self.index_.lock().unwrap();
+        index.ids_.capacity() * (size_of::<(Vec<u8>, u32)>() + self.prefix_len_) + index.candidates_.capacity() * size_of::<u32>()
+    }
+}
+
+struct MemTableIterator {
+    iter_: Iter<Vec<u8, Arena>, KeyComparator>,
+    comparator_: KeyComparator,
+    arena_: Arena,
+    // Copy of the entry at the current position; None iff not valid.
+    entry_: Option<Vec<u8, Arena>>,
+    // The internal key of entry_, if it has a prefix to restore.
+    key_: Vec<u8>,
+}
+
+impl MemTableIterator {
+    fn save_entry(&mut self) {
+        self.entry_ = if self.iter_.valid() { Some(self.iter_.key()) } else { None };
+        self.key_.clear();
+        if let Some(entry) = &self.entry_ {
+            let decoded = self.comparator_.decode(entry);
+            if !decoded.prefix.is_empty() {
+                self.key_.extend_from_slice(decoded.prefix);
+                self.key_.extend_from_slice(decoded.suffix);
+            }
+        }
+    }
+
+    fn entry(&self) -> DecodedEntry<'_> {
+        self.comparator_.decode(self.entry_.as_ref().expect("require valid"))
+    }
+}
+
+impl Iterator for MemTableIterator {
+    fn valid(&self) -> bool {
+        self.entry_.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter_.seek_to_first();
+        self.save_entry();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter_.seek_to_last();
+        self.save_entry();
+    }
+
+    fn seek(&mut self, k: &Slice) {
+        // Encode a suitable internal key target for "k".
+        let mut memkey = encode_varint32(k.size() as u32);
+        memkey.extend(k.data());
+        let target = self.comparator_.seek_target(&memkey, &self.arena_);
+        self.iter_.seek(&target);
+        self.save_entry();
+    }
+
+    fn next(&mut self) {
+        self.iter_.next();
+        self.save_entry();
+    }
+
+    fn prev(&mut self) {
+        self.iter_.prev();
+        self.save_entry();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        if self.key_.is_empty() { Slice::new(self.entry().suffix) } else { Slice::new(&self.key_) }
+    }
+
+    fn value(&self) -> Slice<'_> {
+        get_length_prefixed_slice(self.entry().rest)
+    }
+
+    fn status(&self) -> Status {
+        Status::new_ok()
     }
 }
 
@@ -111,3 +427,141 @@ fn get_length_prefixed_slice(data: &[u8]) -> Slice {
     let (next, n) = get_varint32_idx(data, 0);
     Slice::new_with_range(&data, next as usize, next as usize + n as usize)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{comparator::bytewise_comparator, util::random::Random};
+
+    use super::*;
+
+    fn add(mem: &MemTable, seq: SequenceNumber, key: &[u8], value: &[u8]) {
+        mem.add(seq, ValueType::type_value(), &Slice::new(key), &Slice::new(value));
+    }
+
+    fn contents(mem: &MemTable) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut iter = mem.new_iterator();
+        let mut result = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            result.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+            iter.next();
+        }
+        result
+    }
+
+    fn get(mem: &MemTable, key: &[u8], seq: SequenceNumber) -> Option<Vec<u8>> {
+        match mem.get(&LookupKey::new(&Slice::new(key), seq)) {
+            (value, _, true) => value,
+            _ => None,
+        }
+    }
+
+    /// Fill a plain memtable and one with prefix compression with the
+    /// keys "key(i)" for i in [0,n), in random order, and check that
+    /// they read the same.  Returns their memory usage.
+    fn compare_memtables(icmp: &InternalKeyComparator, n: usize, key: impl Fn(usize) -> Vec<u8>) -> (usize, usize) {
+        let plain = MemTable::new(icmp);
+        let compressed = MemTable::with_prefix_compression(icmp, 40);
+        let mut order: Vec<usize> = (0..n).collect();
+        let mut rnd = Random::new(301);
+        for i in (1..n).rev() {
+            order.swap(i, rnd.uniform(i as i32 + 1) as usize);
+        }
+        for (seq, &i) in order.iter().enumerate() {
+            let value = (i as u64).to_le_bytes();
+            add(&plain, seq as SequenceNumber + 1, &key(i), &value);
+            add(&compressed, seq as SequenceNumber + 1, &key(i), &value);
+        }
+
+        let expected = contents(&plain);
+        assert_eq!(n, expected.len());
+        assert!(expected == contents(&compressed));
+        for i in (0..n).step_by(97) {
+            assert_eq!(Some((i as u64).to_le_bytes().to_vec()), get(&compressed, &key(i), n as SequenceNumber));
+            let mut missing = key(i);
+            missing.push(0);
+            assert_eq!(None, get(&compressed, &missing, n as SequenceNumber));
+        }
+        let mut iter = compressed.new_iterator();
+        let (first, _) = &expected[n / 2];
+        iter.seek(&Slice::new(first));
+        assert_eq!(first.as_slice(), iter.key().data());
+        iter.prev();
+        assert_eq!(expected[n / 2 - 1].0.as_slice(), iter.key().data());
+        (plain.approximate_memory_usage(), compressed.approximate_memory_usage())
+    }
+
+    #[test]
+    fn prefix_compression_test() {
+        // 1M keys under 100 prefixes of 40 bytes
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let (plain, compressed) = compare_memtables(&icmp, 1_000_000, |i| format!("https://tenant-{:02}.example.com/objects/{:08}", i % 100, i).into_bytes());
+        assert_eq!(40, "https://tenant-00.example.com/objects/00".len());
+        // Skiplist nodes keep their size, so the saving on each ~210 byte
+        // entry is the 38 bytes of prefix less the id.
+        assert!(compressed * 100 < plain * 85, "{} {}", plain, compressed);
+    }
+
+    #[test]
+    fn prefix_compression_unshared_test() {
+        // Keys that share no prefix are stored as they are, with a byte
+        // for the prefix id.
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let n = 100_000;
+        let (plain, compressed) = compare_memtables(&icmp, n, |i| format!("{:040}/{}", (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15), i).into_bytes());
+        assert!(compressed <= plain + n + 64 * 1024, "{} {}", plain, compressed);
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn name(&self) -> &'static str { "rucksdb.ReverseComparator" }
+        fn compare(&self, a: &Slice, b: &Slice) -> Ordering { b.data().cmp(a.data()) }
+        fn find_shortest_separator(&self, _start: &mut Vec<u8>, _limit: &Slice) {}
+        fn find_short_successor(&self, _key: &mut Vec<u8>) {}
+    }
+
+    #[test]
+    fn prefix_compression_comparator_test() {
+        // Other comparators see whole keys, including short ones
+        let icmp = InternalKeyComparator::new(Arc::new(ReverseComparator));
+        compare_memtables(&icmp, 10_000, |i| match i % 3 {
+            0 => format!("{:038}", i).into_bytes(),
+            _ => format!("{:040}{}", i % 7, i).into_bytes(),
+        });
+    }
+
+    #[test]
+    fn compare_parts_test() {
+        type Parts<'a> = [&'a [u8]; 2];
+        let cases: [(Parts, Parts, Ordering); 7] = [
+            ([b"ab", b"c"], [b"a", b"bc"], Ordering::Equal),
+            ([b"", b""], [b"", b""], Ordering::Equal),
+            ([b"ab", b""], [b"a", b"bc"], Ordering::Less),
+            ([b"abd", b""], [b"ab", b"c"], Ordering::Greater),
+            ([b"", b"b"], [b"a", b"z"], Ordering::Greater),
+            ([b"a", b"b"], [b"", b"ab\x00"], Ordering::Less),
+            ([b"abc", b"d"], [b"abc", b"e"], Ordering::Less),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(expected, compare_parts(a, b), "{:?} {:?}", a, b);
+            assert_eq!(expected.reverse(), compare_parts(b, a), "{:?} {:?}", b, a);
+            assert_eq!([a[0], a[1]].concat().cmp(&[b[0], b[1]].concat()), expected);
+        }
+    }
+
+    #[test]
+    fn prefix_table_test() {
+        let table = PrefixTable::new(3, Arena::new());
+        assert_eq!(0, table.intern(b"ab"));
+        assert_eq!(0, table.intern(b"abc"));    // Seen once
+        assert_eq!(1, table.intern(b"abcd"));
+        assert_eq!(1, table.intern(b"abc"));
+        assert_eq!(0, table.intern(b"xyz1"));
+        assert_eq!(2, table.intern(b"xyz2"));
+        assert_eq!((b"abc".as_slice(), b"xyz".as_slice(), b"".as_slice()), (table.get(1), table.get(2), table.get(0)));
+        for (id, segment, slot) in [(1, 0, 0), (2, 1, 0), (3, 1, 1), (4, 2, 0), (7, 2, 3), (8, 3, 0), (u32::MAX, 31, (1 << 31) - 1)] {
+            assert_eq!((segment, slot), PrefixTable::position(id), "{}", id);
+        }
+    }
+}
@@ -0,0 +1,480 @@
+//! Rewriting a DB into a new one that orders its keys by another
+//! comparator.  See migrate_comparator().
+//!
+//! The entries of the source are read in its own order, at a snapshot,
+//! with checksums verified.
+//! If the caller maps them to destination keys that come out in the
+//! destination's order, they are streamed into table files that are
+//! ingested whole; otherwise, or if the mapped keys turn out not to be in
+//! order after all, they are written to the destination in batches.
+//! Either way the destination is checked against the source afterwards:
+//! it must hold as many keys, and a sample of them must have the source's
+//! values.
+
+use std::cmp::Ordering;
+
+use crate::{env::log, iterator::Iterator, options::{Options, ReadOptions, WriteOptions}, slice::Slice, status::Status, util::redact::redact, write_batch::WriteBatch};
+
+use super::{sst_file_writer::SstFileWriter, DB};
+
+// Size of the batches written to the destination when not streaming.
+const BATCH_BYTES: usize = 1 << 20;
+
+/// Maps a key of the source DB to its key in the destination.
+pub type KeyTransform = Box<dyn Fn(&[u8]) -> Vec<u8>>;
+
+/// How migrate_comparator_with() migrates.
+pub struct MigrateOptions {
+    /// Maps each source key to its destination key.  Giving one says that
+    /// the mapped keys come out in the destination's order when the
+    /// source is read in its own, which lets the entries be streamed into
+    /// table files; if they do not, the migration falls back to writing
+    /// batches.  Without one the keys are kept as they are.
+    /// Default: None
+    pub key_transform: Option<KeyTransform>,
+
+    /// Migrate what can be read if the source turns out to be damaged,
+    /// instead of failing.  The damage is noted in the report.
+    /// Default: false
+    pub best_effort: bool,
+
+    /// How many of the migrated entries to look up in the destination,
+    /// spread evenly over the source.
+    /// Default: 100
+    pub verify_samples: usize,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        Self { key_transform: None, best_effort: false, verify_samples: 100 }
+    }
+}
+
+/// What migrate_comparator_with() did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Entries read from the source and written to the destination.
+    pub entries: u64,
+
+    /// Bytes of keys and values written to the destination.
+    pub bytes: u64,
+
+    /// Whether the entries were streamed into table files rather than
+    /// written in batches.
+    pub streamed: bool,
+
+    /// Entries of the destination looked up and found to match.
+    pub samples_verified: u64,
+
+    /// With best_effort, the damage found in the source, if any.  The
+    /// entries in the damaged blocks were skipped.
+    pub corruption: Option<String>,
+
+    /// How long the migration took.
+    pub duration_micros: u64,
+}
+
+/// Rewrite the DB at "src_path", opened read-only with "src_options" and
+/// so with its original comparator, into a new DB at "dst_path" that uses the
+/// comparator of "dst_options".  The keys and values are kept as they are.
+/// See migrate_comparator_with().
+pub fn migrate_comparator(src_path: &str, src_options: &Options, dst_path: &str, dst_options: &Options) -> Status {
+    match migrate_comparator_with(src_path, src_options, dst_path, dst_options, &MigrateOptions::default()) {
+        Ok(_) => Status::new_ok(),
+        Err(s) => s,
+    }
+}
+
+/// Rewrite the DB at "src_path" into a new DB at "dst_path" as
+/// "migrate_options" say, and return a report of the migration, which is
+/// also logged to the info_log of "dst_options".  The source should not
+/// be written to meanwhile; only what it held at the start is migrated.
+///
+/// Fails with InvalidArgument if the destination already holds data, and
+/// with Corruption if it does not match the source afterwards (e.g.
+/// because the key transform mapped two keys to one).
+pub fn migrate_comparator_with(src_path: &str, src_options: &Options, dst_path: &str, dst_options: &Options,
+                               migrate_options: &MigrateOptions) -> Result<MigrationReport, Status> {
+    let start_micros = dst_options.env.now_micros();
+    let src = DB::open_read_only(src_options, src_path)?;
+    let dst = DB::open(dst_options, dst_path)?;
+    let mut iter = dst.new_iterator(&ReadOptions::new());
+    iter.seek_to_first();
+    if iter.valid() {
+        return Err(Status::invalid_argument(dst_path, "is not empty"));
+    }
+    drop(iter);
+
+    let snapshot = src.get_snapshot();
+    let read_options = ReadOptions { snapshot: Some(snapshot.clone()), verify_checksums: true, fill_cache: false, ..ReadOptions::new() };
+    let migration = Migration { src: &src, dst: &dst, dst_path, dst_options, migrate_options, read_options };
+    let mut report = MigrationReport::default();
+    let mut s = Status::new_ok();
+    if migrate_options.key_transform.is_some() {
+        s = migration.stream(&mut report);
+        if s.is_invalid_argument() {
+            log(dst_options.info_log.clone(), &format!("Migration of {}: transformed keys are out of order ({}), writing batches instead",
+                                                       src_path, s.to_string()));
+            report = MigrationReport::default();
+            s = Status::new_ok();
+        } else if s.ok() {
+            report.streamed = true;
+        }
+    }
+    if s.ok() && !report.streamed {
+        s = migration.write_batches(&mut report);
+    }
+    if s.ok() {
+        s = dst.flush();
+    }
+    if s.ok() {
+        s = migration.verify(&mut report);
+    }
+    src.release_snapshot(snapshot);
+    if !s.ok() {
+        return Err(s);
+    }
+
+    report.duration_micros = dst_options.env.now_micros().saturating_sub(start_micros);
+    log(dst_options.info_log.clone(), &format!(
+        "Migrated {} to {}: {} entries, {} bytes, {}, {} samples verified, {} micros{}",
+        src_path, dst_path, report.entries, report.bytes, if report.streamed { "streamed" } else { "batched" },
+        report.samples_verified, report.duration_micros,
+        report.corruption.as_ref().map(|c| format!(", skipped damaged entries: {}", c)).unwrap_or_default()));
+    Ok(report)
+}
+
+struct Migration<'a> {
+    src: &'a DB,
+    dst: &'a DB,
+    dst_path: &'a str,
+    dst_options: &'a Options,
+    migrate_options: &'a MigrateOptions,
+    read_options: ReadOptions,
+}
+
+impl Migration<'_> {
+    fn dst_key(&self, key: &[u8]) -> Vec<u8> {
+        match &self.migrate_options.key_transform {
+            Some(transform) => transform(key),
+            None => key.to_vec(),
+        }
+    }
+
+    /// Check how the scan of the source ended.  Damage is noted in
+    /// "report" if best_effort allows it.
+    fn finish_scan(&self, iter: &dyn Iterator, report: &mut MigrationReport) -> Status {
+        let s = iter.status();
+        if s.is_corruption() && self.migrate_options.best_effort {
+            report.corruption = Some(s.to_string());
+            return Status::new_ok();
+        }
+        s
+    }
+
+    /// Stream the entries into table files of up to max_file_size bytes,
+    /// and ingest them.  Fails with InvalidArgument, having left the
+    /// destination as it was, if the transformed keys are out of order.
+    fn stream(&self, report: &mut MigrationReport) -> Status {
+        let mut paths: Vec<String> = Vec::new();
+        let s = self.write_tables(&mut paths, report);
+        let s = if s.ok() && !paths.is_empty() {
+            let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+            self.dst.ingest_external_file(&paths, true)
+        } else {
+            s
+        };
+        // Moved into the destination if the ingest went through
+        for path in &paths {
+            if self.dst_options.env.file_exists(path) {
+                self.dst_options.env.remove_file(path);
+            }
+        }
+        s
+    }
+
+    fn write_tables(&self, paths: &mut Vec<String>, report: &mut MigrationReport) -> Status {
+        let cmp = self.dst_options.comparator.clone();
+        let mut iter = self.src.new_iterator(&self.read_options);
+        iter.seek_to_first();
+        let mut writer: Option<SstFileWriter> = None;
+        let mut file_bytes = 0;
+        let mut last_key: Option<Vec<u8>> = None;
+        while iter.valid() {
+            // Checked here rather than left to SstFileWriter, as the files
+            // must not overlap each other either.
+            let key = self.dst_key(iter.key().data());
+            if last_key.as_ref().is_some_and(|last| cmp.compare(&Slice::new(&key), &Slice::new(last)) != Ordering::Greater) {
+                return Status::invalid_argument("transformed keys must be in strictly increasing order",
+                                                &redact(&key, self.dst_options.diagnostics_redaction));
+            }
+            if writer.is_none() {
+                let path = format!("{}/migrate-{:06}.sst", self.dst_path, paths.len());
+                match SstFileWriter::create(self.dst_options, &path) {
+                    Ok(w) => writer = Some(w),
+                    Err(s) => { return s; },
+                }
+                paths.push(path);
+                file_bytes = 0;
+            }
+            let value = iter.value();
+            let s = writer.as_mut().unwrap().put(&Slice::new(&key), &value);
+            if !s.ok() {
+                return s;
+            }
+            report.entries += 1;
+            report.bytes += (key.len() + value.size()) as u64;
+            file_bytes += key.len() + value.size();
+            if file_bytes >= self.dst_options.max_file_size {
+                let s = writer.take().unwrap().finish();
+                if !s.ok() {
+                    return s;
+                }
+            }
+            last_key = Some(key);
+            iter.next();
+        }
+        if let Some(mut writer) = writer {
+            let s = writer.finish();
+            if !s.ok() {
+                return s;
+            }
+        }
+        self.finish_scan(iter.as_ref(), report)
+    }
+
+    /// Write the entries to the destination in batches, flushing its
+    /// memtable each time a write buffer's worth has been written.
+    fn write_batches(&self, report: &mut MigrationReport) -> Status {
+        let mut iter = self.src.new_iterator(&self.read_options);
+        iter.seek_to_first();
+        let mut batch = WriteBatch::new();
+        let mut unflushed = 0;
+        while iter.valid() {
+            let key = self.dst_key(iter.key().data());
+            let value = iter.value();
+            batch.put(&Slice::new(&key), &value);
+            report.entries += 1;
+            report.bytes += (key.len() + value.size()) as u64;
+            iter.next();
+            if batch.approximate_size() >= BATCH_BYTES || !iter.valid() {
+                unflushed += batch.approximate_size();
+                let s = self.dst.write(&WriteOptions::default(), std::mem::take(&mut batch));
+                if !s.ok() {
+                    return s;
+                }
+                if unflushed >= self.dst_options.write_buffer_size {
+                    let s = self.dst.flush();
+                    if !s.ok() {
+                        return s;
+                    }
+                    unflushed = 0;
+                }
+            }
+        }
+        self.finish_scan(iter.as_ref(), report)
+    }
+
+    /// Check that the destination holds as many keys as were migrated,
+    /// and look up verify_samples of them.
+    fn verify(&self, report: &mut MigrationReport) -> Status {
+        let mut iter = self.dst.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        let mut keys = 0;
+        while iter.valid() {
+            keys += 1;
+            iter.next();
+        }
+        let s = iter.status();
+        if !s.ok() {
+            return s;
+        }
+        if keys != report.entries {
+            return Status::corruption("migrated DB has the wrong number of keys",
+                                      &format!("{} instead of {}", keys, report.entries));
+        }
+
+        let samples = self.migrate_options.verify_samples as u64;
+        if samples == 0 || report.entries == 0 {
+            return Status::new_ok();
+        }
+        let step = report.entries.div_ceil(samples);
+        let mut iter = self.src.new_iterator(&self.read_options);
+        iter.seek_to_first();
+        let mut i = 0;
+        while iter.valid() {
+            if i % step == 0 {
+                let key = self.dst_key(iter.key().data());
+                match self.dst.get(&ReadOptions::new(), &Slice::new(&key)) {
+                    Ok(value) if value == iter.value().data() => { report.samples_verified += 1; },
+                    Ok(_) => { return Status::corruption("migrated DB has a different value for", &redact(&key, self.dst_options.diagnostics_redaction)); },
+                    Err(s) => { return s; },
+                }
+            }
+            i += 1;
+            iter.next();
+        }
+        Status::new_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{comparator::Comparator, db::dbformat::NUM_LEVELS, env::{Env, Logger}, helpers::memenv::new_mem_env};
+
+    use super::*;
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn name(&self) -> &'static str { "test.ReverseComparator" }
+        fn compare(&self, a: &Slice, b: &Slice) -> Ordering { b.data().cmp(a.data()) }
+        fn find_shortest_separator(&self, _start: &mut Vec<u8>, _limit: &Slice) {}
+        fn find_short_successor(&self, _key: &mut Vec<u8>) {}
+    }
+
+    struct CaptureLogger {
+        messages_: Mutex<Vec<String>>,
+    }
+
+    impl Logger for CaptureLogger {
+        fn logv(&self, msg: &str) {
+            self.messages_.lock().unwrap().push(msg.to_string());
+        }
+    }
+
+    fn options_with_env(env: Arc<dyn Env>, reverse: bool) -> Options {
+        let mut options = Options::new();
+        options.env = env;
+        options.create_if_missing = true;
+        options.write_buffer_size = 16 * 1024;
+        options.max_file_size = 8 * 1024;
+        if reverse {
+            options.comparator = std::sync::Arc::new(ReverseComparator);
+        }
+        options
+    }
+
+    fn full_scan(options: &Options, name: &str) -> Vec<(String, String)> {
+        let db = DB::open(options, name).unwrap();
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        let mut entries = Vec::new();
+        while iter.valid() {
+            entries.push((iter.key().to_utf8_string().unwrap(), iter.value().to_utf8_string().unwrap()));
+            iter.next();
+        }
+        entries
+    }
+
+    #[test]
+    fn migrate_comparator_test() {
+        let env = new_mem_env();
+        let (bytewise, reverse) = (options_with_env(env.clone(), false), options_with_env(env.clone(), true));
+        {
+            let db = DB::open(&bytewise, "/src").unwrap();
+            for i in 0..1000 {
+                let value = format!("value{}", i);
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:04}", i).as_bytes()), &Slice::new(value.as_bytes())).ok());
+            }
+            assert!(db.delete(&WriteOptions::default(), &Slice::new(b"key0007")).ok());
+        }
+        let original = full_scan(&bytewise, "/src");
+        assert_eq!(999, original.len());
+
+        // Over to the reverse order, and back.  The source is only read.
+        let files = env.get_children("/src").unwrap();
+        assert!(migrate_comparator("/src", &bytewise, "/reversed", &reverse).ok());
+        assert_eq!(files, env.get_children("/src").unwrap());
+        let mut reversed = full_scan(&reverse, "/reversed");
+        reversed.reverse();
+        assert_eq!(original, reversed);
+        assert!(migrate_comparator("/reversed", &reverse, "/back", &bytewise).ok());
+        assert_eq!(original, full_scan(&bytewise, "/back"));
+
+        // The existing comparator name check keeps each DB to its order
+        let s = DB::open(&bytewise, "/reversed").err().unwrap();
+        assert!(s.is_invalid_argument() && s.to_string().contains("does not match existing comparator"), "{}", s.to_string());
+        assert!(DB::open(&reverse, "/back").err().unwrap().is_invalid_argument());
+
+        // Only into an empty DB
+        assert!(migrate_comparator("/src", &bytewise, "/back", &bytewise).is_invalid_argument());
+    }
+
+    #[test]
+    fn migrate_comparator_streamed_test() {
+        let env = new_mem_env();
+        let logger = Arc::new(CaptureLogger { messages_: Mutex::new(Vec::new()) });
+        let bytewise = options_with_env(env.clone(), false);
+        let reverse = Options { info_log: Some(logger.clone()), ..options_with_env(env.clone(), true) };
+        {
+            let db = DB::open(&bytewise, "/src").unwrap();
+            for i in 0..1000 {
+                let value = format!("value{}", i);
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("{:04}", i).as_bytes()), &Slice::new(value.as_bytes())).ok());
+            }
+        }
+
+        // Complementing the digits turns the ascending keys into ones
+        // that are ascending in the reverse order, so they are streamed.
+        let complement = |key: &[u8]| key.iter().map(|&b| b'0' + b'9' - b).collect::<Vec<u8>>();
+        let migrate_options = MigrateOptions { key_transform: Some(Box::new(complement)), verify_samples: 10, ..MigrateOptions::default() };
+        let report = migrate_comparator_with("/src", &bytewise, "/streamed", &reverse, &migrate_options).unwrap();
+        assert!(report.streamed);
+        assert_eq!((1000, 10, None), (report.entries, report.samples_verified, report.corruption.clone()));
+        assert!(logger.messages_.lock().unwrap().iter().any(|m| m.starts_with("Migrated /src to /streamed: 1000 entries")));
+        let streamed = full_scan(&reverse, "/streamed");
+        assert_eq!(("9999".to_string(), "value0".to_string()), streamed[0]);
+        assert_eq!(("9000".to_string(), "value999".to_string()), streamed[999]);
+        {
+            let db = DB::open(&reverse, "/streamed").unwrap();
+            // Ingested as several files of up to max_file_size bytes
+            assert!((0..NUM_LEVELS).map(|level| db.mutex_.lock().unwrap().versions_.num_level_files(level)).sum::<usize>() > 1);
+        }
+
+        // Keys that are not in the destination order are written in
+        // batches instead.
+        let migrate_options = MigrateOptions { key_transform: Some(Box::new(|key: &[u8]| key.to_vec())), ..MigrateOptions::default() };
+        let report = migrate_comparator_with("/src", &bytewise, "/batched", &reverse, &migrate_options).unwrap();
+        assert!(!report.streamed);
+        assert_eq!(1000, report.entries);
+        assert!(env.get_children("/batched").unwrap().iter().all(|f| !f.starts_with("migrate-")));
+        assert_eq!(full_scan(&bytewise, "/src").into_iter().rev().collect::<Vec<_>>(), full_scan(&reverse, "/batched"));
+
+        // Two keys mapped to one leave the destination short
+        let migrate_options = MigrateOptions { key_transform: Some(Box::new(|key: &[u8]| key[..3].to_vec())), ..MigrateOptions::default() };
+        let s = migrate_comparator_with("/src", &bytewise, "/collided", &bytewise, &migrate_options).unwrap_err();
+        assert!(s.is_corruption() && s.to_string().contains("wrong number of keys"), "{}", s.to_string());
+    }
+
+    #[test]
+    fn migrate_comparator_best_effort_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone(), false);
+        {
+            let db = DB::open(&options, "/src").unwrap();
+            for i in 0..1000 {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{:04}", i).as_bytes()), &Slice::new(&[b'v'; 100])).ok());
+            }
+            assert!(db.compact_range(None, None).ok());
+        }
+
+        // Damage a data block in the middle of the first table
+        let table = env.get_children("/src").unwrap().into_iter().filter(|f| f.ends_with(".ldb")).min().unwrap();
+        let fname = format!("/src/{}", table);
+        let mut contents = env.new_sequential_file(&fname).unwrap().read(usize::MAX).unwrap();
+        contents[2000] ^= 0x55;
+        assert!(crate::util::env::write_string_to_file_sync(env.clone(), &Slice::new(&contents), &fname).ok());
+
+        let reverse = options_with_env(env.clone(), true);
+        let s = migrate_comparator("/src", &options, "/strict", &reverse);
+        assert!(s.is_corruption(), "{}", s.to_string());
+        let migrate_options = MigrateOptions { best_effort: true, ..MigrateOptions::default() };
+        let report = migrate_comparator_with("/src", &options, "/lenient", &reverse, &migrate_options).unwrap();
+        assert!(report.corruption.is_some());
+        assert!(report.entries > 0 && report.entries < 1000, "{}", report.entries);
+        assert_eq!(report.entries as usize, full_scan(&reverse, "/lenient").len());
+    }
+}
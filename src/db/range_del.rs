@@ -0,0 +1,218 @@
+//! Range deletions (see WriteBatch::delete_range) in memtables.
+//!
+//! A memtable keeps the range tombstones written to it in a
+//! RangeTombstones list next to its entries.  Lookups in the memtable and
+//! DB iterators hide an entry if a tombstone covers its key, is newer
+//! than the entry, and is not newer than the read.
+//!
+//! Tombstones do not go on into tables.  When a memtable is written out,
+//! each of its tombstones is expanded into a point deletion, at the
+//! tombstone's sequence number, of every key the tombstone covers in the
+//! memtable or in the older data (see new_flush_iterator()).  Tables and
+//! compactions therefore only ever see ordinary deletions, which drop the
+//! entries they hide and are themselves dropped at the bottom level as
+//! usual.  The price is a scan of the covered keys when the memtable is
+//! written out, and a deletion per key in the new table.
+
+use std::{cmp::Ordering, sync::{Arc, Mutex, RwLock}};
+
+use crate::{comparator::Comparator, iterator::{new_error_iterator, Iterator}, slice::Slice, status::Status, table::merger::new_internal_merging_iterator};
+
+use super::{dbformat::{parse_internal_key, InternalKey, InternalKeyComparator, ValueType, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK}, memtable::MemTable, version_edit::SequenceNumber};
+
+/// Deletion of the user keys in [begin, end) written before "sequence".
+pub(crate) struct RangeTombstone {
+    pub(crate) begin: Vec<u8>,
+    pub(crate) end: Vec<u8>,
+    pub(crate) sequence: SequenceNumber,
+}
+
+/// A piece of the key space that the same tombstones cover.
+struct Fragment {
+    begin: Vec<u8>,
+    end: Vec<u8>,
+    sequences: Vec<SequenceNumber>,     // Of those tombstones, newest first
+}
+
+pub(crate) struct RangeTombstones {
+    comparator_: Arc<dyn Comparator>,
+    tombstones_: RwLock<Vec<RangeTombstone>>,
+
+    // tombstones_ cut at every begin and end key into disjoint fragments,
+    // ordered by key.  Built by the first lookup after an add().  Locked
+    // before tombstones_ when both are.
+    fragments_: Mutex<Option<Arc<Vec<Fragment>>>>,
+}
+
+impl RangeTombstones {
+    pub(crate) fn new(comparator: Arc<dyn Comparator>) -> Self {
+        Self { comparator_: comparator, tombstones_: RwLock::new(Vec::new()), fragments_: Mutex::new(None) }
+    }
+
+    /// Add a tombstone for [begin, end) at "sequence".  An empty range
+    /// covers nothing and is not kept.
+    pub(crate) fn add(&self, begin: &Slice, end: &Slice, sequence: SequenceNumber) {
+        if self.comparator_.compare(begin, end) != Ordering::Less {
+            return;
+        }
+        self.tombstones_.write().unwrap().push(RangeTombstone { begin: begin.data().to_vec(), end: end.data().to_vec(), sequence });
+        *self.fragments_.lock().unwrap() = None;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tombstones_.read().unwrap().is_empty()
+    }
+
+    /// Returns the sequence number of the newest tombstone that covers
+    /// "key" and is not newer than "snapshot", if any.
+    pub(crate) fn covering_sequence(&self, key: &[u8], snapshot: SequenceNumber) -> Option<SequenceNumber> {
+        if self.is_empty() {
+            return None;
+        }
+        let fragments = self.fragments();
+        // Fragments in [0, idx) start at or before key; only the last of
+        // them can contain it.
+        let idx = fragments.partition_point(|f| self.compare(&f.begin, key) != Ordering::Greater);
+        let fragment = idx.checked_sub(1).map(|i| &fragments[i])?;
+        if self.compare(key, &fragment.end) != Ordering::Less {
+            return None;
+        }
+        fragment.sequences.iter().copied().find(|&s| s <= snapshot)
+    }
+
+    /// Returns the [begin, end) ranges of the tombstones not newer than
+    /// "snapshot", in the order they were added.
+    pub(crate) fn visible(&self, snapshot: SequenceNumber) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.tombstones_.read().unwrap().iter()
+            .filter(|t| t.sequence <= snapshot)
+            .map(|t| (t.begin.clone(), t.end.clone()))
+            .collect()
+    }
+
+    /// Returns true iff a tombstone covers a user key in
+    /// [*smallest,*largest], where None means unbounded.
+    pub(crate) fn overlaps(&self, smallest: Option<&[u8]>, largest: Option<&[u8]>) -> bool {
+        self.tombstones_.read().unwrap().iter().any(|t| {
+            largest.is_none_or(|k| self.compare(&t.begin, k) != Ordering::Greater)
+                && smallest.is_none_or(|k| self.compare(&t.end, k) == Ordering::Greater)
+        })
+    }
+
+    /// Returns an estimate of the memory the tombstones take.
+    pub(crate) fn approximate_memory_usage(&self) -> usize {
+        self.tombstones_.read().unwrap().iter().map(|t| t.begin.len() + t.end.len() + std::mem::size_of::<RangeTombstone>()).sum()
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.comparator_.compare(&Slice::new(a), &Slice::new(b))
+    }
+
+    fn fragments(&self) -> Arc<Vec<Fragment>> {
+        self.fragments_.lock().unwrap().get_or_insert_with(|| Arc::new(self.fragment())).clone()
+    }
+
+    /// Cut the tombstones into fragments.  Quadratic in the number of
+    /// tombstones, which range deletions are expected to keep small.
+    fn fragment(&self) -> Vec<Fragment> {
+        let tombstones = self.tombstones_.read().unwrap();
+        let mut points: Vec<&[u8]> = tombstones.iter().flat_map(|t| [&t.begin[..], &t.end[..]]).collect();
+        points.sort_by(|a, b| self.compare(a, b));
+        points.dedup_by(|a, b| self.compare(a, b) == Ordering::Equal);
+        points.windows(2).filter_map(|w| {
+            let mut sequences: Vec<SequenceNumber> = tombstones.iter()
+                .filter(|t| self.compare(&t.begin, w[0]) != Ordering::Greater && self.compare(&t.end, w[1]) != Ordering::Less)
+                .map(|t| t.sequence)
+                .collect();
+            if sequences.is_empty() {
+                return None;
+            }
+            sequences.sort_unstable_by(|a, b| b.cmp(a));
+            Some(Fragment { begin: w[0].to_vec(), end: w[1].to_vec(), sequences })
+        }).collect()
+    }
+}
+
+/// Return an iterator over what to write to a table for "mem": its
+/// entries, plus a point deletion at the sequence number of each of its
+/// range tombstones for every key that the tombstone covers in "mem" or
+/// in "older", and that has an entry older than the tombstone there.
+/// "older" returns iterators over the internal keys of the data "mem"
+/// was written on top of; it is only called if "mem" has tombstones.
+pub(crate) fn new_flush_iterator(icmp: &InternalKeyComparator, mem: &MemTable,
+                                 older: impl FnOnce() -> Vec<Box<dyn Iterator>>) -> Box<dyn Iterator> {
+    let tombstones = mem.range_tombstones();
+    if tombstones.is_empty() {
+        return mem.new_iterator();
+    }
+
+    let deletions = MemTable::new(icmp);
+    let mut sources = older();
+    sources.push(mem.new_iterator());
+    let mut iter = new_internal_merging_iterator(icmp, sources);
+    let ucmp = icmp.user_comparator();
+    for t in tombstones.tombstones_.read().unwrap().iter() {
+        iter.seek(&InternalKey::new_from(&Slice::new(&t.begin), MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK).encode());
+        let mut deleted: Option<Vec<u8>> = None;
+        while iter.valid() {
+            let key = iter.key();
+            let Some(ikey) = parse_internal_key(&key) else {
+                return new_error_iterator(Status::corruption("corrupted internal key while expanding a range deletion", ""));
+            };
+            if ucmp.compare(&ikey.user_key, &Slice::new(&t.end)) != Ordering::Less {
+                break;
+            }
+            if ikey.sequence < t.sequence && deleted.as_deref() != Some(ikey.user_key.data()) {
+                deletions.add(t.sequence, ValueType::type_deletion(), &ikey.user_key, &Slice::new(b""));
+                deleted = Some(ikey.user_key.data().to_vec());
+            }
+            iter.next();
+        }
+    }
+    let s = iter.status();
+    if !s.ok() {
+        return new_error_iterator(s);
+    }
+    new_internal_merging_iterator(icmp, vec![mem.new_iterator(), deletions.new_iterator()])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::bytewise_comparator;
+
+    use super::*;
+
+    #[test]
+    fn covering_sequence_test() {
+        let tombstones = RangeTombstones::new(bytewise_comparator());
+        assert!(tombstones.is_empty());
+        assert_eq!(None, tombstones.covering_sequence(b"a", 100));
+
+        tombstones.add(&Slice::new(b"c"), &Slice::new(b"f"), 10);
+        tombstones.add(&Slice::new(b"e"), &Slice::new(b"h"), 20);
+        tombstones.add(&Slice::new(b"d"), &Slice::new(b"d"), 30);     // Empty
+        tombstones.add(&Slice::new(b"x"), &Slice::new(b"w"), 40);     // Empty
+        assert!(!tombstones.is_empty());
+
+        assert_eq!(None, tombstones.covering_sequence(b"b", 100));
+        assert_eq!(Some(10), tombstones.covering_sequence(b"c", 100));
+        assert_eq!(Some(10), tombstones.covering_sequence(b"d", 100));
+        assert_eq!(Some(20), tombstones.covering_sequence(b"e", 100));
+        assert_eq!(Some(10), tombstones.covering_sequence(b"e", 19));
+        assert_eq!(None, tombstones.covering_sequence(b"e", 9));
+        assert_eq!(Some(20), tombstones.covering_sequence(b"f", 100));     // end is exclusive
+        assert_eq!(None, tombstones.covering_sequence(b"f", 19));
+        assert_eq!(Some(20), tombstones.covering_sequence(b"gzz", 100));
+        assert_eq!(None, tombstones.covering_sequence(b"h", 100));
+        assert_eq!(None, tombstones.covering_sequence(b"w", 100));
+
+        // Lookups after an add see it
+        tombstones.add(&Slice::new(b"a"), &Slice::new(b"z"), 50);
+        assert_eq!(Some(50), tombstones.covering_sequence(b"b", 100));
+        assert_eq!(Some(20), tombstones.covering_sequence(b"e", 49));
+
+        assert!(tombstones.overlaps(Some(b"0"), Some(b"a")));
+        assert!(!tombstones.overlaps(Some(b"z"), Some(b"zz")));
+        assert!(!tombstones.overlaps(None, Some(b"0")));
+        assert!(tombstones.overlaps(Some(b"y"), None));
+    }
+}
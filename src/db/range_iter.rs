@@ -0,0 +1,150 @@
+//! Adapters that walk a DB iterator over a range of user keys, forwards
+//! or backwards, and hand out copies of the entries through
+//! std::iter::Iterator.  See DB::range() and DB::range_rev().
+
+use std::{cmp::Ordering, ops::{Bound, RangeBounds}, sync::Arc};
+
+use crate::{comparator::Comparator, slice::Slice, status::Status};
+
+use super::live_iter::DbIterator;
+
+/// The entries of a key range as owned (key, value) pairs, in key order
+/// or in reverse key order.
+///
+/// Iteration ends early if the underlying iterator fails; check status()
+/// once it is exhausted.
+pub struct RangeIter<'db> {
+    iter_: DbIterator<'db>,
+    user_comparator_: Arc<dyn Comparator>,
+    // Bound where the scan starts, already applied when positioning,
+    // and bound where it stops.
+    stop_: Bound<Vec<u8>>,
+    reverse_: bool,
+    keys_only_: bool,
+    done_: bool,
+}
+
+/// The keys of a RangeIter; see RangeIter::keys_only().
+pub struct RangeKeys<'db>(RangeIter<'db>);
+
+impl<'db> RangeIter<'db> {
+    pub(crate) fn new<R: RangeBounds<[u8]>>(mut iter: DbIterator<'db>, user_comparator: Arc<dyn Comparator>,
+                                            range: R, reverse: bool) -> Self {
+        let compare = |key: &Slice, bound: &[u8]| user_comparator.compare(key, &Slice::new(bound));
+        if reverse {
+            // Position at the largest key inside the upper bound.
+            match range.end_bound() {
+                Bound::Unbounded => iter.seek_to_last(),
+                Bound::Included(k) | Bound::Excluded(k) => {
+                    iter.seek(&Slice::new(k));
+                    if !iter.valid() {
+                        iter.seek_to_last();
+                    } else if matches!(range.end_bound(), Bound::Excluded(_)) || compare(&iter.key(), k) == Ordering::Greater {
+                        iter.prev();
+                    }
+                },
+            }
+        } else {
+            // Position at the smallest key inside the lower bound.
+            match range.start_bound() {
+                Bound::Unbounded => iter.seek_to_first(),
+                Bound::Included(k) => iter.seek(&Slice::new(k)),
+                Bound::Excluded(k) => {
+                    iter.seek(&Slice::new(k));
+                    if iter.valid() && compare(&iter.key(), k) == Ordering::Equal {
+                        iter.next();
+                    }
+                },
+            }
+        }
+        let stop = if reverse { range.start_bound() } else { range.end_bound() };
+        Self {
+            iter_: iter,
+            user_comparator_: user_comparator.clone(),
+            stop_: stop.map(|k| k.to_vec()),
+            reverse_: reverse,
+            keys_only_: false,
+            done_: false,
+        }
+    }
+
+    /// Yield only the keys; values are not copied.
+    pub fn keys_only(mut self) -> RangeKeys<'db> {
+        self.keys_only_ = true;
+        RangeKeys(self)
+    }
+
+    /// Returns the error that ended the iteration early, if any.
+    pub fn status(&self) -> Status {
+        self.iter_.status()
+    }
+
+    /// Returns true iff "key" lies past the bound where the scan stops.
+    fn past_stop(&self, key: &Slice) -> bool {
+        let (bound, inclusive) = match &self.stop_ {
+            Bound::Unbounded => return false,
+            Bound::Included(k) => (k, true),
+            Bound::Excluded(k) => (k, false),
+        };
+        let order = self.user_comparator_.compare(key, &Slice::new(bound));
+        let past = if self.reverse_ { Ordering::Less } else { Ordering::Greater };
+        order == past || (!inclusive && order == Ordering::Equal)
+    }
+}
+
+impl std::iter::Iterator for RangeIter<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done_ || !self.iter_.valid() || self.past_stop(&self.iter_.key()) {
+            self.done_ = true;
+            return None;
+        }
+        let key = self.iter_.key().data().to_vec();
+        let value = if self.keys_only_ { Vec::new() } else { self.iter_.value().data().to_vec() };
+        if self.reverse_ {
+            self.iter_.prev();
+        } else {
+            self.iter_.next();
+        }
+        Some((key, value))
+    }
+}
+
+impl RangeKeys<'_> {
+    /// Returns the error that ended the iteration early, if any.
+    pub fn status(&self) -> Status {
+        self.0.status()
+    }
+}
+
+impl std::iter::Iterator for RangeKeys<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+/// Returns the smallest key greater than every key that starts with
+/// "prefix" in bytewise order, or None if there is no such key (the
+/// prefix is empty or all 0xff bytes).
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let end = prefix.iter().rposition(|&b| b != 0xff)?;
+    let mut successor = prefix[..=end].to_vec();
+    successor[end] += 1;
+    Some(successor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_successor_test() {
+        assert_eq!(Some(b"ac".to_vec()), prefix_successor(b"ab"));
+        assert_eq!(Some(b"b".to_vec()), prefix_successor(b"a\xff\xff"));
+        assert_eq!(None, prefix_successor(b"\xff\xff"));
+        assert_eq!(None, prefix_successor(b""));
+    }
+}
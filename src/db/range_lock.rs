@@ -0,0 +1,287 @@
+//! Advisory key-range locks (see DB::lock_range).
+//!
+//! Locked ranges live in an IntervalMap behind a RwLock.  Writers only
+//! take the read side for the duration of their write, so writes do not
+//! serialize against each other; acquiring or releasing a lock takes the
+//! write side and therefore waits for in-flight writes to finish.  Only
+//! writers that hit a locked range, and lockers that hit an overlapping
+//! lock, wait on the condition variable.
+
+use std::{sync::{Arc, Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard}, sync::atomic::{AtomicU64, Ordering}};
+
+use crate::{comparator::Comparator, slice::Slice, status::Status, util::interval_map::IntervalMap, write_batch::{Handler, WriteBatch}};
+
+pub(crate) struct RangeLockTable {
+    locks_: RwLock<IntervalMap<u64>>,   // Locked range -> owner id
+    next_id_: AtomicU64,
+
+    // Signalled whenever a lock is released.  Waiters hold waiters_ from
+    // their conflict check until they sleep, so a release can't slip in
+    // between.
+    waiters_: Mutex<()>,
+    released_: Condvar,
+}
+
+/// Holds the key range [begin, end) locked until dropped.  While held,
+/// writes into the range made without this guard (see DB::write_locked)
+/// block, or fail with a Busy status if WriteOptions::fail_on_locked_range
+/// is set.
+///
+/// The lock is released when the guard goes out of scope, including
+/// during unwinding.
+pub struct RangeLockGuard {
+    table_: Arc<RangeLockTable>,
+    id_: u64,
+    begin_: Vec<u8>,
+}
+
+impl Drop for RangeLockGuard {
+    fn drop(&mut self) {
+        let removed = self.table_.locks_.write().unwrap_or_else(PoisonError::into_inner).remove(&self.begin_);
+        debug_assert_eq!(Some(self.id_), removed);
+        let _l = self.table_.waiters_.lock().unwrap_or_else(PoisonError::into_inner);
+        self.table_.released_.notify_all();
+    }
+}
+
+/// Keeps locks from being acquired or released while a write is applied.
+pub(crate) struct WriteTicket<'a> {
+    _locks: RwLockReadGuard<'a, IntervalMap<u64>>,
+}
+
+impl RangeLockTable {
+    pub(crate) fn new(comparator: Arc<dyn Comparator>) -> Self {
+        Self {
+            locks_: RwLock::new(IntervalMap::new(comparator)),
+            next_id_: AtomicU64::new(1),
+            waiters_: Mutex::new(()),
+            released_: Condvar::new(),
+        }
+    }
+
+    /// Lock [begin, end).  If it overlaps a range that is already locked,
+    /// wait for that lock to be released, or return a Busy status if
+    /// "wait" is false.
+    /// REQUIRES: begin < end
+    pub(crate) fn lock(self: &Arc<Self>, begin: &[u8], end: &[u8], wait: bool) -> Result<RangeLockGuard, Status> {
+        let id = self.next_id_.fetch_add(1, Ordering::Relaxed);
+        let mut waiters = self.waiters_.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if self.locks_.write().unwrap_or_else(PoisonError::into_inner).insert(begin, end, id) {
+                return Ok(RangeLockGuard { table_: self.clone(), id_: id, begin_: begin.to_vec() });
+            }
+            if !wait {
+                return Err(Status::busy("key range is locked", ""));
+            }
+            waiters = self.released_.wait(waiters).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Wait until no key of "updates" falls in a range locked by anyone
+    /// but "owner", or return a Busy status if "wait" is false.  Ranges
+    /// stay as they are until the returned ticket is dropped.
+    pub(crate) fn begin_write(&self, updates: &WriteBatch, wait: bool, owner: Option<&RangeLockGuard>) -> Result<WriteTicket<'_>, Status> {
+        // Fast path: no conflicting lock, nobody to wait for.
+        let locks = self.locks_.read().unwrap_or_else(PoisonError::into_inner);
+        if !self.conflicts(&locks, updates, owner) {
+            return Ok(WriteTicket { _locks: locks });
+        }
+        drop(locks);
+        if !wait {
+            return Err(Status::busy("write touches a locked key range", ""));
+        }
+
+        let mut waiters = self.waiters_.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let locks = self.locks_.read().unwrap_or_else(PoisonError::into_inner);
+            if !self.conflicts(&locks, updates, owner) {
+                return Ok(WriteTicket { _locks: locks });
+            }
+            drop(locks);
+            waiters = self.released_.wait(waiters).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn conflicts(&self, locks: &IntervalMap<u64>, updates: &WriteBatch, owner: Option<&RangeLockGuard>) -> bool {
+        if locks.is_empty() {
+            return false;
+        }
+        let owner = owner.map(|guard| {
+            debug_assert!(std::ptr::eq(guard.table_.as_ref(), self));
+            guard.id_
+        });
+        let mut checker = ConflictChecker { locks, owner, conflict: false };
+        // A malformed batch is reported by the write itself.
+        let _ = updates.iterate(&mut checker);
+        checker.conflict
+    }
+}
+
+struct ConflictChecker<'a> {
+    locks: &'a IntervalMap<u64>,
+    owner: Option<u64>,
+    conflict: bool,
+}
+
+impl ConflictChecker<'_> {
+    fn check(&mut self, key: &Slice) {
+        if let Some(&id) = self.locks.get(key.data()) {
+            self.conflict |= self.owner != Some(id);
+        }
+    }
+}
+
+impl Handler for ConflictChecker<'_> {
+    fn put(&mut self, key: &Slice, _value: &Slice) {
+        self.check(key);
+    }
+
+    fn delete(&mut self, key: &Slice) {
+        self.check(key);
+    }
+
+    fn delete_range(&mut self, begin: &Slice, end: &Slice) {
+        if self.locks.overlapping(begin.data(), end.data()).any(|&id| self.owner != Some(id)) {
+            self.conflict = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic, sync::{atomic::AtomicBool, mpsc}, thread, time::Duration};
+
+    use crate::comparator::bytewise_comparator;
+
+    use super::*;
+
+    fn new_table() -> Arc<RangeLockTable> {
+        Arc::new(RangeLockTable::new(bytewise_comparator()))
+    }
+
+    fn batch(keys: &[&str]) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.put(&Slice::new(key.as_bytes()), &Slice::new(b"v"));
+        }
+        batch
+    }
+
+    #[test]
+    fn fail_fast_test() {
+        let table = new_table();
+        let guard = table.lock(b"b", b"d", false).unwrap();
+        assert!(table.begin_write(&batch(&["c"]), false, None).err().unwrap().is_busy());
+        assert!(table.begin_write(&batch(&["a", "c"]), false, None).err().unwrap().is_busy());
+        assert!(table.begin_write(&batch(&["a", "d"]), false, None).is_ok());
+        // The owner may write into its own range
+        assert!(table.begin_write(&batch(&["c"]), false, Some(&guard)).is_ok());
+
+        // Overlapping locks conflict, adjacent ones don't
+        assert!(table.lock(b"a", b"c", false).err().unwrap().is_busy());
+        assert!(table.lock(b"c", b"z", false).err().unwrap().is_busy());
+        let other = table.lock(b"d", b"z", false).unwrap();
+        assert!(table.begin_write(&batch(&["c"]), false, Some(&other)).err().unwrap().is_busy());
+
+        // A range deletion touches every key in its range
+        let delete_range = |begin: &[u8], end: &[u8]| {
+            let mut batch = WriteBatch::new();
+            batch.delete_range(&Slice::new(begin), &Slice::new(end));
+            batch
+        };
+        assert!(table.begin_write(&delete_range(b"a", b"c"), false, None).err().unwrap().is_busy());
+        assert!(table.begin_write(&delete_range(b"a", b"b"), false, None).is_ok());
+        assert!(table.begin_write(&delete_range(b"a", b"c"), false, Some(&guard)).is_ok());
+        assert!(table.begin_write(&delete_range(b"c", b"e"), false, Some(&guard)).err().unwrap().is_busy());
+
+        drop(guard);
+        assert!(table.begin_write(&batch(&["c"]), false, None).is_ok());
+        assert!(table.lock(b"a", b"c", false).is_ok());
+    }
+
+    #[test]
+    fn blocking_write_test() {
+        let table = new_table();
+        let guard = table.lock(b"b", b"d", true).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (table, done) = (table.clone(), done.clone());
+            thread::spawn(move || {
+                let _ticket = table.begin_write(&batch(&["c"]), true, None).unwrap();
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(guard);
+        writer.join().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn blocking_lock_test() {
+        let table = new_table();
+        let guard = table.lock(b"b", b"d", true).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let locker = {
+            let table = table.clone();
+            thread::spawn(move || {
+                let _guard = table.lock(b"a", b"c", true).unwrap();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(guard);
+        rx.recv().unwrap();
+        locker.join().unwrap();
+    }
+
+    #[test]
+    fn panic_releases_lock_test() {
+        let table = new_table();
+        let holder = {
+            let table = table.clone();
+            thread::spawn(move || {
+                let _guard = table.lock(b"a", b"z", true).unwrap();
+                thread::sleep(Duration::from_millis(20));
+                panic!("holder failed");
+            })
+        };
+        // Blocks until the holder unwinds, if it got the lock first.
+        thread::sleep(Duration::from_millis(5));
+        drop(table.begin_write(&batch(&["m"]), true, None).unwrap());
+        assert!(holder.join().is_err());
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = table.lock(b"a", b"z", true).unwrap();
+            panic!("boom");
+        }));
+        assert!(result.is_err());
+        assert!(table.lock(b"a", b"z", false).is_ok());
+    }
+
+    #[test]
+    fn disjoint_stress_test() {
+        // Threads lock and write disjoint ranges; none of them ever waits
+        // on another's lock.
+        let table = new_table();
+        let threads: Vec<_> = (0..8u8).map(|t| {
+            let table = table.clone();
+            thread::spawn(move || {
+                let begin = format!("{}", t);
+                let end = format!("{}~", t);
+                for i in 0..500 {
+                    let guard = table.lock(begin.as_bytes(), end.as_bytes(), false).unwrap();
+                    let key = format!("{}{:03}", t, i);
+                    assert!(table.begin_write(&batch(&[&key]), false, Some(&guard)).is_ok());
+                    drop(guard);
+                    assert!(table.begin_write(&batch(&[&key]), false, None).is_ok());
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(table.locks_.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+//! What recent gets cost: how many files of each level they probed and
+//! how many blocks they read, over a sliding window of the last gets.
+//! See DB::read_amplification_report().
+
+use super::dbformat::NUM_LEVELS;
+
+/// Averages over the gets in the window at the time of the report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadAmpReport {
+    /// Number of gets the averages are taken over.
+    pub gets: usize,
+
+    /// For each level, the files a get probed on average.  Level-0 files
+    /// may overlap, so a get can probe several of them.
+    pub per_level_avg_files_probed: Vec<f64>,
+
+    /// Data blocks a get read from table files on average.
+    pub avg_blocks_per_get: f64,
+
+    /// Fraction of the gets answered by a memtable.
+    pub memtable_hit_fraction: f64,
+
+    /// Most files probed by a single get.
+    pub worst_recent_get_files: usize,
+}
+
+/// The cost of one get.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct GetSample {
+    pub(crate) files_probed: [u32; NUM_LEVELS as usize],
+    pub(crate) blocks_read: u32,
+    pub(crate) memtable_hit: bool,
+    pub(crate) probe_misses: u32,       // Files probed that did not hold the key
+    pub(crate) miss_blocks_read: u32,   // Data blocks read from those
+}
+
+impl GetSample {
+    fn total_files(&self) -> u64 {
+        self.files_probed.iter().map(|&n| n as u64).sum()
+    }
+}
+
+/// Ring buffer of the last "capacity" samples, with their sums kept up
+/// to date so that checking the level-0 average is cheap on every get.
+pub(crate) struct ReadAmpWindow {
+    samples_: Vec<GetSample>,
+    capacity_: usize,
+    next_: usize,   // Slot the next sample overwrites once full
+
+    // Sums over samples_
+    files_probed_: [u64; NUM_LEVELS as usize],
+    blocks_read_: u64,
+    memtable_hits_: u64,
+
+    // Env::now_micros() at the last warning, if any
+    last_warning_micros_: Option<u64>,
+}
+
+impl ReadAmpWindow {
+    /// A window over the last "capacity" gets.  Zero records nothing.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            samples_: Vec::with_capacity(capacity),
+            capacity_: capacity,
+            next_: 0,
+            files_probed_: [0; NUM_LEVELS as usize],
+            blocks_read_: 0,
+            memtable_hits_: 0,
+            last_warning_micros_: None,
+        }
+    }
+
+    /// Add "sample", dropping the oldest one if the window is full.
+    pub(crate) fn record(&mut self, sample: GetSample) {
+        if self.capacity_ == 0 {
+            return;
+        }
+        self.add_to_sums(&sample, true);
+        if self.samples_.len() < self.capacity_ {
+            self.samples_.push(sample);
+        } else {
+            let old = std::mem::replace(&mut self.samples_[self.next_], sample);
+            self.add_to_sums(&old, false);
+            self.next_ = (self.next_ + 1) % self.capacity_;
+        }
+    }
+
+    fn add_to_sums(&mut self, sample: &GetSample, add: bool) {
+        let apply = |sum: &mut u64, n: u64| if add { *sum += n } else { *sum -= n };
+        for (sum, &n) in self.files_probed_.iter_mut().zip(&sample.files_probed) {
+            apply(sum, n as u64);
+        }
+        apply(&mut self.blocks_read_, sample.blocks_read as u64);
+        apply(&mut self.memtable_hits_, sample.memtable_hit as u64);
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.capacity_ > 0 && self.samples_.len() == self.capacity_
+    }
+
+    /// Level-0 files a get in the window probed on average.
+    pub(crate) fn l0_average(&self) -> f64 {
+        self.average(self.files_probed_[0])
+    }
+
+    fn average(&self, sum: u64) -> f64 {
+        if self.samples_.is_empty() {
+            0.0
+        } else {
+            sum as f64 / self.samples_.len() as f64
+        }
+    }
+
+    /// Returns true, and notes the time, iff no warning was issued in the
+    /// "interval_secs" before "now_micros".
+    pub(crate) fn warning_due(&mut self, now_micros: u64, interval_secs: u64) -> bool {
+        let due = self.last_warning_micros_
+            .is_none_or(|last| now_micros.saturating_sub(last) >= interval_secs.saturating_mul(1_000_000));
+        if due {
+            self.last_warning_micros_ = Some(now_micros);
+        }
+        due
+    }
+
+    pub(crate) fn report(&self) -> ReadAmpReport {
+        ReadAmpReport {
+            gets: self.samples_.len(),
+            per_level_avg_files_probed: self.files_probed_.iter().map(|&sum| self.average(sum)).collect(),
+            avg_blocks_per_get: self.average(self.blocks_read_),
+            memtable_hit_fraction: self.average(self.memtable_hits_),
+            worst_recent_get_files: self.samples_.iter().map(|s| s.total_files()).max().unwrap_or(0) as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(l0: u32, l1: u32, memtable_hit: bool) -> GetSample {
+        let mut sample = GetSample { blocks_read: l0 + l1, memtable_hit, ..Default::default() };
+        sample.files_probed[0] = l0;
+        sample.files_probed[1] = l1;
+        sample
+    }
+
+    #[test]
+    fn window_test() {
+        let mut window = ReadAmpWindow::new(4);
+        assert_eq!(0, window.report().gets);
+        assert_eq!(0.0, window.l0_average());
+        for _ in 0..4 {
+            window.record(sample(6, 1, false));
+        }
+        assert!(window.is_full());
+        assert_eq!(6.0, window.l0_average());
+
+        // Older samples leave the window
+        window.record(sample(0, 0, true));
+        window.record(sample(2, 1, false));
+        let report = window.report();
+        assert_eq!(4, report.gets);
+        assert_eq!(vec![3.5, 0.75, 0.0], report.per_level_avg_files_probed[..3]);
+        assert_eq!(4.25, report.avg_blocks_per_get);
+        assert_eq!(0.25, report.memtable_hit_fraction);
+        assert_eq!(7, report.worst_recent_get_files);
+        for _ in 0..4 {
+            window.record(sample(0, 1, false));
+        }
+        assert_eq!((0.0, 1), (window.l0_average(), window.report().worst_recent_get_files));
+
+        // Nothing is recorded into an empty window
+        let mut window = ReadAmpWindow::new(0);
+        window.record(sample(6, 1, false));
+        assert!(!window.is_full());
+        assert_eq!(0, window.report().gets);
+    }
+
+    #[test]
+    fn warning_due_test() {
+        let mut window = ReadAmpWindow::new(1);
+        assert!(window.warning_due(5_000_000, 10));
+        assert!(!window.warning_due(5_000_000, 10));
+        assert!(!window.warning_due(14_999_999, 10));
+        assert!(window.warning_due(15_000_000, 10));
+    }
+}
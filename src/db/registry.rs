@@ -0,0 +1,86 @@
+//! The DBs of the process that were opened with Options::instance_name,
+//! for tools that watch several of them at once.  See list_instances().
+//!
+//! A DB is not Send, so the list can not refer to it.  Instead each named
+//! DB owns an Instance holding its name, path and latest health, and the
+//! list keeps weak references to those: dropping the DB drops its
+//! Instance, which takes itself off the list.
+
+use std::{collections::BTreeMap, sync::{Arc, Mutex, Weak}};
+
+use crate::status::Status;
+
+use super::health::DbHealth;
+
+static INSTANCES: Mutex<BTreeMap<String, Weak<Instance>>> = Mutex::new(BTreeMap::new());
+
+/// An open DB as listed by list_instances().
+#[derive(Debug, Clone)]
+pub struct InstanceInfo {
+    /// Options::instance_name of the DB.
+    pub name: String,
+
+    /// The directory the DB was opened in.
+    pub path: String,
+
+    /// DB::health() as of the DB's last flush, compaction, new log file or
+    /// background error.  oldest_unflushed_age_secs and stalled_writers
+    /// are not kept up to date in between.
+    pub health: DbHealth,
+}
+
+/// The registration of one named DB; it is listed for as long as it lives.
+pub(crate) struct Instance {
+    name_: String,
+    path_: String,
+    health_: Mutex<DbHealth>,
+}
+
+impl Instance {
+    /// Add a DB called "name" at "path" to the list.  Fails with
+    /// InvalidArgument if an open DB already has the name.
+    pub(crate) fn register(name: &str, path: &str, health: DbHealth) -> Result<Arc<Instance>, Status> {
+        let mut instances = INSTANCES.lock().expect("failed to acquire lock");
+        // Checked without upgrading: dropping an upgraded reference here
+        // could drop the Instance, whose drop() takes INSTANCES again.
+        if instances.get(name).is_some_and(|instance| instance.strong_count() > 0) {
+            return Err(Status::invalid_argument(name, "is already the instance name of an open DB"));
+        }
+        let instance = Arc::new(Instance {
+            name_: name.to_string(),
+            path_: path.to_string(),
+            health_: Mutex::new(health),
+        });
+        instances.insert(name.to_string(), Arc::downgrade(&instance));
+        Ok(instance)
+    }
+
+    pub(crate) fn set_health(&self, health: DbHealth) {
+        *self.health_.lock().expect("failed to acquire lock") = health;
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let mut instances = INSTANCES.lock().expect("failed to acquire lock");
+        if instances.get(&self.name_).is_some_and(|instance| std::ptr::eq(instance.as_ptr(), self)) {
+            instances.remove(&self.name_);
+        }
+    }
+}
+
+/// List the open DBs of the process that have an Options::instance_name,
+/// ordered by name.
+pub fn list_instances() -> Vec<InstanceInfo> {
+    let live: Vec<Arc<Instance>> = {
+        let instances = INSTANCES.lock().expect("failed to acquire lock");
+        instances.values().filter_map(|instance| instance.upgrade()).collect()
+    };
+    // INSTANCES is released before "live" is dropped, as that may drop
+    // the last reference to an Instance.
+    live.iter().map(|instance| InstanceInfo {
+        name: instance.name_.clone(),
+        path: instance.path_.clone(),
+        health: instance.health_.lock().expect("failed to acquire lock").clone(),
+    }).collect()
+}
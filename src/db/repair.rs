@@ -0,0 +1,515 @@
+//! We recover the contents of the descriptor from the other files we find.
+//! (1) Any log files are first converted to tables
+//! (2) We scan every table to compute
+//!     (a) smallest/largest for the table
+//!     (b) largest sequence number in the table
+//! (3) We generate descriptor contents:
+//!      - log number is set to zero
+//!      - next-file-number is set to 1 + largest file number we found
+//!      - last-sequence-number is set to largest sequence# found across
+//!        all tables (see 2c)
+//!      - compaction pointers are cleared
+//!      - every table file is added at level 0
+//!
+//! Possible optimization 1:
+//!   (a) Compute total size and use to pick appropriate max-level M
+//!   (b) Sort tables by largest sequence# in the table
+//!   (c) For each table: if it overlaps earlier table, place in level-0,
+//!       else place in level-M.
+//! Possible optimization 2:
+//!   Store per-table metadata (smallest, largest, largest-seq#, ...)
+//!   in the table's meta section to speed up scan_table.
+
+use std::{rc::Rc, sync::Arc};
+
+use crate::{env::{log, Env, Logger}, filter_policy::FilterPolicy, iterator::Iterator, options::{Options, ReadOptions}, slice::Slice, status::Status, table::table_builder::TableBuilder, util::{env::checksum_file, redact::redact}, write_batch::{self, TransactionRecord, WriteBatch}};
+
+use super::{builder::build_table, dbformat::{parse_internal_key, InternalFilterPolicy, InternalKey, InternalKeyComparator}, filename::{descriptor_file_name, log_file_name, parse_file_name, set_current_file, sst_table_file_name, table_file_name, temp_file_name, FileType}, log_reader::{Reader, Reporter}, log_writer::Writer, memtable::MemTable, range_del::new_flush_iterator, sanitize_options, table_cache::TableCache, version_edit::{FileMetaData, SequenceNumber, VersionEdit}};
+
+struct TableInfo {
+    meta: FileMetaData,
+    max_sequence: SequenceNumber,
+}
+
+struct Repairer {
+    dbname_: String,
+    env_: Arc<dyn Env>,
+    icmp_: InternalKeyComparator,
+    options_: Options,
+    table_cache_: Arc<TableCache>,
+    edit_: VersionEdit,
+
+    manifests_: Vec<String>,
+    table_numbers_: Vec<u64>,
+    logs_: Vec<u64>,
+    tables_: Vec<TableInfo>,
+    next_file_number_: u64,
+}
+
+impl Repairer {
+    fn new(dbname: &str, options: &Options) -> Self {
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let ipolicy = options.filter_policy.clone().map(|p| Arc::new(InternalFilterPolicy::new(p)) as Arc<dyn FilterPolicy>);
+        let options = sanitize_options(dbname, &icmp, ipolicy, options, true);
+        Self {
+            dbname_: dbname.to_string(),
+            env_: options.env.clone(),
+            icmp_: icmp,
+            table_cache_: Arc::new(TableCache::new(dbname, &options)),
+            options_: options,
+            edit_: VersionEdit::new(),
+            manifests_: Vec::new(),
+            table_numbers_: Vec::new(),
+            logs_: Vec::new(),
+            tables_: Vec::new(),
+            next_file_number_: 1,
+        }
+    }
+
+    fn run(&mut self) -> Status {
+        let mut status = self.find_files();
+        if status.ok() {
+            self.convert_log_files_to_tables();
+            self.extract_meta_data();
+            status = self.write_descriptor();
+        }
+        if status.ok() {
+            let bytes: u64 = self.tables_.iter().map(|t| t.meta.file_size).sum();
+            log(self.options_.info_log.clone(), &format!(
+                "**** Repaired leveldb {}; recovered {} files; {} bytes. Some data may have been lost. ****",
+                self.dbname_, self.tables_.len(), bytes));
+        }
+        status
+    }
+
+    fn find_files(&mut self) -> Status {
+        let filenames = match self.env_.get_children(&self.dbname_) {
+            Ok(filenames) => filenames,
+            Err(s) => { return s; },
+        };
+        // The LOG files may be only the ones this repair just started.
+        if filenames.iter().all(|f| matches!(parse_file_name(f), Some((_, FileType::InfoLogFile)))) {
+            return Status::io_error(&self.dbname_, "repair found no files");
+        }
+
+        for filename in filenames {
+            if let Some((number, type_)) = parse_file_name(&filename) {
+                if type_ == FileType::DescriptorFile {
+                    self.manifests_.push(filename);
+                } else {
+                    if number + 1 > self.next_file_number_ {
+                        self.next_file_number_ = number + 1;
+                    }
+                    match type_ {
+                        FileType::LogFile => { self.logs_.push(number); },
+                        FileType::TableFile => { self.table_numbers_.push(number); },
+                        _ => {},    // Ignore other files
+                    }
+                }
+            }
+        }
+        Status::new_ok()
+    }
+
+    fn convert_log_files_to_tables(&mut self) {
+        // Oldest first, so that the range deletions of a log can be
+        // expanded over the tables of the logs before it.
+        let mut logs = std::mem::take(&mut self.logs_);
+        logs.sort_unstable();
+        for log_number in logs {
+            let logname = log_file_name(&self.dbname_, log_number);
+            let status = self.convert_log_to_table(log_number);
+            if !status.ok() {
+                log(self.options_.info_log.clone(), &format!("Log #{}: ignoring conversion error: {}",
+                    log_number, status.to_string()));
+            }
+            self.archive_file(&logname);
+        }
+    }
+
+    fn convert_log_to_table(&mut self, log_number: u64) -> Status {
+        // Open the log file
+        let logname = log_file_name(&self.dbname_, log_number);
+        let file = match self.env_.new_sequential_file(&logname) {
+            Ok(file) => file,
+            Err(s) => { return s; },
+        };
+
+        // Create the log reader.
+        let reporter = Rc::new(LogReporter { info_log: self.options_.info_log.clone(), lognum: log_number });
+
+        // We intentionally make log::Reader do checksumming so that
+        // corruptions cause entire commits to be skipped instead of
+        // propagating bad information (like overly large sequence
+        // numbers).
+        let mut reader = Reader::new(file, Some(reporter.clone()), true, 0);
+
+        // Read all the records and add to a memtable
+        let mut batch = WriteBatch::new();
+        let mem = MemTable::new(&self.icmp_);
+        let mut counter = 0;
+        while let Some(record) = reader.read_record() {
+            if record.len() < write_batch::HEADER {
+                reporter.corruption(record.len(), &Status::corruption("log record too small", ""));
+                continue;
+            }
+            batch.set_contents(&Slice::new(&record));
+            if let Some(TransactionRecord::Prepare { .. }) = batch.transaction_record() {
+                // Applied only if its transaction committed, which logged
+                // the batch again
+                continue;
+            }
+            let status = batch.insert_into(&mem);
+            if status.ok() {
+                counter += batch.count();
+            } else {
+                // Keep going with rest of file
+                log(self.options_.info_log.clone(), &format!("Log #{}: ignoring {}", log_number, status.to_string()));
+            }
+        }
+
+        // Do not record a version edit for this conversion to a Table
+        // since extract_meta_data() will also generate edits.
+        let mut meta = FileMetaData::new();
+        meta.number = self.next_file_number_;
+        self.next_file_number_ += 1;
+        let mut iter = new_flush_iterator(&self.icmp_, &mem, || {
+            self.table_numbers_.iter()
+                .filter_map(|&number| self.table_file_size(number).ok().map(|(_, size)| (number, size)))
+                .map(|(number, file_size)| {
+                    let mut meta = FileMetaData::new();
+                    meta.number = number;
+                    meta.file_size = file_size;
+                    self.new_table_iterator(&meta)
+                })
+                .collect()
+        });
+        let status = build_table(&self.dbname_, &self.env_, &self.options_, &self.table_cache_, iter.as_mut(), &mut meta);
+        if status.ok() && meta.file_size > 0 {
+            self.table_numbers_.push(meta.number);
+        }
+        log(self.options_.info_log.clone(), &format!("Log #{}: {} ops saved to Table #{} {}",
+            log_number, counter, meta.number, status.to_string()));
+        status
+    }
+
+    fn extract_meta_data(&mut self) {
+        for number in std::mem::take(&mut self.table_numbers_) {
+            self.scan_table(number);
+        }
+    }
+
+    fn new_table_iterator(&self, meta: &FileMetaData) -> Box<dyn Iterator> {
+        // Same as compaction iterators: verify checksums so that damaged
+        // blocks are detected rather than copied.
+        let mut r = ReadOptions::new();
+        r.verify_checksums = true;
+        self.table_cache_.new_iterator(&r, None, meta.number, meta.file_size)
+    }
+
+    /// Return the name and size of table "number".
+    fn table_file_size(&self, number: u64) -> Result<(String, u64), Status> {
+        let fname = table_file_name(&self.dbname_, number);
+        match self.env_.get_file_size(&fname) {
+            Ok(size) => Ok((fname, size)),
+            Err(s) => {
+                // Try alternate file name.
+                let fname = sst_table_file_name(&self.dbname_, number);
+                self.env_.get_file_size(&fname).map(|size| (fname, size)).map_err(|_| s)
+            },
+        }
+    }
+
+    fn scan_table(&mut self, number: u64) {
+        let mut t = TableInfo { meta: FileMetaData::new(), max_sequence: 0 };
+        t.meta.number = number;
+        let fname = match self.table_file_size(number) {
+            Ok((fname, size)) => {
+                t.meta.file_size = size;
+                fname
+            },
+            Err(s) => {
+                self.archive_file(&table_file_name(&self.dbname_, number));
+                self.archive_file(&sst_table_file_name(&self.dbname_, number));
+                log(self.options_.info_log.clone(), &format!("Table #{}: dropped: {}", t.meta.number, s.to_string()));
+                return;
+            },
+        };
+
+        // Extract metadata by scanning through table.
+        let mut counter = 0;
+        let mut iter = self.new_table_iterator(&t.meta);
+        let mut empty = true;
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            match parse_internal_key(&key) {
+                None => {
+                    log(self.options_.info_log.clone(), &format!("Table #{}: unparsable key {:?}",
+                        t.meta.number, redact(key.data(), self.options_.diagnostics_redaction)));
+                },
+                Some(parsed) => {
+                    counter += 1;
+                    if empty {
+                        empty = false;
+                        t.meta.smallest = InternalKey::decode_from(&key);
+                    }
+                    t.meta.largest = InternalKey::decode_from(&key);
+                    if parsed.sequence > t.max_sequence {
+                        t.max_sequence = parsed.sequence;
+                    }
+                },
+            }
+            iter.next();
+        }
+        let status = iter.status();
+        log(self.options_.info_log.clone(), &format!("Table #{}: {} entries {}", t.meta.number, counter, status.to_string()));
+
+        if status.ok() {
+            // The table is taken as it is, so its contents now are what
+            // later checks compare it with.
+            t.meta.file_checksum = checksum_file(self.env_.clone(), &fname).ok()
+                .and_then(|(checksum, size)| (size == t.meta.file_size).then_some(checksum));
+            self.tables_.push(t);
+        } else {
+            self.repair_table(&fname, t);    // repair_table archives input file.
+        }
+    }
+
+    fn repair_table(&mut self, src: &str, mut t: TableInfo) {
+        // We will copy src contents to a new table and then rename the
+        // new table over the source.
+
+        // Create builder.
+        let copy = table_file_name(&self.dbname_, self.next_file_number_);
+        self.next_file_number_ += 1;
+        let file = match self.env_.new_writable_file(&copy) {
+            Ok(file) => file,
+            Err(_) => { return; },
+        };
+        let mut builder = TableBuilder::new(&self.options_, file.clone());
+
+        // Copy data.
+        let mut iter = self.new_table_iterator(&t.meta);
+        let mut counter = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            builder.add(&iter.key(), &iter.value());
+            counter += 1;
+            iter.next();
+        }
+
+        self.archive_file(src);
+        let mut s = Status::new_ok();
+        if counter == 0 {
+            builder.abandon();  // Nothing to save
+        } else {
+            s = builder.finish();
+            if s.ok() {
+                t.meta.file_size = builder.file_size();
+                t.meta.file_checksum = Some(builder.file_checksum());
+            }
+        }
+        if s.ok() {
+            s = file.close();
+        }
+
+        if counter > 0 && s.ok() {
+            let orig = table_file_name(&self.dbname_, t.meta.number);
+            s = self.env_.rename_file(&copy, &orig);
+            if s.ok() {
+                // The cached table still refers to the damaged contents.
+                self.table_cache_.evict(t.meta.number);
+                log(self.options_.info_log.clone(), &format!("Table #{}: {} entries repaired", t.meta.number, counter));
+                self.tables_.push(t);
+            }
+        }
+        if !s.ok() {
+            self.env_.remove_file(&copy);
+        }
+    }
+
+    fn write_descriptor(&mut self) -> Status {
+        let tmp = temp_file_name(&self.dbname_, 1);
+        let file = match self.env_.new_writable_file(&tmp) {
+            Ok(file) => file,
+            Err(s) => { return s; },
+        };
+
+        let max_sequence = self.tables_.iter().map(|t| t.max_sequence).max().unwrap_or(0);
+
+        self.edit_.set_comparator_name(self.icmp_.user_comparator().name());
+        self.edit_.set_log_number(0);
+        self.edit_.set_next_file(self.next_file_number_);
+        self.edit_.set_last_sequence(max_sequence);
+
+        for t in &self.tables_ {
+            // TODO(opt): separate out into multiple levels
+            self.edit_.add_file_with_checksum(0, t.meta.number, t.meta.file_size, &t.meta.smallest, &t.meta.largest, t.meta.file_checksum);
+        }
+        if self.edit_.features_used() != 0 {
+            self.edit_.set_required_features(self.edit_.features_used());
+        }
+
+        let mut status = {
+            let mut log = Writer::new(file.clone());
+            let mut record = Vec::new();
+            self.edit_.encode_to(&mut record);
+            log.add_record(&Slice::new(&record))
+        };
+        if status.ok() {
+            status = file.close();
+        }
+
+        if !status.ok() {
+            self.env_.remove_file(&tmp);
+        } else {
+            // Discard older manifests
+            for manifest in std::mem::take(&mut self.manifests_) {
+                self.archive_file(&format!("{}/{}", self.dbname_, manifest));
+            }
+
+            // Install new manifest
+            status = self.env_.rename_file(&tmp, &descriptor_file_name(&self.dbname_, 1));
+            if status.ok() {
+                status = set_current_file(self.env_.clone(), &self.dbname_, 1);
+            } else {
+                self.env_.remove_file(&tmp);
+            }
+        }
+        status
+    }
+
+    fn archive_file(&self, fname: &str) {
+        // Move into another directory.  E.g., for
+        //    dir/foo
+        // rename to
+        //    dir/lost/foo
+        let (dir, base) = match fname.rfind('/') {
+            Some(slash) => (&fname[..slash], &fname[slash + 1..]),
+            None => ("", fname),
+        };
+        let new_dir = format!("{}/lost", dir);
+        let _ = self.env_.create_dir(&new_dir);     // Ignore error
+        let new_file = format!("{}/{}", new_dir, base);
+        let s = self.env_.rename_file(fname, &new_file);
+        log(self.options_.info_log.clone(), &format!("Archiving {}: {}", fname, s.to_string()));
+    }
+}
+
+struct LogReporter {
+    info_log: Option<Arc<dyn Logger>>,
+    lognum: u64,
+}
+
+impl Reporter for LogReporter {
+    fn corruption(&self, bytes: usize, status: &Status) {
+        // We print error messages for corruption, but continue repairing.
+        log(self.info_log.clone(), &format!("Log #{}: dropping {} bytes; {}", self.lognum, bytes, status.to_string()));
+    }
+}
+
+/// If a DB cannot be opened, you may attempt to call this method to
+/// resurrect as much of the contents of the database as possible.
+/// Some data may be lost, so be careful when calling this function
+/// on a database that contains important information.
+pub fn repair_db(dbname: &str, options: &Options) -> Status {
+    Repairer::new(dbname, options).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::DB, helpers::memenv::new_mem_env, options::WriteOptions};
+
+    use super::*;
+
+    const DBNAME: &str = "/db";
+
+    fn options_with_env(env: Arc<dyn Env>) -> Options {
+        let mut options = Options::new();
+        options.env = env;
+        options.create_if_missing = true;
+        options
+    }
+
+    fn key(i: usize) -> String {
+        format!("key{:04}", i)
+    }
+
+    fn manifest_name(env: &Arc<dyn Env>) -> String {
+        let children = env.get_children(DBNAME).unwrap();
+        let manifest = children.iter().find(|f| f.starts_with("MANIFEST-")).unwrap();
+        format!("{}/{}", DBNAME, manifest)
+    }
+
+    #[test]
+    fn repair_truncated_manifest_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            for i in 0..500 {
+                assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(format!("v{}", i).as_bytes())).ok());
+            }
+            assert!(db.delete(&WriteOptions::default(), &Slice::new(key(7).as_bytes())).ok());
+        }
+
+        // Truncate the MANIFEST: the DB can no longer be opened.
+        let manifest = manifest_name(&env);
+        assert!(env.new_writable_file(&manifest).unwrap().close().ok());
+        assert!(DB::open(&options, DBNAME).is_err());
+
+        assert!(repair_db(DBNAME, &options).ok());
+        let lost = env.get_children(&format!("{}/lost", DBNAME)).unwrap();
+        assert!(lost.iter().any(|f| f.ends_with(".log")), "{:?}", lost);
+        assert!(lost.contains(&manifest.rsplit('/').next().unwrap().to_string()), "{:?}", lost);
+
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(1, db.mutex_.lock().unwrap().versions_.num_level_files(0));
+        for i in 0..500 {
+            let result = db.get(&ReadOptions::new(), &Slice::new(key(i).as_bytes()));
+            if i == 7 {
+                assert!(result.err().unwrap().is_not_found());
+            } else {
+                assert_eq!(format!("v{}", i).into_bytes(), result.unwrap());
+            }
+        }
+
+        // New writes get sequence numbers after the recovered ones.
+        assert!(db.put(&WriteOptions::default(), &Slice::new(key(1).as_bytes()), &Slice::new(b"new")).ok());
+        assert_eq!(b"new".to_vec(), db.get(&ReadOptions::new(), &Slice::new(key(1).as_bytes())).unwrap());
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        let mut count = 0;
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        assert_eq!(499, count);
+        assert!(iter.status().ok());
+    }
+
+    #[test]
+    fn repair_twice_test() {
+        let env = new_mem_env();
+        let options = options_with_env(env.clone());
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"foo"), &Slice::new(b"bar")).ok());
+        }
+        assert!(repair_db(DBNAME, &options).ok());
+        // The tables written by the first repair are picked up again.
+        assert!(repair_db(DBNAME, &options).ok());
+
+        let db = DB::open(&options, DBNAME).unwrap();
+        assert_eq!(b"bar".to_vec(), db.get(&ReadOptions::new(), &Slice::new(b"foo")).unwrap());
+    }
+
+    #[test]
+    fn repair_missing_db_test() {
+        let options = options_with_env(new_mem_env());
+        let s = repair_db(DBNAME, &options);
+        assert!(s.is_io_error());
+        assert!(s.to_string().contains("repair found no files"));
+    }
+}
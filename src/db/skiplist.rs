@@ -63,7 +63,7 @@ impl<K: PartialOrd + Clone, C: Comparator<K>> SkipList<K, C> {
         let x = self.find_greater_or_equal(&key, Some(&mut prev));
 
         // Our data structure does not allow duplicate insertion
-        debug_assert!(x.is_none() || x.unwrap().key != key);
+        debug_assert!(x.is_none() || self.compare_.compare(&x.unwrap().key, &key) != Ordering::Equal);
 
         let height = self.random_height();
         if height > self.get_max_height() {
@@ -92,7 +92,7 @@ impl<K: PartialOrd + Clone, C: Comparator<K>> SkipList<K, C> {
     pub(crate) fn contains(&self, key: &K) -> bool {
         let x = self.find_greater_or_equal(key, None);
         if let Some(n) = x {
-            n.key == *key
+            self.compare_.compare(&n.key, key) == Ordering::Equal
         } else {
             false
         }
@@ -140,7 +140,9 @@ impl<K: PartialOrd + Clone, C: Comparator<K>> SkipList<K, C> {
     fn key_is_after_node(&self, key: &K, n: NullableNodePtr<K>) -> bool {
         // None n is considered infinite
         if let Some(node) = n {
-            return node.key < *key;
+            #[cfg(feature = "arena-canaries")]
+            self.arena_.assert_live(Arc::as_ptr(&node));
+            return self.compare_.compare(&node.key, key) == Ordering::Less;
         }
         false
     }
@@ -182,7 +184,7 @@ impl<K: PartialOrd + Clone, C: Comparator<K>> SkipList<K, C> {
         loop {
             let next = x.next(level);
             if let Some(n) = next {
-                if n.key < *key {
+                if self.compare_.compare(&n.key, key) == Ordering::Less {
                     x = n;
                     continue;
                 }
@@ -244,7 +246,10 @@ impl<K: PartialOrd + Clone, C: Comparator<K>> Iter<K, C> {
     /// Returns the key at the current position.
     /// REQUIRES: Valid()
     pub(crate) fn key(&self) -> K {
-        self.node_.read().unwrap().clone().expect("require non-null").key.clone()
+        let node = self.node_.read().unwrap().clone().expect("require non-null");
+        #[cfg(feature = "arena-canaries")]
+        self.list_.arena_.assert_live(Arc::as_ptr(&node));
+        node.key.clone()
     }
 
     /// Advances to the next position.
@@ -536,7 +541,7 @@ mod tests {
             Key((k << 40) | (g << 8) | (Self::hash_number(k, g) & 0xff))
         }
         fn hash_number(k: u64, g: u64) -> u64 {
-            hash([k.to_ne_bytes(), g.to_ne_bytes()].flatten(), 0) as u64
+            hash([k.to_ne_bytes(), g.to_ne_bytes()].as_flattened(), 0) as u64
         }
     }
     // Per-key generation
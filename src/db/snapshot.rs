@@ -0,0 +1,168 @@
+use std::{collections::LinkedList, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use crate::options::SnapshotExpiry;
+
+use super::version_edit::SequenceNumber;
+
+/// Abstract handle to particular state of a DB.
+/// A Snapshot is an immutable object and can therefore be safely
+/// accessed from multiple threads without any external synchronization.
+///
+/// Snapshots are kept in a doubly-linked list in the DB.
+/// Each Snapshot corresponds to a particular sequence number.
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence_number_: SequenceNumber,
+    created_micros_: u64,
+    on_expiry_: SnapshotExpiry,
+    // Set once the snapshot is older than Options::max_snapshot_age_seconds.
+    stale_: AtomicBool,
+}
+
+impl Snapshot {
+    pub(crate) fn sequence_number(&self) -> SequenceNumber {
+        self.sequence_number_
+    }
+
+    pub(crate) fn created_micros(&self) -> u64 {
+        self.created_micros_
+    }
+
+    pub(crate) fn on_expiry(&self) -> SnapshotExpiry {
+        self.on_expiry_
+    }
+
+    pub(crate) fn stale(&self) -> bool {
+        self.stale_.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the snapshot was taken with SnapshotExpiry::Error
+    /// and has outlived Options::max_snapshot_age_seconds.  Reads through
+    /// an expired snapshot fail.
+    pub fn expired(&self) -> bool {
+        self.on_expiry_ == SnapshotExpiry::Error && self.stale()
+    }
+}
+
+pub(crate) struct SnapshotList {
+    // Ordered from oldest to newest.
+    list_: LinkedList<Arc<Snapshot>>,
+}
+
+impl SnapshotList {
+    pub(crate) fn new() -> Self {
+        Self { list_: LinkedList::new() }
+    }
+
+    pub(crate) fn empty(&self) -> bool {
+        self.list_.is_empty()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn oldest(&self) -> Arc<Snapshot> {
+        debug_assert!(!self.empty());
+        self.list_.front().unwrap().clone()
+    }
+
+    pub(crate) fn newest(&self) -> Arc<Snapshot> {
+        debug_assert!(!self.empty());
+        self.list_.back().unwrap().clone()
+    }
+
+    /// Returns the sequence number of the oldest snapshot that has not
+    /// expired, which is the oldest one compactions must preserve.
+    pub(crate) fn oldest_pinning(&self) -> Option<SequenceNumber> {
+        self.list_.iter().find(|s| !s.expired()).map(|s| s.sequence_number_)
+    }
+
+    pub(crate) fn iter(&self) -> impl std::iter::Iterator<Item = &Arc<Snapshot>> {
+        self.list_.iter()
+    }
+
+    /// Creates a Snapshot and appends it to the end of the list.
+    pub(crate) fn new_snapshot(&mut self, sequence_number: SequenceNumber, created_micros: u64,
+                               on_expiry: SnapshotExpiry) -> Arc<Snapshot> {
+        debug_assert!(self.empty() || self.newest().sequence_number_ <= sequence_number);
+        let snapshot = Arc::new(Snapshot {
+            sequence_number_: sequence_number,
+            created_micros_: created_micros,
+            on_expiry_: on_expiry,
+            stale_: AtomicBool::new(false),
+        });
+        self.list_.push_back(snapshot.clone());
+        snapshot
+    }
+
+    /// Marks the snapshots created before "cutoff_micros" as stale and
+    /// returns the ones that were not stale yet, oldest first.
+    pub(crate) fn mark_stale(&self, cutoff_micros: u64) -> Vec<Arc<Snapshot>> {
+        // Snapshots are created in order, so the stale ones are a prefix.
+        self.list_.iter()
+            .take_while(|s| s.created_micros_ < cutoff_micros)
+            .filter(|s| !s.stale_.swap(true, Ordering::AcqRel))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes a Snapshot from this list.
+    ///
+    /// The snapshot must have been created by calling new_snapshot() on
+    /// this list.
+    pub(crate) fn delete(&mut self, snapshot: &Arc<Snapshot>) {
+        let index = self.list_.iter().position(|s| Arc::ptr_eq(s, snapshot));
+        debug_assert!(index.is_some(), "snapshot does not belong to this list");
+        if let Some(index) = index {
+            let mut tail = self.list_.split_off(index);
+            tail.pop_front();
+            self.list_.append(&mut tail);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_test() {
+        let mut list = SnapshotList::new();
+        assert!(list.empty());
+        let s1 = list.new_snapshot(10, 0, SnapshotExpiry::Error);
+        let s2 = list.new_snapshot(20, 0, SnapshotExpiry::Error);
+        let s3 = list.new_snapshot(20, 0, SnapshotExpiry::Error);
+        assert_eq!(10, list.oldest().sequence_number());
+        assert!(Arc::ptr_eq(&s3, &list.newest()));
+
+        list.delete(&s2);
+        assert!(Arc::ptr_eq(&s1, &list.oldest()));
+        assert!(Arc::ptr_eq(&s3, &list.newest()));
+        list.delete(&s1);
+        assert!(Arc::ptr_eq(&s3, &list.oldest()));
+        list.delete(&s3);
+        assert!(list.empty());
+    }
+
+    #[test]
+    fn mark_stale_test() {
+        let mut list = SnapshotList::new();
+        let s1 = list.new_snapshot(10, 100, SnapshotExpiry::Error);
+        let s2 = list.new_snapshot(20, 200, SnapshotExpiry::Ignore);
+        let s3 = list.new_snapshot(30, 300, SnapshotExpiry::Error);
+        assert_eq!(Some(10), list.oldest_pinning());
+
+        assert!(list.mark_stale(100).is_empty());
+        let stale = list.mark_stale(250);
+        assert_eq!(2, stale.len());
+        assert!(Arc::ptr_eq(&s1, &stale[0]) && Arc::ptr_eq(&s2, &stale[1]));
+        assert!(s1.expired() && !s2.expired() && s2.stale() && !s3.stale());
+        // Ignored stale snapshots still pin their sequence.
+        assert_eq!(Some(20), list.oldest_pinning());
+
+        // Snapshots are reported stale only once.
+        let stale = list.mark_stale(1000);
+        assert_eq!(1, stale.len());
+        assert!(Arc::ptr_eq(&s3, &stale[0]) && s3.expired());
+        list.delete(&s2);
+        assert_eq!(None, list.oldest_pinning());
+    }
+}
@@ -0,0 +1,145 @@
+//! Estimates of how much of the space the table files take is live data,
+//! i.e. would be left after a full compaction.  See DB::space_amp_report().
+//!
+//! The entries of a table are taken to be of equal size, so a table with
+//! "n" entries of which "live" are live holds file_size * live / n bytes
+//! of live data.  An entry is not live if it is a deletion, or if a newer
+//! table holds an entry for its key.  Which keys the newer tables hold is
+//! not known: every entry they have in the key range of the table (found
+//! from approximate file offsets) is taken to overwrite one of its
+//! entries.  That fits overwrite-heavy workloads, while inserts spread
+//! over overlapping level-0 files look more amplified than they are until
+//! they are compacted.
+
+use std::{cmp::{Ordering, Reverse}, sync::Arc};
+
+use crate::{options::ReadOptions, table::Table};
+
+use super::{dbformat::{InternalKey, ValueType, MAX_SEQUENCE_NUMBER, NUM_LEVELS, VALUE_TYPE_FOR_SEEK}, version_edit::FileMetaData, version_set::Version, DbInner};
+
+/// How much more space the table files take than the live data in them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpaceAmpReport {
+    /// Size of the table files of the current version.
+    pub total_file_bytes: u64,
+
+    /// See DB::live_data_size_estimate().
+    pub estimated_live_bytes: u64,
+
+    /// Size of the table files that are no longer current but can not be
+    /// deleted yet, because open iterators or retained versions use them.
+    pub obsolete_file_bytes_pending_deletion: u64,
+
+    /// (total_file_bytes + obsolete_file_bytes_pending_deletion) /
+    /// estimated_live_bytes: 1.0 when compactions have nothing left to
+    /// reclaim.  Infinite if there are files but no live data.
+    pub space_amplification_ratio: f64,
+}
+
+impl SpaceAmpReport {
+    pub(crate) fn new(total_file_bytes: u64, estimated_live_bytes: u64, obsolete_file_bytes_pending_deletion: u64) -> Self {
+        let used = total_file_bytes + obsolete_file_bytes_pending_deletion;
+        let space_amplification_ratio = if used == 0 {
+            1.0
+        } else {
+            used as f64 / estimated_live_bytes as f64
+        };
+        Self { total_file_bytes, estimated_live_bytes, obsolete_file_bytes_pending_deletion, space_amplification_ratio }
+    }
+}
+
+/// A table whose entries may overwrite those of older tables.
+struct NewerTable<'a> {
+    meta: &'a FileMetaData,
+    table: Arc<Table>,
+
+    // Entries left after its own entries overwritten by newer tables are
+    // taken out, deletions included.
+    entries: f64,
+}
+
+impl DbInner {
+    /// Estimate how many bytes of the table files hold live data: entries
+    /// that are neither deletions nor overwritten by newer entries.  Reads
+    /// the metadata of tables that are not open yet.  See space_amp.rs for
+    /// how it is estimated.
+    pub fn live_data_size_estimate(&self) -> u64 {
+        let version = {
+            let state = self.mutex_.lock().expect("failed to acquire lock");
+            state.versions_.current()
+        };
+        self.estimate_live_bytes(&version)
+    }
+
+    /// Report how much space a full compaction could reclaim.
+    pub fn space_amp_report(&self) -> SpaceAmpReport {
+        let (version, pinned_bytes) = {
+            let state = self.mutex_.lock().expect("failed to acquire lock");
+            let versions = &state.versions_;
+            (versions.current(), versions.pinned_bytes())
+        };
+        self.space_amp_report_for(&version, pinned_bytes)
+    }
+
+    /// space_amp_report() of "version", with "pinned_bytes" in obsolete
+    /// files.
+    pub(crate) fn space_amp_report_for(&self, version: &Version, pinned_bytes: u64) -> SpaceAmpReport {
+        let total = (0..NUM_LEVELS).flat_map(|level| version.files(level)).map(|f| f.file_size).sum();
+        SpaceAmpReport::new(total, self.estimate_live_bytes(version), pinned_bytes)
+    }
+
+    fn estimate_live_bytes(&self, version: &Version) -> u64 {
+        // Newest first: level-0 files by number, then the other levels in
+        // order, each of which is older than the ones above it.
+        let mut files: Vec<&FileMetaData> = version.files(0).iter().collect();
+        files.sort_by_key(|f| Reverse(f.number));
+        for level in 1..NUM_LEVELS {
+            files.extend(version.files(level));
+        }
+
+        let ucmp = self.internal_comparator_.user_comparator();
+        let mut newer: Vec<NewerTable> = Vec::new();
+        let mut live_bytes = 0.0;
+        for f in files {
+            let table = self.table_cache_.find_table(&ReadOptions::new(), f.number, f.file_size).ok();
+            let Some((table, properties)) = table.and_then(|t| t.properties().cloned().map(|p| (t, p))) else {
+                // Without entry counts the whole table is taken as live
+                live_bytes += f.file_size as f64;
+                continue;
+            };
+            let num_entries = properties.key_sizes.num() as f64;
+            if num_entries == 0.0 {
+                continue;
+            }
+            let overwritten: f64 = newer.iter()
+                .filter(|g| ucmp.compare(&g.meta.smallest.user_key(), &f.largest.user_key()) != Ordering::Greater
+                        && ucmp.compare(&g.meta.largest.user_key(), &f.smallest.user_key()) != Ordering::Less)
+                .map(|g| g.entries * self.fraction_in_range(g, f))
+                .sum();
+            let entries = (num_entries - overwritten).max(0.0);
+            let live_entries = (entries - properties.num_deletions as f64).max(0.0);
+            live_bytes += f.file_size as f64 * live_entries / num_entries;
+            newer.push(NewerTable { meta: f, table, entries });
+        }
+        live_bytes as u64
+    }
+
+    /// Return the fraction of the bytes of table "g" that hold keys in
+    /// the user key range of "f".
+    fn fraction_in_range(&self, g: &NewerTable, f: &FileMetaData) -> f64 {
+        let ucmp = self.internal_comparator_.user_comparator();
+        let size = g.meta.file_size;
+        let start = if ucmp.compare(&f.smallest.user_key(), &g.meta.smallest.user_key()) != Ordering::Greater {
+            0
+        } else {
+            g.table.approximate_offset_of(&InternalKey::new_from(&f.smallest.user_key(), MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK).encode())
+        };
+        let end = if ucmp.compare(&f.largest.user_key(), &g.meta.largest.user_key()) != Ordering::Less {
+            size
+        } else {
+            // Past every entry for the largest user key of "f"
+            g.table.approximate_offset_of(&InternalKey::new_from(&f.largest.user_key(), 0, ValueType::type_deletion()).encode())
+        };
+        (end.saturating_sub(start) as f64 / size.max(1) as f64).min(1.0)
+    }
+}
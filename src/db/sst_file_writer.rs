@@ -0,0 +1,98 @@
+//! SstFileWriter builds table files outside of a DB, to be added to one
+//! with DB::ingest_external_file().
+//!
+//! The entries are stored the way a DB's own tables store them, with
+//! sequence number zero, and the file records the name of the comparator
+//! that ordered them.
+
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{comparator::Comparator, env::WritableFile, filter_policy::FilterPolicy, options::{DiagnosticRedaction, Options}, slice::Slice, status::Status, table::table_builder::TableBuilder, util::redact::redact};
+
+use super::{dbformat::{InternalFilterPolicy, InternalKey, InternalKeyComparator, ValueType}, sanitize_options};
+
+pub struct SstFileWriter {
+    file_: Arc<dyn WritableFile>,
+    builder_: TableBuilder,
+    comparator_: Arc<dyn Comparator>,
+    last_key_: Option<Vec<u8>>,
+    finished_: bool,
+    redaction_: DiagnosticRedaction,
+}
+
+impl SstFileWriter {
+    /// Create the table file "fname" in options.env, to be filled with
+    /// entries ordered by options.comparator.  options.filter_policy,
+    /// block_size and block_restart_interval apply as they do to the
+    /// tables of a DB.
+    pub fn create(options: &Options, fname: &str) -> Result<SstFileWriter, Status> {
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let ipolicy = options.filter_policy.clone().map(|p| Arc::new(InternalFilterPolicy::new(p)) as Arc<dyn FilterPolicy>);
+        let table_options = sanitize_options(fname, &icmp, ipolicy, options, false);
+        let file = options.env.new_writable_file(fname)?;
+        let mut builder = TableBuilder::new(&table_options, file.clone());
+        builder.set_comparator_name(options.comparator.name());
+        Ok(SstFileWriter {
+            file_: file,
+            builder_: builder,
+            comparator_: options.comparator.clone(),
+            last_key_: None,
+            finished_: false,
+            redaction_: options.diagnostics_redaction,
+        })
+    }
+
+    /// Add "key" with "value" to the file.  Fails with InvalidArgument if
+    /// "key" is not after every key added so far by the comparator.
+    /// REQUIRES: finish() has not been called
+    pub fn put(&mut self, key: &Slice, value: &Slice) -> Status {
+        debug_assert!(!self.finished_);
+        if let Some(last) = &self.last_key_ {
+            if self.comparator_.compare(key, &Slice::new(last)) != Ordering::Greater {
+                return Status::invalid_argument("keys must be added in strictly increasing order",
+                                                &redact(key.data(), self.redaction_));
+            }
+        }
+        let s = self.builder_.status();
+        if !s.ok() {
+            return s;
+        }
+        self.builder_.add(&InternalKey::new_from(key, 0, ValueType::type_value()).encode(), value);
+        self.last_key_ = Some(key.data().to_vec());
+        self.builder_.status()
+    }
+
+    /// Number of entries added so far.
+    pub fn num_entries(&self) -> u64 {
+        self.builder_.num_entries()
+    }
+
+    /// Write out the rest of the file, and sync and close it.  Fails with
+    /// InvalidArgument if no entry was added, as a DB can not take in an
+    /// empty file.
+    /// REQUIRES: finish() has not been called
+    pub fn finish(&mut self) -> Status {
+        debug_assert!(!self.finished_);
+        self.finished_ = true;
+        if self.builder_.num_entries() == 0 {
+            self.builder_.abandon();
+            return Status::invalid_argument("no entries added", "");
+        }
+        let mut s = self.builder_.finish();
+        if s.ok() {
+            s = self.file_.sync();
+        }
+        if s.ok() {
+            s = self.file_.close();
+        }
+        s
+    }
+}
+
+impl Drop for SstFileWriter {
+    fn drop(&mut self) {
+        if !self.finished_ {
+            self.builder_.abandon();
+        }
+    }
+}
@@ -0,0 +1,481 @@
+//! Counters of what a DB has done, for monitoring agents that poll them.
+//! The counters are split into groups, each with a dirty flag that the
+//! code updating the group sets with one relaxed store.  Taking a
+//! snapshot collects the flags: if any was set, the stats epoch moves on
+//! and the groups that changed remember it, so that an agent can ask for
+//! only the groups that changed since the epoch it saw last.  See
+//! DB::stats_snapshot() and DB::stats_delta().
+
+use std::{collections::BTreeMap, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Mutex}};
+
+use super::{read_amp::GetSample, CompactionStats};
+
+/// A group of counters that change together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StatsGroup {
+    /// Writes committed; see WriteStats.
+    Writes,
+    /// Gets and iterators; see ReadStats.
+    Reads,
+    /// Memtable flushes, compactions and the files they leave at each
+    /// level; see LevelStats.
+    Compaction,
+    /// Tables opened by the table cache; see TableCacheStats.
+    TableCache,
+}
+
+const NUM_GROUPS: usize = 4;
+const GROUPS: [StatsGroup; NUM_GROUPS] = [StatsGroup::Writes, StatsGroup::Reads, StatsGroup::Compaction, StatsGroup::TableCache];
+
+/// Number of buckets in a Histogram: one per bit length of a u64.
+const HISTOGRAM_BUCKETS: usize = 65;
+
+/// Counts values in log2 buckets: bucket 0 holds 0, and bucket i the
+/// values in [2^(i-1), 2^i).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets_: Vec<u64>,     // HISTOGRAM_BUCKETS of them
+    count_: u64,
+    sum_: u64,
+    max_: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { buckets_: vec![0; HISTOGRAM_BUCKETS], count_: 0, sum_: 0, max_: 0 }
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, value: u64) {
+        self.buckets_[(u64::BITS - value.leading_zeros()) as usize] += 1;
+        self.count_ += 1;
+        self.sum_ = self.sum_.saturating_add(value);
+        self.max_ = self.max_.max(value);
+    }
+
+    /// Add the values counted by "other", e.g. an earlier interval.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, &n) in self.buckets_.iter_mut().zip(&other.buckets_) {
+            *bucket += n;
+        }
+        self.count_ += other.count_;
+        self.sum_ = self.sum_.saturating_add(other.sum_);
+        self.max_ = self.max_.max(other.max_);
+    }
+
+    /// Number of values added.
+    pub fn count(&self) -> u64 {
+        self.count_
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum_
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max_
+    }
+
+    /// Return an upper bound of the smallest value at least "p" percent
+    /// of the values are at or below: the end of its bucket, or the
+    /// largest value if that is smaller.  Zero if there are none.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count_ == 0 {
+            return 0;
+        }
+        let rank = ((self.count_ as f64 * p / 100.0).ceil() as u64).clamp(1, self.count_);
+        let mut seen = 0;
+        for (bucket, &n) in self.buckets_.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let end = if bucket == HISTOGRAM_BUCKETS - 1 { u64::MAX } else { (1u64 << bucket) - 1 };
+                return end.min(self.max_);
+            }
+        }
+        self.max_
+    }
+
+    /// Render the count, average, median, 99th percentile and maximum on
+    /// one line, then the count of each non-empty bucket on a line of
+    /// its own.
+    pub(crate) fn render(&self) -> String {
+        let average = if self.count_ == 0 { 0.0 } else { self.sum_ as f64 / self.count_ as f64 };
+        let mut value = format!("count {}, average {:.2}, p50 {}, p99 {}, max {}\n",
+                                self.count_, average, self.percentile(50.0), self.percentile(99.0), self.max_);
+        for (bucket, &n) in self.buckets_.iter().enumerate().filter(|(_, &n)| n > 0) {
+            let start = if bucket == 0 { 0 } else { 1u64 << (bucket - 1) };
+            let limit = if bucket == HISTOGRAM_BUCKETS - 1 { "inf".to_string() } else { (1u64 << bucket).to_string() };
+            value.push_str(&format!("  [{}, {}): {}\n", start, limit, n));
+        }
+        value
+    }
+}
+
+/// Writes committed since the DB was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Batches written by put(), delete(), write() and the like.
+    pub batches: u64,
+    /// Entries in those batches.
+    pub entries: u64,
+    /// Bytes of those batches, as logged.
+    pub bytes: u64,
+
+    /// Sizes of the batches, in bytes, since the DB was opened or since
+    /// the last snapshot that reset it.
+    pub batch_bytes: Histogram,
+}
+
+/// Reads made since the DB was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    pub gets: u64,
+    /// Gets answered by a memtable.
+    pub memtable_hits: u64,
+    /// Data blocks gets read from tables.
+    pub blocks_read: u64,
+    pub iterators: u64,
+
+    /// Only collected with Options::lookup_statistics.
+    pub lookups: LookupStats,
+}
+
+/// How gets and seeks found their keys, since the DB was opened; see
+/// Options::lookup_statistics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    /// Gets, including those answered by a memtable.
+    pub gets: u64,
+    pub memtable_hits: u64,
+    /// Per get, the data blocks read from tables.
+    pub blocks_per_get: Histogram,
+    /// Per get, the level-0 files probed.
+    pub level0_files_per_get: Histogram,
+    /// Tables probed by gets.
+    pub table_probes: u64,
+    /// Of those, the tables that did not hold the key.
+    pub probe_misses: u64,
+    /// Data blocks read from the tables that did not hold the key: the
+    /// reads a filter could have saved.
+    pub miss_blocks_read: u64,
+    /// Seeks of DB iterators.
+    pub seeks: u64,
+    /// Seeks that positioned in more than one table.
+    pub multi_table_seeks: u64,
+}
+
+/// What a filter with some bits per key would save gets, judged by the
+/// gets counted in LookupStats; see DB::estimate_bloom_benefit().
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomBenefit {
+    pub bits_per_key: usize,
+    /// Gets the estimate is based on.
+    pub gets: u64,
+    /// Expected fraction of the tables without the key that the filter
+    /// would not rule out.
+    pub false_positive_rate: f64,
+    /// Data blocks a get read on average.
+    pub blocks_per_get: f64,
+    /// Data blocks a get read on average from tables that did not hold
+    /// the key.
+    pub miss_blocks_per_get: f64,
+    /// Data blocks the filter would save a get on average: those read in
+    /// vain, but for its false positives.
+    pub blocks_saved_per_get: f64,
+    /// blocks_saved_per_get as a fraction of blocks_per_get.
+    pub fraction_saved: f64,
+}
+
+impl LookupStats {
+    /// Estimate the reads a bloom filter with "bits_per_key" would save
+    /// the gets counted so far.  The filter is assumed to use as many
+    /// probes as BloomFilterPolicy does, and so to let a fraction
+    /// (1 - e^(-k/bits_per_key))^k of the absent keys through.  Reads
+    /// that a filter in place already saved are not counted, so the
+    /// estimate is of the saving on top of it.
+    pub fn estimate_bloom_benefit(&self, bits_per_key: usize) -> BloomBenefit {
+        let false_positive_rate = if bits_per_key == 0 {
+            1.0
+        } else {
+            let k = ((bits_per_key as f64 * 0.69) as usize).clamp(1, 30) as f64;
+            (1.0 - (-k / bits_per_key as f64).exp()).powf(k)
+        };
+        let per_get = |n: u64| if self.gets == 0 { 0.0 } else { n as f64 / self.gets as f64 };
+        let blocks_per_get = per_get(self.blocks_per_get.sum());
+        let miss_blocks_per_get = per_get(self.miss_blocks_read);
+        let blocks_saved_per_get = miss_blocks_per_get * (1.0 - false_positive_rate);
+        BloomBenefit {
+            bits_per_key,
+            gets: self.gets,
+            false_positive_rate,
+            blocks_per_get,
+            miss_blocks_per_get,
+            blocks_saved_per_get,
+            fraction_saved: if blocks_per_get == 0.0 { 0.0 } else { blocks_saved_per_get / blocks_per_get },
+        }
+    }
+}
+
+/// Memtable flushes and compactions since the DB was opened, and the
+/// files they left.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LevelStats {
+    /// Per level, as DB::compaction_stats() returns them.
+    pub compactions: Vec<CompactionStats>,
+    /// Per level, the number of table files.
+    pub files: Vec<usize>,
+    /// Per level, the bytes of those files.
+    pub bytes: Vec<u64>,
+}
+
+/// Work of the table cache since the DB was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableCacheStats {
+    /// Tables opened because they were not in the cache.
+    pub opens: u64,
+    /// Lookups that could not use a table's filter, because it was built
+    /// by another filter policy.
+    pub filter_bypasses: u64,
+    /// Lookups that found a table's filter in Options::filter_cache.
+    pub filter_cache_hits: u64,
+    /// Lookups that did not, and so read it again unless they could do no
+    /// IO.
+    pub filter_cache_misses: u64,
+    /// Filters read into Options::filter_cache.
+    pub filter_cache_loads: u64,
+    /// The most tables kept open, or None if there is no limit; see
+    /// Options::max_open_files and Options::adaptive_table_cache.
+    pub capacity: Option<u64>,
+    /// Times the adaptive table cache lowered its capacity.
+    pub shrinks: u64,
+}
+
+/// All the counters of a DB; see DB::stats_snapshot().
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DbStats {
+    /// Options::instance_name of the DB, if set.
+    pub instance_name: Option<String>,
+    pub writes: WriteStats,
+    pub reads: ReadStats,
+    pub levels: LevelStats,
+    pub table_cache: TableCacheStats,
+}
+
+/// The counters of one group; see DB::stats_delta().
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupStats {
+    Writes(WriteStats),
+    Reads(ReadStats),
+    Compaction(LevelStats),
+    TableCache(TableCacheStats),
+}
+
+impl DbStats {
+    /// Return the counters of "group".
+    pub fn group(&self, group: StatsGroup) -> GroupStats {
+        match group {
+            StatsGroup::Writes => GroupStats::Writes(self.writes.clone()),
+            StatsGroup::Reads => GroupStats::Reads(self.reads.clone()),
+            StatsGroup::Compaction => GroupStats::Compaction(self.levels.clone()),
+            StatsGroup::TableCache => GroupStats::TableCache(self.table_cache.clone()),
+        }
+    }
+}
+
+/// Changes since some stats epoch: only the groups that changed after it.
+pub type StatsDelta = BTreeMap<StatsGroup, GroupStats>;
+
+/// Epoch bookkeeping, updated when a snapshot collects the dirty flags.
+#[derive(Default)]
+struct Epochs {
+    epoch_: u64,
+    changed_at_: [u64; NUM_GROUPS], // Epoch at which each group last changed
+}
+
+/// The counters of a DB that are not kept elsewhere, and the dirty
+/// flags of every group.
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    dirty_: [AtomicBool; NUM_GROUPS],
+    epochs_: Mutex<Epochs>,
+
+    batches_: AtomicU64,
+    entries_: AtomicU64,
+    bytes_: AtomicU64,
+    batch_bytes_: Mutex<Histogram>,
+
+    gets_: AtomicU64,
+    memtable_hits_: AtomicU64,
+    blocks_read_: AtomicU64,
+    iterators_: AtomicU64,
+    lookups_: Mutex<LookupStats>,
+}
+
+impl StatsCounters {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that the counters of "group" changed.
+    pub(crate) fn mark(&self, group: StatsGroup) {
+        self.dirty_[group as usize].store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write(&self, entries: u64, bytes: u64) {
+        self.batches_.fetch_add(1, Ordering::Relaxed);
+        self.entries_.fetch_add(entries, Ordering::Relaxed);
+        self.bytes_.fetch_add(bytes, Ordering::Relaxed);
+        self.batch_bytes_.lock().unwrap().add(bytes);
+        self.mark(StatsGroup::Writes);
+    }
+
+    pub(crate) fn record_get(&self, memtable_hit: bool, blocks_read: u64) {
+        self.gets_.fetch_add(1, Ordering::Relaxed);
+        if memtable_hit {
+            self.memtable_hits_.fetch_add(1, Ordering::Relaxed);
+        }
+        self.blocks_read_.fetch_add(blocks_read, Ordering::Relaxed);
+        self.mark(StatsGroup::Reads);
+    }
+
+    /// Add a get to the lookup statistics.
+    pub(crate) fn record_lookup(&self, sample: &GetSample) {
+        let mut lookups = self.lookups_.lock().unwrap();
+        lookups.gets += 1;
+        lookups.memtable_hits += sample.memtable_hit as u64;
+        lookups.blocks_per_get.add(sample.blocks_read as u64);
+        lookups.level0_files_per_get.add(sample.files_probed[0] as u64);
+        lookups.table_probes += sample.files_probed.iter().map(|&n| n as u64).sum::<u64>();
+        lookups.probe_misses += sample.probe_misses as u64;
+        lookups.miss_blocks_read += sample.miss_blocks_read as u64;
+        self.mark(StatsGroup::Reads);
+    }
+
+    /// Add a seek that positioned in "tables" tables to the lookup
+    /// statistics.
+    pub(crate) fn record_seek(&self, tables: usize) {
+        let mut lookups = self.lookups_.lock().unwrap();
+        lookups.seeks += 1;
+        lookups.multi_table_seeks += (tables > 1) as u64;
+        self.mark(StatsGroup::Reads);
+    }
+
+    pub(crate) fn record_iterator(&self) {
+        self.iterators_.fetch_add(1, Ordering::Relaxed);
+        self.mark(StatsGroup::Reads);
+    }
+
+    /// Collect the dirty flags, moving to a new epoch if any was set, and
+    /// return the current epoch.
+    pub(crate) fn advance(&self) -> u64 {
+        let mut epochs = self.epochs_.lock().unwrap();
+        let dirty: Vec<usize> = (0..NUM_GROUPS).filter(|&i| self.dirty_[i].swap(false, Ordering::Relaxed)).collect();
+        if !dirty.is_empty() {
+            epochs.epoch_ += 1;
+            for i in dirty {
+                epochs.changed_at_[i] = epochs.epoch_;
+            }
+        }
+        epochs.epoch_
+    }
+
+    /// Return the groups that changed after "epoch", as of the last
+    /// advance().
+    pub(crate) fn changed_since(&self, epoch: u64) -> Vec<StatsGroup> {
+        let epochs = self.epochs_.lock().unwrap();
+        GROUPS.into_iter().filter(|&group| epochs.changed_at_[group as usize] > epoch).collect()
+    }
+
+    /// Return the write counters.  With "reset", batch_bytes starts a
+    /// new interval: batches recorded from now on are counted only in
+    /// the next one.
+    pub(crate) fn writes(&self, reset: bool) -> WriteStats {
+        let batch_bytes = {
+            let mut histogram = self.batch_bytes_.lock().unwrap();
+            if reset && histogram.count() > 0 {
+                // The histogram the next snapshot sees differs
+                self.mark(StatsGroup::Writes);
+                std::mem::take(&mut *histogram)
+            } else {
+                histogram.clone()
+            }
+        };
+        WriteStats {
+            batches: self.batches_.load(Ordering::Relaxed),
+            entries: self.entries_.load(Ordering::Relaxed),
+            bytes: self.bytes_.load(Ordering::Relaxed),
+            batch_bytes,
+        }
+    }
+
+    pub(crate) fn reads(&self) -> ReadStats {
+        ReadStats {
+            gets: self.gets_.load(Ordering::Relaxed),
+            memtable_hits: self.memtable_hits_.load(Ordering::Relaxed),
+            blocks_read: self.blocks_read_.load(Ordering::Relaxed),
+            iterators: self.iterators_.load(Ordering::Relaxed),
+            lookups: self.lookups_.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_test() {
+        let mut histogram = Histogram::new();
+        assert_eq!((0, 0), (histogram.count(), histogram.percentile(50.0)));
+        for value in 1..=100 {
+            histogram.add(value);
+        }
+        assert_eq!((100, 5050, 100), (histogram.count(), histogram.sum(), histogram.max()));
+        assert_eq!(63, histogram.percentile(50.0));     // In [32, 64)
+        assert_eq!(100, histogram.percentile(99.0));    // In [64, 128), capped at the max
+        assert_eq!(1, histogram.percentile(1.0));
+
+        let mut other = Histogram::new();
+        other.add(0);
+        other.add(u64::MAX);
+        histogram.merge(&other);
+        assert_eq!((102, u64::MAX, u64::MAX), (histogram.count(), histogram.sum(), histogram.max()));
+        assert_eq!(0, { let mut h = Histogram::new(); h.add(0); h.percentile(100.0) });
+        assert_eq!(u64::MAX, histogram.percentile(100.0));
+    }
+
+    #[test]
+    fn epochs_test() {
+        let counters = StatsCounters::new();
+        assert_eq!(0, counters.advance());
+        assert!(counters.changed_since(0).is_empty());
+
+        counters.record_write(3, 100);
+        counters.mark(StatsGroup::TableCache);
+        assert_eq!(1, counters.advance());
+        assert_eq!(1, counters.advance());
+        assert_eq!(vec![StatsGroup::Writes, StatsGroup::TableCache], counters.changed_since(0));
+        assert!(counters.changed_since(1).is_empty());
+
+        counters.record_iterator();
+        assert_eq!(2, counters.advance());
+        assert_eq!(vec![StatsGroup::Reads], counters.changed_since(1));
+        assert_eq!(vec![StatsGroup::Writes, StatsGroup::Reads, StatsGroup::TableCache], counters.changed_since(0));
+        let writes = counters.writes(false);
+        assert_eq!((1, 3, 100), (writes.batches, writes.entries, writes.bytes));
+        assert_eq!((1, 100), (writes.batch_bytes.count(), writes.batch_bytes.max()));
+
+        // Resetting the histogram starts an epoch of its own
+        assert_eq!(1, counters.writes(true).batch_bytes.count());
+        assert_eq!(3, counters.advance());
+        assert_eq!(vec![StatsGroup::Writes], counters.changed_since(2));
+        assert_eq!(0, counters.writes(true).batch_bytes.count());
+        assert_eq!(3, counters.advance());
+        assert_eq!(1, counters.writes(false).batches);
+    }
+}
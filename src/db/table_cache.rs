@@ -0,0 +1,506 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}};
+
+use crate::{env::Env, iterator::{new_error_iterator, Iterator, ValueLocation}, options::{DiagnosticRedaction, Options, ReadOptions}, slice::Slice, status::{Status, SubCode}, table::{FilterCacheCounters, KeyValue, Table}};
+
+use super::{filename::{sst_table_file_name, table_file_name}, version_edit::FileMetaData};
+
+/// Keeps the tables of a DB open so that their index blocks are read
+/// only once.  Tables stay open until they are evicted, which happens
+/// when the file they belong to is deleted, or when the cache is over
+/// its capacity (see Options::max_open_files and
+/// Options::adaptive_table_cache) and they are the least recently used.
+/// Safe to share between threads: tables are opened outside of the
+/// lock, so a table may be opened twice by concurrent lookups, and one
+/// of them is kept.
+pub(crate) struct TableCache {
+    env_: Arc<dyn Env>,
+    dbname_: String,
+    options_: Options,
+    cache_: Mutex<CacheState>,
+
+    // Times the capacity of an adaptive cache was lowered.
+    shrinks_: AtomicU64,
+
+    // Lookups into tables whose filter was built by a policy other than
+    // the configured one, and so could not be used.
+    filter_bypasses_: AtomicU64,
+
+    // Lookups of the tables' filters in Options::filter_cache.
+    filter_counters_: Arc<FilterCacheCounters>,
+
+    // Tables opened because they were not cached.
+    opens_: AtomicU64,
+    // Have opens_ or filter_bypasses_ changed since take_changed()?
+    changed_: AtomicBool,
+}
+
+struct CachedTable {
+    table: Arc<Table>,
+    last_use: u64,      // Key of the table in CacheState::lru_
+}
+
+struct CacheState {
+    tables_: HashMap<u64, CachedTable>,
+    // File numbers by last use, oldest first
+    lru_: BTreeMap<u64, u64>,
+    clock_: u64,
+
+    // Most tables kept open; usize::MAX for no limit.
+    capacity_: usize,
+
+    // Set when a table could not be opened because the process had too
+    // many files open: the capacity the cache shrank to, and the room
+    // the Env reported then.  Caps the capacity until the Env reports
+    // more room.
+    emfile_cap_: Option<(usize, u64)>,
+}
+
+impl TableCache {
+    pub(crate) fn new(dbname: &str, options: &Options) -> Self {
+        let capacity = if options.max_open_files > 0 { options.max_open_files } else { usize::MAX };
+        let cache = Self {
+            env_: options.env.clone(),
+            dbname_: dbname.to_string(),
+            options_: options.clone(),
+            cache_: Mutex::new(CacheState { tables_: HashMap::new(), lru_: BTreeMap::new(), clock_: 0, capacity_: capacity, emfile_cap_: None }),
+            shrinks_: AtomicU64::new(0),
+            filter_bypasses_: AtomicU64::new(0),
+            filter_counters_: Arc::default(),
+            opens_: AtomicU64::new(0),
+            changed_: AtomicBool::new(false),
+        };
+        if options.adaptive_table_cache {
+            let mut state = cache.cache_.lock().unwrap();
+            state.capacity_ = cache.target(&mut state);
+        }
+        cache
+    }
+
+    /// Return an iterator for the specified file number (the corresponding
+    /// file length must be exactly "file_size" bytes), read at "level" if
+    /// it is in one.
+    pub(crate) fn new_iterator(&self, options: &ReadOptions, level: Option<i32>, file_number: u64, file_size: u64) -> Box<dyn Iterator> {
+        match self.find_table(options, file_number, file_size) {
+            Ok(table) => table.new_file_iterator(options, level, file_number, file_size),
+            Err(s) => new_error_iterator(s),
+        }
+    }
+
+    /// Return a copy of the value at "location", in a table file of
+    /// "level"; see DB::read_value().
+    pub(crate) fn read_value(&self, options: &ReadOptions, level: i32, location: &ValueLocation) -> Result<Vec<u8>, Status> {
+        let table = self.find_table(options, location.file_number, location.file_size)?;
+        table.read_value(options, Some(level), location)
+    }
+
+    /// If a seek to internal key "k" in file "f" of "level" finds an entry,
+    /// return a copy of its key and value.  With "no_io", fails with an
+    /// Incomplete status instead of opening the table or reading a block.
+    /// Adds the number of data blocks read to "blocks_read".
+    pub(crate) fn get(&self, options: &ReadOptions, level: i32, f: &FileMetaData,
+                      k: &Slice, no_io: bool, blocks_read: &mut u32) -> Result<Option<KeyValue>, Status> {
+        let cached = self.lookup(f.number);
+        let table = match cached {
+            Some(table) => table,
+            None if no_io => { return Err(Status::incomplete("table not open", &f.number.to_string())); },
+            None => self.find_table(options, f.number, f.file_size)?,
+        };
+        if table.filter_name().is_some() && !table.filter_usable() {
+            self.filter_bypasses_.fetch_add(1, Ordering::Relaxed);
+            self.changed_.store(true, Ordering::Relaxed);
+        }
+        table.internal_get(options, Some(level), k, no_io, blocks_read)
+    }
+
+    /// Number of lookups that could not use the table's filter because it
+    /// was built by a different filter policy (or none is configured).
+    pub(crate) fn filter_bypasses(&self) -> u64 {
+        self.filter_bypasses_.load(Ordering::Relaxed)
+    }
+
+    /// Lookups of filters in Options::filter_cache, by the tables this
+    /// cache opened.
+    pub(crate) fn filter_cache_counters(&self) -> &FilterCacheCounters {
+        &self.filter_counters_
+    }
+
+    /// Number of tables opened because they were not in the cache.
+    pub(crate) fn opens(&self) -> u64 {
+        self.opens_.load(Ordering::Relaxed)
+    }
+
+    /// Return true if a table was opened, a filter bypassed or looked up
+    /// in the filter cache since the last call.
+    pub(crate) fn take_changed(&self) -> bool {
+        self.changed_.swap(false, Ordering::Relaxed) | self.filter_counters_.take_changed()
+    }
+
+    /// The most tables the cache keeps open, or None if there is no
+    /// limit.
+    pub(crate) fn capacity(&self) -> Option<u64> {
+        let capacity = self.cache_.lock().unwrap().capacity_;
+        (capacity != usize::MAX).then_some(capacity as u64)
+    }
+
+    /// Number of times an adaptive cache lowered its capacity.
+    pub(crate) fn shrinks(&self) -> u64 {
+        self.shrinks_.load(Ordering::Relaxed)
+    }
+
+    /// Bytes held in memory by the open tables.
+    pub(crate) fn approximate_memory_usage(&self) -> usize {
+        self.cache_.lock().unwrap().tables_.values().map(|cached| cached.table.approximate_memory_usage()).sum()
+    }
+
+    pub(crate) fn env(&self) -> &dyn Env {
+        self.env_.as_ref()
+    }
+
+    /// How user keys are shown in the errors of lookups (see
+    /// Options::diagnostics_redaction).
+    pub(crate) fn redaction(&self) -> DiagnosticRedaction {
+        self.options_.diagnostics_redaction
+    }
+
+    /// Evict any entry for the specified file number
+    pub(crate) fn evict(&self, file_number: u64) {
+        let mut state = self.cache_.lock().unwrap();
+        if let Some(cached) = state.tables_.remove(&file_number) {
+            state.lru_.remove(&cached.last_use);
+        }
+        if self.options_.adaptive_table_cache {
+            self.retarget(&mut state);
+        }
+    }
+
+    pub(crate) fn find_table(&self, options: &ReadOptions, file_number: u64, file_size: u64) -> Result<Arc<Table>, Status> {
+        if let Some(table) = self.lookup(file_number) {
+            return Ok(table);
+        }
+        let s = options.check_deadline(self.env_.as_ref());
+        if !s.ok() {
+            return Err(s);
+        }
+
+        let table = match self.open_table(file_number, file_size) {
+            Err(s) if self.options_.adaptive_table_cache && s.subcode() == SubCode::TooManyOpenFiles => {
+                // Close tables to make room, and try once more.
+                self.shrink_for_open_failure();
+                self.open_table(file_number, file_size)?
+            },
+            result => result?,
+        };
+        self.opens_.fetch_add(1, Ordering::Relaxed);
+        self.changed_.store(true, Ordering::Relaxed);
+
+        let mut state = self.cache_.lock().unwrap();
+        let table = match state.tables_.get(&file_number) {
+            Some(cached) => cached.table.clone(),
+            None => {
+                state.clock_ += 1;
+                let last_use = state.clock_;
+                state.tables_.insert(file_number, CachedTable { table: table.clone(), last_use });
+                state.lru_.insert(last_use, file_number);
+                table
+            },
+        };
+        if self.options_.adaptive_table_cache {
+            self.retarget(&mut state);
+        }
+        Self::evict_to_capacity(&mut state);
+        Ok(table)
+    }
+
+    /// Return the cached table of "file_number", if any, marking it as
+    /// the most recently used.
+    fn lookup(&self, file_number: u64) -> Option<Arc<Table>> {
+        let mut state = self.cache_.lock().unwrap();
+        state.clock_ += 1;
+        let last_use = state.clock_;
+        let cached = state.tables_.get_mut(&file_number)?;
+        let previous_use = std::mem::replace(&mut cached.last_use, last_use);
+        let table = cached.table.clone();
+        state.lru_.remove(&previous_use);
+        state.lru_.insert(last_use, file_number);
+        Some(table)
+    }
+
+    /// Close the least recently used tables until the cache is within its
+    /// capacity.
+    fn evict_to_capacity(state: &mut CacheState) {
+        while state.tables_.len() > state.capacity_ {
+            let Some((_, oldest)) = state.lru_.pop_first() else {
+                break;
+            };
+            state.tables_.remove(&oldest);
+        }
+    }
+
+    /// The number of tables the Env's open file limit leaves room for,
+    /// besides the other files open through it, if it reports a limit.
+    fn room(&self, state: &CacheState) -> Option<u64> {
+        let (open, soft_limit) = self.env_.open_file_limit()?;
+        let others = open.saturating_sub(state.tables_.len() as u64);
+        Some(soft_limit.saturating_sub(self.options_.adaptive_table_cache_headroom as u64).saturating_sub(others))
+    }
+
+    /// Set the capacity of an adaptive cache to its target.  Evicts
+    /// nothing.
+    fn retarget(&self, state: &mut CacheState) {
+        let target = self.target(state);
+        if target < state.capacity_ {
+            self.shrinks_.fetch_add(1, Ordering::Relaxed);
+            self.changed_.store(true, Ordering::Relaxed);
+        }
+        state.capacity_ = target;
+    }
+
+    /// The capacity of an adaptive cache: what the Env's open file limit
+    /// leaves room for, within max_open_files.
+    fn target(&self, state: &mut CacheState) -> usize {
+        let mut target = if self.options_.max_open_files > 0 { self.options_.max_open_files } else { usize::MAX };
+        if let Some(room) = self.room(state) {
+            if state.emfile_cap_.is_some_and(|(_, emfile_room)| room > emfile_room) {
+                // The pressure has eased since the failed open.
+                state.emfile_cap_ = None;
+            }
+            // Keep one table open, so that reads make progress.
+            target = target.min(room.max(1).try_into().unwrap_or(usize::MAX));
+        }
+        if let Some((cap, _)) = state.emfile_cap_ {
+            target = target.min(cap);
+        }
+        target
+    }
+
+    /// After a table could not be opened because the process has too
+    /// many files open, close half of the open tables (the least recently
+    /// used ones), and keep the cache that small until the Env reports
+    /// more room.
+    fn shrink_for_open_failure(&self) {
+        let mut state = self.cache_.lock().unwrap();
+        let cap = (state.tables_.len() / 2).max(1);
+        let room = self.room(&state).unwrap_or(0);
+        state.emfile_cap_ = Some((cap, room));
+        self.retarget(&mut state);
+        Self::evict_to_capacity(&mut state);
+    }
+
+    fn open_table(&self, file_number: u64, file_size: u64) -> Result<Arc<Table>, Status> {
+        let fname = table_file_name(&self.dbname_, file_number);
+        let file = match self.env_.new_random_access_file(&fname) {
+            Ok(file) => file,
+            Err(s) => {
+                // Fall back to the legacy file name, but report the
+                // original error if that does not exist either.
+                let old_fname = sst_table_file_name(&self.dbname_, file_number);
+                self.env_.new_random_access_file(&old_fname).map_err(|_| s)?
+            },
+        };
+        // We do not cache error results so that if the error is transient,
+        // or somebody repairs the file, we recover automatically.
+        Table::open_with_filter_counters(&self.options_, file, file_size, self.filter_counters_.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use crate::{db::dbformat::{InternalKey, ValueType}, env::{FileLock, RandomAccessFile, SequentialFile, WritableFile}, helpers::memenv::new_mem_env, table::table_builder::TableBuilder};
+
+    use super::*;
+
+    const DBNAME: &str = "/db";
+
+    /// An in-memory Env with a settable soft open file limit, that can
+    /// fail the next table open as if the process had too many files open.
+    struct LimitedEnv {
+        base_: Arc<dyn Env>,
+        soft_limit_: AtomicU64,
+        limit_reads_: AtomicU64,
+        fail_next_open_: AtomicBool,
+    }
+
+    impl Env for LimitedEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+            if self.fail_next_open_.swap(false, Ordering::SeqCst) {
+                return Err(Status::io_error(fname, "Too many open files").with_subcode(SubCode::TooManyOpenFiles));
+            }
+            self.base_.new_random_access_file(fname)
+        }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn open_file_limit(&self) -> Option<(u64, u64)> {
+            self.limit_reads_.fetch_add(1, Ordering::SeqCst);
+            let (open, _) = self.base_.open_file_limit()?;
+            Some((open, self.soft_limit_.load(Ordering::SeqCst)))
+        }
+        fn now_micros(&self) -> u64 { self.base_.now_micros() }
+        fn sleep_for_microseconds(&self, micros: u64) { self.base_.sleep_for_microseconds(micros) }
+    }
+
+    fn new_limited_env(soft_limit: u64) -> Arc<LimitedEnv> {
+        Arc::new(LimitedEnv { base_: new_mem_env(), soft_limit_: AtomicU64::new(soft_limit), limit_reads_: AtomicU64::new(0), fail_next_open_: AtomicBool::new(false) })
+    }
+
+    fn options_with_env(env: Arc<dyn Env>, adaptive: bool, max_open_files: usize) -> Options {
+        let mut options = Options::new();
+        options.env = env;
+        options.adaptive_table_cache = adaptive;
+        options.adaptive_table_cache_headroom = 2;
+        options.max_open_files = max_open_files;
+        options
+    }
+
+    /// Write "n" tables of one entry each, numbered from 1, and return
+    /// their sizes.
+    fn build_tables(options: &Options, n: u64) -> Vec<u64> {
+        (1..=n).map(|number| {
+            let file = options.env.new_writable_file(&table_file_name(DBNAME, number)).unwrap();
+            let mut builder = TableBuilder::new(options, file.clone());
+            let key = InternalKey::new_from(&Slice::new(format!("key{:04}", number).as_bytes()), 1, ValueType::type_value());
+            builder.add(&key.encode(), &Slice::new(b"value"));
+            assert!(builder.finish().ok());
+            assert!(file.close().ok());
+            builder.file_size()
+        }).collect()
+    }
+
+    fn find(cache: &TableCache, number: u64, sizes: &[u64]) {
+        assert!(cache.find_table(&ReadOptions::new(), number, sizes[number as usize - 1]).is_ok());
+    }
+
+    fn cached(cache: &TableCache) -> usize {
+        cache.cache_.lock().unwrap().tables_.len()
+    }
+
+    #[test]
+    fn lru_eviction_test() {
+        let options = options_with_env(new_mem_env(), false, 3);
+        let sizes = build_tables(&options, 4);
+        let cache = TableCache::new(DBNAME, &options);
+        assert_eq!(Some(3), cache.capacity());
+        for number in [1, 2, 3, 1, 4] {
+            find(&cache, number, &sizes);
+        }
+        assert_eq!(3, cached(&cache));
+        assert_eq!(4, cache.opens());
+        // 2 was the least recently used table when 4 was opened.
+        find(&cache, 1, &sizes);
+        find(&cache, 3, &sizes);
+        assert_eq!(4, cache.opens());
+        find(&cache, 2, &sizes);
+        assert_eq!(5, cache.opens());
+        assert_eq!(0, cache.shrinks());
+    }
+
+    #[test]
+    fn unbounded_test() {
+        let env = new_limited_env(8);
+        let options = options_with_env(env.clone(), false, 0);
+        let sizes = build_tables(&options, 20);
+        let cache = TableCache::new(DBNAME, &options);
+        for _ in 0..2 {
+            for number in 1..=20 {
+                find(&cache, number, &sizes);
+            }
+        }
+        // Without adaptive sizing, the open file limit is not consulted.
+        assert_eq!(None, cache.capacity());
+        assert_eq!(20, cached(&cache));
+        assert_eq!(20, cache.opens());
+        assert_eq!(0, env.limit_reads_.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn adaptive_capacity_test() {
+        // Room for 12 - 2 (headroom) = 10 tables.
+        let env = new_limited_env(12);
+        let options = options_with_env(env.clone(), true, 0);
+        let sizes = build_tables(&options, 20);
+        let cache = TableCache::new(DBNAME, &options);
+        for number in 1..=20 {
+            find(&cache, number, &sizes);
+            assert!(cached(&cache) <= 10);
+        }
+        assert_eq!(Some(10), cache.capacity());
+        assert_eq!(10, cached(&cache));
+        assert_eq!(0, cache.shrinks());
+
+        // Other files open through the Env leave less room: the cache
+        // follows within one open.
+        let others: Vec<_> = (0..4).map(|i| env.new_writable_file(&format!("{}/other{}", DBNAME, i)).unwrap()).collect();
+        find(&cache, 1, &sizes);
+        assert_eq!(Some(6), cache.capacity());
+        assert_eq!(6, cached(&cache));
+        assert_eq!(1, cache.shrinks());
+
+        // And grows back once they are closed.
+        drop(others);
+        find(&cache, 2, &sizes);
+        assert_eq!(Some(10), cache.capacity());
+        assert_eq!(1, cache.shrinks());
+
+        // max_open_files still caps the capacity.
+        env.soft_limit_.store(1000, Ordering::SeqCst);
+        let options = options_with_env(env.clone(), true, 5);
+        let cache = TableCache::new(DBNAME, &options);
+        for number in 1..=20 {
+            find(&cache, number, &sizes);
+        }
+        assert_eq!(Some(5), cache.capacity());
+        assert_eq!(5, cached(&cache));
+    }
+
+    #[test]
+    fn adaptive_too_many_open_files_test() {
+        let env = new_limited_env(1000);
+        let options = options_with_env(env.clone(), true, 0);
+        let sizes = build_tables(&options, 20);
+        let cache = TableCache::new(DBNAME, &options);
+        for number in 1..=8 {
+            find(&cache, number, &sizes);
+        }
+        assert_eq!(Some(998), cache.capacity());
+
+        // The process runs out of files while others are open: half of
+        // the tables are closed, and the open is retried.
+        let others: Vec<_> = (0..3).map(|i| env.new_writable_file(&format!("{}/other{}", DBNAME, i)).unwrap()).collect();
+        env.fail_next_open_.store(true, Ordering::SeqCst);
+        find(&cache, 9, &sizes);
+        assert_eq!(Some(4), cache.capacity());
+        assert_eq!(4, cached(&cache));
+        assert_eq!(1, cache.shrinks());
+        assert_eq!(9, cache.opens());
+        for number in 10..=20 {
+            find(&cache, number, &sizes);
+        }
+        assert_eq!(4, cached(&cache));
+
+        // Once files are closed, the cache may grow again.
+        drop(others);
+        find(&cache, 1, &sizes);
+        assert_eq!(Some(998), cache.capacity());
+        for number in 1..=20 {
+            find(&cache, number, &sizes);
+        }
+        assert_eq!(20, cached(&cache));
+
+        // Non-adaptive caches report the error.
+        let cache = TableCache::new(DBNAME, &options_with_env(env.clone(), false, 0));
+        env.fail_next_open_.store(true, Ordering::SeqCst);
+        let s = cache.find_table(&ReadOptions::new(), 1, sizes[0]).err().unwrap();
+        assert_eq!(SubCode::TooManyOpenFiles, s.subcode());
+    }
+}
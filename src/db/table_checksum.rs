@@ -0,0 +1,61 @@
+//! Checks of the table files of a DB against the whole-file checksums
+//! recorded for them in the manifest.  See DB::verify_table_checksums().
+//!
+//! Block checksums only cover what a read of the table looks at, so they
+//! miss damage outside the blocks, such as bytes appended to a file after
+//! it was written.  Every table written, compacted, repaired or ingested
+//! since whole-file checksums were introduced has the crc32c of its
+//! contents recorded next to its size; tables recorded by older versions
+//! have none and can only be reported as unchecked.
+
+use crate::{env::log, status::Status, util::env::checksum_file};
+
+use super::{dbformat::NUM_LEVELS, filename::table_file_name, DbInner};
+
+/// What DB::verify_table_checksums() found, by table file number.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableChecksumReport {
+    /// Tables whose size and contents match what was recorded.
+    pub verified: Vec<u64>,
+
+    /// Tables recorded without a checksum, which could not be checked.
+    pub unchecked: Vec<u64>,
+
+    /// Tables whose size or contents differ from what was recorded.
+    pub mismatched: Vec<u64>,
+}
+
+impl DbInner {
+    /// Read every table file of the current version whole and compare it
+    /// with the checksum recorded for it.  Mismatches are reported, and
+    /// logged, rather than failing the call; an error is only returned if
+    /// a file could not be read.
+    pub fn verify_table_checksums(&self) -> Result<TableChecksumReport, Status> {
+        self.check_open()?;
+        // The version keeps its files from being deleted while they are
+        // read without the lock.
+        let version = {
+            let state = self.mutex_.lock().expect("failed to acquire lock");
+            state.versions_.current()
+        };
+        let mut report = TableChecksumReport::default();
+        for level in 0..NUM_LEVELS {
+            for f in version.files(level) {
+                let Some(expected) = f.file_checksum else {
+                    report.unchecked.push(f.number);
+                    continue;
+                };
+                let (crc, size) = checksum_file(self.env_.clone(), &table_file_name(&self.dbname_, f.number))?;
+                if crc == expected && size == f.file_size {
+                    report.verified.push(f.number);
+                } else {
+                    log(self.options_.info_log.clone(),
+                        &format!("Table #{} does not match its checksum: {} bytes, crc {:#010x}; expected {} bytes, crc {:#010x}",
+                                 f.number, size, crc, f.file_size, expected));
+                    report.mismatched.push(f.number);
+                }
+            }
+        }
+        Ok(report)
+    }
+}
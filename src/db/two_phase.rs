@@ -0,0 +1,254 @@
+//! A DB's part in a transaction across several DBs; see MultiDbWriteBatch,
+//! which drives it.
+//!
+//! A participant logs its share of the transaction as a prepared batch,
+//! synced but not applied, and, once every participant has prepared,
+//! logs it again as a committed batch, which is applied like any write.
+//! A transaction that does not get that far is rolled back.  The log
+//! holding the prepare record is kept until the transaction is released,
+//! so that after a crash the other participants' logs still tell how it
+//! ended.
+//!
+//! DB::open() sets aside the batches it finds prepared but neither
+//! committed nor rolled back, and has Options::prepared_batch_resolver
+//! decide each.  It then logs the outcome in the new log, together with
+//! the batch if it commits, so that the other participants can still
+//! learn it there until the prepare record is gone.
+//!
+//! These writes go through neither Options::batch_transformer nor the
+//! range locks, and are always synced.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{db::{features, filename::{current_file_name, log_file_name, parse_file_name, FileType}, log_reader::Reader, DbInner, DbState}, env::{log, Env}, slice::{escape_bytes, Slice}, status::Status, write_batch::{self, TransactionId, TransactionRecord, WriteBatch}};
+
+/// A batch recovery found prepared, waiting for its transaction to end.
+pub(super) struct PreparedBatch {
+    pub(super) batch: WriteBatch,
+    pub(super) participant: u32,
+    pub(super) participants: u32,
+}
+
+impl DbInner {
+    /// Log "batch", the share of participant "participant" (of
+    /// "participants") in transaction "txn", without applying it, and
+    /// keep the log until release_transaction() is called for "txn".
+    pub(crate) fn prepare_transaction(&self, txn: &TransactionId, participant: u32, participants: u32, batch: &WriteBatch) -> Status {
+        self.transaction_write("prepare_transaction", batch, |db, state| {
+            if state.prepared_.contains_key(txn) {
+                return Status::invalid_argument("transaction is already prepared", &escape_bytes(txn));
+            }
+            let mut prepared = batch.clone();
+            prepared.set_transaction_record(&TransactionRecord::Prepare { txn: *txn, participant, participants });
+            let s = db.log_synced(state, &prepared);
+            if s.ok() {
+                let logfile_number = state.logfile_number_;
+                state.prepared_.insert(*txn, logfile_number);
+            }
+            s
+        })
+    }
+
+    /// Log and apply "batch", the share prepared for transaction "txn".
+    /// REQUIRES: "txn" was prepared by prepare_transaction() with "batch"
+    pub(crate) fn commit_transaction(&self, txn: &TransactionId, batch: &WriteBatch) -> Status {
+        let s = self.transaction_write("commit_transaction", batch, |db, state| {
+            if !state.prepared_.contains_key(txn) {
+                return Status::invalid_argument("transaction is not prepared", &escape_bytes(txn));
+            }
+            db.commit_locked(state, *txn, batch.clone())
+        });
+        if s.ok() {
+            self.stats_counters_.record_write(batch.count() as u64, batch.byte_size() as u64);
+        }
+        s
+    }
+
+    /// Log that transaction "txn" is abandoned, and release it.  Logs the
+    /// record even if "txn" is not known to be prepared: its prepare may
+    /// have reached the log even though it failed.
+    pub(crate) fn rollback_transaction(&self, txn: &TransactionId) -> Status {
+        self.transaction_write("rollback_transaction", &WriteBatch::new(), |db, state| {
+            let mut marker = WriteBatch::new();
+            marker.set_transaction_record(&TransactionRecord::Rollback(*txn));
+            let s = db.log_synced(state, &marker);
+            if s.ok() {
+                state.prepared_.remove(txn);
+            }
+            s
+        })
+    }
+
+    /// Stop keeping the log with the prepare record of transaction "txn",
+    /// once every participant has committed it.
+    pub(crate) fn release_transaction(&self, txn: &TransactionId) {
+        let mut state = self.mutex_.lock().expect("failed to acquire lock");
+        state.prepared_.remove(txn);
+    }
+
+    /// Run "f" on the state as the only writer, with room made for
+    /// "batch" and the features it needs recorded.
+    fn transaction_write(&self, op: &str, batch: &WriteBatch, f: impl FnOnce(&DbInner, &mut DbState) -> Status) -> Status {
+        if let Err(s) = self.check_open() {
+            return s;
+        }
+        if self.options_.replica_mode {
+            return Status::not_supported(op, "database is opened in replica mode");
+        }
+        if self.read_only_ {
+            return Status::not_supported(op, "database is opened read-only");
+        }
+        let (state, w) = self.begin_exclusive_write();
+        let (mut state, mut s) = self.make_room_for_write(state, batch.approximate_size() as u64);
+        if s.ok() {
+            // Builds that do not know the transaction records must not
+            // apply a prepared batch as it is.
+            s = self.require_features(&mut state, features::TWO_PHASE_COMMIT);
+        }
+        if s.ok() && batch.has_range_deletions() {
+            s = self.require_features(&mut state, features::RANGE_DELETIONS);
+        }
+        if s.ok() {
+            s = f(self, &mut state);
+        }
+        self.end_exclusive_write(&mut state, &w);
+        s
+    }
+
+    /// Log "batch" with a commit record for "txn", at the next sequence
+    /// numbers, and apply it to mem_.
+    /// REQUIRES: "state" is mutex_'s, and no writer is logging
+    fn commit_locked(&self, state: &mut DbState, txn: TransactionId, mut batch: WriteBatch) -> Status {
+        batch.set_transaction_record(&TransactionRecord::Commit(txn));
+        let first_sequence = state.versions_.last_sequence() + 1;
+        batch.set_sequence(first_sequence);
+        let mut s = self.log_synced(state, &batch);
+        if s.ok() {
+            s = batch.insert_into(state.mem_.as_ref().unwrap());
+            self.note_memtable_write(state);
+        }
+        if s.ok() {
+            state.versions_.set_last_sequence(first_sequence + batch.count() as u64 - 1);
+        }
+        s
+    }
+
+    /// Add "batch" to the log and sync it.  A failure is handled as a
+    /// group commit's is.
+    /// REQUIRES: "state" is mutex_'s, and no writer is logging
+    fn log_synced(&self, state: &mut DbState, batch: &WriteBatch) -> Status {
+        let mut s = state.log_.as_mut().unwrap().add_record(&batch.contents());
+        if !s.ok() {
+            if self.options_.paranoid_checks {
+                self.record_background_error(state, &s);
+            }
+            return s;
+        }
+        state.log_records_ += 1;
+        s = state.logfile_.as_ref().unwrap().sync();
+        if !s.ok() {
+            // As in lead_write_group(): the log cannot be trusted past
+            // its last good sync.
+            self.record_background_error(state, &s);
+            state.log_ = None;
+            state.logfile_ = None;
+        }
+        s
+    }
+
+    /// The number of the oldest log still needed once the memtables
+    /// logged before the current log are flushed: the current one, or an
+    /// older one with the prepare record of a transaction not released.
+    /// REQUIRES: "state" is mutex_'s
+    pub(super) fn oldest_needed_log(state: &DbState) -> u64 {
+        state.prepared_.values().copied().fold(state.logfile_number_, u64::min)
+    }
+
+    /// Have Options::prepared_batch_resolver decide the transactions of
+    /// the batches in "prepared", which recovery found prepared but
+    /// neither committed nor rolled back.  A read-only DB applies the
+    /// committed ones to mem_; any other leaves the outcomes in
+    /// state.resolved_ for log_resolved_transactions().  Fails with
+    /// InvalidArgument if there is a batch and no resolver.
+    /// REQUIRES: "state" is mutex_'s, and DB::open() is recovering
+    pub(super) fn resolve_prepared_transactions(&self, state: &mut DbState, prepared: BTreeMap<TransactionId, PreparedBatch>) -> Status {
+        if prepared.is_empty() {
+            return Status::new_ok();
+        }
+        let Some(resolver) = self.options_.prepared_batch_resolver.clone() else {
+            return Status::invalid_argument(&self.dbname_,
+                &format!("log holds {} prepared transactions, and prepared_batch_resolver is not set", prepared.len()));
+        };
+        for (txn, prepared) in prepared {
+            let commit = match resolver.resolve(&self.dbname_, &txn, prepared.participant, prepared.participants) {
+                Ok(commit) => commit,
+                Err(s) => { return s; },
+            };
+            log(self.options_.info_log.clone(), &format!("Prepared transaction {} (participant {} of {}): {}",
+                escape_bytes(&txn), prepared.participant, prepared.participants, if commit { "commit" } else { "roll back" }));
+            if !self.read_only_ {
+                state.resolved_.push((txn, commit.then_some(prepared.batch)));
+            } else if commit {
+                let mut batch = prepared.batch;
+                let first_sequence = state.versions_.last_sequence() + 1;
+                batch.set_sequence(first_sequence);
+                let mem = state.mem_.get_or_insert_with(|| Arc::new(self.new_memtable()));
+                let s = batch.insert_into(mem);
+                if !s.ok() {
+                    return s;
+                }
+                state.versions_.set_last_sequence(first_sequence + batch.count() as u64 - 1);
+            }
+        }
+        Status::new_ok()
+    }
+
+    /// Log the outcomes resolve_prepared_transactions() left in
+    /// state.resolved_ to the new log, applying the committed batches.
+    /// REQUIRES: "state" is mutex_'s, and DB::open() has just created the
+    /// log and mem_
+    pub(super) fn log_resolved_transactions(&self, state: &mut DbState) -> Status {
+        for (txn, batch) in std::mem::take(&mut state.resolved_) {
+            let s = match batch {
+                Some(batch) => self.commit_locked(state, txn, batch),
+                None => {
+                    let mut marker = WriteBatch::new();
+                    marker.set_transaction_record(&TransactionRecord::Rollback(txn));
+                    self.log_synced(state, &marker)
+                },
+            };
+            if !s.ok() {
+                return s;
+            }
+        }
+        Status::new_ok()
+    }
+}
+
+/// Return the transaction records in the logs of the database "dbname",
+/// oldest first, without opening it.  Damaged records are skipped.  A
+/// database that does not exist yet has none.
+pub(crate) fn logged_transaction_records(env: &Arc<dyn Env>, dbname: &str) -> Result<Vec<TransactionRecord>, Status> {
+    if !env.file_exists(&current_file_name(dbname)) {
+        return Ok(Vec::new());
+    }
+    let mut logs: Vec<u64> = env.get_children(dbname)?.iter()
+        .filter_map(|filename| parse_file_name(filename))
+        .filter(|&(_, type_)| type_ == FileType::LogFile)
+        .map(|(number, _)| number)
+        .collect();
+    logs.sort();
+    let mut records = Vec::new();
+    let mut batch = WriteBatch::new();
+    for log_number in logs {
+        let mut reader = Reader::new(env.new_sequential_file(&log_file_name(dbname, log_number))?, None, true, 0);
+        while let Some(record) = reader.read_record() {
+            if record.len() < write_batch::HEADER {
+                continue;
+            }
+            batch.set_contents(&Slice::new(&record));
+            records.extend(batch.transaction_record());
+        }
+    }
+    Ok(records)
+}
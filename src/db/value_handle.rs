@@ -0,0 +1,132 @@
+//! Values fetched after the fact.  See Iterator::value_lazy() and
+//! DB::read_value().
+//!
+//! A scan that looks at every key but only at a few values need not
+//! copy the rest: for an entry in a table, a DB iterator can hand out
+//! where the value lies (the table file, the data block, and the value's
+//! offset and length in the block) and the value is read when it turns
+//! out to be wanted.  Entries in memtables, and entries the iterator
+//! only holds a copy of, are handed out inline.
+//!
+//! The tables a handle points into are kept by the version the iterator
+//! reads, so a handle is good for as long as its iterator is alive, and
+//! no longer, unless it is pinned: a pinned handle keeps the version,
+//! and with it the table, alive itself.
+
+use std::sync::{Arc, Weak};
+
+use crate::{iterator::ValueLocation, options::ReadOptions, status::Status};
+
+use super::{dbformat::NUM_LEVELS, version_set::Version, DbInner};
+
+/// The version a DB iterator reads, shared with the handles it hands
+/// out.  Each iterator has its own, so that its handles lapse with it.
+pub(crate) struct IteratorPin {
+    version_: Arc<Version>,
+}
+
+impl IteratorPin {
+    pub(crate) fn new(version: Arc<Version>) -> Arc<Self> {
+        Arc::new(Self { version_: version })
+    }
+
+    /// Return a new pin on the same version, which the handles of this
+    /// one do not share.
+    pub(crate) fn renewed(&self) -> Arc<Self> {
+        Self::new(self.version_.clone())
+    }
+}
+
+enum HandleValue {
+    Inline(Vec<u8>),
+    Table(ValueLocation),
+}
+
+enum HandlePin {
+    Iterator(Weak<IteratorPin>),
+    Pinned(Arc<IteratorPin>),
+}
+
+/// The value of an entry of a DB iterator, to be read later with
+/// DB::read_value(); see Iterator::value_lazy().
+pub struct ValueHandle {
+    value_: HandleValue,
+    pin_: HandlePin,
+}
+
+impl ValueHandle {
+    /// A handle holding "value" itself.
+    pub(crate) fn inline(value: Vec<u8>, pin: &Arc<IteratorPin>) -> Self {
+        Self { value_: HandleValue::Inline(value), pin_: HandlePin::Iterator(Arc::downgrade(pin)) }
+    }
+
+    /// A handle on the value at "location", a table of the version
+    /// "pin" holds.
+    pub(crate) fn in_table(location: ValueLocation, pin: &Arc<IteratorPin>) -> Self {
+        Self { value_: HandleValue::Table(location), pin_: HandlePin::Iterator(Arc::downgrade(pin)) }
+    }
+
+    /// Return a handle on the same value that stays good after the
+    /// iterator it came from is dropped, by holding on to the version
+    /// the iterator read.  Like the iterator, a pinned handle keeps the
+    /// tables of that version from being deleted while it lives.
+    /// Fails with InvalidArgument if the iterator is gone already.
+    pub fn pin(&self) -> Result<ValueHandle, Status> {
+        let value = match &self.value_ {
+            HandleValue::Inline(value) => HandleValue::Inline(value.clone()),
+            HandleValue::Table(location) => HandleValue::Table(location.clone()),
+        };
+        // A pin of its own, so as not to keep the iterator's handles good
+        Ok(Self { value_: value, pin_: HandlePin::Pinned(self.upgrade()?.renewed()) })
+    }
+
+    /// Returns true if the handle holds the value itself, so that reading
+    /// it does no I/O.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.value_, HandleValue::Inline(_))
+    }
+
+    /// Returns the length of the value in bytes.
+    pub fn len(&self) -> usize {
+        match &self.value_ {
+            HandleValue::Inline(value) => value.len(),
+            HandleValue::Table(location) => location.len,
+        }
+    }
+
+    /// Returns true if the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn upgrade(&self) -> Result<Arc<IteratorPin>, Status> {
+        match &self.pin_ {
+            HandlePin::Iterator(pin) => pin.upgrade().ok_or_else(|| Status::invalid_argument("value handle outlived its iterator", "")),
+            HandlePin::Pinned(pin) => Ok(pin.clone()),
+        }
+    }
+}
+
+impl DbInner {
+    /// Read the value "handle" stands for, through the table cache and
+    /// the block cache like any other read.  Fails with InvalidArgument
+    /// if the iterator the handle came from is gone and the handle was
+    /// not pinned, or if the handle is not one of this DB's.
+    pub fn read_value(&self, handle: &ValueHandle) -> Result<Vec<u8>, Status> {
+        self.check_open()?;
+        let pin = handle.upgrade()?;
+        let location = match &handle.value_ {
+            HandleValue::Inline(value) => { return Ok(value.clone()); },
+            HandleValue::Table(location) => location,
+        };
+        // The version holds the table, and says at which level its
+        // blocks are cached.
+        let level = (0..NUM_LEVELS).find(|&level| {
+            pin.version_.files(level).iter().any(|f| f.number == location.file_number && f.file_size == location.file_size)
+        });
+        let Some(level) = level else {
+            return Err(Status::invalid_argument("value handle is not from this DB", &location.file_number.to_string()));
+        };
+        self.table_cache_.read_value(&ReadOptions::new(), level, location)
+    }
+}
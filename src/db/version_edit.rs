@@ -1,8 +1,8 @@
-use std::collections::BTreeSet;
+use std::{collections::BTreeSet, sync::{atomic::{AtomicI32, Ordering}, Arc}};
 
 use crate::{db::dbformat::NUM_LEVELS, slice::Slice, status::Status, util::coding::{get_length_prefixed_slice, get_varint32, get_varint64, put_length_prefixed_slice, put_varint32, put_varint64}};
 
-use super::dbformat::InternalKey;
+use super::{dbformat::InternalKey, features::{self, check_supported}};
 
 // Tag numbers for serialized VersionEdit.  These numbers are written to
 // disk and should not be changed.
@@ -15,24 +15,36 @@ const DELETED_FILE: u8 = 6;
 const NEW_FILE: u8 = 7;
 // 8 was used for large value refs
 const PREV_LOG_NUMBER: u8 = 9;
+const REQUIRED_FEATURES: u8 = 10;
+const FENCING_TOKEN: u8 = 11;
+const UNLOGGED_WRITES: u8 = 12;
+// NEW_FILE followed by the file's whole-file checksum
+const NEW_FILE2: u8 = 13;
 
 pub(crate) type SequenceNumber = u64;
 type DeletedFileSet = BTreeSet<(i32, u64)>;
 
 pub(crate) struct VersionEdit {
-    comparator_: String,
-    log_number_: u64,
-    prev_log_number_: u64,
-    next_file_number_: u64,
-    last_sequence_: SequenceNumber,
-    has_comparator_: bool,
-    has_log_number_: bool,
-    has_prev_log_number_: bool,
-    has_next_file_number_: bool,
-    has_last_sequence_: bool,
-    compact_pointers_: Vec<(i32, InternalKey)>,
-    deleted_files_: DeletedFileSet,
-    new_files_: Vec<(i32, FileMetaData)>,
+    pub(crate) comparator_: String,
+    pub(crate) log_number_: u64,
+    pub(crate) prev_log_number_: u64,
+    pub(crate) next_file_number_: u64,
+    pub(crate) last_sequence_: SequenceNumber,
+    pub(crate) has_comparator_: bool,
+    pub(crate) has_log_number_: bool,
+    pub(crate) has_prev_log_number_: bool,
+    pub(crate) has_next_file_number_: bool,
+    pub(crate) has_last_sequence_: bool,
+    // Bits from db::features; see set_required_features().
+    pub(crate) required_features_: u64,
+    pub(crate) has_required_features_: bool,
+    pub(crate) fencing_token_: u64,
+    pub(crate) has_fencing_token_: bool,
+    pub(crate) unlogged_writes_: bool,
+    pub(crate) has_unlogged_writes_: bool,
+    pub(crate) compact_pointers_: Vec<(i32, InternalKey)>,
+    pub(crate) deleted_files_: DeletedFileSet,
+    pub(crate) new_files_: Vec<(i32, FileMetaData)>,
 }
 
 impl VersionEdit {
@@ -48,6 +60,12 @@ impl VersionEdit {
             has_prev_log_number_: false,
             has_next_file_number_: false,
             has_last_sequence_: false,
+            required_features_: 0,
+            has_required_features_: false,
+            fencing_token_: 0,
+            has_fencing_token_: false,
+            unlogged_writes_: false,
+            has_unlogged_writes_: false,
             compact_pointers_: Vec::new(),
             deleted_files_: BTreeSet::new(),
             new_files_: Vec::new(),
@@ -75,6 +93,18 @@ impl VersionEdit {
             put_varint32(dst, LAST_SEQUENCE as u32);
             put_varint64(dst, self.last_sequence_);
         }
+        if self.has_required_features_ {
+            put_varint32(dst, REQUIRED_FEATURES as u32);
+            put_varint64(dst, self.required_features_);
+        }
+        if self.has_fencing_token_ {
+            put_varint32(dst, FENCING_TOKEN as u32);
+            put_varint64(dst, self.fencing_token_);
+        }
+        if self.has_unlogged_writes_ {
+            put_varint32(dst, UNLOGGED_WRITES as u32);
+            put_varint32(dst, self.unlogged_writes_ as u32);
+        }
 
         for pointer in &self.compact_pointers_ {
             put_varint32(dst, COMPACT_POINTER as u32);
@@ -88,12 +118,15 @@ impl VersionEdit {
         }
         for file in &self.new_files_ {
             let meta = &file.1;
-            put_varint32(dst, NEW_FILE as u32);
+            put_varint32(dst, if meta.file_checksum.is_some() { NEW_FILE2 } else { NEW_FILE } as u32);
             put_varint32(dst, file.0 as u32);   // level
             put_varint64(dst, meta.number);
             put_varint64(dst, meta.file_size);
             put_length_prefixed_slice(dst, &meta.smallest.encode());
             put_length_prefixed_slice(dst, &meta.largest.encode());
+            if let Some(checksum) = meta.file_checksum {
+                put_varint32(dst, checksum);
+            }
         }
     }
 
@@ -155,6 +188,33 @@ impl VersionEdit {
                                 None => { msg = "last sequence number".to_string(); },
                             }
                         },
+                        REQUIRED_FEATURES => {
+                            match get_varint64(&mut input) {
+                                Some(n) => {
+                                    result.required_features_ = n;
+                                    result.has_required_features_ = true;
+                                },
+                                None => { msg = "required features".to_string(); },
+                            }
+                        },
+                        FENCING_TOKEN => {
+                            match get_varint64(&mut input) {
+                                Some(n) => {
+                                    result.fencing_token_ = n;
+                                    result.has_fencing_token_ = true;
+                                },
+                                None => { msg = "fencing token".to_string(); },
+                            }
+                        },
+                        UNLOGGED_WRITES => {
+                            match get_varint32(&mut input) {
+                                Some(n) if n <= 1 => {
+                                    result.unlogged_writes_ = n == 1;
+                                    result.has_unlogged_writes_ = true;
+                                },
+                                _ => { msg = "unlogged writes".to_string(); },
+                            }
+                        },
                         COMPACT_POINTER => {
                             match (get_level(&mut input), get_internal_key(&mut input)) {
                                 (Some(l), Some(k)) => {
@@ -171,7 +231,7 @@ impl VersionEdit {
                                 _ => { msg = "deleted file".to_string(); },
                             }
                         },
-                        NEW_FILE => {
+                        NEW_FILE | NEW_FILE2 => {
                             match (get_level(&mut input), get_varint64(&mut input), get_varint64(&mut input),
                                     get_internal_key(&mut input), get_internal_key(&mut input)) {
                                 (Some(level), Some(number), Some(file_size), 
@@ -181,6 +241,12 @@ impl VersionEdit {
                                     meta.file_size = file_size;
                                     meta.smallest = smallest;
                                     meta.largest = largest;
+                                    if tag as u8 == NEW_FILE2 {
+                                        match get_varint32(&mut input) {
+                                            Some(checksum) => meta.file_checksum = Some(checksum),
+                                            None => { msg = "new-file checksum".to_string(); },
+                                        }
+                                    }
                                     result.new_files_.push((level, meta));
                                 },
                                 _ => { msg = "new-file entry".to_string(); },
@@ -201,6 +267,9 @@ impl VersionEdit {
 
         if msg.is_empty() {
             Ok(result)
+        } else if msg == "unknown tag" && result.has_required_features_ && !check_supported(result.required_features_).ok() {
+            // Written along with the record of the feature this build lacks
+            Err(check_supported(result.required_features_))
         } else {
             Err(Status::corruption("VersionEdit", &msg))
         }
@@ -209,13 +278,22 @@ impl VersionEdit {
     /// Add the specified file at the specified number.
     /// REQUIRES: This version has not been saved (see VersionSet::SaveTo)
     /// REQUIRES: "smallest" and "largest" are smallest and largest keys in file
+    #[cfg(test)]
     pub(crate) fn add_file(&mut self, level: i32, file: u64, file_size: u64, 
         smallest: &InternalKey, largest: &InternalKey) {
+        self.add_file_with_checksum(level, file, file_size, smallest, largest, None);
+    }
+
+    /// Like add_file(), also recording the whole-file checksum of the
+    /// file (see TableBuilder::file_checksum), if it is known.
+    pub(crate) fn add_file_with_checksum(&mut self, level: i32, file: u64, file_size: u64,
+        smallest: &InternalKey, largest: &InternalKey, file_checksum: Option<u32>) {
         let mut meta = FileMetaData::new();
         meta.number = file;
         meta.file_size = file_size;
         meta.smallest = smallest.clone();
         meta.largest = largest.clone();
+        meta.file_checksum = file_checksum;
         self.new_files_.push((level, meta));
     }
 
@@ -249,6 +327,44 @@ impl VersionEdit {
         self.last_sequence_ = seq;
     }
 
+    /// Record that the database depends on the optional features in
+    /// "features" (bits from db::features).  Only written once a database
+    /// uses a feature, so baseline MANIFESTs stay readable by any build.
+    pub(crate) fn set_required_features(&mut self, features: u64) {
+        self.has_required_features_ = true;
+        self.required_features_ = features;
+    }
+
+    /// Return the bits from db::features for the records in the edit
+    /// that builds without those features cannot decode.
+    pub(crate) fn features_used(&self) -> u64 {
+        let mut features = 0;
+        if self.new_files_.iter().any(|(_, f)| f.file_checksum.is_some()) {
+            features |= features::TABLE_CHECKSUMS;
+        }
+        if self.has_fencing_token_ {
+            features |= features::FENCING_TOKENS;
+        }
+        if self.has_unlogged_writes_ {
+            features |= features::UNLOGGED_WRITES;
+        }
+        features
+    }
+
+    /// Record the largest Options::fencing_token the database has been
+    /// opened with.
+    pub(crate) fn set_fencing_token(&mut self, token: u64) {
+        self.has_fencing_token_ = true;
+        self.fencing_token_ = token;
+    }
+
+    /// Record whether the memtables may hold writes made with
+    /// WriteOptions::disable_wal, which a crash would lose.
+    pub(crate) fn set_unlogged_writes(&mut self, unlogged: bool) {
+        self.has_unlogged_writes_ = true;
+        self.unlogged_writes_ = unlogged;
+    }
+
     pub(crate) fn set_compact_pointer(&mut self, level: i32, key: InternalKey) {
         self.compact_pointers_.push((level, key));
     }
@@ -268,25 +384,56 @@ fn get_level(input: &mut Slice) -> Option<i32> {
     }
 }
 
+/// Seeks allowed on a file until it is compacted, shared by the copies of
+/// its metadata in every version that holds the file.  Charged by lookups
+/// running in any thread.
+#[derive(Debug, Clone)]
+pub(crate) struct AllowedSeeks(Arc<AtomicI32>);
+
+impl AllowedSeeks {
+    pub(crate) fn new(seeks: i32) -> Self {
+        Self(Arc::new(AtomicI32::new(seeks)))
+    }
+
+    pub(crate) fn get(&self) -> i32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Charge one seek, and return the number of seeks left.
+    pub(crate) fn charge(&self) -> i32 {
+        self.0.fetch_sub(1, Ordering::Relaxed) - 1
+    }
+}
+
+impl PartialEq for AllowedSeeks {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) struct FileMetaData {
-    refs: i32,
-    allowed_seeks: i32, // Seeks allowed until compaction
+    pub(crate) refs: i32,
+    pub(crate) allowed_seeks: AllowedSeeks,
     pub(crate) number: u64,
     pub(crate) file_size: u64,     // File size in bytes
     pub(crate) smallest: InternalKey, // Smallest internal key served by table
     pub(crate) largest: InternalKey,  // Largest internal key served by table
+    // crc32c of the whole file; None for files recorded before whole-file
+    // checksums were.
+    pub(crate) file_checksum: Option<u32>,
 }
 
 impl FileMetaData {
     pub(crate) fn new() -> Self {
         Self { 
             refs: 0, 
-            allowed_seeks: 1i32 << 30, 
+            allowed_seeks: AllowedSeeks::new(1i32 << 30),
             number: 0,  // 0 shouldn't be used, just for initialization
             file_size: 0, 
             smallest: InternalKey::new(), // empty key shouldn't be used either
             largest: InternalKey::new(),
+            file_checksum: None,
         }
     }
 }
@@ -325,5 +472,61 @@ mod tests {
         edit.set_next_file(BIG + 200);
         edit.set_last_sequence(BIG + 1000);
         test_encode_decode(&edit);
+        edit.set_required_features(BIG + 1);
+        test_encode_decode(&edit);
+        edit.set_fencing_token(BIG + 2);
+        test_encode_decode(&edit);
+        edit.set_unlogged_writes(true);
+        test_encode_decode(&edit);
+    }
+
+    #[test]
+    fn file_checksum_test() {
+        let key = |k: &[u8]| InternalKey::new_from(&Slice::new(k), 1, ValueType::type_value());
+        let mut edit = VersionEdit::new();
+        edit.add_file(1, 10, 100, &key(b"a"), &key(b"b"));
+        edit.add_file_with_checksum(2, 11, 200, &key(b"c"), &key(b"d"), Some(u32::MAX));
+        edit.add_file_with_checksum(2, 12, 300, &key(b"e"), &key(b"f"), Some(0));
+        test_encode_decode(&edit);
+
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        let parsed = VersionEdit::decode_from(&Slice::new(&encoded)).unwrap();
+        let checksums: Vec<Option<u32>> = parsed.new_files_.iter().map(|(_, f)| f.file_checksum).collect();
+        assert_eq!(vec![None, Some(u32::MAX), Some(0)], checksums);
+
+        // An entry cut off before its checksum is corrupt
+        let mut edit = VersionEdit::new();
+        edit.add_file_with_checksum(2, 11, 200, &key(b"c"), &key(b"d"), Some(1));
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        encoded.pop();
+        assert!(VersionEdit::decode_from(&Slice::new(&encoded)).err().unwrap().is_corruption());
+    }
+
+    #[test]
+    fn unknown_tag_test() {
+        let mut edit = VersionEdit::new();
+        edit.set_log_number(7);
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        put_varint32(&mut encoded, 99);
+        assert!(VersionEdit::decode_from(&Slice::new(&encoded)).err().unwrap().is_corruption());
+
+        // Next to a feature this build lacks, the feature is to blame
+        edit.set_required_features(features::TABLE_CHECKSUMS | 1 << 20);
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        put_varint32(&mut encoded, 99);
+        let s = VersionEdit::decode_from(&Slice::new(&encoded)).err().unwrap();
+        assert!(s.is_not_supported_error());
+        assert_eq!("Not implemented: this database requires: unknown feature 20 support", s.to_string());
+
+        // Which is not the case for one it has
+        edit.set_required_features(features::TABLE_CHECKSUMS);
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        put_varint32(&mut encoded, 99);
+        assert!(VersionEdit::decode_from(&Slice::new(&encoded)).err().unwrap().is_corruption());
     }
 }
@@ -8,11 +8,50 @@
 //! Version,VersionSet are thread-compatible, but require external
 //! synchronization on all accesses.
 
-use std::{cmp::Ordering, rc::{Rc, Weak}, sync::Arc};
+use std::{cell::RefCell, cmp::{Ordering, Reverse}, collections::{BTreeMap, BTreeSet, VecDeque}, rc::Rc, sync::{Arc, Mutex, Weak}};
 
-use crate::{comparator::Comparator, db::dbformat::{InternalKey, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK}, slice::Slice, status::Status};
+use crate::{comparator::Comparator, db::dbformat::{InternalKey, LookupKey, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK}, env::{log, Env, WritableFile}, iterator::{new_error_iterator, Iterator}, options::{Options, ReadOptions}, slice::Slice, status::Status, table::{merger::new_internal_merging_iterator, two_level_iterator::new_two_level_iterator}, util::{coding::{decode_fixed64_bytes, encode_fixed64}, env::read_file_to_string, redact::redact}};
 
-use super::{dbformat::{InternalKeyComparator, NUM_LEVELS}, version_edit::FileMetaData};
+use super::{features::check_supported, dbformat::{parse_internal_key, InternalKeyComparator, ValueType, L0_COMPACTION_TRIGGER, MAX_MEM_COMPACT_LEVEL, NUM_LEVELS}, filename::{current_file_name, descriptor_file_name, parse_file_name, set_current_file, FileType}, log_reader::{Reader, Reporter}, log_writer::Writer, memtable::MemTable, table_cache::TableCache, version_edit::{AllowedSeeks, FileMetaData, SequenceNumber, VersionEdit}};
+
+fn target_file_size(options: &Options) -> u64 {
+    options.max_file_size as u64
+}
+
+/// Maximum bytes of overlaps in grandparent (i.e., level+2) before we
+/// stop building a single file in a level->level+1 compaction.
+fn max_grand_parent_overlap_bytes(options: &Options) -> u64 {
+    10 * target_file_size(options)
+}
+
+/// Maximum number of bytes in all compacted files.  We avoid expanding
+/// the lower level file set of a compaction if it would make the
+/// total compaction cover more than this many bytes.
+fn expanded_compaction_byte_size_limit(options: &Options) -> u64 {
+    25 * target_file_size(options)
+}
+
+fn max_bytes_for_level(_options: &Options, mut level: i32) -> f64 {
+    // Note: the result for level zero is not really used since we set
+    // the level-0 compaction threshold based on number of files.
+
+    // Result for both level-0 and level-1
+    let mut result = 10. * 1048576.0;
+    while level > 1 {
+        result *= 10.0;
+        level -= 1;
+    }
+    result
+}
+
+fn max_file_size_for_level(options: &Options, _level: i32) -> u64 {
+    // We could vary per level to reduce number of files?
+    target_file_size(options)
+}
+
+fn total_file_size(files: &[FileMetaData]) -> u64 {
+    files.iter().map(|f| f.file_size).sum()
+}
 
 fn find_file(cmp: &InternalKeyComparator, files: &Vec<FileMetaData>, key: &Slice) -> usize {
     let mut left = 0;
@@ -33,23 +72,22 @@ fn find_file(cmp: &InternalKeyComparator, files: &Vec<FileMetaData>, key: &Slice
     right
 }
 
-fn after_file(cmp: &Arc<dyn Comparator>, user_key: &Slice, f: &FileMetaData) -> bool {
+fn after_file(cmp: &Arc<dyn Comparator>, user_key: Option<&Slice>, f: &FileMetaData) -> bool {
     // null user_key occurs before all keys and is therefore never after *f
-    !user_key.is_empty() &&
-    cmp.compare(user_key, &f.largest.user_key()) == Ordering::Greater
+    user_key.is_some_and(|user_key| cmp.compare(user_key, &f.largest.user_key()) == Ordering::Greater)
 }
 
-fn before_file(cmp: &Arc<dyn Comparator>, user_key: &Slice, f: &FileMetaData) -> bool {
+fn before_file(cmp: &Arc<dyn Comparator>, user_key: Option<&Slice>, f: &FileMetaData) -> bool {
     // null user_key occurs after all keys and is therefore never before *f
-    !user_key.is_empty() &&
-    cmp.compare(user_key, &f.smallest.user_key()) == Ordering::Less
+    user_key.is_some_and(|user_key| cmp.compare(user_key, &f.smallest.user_key()) == Ordering::Less)
 }
 
 /// Return true iff there exists at least one file overlaps with range
-/// [smallest_user_key, largest_user_key].
+/// [smallest_user_key, largest_user_key].  None represents a key smaller
+/// (resp. larger) than all keys.
 fn some_file_overlaps_range(cmp: &InternalKeyComparator, disjoint_sorted_files: bool,
                             files: &Vec<FileMetaData>,
-                            smallest_user_key: &Slice, largest_user_key: &Slice) -> bool {
+                            smallest_user_key: Option<&Slice>, largest_user_key: Option<&Slice>) -> bool {
     let ucmp = cmp.user_comparator();
     if !disjoint_sorted_files {
         // Need to check against all files
@@ -66,7 +104,7 @@ fn some_file_overlaps_range(cmp: &InternalKeyComparator, disjoint_sorted_files:
 
     // Binary search over file list
     let mut index = 0;
-    if !smallest_user_key.is_empty() {
+    if let Some(smallest_user_key) = smallest_user_key {
         // Find the earliest possible internal key for smallest_user_key
         let small_key = InternalKey::new_from(smallest_user_key, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK);
         index = find_file(cmp, files, &small_key.encode());
@@ -80,18 +118,18 @@ fn some_file_overlaps_range(cmp: &InternalKeyComparator, disjoint_sorted_files:
     !before_file(&ucmp, largest_user_key, &files[index])
 }
 
+/// Versions are shared through Arc: a Version stays alive for as long as
+/// some reader (or the VersionSet, for the current one) holds on to it.
 pub(crate) struct Version {
-    vset_: Rc<VersionSet>,  // VersionSet to which this Version belongs
-    next_: Rc<Version>,     // Next version in linked list
-    prev_: Weak<Version>,   // Previous version in linked list
-    refs_: i32,             // Number of live refs to this version
+    icmp_: InternalKeyComparator,
+    table_cache_: Arc<TableCache>,
 
     // List of files per level
-    files_: Vec<FileMetaData>,
+    files_: Vec<Vec<FileMetaData>>,
 
-    // Next file to compact based on seek stats.
-    file_to_compact_: FileMetaData,
-    file_to_compact_level_: i32,
+    // Next file to compact based on seek stats, and its level.  Set by
+    // lookups, which only share the version.
+    file_to_compact_: Mutex<Option<(i32, FileMetaData)>>,
 
     // Level that should be compacted next and its compaction score.
     // Score < 1 means compaction is not strictly needed.  These fields
@@ -99,38 +137,709 @@ pub(crate) struct Version {
     compaction_score_: f64,
     compaction_level_: i32,
 }
+
+/// What a lookup tells about the files it probed; see Version::get()
+/// and Version::update_stats().
 pub(crate) struct GetStats {
     pub(crate) seek_file: FileMetaData,
-    pub(crate) seek_file_level: i32,
+    pub(crate) seek_file_level: i32,    // -1 if no file is charged a seek
+    pub(crate) files_probed: [u32; NUM_LEVELS as usize],
+    pub(crate) blocks_read: u32,        // Data blocks read from the files
+    pub(crate) probe_misses: u32,       // Files probed that did not hold the key
+    pub(crate) miss_blocks_read: u32,   // Data blocks read from those
 }
+
+impl GetStats {
+    pub(crate) fn new() -> Self {
+        Self { seek_file: FileMetaData::new(), seek_file_level: -1, files_probed: [0; NUM_LEVELS as usize],
+               blocks_read: 0, probe_misses: 0, miss_blocks_read: 0 }
+    }
+}
+
 impl Version {
-    fn new(vset: Rc<VersionSet>) -> Self {
+    fn new(icmp: &InternalKeyComparator, table_cache: &Arc<TableCache>) -> Self {
         Self {
-            vset_: vset,
-            next_: todo!(),
-            prev_: todo!(),
-            refs_: 0,
-            files_: vec![FileMetaData::new(); NUM_LEVELS as usize],
-            file_to_compact_: FileMetaData::new(),
-            file_to_compact_level_: -1,
+            icmp_: icmp.clone(),
+            table_cache_: table_cache.clone(),
+            files_: vec![Vec::new(); NUM_LEVELS as usize],
+            file_to_compact_: Mutex::new(None),
             compaction_score_: -1.0,
             compaction_level_: -1,
         }
     }
+
+    /// Return the number of files at the specified level.
+    pub(crate) fn num_files(&self, level: i32) -> usize {
+        self.files_[level as usize].len()
+    }
+
+    /// Return the files at the specified level.
+    pub(crate) fn files(&self, level: i32) -> &Vec<FileMetaData> {
+        &self.files_[level as usize]
+    }
+
+    /// Append to "iters" a sequence of iterators that will
+    /// yield the contents of this Version when merged together.
+    /// REQUIRES: This version has been saved (see VersionSet::save_to)
+    /// Files holding only keys at or past options.iterate_upper_bound
+    /// are left out, so that they are never opened.
+    pub(crate) fn add_iterators(&self, options: &ReadOptions, iters: &mut Vec<Box<dyn Iterator>>) {
+        let ucmp = self.icmp_.user_comparator();
+        let below_bound = |f: &&FileMetaData| options.iterate_upper_bound.as_ref().is_none_or(|bound| {
+            ucmp.compare(&f.smallest.user_key(), &Slice::new(bound)) == Ordering::Less
+        });
+
+        // Merge all level zero files together since they may overlap
+        for f in self.files_[0].iter().filter(below_bound) {
+            iters.push(self.table_cache_.new_iterator(options, Some(0), f.number, f.file_size));
+        }
+
+        // For levels > 0, we can use a concatenating iterator that sequentially
+        // walks through the non-overlapping files in the level, opening them
+        // lazily.
+        for level in 1..NUM_LEVELS {
+            let files: Vec<_> = self.files_[level as usize].iter().filter(below_bound).cloned().collect();
+            if !files.is_empty() {
+                iters.push(self.new_concatenating_iterator(options, level, files));
+            }
+        }
+    }
+
+    /// Returns true iff some file in the specified level overlaps
+    /// some part of [smallest_user_key, largest_user_key].
+    /// smallest_user_key==None represents a key smaller than all the DB's keys.
+    /// largest_user_key==None represents a key larger than all the DB's keys.
+    pub(crate) fn overlap_in_level(&self, level: i32, smallest_user_key: Option<&Slice>, 
+                                   largest_user_key: Option<&Slice>) -> bool {
+        some_file_overlaps_range(&self.icmp_, level > 0, &self.files_[level as usize], 
+                                 smallest_user_key, largest_user_key)
+    }
+
+    /// Return the level at which we should place a new memtable compaction
+    /// result that covers the range [smallest_user_key,largest_user_key].
+    pub(crate) fn pick_level_for_mem_table_output(&self, options: &Options, smallest_user_key: &Slice, 
+                                                  largest_user_key: &Slice) -> i32 {
+        let mut level = 0;
+        if !self.overlap_in_level(0, Some(smallest_user_key), Some(largest_user_key)) {
+            // Push to next level if there is no overlap in next level,
+            // and the #bytes overlapping in the level after that are limited.
+            let start = InternalKey::new_from(smallest_user_key, MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK);
+            let limit = InternalKey::new_from(largest_user_key, 0, ValueType::type_deletion());
+            while level < MAX_MEM_COMPACT_LEVEL {
+                if self.overlap_in_level(level + 1, Some(smallest_user_key), Some(largest_user_key)) {
+                    break;
+                }
+                if level + 2 < NUM_LEVELS {
+                    // Check that file does not overlap too many grandparent bytes.
+                    let overlaps = self.get_overlapping_inputs(level + 2, Some(&start), Some(&limit));
+                    if total_file_size(&overlaps) > max_grand_parent_overlap_bytes(options) {
+                        break;
+                    }
+                }
+                level += 1;
+            }
+        }
+        level
+    }
+
+    /// Return all files in "level" that overlap [begin,end].
+    /// begin==None means before all keys, end==None means after all keys.
+    pub(crate) fn get_overlapping_inputs(&self, level: i32, begin: Option<&InternalKey>, 
+                                         end: Option<&InternalKey>) -> Vec<FileMetaData> {
+        debug_assert!(level >= 0);
+        debug_assert!(level < NUM_LEVELS);
+        let ucmp = self.icmp_.user_comparator();
+        let files = &self.files_[level as usize];
+        let mut user_begin = begin.map(|k| k.user_key());
+        let mut user_end = end.map(|k| k.user_key());
+        let mut inputs = Vec::new();
+        let mut i = 0;
+        while i < files.len() {
+            let f = &files[i];
+            i += 1;
+            let file_start = f.smallest.user_key();
+            let file_limit = f.largest.user_key();
+            if user_begin.as_ref().is_some_and(|b| ucmp.compare(&file_limit, b) == Ordering::Less) {
+                // "f" is completely before specified range; skip it
+            } else if user_end.as_ref().is_some_and(|e| ucmp.compare(&file_start, e) == Ordering::Greater) {
+                // "f" is completely after specified range; skip it
+            } else {
+                inputs.push(f.clone());
+                if level == 0 {
+                    // Level-0 files may overlap each other.  So check if the newly
+                    // added file has expanded the range.  If so, restart search.
+                    if user_begin.as_ref().is_some_and(|b| ucmp.compare(&file_start, b) == Ordering::Less) {
+                        user_begin = Some(file_start);
+                        inputs.clear();
+                        i = 0;
+                    } else if user_end.as_ref().is_some_and(|e| ucmp.compare(&file_limit, e) == Ordering::Greater) {
+                        user_end = Some(file_limit);
+                        inputs.clear();
+                        i = 0;
+                    }
+                }
+            }
+        }
+        inputs
+    }
+
+    fn new_concatenating_iterator(&self, options: &ReadOptions, level: i32, files: Vec<FileMetaData>) -> Box<dyn Iterator> {
+        let table_cache = self.table_cache_.clone();
+        new_two_level_iterator(
+            Box::new(LevelFileNumIterator::new(&self.icmp_, files)),
+            Box::new(move |options, file_value| get_file_iterator(&table_cache, options, level, file_value)),
+            options)
+    }
+
+    /// Lookup the value for key.  If found, returns it.  Returns a
+    /// NotFound status if no file holds the key.  If "no_io" is set,
+    /// only tables that are already open are consulted, through their
+    /// filters and indexes: an Incomplete status is returned as soon as
+    /// the lookup would need to read from a file.  Fills "stats" with
+    /// the file to charge a seek to, if the lookup probed more than one,
+    /// and counts the files probed and blocks read, and of those the
+    /// ones that did not hold the key.
+    /// REQUIRES: lock is not held
+    pub(crate) fn get(&self, options: &ReadOptions, k: &LookupKey, no_io: bool, stats: &mut GetStats) -> Result<Vec<u8>, Status> {
+        let ikey = k.internal_key();
+        let user_key = k.user_key();
+        let ucmp = self.icmp_.user_comparator();
+
+        stats.seek_file_level = -1;
+        let mut last_file_read: Option<(i32, &FileMetaData)> = None;
+        for (level, f) in self.overlapping_files(&user_key, &ikey) {
+            if let Some((last_level, last_file)) = last_file_read {
+                let s = options.check_deadline(self.table_cache_.env());
+                if !s.ok() {
+                    return Err(s);
+                }
+                if stats.seek_file_level < 0 {
+                    // We have had more than one seek for this read.  Charge
+                    // the 1st file.
+                    stats.seek_file = last_file.clone();
+                    stats.seek_file_level = last_level;
+                }
+            }
+            last_file_read = Some((level, f));
+            stats.files_probed[level as usize] += 1;
+
+            let blocks_before = stats.blocks_read;
+            let found = self.table_cache_.get(options, level, f, &ikey, no_io, &mut stats.blocks_read)?;
+            if let Some((found_key, value)) = found {
+                match parse_internal_key(&Slice::new(&found_key)) {
+                    None => { return Err(Status::corruption("corrupted key for ", &redact(user_key.data(), self.table_cache_.redaction()))); },
+                    Some(parsed) if ucmp.compare(&parsed.user_key, &user_key) == Ordering::Equal => {
+                        if parsed.type_ == ValueType::type_value() {
+                            return Ok(value);
+                        }
+                        return Err(Status::not_found("", ""));  // Deleted
+                    },
+                    Some(_) => {},  // Keep searching in other files
+                }
+            }
+            stats.probe_misses += 1;
+            stats.miss_blocks_read += stats.blocks_read - blocks_before;
+        }
+        Err(Status::not_found("", ""))
+    }
+
+    /// Return the files that may hold "user_key", in the order a lookup
+    /// searches them: level-0 from newest to oldest, then each deeper
+    /// level, where at most one file can contain the key.  "ikey" is an
+    /// internal key for "user_key".
+    fn overlapping_files(&self, user_key: &Slice, ikey: &Slice) -> Vec<(i32, &FileMetaData)> {
+        let ucmp = self.icmp_.user_comparator();
+        let mut files: Vec<(i32, &FileMetaData)> = self.files_[0].iter()
+            .filter(|f| ucmp.compare(user_key, &f.smallest.user_key()) != Ordering::Less &&
+                        ucmp.compare(user_key, &f.largest.user_key()) != Ordering::Greater)
+            .map(|f| (0, f))
+            .collect();
+        files.sort_by_key(|(_, f)| Reverse(f.number));
+        for level in 1..NUM_LEVELS {
+            let level_files = &self.files_[level as usize];
+            let index = find_file(&self.icmp_, level_files, ikey);
+            if index < level_files.len() &&
+                ucmp.compare(user_key, &level_files[index].smallest.user_key()) != Ordering::Less {
+                files.push((level, &level_files[index]));
+            }
+        }
+        files
+    }
+
+    /// Return the number of tables a seek to "user_key" positions in: the
+    /// level-0 files that end at or after it, and in each deeper level
+    /// the first file that does.
+    pub(crate) fn tables_for_seek(&self, user_key: &Slice) -> usize {
+        let ucmp = self.icmp_.user_comparator();
+        let level0 = self.files_[0].iter().filter(|f| ucmp.compare(user_key, &f.largest.user_key()) != Ordering::Greater).count();
+        level0 + (1..NUM_LEVELS).filter(|&level| {
+            let files = &self.files_[level as usize];
+            files.last().is_some_and(|f| ucmp.compare(user_key, &f.largest.user_key()) != Ordering::Greater)
+        }).count()
+    }
+
+    /// Record a sample of bytes read at the specified internal key, as
+    /// iterators do about once per READ_BYTES_PERIOD bytes.  If more than
+    /// one file may hold the key, the first is charged a seek, as a
+    /// lookup of it would be.  Returns true iff that file ran out of
+    /// allowed seeks and a new compaction may need to be triggered.
+    pub(crate) fn record_read_sample(&self, internal_key: &Slice) -> bool {
+        let Some(ikey) = parse_internal_key(internal_key) else {
+            return false;
+        };
+        let files = self.overlapping_files(&ikey.user_key, internal_key);
+        // Must have at least two matches since we want to merge across
+        // files.  But what if we have a single file that contains many
+        // overwrites and deletions?  Should we have another mechanism
+        // for finding such files?
+        let [(level, first), _, ..] = files[..] else {
+            return false;
+        };
+        let stats = GetStats { seek_file: first.clone(), seek_file_level: level, ..GetStats::new() };
+        self.update_stats(&stats)
+    }
+
+    /// Charge the seek recorded in "stats" to its file.  Returns true iff
+    /// that file ran out of allowed seeks and a new compaction may need
+    /// to be triggered.
+    /// REQUIRES: lock is held
+    pub(crate) fn update_stats(&self, stats: &GetStats) -> bool {
+        if stats.seek_file_level < 0 {
+            return false;
+        }
+        let f = &stats.seek_file;
+        let mut file_to_compact = self.file_to_compact_.lock().expect("failed to acquire lock");
+        if f.allowed_seeks.charge() <= 0 && file_to_compact.is_none() {
+            *file_to_compact = Some((stats.seek_file_level, f.clone()));
+            return true;
+        }
+        false
+    }
+
+    /// The file that ran out of allowed seeks first, and its level, if any.
+    pub(crate) fn file_to_compact(&self) -> Option<(i32, FileMetaData)> {
+        self.file_to_compact_.lock().expect("failed to acquire lock").clone()
+    }
 }
 
 pub(crate) struct VersionSet {
-
+    env_: Arc<dyn Env>,
+    dbname_: String,
+    options_: Options,
+    table_cache_: Arc<TableCache>,
+    icmp_: InternalKeyComparator,
     next_file_number_: u64,
+    manifest_file_number_: u64,
+    last_sequence_: SequenceNumber,
+    log_number_: u64,
+    prev_log_number_: u64,  // 0 or backing store for memtable being compacted
+    required_features_: u64,    // Optional features the database uses; see db::features
+    fencing_token_: u64,        // Largest Options::fencing_token the database was opened with
+    unlogged_writes_: bool,     // The memtables may hold writes that are not in the log
+
+    // Opened lazily
+    descriptor_file_: Option<Arc<dyn WritableFile>>,
+    descriptor_log_: Option<Writer>,
+    // A write or sync of the MANIFEST failed, so the next edit must start
+    // a new one (see WritableFile::sync)
+    manifest_failed_: bool,
+    current_: Arc<Version>,
+
+    // Older versions that may still be in use by readers.  Their files
+    // must not be deleted until they are dropped.
+    old_versions_: Vec<Weak<Version>>,
+
+    // Per-level key at which the next compaction at that level should start.
+    // Either an empty string, or a valid InternalKey.
+    compact_pointer_: Vec<Vec<u8>>,
+
+    // Up to options_.keep_old_versions superseded versions, oldest first.
+    retained_: VecDeque<Retained>,
+    next_retained_id_: u64,
+}
+
+/// A superseded Version kept for reads as of the moment it was replaced.
+#[derive(Clone)]
+pub(crate) struct Retained {
+    pub(crate) id: u64,
+    pub(crate) version: Arc<Version>,
+    pub(crate) replaced_at_micros: u64,
+    pub(crate) last_sequence: SequenceNumber,
+    // Newest first: the memtables holding what had been written when the
+    // version was replaced but was not in its files yet.
+    pub(crate) mems: Vec<Arc<MemTable>>,
+}
+
+/// Describes a superseded file layout kept because of
+/// Options::keep_old_versions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetainedVersion {
+    /// Identifies the version in DB::get_at_version() and
+    /// DB::new_iterator_at_version().
+    pub id: u64,
+    /// Env::now_micros() when the version stopped being current.
+    pub replaced_at_micros: u64,
+    /// The last sequence number written when it was replaced; reads at
+    /// this version see exactly the updates up to it.
+    pub last_sequence: u64,
+    pub num_files: usize,
+    pub total_bytes: u64,
 }
+
 impl VersionSet {
-    pub(crate) fn new() -> Self {
-        todo!()
+    pub(crate) fn new(dbname: &str, options: &Options, table_cache: &Arc<TableCache>, icmp: &InternalKeyComparator) -> Self {
+        Self {
+            env_: options.env.clone(),
+            dbname_: dbname.to_string(),
+            options_: options.clone(),
+            table_cache_: table_cache.clone(),
+            icmp_: icmp.clone(),
+            next_file_number_: 2,
+            manifest_file_number_: 0,   // Filled by recover()
+            last_sequence_: 0,
+            log_number_: 0,
+            prev_log_number_: 0,
+            descriptor_file_: None,
+            descriptor_log_: None,
+            manifest_failed_: false,
+            current_: Arc::new(Version::new(icmp, table_cache)),
+            old_versions_: Vec::new(),
+            compact_pointer_: vec![Vec::new(); NUM_LEVELS as usize],
+            retained_: VecDeque::new(),
+            next_retained_id_: 1,
+            required_features_: 0,
+            fencing_token_: 0,
+            unlogged_writes_: false,
+        }
+    }
+
+    /// Apply *edit to the current version to form a new descriptor that
+    /// is both saved to persistent state and installed as the new
+    /// current version.
+    /// 
+    /// The replaced version is kept if options.keep_old_versions asks for
+    /// it and "live_mems" names the memtables holding the data written
+    /// so far that is not in its files.
+    /// 
+    /// REQUIRES: *mu is held on entry.
+    pub(crate) fn log_and_apply(&mut self, edit: &mut VersionEdit, live_mems: Option<&[Arc<MemTable>]>) -> Status {
+        if edit.has_log_number_ {
+            debug_assert!(edit.log_number_ >= self.log_number_);
+            debug_assert!(edit.log_number_ < self.next_file_number_);
+        } else {
+            edit.set_log_number(self.log_number_);
+        }
+
+        if !edit.has_prev_log_number_ {
+            edit.set_prev_log_number(self.prev_log_number_);
+        }
+
+        // Records older builds cannot decode go out along with the
+        // feature bits that make those builds refuse the MANIFEST.
+        let required = if edit.has_required_features_ { edit.required_features_ } else { self.required_features_ };
+        if edit.features_used() & !required != 0 {
+            edit.set_required_features(required | edit.features_used());
+        }
+
+        // Never write to a MANIFEST again once a write or sync of it has
+        // failed: its tail may be gone even if a retry succeeds.  It stays
+        // CURRENT until a new one replaces it.
+        let failed_manifest = self.manifest_file_number_;
+        if self.descriptor_log_.is_none() && self.manifest_failed_ {
+            self.manifest_file_number_ = self.new_file_number();
+        }
+
+        edit.set_next_file(self.next_file_number_);
+        edit.set_last_sequence(self.last_sequence_);
+
+        let mut v = Version::new(&self.icmp_, &self.table_cache_);
+        {
+            let mut builder = Builder::new(&self.icmp_, self.current_.clone());
+            builder.apply(edit, &mut self.compact_pointer_);
+            builder.save_to(&mut v);
+        }
+        self.finalize(&mut v);
+
+        // Initialize new descriptor log file if necessary by creating
+        // a temporary file that contains a snapshot of the current version.
+        let mut new_manifest_file = String::new();
+        let mut s = Status::new_ok();
+        if self.descriptor_log_.is_none() {
+            // No reason to unlock *mu here since we only hit this path in the
+            // first call to LogAndApply (when opening the database).
+            debug_assert!(self.descriptor_file_.is_none());
+            new_manifest_file = descriptor_file_name(&self.dbname_, self.manifest_file_number_);
+            match self.env_.new_writable_file(&new_manifest_file) {
+                Ok(file) => {
+                    self.descriptor_file_ = Some(file.clone());
+                    self.descriptor_log_ = Some(Writer::with_preallocation(file, self.options_.max_file_size as u64 / 4,
+                                                                           self.options_.preallocation_block_size as u64));
+                    s = self.write_snapshot();
+                },
+                Err(e) => { s = e; },
+            }
+        }
+
+        // Write new record to MANIFEST log
+        sync_point!("versionset:before-manifest-write", s);
+        if s.ok() {
+            let mut record = Vec::new();
+            edit.encode_to(&mut record);
+            s = self.descriptor_log_.as_mut().unwrap().add_record(&Slice::new(&record));
+            if s.ok() {
+                s = self.descriptor_file_.as_ref().unwrap().sync();
+            }
+            sync_point!("versionset:after-manifest-sync", s);
+            if !s.ok() {
+                log(self.options_.info_log.clone(), &format!("MANIFEST write: {}", s.to_string()));
+                if new_manifest_file.is_empty() {
+                    self.descriptor_log_ = None;
+                    self.descriptor_file_ = None;
+                    self.manifest_failed_ = true;
+                }
+            }
+        }
+
+        // If we just created a new descriptor file, install it by writing a
+        // new CURRENT file that points to it.
+        if !new_manifest_file.is_empty() {
+            sync_point!("versionset:before-current-rename", s);
+        }
+        if s.ok() && !new_manifest_file.is_empty() {
+            s = set_current_file(self.env_.clone(), &self.dbname_, self.manifest_file_number_);
+        }
+
+        // Install the new version
+        if s.ok() {
+            self.manifest_failed_ = false;
+            let old = self.current_.clone();
+            self.append_version(v);
+            self.log_number_ = edit.log_number_;
+            self.prev_log_number_ = edit.prev_log_number_;
+            if edit.has_required_features_ {
+                self.required_features_ = edit.required_features_;
+            }
+            if edit.has_fencing_token_ {
+                self.fencing_token_ = edit.fencing_token_;
+            }
+            if edit.has_unlogged_writes_ {
+                self.unlogged_writes_ = edit.unlogged_writes_;
+            }
+            if let Some(mems) = live_mems {
+                self.retain_version(old, mems);
+            }
+        } else if !new_manifest_file.is_empty() {
+            self.descriptor_log_ = None;
+            self.descriptor_file_ = None;
+            self.env_.remove_file(&new_manifest_file);
+            if self.manifest_failed_ {
+                self.manifest_file_number_ = failed_manifest;
+            }
+        }
+
+        s
     }
 
     /// Recover the last saved descriptor from persistent storage.
+    /// On success returns whether the caller must write a new MANIFEST
+    /// (via log_and_apply) before the recovered state is durable.
     pub(crate) fn recover(&mut self) -> Result<bool, Status> {
-        todo!()
+        // Read "CURRENT" file, which contains a pointer to the current manifest file
+        let current_name = current_file_name(&self.dbname_);
+        let mut current = read_file_to_string(self.env_.clone(), &current_name).map_err(|s| s.with_filename(&current_name))?;
+        if current.is_empty() || !current.ends_with('\n') {
+            return Err(Status::corruption("CURRENT file does not end with newline", "").with_filename(&current_name));
+        }
+        current.pop();
+
+        let dscname = format!("{}/{}", self.dbname_, current);
+        let file = match self.env_.new_sequential_file(&dscname) {
+            Ok(file) => file,
+            Err(s) => {
+                if s.is_not_found() {
+                    return Err(Status::corruption("CURRENT points to a non-existent file", &s.to_string()).with_filename(&dscname));
+                }
+                return Err(s.with_filename(&dscname));
+            },
+        };
+
+        let mut have_log_number = false;
+        let mut have_prev_log_number = false;
+        let mut have_next_file = false;
+        let mut have_last_sequence = false;
+        let mut next_file = 0;
+        let mut last_sequence = 0;
+        let mut log_number = 0;
+        let mut prev_log_number = 0;
+        let mut required_features = 0;  // MANIFESTs without the record are baseline
+        let mut fencing_token = 0;
+        let mut unlogged_writes = false;
+        let mut builder = Builder::new(&self.icmp_, self.current_.clone());
+        let mut read_records = 0;
+
+        let reporter = Rc::new(LogReporter { status_: RefCell::new(Status::new_ok()) });
+        let mut s = Status::new_ok();
+        {
+            let mut reader = Reader::new(file, Some(reporter.clone()), true, 0);
+            while let Some(record) = reader.read_record() {
+                s = reporter.status_.borrow().clone();
+                if !s.ok() {
+                    break;
+                }
+                read_records += 1;
+                let edit = match VersionEdit::decode_from(&Slice::new(&record)) {
+                    Ok(edit) => edit,
+                    Err(e) => {
+                        // A record this build cannot decode may be one of
+                        // a feature recorded earlier that it lacks.
+                        let unsupported = check_supported(required_features);
+                        s = if unsupported.ok() { e } else { unsupported };
+                        break;
+                    },
+                };
+                if edit.has_comparator_ && edit.comparator_ != self.icmp_.user_comparator().name() {
+                    s = Status::invalid_argument(&format!("{} does not match existing comparator ", edit.comparator_), 
+                                                    self.icmp_.user_comparator().name());
+                    break;
+                }
+
+                builder.apply(&edit, &mut self.compact_pointer_);
+
+                if edit.has_log_number_ {
+                    log_number = edit.log_number_;
+                    have_log_number = true;
+                }
+
+                if edit.has_prev_log_number_ {
+                    prev_log_number = edit.prev_log_number_;
+                    have_prev_log_number = true;
+                }
+
+                if edit.has_next_file_number_ {
+                    next_file = edit.next_file_number_;
+                    have_next_file = true;
+                }
+
+                if edit.has_last_sequence_ {
+                    last_sequence = edit.last_sequence_;
+                    have_last_sequence = true;
+                }
+
+                if edit.has_required_features_ {
+                    required_features = edit.required_features_;
+                }
+
+                if edit.has_fencing_token_ {
+                    fencing_token = edit.fencing_token_;
+                }
+
+                if edit.has_unlogged_writes_ {
+                    unlogged_writes = edit.unlogged_writes_;
+                }
+            }
+        }
+        if s.ok() {
+            s = reporter.status_.borrow().clone();
+        }
+        if s.ok() {
+            // Refuse a database this build cannot fully read before anything
+            // else looks at its files.
+            s = check_supported(required_features);
+        }
+
+        if s.ok() {
+            if !have_next_file {
+                s = Status::corruption("no meta-nextfile entry in descriptor", "");
+            } else if !have_log_number {
+                s = Status::corruption("no meta-lognumber entry in descriptor", "");
+            } else if !have_last_sequence {
+                s = Status::corruption("no last-sequence-number entry in descriptor", "");
+            }
+
+            if !have_prev_log_number {
+                prev_log_number = 0;
+            }
+
+            self.mark_file_number_used(prev_log_number);
+            self.mark_file_number_used(log_number);
+        }
+
+        if !s.ok() {
+            let s = s.with_filename(&dscname);
+            log(self.options_.info_log.clone(), &format!("Error recovering version set with {} records: {}", 
+                read_records, s.to_string()));
+            return Err(s);
+        }
+
+        let mut v = Version::new(&self.icmp_, &self.table_cache_);
+        builder.save_to(&mut v);
+        // Install recovered version
+        self.finalize(&mut v);
+        self.append_version(v);
+        self.manifest_file_number_ = next_file;
+        self.next_file_number_ = next_file + 1;
+        self.last_sequence_ = last_sequence;
+        self.log_number_ = log_number;
+        self.prev_log_number_ = prev_log_number;
+        self.required_features_ = required_features;
+        self.fencing_token_ = fencing_token;
+        self.unlogged_writes_ = unlogged_writes;
+
+        // See if we can reuse the existing MANIFEST file.
+        Ok(!self.reuse_manifest(&dscname, &current))
+    }
+
+    /// Keep appending to the MANIFEST "dscname" if options.reuse_logs
+    /// allows it and the file is not too big yet.  Returns true iff it
+    /// will be appended to.
+    fn reuse_manifest(&mut self, dscname: &str, dscbase: &str) -> bool {
+        if !self.options_.reuse_logs {
+            return false;
+        }
+        let Some((manifest_number, FileType::DescriptorFile)) = parse_file_name(dscbase) else {
+            return false;
+        };
+        // Make new compacted MANIFEST if old one is too big
+        let manifest_size = match self.env_.get_file_size(dscname) {
+            Ok(size) if size < self.options_.max_file_size as u64 => size,
+            _ => return false,
+        };
+
+        match self.env_.new_appendable_file(dscname) {
+            Ok(file) => {
+                log(self.options_.info_log.clone(), &format!("Reusing MANIFEST {}", dscname));
+                self.descriptor_log_ = Some(Writer::new2(file.clone(), manifest_size));
+                self.descriptor_file_ = Some(file);
+                self.manifest_file_number_ = manifest_number;
+                true
+            },
+            Err(s) => {
+                log(self.options_.info_log.clone(), &format!("Reuse MANIFEST: {}", s.to_string()));
+                false
+            },
+        }
+    }
+
+    /// Return the current version.
+    pub(crate) fn current(&self) -> Arc<Version> {
+        self.current_.clone()
+    }
+
+    /// Return the current manifest file number
+    pub(crate) fn manifest_file_number(&self) -> u64 {
+        self.manifest_file_number_
+    }
+
+    /// Returns true if the last write or sync of the MANIFEST failed and
+    /// no new one has replaced it yet.
+    pub(crate) fn manifest_failed(&self) -> bool {
+        self.manifest_failed_
+    }
+
+    /// Return the number of bytes of the current manifest file that
+    /// hold edits.  Zero before the first log_and_apply() on open.
+    pub(crate) fn manifest_file_size(&self) -> u64 {
+        self.descriptor_log_.as_ref().map_or(0, |log| log.file_offset())
     }
 
     /// Allocate and return a new file number
@@ -139,15 +848,911 @@ impl VersionSet {
         self.next_file_number_ += 1;
         file_number
     }
+
+    /// Arrange to reuse "file_number" unless a newer file number has
+    /// already been allocated.
+    /// REQUIRES: "file_number" was returned by a call to NewFileNumber().
+    pub(crate) fn reuse_file_number(&mut self, file_number: u64) {
+        if self.next_file_number_ == file_number + 1 {
+            self.next_file_number_ = file_number;
+        }
+    }
+
+    /// Return the number of Table files at the specified level.
+    pub(crate) fn num_level_files(&self, level: i32) -> usize {
+        debug_assert!(level >= 0);
+        debug_assert!(level < NUM_LEVELS);
+        self.current_.num_files(level)
+    }
+
+    /// Return the optional features (bits from db::features) the
+    /// database is recorded as using.
+    pub(crate) fn required_features(&self) -> u64 {
+        self.required_features_
+    }
+
+    /// Return the largest fencing token the database was opened with,
+    /// or zero if none.
+    pub(crate) fn fencing_token(&self) -> u64 {
+        self.fencing_token_
+    }
+
+    /// Return true if the MANIFEST records that the memtables may hold
+    /// writes made with WriteOptions::disable_wal.
+    pub(crate) fn unlogged_writes(&self) -> bool {
+        self.unlogged_writes_
+    }
+
+    /// Return the last sequence number.
+    pub(crate) fn last_sequence(&self) -> SequenceNumber {
+        self.last_sequence_
+    }
+
+    /// Set the last sequence number to s.
+    pub(crate) fn set_last_sequence(&mut self, s: SequenceNumber) {
+        debug_assert!(s >= self.last_sequence_);
+        self.last_sequence_ = s;
+    }
+
+    /// Mark the specified file number as used.
+    pub(crate) fn mark_file_number_used(&mut self, number: u64) {
+        if self.next_file_number_ <= number {
+            self.next_file_number_ = number + 1;
+        }
+    }
+
+    /// Return the current log file number.
+    pub(crate) fn log_number(&self) -> u64 {
+        self.log_number_
+    }
+
+    /// Return the log file number for the log file that is currently
+    /// being compacted, or zero if there is no such log file.
+    pub(crate) fn prev_log_number(&self) -> u64 {
+        self.prev_log_number_
+    }
+
+    /// Add all files listed in any live version to *live.
+    pub(crate) fn add_live_files(&self, live: &mut BTreeSet<u64>) {
+        let old_versions = self.old_versions_.iter().filter_map(|v| v.upgrade());
+        for v in old_versions.chain(std::iter::once(self.current_.clone())) {
+            for files in &v.files_ {
+                for f in files {
+                    live.insert(f.number);
+                }
+            }
+        }
+    }
+
+    /// Estimate how many bytes compactions must rewrite to bring every
+    /// level within its limit: all of level-0 once it has enough files
+    /// to be compacted, and what each deeper level holds beyond its size
+    /// limit.  Files pulled in from the next level are not counted.
+    pub(crate) fn compaction_backlog_bytes(&self) -> u64 {
+        let v = &self.current_;
+        let mut backlog = 0;
+        if v.files_[0].len() >= L0_COMPACTION_TRIGGER as usize {
+            backlog += total_file_size(&v.files_[0]);
+        }
+        for level in 1..NUM_LEVELS - 1 {
+            let limit = max_bytes_for_level(&self.options_, level) as u64;
+            backlog += total_file_size(&v.files_[level as usize]).saturating_sub(limit);
+        }
+        backlog
+    }
+
+    /// Returns true iff some level needs a compaction.
+    pub(crate) fn needs_compaction(&self) -> bool {
+        let v = &self.current_;
+        v.compaction_score_ >= 1.0 || v.file_to_compact().is_some()
+    }
+
+    /// Pick level and inputs for a new compaction.
+    /// Returns None if there is no compaction to be done.
+    pub(crate) fn pick_compaction(&mut self) -> Option<Compaction> {
+        let current = self.current_.clone();
+
+        // We prefer compactions triggered by too much data in a level over
+        // the compactions triggered by seeks.
+        let size_compaction = current.compaction_score_ >= 1.0;
+        let seek_compaction = current.file_to_compact();
+        let mut c;
+        if size_compaction {
+            let level = current.compaction_level_;
+            debug_assert!(level >= 0);
+            debug_assert!(level + 1 < NUM_LEVELS);
+            c = Compaction::new(&self.options_, &self.icmp_, level, current.clone());
+
+            // Pick the first file that comes after compact_pointer_[level]
+            let pointer = &self.compact_pointer_[level as usize];
+            let f = current.files(level).iter()
+                .find(|f| pointer.is_empty() || self.icmp_.compare(&f.largest.encode(), &Slice::new(pointer)) == Ordering::Greater)
+                // Wrap-around to the beginning of the key space
+                .or(current.files(level).first())?;
+            c.inputs_[0].push(f.clone());
+        } else if let Some((level, f)) = seek_compaction {
+            c = Compaction::new(&self.options_, &self.icmp_, level, current.clone());
+            c.inputs_[0].push(f);
+        } else {
+            return None;
+        }
+
+        // Files in level 0 may overlap each other, so pick up all overlapping ones
+        if c.level() == 0 {
+            let (smallest, largest) = self.get_range(&c.inputs_[0]);
+            // Note that the next call will discard the file we placed in
+            // c.inputs_[0] earlier and replace it with an overlapping set
+            // which will include the picked file.
+            c.inputs_[0] = current.get_overlapping_inputs(0, Some(&smallest), Some(&largest));
+            debug_assert!(!c.inputs_[0].is_empty());
+        }
+
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    /// Return the last non-empty level, and the run of adjacent small
+    /// files in it to merge, if the level holds more files than
+    /// options_.max_bottommost_files, or its files average less than
+    /// options_.min_bottommost_file_size.  Files smaller than half the
+    /// level's file size are small; the run is the first of at least two
+    /// of them, cut once it holds a file's worth of bytes.
+    fn housekeeping_inputs(&self) -> Option<(i32, Vec<FileMetaData>)> {
+        let (max_files, min_size) = (self.options_.max_bottommost_files, self.options_.min_bottommost_file_size);
+        if max_files == 0 && min_size == 0 {
+            return None;
+        }
+        // Level-0 files may overlap, so they are left to the usual compactions
+        let level = (1..NUM_LEVELS).rev().find(|&level| !self.current_.files(level).is_empty())?;
+        let files = self.current_.files(level);
+        let too_many = max_files > 0 && files.len() > max_files;
+        let too_small = min_size > 0 && total_file_size(files) < min_size * files.len() as u64;
+        if !too_many && !too_small {
+            return None;
+        }
+        let limit = max_file_size_for_level(&self.options_, level);
+        let mut run: Vec<FileMetaData> = Vec::new();
+        for f in files {
+            if f.file_size * 2 >= limit {
+                if run.len() >= 2 {
+                    break;
+                }
+                run.clear();
+                continue;
+            }
+            run.push(f.clone());
+            if total_file_size(&run) >= limit {
+                break;
+            }
+        }
+        (run.len() >= 2).then_some((level, run))
+    }
+
+    /// Whether pick_housekeeping_compaction() would find a compaction.
+    pub(crate) fn housekeeping_due(&self) -> bool {
+        self.housekeeping_inputs().is_some()
+    }
+
+    /// Pick a housekeeping compaction: one that merges small files of the
+    /// last non-empty level into files of the usual size in the same
+    /// level, to keep the number of files down.  Only called when there
+    /// is no other compaction to do.
+    pub(crate) fn pick_housekeeping_compaction(&mut self) -> Option<Compaction> {
+        let (level, inputs) = self.housekeeping_inputs()?;
+        let mut c = Compaction::new(&self.options_, &self.icmp_, level, self.current_.clone());
+        c.inputs_[0] = inputs;
+        c.housekeeping_ = true;
+        Some(c)
+    }
+
+    /// Return a compaction object for compacting the range [begin,end] in
+    /// the specified level.  Returns None if there is nothing in that
+    /// level that overlaps the specified range.
+    /// REQUIRES: level is not the last level
+    pub(crate) fn compact_range(&mut self, level: i32, begin: Option<&InternalKey>, 
+                                end: Option<&InternalKey>) -> Option<Compaction> {
+        debug_assert!(level + 1 < NUM_LEVELS);
+        let mut inputs = self.current_.get_overlapping_inputs(level, begin, end);
+        if inputs.is_empty() {
+            return None;
+        }
+
+        // Avoid compacting too much in one shot in case the range is large.
+        // But we cannot do this for level-0 since level-0 files can overlap
+        // and we must not pick one file and drop another older file if the
+        // two files overlap.
+        if level > 0 {
+            let limit = max_file_size_for_level(&self.options_, level);
+            let mut total = 0;
+            if let Some(i) = inputs.iter().position(|f| { total += f.file_size; total >= limit }) {
+                inputs.truncate(i + 1);
+            }
+        }
+
+        let mut c = Compaction::new(&self.options_, &self.icmp_, level, self.current_.clone());
+        c.inputs_[0] = inputs;
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    /// Create an iterator that reads over the compaction inputs for "c".
+    pub(crate) fn make_input_iterator(&self, c: &Compaction) -> Box<dyn Iterator> {
+        let mut options = ReadOptions::new();
+        options.fill_cache = false;
+
+        // Level-0 files have to be merged together.  For other levels,
+        // we will make a concatenating iterator per level.
+        let mut list = Vec::new();
+        for (which, files) in c.inputs_.iter().enumerate() {
+            if files.is_empty() {
+                continue;
+            }
+            let level = c.level() + which as i32;
+            if level == 0 {
+                for f in files {
+                    list.push(self.table_cache_.new_iterator(&options, Some(0), f.number, f.file_size));
+                }
+            } else {
+                // Create concatenating iterator for the files from this level
+                let table_cache = self.table_cache_.clone();
+                list.push(new_two_level_iterator(
+                    Box::new(LevelFileNumIterator::new(&self.icmp_, files.clone())),
+                    Box::new(move |options, file_value| get_file_iterator(&table_cache, options, level, file_value)),
+                    &options));
+            }
+        }
+        new_internal_merging_iterator(&self.icmp_, list)
+    }
+
+    /// Precomputed best level for next compaction
+    fn finalize(&self, v: &mut Version) {
+        let mut best_level = -1;
+        let mut best_score = -1.0;
+
+        for level in 0..NUM_LEVELS - 1 {
+            let score = if level == 0 {
+                // We treat level-0 specially by bounding the number of files
+                // instead of number of bytes for two reasons:
+                //
+                // (1) With larger write-buffer sizes, it is nice not to do too
+                // many level-0 compactions.
+                //
+                // (2) The files in level-0 are merged on every read and
+                // therefore we wish to avoid too many files when the individual
+                // file size is small (perhaps because of a small write-buffer
+                // setting, or very high compression ratios, or lots of
+                // overwrites/deletions).
+                v.files_[0].len() as f64 / L0_COMPACTION_TRIGGER as f64
+            } else {
+                // Compute the ratio of current size to size limit.
+                total_file_size(&v.files_[level as usize]) as f64 / max_bytes_for_level(&self.options_, level)
+            };
+
+            if score > best_score {
+                best_level = level;
+                best_score = score;
+            }
+        }
+
+        v.compaction_level_ = best_level;
+        v.compaction_score_ = best_score;
+    }
+
+    fn setup_other_inputs(&mut self, c: &mut Compaction) {
+        let level = c.level();
+        let current = self.current_.clone();
+
+        add_boundary_inputs(&self.icmp_, current.files(level), &mut c.inputs_[0]);
+        let (smallest, mut largest) = self.get_range(&c.inputs_[0]);
+
+        c.inputs_[1] = current.get_overlapping_inputs(level + 1, Some(&smallest), Some(&largest));
+        add_boundary_inputs(&self.icmp_, current.files(level + 1), &mut c.inputs_[1]);
+
+        // Get entire range covered by compaction
+        let (mut all_start, mut all_limit) = self.get_range2(&c.inputs_[0], &c.inputs_[1]);
+
+        // See if we can grow the number of inputs in "level" without
+        // changing the number of "level+1" files we pick up.
+        if !c.inputs_[1].is_empty() {
+            let mut expanded0 = current.get_overlapping_inputs(level, Some(&all_start), Some(&all_limit));
+            add_boundary_inputs(&self.icmp_, current.files(level), &mut expanded0);
+            let inputs0_size = total_file_size(&c.inputs_[0]);
+            let inputs1_size = total_file_size(&c.inputs_[1]);
+            let expanded0_size = total_file_size(&expanded0);
+            if expanded0.len() > c.inputs_[0].len() && 
+                inputs1_size + expanded0_size < expanded_compaction_byte_size_limit(&self.options_) {
+                let (new_start, new_limit) = self.get_range(&expanded0);
+                let mut expanded1 = current.get_overlapping_inputs(level + 1, Some(&new_start), Some(&new_limit));
+                add_boundary_inputs(&self.icmp_, current.files(level + 1), &mut expanded1);
+                if expanded1.len() == c.inputs_[1].len() {
+                    log(self.options_.info_log.clone(), &format!("Expanding@{} {}+{} ({}+{} bytes) to {}+{} ({}+{} bytes)", 
+                        level, c.inputs_[0].len(), c.inputs_[1].len(), inputs0_size, inputs1_size, 
+                        expanded0.len(), expanded1.len(), expanded0_size, inputs1_size));
+                    largest = new_limit;
+                    c.inputs_[0] = expanded0;
+                    c.inputs_[1] = expanded1;
+                    (all_start, all_limit) = self.get_range2(&c.inputs_[0], &c.inputs_[1]);
+                }
+            }
+        }
+
+        // Compute the set of grandparent files that overlap this compaction
+        // (parent == level+1; grandparent == level+2)
+        if level + 2 < NUM_LEVELS {
+            c.grandparents_ = current.get_overlapping_inputs(level + 2, Some(&all_start), Some(&all_limit));
+        }
+
+        // Update the place where we will do the next compaction for this level.
+        // We update this immediately instead of waiting for the VersionEdit
+        // to be applied so that if the compaction fails, we will try a different
+        // key range next time.
+        self.compact_pointer_[level as usize] = largest.encode().data().to_vec();
+        c.edit_.set_compact_pointer(level, largest);
+    }
+
+    /// Return the smallest and largest keys covered by "inputs".
+    /// REQUIRES: inputs is not empty
+    fn get_range(&self, inputs: &[FileMetaData]) -> (InternalKey, InternalKey) {
+        debug_assert!(!inputs.is_empty());
+        let mut smallest = &inputs[0].smallest;
+        let mut largest = &inputs[0].largest;
+        for f in &inputs[1..] {
+            if self.icmp_.compare2(&f.smallest, smallest) == Ordering::Less {
+                smallest = &f.smallest;
+            }
+            if self.icmp_.compare2(&f.largest, largest) == Ordering::Greater {
+                largest = &f.largest;
+            }
+        }
+        (smallest.clone(), largest.clone())
+    }
+
+    /// Return the smallest and largest keys covered by "inputs1" and "inputs2".
+    /// REQUIRES: inputs is not empty
+    fn get_range2(&self, inputs1: &[FileMetaData], inputs2: &[FileMetaData]) -> (InternalKey, InternalKey) {
+        let all: Vec<FileMetaData> = inputs1.iter().chain(inputs2).cloned().collect();
+        self.get_range(&all)
+    }
+
+    fn retain_version(&mut self, v: Arc<Version>, mems: &[Arc<MemTable>]) {
+        if self.options_.keep_old_versions == 0 {
+            return;
+        }
+        self.retained_.push_back(Retained {
+            id: self.next_retained_id_,
+            version: v,
+            replaced_at_micros: self.env_.now_micros(),
+            last_sequence: self.last_sequence_,
+            mems: mems.to_vec(),
+        });
+        self.next_retained_id_ += 1;
+        // Versions aging out of the window release their files.
+        while self.retained_.len() > self.options_.keep_old_versions {
+            self.retained_.pop_front();
+        }
+    }
+
+    /// Return the retained version with the given id, if it is still kept.
+    pub(crate) fn retained_version(&self, id: u64) -> Option<&Retained> {
+        self.retained_.iter().find(|r| r.id == id)
+    }
+
+    /// Describe the retained versions, oldest first.
+    pub(crate) fn retained_versions(&self) -> Vec<RetainedVersion> {
+        self.retained_.iter().map(|r| {
+            let files = r.version.files_.iter().flatten();
+            RetainedVersion {
+                id: r.id,
+                replaced_at_micros: r.replaced_at_micros,
+                last_sequence: r.last_sequence,
+                num_files: files.clone().count(),
+                total_bytes: files.map(|f| f.file_size).sum(),
+            }
+        }).collect()
+    }
+
+    /// Total size of the table files that are no longer part of the
+    /// current version but are kept alive by older ones, whether in use by
+    /// readers or retained.
+    pub(crate) fn pinned_bytes(&self) -> u64 {
+        let mut live = BTreeSet::new();
+        for files in &self.current_.files_ {
+            live.extend(files.iter().map(|f| f.number));
+        }
+        let mut pinned = BTreeMap::new();
+        for v in self.old_versions_.iter().filter_map(|v| v.upgrade()) {
+            for f in v.files_.iter().flatten().filter(|f| !live.contains(&f.number)) {
+                pinned.insert(f.number, f.file_size);
+            }
+        }
+        pinned.values().sum()
+    }
+
+    fn append_version(&mut self, v: Version) {
+        // Make "v" current
+        let old = std::mem::replace(&mut self.current_, Arc::new(v));
+        self.old_versions_.retain(|v| v.strong_count() > 0);
+        if Arc::strong_count(&old) > 1 {
+            self.old_versions_.push(Arc::downgrade(&old));
+        }
+    }
+
+    /// Save current contents to the descriptor log.
+    fn write_snapshot(&mut self) -> Status {
+        // TODO: Break up into multiple records to reduce memory usage on recovery?
+
+        // Save metadata
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(self.icmp_.user_comparator().name());
+        if self.required_features_ != 0 {
+            edit.set_required_features(self.required_features_);
+        }
+        if self.fencing_token_ != 0 {
+            edit.set_fencing_token(self.fencing_token_);
+        }
+        if self.unlogged_writes_ {
+            edit.set_unlogged_writes(true);
+        }
+
+        // Save compaction pointers
+        for (level, pointer) in self.compact_pointer_.iter().enumerate() {
+            if !pointer.is_empty() {
+                edit.set_compact_pointer(level as i32, InternalKey::decode_from(&Slice::new(pointer)));
+            }
+        }
+
+        // Save files
+        for (level, files) in self.current_.files_.iter().enumerate() {
+            for f in files {
+                edit.add_file_with_checksum(level as i32, f.number, f.file_size, &f.smallest, &f.largest, f.file_checksum);
+            }
+        }
+
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        self.descriptor_log_.as_mut().unwrap().add_record(&Slice::new(&record))
+    }
+}
+
+/// An internal iterator.  For a given version/level pair, yields
+/// information about the files in the level.  For a given entry, key()
+/// is the largest key that occurs in the file, and value() is an
+/// 16-byte value containing the file number and file size, both
+/// encoded using encode_fixed64.
+struct LevelFileNumIterator {
+    icmp_: InternalKeyComparator,
+    flist_: Vec<FileMetaData>,
+    index_: usize,
+    value_buf_: [u8; 16],   // Backing store for value().  Holds the file number and size.
+}
+
+impl LevelFileNumIterator {
+    fn new(icmp: &InternalKeyComparator, flist: Vec<FileMetaData>) -> Self {
+        let index_ = flist.len();   // Marks as invalid
+        Self { icmp_: icmp.clone(), flist_: flist, index_, value_buf_: [0; 16] }
+    }
+
+    fn fill_value(&mut self) {
+        if let Some(f) = self.flist_.get(self.index_) {
+            self.value_buf_[..8].copy_from_slice(&encode_fixed64(f.number));
+            self.value_buf_[8..].copy_from_slice(&encode_fixed64(f.file_size));
+        }
+    }
+}
+
+impl Iterator for LevelFileNumIterator {
+    fn valid(&self) -> bool {
+        self.index_ < self.flist_.len()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index_ = 0;
+        self.fill_value();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index_ = if self.flist_.is_empty() { 0 } else { self.flist_.len() - 1 };
+        self.fill_value();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.index_ = find_file(&self.icmp_, &self.flist_, target);
+        self.fill_value();
+    }
+
+    fn next(&mut self) {
+        debug_assert!(self.valid());
+        self.index_ += 1;
+        self.fill_value();
+    }
+
+    fn prev(&mut self) {
+        debug_assert!(self.valid());
+        if self.index_ == 0 {
+            self.index_ = self.flist_.len();  // Marks as invalid
+        } else {
+            self.index_ -= 1;
+            self.fill_value();
+        }
+    }
+
+    fn key(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        self.flist_[self.index_].largest.encode()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        Slice::new(&self.value_buf_)
+    }
+
+    fn status(&self) -> Status {
+        Status::new_ok()
+    }
+}
+
+fn get_file_iterator(table_cache: &TableCache, options: &ReadOptions, level: i32, file_value: &Slice) -> Box<dyn Iterator> {
+    if file_value.size() != 16 {
+        new_error_iterator(Status::corruption("FileReader invoked with unexpected value", ""))
+    } else {
+        let data = file_value.data();
+        table_cache.new_iterator(options, Some(level), decode_fixed64_bytes(&data[..8]), decode_fixed64_bytes(&data[8..]))
+    }
+}
+
+/// Remembers the first corruption reported while reading the MANIFEST.
+struct LogReporter {
+    status_: RefCell<Status>,
+}
+
+impl Reporter for LogReporter {
+    fn corruption(&self, _bytes: usize, status: &Status) {
+        if self.status_.borrow().ok() {
+            *self.status_.borrow_mut() = status.clone();
+        }
+    }
+}
+
+/// Files added to or deleted from one level by the edits applied so far.
+struct LevelState {
+    deleted_files: BTreeSet<u64>,
+    added_files: Vec<FileMetaData>,
+}
+
+/// A helper class so we can efficiently apply a whole sequence
+/// of edits to a particular state without creating intermediate
+/// Versions that contain full copies of the intermediate state.
+struct Builder {
+    icmp_: InternalKeyComparator,
+    base_: Arc<Version>,
+    levels_: Vec<LevelState>,
+}
+
+impl Builder {
+    /// Initialize a builder with the files from *base and other info from *vset
+    fn new(icmp: &InternalKeyComparator, base: Arc<Version>) -> Self {
+        let levels_ = (0..NUM_LEVELS).map(|_| LevelState { 
+            deleted_files: BTreeSet::new(), 
+            added_files: Vec::new(),
+        }).collect();
+        Self { icmp_: icmp.clone(), base_: base, levels_ }
+    }
+
+    /// Apply all of the edits in *edit to the current state.
+    fn apply(&mut self, edit: &VersionEdit, compact_pointer: &mut [Vec<u8>]) {
+        // Update compaction pointers
+        for (level, key) in &edit.compact_pointers_ {
+            compact_pointer[*level as usize] = key.encode().data().to_vec();
+        }
+
+        // Delete files
+        for &(level, number) in &edit.deleted_files_ {
+            self.levels_[level as usize].deleted_files.insert(number);
+        }
+
+        // Add new files
+        for (level, meta) in &edit.new_files_ {
+            let mut f = meta.clone();
+            f.refs = 1;
+
+            // We arrange to automatically compact this file after
+            // a certain number of seeks.  Let's assume:
+            //   (1) One seek costs 10ms
+            //   (2) Writing or reading 1MB costs 10ms (100MB/s)
+            //   (3) A compaction of 1MB does 25MB of IO:
+            //         1MB read from this level
+            //         10-12MB read from next level (boundaries may be misaligned)
+            //         10-12MB written to next level
+            // This implies that 25 seeks cost the same as the compaction
+            // of 1MB of data.  I.e., one seek costs approximately the
+            // same as the compaction of 40KB of data.  We are a little
+            // conservative and allow approximately one seek for every 16KB
+            // of data before triggering a compaction.
+            f.allowed_seeks = AllowedSeeks::new(((f.file_size / 16384) as i32).max(100));
+
+            self.levels_[*level as usize].deleted_files.remove(&f.number);
+            self.levels_[*level as usize].added_files.push(f);
+        }
+    }
+
+    /// Save the current state in *v.
+    fn save_to(&self, v: &mut Version) {
+        let by_smallest_key = |f1: &FileMetaData, f2: &FileMetaData| {
+            match self.icmp_.compare2(&f1.smallest, &f2.smallest) {
+                Ordering::Equal => f1.number.cmp(&f2.number), // Break ties by file number
+                r => r,
+            }
+        };
+        for level in 0..(NUM_LEVELS as usize) {
+            // Merge the set of added files with the set of pre-existing files.
+            // Drop any deleted files.  Store the result in *v.
+            let base_files = &self.base_.files_[level];
+            let mut added_files = self.levels_[level].added_files.clone();
+            added_files.sort_by(by_smallest_key);
+            v.files_[level].reserve(base_files.len() + added_files.len());
+
+            let mut base_iter = base_files.iter().peekable();
+            for added_file in &added_files {
+                // Add all smaller files listed in base_
+                while let Some(base_file) = base_iter.next_if(|f| by_smallest_key(f, added_file) == Ordering::Less) {
+                    self.maybe_add_file(v, level, base_file);
+                }
+                self.maybe_add_file(v, level, added_file);
+            }
+
+            // Add remaining base files
+            for base_file in base_iter {
+                self.maybe_add_file(v, level, base_file);
+            }
+
+            // Make sure there is no overlap in levels > 0
+            if cfg!(debug_assertions) && level > 0 {
+                for i in 1..v.files_[level].len() {
+                    let prev_end = &v.files_[level][i - 1].largest;
+                    let this_begin = &v.files_[level][i].smallest;
+                    assert!(self.icmp_.compare2(prev_end, this_begin) == Ordering::Less, 
+                            "overlapping ranges in same level");
+                }
+            }
+        }
+    }
+
+    fn maybe_add_file(&self, v: &mut Version, level: usize, f: &FileMetaData) {
+        if self.levels_[level].deleted_files.contains(&f.number) {
+            // File is deleted: do nothing
+        } else {
+            let files = &mut v.files_[level];
+            if level > 0 && !files.is_empty() {
+                // Must not overlap
+                debug_assert!(self.icmp_.compare2(&files.last().unwrap().largest, &f.smallest) == Ordering::Less);
+            }
+            files.push(f.clone());
+        }
+    }
 }
 
 /// A Compaction encapsulates information about a compaction.
 pub(crate) struct Compaction {
+    level_: i32,
+    max_output_file_size_: u64,
+    max_grand_parent_overlap_bytes_: u64,
+    icmp_: InternalKeyComparator,
+    input_version_: Arc<Version>,
+    edit_: VersionEdit,
+
+    // Each compaction reads inputs from "level_" and "level_+1"
+    inputs_: [Vec<FileMetaData>; 2],    // The two sets of inputs
+
+    // State used to check for number of overlapping grandparent files
+    // (parent == level_ + 1, grandparent == level_ + 2)
+    grandparents_: Vec<FileMetaData>,
+    grandparent_index_: usize,  // Index in grandparents_
+    seen_key_: bool,            // Some output key has been seen
+    overlapped_bytes_: u64,     // Bytes of overlap between current output
+                                // and grandparent files
+
+    // State for implementing is_base_level_for_key
 
+    // level_ptrs_ holds indices into input_version_.files_: our state
+    // is that we are positioned at one of the file ranges for each
+    // higher level than the ones involved in this compaction (i.e. for
+    // all L > output_level()).
+    level_ptrs_: Vec<usize>,
+
+    // Set for a compaction that merges files of the last level into the
+    // same level; see VersionSet::pick_housekeeping_compaction().
+    housekeeping_: bool,
 }
+
 impl Compaction {
-    fn new() -> Self {
-        todo!()
+    fn new(options: &Options, icmp: &InternalKeyComparator, level: i32, input_version: Arc<Version>) -> Self {
+        Self {
+            level_: level,
+            max_output_file_size_: max_file_size_for_level(options, level),
+            max_grand_parent_overlap_bytes_: max_grand_parent_overlap_bytes(options),
+            icmp_: icmp.clone(),
+            input_version_: input_version,
+            edit_: VersionEdit::new(),
+            inputs_: [Vec::new(), Vec::new()],
+            grandparents_: Vec::new(),
+            grandparent_index_: 0,
+            seen_key_: false,
+            overlapped_bytes_: 0,
+            level_ptrs_: vec![0; NUM_LEVELS as usize],
+            housekeeping_: false,
+        }
+    }
+
+    /// Return the level that is being compacted.  Inputs from "level"
+    /// and "level+1" will be merged to produce a set of "level+1" files.
+    pub(crate) fn level(&self) -> i32 {
+        self.level_
+    }
+
+    /// Return the level the outputs go to: "level+1", or "level" itself
+    /// for a housekeeping compaction.
+    pub(crate) fn output_level(&self) -> i32 {
+        if self.housekeeping_ { self.level_ } else { self.level_ + 1 }
+    }
+
+    /// Whether this is a housekeeping compaction, which merges files of
+    /// the last non-empty level into the same level.
+    pub(crate) fn is_housekeeping(&self) -> bool {
+        self.housekeeping_
+    }
+
+    /// Return the object that holds the edits to the descriptor done
+    /// by this compaction.
+    pub(crate) fn edit(&mut self) -> &mut VersionEdit {
+        &mut self.edit_
+    }
+
+    /// "which" must be either 0 or 1
+    pub(crate) fn num_input_files(&self, which: usize) -> usize {
+        self.inputs_[which].len()
+    }
+
+    /// Return the ith input file at "level()+which" ("which" must be 0 or 1).
+    pub(crate) fn input(&self, which: usize, i: usize) -> &FileMetaData {
+        &self.inputs_[which][i]
+    }
+
+    /// Maximum size of files to build during this compaction.
+    pub(crate) fn max_output_file_size(&self) -> u64 {
+        self.max_output_file_size_
+    }
+
+    /// Is this a trivial compaction that can be implemented by just
+    /// moving a single input file to the next level (no merging or splitting)
+    pub(crate) fn is_trivial_move(&self) -> bool {
+        // Avoid a move if there is lots of overlapping grandparent data.
+        // Otherwise, the move could create a parent file that will require
+        // a very expensive merge later on.
+        self.num_input_files(0) == 1 && self.num_input_files(1) == 0 &&
+            total_file_size(&self.grandparents_) <= self.max_grand_parent_overlap_bytes_
+    }
+
+    /// Total size of the input files.
+    pub(crate) fn input_bytes(&self) -> u64 {
+        total_file_size(&self.inputs_[0]) + total_file_size(&self.inputs_[1])
+    }
+
+    /// Pick up to "max_subranges - 1" user keys, each the smallest key of
+    /// an input file, that split the input into subranges holding about
+    /// the same number of bytes.  A subrange starts at its split key,
+    /// inclusive, and ends at the next one, exclusive.  Returns no keys if
+    /// the inputs do not split.
+    pub(crate) fn split_keys(&self, max_subranges: usize) -> Vec<Vec<u8>> {
+        let user_cmp = self.icmp_.user_comparator();
+        let files: Vec<&FileMetaData> = self.inputs_.iter().flatten().collect();
+        let total = self.input_bytes();
+        let mut candidates: Vec<Slice> = files.iter().map(|f| f.smallest.user_key()).collect();
+        candidates.sort_by(|a, b| user_cmp.compare(a, b));
+        candidates.dedup_by(|a, b| user_cmp.compare(a, b) == Ordering::Equal);
+
+        // Splitting at the smallest key would leave the first subrange
+        // empty.
+        let mut keys = Vec::new();
+        for key in candidates.iter().skip(1) {
+            if keys.len() + 1 >= max_subranges {
+                break;
+            }
+            // Bytes of the files starting before "key"
+            let before: u64 = files.iter()
+                .filter(|f| user_cmp.compare(&f.smallest.user_key(), key) == Ordering::Less)
+                .map(|f| f.file_size)
+                .sum();
+            if before * max_subranges as u64 >= total * (keys.len() as u64 + 1) {
+                keys.push(key.data().to_vec());
+            }
+        }
+        keys
+    }
+
+    /// Return a compaction over the same inputs, with its own state for
+    /// should_stop_before() and is_base_level_for_key() and an empty
+    /// edit, for a subcompaction to walk its subrange with.
+    pub(crate) fn new_subcompaction(&self) -> Compaction {
+        Self {
+            level_: self.level_,
+            max_output_file_size_: self.max_output_file_size_,
+            max_grand_parent_overlap_bytes_: self.max_grand_parent_overlap_bytes_,
+            icmp_: self.icmp_.clone(),
+            input_version_: self.input_version_.clone(),
+            edit_: VersionEdit::new(),
+            inputs_: self.inputs_.clone(),
+            grandparents_: self.grandparents_.clone(),
+            grandparent_index_: 0,
+            seen_key_: false,
+            overlapped_bytes_: 0,
+            level_ptrs_: vec![0; NUM_LEVELS as usize],
+            housekeeping_: self.housekeeping_,
+        }
+    }
+
+    /// Add all inputs to this compaction as delete operations to edit().
+    pub(crate) fn add_input_deletions(&mut self) {
+        for which in 0..2 {
+            for f in &self.inputs_[which] {
+                self.edit_.remove_file(self.level_ + which as i32, f.number);
+            }
+        }
+    }
+
+    /// Returns true if the information we have available guarantees that
+    /// the compaction is producing data in output_level() for which no
+    /// data exists in levels greater than output_level().
+    pub(crate) fn is_base_level_for_key(&mut self, user_key: &Slice) -> bool {
+        // Maybe use binary search to find right entry instead of linear search?
+        let user_cmp = self.icmp_.user_comparator();
+        for lvl in (self.output_level() + 1) as usize..NUM_LEVELS as usize {
+            let files = &self.input_version_.files_[lvl];
+            while let Some(f) = files.get(self.level_ptrs_[lvl]) {
+                if user_cmp.compare(user_key, &f.largest.user_key()) != Ordering::Greater {
+                    // We've advanced far enough
+                    if user_cmp.compare(user_key, &f.smallest.user_key()) != Ordering::Less {
+                        // Key falls in this file's range, so definitely not base level
+                        return false;
+                    }
+                    break;
+                }
+                self.level_ptrs_[lvl] += 1;
+            }
+        }
+        true
+    }
+
+    /// Returns true iff a file of the grandparent level ends between the
+    /// key last passed to should_stop_before() and "internal_key", so
+    /// that the entries up to "internal_key" might not all have gone to
+    /// the current output.
+    pub(crate) fn grandparent_boundary_before(&self, internal_key: &Slice) -> bool {
+        self.grandparents_.get(self.grandparent_index_)
+            .is_some_and(|f| self.icmp_.compare(&f.largest.encode(), internal_key) == Ordering::Less)
+    }
+
+    /// Returns true iff we should stop building the current output
+    /// before processing "internal_key".
+    pub(crate) fn should_stop_before(&mut self, internal_key: &Slice) -> bool {
+        // Scan to find earliest grandparent file that contains key.
+        while let Some(f) = self.grandparents_.get(self.grandparent_index_) {
+            if self.icmp_.compare(internal_key, &f.largest.encode()) != Ordering::Greater {
+                break;
+            }
+            if self.seen_key_ {
+                self.overlapped_bytes_ += f.file_size;
+            }
+            self.grandparent_index_ += 1;
+        }
+        self.seen_key_ = true;
+
+        if self.overlapped_bytes_ > self.max_grand_parent_overlap_bytes_ {
+            // Too much overlap for current output; start new output
+            self.overlapped_bytes_ = 0;
+            true
+        } else {
+            false
+        }
     }
 }
 
@@ -225,7 +1830,7 @@ fn add_boundary_inputs(icmp: &InternalKeyComparator,
 
 #[cfg(test)]
 mod tests {
-    use crate::{comparator::bytewise_comparator, db::{dbformat::{InternalKey, InternalKeyComparator, ValueType}, version_edit::{FileMetaData, SequenceNumber}}, slice::Slice};
+    use crate::{comparator::bytewise_comparator, db::{dbformat::{InternalKey, InternalKeyComparator, ValueType}, filename::table_file_name, version_edit::{FileMetaData, SequenceNumber}}, helpers::memenv::new_mem_env, slice::Slice, sync_point, table::table_builder::TableBuilder, util::env::write_string_to_file_sync};
 
     use super::*;
 
@@ -252,8 +1857,12 @@ mod tests {
         }
         fn overlaps(&self, smallest: &str, largest: &str) -> bool {
             let cmp = InternalKeyComparator::new(bytewise_comparator());
+            // "" stands for an unbounded end of the range
+            fn bound(s: &str) -> Option<Slice<'_>> {
+                (!s.is_empty()).then(|| Slice::new(s.as_bytes()))
+            }
             some_file_overlaps_range(&cmp, self.disjoint_sorted_files_, &self.files_,
-            &Slice::new(smallest.as_bytes()), &Slice::new(largest.as_bytes()))
+            bound(smallest).as_ref(), bound(largest).as_ref())
         }
     }
 
@@ -551,4 +2160,296 @@ mod tests {
         assert_eq!(f4, t.compaction_files_[1]);
         assert_eq!(f3, t.compaction_files_[2]);
     }
+
+    #[test]
+    fn builder_allowed_seeks_test() {
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let table_cache = Arc::new(TableCache::new("/db", &Options::new()));
+        let key = |k: &str| InternalKey::new_from(&Slice::new(k.as_bytes()), 1, ValueType::type_value());
+        let mut edit = VersionEdit::new();
+        for (number, size, smallest, largest) in [(1, 0, "a", "b"), (2, 16384 * 100 - 1, "c", "d"),
+                                                  (3, 16384 * 100, "e", "f"), (4, 16384 * 1000 + 5, "g", "h")] {
+            edit.add_file(1, number, size, &key(smallest), &key(largest));
+        }
+        let mut compact_pointer = vec![Vec::new(); NUM_LEVELS as usize];
+        let mut builder = Builder::new(&icmp, Arc::new(Version::new(&icmp, &table_cache)));
+        builder.apply(&edit, &mut compact_pointer);
+        let mut v = Version::new(&icmp, &table_cache);
+        builder.save_to(&mut v);
+
+        // One seek per 16KB, and at least 100
+        let allowed_seeks = |v: &Version| v.files(1).iter().map(|f| f.allowed_seeks.get()).collect::<Vec<_>>();
+        assert_eq!(vec![100, 100, 100, 1000], allowed_seeks(&v));
+
+        // Later versions share the count of the files they keep
+        let v = Arc::new(v);
+        let mut stats = GetStats::new();
+        stats.seek_file = v.files(1)[0].clone();
+        stats.seek_file_level = 1;
+        for _ in 0..99 {
+            assert!(!v.update_stats(&stats));
+        }
+        let mut builder = Builder::new(&icmp, v.clone());
+        builder.apply(&VersionEdit::new(), &mut compact_pointer);
+        let mut next = Version::new(&icmp, &table_cache);
+        builder.save_to(&mut next);
+        assert_eq!(vec![1, 100, 100, 1000], allowed_seeks(&next));
+
+        // The last allowed seek marks the file for compaction, once
+        assert!(v.update_stats(&stats));
+        assert_eq!(Some((1, 1)), v.file_to_compact().map(|(level, f)| (level, f.number)));
+        assert!(!v.update_stats(&stats));
+        assert!(!v.update_stats(&GetStats::new()));
+        assert_eq!(-1, allowed_seeks(&next)[0]);
+    }
+
+    #[test]
+    fn record_read_sample_test() {
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let table_cache = Arc::new(TableCache::new("/db", &Options::new()));
+        let key = |k: &str| InternalKey::new_from(&Slice::new(k.as_bytes()), 1, ValueType::type_value());
+        let mut edit = VersionEdit::new();
+        edit.add_file(1, 1, 1000, &key("a"), &key("m"));
+        edit.add_file(2, 2, 1000, &key("a"), &key("z"));
+        let mut builder = Builder::new(&icmp, Arc::new(Version::new(&icmp, &table_cache)));
+        builder.apply(&edit, &mut vec![Vec::new(); NUM_LEVELS as usize]);
+        let mut v = Version::new(&icmp, &table_cache);
+        builder.save_to(&mut v);
+        let allowed_seeks = |v: &Version| [v.files(1)[0].allowed_seeks.get(), v.files(2)[0].allowed_seeks.get()];
+
+        // Keys in a single file, and garbage, are not charged
+        assert!(!v.record_read_sample(&key("p").encode()));
+        assert!(!v.record_read_sample(&Slice::new(b"short")));
+        assert_eq!([100, 100], allowed_seeks(&v));
+
+        // Keys in both are charged to the first file searched
+        for _ in 0..99 {
+            assert!(!v.record_read_sample(&key("c").encode()));
+        }
+        assert_eq!([1, 100], allowed_seeks(&v));
+        assert!(v.record_read_sample(&key("c").encode()));
+        assert_eq!(Some((1, 1)), v.file_to_compact().map(|(level, f)| (level, f.number)));
+    }
+
+    #[test]
+    fn get_test() {
+        let env = new_mem_env();
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let options = Options { env: env.clone(), comparator: Arc::new(icmp.clone()), ..Options::new() };
+        let table_cache = Arc::new(TableCache::new("/db", &options));
+        let key = |k: &str, seq, t| InternalKey::new_from(&Slice::new(k.as_bytes()), seq, t);
+        let (value, deletion) = (ValueType::type_value(), ValueType::type_deletion());
+        let mut edit = VersionEdit::new();
+        let mut add_table = |level, number, entries: &[(&str, SequenceNumber, ValueType, &str)]| {
+            let file = env.new_writable_file(&table_file_name("/db", number)).unwrap();
+            let mut builder = TableBuilder::new(&options, file.clone());
+            for &(k, seq, t, v) in entries {
+                builder.add(&key(k, seq, t).encode(), &Slice::new(v.as_bytes()));
+            }
+            assert!(builder.finish().ok() && file.close().ok());
+            let (first, last) = (entries[0], entries[entries.len() - 1]);
+            edit.add_file(level, number, builder.file_size(), &key(first.0, first.1, first.2), &key(last.0, last.1, last.2));
+        };
+        // Two overlapping level-0 files, the newer one overwriting "a" and
+        // deleting "c", above a level-1 file.
+        add_table(0, 1, &[("a", 1, value, "a1"), ("b", 2, value, "b1"), ("c", 3, value, "c1")]);
+        add_table(0, 2, &[("a", 4, value, "a2"), ("c", 5, deletion, "")]);
+        add_table(1, 3, &[("b", 0, value, "b0"), ("d", 0, value, "d0")]);
+        let mut builder = Builder::new(&icmp, Arc::new(Version::new(&icmp, &table_cache)));
+        builder.apply(&edit, &mut vec![Vec::new(); NUM_LEVELS as usize]);
+        let mut v = Version::new(&icmp, &table_cache);
+        builder.save_to(&mut v);
+
+        let get = |k: &str, seq| {
+            let mut stats = GetStats::new();
+            let result = v.get(&ReadOptions::new(), &LookupKey::new(&Slice::new(k.as_bytes()), seq), false, &mut stats);
+            let charged = (stats.seek_file_level >= 0).then_some((stats.seek_file_level, stats.seek_file.number));
+            (result.map_err(|s| s.is_not_found()), charged, stats.files_probed[..2].to_vec())
+        };
+        // The newest level-0 file wins, and a lookup that stops at the
+        // first file probed charges no seek.
+        assert_eq!((Ok(b"a2".to_vec()), None, vec![1, 0]), get("a", 100));
+        // Older data shows through at an older sequence.
+        assert_eq!((Ok(b"a1".to_vec()), Some((0, 2)), vec![2, 0]), get("a", 3));
+        // A lookup that probes more than one file charges the first.
+        assert_eq!((Ok(b"b1".to_vec()), Some((0, 2)), vec![2, 0]), get("b", 100));
+        assert_eq!((Err(true), None, vec![1, 0]), get("c", 100));
+        assert_eq!((Ok(b"c1".to_vec()), Some((0, 2)), vec![2, 0]), get("c", 4));
+        assert_eq!((Ok(b"d0".to_vec()), None, vec![0, 1]), get("d", 100));
+        assert_eq!((Err(true), None, vec![0, 0]), get("z", 100));
+    }
+
+    #[test]
+    fn split_keys_test() {
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let table_cache = Arc::new(TableCache::new("/db", &Options::new()));
+        let mut c = Compaction::new(&Options::new(), &icmp, 1, Arc::new(Version::new(&icmp, &table_cache)));
+        let file = |number, size, smallest: &str, largest: &str| {
+            let mut f = FileMetaData::new();
+            f.number = number;
+            f.file_size = size;
+            f.smallest = InternalKey::new_from(&Slice::new(smallest.as_bytes()), 1, ValueType::type_value());
+            f.largest = InternalKey::new_from(&Slice::new(largest.as_bytes()), 1, ValueType::type_value());
+            f
+        };
+        let split_keys = |c: &Compaction, max| c.split_keys(max).into_iter().map(|k| String::from_utf8(k).unwrap()).collect::<Vec<_>>();
+        assert!(split_keys(&c, 4).is_empty());
+
+        c.inputs_[0] = vec![file(1, 100, "a", "c"), file(2, 100, "e", "g")];
+        c.inputs_[1] = vec![file(3, 100, "a", "b"), file(4, 100, "c", "d"), file(5, 100, "e", "f"), file(6, 100, "g", "h")];
+        assert_eq!(600, c.input_bytes());
+        assert!(split_keys(&c, 1).is_empty());
+        // 300 bytes start before "e"
+        assert_eq!(vec!["e"], split_keys(&c, 2));
+        assert_eq!(vec!["c", "e", "g"], split_keys(&c, 4));
+        // Never more subranges than distinct file starts
+        assert_eq!(vec!["c", "e", "g"], split_keys(&c, 10));
+
+        // A file holding several shares gets a subrange to itself
+        c.inputs_[1] = vec![file(3, 1000, "a", "b"), file(4, 10, "c", "d")];
+        assert_eq!(vec!["c", "e"], split_keys(&c, 4));
+        c.inputs_ = [vec![file(1, 100, "a", "z")], Vec::new()];
+        assert!(split_keys(&c, 4).is_empty());
+    }
+
+    /// Write "edits" to MANIFEST-000001 under "/db", less the last
+    /// "cut" bytes, and point CURRENT at it.
+    fn write_manifest(env: &Arc<dyn Env>, edits: &[VersionEdit], cut: u64) {
+        let _ = env.create_dir("/db");
+        let fname = descriptor_file_name("/db", 1);
+        let mut writer = Writer::new(env.new_writable_file(&fname).unwrap());
+        for edit in edits {
+            let mut record = Vec::new();
+            edit.encode_to(&mut record);
+            assert!(writer.add_record(&Slice::new(&record)).ok());
+        }
+        if cut > 0 {
+            let size = env.get_file_size(&fname).unwrap();
+            let data = env.new_random_access_file(&fname).unwrap().read(0, (size - cut) as usize).unwrap();
+            let file = env.new_writable_file(&fname).unwrap();
+            assert!(file.append(&Slice::new(&data)).ok() && file.close().ok());
+        }
+        assert!(set_current_file(env.clone(), "/db", 1).ok());
+    }
+
+    fn recover(env: &Arc<dyn Env>, reuse_logs: bool) -> (VersionSet, Result<bool, Status>) {
+        let options = Options { env: env.clone(), reuse_logs, ..Options::new() };
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let table_cache = Arc::new(TableCache::new("/db", &options));
+        let mut versions = VersionSet::new("/db", &options, &table_cache, &icmp);
+        let result = versions.recover();
+        (versions, result)
+    }
+
+    #[test]
+    fn recover_test() {
+        let env = new_mem_env();
+        let key = |k: &str, seq| InternalKey::new_from(&Slice::new(k.as_bytes()), seq, ValueType::type_value());
+        let first = || {
+            let mut edit = VersionEdit::new();
+            edit.set_comparator_name(bytewise_comparator().name());
+            edit.set_log_number(5);
+            edit.set_next_file(10);
+            edit.set_last_sequence(100);
+            edit.add_file(0, 6, 1000, &key("a", 1), &key("m", 50));
+            edit
+        };
+        let second = || {
+            let mut edit = VersionEdit::new();
+            edit.set_prev_log_number(4);
+            edit.set_last_sequence(200);
+            edit.add_file(2, 7, 2000, &key("n", 60), &key("z", 150));
+            edit.remove_file(0, 6);
+            edit
+        };
+
+        write_manifest(&env, &[first(), second()], 0);
+        let (mut versions, result) = recover(&env, false);
+        assert!(result.unwrap());
+        assert_eq!((0, 1), (versions.num_level_files(0), versions.num_level_files(2)));
+        assert_eq!((200, 5, 4), (versions.last_sequence(), versions.log_number(), versions.prev_log_number()));
+        assert_eq!(10, versions.manifest_file_number());
+        assert_eq!(11, versions.new_file_number());
+
+        // With reuse_logs, the MANIFEST is appended to instead.
+        let (versions, result) = recover(&env, true);
+        assert!(!result.unwrap());
+        assert_eq!(1, versions.manifest_file_number());
+
+        // A record cut short at the end is dropped, as a crash in the
+        // middle of writing it would leave it.
+        write_manifest(&env, &[first(), second()], 3);
+        let (versions, result) = recover(&env, false);
+        assert!(result.is_ok());
+        assert_eq!((1, 0), (versions.num_level_files(0), versions.num_level_files(2)));
+        assert_eq!((100, 0), (versions.last_sequence(), versions.prev_log_number()));
+
+        let without = |clear: fn(&mut VersionEdit)| {
+            let mut edit = first();
+            clear(&mut edit);
+            edit
+        };
+        for (edit, msg) in [(without(|edit| edit.has_last_sequence_ = false), "no last-sequence-number entry"),
+                            (without(|edit| edit.has_next_file_number_ = false), "no meta-nextfile entry"),
+                            (without(|edit| edit.has_log_number_ = false), "no meta-lognumber entry")] {
+            write_manifest(&env, &[edit], 0);
+            let s = recover(&env, false).1.unwrap_err();
+            assert!(s.is_corruption() && s.to_string().contains(msg), "{}", s.to_string());
+        }
+
+        let mut other_comparator = first();
+        other_comparator.set_comparator_name("leveldb.OtherComparator");
+        write_manifest(&env, &[other_comparator, second()], 0);
+        let s = recover(&env, false).1.unwrap_err();
+        assert!(s.is_invalid_argument() && s.to_string().contains("leveldb.OtherComparator"), "{}", s.to_string());
+
+        // CURRENT must end in a newline, and name a file that exists.
+        assert!(write_string_to_file_sync(env.clone(), &Slice::new(b"MANIFEST-000001"), &current_file_name("/db")).ok());
+        assert!(recover(&env, false).1.unwrap_err().is_corruption());
+        assert!(env.remove_file(&descriptor_file_name("/db", 1)).ok());
+        assert!(set_current_file(env.clone(), "/db", 1).ok());
+        let s = recover(&env, false).1.unwrap_err();
+        assert!(s.to_string().contains("MANIFEST-000001"), "{}", s.to_string());
+    }
+
+    #[test]
+    fn manifest_failure_test() {
+        let env = new_mem_env();
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(bytewise_comparator().name());
+        edit.set_log_number(5);
+        edit.set_next_file(10);
+        edit.set_last_sequence(100);
+        write_manifest(&env, &[edit], 0);
+        let (mut versions, result) = recover(&env, false);
+        assert!(result.unwrap());
+        let add = |versions: &mut VersionSet, k: &str| {
+            let key = |k: &str| InternalKey::new_from(&Slice::new(k.as_bytes()), 1, ValueType::type_value());
+            let mut edit = VersionEdit::new();
+            edit.add_file(1, versions.new_file_number(), 1000, &key(k), &key(k));
+            versions.log_and_apply(&mut edit, None)
+        };
+
+        assert!(add(&mut versions, "a").ok());
+        let manifest = versions.manifest_file_number();
+
+        // An edit whose sync fails is not applied, and that MANIFEST is
+        // never written again, though it stays CURRENT for now.
+        {
+            let _failure = sync_point::fail("versionset:after-manifest-sync", Status::io_error("sync", "injected"));
+            assert!(add(&mut versions, "b").is_io_error());
+        }
+        assert!(versions.manifest_failed());
+        assert_eq!((manifest, 1), (versions.manifest_file_number(), versions.num_level_files(1)));
+        assert_eq!(format!("MANIFEST-{:06}\n", manifest), read_file_to_string(env.clone(), &current_file_name("/db")).unwrap());
+
+        // The next edit starts a new one
+        assert!(add(&mut versions, "c").ok());
+        assert!(!versions.manifest_failed());
+        assert!(versions.manifest_file_number() > manifest);
+        assert_eq!(format!("MANIFEST-{:06}\n", versions.manifest_file_number()), read_file_to_string(env.clone(), &current_file_name("/db")).unwrap());
+        let (versions, result) = recover(&env, false);
+        assert!(result.is_ok());
+        assert_eq!(2, versions.num_level_files(1));
+    }
 }
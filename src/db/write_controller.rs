@@ -0,0 +1,126 @@
+//! Write delays that grow with compaction debt.  See DB::write_controller().
+//!
+//! Compaction debt is the work the background thread has to catch up on
+//! before writes can go on at full speed: the memtable waiting to be
+//! flushed, if any, plus the bytes compactions are due to rewrite (see
+//! DB::estimate_compaction_backlog()).  The delay of each write follows
+//! the curve between Options::write_delay_start and
+//! Options::write_delay_full.
+//!
+//! Level-0 files count on their own, as every one of them slows down
+//! reads.  A level-0 file count between l0_slowdown_writes_trigger and
+//! l0_stop_writes_trigger is placed on the curve as if it were a debt
+//! between the two breakpoints, and the larger of the two delays is
+//! taken.  So with the defaults, writes are delayed by 1ms at the
+//! slowdown trigger, as they always were, and by more as level-0 nears
+//! the stop trigger, which still stops them outright.
+
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use crate::{env::Env, options::WriteDelayPoint};
+
+/// A handle on the delays the DB puts on writes, shared with it.  Users
+/// with their own admission control can feed it estimated_delay_per_write(),
+/// or wait in acquire() before handing work to the DB.
+pub struct WriteController {
+    env_: Arc<dyn Env>,
+    start_: WriteDelayPoint,
+    full_: WriteDelayPoint,
+
+    // Set under the DB's mutex_ whenever the debt may have changed: when
+    // a version is installed, a memtable retired, or an option changed.
+    // Read without it.
+    debt_bytes_: AtomicU64,
+    delay_micros_: AtomicU64,
+}
+
+impl WriteController {
+    pub(crate) fn new(env: Arc<dyn Env>, start: WriteDelayPoint, full: WriteDelayPoint) -> Self {
+        Self { env_: env, start_: start, full_: full, debt_bytes_: AtomicU64::new(0), delay_micros_: AtomicU64::new(0) }
+    }
+
+    /// Recompute the delay for "debt_bytes" of compaction debt and
+    /// "l0_files" level-0 files, with writes slowed down at "slowdown"
+    /// level-0 files and stopped at "stop".
+    pub(crate) fn update(&self, debt_bytes: u64, l0_files: i32, slowdown: i32, stop: i32) {
+        let l0_debt = if l0_files < slowdown {
+            0
+        } else if l0_files >= stop {
+            self.full_.debt_bytes
+        } else {
+            let span = self.full_.debt_bytes.saturating_sub(self.start_.debt_bytes) as u128;
+            self.start_.debt_bytes + (span * (l0_files - slowdown) as u128 / (stop - slowdown) as u128) as u64
+        };
+        self.debt_bytes_.store(debt_bytes, Ordering::Release);
+        self.delay_micros_.store(self.delay_at(debt_bytes.max(l0_debt)), Ordering::Release);
+    }
+
+    /// Returns the delay on the curve for "debt" bytes of debt.
+    fn delay_at(&self, debt: u64) -> u64 {
+        let (start, full) = (&self.start_, &self.full_);
+        if debt < start.debt_bytes {
+            0
+        } else if debt >= full.debt_bytes {
+            full.delay_micros
+        } else {
+            let along = (debt - start.debt_bytes) as f64 / (full.debt_bytes - start.debt_bytes) as f64;
+            (start.delay_micros as f64 + (full.delay_micros as f64 - start.delay_micros as f64) * along) as u64
+        }
+    }
+
+    /// Returns the compaction debt in bytes, as of the last time it changed.
+    /// Level-0 files count only through the bytes compactions will
+    /// rewrite for them.
+    pub fn compaction_debt_bytes(&self) -> u64 {
+        self.debt_bytes_.load(Ordering::Acquire)
+    }
+
+    /// Returns how many microseconds each write is delayed by now.  Zero
+    /// while compactions keep up.
+    pub fn estimated_delay_per_write(&self) -> u64 {
+        self.delay_micros_.load(Ordering::Acquire)
+    }
+
+    /// Wait out the delay of a write of "bytes" bytes with the DB's Env,
+    /// and return how many microseconds that was.  Empty writes add no
+    /// debt and are not delayed.
+    pub fn acquire(&self, bytes: u64) -> u64 {
+        let delay = if bytes == 0 { 0 } else { self.estimated_delay_per_write() };
+        if delay > 0 {
+            self.env_.sleep_for_microseconds(delay);
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::default_env;
+
+    use super::*;
+
+    #[test]
+    fn delay_curve_test() {
+        let controller = WriteController::new(default_env(), WriteDelayPoint { debt_bytes: 1000, delay_micros: 100 },
+                                              WriteDelayPoint { debt_bytes: 2000, delay_micros: 1100 });
+        let delay = |debt, l0_files| {
+            controller.update(debt, l0_files, 8, 12);
+            controller.estimated_delay_per_write()
+        };
+        assert_eq!(0, delay(0, 0));
+        assert_eq!(0, delay(999, 7));
+        assert_eq!(999, controller.compaction_debt_bytes());
+        assert_eq!(100, delay(1000, 0));
+        assert_eq!(600, delay(1500, 0));
+        assert_eq!(1100, delay(2000, 0));
+        assert_eq!(1100, delay(u64::MAX, 0));
+
+        // Level-0 files go from the first breakpoint at the slowdown
+        // trigger to the second at the stop trigger.
+        assert_eq!(100, delay(0, 8));
+        assert_eq!(350, delay(0, 9));
+        assert_eq!(1100, delay(0, 12));
+        assert_eq!(850, delay(1750, 9));
+        assert_eq!(0, controller.acquire(0));
+    }
+}
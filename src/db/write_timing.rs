@@ -0,0 +1,157 @@
+//! Where the time of a write went: waiting in the writer queue, appending
+//! to the log, syncing it, and inserting into the memtable.  Measured
+//! only for writes made with WriteOptions::collect_timing.  See
+//! DB::last_write_timing() and DB::write_timing_report().
+
+use std::cell::Cell;
+
+/// Number of timed writes DB::write_timing_report() covers.
+pub(crate) const WRITE_TIMING_WINDOW: usize = 1000;
+
+/// The steps of one write, in microseconds of Env::now_micros().
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteTiming {
+    /// Waiting for locked key ranges and for the writers ahead in the
+    /// queue.  For a write committed as part of another writer's group,
+    /// this covers that writer's whole turn.
+    pub queue_wait_micros: u64,
+
+    /// Adding the record to the log.
+    pub wal_append_micros: u64,
+
+    /// Syncing the log, for a sync write.
+    pub wal_sync_micros: u64,
+
+    /// Inserting the batch into the memtable.
+    pub memtable_insert_micros: u64,
+
+    /// From the call to its return, including the steps above and any
+    /// wait for room in the memtable.
+    pub total_micros: u64,
+
+    /// True if the write was committed by another writer, as part of its
+    /// group.  That writer did the logging and inserting, so those steps
+    /// are reported as zero here.
+    pub grouped: bool,
+}
+
+/// Median and 99th percentile of one step over the recent timed writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StepLatency {
+    pub p50_micros: u64,
+    pub p99_micros: u64,
+}
+
+/// Percentiles of each step over the last timed writes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteTimingReport {
+    /// Number of writes the percentiles are taken over.
+    pub writes: usize,
+    /// Of those, the writes committed by another writer's group.
+    pub grouped_writes: usize,
+
+    pub queue_wait: StepLatency,
+    pub wal_append: StepLatency,
+    pub wal_sync: StepLatency,
+    pub memtable_insert: StepLatency,
+    pub total: StepLatency,
+}
+
+thread_local! {
+    /// Timing of the last timed write made on this thread.
+    pub(crate) static LAST_WRITE_TIMING: Cell<Option<WriteTiming>> = const { Cell::new(None) };
+}
+
+/// Ring buffer of the last "capacity" timings.
+pub(crate) struct WriteTimingWindow {
+    samples_: Vec<WriteTiming>,
+    capacity_: usize,
+    next_: usize,   // Slot the next sample overwrites once full
+}
+
+impl WriteTimingWindow {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { samples_: Vec::with_capacity(capacity), capacity_: capacity, next_: 0 }
+    }
+
+    /// Add "timing", dropping the oldest one if the window is full.
+    pub(crate) fn record(&mut self, timing: WriteTiming) {
+        if self.samples_.len() < self.capacity_ {
+            self.samples_.push(timing);
+        } else if self.capacity_ > 0 {
+            self.samples_[self.next_] = timing;
+            self.next_ = (self.next_ + 1) % self.capacity_;
+        }
+    }
+
+    pub(crate) fn report(&self) -> WriteTimingReport {
+        let step = |micros: fn(&WriteTiming) -> u64| {
+            let mut values: Vec<u64> = self.samples_.iter().map(micros).collect();
+            values.sort_unstable();
+            StepLatency { p50_micros: percentile(&values, 50), p99_micros: percentile(&values, 99) }
+        };
+        WriteTimingReport {
+            writes: self.samples_.len(),
+            grouped_writes: self.samples_.iter().filter(|t| t.grouped).count(),
+            queue_wait: step(|t| t.queue_wait_micros),
+            wal_append: step(|t| t.wal_append_micros),
+            wal_sync: step(|t| t.wal_sync_micros),
+            memtable_insert: step(|t| t.memtable_insert_micros),
+            total: step(|t| t.total_micros),
+        }
+    }
+}
+
+/// The smallest value at least "p" percent of "sorted" are at or below,
+/// or zero if it is empty.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(queue_wait: u64, wal_sync: u64, grouped: bool) -> WriteTiming {
+        WriteTiming { queue_wait_micros: queue_wait, wal_sync_micros: wal_sync, total_micros: queue_wait + wal_sync, grouped, ..Default::default() }
+    }
+
+    #[test]
+    fn percentile_test() {
+        assert_eq!(0, percentile(&[], 50));
+        assert_eq!(7, percentile(&[7], 99));
+        let values: Vec<u64> = (1..=100).collect();
+        assert_eq!((50, 99, 100), (percentile(&values, 50), percentile(&values, 99), percentile(&values, 100)));
+        assert_eq!((2, 4), (percentile(&[1, 2, 3, 4], 50), percentile(&[1, 2, 3, 4], 99)));
+    }
+
+    #[test]
+    fn window_test() {
+        let mut window = WriteTimingWindow::new(4);
+        assert_eq!(WriteTimingReport::default(), window.report());
+        for i in 1..=4 {
+            window.record(timing(i, 100 * i, false));
+        }
+        let report = window.report();
+        assert_eq!((4, 0), (report.writes, report.grouped_writes));
+        assert_eq!(StepLatency { p50_micros: 2, p99_micros: 4 }, report.queue_wait);
+        assert_eq!(StepLatency { p50_micros: 200, p99_micros: 400 }, report.wal_sync);
+        assert_eq!(StepLatency::default(), report.wal_append);
+
+        // Older samples leave the window
+        window.record(timing(1000, 0, true));
+        window.record(timing(1000, 0, true));
+        let report = window.report();
+        assert_eq!((4, 2), (report.writes, report.grouped_writes));
+        assert_eq!(StepLatency { p50_micros: 4, p99_micros: 1000 }, report.queue_wait);
+
+        // Nothing is recorded into an empty window
+        let mut window = WriteTimingWindow::new(0);
+        window.record(timing(1, 1, false));
+        assert_eq!(0, window.report().writes);
+    }
+}
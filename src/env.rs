@@ -6,11 +6,27 @@
 //! All Env implementations are safe for concurrent access from
 //! multiple threads without any external synchronization.
 
-use std::{any::Any, rc::Rc};
+use std::{sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::{slice::Slice, status::Status};
+use crate::{slice::Slice, status::Status, util::env_posix};
 
-pub trait Env {
+pub trait Env: Send + Sync {
+    /// Create an object that sequentially reads the file with the specified name.
+    /// On success, returns the new file.  On failure returns non-OK.  If the
+    /// file does not exist, returns a non-OK status.  Implementations should
+    /// return a NotFound status when the file does not exist.
+    /// 
+    /// The returned file will only be accessed by one thread at a time.
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status>;
+
+    /// Create an object supporting random-access reads from the file with the
+    /// specified name.  On success, returns the new file.  On failure returns
+    /// non-OK.  If the file does not exist, returns a non-OK status.
+    /// Implementations should return a NotFound status when the file does
+    /// not exist.
+    /// 
+    /// The returned file may be concurrently accessed by multiple threads.
+    fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status>;
 
     /// Create an object that writes to a new file with the specified
     /// name.  Deletes any existing file with the same name and creates a
@@ -19,11 +35,28 @@ pub trait Env {
     /// returns non-OK.
     /// 
     /// The returned file will only be accessed by one thread at a time.
-    fn new_writable_file(&self, fname: &str) -> Result<Rc<dyn WritableFile>, Status>;
+    fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status>;
+
+    /// Create an object that either appends to an existing file, or
+    /// writes to a new file (if the file does not exist to begin with).
+    /// 
+    /// The returned file will only be accessed by one thread at a time.
+    /// 
+    /// May return a NotSupported error if this Env does not allow
+    /// appending to an existing file.  Users of Env (including the
+    /// leveldb implementation) must be prepared to deal with an Env that
+    /// does not support appending.
+    fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        Err(Status::not_supported("appending is not supported", fname))
+    }
 
     /// Returns true iff the named file exists.
     fn file_exists(&self, fname: &str) -> bool;
 
+    /// Return the names of the children of the specified directory.
+    /// The names are relative to "dir".
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Status>;
+
     /// Delete the named file.
     /// 
     /// The default implementation calls DeleteFile, to support legacy Env
@@ -35,12 +68,28 @@ pub trait Env {
     /// RemoveDir.
     fn remove_file(&self, fname: &str) -> Status;
 
+    /// Return the size of fname.
+    fn get_file_size(&self, fname: &str) -> Result<u64, Status>;
+
     /// Create the specified directory.
     fn create_dir(&self, dirname: &str) -> Result<(), Status>;
 
+    /// Delete the specified directory.
+    fn remove_dir(&self, dirname: &str) -> Status;
+
     /// Rename file src to target.
     fn rename_file(&self, src: &str, target: &str) -> Status;
 
+    /// Make target another name for the existing file src, so that the
+    /// contents stay reachable through target after src is removed.
+    /// Fails if target already exists.
+    /// 
+    /// May return a NotSupported error if this Env can not link files;
+    /// callers must then fall back to copying.
+    fn link_file(&self, src: &str, _target: &str) -> Status {
+        Status::not_supported("linking is not supported", src)
+    }
+
     /// Lock the specified file.  Used to prevent concurrent access to
     /// the same db by multiple processes.  On failure, stores nullptr in
     /// *lock and returns non-OK.
@@ -51,42 +100,192 @@ pub trait Env {
     /// the lock will be automatically released.
     /// 
     /// If somebody else already holds the lock, finishes immediately
-    /// with a failure.  I.e., this call does not wait for existing locks
-    /// to go away.
+    /// with a Busy failure whose subcode is SubCode::LockHeld.  I.e., this
+    /// call does not wait for existing locks to go away.
     /// 
     /// May create the named file if it does not already exist.
     fn lock_file(&self, fname: &str) -> Result<FileLock, Status>;
 
-    /// Arrange to run "(*function)(arg)" once in a background thread.
+    /// Release the lock acquired by a previous successful call to lock_file.
+    /// REQUIRES: lock was returned by a successful lock_file() call
+    /// REQUIRES: lock has not already been unlocked.
+    fn unlock_file(&self, lock: FileLock) -> Status;
+
+    /// Arrange to run "work" once in a background thread.
     /// 
-    /// "function" may run in an unspecified thread.  Multiple functions
+    /// "work" may run in an unspecified thread.  Multiple work items
     /// added to the same Env may run concurrently in different threads.
     /// I.e., the caller may not assume that background work items are
     /// serialized.
-    fn schedule(&self, func: &dyn Fn(&dyn Any));
+    ///
+    /// "label", if given, names the work for diagnostics; an Env that runs
+    /// work on its own threads may use it to name them.
+    fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>);
+
+    /// Create and return a log file for storing informational messages.
+    /// The default writes timestamped lines to a new writable file.
+    fn new_logger(&self, fname: &str) -> Result<Arc<dyn Logger>, Status> {
+        let file = self.new_writable_file(fname)?;
+        Ok(Arc::new(FileLogger { file_: Mutex::new(file) }))
+    }
+
+    /// Return the number of files open through this Env and the number
+    /// the process may have open (its soft limit), or None if this Env
+    /// can not tell.  See Options::adaptive_table_cache.
+    fn open_file_limit(&self) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Returns the number of micro-seconds since some fixed point in time.
+    /// Only useful for computing deltas of time.
+    fn now_micros(&self) -> u64;
+
+    /// Sleep/delay the thread for the prescribed number of micro-seconds.
+    fn sleep_for_microseconds(&self, micros: u64);
+}
+
+/// Return a default environment suitable for the current operating
+/// system.  Sophisticated users may wish to provide their own Env
+/// implementation instead of relying on this default environment.
+pub fn default_env() -> Arc<dyn Env> {
+    env_posix::default_env()
 }
 
 /// Identifies a locked file.
-pub struct FileLock;
+pub struct FileLock {
+    fname_: String,
+}
+
+impl FileLock {
+    pub fn new(fname: &str) -> Self {
+        Self { fname_: fname.to_string() }
+    }
+
+    /// The name of the locked file.
+    pub fn name(&self) -> &str {
+        &self.fname_
+    }
+}
+
+/// A file abstraction for reading sequentially through a file
+pub trait SequentialFile: Send {
+    /// Read up to "n" bytes from the file.  Fewer than "n" bytes are
+    /// returned only if the end of the file was reached (or an error
+    /// occurred); an empty result means end of file.
+    /// 
+    /// REQUIRES: External synchronization
+    fn read(&mut self, n: usize) -> Result<Vec<u8>, Status>;
+
+    /// Skip "n" bytes from the file. This is guaranteed to be no
+    /// slower that reading the same data, but may be faster.
+    /// 
+    /// If end of file is reached, skipping will stop at the end of the
+    /// file, and Skip will return OK.
+    /// 
+    /// REQUIRES: External synchronization
+    fn skip(&mut self, n: u64) -> Status;
+}
+
+/// A file abstraction for randomly reading the contents of a file.
+pub trait RandomAccessFile: Send + Sync {
+    /// Read up to "n" bytes from the file starting at "offset".
+    /// Fewer than "n" bytes are returned only if the end of the file
+    /// was reached.
+    /// 
+    /// Safe for concurrent use by multiple threads.
+    fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status>;
+}
 
 /// A file abstraction for sequential writing.  The implementation
 /// must provide buffering since callers may append small fragments
 /// at a time to the file.
-pub trait WritableFile {
+/// 
+/// A failed sync() or close() is permanent for the file: the data
+/// appended since the last successful sync may be gone even if a retry
+/// reports success (as fsync does on Linux once it has reported a write
+/// back error).  So once either has failed, every later call on the
+/// file must fail with the same error.
+pub trait WritableFile: Send + Sync {
     fn append(&self, data: &Slice) -> Status;
     fn close(&self) -> Status;
     fn flush(&self) -> Status;
+
+    /// Make the data appended so far durable.  See the type-level comment
+    /// for what must happen after a failure.
     fn sync(&self) -> Status;
+
+    /// Hint that "len" bytes starting at "offset" are about to be written,
+    /// so the file system can reserve the space in one go.  Must not
+    /// change the visible length of the file.  The default does nothing.
+    fn preallocate(&self, _offset: u64, _len: u64) -> Status {
+        Status::new_ok()
+    }
 }
 
 /// An interface for writing log messages.
-pub trait Logger {
+pub trait Logger: Send + Sync {
     /// Write an entry to the log file with the specified format.
     fn logv(&self, msg: &str);
 }
 
-pub(crate) fn log(info_log: Option<Rc<dyn Logger>>, msg: &str) {
+pub(crate) fn log(info_log: Option<Arc<dyn Logger>>, msg: &str) {
     if let Some(logger) = info_log {
         logger.logv(msg);
     }
 }
+
+/// A Logger that puts "prefix" in front of each message before passing
+/// it on to "base".
+pub(crate) struct PrefixLogger {
+    prefix_: String,
+    base_: Arc<dyn Logger>,
+}
+
+impl PrefixLogger {
+    pub(crate) fn new(prefix: String, base: Arc<dyn Logger>) -> Self {
+        Self { prefix_: prefix, base_: base }
+    }
+}
+
+impl Logger for PrefixLogger {
+    fn logv(&self, msg: &str) {
+        self.base_.logv(&format!("{}{}", self.prefix_, msg));
+    }
+}
+
+/// The Logger of Env::new_logger(): writes each message to "file_" as a
+/// line, after the UTC time it was logged at.
+struct FileLogger {
+    // The lock keeps concurrent lines whole.
+    file_: Mutex<Arc<dyn WritableFile>>,
+}
+
+impl Logger for FileLogger {
+    fn logv(&self, msg: &str) {
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        let line = format!("{} {}\n", format_utc_micros(micros), msg.trim_end_matches('\n'));
+        let file = self.file_.lock().unwrap();
+        // Logging is best effort: errors are dropped.
+        let _ = file.append(&Slice::new(line.as_bytes()));
+        let _ = file.flush();
+    }
+}
+
+/// Format "micros" since the Unix epoch as "yyyy/mm/dd-hh:mm:ss.uuuuuu".
+fn format_utc_micros(micros: u64) -> String {
+    let secs = micros / 1_000_000;
+    let days = (secs / 86400) as i64;
+    // Civil date from days since 1970-01-01, after Howard Hinnant's
+    // days_from_civil inverse.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}/{:02}/{:02}-{:02}:{:02}:{:02}.{:06}", year, month, day,
+            secs / 3600 % 24, secs / 60 % 60, secs % 60, micros % 1_000_000)
+}
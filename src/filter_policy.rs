@@ -9,6 +9,44 @@
 //! Most people will want to use the builtin bloom filter support (see
 //! NewBloomFilterPolicy() below).
 
-pub trait FilterPolicy {
-    
+use std::sync::Arc;
+
+use crate::{slice::Slice, util::bloom::BloomFilterPolicy};
+
+pub trait FilterPolicy: Send + Sync {
+    /// Return the name of this policy.  Note that if the filter encoding
+    /// changes in an incompatible way, the name returned by this method
+    /// must be changed.  Otherwise, old incompatible filters may be
+    /// passed to methods of this type.
+    fn name(&self) -> &str;
+
+    /// keys[0,n-1] contains a list of keys (potentially with duplicates)
+    /// that are ordered according to the user supplied comparator.
+    /// Append a filter that summarizes keys[0,n-1] to *dst.
+    ///
+    /// Warning: do not change the initial contents of *dst.  Instead,
+    /// append the newly constructed filter to *dst.
+    fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>);
+
+    /// "filter" contains the data appended by a preceding call to
+    /// create_filter() on this class.  This method must return true if
+    /// the key was in the list of keys passed to create_filter().
+    /// This method may return true or false if the key was not on the
+    /// list, but it should aim to return false with a high probability.
+    fn key_may_match(&self, key: &Slice, filter: &Slice) -> bool;
+}
+
+/// Return a new filter policy that uses a bloom filter with approximately
+/// the specified number of bits per key.  A good value for bits_per_key
+/// is 10, which yields a filter with ~ 1% false positive rate.
+///
+/// Note: if you are using a custom comparator that ignores some parts
+/// of the keys being compared, you must not use new_bloom_filter_policy()
+/// and must provide your own FilterPolicy that also ignores the
+/// corresponding parts of the keys.  For example, if the comparator
+/// ignores trailing spaces, it would be incorrect to use a
+/// FilterPolicy (like new_bloom_filter_policy) that does not ignore
+/// trailing spaces in keys.
+pub fn new_bloom_filter_policy(bits_per_key: usize) -> Arc<dyn FilterPolicy> {
+    Arc::new(BloomFilterPolicy::new(bits_per_key))
 }
@@ -0,0 +1,378 @@
+//! An Env that stores its files in memory.  Mostly useful for tests,
+//! but also for applications that want a throw-away database.
+
+use std::{collections::{HashMap, HashSet}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{env::{Env, FileLock, RandomAccessFile, SequentialFile, WritableFile}, slice::Slice, status::{Status, SubCode}, util::env::OpenFileGuard};
+
+/// The open file limit an in-memory Env reports, as there is none.
+const OPEN_FILE_LIMIT: u64 = 1024;
+
+/// Returns a new environment that stores its data in memory.
+/// Non-file operations (e.g. schedule) are run on the calling thread.
+pub fn new_mem_env() -> Arc<dyn Env> {
+    Arc::new(InMemoryEnv::new())
+}
+
+/// The contents of one file.  Shared between the file map and every
+/// open handle, so a file that is removed stays readable by handles
+/// that were opened before.
+#[derive(Clone)]
+struct FileState {
+    data_: Arc<Mutex<Vec<u8>>>,
+}
+
+impl FileState {
+    fn new() -> Self {
+        Self { data_: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    fn size(&self) -> u64 {
+        self.data_.lock().unwrap().len() as u64
+    }
+
+    fn truncate(&self) {
+        self.data_.lock().unwrap().clear();
+    }
+
+    fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+        let data = self.data_.lock().unwrap();
+        if offset > data.len() as u64 {
+            return Err(Status::io_error("Offset greater than file size.", ""));
+        }
+        let offset = offset as usize;
+        let available = data.len() - offset;
+        let n = if n > available { available } else { n };
+        Ok(data[offset..(offset + n)].to_vec())
+    }
+
+    fn append(&self, data: &Slice) -> Status {
+        self.data_.lock().unwrap().extend(data.data());
+        Status::new_ok()
+    }
+}
+
+struct SequentialFileImpl {
+    file_: FileState,
+    pos_: u64,
+    _open_: OpenFileGuard,
+}
+
+impl SequentialFile for SequentialFileImpl {
+    fn read(&mut self, n: usize) -> Result<Vec<u8>, Status> {
+        let result = self.file_.read(self.pos_, n)?;
+        self.pos_ += result.len() as u64;
+        Ok(result)
+    }
+
+    fn skip(&mut self, n: u64) -> Status {
+        let size = self.file_.size();
+        if self.pos_ > size {
+            return Status::io_error("pos_ > file_->Size()", "");
+        }
+        let available = size - self.pos_;
+        self.pos_ += if n > available { available } else { n };
+        Status::new_ok()
+    }
+}
+
+struct RandomAccessFileImpl {
+    file_: FileState,
+    _open_: OpenFileGuard,
+}
+
+impl RandomAccessFile for RandomAccessFileImpl {
+    fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+        self.file_.read(offset, n)
+    }
+}
+
+/// Memory never fails to sync or close, so there is no error to keep
+/// sticky (see WritableFile).
+struct WritableFileImpl {
+    file_: FileState,
+    _open_: OpenFileGuard,
+}
+
+impl WritableFile for WritableFileImpl {
+    fn append(&self, data: &Slice) -> Status {
+        self.file_.append(data)
+    }
+
+    fn close(&self) -> Status { Status::new_ok() }
+    fn flush(&self) -> Status { Status::new_ok() }
+    fn sync(&self) -> Status { Status::new_ok() }
+}
+
+struct InMemoryEnv {
+    // Map from filenames to FileState objects, representing a simple file system.
+    file_map_: Mutex<HashMap<String, FileState>>,
+
+    // Names of the files locked through this Env.
+    locks_: Mutex<HashSet<String>>,
+
+    // Handles of files opened through this Env and not dropped yet.
+    open_files_: Arc<AtomicU64>,
+}
+
+impl InMemoryEnv {
+    fn new() -> Self {
+        Self { file_map_: Mutex::new(HashMap::new()), locks_: Mutex::new(HashSet::new()), open_files_: Arc::new(AtomicU64::new(0)) }
+    }
+
+    fn find(&self, fname: &str) -> Result<FileState, Status> {
+        match self.file_map_.lock().unwrap().get(fname) {
+            Some(file) => Ok(file.clone()),
+            None => Err(Status::io_error(fname, "File not found")),
+        }
+    }
+}
+
+impl Env for InMemoryEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> {
+        let file = self.find(fname)?;
+        Ok(Box::new(SequentialFileImpl { file_: file, pos_: 0, _open_: OpenFileGuard::new(&self.open_files_) }))
+    }
+
+    fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+        let file = self.find(fname)?;
+        Ok(Arc::new(RandomAccessFileImpl { file_: file, _open_: OpenFileGuard::new(&self.open_files_) }))
+    }
+
+    fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        let mut file_map = self.file_map_.lock().unwrap();
+        let file = match file_map.get(fname) {
+            Some(file) => {
+                file.truncate();
+                file.clone()
+            },
+            None => {
+                let file = FileState::new();
+                file_map.insert(fname.to_string(), file.clone());
+                file
+            },
+        };
+        Ok(Arc::new(WritableFileImpl { file_: file, _open_: OpenFileGuard::new(&self.open_files_) }))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        let mut file_map = self.file_map_.lock().unwrap();
+        let file = file_map.entry(fname.to_string()).or_insert_with(FileState::new).clone();
+        Ok(Arc::new(WritableFileImpl { file_: file, _open_: OpenFileGuard::new(&self.open_files_) }))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        self.file_map_.lock().unwrap().contains_key(fname)
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> {
+        let prefix = format!("{}/", dir);
+        let file_map = self.file_map_.lock().unwrap();
+        Ok(file_map.keys()
+            .filter_map(|filename| filename.strip_prefix(&prefix))
+            .filter(|child| !child.contains('/'))
+            .map(|child| child.to_string())
+            .collect())
+    }
+
+    fn remove_file(&self, fname: &str) -> Status {
+        match self.file_map_.lock().unwrap().remove(fname) {
+            Some(_) => Status::new_ok(),
+            None => Status::io_error(fname, "File not found"),
+        }
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Status> {
+        Ok(self.find(fname)?.size())
+    }
+
+    fn create_dir(&self, _dirname: &str) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn remove_dir(&self, _dirname: &str) -> Status {
+        Status::new_ok()
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Status {
+        let mut file_map = self.file_map_.lock().unwrap();
+        match file_map.remove(src) {
+            Some(file) => {
+                file_map.insert(target.to_string(), file);
+                Status::new_ok()
+            },
+            None => Status::io_error(src, "File not found"),
+        }
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Status {
+        let mut file_map = self.file_map_.lock().unwrap();
+        if file_map.contains_key(target) {
+            return Status::io_error(target, "File exists");
+        }
+        match file_map.get(src) {
+            Some(file) => {
+                let file = file.clone();
+                file_map.insert(target.to_string(), file);
+                Status::new_ok()
+            },
+            None => Status::io_error(src, "File not found"),
+        }
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<FileLock, Status> {
+        if !self.locks_.lock().unwrap().insert(fname.to_string()) {
+            return Err(Status::busy(&format!("lock {}", fname), "already held").with_subcode(SubCode::LockHeld).with_filename(fname));
+        }
+        Ok(FileLock::new(fname))
+    }
+
+    fn unlock_file(&self, lock: FileLock) -> Status {
+        if self.locks_.lock().unwrap().remove(lock.name()) {
+            Status::new_ok()
+        } else {
+            Status::io_error(&format!("unlock {}", lock.name()), "not locked by this Env")
+        }
+    }
+
+    fn schedule(&self, _label: Option<&str>, work: Box<dyn FnOnce() + Send>) {
+        work();
+    }
+
+    fn open_file_limit(&self) -> Option<(u64, u64)> {
+        let open = self.open_files_.load(Ordering::Relaxed) + self.locks_.lock().unwrap().len() as u64;
+        Some((open, OPEN_FILE_LIMIT))
+    }
+
+    fn now_micros(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        std::thread::sleep(Duration::from_micros(micros));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basics_test() {
+        let env = new_mem_env();
+        assert!(env.create_dir("/dir").is_ok());
+        assert!(!env.file_exists("/dir/non_existent"));
+        assert!(env.get_file_size("/dir/non_existent").is_err());
+        assert!(env.get_children("/dir").unwrap().is_empty());
+
+        // Create a file.
+        let writable_file = env.new_writable_file("/dir/f").unwrap();
+        assert_eq!(0, env.get_file_size("/dir/f").unwrap());
+
+        // Check that the file exists.
+        assert!(env.file_exists("/dir/f"));
+        assert_eq!(vec!["f".to_string()], env.get_children("/dir").unwrap());
+
+        // Write to the file.
+        assert!(writable_file.append(&Slice::new(b"abc")).ok());
+
+        // Check that the file has the right size.
+        assert_eq!(3, env.get_file_size("/dir/f").unwrap());
+
+        // Check that renaming works.
+        assert!(!env.rename_file("/dir/non_existent", "/dir/g").ok());
+        assert!(env.rename_file("/dir/f", "/dir/g").ok());
+        assert!(!env.file_exists("/dir/f"));
+        assert!(env.file_exists("/dir/g"));
+        assert_eq!(3, env.get_file_size("/dir/g").unwrap());
+        assert_eq!(vec!["g".to_string()], env.get_children("/dir").unwrap());
+
+        // Check that opening non-existent file fails.
+        assert!(env.new_sequential_file("/dir/non_existent").is_err());
+        assert!(env.new_random_access_file("/dir/non_existent").is_err());
+
+        // Check that deleting works.
+        assert!(!env.remove_file("/dir/non_existent").ok());
+        assert!(env.remove_file("/dir/g").ok());
+        assert!(!env.file_exists("/dir/g"));
+        assert!(env.get_children("/dir").unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_write_test() {
+        let env = new_mem_env();
+        let writable_file = env.new_writable_file("/dir/f").unwrap();
+        assert!(writable_file.append(&Slice::new(b"hello ")).ok());
+        assert!(writable_file.append(&Slice::new(b"world")).ok());
+
+        // Read sequentially.
+        let mut seq_file = env.new_sequential_file("/dir/f").unwrap();
+        assert_eq!(b"hello".to_vec(), seq_file.read(5).unwrap());
+        assert!(seq_file.skip(1).ok());
+        assert_eq!(b"world".to_vec(), seq_file.read(1000).unwrap());
+        assert!(seq_file.read(1000).unwrap().is_empty());   // Try reading past EOF.
+        assert!(seq_file.skip(100).ok());   // Try to skip past end of file.
+        assert!(seq_file.read(1000).unwrap().is_empty());
+
+        // Random reads.
+        let rand_file = env.new_random_access_file("/dir/f").unwrap();
+        assert_eq!(b"world".to_vec(), rand_file.read(6, 5).unwrap());
+        assert_eq!(b"hello".to_vec(), rand_file.read(0, 5).unwrap());
+        assert_eq!(b"d".to_vec(), rand_file.read(10, 100).unwrap());
+
+        // Too high offset.
+        assert!(rand_file.read(1000, 5).is_err());
+    }
+
+    #[test]
+    fn overwrite_open_file_test() {
+        let env = new_mem_env();
+        let writable_file = env.new_writable_file("/dir/f").unwrap();
+        assert!(writable_file.append(&Slice::new(b"write1 data")).ok());
+        let rand_file = env.new_random_access_file("/dir/f").unwrap();
+
+        // Truncate the file while a reader holds it open.
+        let writable_file = env.new_writable_file("/dir/f").unwrap();
+        assert!(writable_file.append(&Slice::new(b"write2")).ok());
+        assert_eq!(b"write2".to_vec(), rand_file.read(0, 100).unwrap());
+    }
+
+    #[test]
+    fn appendable_file_test() {
+        let env = new_mem_env();
+        let file = env.new_appendable_file("/dir/f").unwrap();
+        assert!(file.append(&Slice::new(b"hello ")).ok());
+        let file = env.new_appendable_file("/dir/f").unwrap();
+        assert!(file.append(&Slice::new(b"world")).ok());
+        assert_eq!(b"hello world".to_vec(), env.new_random_access_file("/dir/f").unwrap().read(0, 100).unwrap());
+    }
+
+    #[test]
+    fn link_file_test() {
+        let env = new_mem_env();
+        assert!(env.link_file("/dir/f", "/dir/g").is_io_error());
+        let file = env.new_writable_file("/dir/f").unwrap();
+        assert!(file.append(&Slice::new(b"hello")).ok());
+        assert!(env.link_file("/dir/f", "/dir/g").ok());
+        assert!(env.link_file("/dir/f", "/dir/g").is_io_error());
+
+        // Both names reach the same contents, until the last one is removed.
+        assert!(env.remove_file("/dir/f").ok());
+        assert_eq!(b"hello".to_vec(), env.new_random_access_file("/dir/g").unwrap().read(0, 100).unwrap());
+        assert_eq!(vec!["g".to_string()], env.get_children("/dir").unwrap());
+    }
+
+    #[test]
+    fn lock_test() {
+        let env = new_mem_env();
+        let lock = env.lock_file("/dir/LOCK").unwrap();
+        assert!(env.lock_file("/dir/LOCK").err().unwrap().is_busy());
+        let other = env.lock_file("/dir2/LOCK").unwrap();
+
+        assert!(env.unlock_file(lock).ok());
+        assert!(env.unlock_file(FileLock::new("/dir/LOCK")).is_io_error());
+        let lock = env.lock_file("/dir/LOCK").unwrap();
+        assert!(env.unlock_file(lock).ok());
+        assert!(env.unlock_file(other).ok());
+    }
+}
@@ -0,0 +1,138 @@
+//! An iterator yields a sequence of key/value pairs from a source.
+//! The following class defines the interface.  Multiple implementations
+//! are provided by this library.  In particular, iterators are provided
+//! to access the contents of a Table or a DB.
+//!
+//! Multiple threads can invoke const methods on an Iterator without
+//! external synchronization, but if any of the threads may call a
+//! non-const method, all threads accessing the same Iterator must use
+//! external synchronization.
+
+use crate::{db::ValueHandle, slice::Slice, status::Status, table::format::BlockHandle};
+
+pub use crate::table::block::RawBlock;
+
+/// Where the value of an entry lies in a table: the table file, the data
+/// block, and the extent of the value in the block.  Block iterators
+/// fill in the extent, and the iterator over a table file the rest;
+/// until it has, "block" is None.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct ValueLocation {
+    pub(crate) file_number: u64,
+    pub(crate) file_size: u64,
+    pub(crate) block: Option<BlockHandle>,
+    pub(crate) offset: usize,
+    pub(crate) len: usize,
+}
+
+pub trait Iterator: Send {
+    /// An iterator is either positioned at a key/value pair, or
+    /// not valid.  This method returns true iff the iterator is valid.
+    fn valid(&self) -> bool;
+
+    /// Position at the first key in the source.  The iterator is Valid()
+    /// after this call iff the source is not empty.
+    fn seek_to_first(&mut self);
+
+    /// Position at the last key in the source.  The iterator is
+    /// Valid() after this call iff the source is not empty.
+    fn seek_to_last(&mut self);
+
+    /// Position at the first key in the source that is at or past target.
+    /// The iterator is Valid() after this call iff the source contains
+    /// an entry that comes at or past target.
+    fn seek(&mut self, target: &Slice);
+
+    /// Moves to the next entry in the source.  After this call, Valid() is
+    /// true iff the iterator was not positioned at the last entry in the source.
+    /// REQUIRES: Valid()
+    fn next(&mut self);
+
+    /// Moves to the previous entry in the source.  After this call, Valid() is
+    /// true iff the iterator was not positioned at the first entry in source.
+    /// REQUIRES: Valid()
+    fn prev(&mut self);
+
+    /// Return the key for the current entry.  The underlying storage for
+    /// the returned slice is valid only until the next modification of
+    /// the iterator.
+    /// REQUIRES: Valid()
+    fn key(&self) -> Slice<'_>;
+
+    /// Return the value for the current entry.  The underlying storage for
+    /// the returned slice is valid only until the next modification of
+    /// the iterator.
+    /// REQUIRES: Valid()
+    fn value(&self) -> Slice<'_>;
+
+    /// If an error has occurred, return it.  Else return an ok status.
+    fn status(&self) -> Status;
+
+    /// If the iterator is at the first entry of a table data block and
+    /// nothing else it yields falls in the block's key range, return the
+    /// block, so that a compaction can copy it into its output whole.
+    /// Iterators over anything but tables return None.
+    #[doc(hidden)]
+    fn raw_block(&self) -> Option<RawBlock> {
+        None
+    }
+
+    /// Move past the entries of the block raw_block() returned, as if
+    /// next() had been called for each of them.
+    /// REQUIRES: raw_block() returned a block at the current position
+    #[doc(hidden)]
+    fn skip_block(&mut self) {
+        unreachable!("skip_block() without a raw block");
+    }
+
+    /// Return a handle on the value of the current entry, for
+    /// DB::read_value() to read when it is wanted, instead of the value
+    /// itself.  The handle is good while the iterator lives; see
+    /// ValueHandle::pin().  Only iterators over a DB return handles.
+    /// REQUIRES: Valid()
+    fn value_lazy(&self) -> Option<ValueHandle> {
+        None
+    }
+
+    /// Return where the value of the current entry lies in a table, if
+    /// it is read from one and the iterator knows.
+    /// REQUIRES: Valid()
+    #[doc(hidden)]
+    fn value_location(&self) -> Option<ValueLocation> {
+        None
+    }
+}
+
+/// An iterator over nothing that reports "status".
+struct EmptyIterator {
+    status_: Status,
+}
+
+impl Iterator for EmptyIterator {
+    fn valid(&self) -> bool { false }
+    fn seek_to_first(&mut self) {}
+    fn seek_to_last(&mut self) {}
+    fn seek(&mut self, _target: &Slice) {}
+    fn next(&mut self) { debug_assert!(self.valid()); }
+    fn prev(&mut self) { debug_assert!(self.valid()); }
+    fn key(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        Slice::new(b"")
+    }
+    fn value(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        Slice::new(b"")
+    }
+    fn status(&self) -> Status { self.status_.clone() }
+}
+
+/// Return an empty iterator (yields nothing).
+pub(crate) fn new_empty_iterator() -> Box<dyn Iterator> {
+    Box::new(EmptyIterator { status_: Status::new_ok() })
+}
+
+/// Return an empty iterator with the specified status.
+pub(crate) fn new_error_iterator(status: Status) -> Box<dyn Iterator> {
+    Box::new(EmptyIterator { status_: status })
+}
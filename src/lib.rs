@@ -1,15 +1,50 @@
-#![feature(allocator_api, slice_flatten)]
+#![feature(allocator_api)]
+// Unsafe code is confined to util/unsafe_impl.rs.
+#![deny(unsafe_code)]
 
+/// Mark a point where tests can hook into the engine; see sync_point.
+/// "sync_point!(name, s)" also lets a callback fail the step: an injected
+/// Status replaces "s" if it is still OK.  Expands to nothing unless
+/// testing or built with the "failpoints" feature.
+macro_rules! sync_point {
+    ($name:expr) => {
+        #[cfg(any(test, feature = "failpoints"))]
+        {
+            let _ = $crate::sync_point::process($name);
+        }
+    };
+    ($name:expr, $s:ident) => {
+        #[cfg(any(test, feature = "failpoints"))]
+        if $s.ok() {
+            if let Some(injected) = $crate::sync_point::process($name) {
+                $s = injected;
+            }
+        }
+    };
+}
+
+pub mod batch_transformer;
 pub mod db;
 pub mod status;
 pub mod slice;
 pub mod options;
 pub mod cache;
 pub mod comparator;
+pub mod compaction_filter;
 pub mod env;
 pub mod filter_policy;
+pub mod helpers;
+pub mod iterator;
+pub mod split_policy;
+pub mod utilities;
+pub mod write_batch;
+#[cfg(any(test, feature = "failpoints"))]
+pub mod sync_point;
+mod table;
 mod util;
 
+pub use db::{list_instances, InstanceInfo};
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -1,9 +1,26 @@
-use std::{rc::Rc, sync::Arc};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}};
 
-use crate::{cache::Cache, comparator::Comparator, env::{Env, Logger}, filter_policy::FilterPolicy};
+use crate::{batch_transformer::BatchTransformer, cache::Cache, compaction_filter::CompactionFilter, comparator::{bytewise_comparator, Comparator}, db::{dbformat::{L0_SLOWDOWN_WRITES_TRIGGER, L0_STOP_WRITES_TRIGGER}, snapshot::Snapshot}, env::{default_env, Env, Logger}, filter_policy::FilterPolicy, slice::{escape_bytes, parse_escaped}, split_policy::SplitPolicy, status::Status, utilities::multi_db::PreparedBatchResolver};
+
+// Bounds enforced on write_buffer_size, both when a DB is opened and when
+// the value is changed at runtime.
+pub(crate) const MIN_WRITE_BUFFER_SIZE: usize = 64 << 10;
+pub(crate) const MAX_WRITE_BUFFER_SIZE: usize = 1 << 30;
+
+// Bounds max_open_files (other than zero) and block_size are clamped to
+// when a DB is opened.
+pub(crate) const MIN_MAX_OPEN_FILES: usize = 74;
+pub(crate) const MAX_MAX_OPEN_FILES: usize = 50000;
+pub(crate) const MIN_BLOCK_SIZE: usize = 1 << 10;
+pub(crate) const MAX_BLOCK_SIZE: usize = 4 << 20;
+
+// Capacity of the block cache a DB creates when Options::block_cache is
+// not set.
+pub(crate) const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 << 20;
 
 
 /// Options to control the behavior of a database (passed to DB::Open)
+#[derive(Clone)]
 pub struct Options {
     // -------------------
     // Parameters that affect behavior
@@ -22,39 +39,1055 @@ pub struct Options {
     /// If true, an error is raised if the database already exists.
     pub error_if_exists: bool,
 
+    /// If true, the implementation will do aggressive checking of the
+    /// data it is processing and will stop early if it detects any
+    /// errors: DB::open() fails on a damaged log record (a record cut
+    /// short at the end of a log is still left to wal_recovery_mode),
+    /// every table block read is checksummed, and a failed log write
+    /// fails all later writes.  This may have unforeseen ramifications:
+    /// for example, a corruption of one DB entry may cause a large
+    /// number of entries to become unreadable or for the entire DB to
+    /// become unopenable.
+    /// Default: false
+    pub paranoid_checks: bool,
+
     /// Use the specified object to interact with the environment,
     /// e.g. to read/write files, schedule background work, etc.
     /// Default: Env::Default()
-    pub env: Rc<dyn Env>,
+    pub env: Arc<dyn Env>,
 
     /// Any internal progress/error information generated by the db will
     /// be written to info_log if it is non-null, or to a file stored
     /// in the same directory as the DB contents if info_log is null.
-    pub info_log: Option<Rc<dyn Logger>>,
+    /// That file is dbname/LOG; the previous one is kept as LOG.old.
+    pub info_log: Option<Arc<dyn Logger>>,
+
+    /// How user keys and values are shown in diagnostics: info_log lines
+    /// and the messages of the errors returned.  Keys and values may hold
+    /// personal data that should not end up in logs.
+    /// Default: DiagnosticRedaction::Full
+    pub diagnostics_redaction: DiagnosticRedaction,
+
+    // -------------------
+    // Parameters that affect performance
+
+    /// Amount of data to build up in memory (backed by an unsorted log
+    /// on disk) before converting to a sorted on-disk file.
+    /// 
+    /// Larger values increase performance, especially during bulk loads.
+    /// Up to two write buffers may be held in memory at the same time,
+    /// so you may wish to adjust this parameter to control memory usage.
+    /// Also, a larger write buffer will result in a longer recovery time
+    /// the next time the database is opened.
+    /// 
+    /// May be changed on a live DB with DB::set_options.  Clamped to
+    /// [64KB, 1GB] when the DB is opened.
+    /// Default: 4MB
+    pub write_buffer_size: usize,
+
+    /// If not zero, the memtable stores the first this many bytes of the
+    /// user keys once, in a dictionary of prefixes, rather than in every
+    /// entry: an entry keeps the id of its prefix and the rest of its
+    /// key.  This saves memory when many keys share long prefixes, such
+    /// as URLs or tenant ids.  A prefix is added to the dictionary when a
+    /// second key with it is written, so keys that share no prefix are
+    /// stored as they are.  Reads and the tables written are the same
+    /// either way.  Comparisons are fastest with the bytewise comparator;
+    /// others are handed reassembled keys.
+    /// Default: 0
+    pub memtable_prefix_compression: usize,
+
+    /// Number of tables the DB keeps open.  Once the table cache holds
+    /// this many, opening another closes the one used least recently.
+    /// Zero leaves tables open until their file is deleted; other values
+    /// are clamped to [74, 50000] when the DB is opened.
+    /// Default: 1000
+    pub max_open_files: usize,
 
+    /// If true, the table cache sizes itself to the open file limit that
+    /// options.env reports (see Env::open_file_limit()): it keeps at
+    /// most soft_limit - adaptive_table_cache_headroom - (the files open
+    /// through the Env that are not its tables) open, and no more than
+    /// max_open_files, if set.  The target follows the Env's numbers as
+    /// tables are opened and closed, and an open that fails because the
+    /// process has too many files open shrinks the cache and is retried.
+    /// Without a limit from the Env, only max_open_files applies.
+    /// Default: false
+    pub adaptive_table_cache: bool,
+
+    /// Files the adaptive table cache leaves to the rest of the process
+    /// under the Env's open file limit.
+    /// Default: 64
+    pub adaptive_table_cache_headroom: usize,
 
     /// Control over blocks (user data is stored in a set of blocks, and
     /// a block is the unit of reading from disk).
     /// 
-    /// If non-NULL, use the specified cache for blocks.
-    /// If NULL, leveldb will automatically create and use an 8MB internal cache.
+    /// If non-NULL, use the specified cache for blocks, e.g. one made by
+    /// new_lru_cache().  If NULL, the DB creates an 8MB cache of its own,
+    /// unless no_block_cache is set.
+    /// Default: NULL
+    pub block_cache: Option<Arc<dyn Cache>>,
+
+    /// If set, the block cache the DB creates when block_cache is NULL is
+    /// split between groups of levels, each getting its fraction of the
+    /// capacity: the first entry is for levels 0-1, the next for levels
+    /// 2-3 and so on, and the last also covers every deeper level.  Scans
+    /// of the large bottom levels then no longer evict the blocks of the
+    /// upper ones.  Ignored if block_cache is set; pass a cache made by
+    /// new_partitioned_lru_cache() instead.
     /// Default: NULL
-    pub block_cache: Option<Box<dyn Cache>>,
+    pub block_cache_level_partitions: Option<Vec<f32>>,
+
+    /// If true, a partition of the cache made for
+    /// block_cache_level_partitions may use the capacity other partitions
+    /// leave unused, and gives it back when they need it.
+    /// Default: false
+    pub block_cache_partition_borrowing: bool,
 
     /// Disable block cache. If this is set to true,
     /// then no block cache should be used, and the block_cache should
     /// point to a NULL object.
     pub no_block_cache: bool,
 
+    /// Approximate size of user data packed per block.  Note that the
+    /// block size specified here corresponds to uncompressed data.  The
+    /// actual size of the unit read from disk may be smaller if
+    /// compression is enabled.  This parameter can be changed dynamically.
+    /// Clamped to [1KB, 4MB] when the DB is opened.
+    pub block_size: usize,
+
+    /// Number of keys between restart points for delta encoding of keys.
+    /// This parameter can be changed dynamically.  Most clients should
+    /// leave this parameter alone.
+    pub block_restart_interval: usize,
+
+    /// Leveldb will write up to this amount of bytes to a file before
+    /// switching to a new one.
+    /// Most clients should leave this parameter alone.  However if your
+    /// filesystem is more efficient with larger files, you could
+    /// consider increasing the value.  The downside will be longer
+    /// compactions and hence longer latency/performance hiccups.
+    /// Another reason to increase this parameter might be when you are
+    /// initially populating a large database.  Capped at 1GB on 32-bit
+    /// targets.
+    /// Default: 2MB
+    pub max_file_size: usize,
+
+    /// Compress blocks using the specified compression algorithm.  This
+    /// parameter can be changed dynamically.
+    /// 
+    /// Default: SnappyCompression, which gives lightweight but fast
+    /// compression.  Snappy support is not compiled into this build, so
+    /// as LevelDB does without it, blocks are then stored uncompressed.
+    pub compression: CompressionType,
+
     /// If non-null, use the specified filter policy to reduce disk reads.
     /// Many applications will benefit from passing the result of
     /// NewBloomFilterPolicy() here.
-    pub filter_policy: Option<Rc<dyn FilterPolicy>>,
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+
+    /// If non-null, the filters of open tables are kept in this cache,
+    /// charged by their size, instead of for as long as each table is
+    /// open.  A filter evicted from it is read again by the next lookup
+    /// that needs it.  Separate from block_cache so that filters and data
+    /// blocks get their own capacities.
+    /// Default: NULL (every open table keeps its filter in memory)
+    pub filter_cache: Option<Arc<dyn Cache>>,
+
+    /// If non-null, compaction never lets an output file span a boundary
+    /// reported by this policy (unless the entries of a single user key
+    /// alone exceed the output file size), so the same data always gets
+    /// cut into the same files.  See FixedPrefixSplitPolicy.
+    /// Default: NULL (outputs are cut by size only)
+    pub output_split_key_policy: Option<Arc<dyn SplitPolicy>>,
+
+    /// If non-null, compaction removes the values this filter picks.
+    /// See CompactionFilter.
+    /// Default: NULL
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+
+    /// If non-null, each batch given to DB::write() (or made by put(),
+    /// delete() and delete_range()) is replaced by what this transformer
+    /// returns for it, before sequence numbers are assigned.  Replicated
+    /// batches and ingested files are not transformed.  See
+    /// BatchTransformer.
+    /// Default: NULL
+    pub batch_transformer: Option<Arc<dyn BatchTransformer>>,
+
+    /// If non-null, decides on DB::open() whether to commit or roll back
+    /// each transaction across several DBs (see MultiDbWriteBatch) that
+    /// the log holds prepared but neither committed nor rolled back, as
+    /// a crash in the middle of MultiDbWriteBatch::commit() leaves it.
+    /// Opening such a database fails with InvalidArgument without one.
+    /// open_participants() sets one that agrees with the other DBs.
+    /// Default: NULL
+    pub prepared_batch_resolver: Option<Arc<dyn PreparedBatchResolver>>,
+
+    /// If true, the database is a replica of another one: put(), delete()
+    /// and write() are rejected, and updates arrive only through
+    /// DB::apply_replicated_batch(), so its sequence numbers cannot fork
+    /// from the primary's.
+    /// Default: false
+    pub replica_mode: bool,
+
+    /// Number of superseded file layouts (Versions) to keep around for
+    /// DB::get_at_version() and DB::new_iterator_at_version().  Each one
+    /// keeps its table files, and the memtables that were
+    /// live when it was replaced, from being released.
+    /// Default: 0
+    pub keep_old_versions: usize,
+
+    /// Number of idempotency tokens (see WriteOptions::idempotency_token)
+    /// the DB remembers.  A write whose token is among the most recent
+    /// idempotency_window tokens fails with an AlreadyApplied status
+    /// instead of being applied again.  Zero disables the check.
+    /// Default: 1024
+    pub idempotency_window: usize,
+
+    /// Number of log files to keep after their contents have been
+    /// written to tables.  Tokens of writes in a deleted log are
+    /// forgotten, so this bounds how long after a flush a retried write
+    /// is still recognized across a reopen.
+    /// Default: 0
+    pub keep_log_file_num: usize,
+
+    /// Log and MANIFEST files reserve disk space ahead of their writes
+    /// (see WritableFile::preallocate): write_buffer_size bytes for a
+    /// new log file and max_file_size / 4 for a new MANIFEST, then chunks
+    /// of this many bytes as they grow.  Zero turns preallocation off.
+    /// Default: 1MB
+    pub preallocation_block_size: usize,
+
+    /// Number of recent gets DB::read_amplification_report() averages
+    /// over.  Zero turns the report and the warning below off.
+    /// Default: 1000
+    pub read_amp_window: usize,
+
+    /// Once read_amp_window gets have been made, log a warning to
+    /// info_log when they probed more than this many level-0 files on
+    /// average, and so did the latest get.
+    /// Default: 8
+    pub read_amp_warning_l0_files: usize,
+
+    /// Log the warning above at most once per this many seconds.
+    /// Default: 600
+    pub read_amp_warning_interval_secs: u64,
+
+    /// If true, collect statistics of how point lookups and seeks find
+    /// their keys: the data blocks and level-0 files each get reads, the
+    /// tables it probes in vain, and the seeks that position in more than
+    /// one table.  They are reported in ReadStats::lookups and by the
+    /// "leveldb.lookup-efficiency" property, and feed
+    /// DB::estimate_bloom_benefit().  When false, gets and seeks skip
+    /// the bookkeeping.
+    /// Default: false
+    pub lookup_statistics: bool,
+
+    /// If true, append to the existing MANIFEST and log files when a
+    /// database is opened, instead of starting new ones.  This can
+    /// significantly speed up open.  A log is only reused if it was
+    /// replayed without damage and fits in one memtable, as after a
+    /// clean shutdown, and only if options.env supports
+    /// new_appendable_file().
+    /// Default: false
+    pub reuse_logs: bool,
+
+    /// What DB::open() does with a corrupted or truncated record in a
+    /// log file it replays; see WalRecoveryMode.
+    /// Default: WalRecoveryMode::PointInTime
+    pub wal_recovery_mode: WalRecoveryMode,
+
+    /// If set, fences off older instances of the DB on shared storage
+    /// where file locks can not be trusted (e.g. after a failover).
+    /// DB::open() fails with InvalidArgument if the DB was already opened
+    /// with a larger token, and otherwise records this one in the MANIFEST
+    /// and in the DB's FENCE file.  Before each MANIFEST write and each
+    /// new log file, the open DB checks the FENCE file; once it holds a
+    /// larger token, every write fails from then on, while reads keep
+    /// working.  Writes into the current log file are not checked.  A DB
+    /// opened without a token neither checks nor records one.
+    /// Default: None
+    pub fencing_token: Option<u64>,
+
+    /// If set, names the DB within the process: each line it writes to
+    /// info_log starts with "[<name>] ", and the DB is listed by
+    /// list_instances() while it is open.  DB::open() fails with
+    /// InvalidArgument if another open DB has the same name.
+    /// Default: None
+    pub instance_name: Option<String>,
+
+    /// Number of dropped DB iterators to keep for reuse by later
+    /// new_iterator() calls, which saves building the iterators over the
+    /// memtables and tables for each short scan.  Pooled iterators are
+    /// dropped whenever the memtables or the set of tables change, and
+    /// iterators with a deadline or an iterate_upper_bound are not
+    /// pooled.  Zero disables pooling.
+    /// Default: 0
+    pub iterator_pool_size: usize,
+
+    /// A compaction whose input files hold at least
+    /// subcompaction_threshold_bytes is split into up to this many
+    /// subcompactions over disjoint key ranges, cut at the start of input
+    /// files so that each gets about the same number of bytes.  They run
+    /// on threads of their own, each writing its own output files, and
+    /// their outputs are installed together once all of them succeeded.
+    /// One disables splitting.
+    /// Default: 1
+    pub max_subcompactions: usize,
+
+    /// See max_subcompactions.
+    /// Default: 64MB
+    pub subcompaction_threshold_bytes: u64,
+
+    /// Snapshots older than this are stale.  Before each compaction the
+    /// DB looks for stale snapshots: one taken with
+    /// SnapshotExpiry::Error expires, so that reads through it fail with
+    /// InvalidArgument and it no longer keeps compactions from dropping
+    /// the entries it would see; one taken with SnapshotExpiry::Ignore is
+    /// logged to info_log and counted in the "leveldb.snapshots" property,
+    /// but keeps working.  Zero means snapshots never go stale.
+    /// Default: 0
+    pub max_snapshot_age_seconds: u64,
+
+    /// Compactions write their output tables at no more than this many
+    /// bytes per second, all of them together, leaving the disk to
+    /// foreground reads and writes.  Zero means no limit.
+    /// 
+    /// May be changed on a live DB with DB::set_options.
+    /// Default: 0
+    pub compaction_rate_limit_bytes_per_sec: u64,
+
+    /// A flush or compaction that fails with an IO error is retried, after
+    /// a growing pause, up to this many times in a row before the error
+    /// becomes a background error that fails every later write.  Errors
+    /// writing the MANIFEST are never retried.
+    /// 
+    /// May be changed on a live DB with DB::set_options.
+    /// Default: 0
+    pub max_background_error_retries: usize,
+
+    /// When no compaction is needed otherwise, a table above the last
+    /// level in which at least this fraction of the entries are deletion
+    /// markers is compacted into the level below, so that the deletions
+    /// reach the data they hide and both can be dropped.  Zero disables
+    /// these compactions.  Clamped to [0, 1] when the DB is opened.
+    /// 
+    /// May be changed on a live DB with DB::set_options.
+    /// Default: 0
+    pub tombstone_compaction_ratio: f64,
+
+    /// When no compaction is needed otherwise and the last non-empty
+    /// level holds more than this many files, runs of adjacent files
+    /// there smaller than half of max_file_size are merged into files of
+    /// the usual size, in the same level.  Zero disables the check.
+    /// Default: 0
+    pub max_bottommost_files: usize,
+
+    /// Like max_bottommost_files, for when the files of the last non-empty
+    /// level average less than this many bytes.  Zero disables the check.
+    /// Default: 0
+    pub min_bottommost_file_size: u64,
+
+    /// This fraction of the gets and new iterators verify the checksums
+    /// of the blocks they read even though their ReadOptions do not ask
+    /// for it, so that damage is noticed without paying for checks on
+    /// every read.  Clamped to [0, 1] when the DB is opened.
+    /// 
+    /// May be changed on a live DB with DB::set_options.
+    /// Default: 0
+    pub checksum_verification_sample_rate: f64,
+
+    /// Where writes start to be delayed on the curve of write delays over
+    /// compaction debt (see DB::write_controller()): once the debt
+    /// reaches "debt_bytes", every write waits "delay_micros".
+    /// Default: 256MB, 1ms
+    pub write_delay_start: WriteDelayPoint,
+
+    /// Where the curve levels off: the delay grows linearly from
+    /// write_delay_start to "delay_micros" at "debt_bytes", and stays
+    /// there for any larger debt.  A point with no more debt than
+    /// write_delay_start, or a shorter delay, is raised to just past it.
+    /// Default: 1GB, 10ms
+    pub write_delay_full: WriteDelayPoint,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DB contents are stored in a set of blocks, each of which holds a
+/// sequence of key,value pairs.  Each block may be compressed before
+/// being stored in a file.  The following enum describes which
+/// compression method (if any) is used to compress a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionType {
+    NoCompression,
+    #[default]
+    SnappyCompression,
+}
+
+/// A point on the curve of write delays over compaction debt (see
+/// Options::write_delay_start).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteDelayPoint {
+    pub debt_bytes: u64,
+    pub delay_micros: u64,
+}
+
+/// How user keys and values are rendered in diagnostic output (see
+/// Options::diagnostics_redaction).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagnosticRedaction {
+    /// Show the bytes in full.
+    #[default]
+    Full,
+
+    /// Show at most this many bytes, followed by "..." if some were cut.
+    TruncateTo(usize),
+
+    /// Show only the length and a short hash of the bytes, which is enough
+    /// to tell whether two messages are about the same key.
+    HashOnly,
+}
+
+/// How far DB::open() replays a log file that is damaged.  The records
+/// that are not replayed are lost; DB::open() logs how many to info_log.
+/// A log closed cleanly ends in a trailer recording how many records it
+/// holds; if that does not match what was read, the trailer counts as a
+/// damaged record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecoveryMode {
+    /// Skip the damaged records and replay the ones after them, which
+    /// may leave holes in the recovered writes.  The records skipped are
+    /// counted in the "leveldb.wal-recovery-dropped-records" property.
+    TolerateCorruptedTail,
+
+    /// Fail DB::open() on any damage, including a record cut short at
+    /// the end of a log, as a crash in the middle of a write leaves it.
+    AbsoluteConsistency,
+
+    /// Replay up to the first damaged record and drop it together with
+    /// every record after it, in this log and in later ones, so that the
+    /// DB is recovered to a point in time.
+    #[default]
+    PointInTime,
 }
 
 impl Options {
     /// Create an Options object with default values for all fields.
     pub fn new() -> Self {
-        todo!()
+        Self {
+            comparator: bytewise_comparator(),
+            create_if_missing: false,
+            error_if_exists: false,
+            paranoid_checks: false,
+            env: default_env(),
+            info_log: None,
+            diagnostics_redaction: DiagnosticRedaction::Full,
+            write_buffer_size: 4 * 1024 * 1024,
+            memtable_prefix_compression: 0,
+            max_open_files: 1000,
+            adaptive_table_cache: false,
+            adaptive_table_cache_headroom: 64,
+            block_cache: None,
+            block_cache_level_partitions: None,
+            block_cache_partition_borrowing: false,
+            no_block_cache: false,
+            block_size: 4 * 1024,
+            block_restart_interval: 16,
+            max_file_size: 2 * 1024 * 1024,
+            compression: CompressionType::SnappyCompression,
+            filter_policy: None,
+            filter_cache: None,
+            output_split_key_policy: None,
+            compaction_filter: None,
+            batch_transformer: None,
+            prepared_batch_resolver: None,
+            replica_mode: false,
+            keep_old_versions: 0,
+            idempotency_window: 1024,
+            keep_log_file_num: 0,
+            preallocation_block_size: 1024 * 1024,
+            read_amp_window: 1000,
+            read_amp_warning_l0_files: 8,
+            read_amp_warning_interval_secs: 600,
+            lookup_statistics: false,
+            reuse_logs: false,
+            wal_recovery_mode: WalRecoveryMode::PointInTime,
+            fencing_token: None,
+            instance_name: None,
+            iterator_pool_size: 0,
+            max_subcompactions: 1,
+            subcompaction_threshold_bytes: 64 * 1024 * 1024,
+            max_snapshot_age_seconds: 0,
+            compaction_rate_limit_bytes_per_sec: 0,
+            max_background_error_retries: 0,
+            tombstone_compaction_ratio: 0.0,
+            max_bottommost_files: 0,
+            min_bottommost_file_size: 0,
+            checksum_verification_sample_rate: 0.0,
+            write_delay_start: WriteDelayPoint { debt_bytes: 256 << 20, delay_micros: 1000 },
+            write_delay_full: WriteDelayPoint { debt_bytes: 1 << 30, delay_micros: 10_000 },
+        }
+    }
+}
+
+/// Options that control read operations
+#[derive(Clone)]
+pub struct ReadOptions {
+    /// If true, all data read from underlying storage will be
+    /// verified against corresponding checksums: each table block read
+    /// has its crc recomputed, and a mismatch fails the read, or the
+    /// iterator's status(), with a Corruption status.  Otherwise only
+    /// Options::paranoid_checks makes block reads check them.
+    /// Default: false
+    pub verify_checksums: bool,
+
+    /// Should the data read for this iteration be cached in memory?
+    /// Callers may wish to set this field to false for bulk scans.  The
+    /// blocks they read are then still found in Options::block_cache if
+    /// already there, but not added to it, so that a scan does not evict
+    /// the blocks of other reads.
+    /// Default: true
+    pub fill_cache: bool,
+
+    /// If "snapshot" is non-null, read as of the supplied snapshot
+    /// (which must belong to the DB that is being read and which must
+    /// not have been released).  If "snapshot" is null, use an implicit
+    /// snapshot of the state at the beginning of this read operation.
+    pub snapshot: Option<Arc<Snapshot>>,
+
+    /// If non-null, the read gives up with a TimedOut status once
+    /// Env::now_micros() passes this value.  The deadline is checked
+    /// before each block read, before opening a table that is not
+    /// cached, and between the files a get() visits; an iterator that
+    /// times out becomes invalid and reports the status.
+    /// Default: NULL (no deadline)
+    pub deadline: Option<u64>,
+
+    /// Which data the read may look at; see ReadTier.
+    /// Default: ReadTier::Default
+    pub read_tier: ReadTier,
+
+    /// If non-null, an iterator treats every key at or past this one as
+    /// the end of the database: it never yields such keys, and the
+    /// tables holding only such keys are not opened.  seek_to_last()
+    /// starts at the last key before the bound.
+    /// Default: NULL (no bound)
+    pub iterate_upper_bound: Option<Vec<u8>>,
+}
+
+/// Where a read looks for data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadTier {
+    /// Everything: the memtable, the immutable memtable and the tables.
+    #[default]
+    Default,
+
+    /// Only the immutable memtable and the tables, skipping the memtable
+    /// that writes go to.  The read sees the database as of the last
+    /// memtable switch (or as of options.snapshot, if that is older), so
+    /// it may miss recent writes but never sees part of a write.  See
+    /// DB::get_with_sequence() for the sequence a read sees.
+    PersistedAndImmutable,
+}
+
+impl ReadOptions {
+    pub fn new() -> Self {
+        Self { verify_checksums: false, fill_cache: true, snapshot: None, deadline: None, read_tier: ReadTier::Default, iterate_upper_bound: None }
+    }
+
+    /// Parse options written as "name=value" pairs separated by ';', as
+    /// to_option_string() writes them, e.g.
+    /// "fill_cache=false;iterate_upper_bound=user\x3b".  Options that are
+    /// not named keep their defaults.  The snapshot can not be given.
+    pub fn parse(s: &str) -> Result<Self, Status> {
+        let mut options = Self::new();
+        for (name, value) in split_option_string(s)? {
+            match name {
+                "verify_checksums" => options.verify_checksums = parse_bool(name, value)?,
+                "fill_cache" => options.fill_cache = parse_bool(name, value)?,
+                "deadline" => options.deadline = Some(parse_number(name, value)?),
+                "read_tier" => {
+                    options.read_tier = match value {
+                        "default" => ReadTier::Default,
+                        "persisted_and_immutable" => ReadTier::PersistedAndImmutable,
+                        _ => return Err(Status::invalid_argument(name, &format!("cannot parse \"{}\"", value))),
+                    };
+                },
+                "iterate_upper_bound" => options.iterate_upper_bound = Some(parse_escaped(value)?),
+                _ => return Err(Status::invalid_argument(name, "not a read option")),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Return the options in the form parse() reads, leaving out the
+    /// snapshot.  The options appear in a fixed order, the optional ones
+    /// only if set.
+    pub fn to_option_string(&self) -> String {
+        let mut result = format!("verify_checksums={};fill_cache={}", self.verify_checksums, self.fill_cache);
+        if let Some(deadline) = self.deadline {
+            result.push_str(&format!(";deadline={}", deadline));
+        }
+        result.push_str(match self.read_tier {
+            ReadTier::Default => ";read_tier=default",
+            ReadTier::PersistedAndImmutable => ";read_tier=persisted_and_immutable",
+        });
+        if let Some(bound) = &self.iterate_upper_bound {
+            result.push_str(&format!(";iterate_upper_bound={}", escape_bytes(bound)));
+        }
+        result
+    }
+
+    /// Returns a TimedOut status if the deadline has passed according to
+    /// "env".  Reads the clock only when a deadline is set.
+    pub(crate) fn check_deadline(&self, env: &dyn Env) -> Status {
+        match self.deadline {
+            Some(deadline) if env.now_micros() > deadline => Status::timed_out("deadline exceeded", ""),
+            _ => Status::new_ok(),
+        }
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Options that control write operations
+#[derive(Clone, Default)]
+pub struct WriteOptions {
+    /// If true, the write will be flushed from the operating system
+    /// buffer cache (by calling WritableFile::Sync()) before the write
+    /// is considered complete.  If this flag is true, writes will be
+    /// slower.
+    /// 
+    /// If this flag is false, and the machine crashes, some recent
+    /// writes may be lost.  Note that if it is just the process that
+    /// crashes (i.e., the machine does not reboot), no writes will be
+    /// lost even if sync==false.
+    /// 
+    /// In other words, a DB write with sync==false has similar
+    /// crash semantics as the "write()" system call.  A DB write
+    /// with sync==true has similar crash semantics to a "write()"
+    /// system call followed by "fsync()".
+    /// 
+    /// If the sync fails, it is unknown whether the write will survive a
+    /// restart, so the write fails and so does every later write.
+    pub sync: bool,
+
+    /// If true, a write touching a key range locked with DB::lock_range
+    /// fails immediately with a Busy status instead of waiting for the
+    /// lock to be released.
+    pub fail_on_locked_range: bool,
+
+    /// If non-null, the token is stored with the batch in the log, and a
+    /// later write carrying the same token is not applied again: it fails
+    /// with an AlreadyApplied status naming the sequence number the first
+    /// write was committed at.  See Options::idempotency_window and
+    /// Options::keep_log_file_num for how long tokens are remembered.
+    /// Default: NULL
+    pub idempotency_token: Option<[u8; 16]>,
+
+    /// If true, the time the write spends in each step of its commit is
+    /// measured; see DB::last_write_timing() and DB::write_timing_report().
+    /// If false, the write does not read the clock for this.
+    /// Default: false
+    pub collect_timing: bool,
+
+    /// If true, the write goes to the memtable only, not to the log: a
+    /// crash loses it unless the memtable holding it was flushed first.
+    /// Meant for loading data the caller can load again after a crash.
+    /// The DB records in the MANIFEST that its memtables hold unlogged
+    /// writes until they are flushed, so that DB::open() after a crash
+    /// warns to info_log and reports the possible loss in the
+    /// "leveldb.possible-unlogged-data-loss" property.  Logged and
+    /// unlogged writes may be committed together; only the logged ones
+    /// are added to the log.
+    /// Default: false
+    pub disable_wal: bool,
+}
+
+impl WriteOptions {
+    /// Parse options written as "name=value" pairs separated by ';', as
+    /// to_option_string() writes them, e.g. "sync=true".  Options that are
+    /// not named keep their defaults.  The idempotency token is given in
+    /// hex, as "0x" followed by 32 digits.
+    pub fn parse(s: &str) -> Result<Self, Status> {
+        let mut options = Self::default();
+        for (name, value) in split_option_string(s)? {
+            match name {
+                "sync" => options.sync = parse_bool(name, value)?,
+                "fail_on_locked_range" => options.fail_on_locked_range = parse_bool(name, value)?,
+                "idempotency_token" => {
+                    let token = value.starts_with("0x").then(|| parse_escaped(value).ok()).flatten();
+                    match token.and_then(|token| token.try_into().ok()) {
+                        Some(token) => options.idempotency_token = Some(token),
+                        None => return Err(Status::invalid_argument(name, &format!("\"{}\" is not 16 bytes of hex", value))),
+                    }
+                },
+                "collect_timing" => options.collect_timing = parse_bool(name, value)?,
+                "disable_wal" => options.disable_wal = parse_bool(name, value)?,
+                _ => return Err(Status::invalid_argument(name, "not a write option")),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Return the options in the form parse() reads.  The options appear
+    /// in a fixed order, the token only if set.
+    pub fn to_option_string(&self) -> String {
+        let mut result = format!("sync={};fail_on_locked_range={}", self.sync, self.fail_on_locked_range);
+        if let Some(token) = &self.idempotency_token {
+            result.push_str(";idempotency_token=0x");
+            for b in token {
+                result.push_str(&format!("{:02x}", b));
+            }
+        }
+        result.push_str(&format!(";collect_timing={};disable_wal={}", self.collect_timing, self.disable_wal));
+        result
+    }
+}
+
+/// What happens to a snapshot once it is older than
+/// Options::max_snapshot_age_seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotExpiry {
+    /// The snapshot expires: reads through it fail with InvalidArgument,
+    /// and compactions may drop the entries only it could see.
+    #[default]
+    Error,
+
+    /// The snapshot keeps working and keeps its entries from being
+    /// compacted away; it is only logged and counted.
+    Ignore,
+}
+
+/// Options that control DB::get_snapshot_with().
+#[derive(Debug, Clone, Default)]
+pub struct GetSnapshotOptions {
+    /// See SnapshotExpiry.
+    /// Default: SnapshotExpiry::Error
+    pub on_expiry: SnapshotExpiry,
+}
+
+/// Controls how DB::open_with_retry() waits for a database whose lock is
+/// held by someone else, e.g. a previous process that is still shutting
+/// down.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Give up once this much time has been spent waiting.
+    /// Default: 10s
+    pub max_wait_micros: u64,
+
+    /// Time to wait after the first failed attempt.
+    /// Default: 10ms
+    pub backoff_start_micros: u64,
+
+    /// Each wait is this many times longer than the one before.
+    /// Default: 2.0
+    pub backoff_multiplier: f64,
+
+    /// If non-null, checked after every wait: once it is set, no further
+    /// attempt is made.
+    /// Default: NULL
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self {
+            max_wait_micros: 10_000_000,
+            backoff_start_micros: 10_000,
+            backoff_multiplier: 2.0,
+            cancel: None,
+        }
+    }
+
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Acquire))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The subset of Options that may be changed while the DB is running
+/// (see DB::set_options).  The DB keeps one copy guarded by its mutex;
+/// readers always observe either all or none of a set_options call.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MutableOptions {
+    pub(crate) write_buffer_size: usize,
+    pub(crate) l0_slowdown_writes_trigger: i32,
+    pub(crate) l0_stop_writes_trigger: i32,
+    pub(crate) compaction_rate_limit_bytes_per_sec: u64,
+    pub(crate) max_background_error_retries: usize,
+    pub(crate) tombstone_compaction_ratio: f64,
+    pub(crate) checksum_verification_sample_rate: f64,
+}
+
+impl MutableOptions {
+    /// Names accepted by set_options.
+    pub(crate) const SUPPORTED: [&'static str; 7] = [
+        "write_buffer_size",
+        "l0_slowdown_writes_trigger",
+        "l0_stop_writes_trigger",
+        "compaction_rate_limit_bytes_per_sec",
+        "max_background_error_retries",
+        "tombstone_compaction_ratio",
+        "checksum_verification_sample_rate",
+    ];
+
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            write_buffer_size: options.write_buffer_size,
+            l0_slowdown_writes_trigger: L0_SLOWDOWN_WRITES_TRIGGER,
+            l0_stop_writes_trigger: L0_STOP_WRITES_TRIGGER,
+            compaction_rate_limit_bytes_per_sec: options.compaction_rate_limit_bytes_per_sec,
+            max_background_error_retries: options.max_background_error_retries,
+            tombstone_compaction_ratio: options.tombstone_compaction_ratio,
+            checksum_verification_sample_rate: options.checksum_verification_sample_rate,
+        }
+    }
+
+    /// Parse and validate every entry of "changes" and apply them all, or
+    /// none of them if any entry is rejected.  The first rejected entry is
+    /// named in the returned InvalidArgument status.
+    /// 
+    /// On success returns (name, old value, new value) for every change.
+    pub(crate) fn apply(&mut self, changes: &[(&str, &str)]) -> Result<Vec<(String, String, String)>, Status> {
+        let mut updated = self.clone();
+        let mut applied = Vec::with_capacity(changes.len());
+        for &(name, value) in changes {
+            let old = updated.get(name);
+            match name {
+                "write_buffer_size" => {
+                    let v = parse_number::<usize>(name, value)?;
+                    if !(MIN_WRITE_BUFFER_SIZE..=MAX_WRITE_BUFFER_SIZE).contains(&v) {
+                        return Err(Status::invalid_argument(name, &format!("{} is outside [{}, {}]", 
+                                    value, MIN_WRITE_BUFFER_SIZE, MAX_WRITE_BUFFER_SIZE)));
+                    }
+                    updated.write_buffer_size = v;
+                },
+                "l0_slowdown_writes_trigger" => {
+                    updated.l0_slowdown_writes_trigger = parse_positive(name, value)?;
+                },
+                "l0_stop_writes_trigger" => {
+                    updated.l0_stop_writes_trigger = parse_positive(name, value)?;
+                },
+                "compaction_rate_limit_bytes_per_sec" => {
+                    updated.compaction_rate_limit_bytes_per_sec = parse_number(name, value)?;
+                },
+                "max_background_error_retries" => {
+                    updated.max_background_error_retries = parse_number(name, value)?;
+                },
+                "tombstone_compaction_ratio" => {
+                    updated.tombstone_compaction_ratio = parse_fraction(name, value)?;
+                },
+                "checksum_verification_sample_rate" => {
+                    updated.checksum_verification_sample_rate = parse_fraction(name, value)?;
+                },
+                _ => {
+                    return Err(Status::invalid_argument(name, &format!("not a dynamic option (supported: {})", 
+                                Self::SUPPORTED.join(", "))));
+                },
+            }
+            applied.push((name.to_string(), old, updated.get(name)));
+        }
+        if updated.l0_slowdown_writes_trigger > updated.l0_stop_writes_trigger {
+            return Err(Status::invalid_argument("l0_slowdown_writes_trigger", 
+                        "must not be greater than l0_stop_writes_trigger"));
+        }
+        *self = updated;
+        Ok(applied)
+    }
+
+    fn get(&self, name: &str) -> String {
+        match name {
+            "write_buffer_size" => self.write_buffer_size.to_string(),
+            "l0_slowdown_writes_trigger" => self.l0_slowdown_writes_trigger.to_string(),
+            "l0_stop_writes_trigger" => self.l0_stop_writes_trigger.to_string(),
+            "compaction_rate_limit_bytes_per_sec" => self.compaction_rate_limit_bytes_per_sec.to_string(),
+            "max_background_error_retries" => self.max_background_error_retries.to_string(),
+            "tombstone_compaction_ratio" => self.tombstone_compaction_ratio.to_string(),
+            "checksum_verification_sample_rate" => self.checksum_verification_sample_rate.to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Status> {
+    value.trim().parse::<T>().map_err(|_| {
+        Status::invalid_argument(name, &format!("cannot parse \"{}\"", value))
+    })
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, Status> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(Status::invalid_argument(name, &format!("cannot parse \"{}\"", value))),
+    }
+}
+
+/// Split "name=value;name=value" into its pairs, ignoring whitespace
+/// around them and empty entries.
+fn split_option_string(s: &str) -> Result<Vec<(&str, &str)>, Status> {
+    s.split(';').map(str::trim).filter(|entry| !entry.is_empty()).map(|entry| {
+        entry.split_once('=')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .ok_or_else(|| Status::invalid_argument(entry, "expected name=value"))
+    }).collect()
+}
+
+/// Parse a fraction in [0, 1], the range sanitize_options() clamps to.
+fn parse_fraction(name: &str, value: &str) -> Result<f64, Status> {
+    let v = parse_number::<f64>(name, value)?;
+    if !(0.0..=1.0).contains(&v) {
+        return Err(Status::invalid_argument(name, &format!("{} is outside [0, 1]", value)));
+    }
+    Ok(v)
+}
+
+fn parse_positive(name: &str, value: &str) -> Result<i32, Status> {
+    let v = parse_number::<i32>(name, value)?;
+    if v <= 0 {
+        return Err(Status::invalid_argument(name, &format!("{} is not positive", value)));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> MutableOptions {
+        MutableOptions::new(&Options::new())
+    }
+
+    #[test]
+    fn apply_test() {
+        let mut opts = defaults();
+        let applied = opts.apply(&[("write_buffer_size", "1048576"), ("l0_stop_writes_trigger", "20")]).unwrap();
+        assert_eq!(1 << 20, opts.write_buffer_size);
+        assert_eq!(20, opts.l0_stop_writes_trigger);
+        assert_eq!(("write_buffer_size".to_string(), (4 << 20).to_string(), (1 << 20).to_string()), applied[0]);
+        assert_eq!(("l0_stop_writes_trigger".to_string(), "12".to_string(), "20".to_string()), applied[1]);
+
+        let applied = opts.apply(&[("compaction_rate_limit_bytes_per_sec", "1048576"), ("max_background_error_retries", "3"),
+                                   ("tombstone_compaction_ratio", "0.5"), ("checksum_verification_sample_rate", "1")]).unwrap();
+        assert_eq!((1 << 20, 3), (opts.compaction_rate_limit_bytes_per_sec, opts.max_background_error_retries));
+        assert_eq!((0.5, 1.0), (opts.tombstone_compaction_ratio, opts.checksum_verification_sample_rate));
+        assert_eq!(("tombstone_compaction_ratio".to_string(), "0".to_string(), "0.5".to_string()), applied[2]);
+    }
+
+    #[test]
+    fn apply_is_all_or_nothing_test() {
+        let mut opts = defaults();
+        let s = opts.apply(&[("write_buffer_size", "1048576"), ("l0_slowdown_writes_trigger", "-1"),
+                            ("l0_stop_writes_trigger", "20")]).unwrap_err();
+        assert!(s.is_invalid_argument());
+        assert!(s.to_string().contains("l0_slowdown_writes_trigger"));
+        assert_eq!(defaults(), opts);
+
+        // Values outside the range enforced at open time are rejected too.
+        assert!(opts.apply(&[("write_buffer_size", "1024")]).is_err());
+        assert!(opts.apply(&[("write_buffer_size", "4MB")]).is_err());
+        for (name, value) in [("compaction_rate_limit_bytes_per_sec", "-1"), ("max_background_error_retries", "x"),
+                              ("tombstone_compaction_ratio", "1.5"), ("checksum_verification_sample_rate", "-0.1"),
+                              ("checksum_verification_sample_rate", "NaN")] {
+            let s = opts.apply(&[("write_buffer_size", "1048576"), (name, value)]).unwrap_err();
+            assert!(s.is_invalid_argument() && s.to_string().contains(name), "{}", s.to_string());
+        }
+        // Each value is valid on its own, but the combination is not.
+        assert!(opts.apply(&[("l0_slowdown_writes_trigger", "30")]).is_err());
+        assert_eq!(defaults(), opts);
+    }
+
+    #[test]
+    fn read_options_string_test() {
+        let options = ReadOptions::new();
+        assert_eq!("verify_checksums=false;fill_cache=true;read_tier=default", options.to_option_string());
+        let options = ReadOptions::parse(" fill_cache=false; read_tier=persisted_and_immutable;;iterate_upper_bound=a\\x3bb ;deadline=7").unwrap();
+        assert!(!options.fill_cache && !options.verify_checksums);
+        assert_eq!((Some(7), ReadTier::PersistedAndImmutable), (options.deadline, options.read_tier));
+        assert_eq!(Some(b"a;b".to_vec()), options.iterate_upper_bound);
+        let s = options.to_option_string();
+        assert_eq!("verify_checksums=false;fill_cache=false;deadline=7;read_tier=persisted_and_immutable;iterate_upper_bound=a\\x3bb", s);
+        assert_eq!(s, ReadOptions::parse(&s).unwrap().to_option_string());
+        assert_eq!(ReadOptions::new().to_option_string(), ReadOptions::parse("").unwrap().to_option_string());
+
+        for bad in ["fill_cache=yes", "snapshot=1", "read_tier=all", "deadline=-1", "fill_cache", "iterate_upper_bound=\\q"] {
+            assert!(ReadOptions::parse(bad).err().unwrap().is_invalid_argument(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn write_options_string_test() {
+        assert_eq!("sync=false;fail_on_locked_range=false;collect_timing=false;disable_wal=false", WriteOptions::default().to_option_string());
+        let s = "sync=true;fail_on_locked_range=false;idempotency_token=0x000102030405060708090a0b0c0d0eff;collect_timing=true;disable_wal=true";
+        let options = WriteOptions::parse(s).unwrap();
+        assert!(options.sync && options.collect_timing && options.disable_wal && !options.fail_on_locked_range);
+        assert_eq!(Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 0xff]), options.idempotency_token);
+        assert_eq!(s, options.to_option_string());
+
+        for bad in ["sync=1", "idempotency_token=0x00", "idempotency_token=abcdefghijklmnop", "verify_checksums=true"] {
+            assert!(WriteOptions::parse(bad).err().unwrap().is_invalid_argument(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn apply_unsupported_test() {
+        let mut opts = defaults();
+        let s = opts.apply(&[("max_open_files", "10")]).unwrap_err();
+        assert!(s.is_invalid_argument());
+        for name in MutableOptions::SUPPORTED {
+            assert!(s.to_string().contains(name));
+        }
+        assert_eq!(defaults(), opts);
+    }
+
+    #[test]
+    fn read_write_options_default_test() {
+        let ro = ReadOptions::default();
+        assert!(!ro.verify_checksums && ro.fill_cache);
+        assert!(ro.snapshot.is_none() && ro.iterate_upper_bound.is_none() && ro.deadline.is_none());
+        assert_eq!(ReadTier::Default, ro.read_tier);
+        assert_eq!(ReadOptions::new().to_option_string(), ro.to_option_string());
+
+        let wo = WriteOptions::default();
+        assert!(!wo.sync && !wo.fail_on_locked_range && wo.idempotency_token.is_none() && !wo.disable_wal);
+    }
+
+    #[test]
+    fn default_test() {
+        let options = Options::default();
+        assert_eq!("leveldb.BytewiseComparator", options.comparator.name());
+        assert!(!options.create_if_missing && !options.error_if_exists && !options.paranoid_checks && !options.reuse_logs);
+        assert!(options.info_log.is_none() && options.block_cache.is_none() && options.filter_policy.is_none() && options.filter_cache.is_none());
+        assert!(options.block_cache_level_partitions.is_none() && !options.block_cache_partition_borrowing);
+        assert_eq!(DiagnosticRedaction::Full, options.diagnostics_redaction);
+        assert_eq!(4 << 20, options.write_buffer_size);
+        assert_eq!(1000, options.max_open_files);
+        assert_eq!(4 << 10, options.block_size);
+        assert_eq!(16, options.block_restart_interval);
+        assert_eq!(2 << 20, options.max_file_size);
+        assert_eq!(CompressionType::SnappyCompression, options.compression);
+        assert_eq!(CompressionType::SnappyCompression, CompressionType::default());
+        assert_eq!(0, options.max_snapshot_age_seconds);
+        assert_eq!((0, 0), (options.compaction_rate_limit_bytes_per_sec, options.max_background_error_retries));
+        assert_eq!((0.0, 0.0), (options.tombstone_compaction_ratio, options.checksum_verification_sample_rate));
+        assert_eq!((0, 0), (options.max_bottommost_files, options.min_bottommost_file_size));
+        assert_eq!(SnapshotExpiry::Error, GetSnapshotOptions::default().on_expiry);
+        assert_eq!(WriteDelayPoint { debt_bytes: 256 << 20, delay_micros: 1000 }, options.write_delay_start);
+        assert_eq!(WriteDelayPoint { debt_bytes: 1 << 30, delay_micros: 10_000 }, options.write_delay_full);
     }
 }
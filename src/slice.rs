@@ -8,7 +8,9 @@
 //! non-const method, all threads accessing the same Slice must use
 //! external synchronization.
 
-use std::{cmp::Ordering, str::from_utf8};
+use std::{cmp::Ordering, fmt::Write, str::from_utf8};
+
+use crate::status::Status;
 
 #[derive(Clone)]
 pub struct Slice<'a> {
@@ -61,6 +63,11 @@ impl<'a> Slice<'a> {
         }
     }
 
+    /// Return the referenced data in the printable form of escape_bytes().
+    pub fn to_escaped_string(&self) -> String {
+        escape_bytes(self.data())
+    }
+
     /// Three-way comparison.  Returns value:
     ///   <  0 iff "*this" <  "b",
     ///   == 0 iff "*this" == "b",
@@ -85,6 +92,64 @@ impl<'a> PartialEq<&[u8]> for Slice<'a> {
     } 
 }
 
+/// Return "bytes" as a printable string without whitespace, ';' or
+/// unprintable characters, from which parse_escaped() recovers them:
+/// printable ASCII stands for itself, and every other byte, '\' and ';'
+/// are written as "\xNN".  So is the '0' of a leading "0x", which
+/// parse_escaped() would read as hex.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len());
+    for (i, &b) in bytes.iter().enumerate() {
+        let hex_prefix = i == 0 && bytes.starts_with(b"0x");
+        if (0x21..=0x7e).contains(&b) && b != b'\\' && b != b';' && !hex_prefix {
+            result.push(b as char);
+        } else {
+            let _ = write!(result, "\\x{:02x}", b);
+        }
+    }
+    result
+}
+
+/// Parse a key or value typed by a person: either "0x" followed by an
+/// even number of hex digits, or text in which "\xNN" stands for the
+/// byte with hex value NN and "\\" for a backslash, as written by
+/// escape_bytes().
+pub fn parse_escaped(s: &str) -> Result<Vec<u8>, Status> {
+    let bad = |why: &str| Status::invalid_argument(why, s);
+    if let Some(hex) = s.strip_prefix("0x") {
+        if hex.len() % 2 != 0 {
+            return Err(bad("odd number of hex digits"));
+        }
+        return (0..hex.len()).step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(parse_hex_byte).ok_or_else(|| bad("bad hex digit")))
+            .collect();
+    }
+    let mut result = Vec::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        result.extend_from_slice(&rest.as_bytes()[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('\\') {
+            result.push(b'\\');
+            rest = after;
+        } else {
+            let byte = rest.strip_prefix('x').and_then(|hex| hex.get(..2)).and_then(parse_hex_byte);
+            result.push(byte.ok_or_else(|| bad("bad escape sequence"))?);
+            rest = &rest[3..];
+        }
+    }
+    result.extend_from_slice(rest.as_bytes());
+    Ok(result)
+}
+
+fn parse_hex_byte(digits: &str) -> Option<u8> {
+    if digits.bytes().all(|d| d.is_ascii_hexdigit()) {
+        u8::from_str_radix(digits, 16).ok()
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -93,4 +158,30 @@ mod tests {
     fn compare_with_empty_test() {
         assert!(Slice::new(b"s").compare(&Slice::new(b"")) == Ordering::Greater);
     }
+
+    #[test]
+    fn escape_test() {
+        assert_eq!("abc", escape_bytes(b"abc"));
+        assert_eq!("a\\x20b\\x3b\\x5c\\x00\\xff", escape_bytes(b"a b;\\\x00\xff"));
+        assert_eq!("\\x30x1", escape_bytes(b"0x1"));
+        assert_eq!("x0x1", escape_bytes(b"x0x1"));
+        assert_eq!("k\\x01", Slice::new(b"k\x01").to_escaped_string());
+
+        let samples: [&[u8]; 6] = [b"", b"abc", b"0x", b"0xab", b"\\\\x", b"\x00\x7f\x80\xff;= "];
+        for bytes in samples {
+            assert_eq!(bytes.to_vec(), parse_escaped(&escape_bytes(bytes)).unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_escaped_test() {
+        assert_eq!(b"a b".to_vec(), parse_escaped("a\\x20b").unwrap());
+        assert_eq!(b"\\".to_vec(), parse_escaped("\\\\").unwrap());
+        assert_eq!(b"\xab\x01".to_vec(), parse_escaped("0xAb01").unwrap());
+        assert_eq!(Vec::<u8>::new(), parse_escaped("0x").unwrap());
+        assert_eq!("\u{e9}".as_bytes().to_vec(), parse_escaped("\u{e9}").unwrap());
+        for bad in ["0xabc", "0xzz", "0xa\u{e9}b", "\\", "a\\x4", "\\x4g", "\\n", "\\x\u{e9}"] {
+            assert!(parse_escaped(bad).unwrap_err().is_invalid_argument(), "{}", bad);
+        }
+    }
 }
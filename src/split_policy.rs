@@ -0,0 +1,63 @@
+//! A database can be configured with a SplitPolicy that decides where
+//! compaction may cut its output into separate table files, in addition
+//! to the usual size limit.  Cutting at boundaries that depend only on
+//! the keys (e.g. a tenant prefix) makes the files produced for the same
+//! logical data identical from one compaction to the next.
+
+use crate::slice::Slice;
+
+pub trait SplitPolicy: Send + Sync {
+    /// Return the name of this policy.  Recorded for inspection; changing
+    /// policies on an existing database is allowed.
+    fn name(&self) -> &str;
+
+    /// Return true if an output file whose last user key is "prev" must
+    /// be finished before "key" is added, so that no file spans both.
+    /// REQUIRES: prev < key in the user comparator's order
+    fn should_split_before(&self, prev: &Slice, key: &Slice) -> bool;
+}
+
+/// Split whenever the first "n" bytes of the user key change.  Keys
+/// shorter than "n" bytes are treated as a prefix of their own.
+pub struct FixedPrefixSplitPolicy {
+    prefix_len_: usize,
+    name_: String,
+}
+
+impl FixedPrefixSplitPolicy {
+    pub fn new(n: usize) -> Self {
+        Self { prefix_len_: n, name_: format!("rucksdb.FixedPrefixSplitPolicy.{}", n) }
+    }
+
+    fn prefix<'a>(&self, key: &'a Slice) -> &'a [u8] {
+        let data = key.data();
+        &data[..data.len().min(self.prefix_len_)]
+    }
+}
+
+impl SplitPolicy for FixedPrefixSplitPolicy {
+    fn name(&self) -> &str {
+        &self.name_
+    }
+
+    fn should_split_before(&self, prev: &Slice, key: &Slice) -> bool {
+        self.prefix(prev) != self.prefix(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_prefix_test() {
+        let policy = FixedPrefixSplitPolicy::new(4);
+        assert_eq!("rucksdb.FixedPrefixSplitPolicy.4", policy.name());
+        let split = |a: &str, b: &str| policy.should_split_before(&Slice::new(a.as_bytes()), &Slice::new(b.as_bytes()));
+        assert!(!split("t001a", "t001b"));
+        assert!(!split("t001", "t001zzz"));
+        assert!(split("t001z", "t002a"));
+        assert!(split("t00", "t001"));
+        assert!(split("", "a"));
+    }
+}
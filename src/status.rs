@@ -6,13 +6,15 @@
 //! non-const method, all threads accessing the same Status must use
 //! external synchronization.
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Status {
     // OK status has a None state_.  Otherwise, state_ is a byte vector
     // of the following form:
-    //    state_[0..3] == length of message
-    //    state_[4]    == code
-    //    state_[5..]  == message
+    //    state_[0..3]          == length of message
+    //    state_[4]             == code
+    //    state_[5]             == subcode
+    //    state_[6..6+length]   == message
+    //    state_[6+length..]    == name of the file involved, if any
     state_: Option<Vec<u8>>,
 }
 
@@ -24,19 +26,34 @@ impl Status {
 
     // Return error status of an appropriate type.
     pub fn not_found(msg: &str, msg2: &str) -> Self {
-        Self::new(Code::not_found(), msg, msg2)
+        Self::new(Code::NotFound, msg, msg2)
     }
     pub fn corruption(msg: &str, msg2: &str) -> Self {
-        Self::new(Code::corruption(), msg, msg2)
+        Self::new(Code::Corruption, msg, msg2)
     }
     pub fn not_supported(msg: &str, msg2: &str) -> Self {
-        Self::new(Code::not_supported(), msg, msg2)
+        Self::new(Code::NotSupported, msg, msg2)
     }
     pub fn invalid_argument(msg: &str, msg2: &str) -> Self {
-        Self::new(Code::invalid_argument(), msg, msg2)
+        Self::new(Code::InvalidArgument, msg, msg2)
     }
     pub fn io_error(msg: &str, msg2: &str) -> Self {
-        Self::new(Code::io_error(), msg, msg2)
+        Self::new(Code::IOError, msg, msg2)
+    }
+    pub fn busy(msg: &str, msg2: &str) -> Self {
+        Self::new(Code::Busy, msg, msg2)
+    }
+    pub fn gap(msg: &str, msg2: &str) -> Self {
+        Self::new(Code::Gap, msg, msg2)
+    }
+    pub fn timed_out(msg: &str, msg2: &str) -> Self {
+        Self::new(Code::TimedOut, msg, msg2)
+    }
+    pub fn already_applied(msg: &str, msg2: &str) -> Self {
+        Self::new(Code::AlreadyApplied, msg, msg2)
+    }
+    pub fn incomplete(msg: &str, msg2: &str) -> Self {
+        Self::new(Code::Incomplete, msg, msg2)
     }
 
     /// Returns true iff the status indicates success.
@@ -46,30 +63,145 @@ impl Status {
 
     /// Returns true iff the status indicates a NotFound error.
     pub fn is_not_found(&self) -> bool {
-        self.code().is_not_found()
+        self.code() == Code::NotFound
     }
 
     /// Returns true iff the status indicates a Corruption error.
     pub fn is_corruption(&self) -> bool {
-        self.code().is_corruption()
+        self.code() == Code::Corruption
+    }
+
+    /// Returns true iff the status indicates a NotSupportedError.
+    pub fn is_not_supported_error(&self) -> bool {
+        self.code() == Code::NotSupported
     }
 
     /// Returns true iff the status indicates an IOError.
     pub fn is_io_error(&self) -> bool {
-        self.code().is_io_error()
+        self.code() == Code::IOError
     }
 
-    fn new(code: Code, msg: &str, msg2: &str) -> Self {
-        todo!()
+    /// Returns true iff the status indicates an InvalidArgument.
+    pub fn is_invalid_argument(&self) -> bool {
+        self.code() == Code::InvalidArgument
+    }
+
+    /// Returns true iff the status indicates that a resource (such as a
+    /// locked key range, or the lock of an open database) is held by
+    /// someone else.
+    pub fn is_busy(&self) -> bool {
+        self.code() == Code::Busy
+    }
+
+    /// Returns true iff the status indicates that a replicated batch did
+    /// not start at the next expected sequence number.
+    pub fn is_gap(&self) -> bool {
+        self.code() == Code::Gap
+    }
+
+    /// Returns true iff the status indicates that an operation gave up
+    /// because its deadline passed.
+    pub fn is_timed_out(&self) -> bool {
+        self.code() == Code::TimedOut
+    }
+
+    /// Returns true iff the status indicates that a write carried an
+    /// idempotency token that an earlier write already committed.
+    pub fn is_already_applied(&self) -> bool {
+        self.code() == Code::AlreadyApplied
+    }
+
+    /// Returns true iff the status indicates that an operation stopped
+    /// because finishing it would have needed a read from disk.
+    pub fn is_incomplete(&self) -> bool {
+        self.code() == Code::Incomplete
+    }
+
+    /// Return the kind of error, or Code::Ok for success.
+    pub fn code(&self) -> Code {
+        match self.state_.as_ref() {
+            Some(s) => Code::from(s[4]),
+            None => Code::Ok,
+        }
+    }
+
+    /// Return what more is known about the error, e.g. why a DB could not
+    /// be opened.  SubCode::None for success and most errors.
+    pub fn subcode(&self) -> SubCode {
+        match self.state_.as_ref() {
+            Some(s) => SubCode::from(s[5]),
+            None => SubCode::None,
+        }
+    }
+
+    /// Return the name of the file the error is about, if it is known.
+    pub fn filename(&self) -> Option<&str> {
+        let s = self.state_.as_ref()?;
+        let name = &s[6 + self.message_len()..];
+        (!name.is_empty()).then(|| std::str::from_utf8(name).expect("file name is not UTF-8"))
+    }
+
+    /// Return this status with "subcode".  An OK status is returned as
+    /// it is.
+    pub fn with_subcode(mut self, subcode: SubCode) -> Self {
+        if let Some(s) = self.state_.as_mut() {
+            s[5] = subcode as u8;
+        }
+        self
+    }
+
+    /// Return this status naming "fname" as the file involved, in place
+    /// of any file it named.  An OK status is returned as it is.
+    pub fn with_filename(mut self, fname: &str) -> Self {
+        let length = self.message_len();
+        if let Some(s) = self.state_.as_mut() {
+            s.truncate(6 + length);
+            s.extend(fname.as_bytes());
+        }
+        self
     }
 
-    fn code(&self) -> Code {
+    /// Return a status with the same code, subcode and file, and
+    /// "context" in front of the message.  An OK status is returned as it
+    /// is.
+    pub(crate) fn annotate(&self, context: &str) -> Self {
         match self.state_.as_ref() {
             Some(s) => {
-                assert!(s.len() >= 5);  // TODO
-                Code::from(s[4])
+                let mut result = Self::new(Code::from(s[4]), context, self.message()).with_subcode(SubCode::from(s[5]));
+                if let Some(fname) = self.filename() {
+                    result = result.with_filename(fname);
+                }
+                result
             },
-            None => Code::ok(),
+            None => self.clone(),
+        }
+    }
+
+    fn new(code: Code, msg: &str, msg2: &str) -> Self {
+        debug_assert!(code != Code::Ok);
+        let len1 = msg.len();
+        let len2 = msg2.len();
+        let size = len1 + if len2 > 0 { 2 + len2 } else { 0 };
+        let mut result = Vec::with_capacity(size + 6);
+        result.extend((size as u32).to_le_bytes());
+        result.push(code as u8);
+        result.push(SubCode::None as u8);
+        result.extend(msg.as_bytes());
+        if len2 > 0 {
+            result.extend(b": ");
+            result.extend(msg2.as_bytes());
+        }
+        Self { state_: Some(result) }
+    }
+
+    fn message_len(&self) -> usize {
+        self.state_.as_ref().map_or(0, |s| u32::from_le_bytes([s[0], s[1], s[2], s[3]]) as usize)
+    }
+
+    fn message(&self) -> &str {
+        match self.state_.as_ref() {
+            Some(s) => std::str::from_utf8(&s[6..(6 + self.message_len())]).expect("message is not UTF-8"),
+            None => "",
         }
     }
 }
@@ -77,36 +209,146 @@ impl Status {
 impl ToString for Status {
     /// Return a string representation of this status suitable for printing.
     /// Returns the string "OK" for success.
+    /// The file involved is named at the end, unless the message already
+    /// names it.
     fn to_string(&self) -> String {
-        todo!()
+        let type_ = match self.code() {
+            Code::Ok => { return "OK".to_string(); },
+            Code::NotFound => "NotFound: ",
+            Code::Corruption => "Corruption: ",
+            Code::NotSupported => "Not implemented: ",
+            Code::InvalidArgument => "Invalid argument: ",
+            Code::IOError => "IO error: ",
+            Code::Busy => "Busy: ",
+            Code::Gap => "Gap: ",
+            Code::TimedOut => "Timed out: ",
+            Code::AlreadyApplied => "Already applied: ",
+            Code::Incomplete => "Incomplete: ",
+        };
+        let message = self.message();
+        match self.filename() {
+            Some(fname) if !message.contains(fname) => format!("{}{} (file {})", type_, message, fname),
+            _ => format!("{}{}", type_, message),
+        }
     }
 }
 
-struct Code(u8);
+/// The kind of error a Status holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    Ok = 0,
+    NotFound = 1,
+    Corruption = 2,
+    NotSupported = 3,
+    InvalidArgument = 4,
+    IOError = 5,
+    Busy = 6,
+    Gap = 7,
+    TimedOut = 8,
+    AlreadyApplied = 9,
+    Incomplete = 10,
+}
+
 impl Code {
-    fn ok() -> Self { Self(0) }
-    fn not_found() -> Self { Self(1) }
-    fn corruption() -> Self { Self(2) }
-    fn not_supported() -> Self { Self(3) }
-    fn invalid_argument() -> Self { Self(4) }
-    fn io_error() -> Self { Self(5) }
-    fn unsupported() -> Self { Self(u8::MAX) }
-
-    fn is_not_found(&self) -> bool { self.0 == 1 }
-    fn is_corruption(&self) -> bool { self.0 == 2 }
-    fn is_io_error(&self) -> bool { self.0 == 5 }
+    fn from(c: u8) -> Self {
+        match c {
+            0 => Self::Ok,
+            1 => Self::NotFound,
+            2 => Self::Corruption,
+            3 => Self::NotSupported,
+            4 => Self::InvalidArgument,
+            5 => Self::IOError,
+            6 => Self::Busy,
+            7 => Self::Gap,
+            8 => Self::TimedOut,
+            9 => Self::AlreadyApplied,
+            10 => Self::Incomplete,
+            _ => panic!("unknown status code {}", c),
+        }
+    }
+}
+
+/// Refines the Code of a Status, for errors callers handle on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubCode {
+    None = 0,
+
+    /// A Busy error: the lock on the file is held, by this process or
+    /// another one.  DB::open() fails with it while another DB has the
+    /// database open.
+    LockHeld = 1,
 
+    /// An InvalidArgument error from DB::open(): there is no database
+    /// and options.create_if_missing is false.
+    NoDatabase = 2,
+
+    /// An IOError: the process has as many files open as it may (EMFILE).
+    TooManyOpenFiles = 3,
+}
+
+impl SubCode {
     fn from(c: u8) -> Self {
         match c {
-            0 => Self::ok(),
-            1 => Self::not_found(),
-            2 => Self::corruption(),
-            3 => Self::not_supported(),
-            4 => Self::invalid_argument(),
-            5 => Self::io_error(),
-            _ => Self::unsupported(),
+            0 => Self::None,
+            1 => Self::LockHeld,
+            2 => Self::NoDatabase,
+            3 => Self::TooManyOpenFiles,
+            _ => panic!("unknown status subcode {}", c),
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, String>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_string_test() {
+        assert_eq!("OK", Status::new_ok().to_string());
+        assert_eq!("NotFound: foo", Status::not_found("foo", "").to_string());
+        assert_eq!("Invalid argument: foo: bar", Status::invalid_argument("foo", "bar").to_string());
+        assert!(Status::invalid_argument("foo", "bar").is_invalid_argument());
+        assert!(!Status::corruption("foo", "bar").is_invalid_argument());
+        assert_eq!("Busy: foo", Status::busy("foo", "").to_string());
+        assert!(Status::busy("foo", "").is_busy());
+        assert_eq!("Gap: expected sequence: 7", Status::gap("expected sequence", "7").to_string());
+        assert!(Status::gap("foo", "").is_gap());
+        assert!(!Status::busy("foo", "").is_gap());
+        assert_eq!("Timed out: foo", Status::timed_out("foo", "").to_string());
+        assert!(Status::timed_out("foo", "").is_timed_out());
+        assert_eq!("Already applied: committed at sequence: 5", Status::already_applied("committed at sequence", "5").to_string());
+        assert!(Status::already_applied("foo", "").is_already_applied());
+        assert!(!Status::timed_out("foo", "").is_already_applied());
+        assert_eq!("Incomplete: foo", Status::incomplete("foo", "").to_string());
+        assert!(Status::incomplete("foo", "").is_incomplete());
+        let annotated = Status::busy("lock", "held").annotate("after 3 attempts");
+        assert_eq!("Busy: after 3 attempts: lock: held", annotated.to_string());
+        assert!(annotated.is_busy());
+        assert!(Status::new_ok().annotate("foo").ok());
+    }
+
+    #[test]
+    fn code_test() {
+        assert_eq!((Code::Ok, SubCode::None, None), (Status::new_ok().code(), Status::new_ok().subcode(), Status::new_ok().filename()));
+        assert!(Status::new_ok().with_subcode(SubCode::LockHeld).with_filename("/db/LOCK").ok());
+        assert_eq!(Code::Gap, Status::gap("foo", "").code());
+        assert_eq!((Code::Corruption, SubCode::None, None), (Status::corruption("foo", "").code(), Status::corruption("foo", "").subcode(), Status::corruption("foo", "").filename()));
+
+        let s = Status::busy("lock /db/LOCK", "already held").with_subcode(SubCode::LockHeld).with_filename("/db/LOCK");
+        assert_eq!((Code::Busy, SubCode::LockHeld, Some("/db/LOCK")), (s.code(), s.subcode(), s.filename()));
+        assert_eq!("Busy: lock /db/LOCK: already held", s.to_string());
+
+        // The file is named where the message does not, and replaced by
+        // a later one.
+        let s = Status::corruption("bad record", "").with_filename("/db/MANIFEST-000001");
+        assert_eq!("Corruption: bad record (file /db/MANIFEST-000001)", s.to_string());
+        let s = s.with_filename("/db/MANIFEST-000002").annotate("recovering");
+        assert_eq!("Corruption: recovering: bad record (file /db/MANIFEST-000002)", s.to_string());
+        assert_eq!((Code::Corruption, SubCode::None), (s.code(), s.subcode()));
+
+        let s = Status::invalid_argument("/db", "does not exist").with_subcode(SubCode::NoDatabase).annotate("open");
+        assert_eq!((Code::InvalidArgument, SubCode::NoDatabase, None), (s.code(), s.subcode(), s.filename()));
+    }
+}
@@ -0,0 +1,248 @@
+//! Named points in the engine where tests can pause it, slow it down,
+//! crash it or make it fail, to reach states that an Env wrapper can not
+//! (e.g. between building a table and recording it in the MANIFEST).
+//!
+//! The points are compiled in only for this crate's unit tests and with
+//! the "failpoints" feature; otherwise sync_point!() expands to nothing.
+//!
+//! A point does nothing until a callback is activated for its name.  The
+//! callback runs every time the point is reached, and may return a Status
+//! that the engine then treats as the result of the step at that point.
+//! By default a callback only fires on the thread that activated it, so
+//! tests running in parallel can use the same points without seeing each
+//! other's callbacks.
+//!
+//! Points in use:
+//!    db:open:after-recover                  recovery done, nothing saved yet
+//!    db:write:before-log                    write group built, not logged
+//!    db:write:after-log                     logged, not in the memtable
+//!    db:make-room:before-switch             memtable full, log not switched
+//!    db:background-compaction:start
+//!    db:flush:after-build                   level-0 table built, not in MANIFEST
+//!    db:remove-obsolete-files:before-delete live files collected, none deleted
+//!    compaction:after-output-sync           an output table synced, not closed
+//!    compaction:before-install              outputs done, not in MANIFEST
+//!    build-table:before-sync                table written, not synced
+//!    build-table:after-sync
+//!    versionset:before-manifest-write       new version built, edit not logged
+//!    versionset:after-manifest-sync         edit logged and synced
+//!    versionset:before-current-rename       new MANIFEST synced, CURRENT not switched
+//!    db:ingest:before-install               files moved or copied in, not in MANIFEST
+//!    checkpoint:after-live-files            memtable flushed, nothing linked
+//!    checkpoint:before-current              files linked and copied, no CURRENT
+//!    multi-db:commit:after-prepare          a participant prepared, per participant
+//!    multi-db:commit:after-commit           a participant committed, per participant
+
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc, Condvar, Mutex}, thread::{self, ThreadId}, time::{Duration, Instant}};
+
+use once_cell::sync::Lazy;
+
+use crate::status::Status;
+
+type Callback = Arc<dyn Fn() -> Option<Status> + Send + Sync>;
+
+struct Activation {
+    id: u64,
+    thread: Option<ThreadId>,   // None: fires on every thread
+    callback: Callback,
+}
+
+static REGISTRY: Lazy<Mutex<HashMap<String, Vec<Activation>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Keeps a callback activated; dropping it deactivates the callback.
+#[must_use = "the callback is deactivated when the guard is dropped"]
+pub struct Guard {
+    name_: String,
+    id_: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(activations) = registry.get_mut(&self.name_) {
+            activations.retain(|a| a.id != self.id_);
+            if activations.is_empty() {
+                registry.remove(&self.name_);
+            }
+        }
+    }
+}
+
+fn register(name: &str, thread: Option<ThreadId>, callback: Callback) -> Guard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry.entry(name.to_string()).or_default().push(Activation { id, thread, callback });
+    Guard { name_: name.to_string(), id_: id }
+}
+
+/// Call "callback" whenever the current thread reaches the point "name".
+pub fn activate<F>(name: &str, callback: F) -> Guard
+where F: Fn() -> Option<Status> + Send + Sync + 'static {
+    register(name, Some(thread::current().id()), Arc::new(callback))
+}
+
+/// Call "callback" whenever any thread reaches the point "name".
+pub fn activate_any_thread<F>(name: &str, callback: F) -> Guard
+where F: Fn() -> Option<Status> + Send + Sync + 'static {
+    register(name, None, Arc::new(callback))
+}
+
+/// Make the step at "name" fail with "status" on the current thread.
+pub fn fail(name: &str, status: Status) -> Guard {
+    activate(name, move || Some(status.clone()))
+}
+
+/// Sleep for "duration" at "name" on the current thread.
+pub fn sleep(name: &str, duration: Duration) -> Guard {
+    activate(name, move || {
+        thread::sleep(duration);
+        None
+    })
+}
+
+/// Panic at "name" on the current thread, e.g. to simulate a crash.
+pub fn panic(name: &str) -> Guard {
+    let message = format!("sync point {} reached", name);
+    activate(name, move || panic!("{}", message))
+}
+
+/// Called by sync_point!(): run the callbacks activated for "name" in the
+/// order they were activated.  Returns the first Status one returned.
+pub fn process(name: &str) -> Option<Status> {
+    let callbacks: Vec<Callback> = {
+        let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        let activations = registry.get(name)?;
+        let current = thread::current().id();
+        activations.iter()
+            .filter(|a| a.thread.is_none_or(|t| t == current))
+            .map(|a| a.callback.clone())
+            .collect()
+    };
+    // Callbacks may block, so they run without the registry locked.
+    callbacks.iter().fold(None, |injected, callback| injected.or(callback()))
+}
+
+#[derive(Default)]
+struct PauseState {
+    reached_: usize,
+    released_: bool,
+}
+
+/// Blocks every thread that reaches a point until release() is called,
+/// so a test can act while the engine is stopped there.  The thread that
+/// waits for the point must not be the one that reaches it.
+pub struct Pause {
+    state_: Arc<(Mutex<PauseState>, Condvar)>,
+    _guard: Guard,
+}
+
+impl Pause {
+    /// Pause any thread that reaches "name".
+    pub fn new(name: &str) -> Self {
+        let state = Arc::new((Mutex::new(PauseState::default()), Condvar::new()));
+        let s = state.clone();
+        let guard = activate_any_thread(name, move || {
+            let (lock, cv) = &*s;
+            let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+            state.reached_ += 1;
+            cv.notify_all();
+            drop(cv.wait_while(state, |state| !state.released_).unwrap_or_else(|e| e.into_inner()));
+            None
+        });
+        Self { state_: state, _guard: guard }
+    }
+
+    /// Wait until a thread has reached the point.  Returns false if none
+    /// did within "timeout".
+    pub fn wait_reached(&self, timeout: Duration) -> bool {
+        let (lock, cv) = &*self.state_;
+        let deadline = Instant::now() + timeout;
+        let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+        while state.reached_ == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = cv.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        true
+    }
+
+    /// Number of times a thread reached the point.
+    pub fn reached(&self) -> usize {
+        self.state_.0.lock().unwrap_or_else(|e| e.into_inner()).reached_
+    }
+
+    /// Let the paused threads go on.  Threads that reach the point from
+    /// now on pass without stopping.
+    pub fn release(&self) {
+        let (lock, cv) = &*self.state_;
+        lock.lock().unwrap_or_else(|e| e.into_inner()).released_ = true;
+        cv.notify_all();
+    }
+}
+
+impl Drop for Pause {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test]
+    fn activate_test() {
+        assert!(process("sync_point:test:activate").is_none());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let guard = activate("sync_point:test:activate", move || {
+            c.fetch_add(1, Ordering::SeqCst);
+            None
+        });
+        let injected = fail("sync_point:test:activate", Status::io_error("injected", ""));
+        assert!(process("sync_point:test:activate").unwrap().is_io_error());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        // Other threads do not see the callbacks
+        thread::spawn(|| assert!(process("sync_point:test:activate").is_none())).join().unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        drop(injected);
+        assert!(process("sync_point:test:activate").is_none());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        drop(guard);
+        assert!(process("sync_point:test:activate").is_none());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panic_test() {
+        let _guard = panic("sync_point:test:panic");
+        assert!(thread::spawn(|| process("sync_point:test:panic")).join().is_ok());
+        assert!(std::panic::catch_unwind(|| process("sync_point:test:panic")).is_err());
+    }
+
+    #[test]
+    fn pause_test() {
+        let pause = Pause::new("sync_point:test:pause");
+        assert!(!pause.wait_reached(Duration::from_millis(10)));
+        let passed = Arc::new(AtomicUsize::new(0));
+        let p = passed.clone();
+        let worker = thread::spawn(move || {
+            process("sync_point:test:pause");
+            p.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(pause.wait_reached(Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(0, passed.load(Ordering::SeqCst));
+        pause.release();
+        worker.join().unwrap();
+        assert_eq!(1, passed.load(Ordering::SeqCst));
+        assert_eq!(1, pause.reached());
+    }
+}
@@ -0,0 +1,845 @@
+use std::sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc};
+
+use crate::{cache::{Cache, UNKNOWN_LEVEL}, comparator::bytewise_comparator, env::RandomAccessFile, iterator::{new_error_iterator, Iterator, ValueLocation}, options::{Options, ReadOptions}, slice::Slice, status::Status, util::coding::put_fixed64};
+
+use self::{block::Block, filter_block::{FilterBlockReader, FILTER_META_PREFIX}, format::{read_block, read_block_with_crc, BlockHandle, Footer}, properties::{TableProperties, COMPARATOR_META_KEY, PROPERTIES_META_KEY}, two_level_iterator::{new_located_two_level_iterator, new_two_level_iterator}};
+
+pub(crate) mod block;
+pub(crate) mod block_builder;
+pub(crate) mod filter_block;
+pub(crate) mod format;
+pub(crate) mod merger;
+pub(crate) mod properties;
+pub(crate) mod table_builder;
+pub(crate) mod two_level_iterator;
+
+/// A copy of the key and value of one table entry.
+pub(crate) type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Counts of the lookups tables make for their filters in
+/// Options::filter_cache, shared by the tables opened with them.
+#[derive(Default)]
+pub(crate) struct FilterCacheCounters {
+    hits_: AtomicU64,
+    misses_: AtomicU64,
+    loads_: AtomicU64,     // Misses that read the filter into the cache
+    changed_: AtomicBool,
+}
+
+impl FilterCacheCounters {
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits_.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses_.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn loads(&self) -> u64 {
+        self.loads_.load(Ordering::Relaxed)
+    }
+
+    /// Return true if a counter changed since the last call.
+    pub(crate) fn take_changed(&self) -> bool {
+        self.changed_.swap(false, Ordering::Relaxed)
+    }
+
+    fn record(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.changed_.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A Table is a sorted map from strings to strings.  Tables are
+/// immutable and persistent.  A Table may be safely accessed from
+/// multiple threads without external synchronization.
+pub(crate) struct Table {
+    options_: Options,
+    file_: Arc<dyn RandomAccessFile>,
+    metaindex_handle_: BlockHandle,  // Handle to metaindex_block: saved from footer
+    index_block_: Arc<Block>,
+
+    // Name of the policy that built the file's filter block, if it has one.
+    // The filter is only used if options_.filter_policy has the same name.
+    filter_name_: Option<String>,
+    filter_: Option<Arc<FilterBlockReader>>,
+
+    // Where the filter is in the file, if it is kept in
+    // options_.filter_cache rather than in filter_, and the prefix of its
+    // key there.
+    filter_handle_: Option<BlockHandle>,
+    filter_cache_id_: u64,
+    filter_counters_: Arc<FilterCacheCounters>,
+
+    // None for tables written before the properties block existed.
+    properties_: Option<TableProperties>,
+
+    // Name of the comparator of the user keys, if the table records it.
+    comparator_name_: Option<String>,
+
+    // Prefix of this table's keys in options_.block_cache.
+    cache_id_: u64,
+}
+
+impl Table {
+    /// Attempt to open the table that is stored in bytes [0..file_size)
+    /// of "file", and read the metadata entries necessary to allow
+    /// retrieving data from the table.
+    ///
+    /// If successful, returns the newly opened table.  If there was an
+    /// error while initializing the table, returns a non-ok status.
+    ///
+    /// "file" must remain live while this Table is in use.
+    pub(crate) fn open(options: &Options, file: Arc<dyn RandomAccessFile>, size: u64) -> Result<Arc<Table>, Status> {
+        Self::open_with_filter_counters(options, file, size, Arc::default())
+    }
+
+    /// Like open(), but count the table's lookups in
+    /// options.filter_cache in "counters".
+    pub(crate) fn open_with_filter_counters(options: &Options, file: Arc<dyn RandomAccessFile>, size: u64,
+                                            counters: Arc<FilterCacheCounters>) -> Result<Arc<Table>, Status> {
+        if size < Footer::ENCODED_LENGTH as u64 {
+            return Err(Status::corruption("file is too short to be an sstable", ""));
+        }
+
+        let footer_input = file.read(size - Footer::ENCODED_LENGTH as u64, Footer::ENCODED_LENGTH)?;
+        let footer = Footer::decode_from(&Slice::new(&footer_input))?;
+
+        // Read the index block
+        let mut opt = ReadOptions::new();
+        if options.paranoid_checks {
+            opt.verify_checksums = true;
+        }
+        let index_block_contents = read_block(file.as_ref(), &opt, footer.index_handle())?;
+
+        // We've successfully read the footer and the index block: we're
+        // ready to serve requests.
+        let mut table = Table {
+            options_: options.clone(),
+            file_: file,
+            metaindex_handle_: *footer.metaindex_handle(),
+            index_block_: Arc::new(Block::new(index_block_contents)),
+            filter_name_: None,
+            filter_: None,
+            filter_handle_: None,
+            filter_cache_id_: 0,
+            filter_counters_: counters,
+            properties_: None,
+            comparator_name_: None,
+            cache_id_: 0,
+        };
+        if let Some(cache) = table.block_cache() {
+            table.cache_id_ = cache.new_id();
+        }
+        if let Some(cache) = &options.filter_cache {
+            table.filter_cache_id_ = cache.new_id();
+        }
+        table.read_meta();
+        Ok(Arc::new(table))
+    }
+
+    /// Name of the filter policy whose filter this table carries, if any.
+    pub(crate) fn filter_name(&self) -> Option<&str> {
+        self.filter_name_.as_deref()
+    }
+
+    /// Returns true iff point lookups can skip blocks using this table's
+    /// filter, i.e. it has one built by the configured filter policy.
+    pub(crate) fn filter_usable(&self) -> bool {
+        self.filter_.is_some() || self.filter_handle_.is_some()
+    }
+
+    /// Statistics gathered while the table was built, if it has them.
+    pub(crate) fn properties(&self) -> Option<&TableProperties> {
+        self.properties_.as_ref()
+    }
+
+    /// Number of data blocks, as listed by the index block.
+    pub(crate) fn num_data_blocks(&self) -> usize {
+        let mut iter = self.index_block_.clone().new_iterator(bytewise_comparator());
+        let mut blocks = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            blocks += 1;
+            iter.next();
+        }
+        blocks
+    }
+
+    /// Name of the comparator that ordered the user keys, for tables
+    /// that record it (see TableBuilder::set_comparator_name).
+    pub(crate) fn comparator_name(&self) -> Option<&str> {
+        self.comparator_name_.as_deref()
+    }
+
+    /// Bytes held in memory while the table is open: its index block and
+    /// filter.  Data blocks are read per lookup, and kept only by the
+    /// block cache, which accounts for them itself, as the filter cache
+    /// does for the filters in it.
+    pub(crate) fn approximate_memory_usage(&self) -> usize {
+        self.index_block_.size() + self.filter_.as_ref().map_or(0, |filter| filter.size())
+    }
+
+    fn read_meta(&mut self) {
+        // Do not propagate errors since meta info is not needed for operation
+        let Ok(contents) = read_block(self.file_.as_ref(), &ReadOptions::new(), &self.metaindex_handle_) else {
+            return;
+        };
+        let mut iter = Arc::new(Block::new(contents)).new_iterator(bytewise_comparator());
+        let mut filter_handle = None;
+        iter.seek(&Slice::new(FILTER_META_PREFIX.as_bytes()));
+        while iter.valid() {
+            let key = iter.key();
+            let Some(name) = key.data().strip_prefix(FILTER_META_PREFIX.as_bytes()) else {
+                break;
+            };
+            let name = String::from_utf8_lossy(name).into_owned();
+            let matches = self.options_.filter_policy.as_ref().is_some_and(|p| p.name() == name);
+            if self.filter_name_.is_none() || matches {
+                self.filter_name_ = Some(name);
+            }
+            if matches {
+                filter_handle = BlockHandle::decode_from(&mut iter.value()).ok();
+                break;
+            }
+            iter.next();
+        }
+
+        if let (Some(policy), Some(handle)) = (&self.options_.filter_policy, filter_handle) {
+            if self.options_.filter_cache.is_some() {
+                // Read by the first lookup
+                self.filter_handle_ = Some(handle);
+            } else if let Ok(block) = read_block(self.file_.as_ref(), &ReadOptions::new(), &handle) {
+                self.filter_ = Some(Arc::new(FilterBlockReader::new(policy.clone(), block)));
+            }
+        }
+
+        iter.seek(&Slice::new(COMPARATOR_META_KEY.as_bytes()));
+        if iter.valid() && iter.key().data() == COMPARATOR_META_KEY.as_bytes() {
+            self.comparator_name_ = iter.value().to_utf8_string();
+        }
+
+        iter.seek(&Slice::new(PROPERTIES_META_KEY.as_bytes()));
+        if iter.valid() && iter.key().data() == PROPERTIES_META_KEY.as_bytes() {
+            if let Ok(handle) = BlockHandle::decode_from(&mut iter.value()) {
+                if let Ok(block) = read_block(self.file_.as_ref(), &ReadOptions::new(), &handle) {
+                    self.properties_ = TableProperties::decode_from(&Slice::new(&block)).ok();
+                }
+            }
+        }
+    }
+
+    /// Returns a new iterator over the table contents.
+    /// The result of new_iterator() is initially invalid (caller must
+    /// call one of the seek methods on the iterator before using it).
+    /// "level" is the level the table is read at, if any, which keys the
+    /// blocks it caches.
+    pub(crate) fn new_iterator(self: &Arc<Self>, options: &ReadOptions, level: Option<i32>) -> Box<dyn Iterator> {
+        let table = self.clone();
+        new_two_level_iterator(
+            self.index_block_.new_iterator(self.options_.comparator.clone()),
+            Box::new(move |options, index_value| table.block_reader(options, level, index_value)),
+            options)
+    }
+
+    /// Like new_iterator(), for the table in file "file_number" of
+    /// "file_size" bytes, whose iterator knows where its values lie; see
+    /// Iterator::value_location().
+    pub(crate) fn new_file_iterator(self: &Arc<Self>, options: &ReadOptions, level: Option<i32>,
+                                    file_number: u64, file_size: u64) -> Box<dyn Iterator> {
+        let table = self.clone();
+        new_located_two_level_iterator(
+            self.index_block_.new_iterator(self.options_.comparator.clone()),
+            Box::new(move |options, index_value| table.block_reader(options, level, index_value)),
+            Box::new(move |index_value, location| {
+                let block = BlockHandle::decode_from(&mut index_value.clone()).ok()?;
+                Some(ValueLocation { file_number, file_size, block: Some(block), ..location })
+            }),
+            options)
+    }
+
+    /// Return a copy of the value at "location", which must be in this
+    /// table, read through the block cache like any other read at "level".
+    pub(crate) fn read_value(&self, options: &ReadOptions, level: Option<i32>, location: &ValueLocation) -> Result<Vec<u8>, Status> {
+        let Some(handle) = &location.block else {
+            return Err(Status::invalid_argument("value location without a block", ""));
+        };
+        let block = self.read_data_block(options, level, handle)?;
+        match block.value_at(location.offset, location.len) {
+            Some(value) => Ok(value.to_vec()),
+            None => Err(Status::corruption("value lies outside its block", "")),
+        }
+    }
+
+    /// Given a key, return an approximate byte offset in the file where
+    /// the data for that key begins (or would begin if the key were
+    /// present in the file).  The returned value is in terms of file
+    /// bytes, and so includes effects like compression of the underlying data.
+    /// E.g., the approximate offset of the last key in the table will
+    /// be close to the file length.
+    pub(crate) fn approximate_offset_of(&self, key: &Slice) -> u64 {
+        let mut index_iter = self.index_block_.new_iterator(self.options_.comparator.clone());
+        index_iter.seek(key);
+        if index_iter.valid() {
+            if let Ok(handle) = BlockHandle::decode_from(&mut index_iter.value()) {
+                return handle.offset();
+            }
+            // Strange: we can't decode the block handle in the index block.
+            // We'll just return the offset of the metaindex block, which is
+            // close to the whole file size for this case.
+        }
+        // key is past the last key in the file.  Approximate the offset
+        // by returning the offset of the metaindex block (which is
+        // right near the end of the file).
+        self.metaindex_handle_.offset()
+    }
+
+    /// Seeks to the first entry at or past "k" and returns a copy of its
+    /// key and value, or None if no such entry exists in the block that
+    /// could hold "k".  If "no_io" is set and the filter can not rule the
+    /// block out, returns an Incomplete status instead of reading it.
+    /// Adds the number of data blocks read to "blocks_read".
+    pub(crate) fn internal_get(&self, options: &ReadOptions, level: Option<i32>, k: &Slice, no_io: bool, blocks_read: &mut u32) -> Result<Option<KeyValue>, Status> {
+        let mut iiter = self.index_block_.new_iterator(self.options_.comparator.clone());
+        iiter.seek(k);
+        let mut result = None;
+        let filtered_out = |handle_value: &Slice| match (self.filter(no_io), BlockHandle::decode_from(&mut handle_value.clone())) {
+            (Some(filter), Ok(handle)) => !filter.key_may_match(handle.offset(), k),
+            _ => false,
+        };
+        if iiter.valid() && !filtered_out(&iiter.value()) {
+            if no_io {
+                return Err(Status::incomplete("block not in memory", ""));
+            }
+            *blocks_read += 1;
+            let mut block_iter = self.block_reader(options, level, &iiter.value());
+            block_iter.seek(k);
+            if block_iter.valid() {
+                result = Some((block_iter.key().data().to_vec(), block_iter.value().data().to_vec()));
+            }
+            let s = block_iter.status();
+            if !s.ok() {
+                return Err(s);
+            }
+        }
+        let s = iiter.status();
+        if s.ok() { Ok(result) } else { Err(s) }
+    }
+
+    /// Convert an index iterator value (i.e., an encoded BlockHandle)
+    /// into an iterator over the contents of the corresponding block.
+    /// Blocks found in the cache are used without reading the file, and
+    /// blocks read are added to it unless options.fill_cache is false.
+    /// Cache keys end with "level", so that a partitioned cache can tell
+    /// which group of levels a block belongs to.
+    fn block_reader(&self, options: &ReadOptions, level: Option<i32>, index_value: &Slice) -> Box<dyn Iterator> {
+        let s = options.check_deadline(self.options_.env.as_ref());
+        if !s.ok() {
+            return new_error_iterator(s);
+        }
+        let mut input = index_value.clone();
+        // We intentionally allow extra stuff in index_value so that we
+        // can add more features in the future.
+        let handle = match BlockHandle::decode_from(&mut input) {
+            Ok(handle) => handle,
+            Err(s) => return new_error_iterator(s),
+        };
+        match self.read_data_block(options, level, &handle) {
+            Ok(block) => block.new_iterator(self.options_.comparator.clone()),
+            Err(s) => new_error_iterator(s),
+        }
+    }
+
+    /// Return the data block at "handle", from the block cache if it is
+    /// there, or else read from the file and, unless options.fill_cache
+    /// is false, added to the cache under "level".
+    fn read_data_block(&self, options: &ReadOptions, level: Option<i32>, handle: &BlockHandle) -> Result<Arc<Block>, Status> {
+        let cache = self.block_cache();
+        let cache_key = cache.map(|_| {
+            let mut key = Vec::with_capacity(17);
+            put_fixed64(&mut key, self.cache_id_);
+            put_fixed64(&mut key, handle.offset());
+            key.push(level.map_or(UNKNOWN_LEVEL, |level| level as u8));
+            key
+        });
+        if let (Some(cache), Some(key)) = (cache, &cache_key) {
+            if let Some(block) = cache.lookup(key).and_then(|value| value.downcast::<Block>().ok()) {
+                return Ok(block);
+            }
+        }
+
+        let (contents, crc) = if self.options_.paranoid_checks && !options.verify_checksums {
+            let options = ReadOptions { verify_checksums: true, ..options.clone() };
+            read_block_with_crc(self.file_.as_ref(), &options, handle)?
+        } else {
+            read_block_with_crc(self.file_.as_ref(), options, handle)?
+        };
+        let block = Arc::new(Block::with_crc(contents, crc));
+        if let (Some(cache), Some(key), true) = (cache, &cache_key, options.fill_cache) {
+            cache.insert(key, block.clone(), block.size());
+        }
+        Ok(block)
+    }
+
+    /// The table's filter: the one it keeps, or the one in
+    /// options_.filter_cache, which is read into the cache if it is not
+    /// there, unless "no_io" is set.  None if the table has no usable
+    /// filter, or it could not be read.
+    fn filter(&self, no_io: bool) -> Option<Arc<FilterBlockReader>> {
+        if let Some(filter) = &self.filter_ {
+            return Some(filter.clone());
+        }
+        let (Some(cache), Some(policy), Some(handle)) = (&self.options_.filter_cache, &self.options_.filter_policy, &self.filter_handle_) else {
+            return None;
+        };
+        let mut key = Vec::with_capacity(14);
+        put_fixed64(&mut key, self.filter_cache_id_);
+        key.extend_from_slice(b"filter");
+        if let Some(filter) = cache.lookup(&key).and_then(|value| value.downcast::<FilterBlockReader>().ok()) {
+            self.filter_counters_.record(&self.filter_counters_.hits_);
+            return Some(filter);
+        }
+        self.filter_counters_.record(&self.filter_counters_.misses_);
+        if no_io {
+            return None;
+        }
+        let block = read_block(self.file_.as_ref(), &ReadOptions::new(), handle).ok()?;
+        self.filter_counters_.record(&self.filter_counters_.loads_);
+        let filter = Arc::new(FilterBlockReader::new(policy.clone(), block));
+        cache.insert(&key, filter.clone(), filter.size());
+        Some(filter)
+    }
+
+    /// The cache data blocks are kept in, if any.
+    fn block_cache(&self) -> Option<&Arc<dyn Cache>> {
+        self.options_.block_cache.as_ref().filter(|_| !self.options_.no_block_cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::new_lru_cache, comparator::bytewise_comparator, env::Env, filter_policy::{new_bloom_filter_policy, FilterPolicy}, helpers::memenv::new_mem_env, options::CompressionType};
+
+    use super::{table_builder::TableBuilder, *};
+
+    fn build_table(env: &Arc<dyn Env>, options: &Options, n: usize) -> Arc<Table> {
+        let file = env.new_writable_file("/table").unwrap();
+        let mut builder = TableBuilder::new(options, file.clone());
+        for i in 0..n {
+            builder.add(&Slice::new(format!("k{:05}", i).as_bytes()), &Slice::new(format!("value{}", i).as_bytes()));
+        }
+        assert!(builder.finish().ok());
+        assert_eq!(n as u64, builder.num_entries());
+        assert!(file.close().ok());
+
+        let size = env.get_file_size("/table").unwrap();
+        assert_eq!(builder.file_size(), size);
+        Table::open(options, env.new_random_access_file("/table").unwrap(), size).unwrap()
+    }
+
+    fn small_block_options(env: &Arc<dyn Env>) -> Options {
+        let mut options = Options::new();
+        options.env = env.clone();
+        options.comparator = bytewise_comparator();
+        options.block_size = 256;
+        options.block_restart_interval = 4;
+        options
+    }
+
+    #[test]
+    fn properties_test() {
+        let env = new_mem_env();
+        let options = small_block_options(&env);
+        let table = build_table(&env, &options, 1000);
+        let properties = table.properties().unwrap();
+        assert_eq!(1000, properties.key_sizes.num());
+        assert_eq!(1000, properties.key_sizes.count(2));    // "k00000" .. "k00999"
+        // "value0" .. "value999"
+        assert_eq!(1000, properties.value_sizes.num());
+        assert_eq!(10 * 6 + 90 * 7 + 900 * 8, properties.value_sizes.sum());
+        assert_eq!(10 + 90, properties.value_sizes.count(2));
+        assert_eq!(900, properties.value_sizes.count(3));
+
+        // Tables without the block still open, and report no properties.
+        let file = env.new_writable_file("/old").unwrap();
+        let mut builder = TableBuilder::new(&options, file.clone());
+        builder.skip_properties();
+        builder.add(&Slice::new(b"k"), &Slice::new(b"v"));
+        assert!(builder.finish().ok());
+        let table = Table::open(&options, env.new_random_access_file("/old").unwrap(), builder.file_size()).unwrap();
+        assert!(table.properties().is_none());
+        assert_eq!(Some((b"k".to_vec(), b"v".to_vec())), table.internal_get(&ReadOptions::new(), None, &Slice::new(b"k"), false, &mut 0).unwrap());
+    }
+
+    #[test]
+    fn empty_table_test() {
+        let env = new_mem_env();
+        let table = build_table(&env, &small_block_options(&env), 0);
+        let mut iter = table.new_iterator(&ReadOptions::new(), None);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(iter.status().ok());
+    }
+
+    #[test]
+    fn iterate_test() {
+        let env = new_mem_env();
+        let table = build_table(&env, &small_block_options(&env), 1000);
+        let mut options = ReadOptions::new();
+        options.verify_checksums = true;
+        let mut iter = table.new_iterator(&options, None);
+
+        iter.seek_to_first();
+        for i in 0..1000 {
+            assert!(iter.valid());
+            assert_eq!(format!("k{:05}", i).as_bytes(), iter.key().data());
+            assert_eq!(format!("value{}", i).as_bytes(), iter.value().data());
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        for i in (0..1000).rev() {
+            assert_eq!(format!("k{:05}", i).as_bytes(), iter.key().data());
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        iter.seek(&Slice::new(b"k00500x"));
+        assert_eq!(b"k00501", iter.key().data());
+        assert!(iter.status().ok());
+    }
+
+    #[test]
+    fn raw_block_test() {
+        let env = new_mem_env();
+        let mut options = small_block_options(&env);
+        options.filter_policy = Some(new_bloom_filter_policy(10));
+        let source = build_table(&env, &options, 1000);
+
+        // Copy every other block whole, and add the entries of the others
+        // one by one.
+        let file = env.new_writable_file("/copy").unwrap();
+        let mut builder = TableBuilder::new(&options, file.clone());
+        let mut iter = source.new_iterator(&ReadOptions::new(), None);
+        let mut copy = false;
+        let mut copied = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            match iter.raw_block() {
+                Some(raw) if copy => {
+                    builder.add_raw_block(&raw);
+                    iter.skip_block();
+                    copied += 1;
+                },
+                raw => {
+                    if raw.is_some() {
+                        copy = true;
+                    }
+                    builder.add(&iter.key(), &iter.value());
+                    iter.next();
+                    continue;
+                },
+            }
+            copy = false;
+        }
+        // Only the first entry of a block starts one
+        assert!(copied > 10 && copied < 500);
+        assert!(builder.finish().ok());
+        assert_eq!(1000, builder.num_entries());
+        assert!(file.close().ok());
+        let table = Table::open(&options, env.new_random_access_file("/copy").unwrap(), builder.file_size()).unwrap();
+
+        let properties = table.properties().unwrap();
+        assert_eq!(1000, properties.key_sizes.num());
+        assert_eq!(10 * 6 + 90 * 7 + 900 * 8, properties.value_sizes.sum());
+        let mut read_options = ReadOptions::new();
+        read_options.verify_checksums = true;
+        let mut iter = table.new_iterator(&read_options, None);
+        iter.seek_to_first();
+        for i in 0..1000 {
+            assert!(iter.valid());
+            assert_eq!(format!("k{:05}", i).as_bytes(), iter.key().data());
+            assert_eq!(format!("value{}", i).as_bytes(), iter.value().data());
+            let key = iter.key().data().to_vec();
+            assert_eq!(Some((key.clone(), iter.value().data().to_vec())), table.internal_get(&read_options, None, &Slice::new(&key), false, &mut 0).unwrap());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        assert!(iter.status().ok());
+    }
+
+    #[test]
+    fn internal_get_test() {
+        let env = new_mem_env();
+        let table = build_table(&env, &small_block_options(&env), 1000);
+        let (key, value) = table.internal_get(&ReadOptions::new(), None, &Slice::new(b"k00042"), false, &mut 0).unwrap().unwrap();
+        assert_eq!(b"k00042".to_vec(), key);
+        assert_eq!(b"value42".to_vec(), value);
+        assert!(table.internal_get(&ReadOptions::new(), None, &Slice::new(b"z"), false, &mut 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn approximate_offset_of_test() {
+        let env = new_mem_env();
+        let table = build_table(&env, &small_block_options(&env), 1000);
+        let size = env.get_file_size("/table").unwrap();
+        assert_eq!(0, table.approximate_offset_of(&Slice::new(b"a")));
+        let middle = table.approximate_offset_of(&Slice::new(b"k00500"));
+        assert!(middle > size / 3 && middle < size * 2 / 3, "{} of {}", middle, size);
+        assert!(table.approximate_offset_of(&Slice::new(b"z")) > size * 9 / 10);
+    }
+
+    #[test]
+    fn block_size_limit_test() {
+        let env = new_mem_env();
+        let mut options = small_block_options(&env);
+        options.block_size = 1 << 20;
+        let value = [b'x'; 400];
+
+        // A full block is cut early to keep an entry within the limit
+        let file = env.new_writable_file("/table").unwrap();
+        let mut builder = TableBuilder::new(&options, file.clone());
+        builder.set_max_block_size(1000);
+        for i in 0..10 {
+            builder.add(&Slice::new(format!("k{}", i).as_bytes()), &Slice::new(&value));
+            assert!(builder.status().ok());
+        }
+        assert!(builder.finish().ok());
+        let size = builder.file_size();
+        let table = Table::open(&options, env.new_random_access_file("/table").unwrap(), size).unwrap();
+        let mut iter = table.new_iterator(&ReadOptions::new(), None);
+        iter.seek_to_first();
+        for i in 0..10 {
+            assert_eq!((format!("k{}", i).as_bytes(), &value[..]), (iter.key().data(), iter.value().data()));
+            iter.next();
+        }
+        assert!(!iter.valid() && iter.status().ok());
+        assert!(table.approximate_offset_of(&Slice::new(b"k5")) > 4 * 400);
+
+        // An entry that does not fit in a block on its own fails the table
+        let mut builder = TableBuilder::new(&options, env.new_writable_file("/table").unwrap());
+        builder.set_max_block_size(1000);
+        builder.add(&Slice::new(b"k0"), &Slice::new(&value));
+        builder.add(&Slice::new(b"k1"), &Slice::new(&[b'x'; 1000]));
+        assert!(builder.status().is_invalid_argument());
+        assert!(builder.status().to_string().contains("1000 value bytes"));
+        builder.add(&Slice::new(b"k2"), &Slice::new(&value));
+        assert_eq!(1, builder.num_entries());
+        assert!(builder.finish().is_invalid_argument());
+
+        // So does an index block that outgrows the limit
+        options.block_size = 1;
+        let mut builder = TableBuilder::new(&options, env.new_writable_file("/table").unwrap());
+        builder.set_max_block_size(100);
+        for i in 0..20 {
+            builder.add(&Slice::new(format!("k{:02}", i).as_bytes()), &Slice::new(b"v"));
+        }
+        let s = builder.finish();
+        assert!(s.is_invalid_argument() && s.to_string().contains("index block too large"), "{}", s.to_string());
+    }
+
+    #[test]
+    fn corruption_test() {
+        let env = new_mem_env();
+        let options = small_block_options(&env);
+        let _ = build_table(&env, &options, 100);
+        let size = env.get_file_size("/table").unwrap();
+
+        // A file shorter than the footer is not a table
+        let s = Table::open(&options, env.new_random_access_file("/table").unwrap(), 10).err().unwrap();
+        assert!(s.is_corruption());
+
+        // Flip a byte in the first data block
+        let mut contents = env.new_random_access_file("/table").unwrap().read(0, size as usize).unwrap();
+        contents[10] ^= 0x80;
+        let file = env.new_writable_file("/table").unwrap();
+        assert!(file.append(&Slice::new(&contents)).ok());
+        let table = Table::open(&options, env.new_random_access_file("/table").unwrap(), size).unwrap();
+        let mut read_options = ReadOptions::new();
+        read_options.verify_checksums = true;
+        let mut iter = table.new_iterator(&read_options, None);
+        iter.seek_to_first();
+        assert!(iter.status().is_corruption());
+
+        // Paranoid checks verify every block, whatever the read asks for
+        let mut iter = table.new_iterator(&ReadOptions::new(), None);
+        iter.seek_to_first();
+        assert!(iter.status().ok());
+        let paranoid = Options { paranoid_checks: true, ..options };
+        let table = Table::open(&paranoid, env.new_random_access_file("/table").unwrap(), size).unwrap();
+        let mut iter = table.new_iterator(&ReadOptions::new(), None);
+        iter.seek_to_first();
+        assert!(iter.status().is_corruption());
+    }
+
+    /// A bloom filter under a name of its own, as if built by an
+    /// unrelated policy.
+    struct RenamedFilterPolicy(Arc<dyn FilterPolicy>);
+
+    impl FilterPolicy for RenamedFilterPolicy {
+        fn name(&self) -> &str {
+            "test.RenamedFilterPolicy"
+        }
+
+        fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>) {
+            self.0.create_filter(keys, dst)
+        }
+
+        fn key_may_match(&self, key: &Slice, filter: &Slice) -> bool {
+            self.0.key_may_match(key, filter)
+        }
+    }
+
+    #[test]
+    fn filter_policy_mismatch_test() {
+        let env = new_mem_env();
+        let policies: [Option<Arc<dyn FilterPolicy>>; 3] = [
+            None,
+            Some(new_bloom_filter_policy(10)),
+            Some(Arc::new(RenamedFilterPolicy(new_bloom_filter_policy(5)))),
+        ];
+        for build_policy in &policies {
+            let mut options = small_block_options(&env);
+            options.filter_policy = build_policy.clone();
+            let _ = build_table(&env, &options, 1000);
+            let size = env.get_file_size("/table").unwrap();
+
+            for read_policy in &policies {
+                options.filter_policy = read_policy.clone();
+                let table = Table::open(&options, env.new_random_access_file("/table").unwrap(), size).unwrap();
+                let build_name = build_policy.as_ref().map(|p| p.name());
+                let usable = build_name.is_some() && build_name == read_policy.as_ref().map(|p| p.name());
+                assert_eq!(build_name, table.filter_name());
+                assert_eq!(usable, table.filter_usable());
+
+                // Filters never hide keys that are present
+                for i in 0..1000 {
+                    let key = format!("k{:05}", i);
+                    let (found, _) = table.internal_get(&ReadOptions::new(), None, &Slice::new(key.as_bytes()), false, &mut 0).unwrap().unwrap();
+                    assert_eq!(key.as_bytes(), found.as_slice());
+                }
+
+                // Without a usable filter, a lookup for a missing key reads
+                // the block and lands on the next key.
+                let skipped = (0..999).filter(|i| {
+                    let key = format!("k{:05}x", i);
+                    table.internal_get(&ReadOptions::new(), None, &Slice::new(key.as_bytes()), false, &mut 0).unwrap().is_none()
+                }).count();
+                if usable {
+                    assert!(skipped > 800, "{}", skipped);
+                } else {
+                    assert_eq!(0, skipped);
+                }
+            }
+        }
+    }
+
+    /// Counts the reads of the file it wraps.
+    struct CountingFile {
+        file_: Arc<dyn RandomAccessFile>,
+        reads_: Arc<AtomicU64>,
+    }
+
+    impl RandomAccessFile for CountingFile {
+        fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+            self.reads_.fetch_add(1, Ordering::Relaxed);
+            self.file_.read(offset, n)
+        }
+    }
+
+    #[test]
+    fn filter_cache_test() {
+        let env = new_mem_env();
+        let mut options = small_block_options(&env);
+        options.filter_policy = Some(new_bloom_filter_policy(10));
+        let _ = build_table(&env, &options, 1000);
+        let size = env.get_file_size("/table").unwrap();
+        let reads = Arc::new(AtomicU64::new(0));
+        let open = |options: &Options, counters: &Arc<FilterCacheCounters>| {
+            let file = Arc::new(CountingFile { file_: env.new_random_access_file("/table").unwrap(), reads_: reads.clone() });
+            Table::open_with_filter_counters(options, file, size, counters.clone()).unwrap()
+        };
+        // Returns the file reads and the data blocks read by lookups of
+        // keys that are not in the table.
+        let lookup_missing = |table: &Table| {
+            let before = reads.load(Ordering::Relaxed);
+            let mut blocks_read = 0;
+            for i in 0..100 {
+                let key = format!("k{:05}x", i);
+                assert!(table.internal_get(&ReadOptions::new(), None, &Slice::new(key.as_bytes()), false, &mut blocks_read).is_ok());
+            }
+            (reads.load(Ordering::Relaxed) - before, blocks_read as u64)
+        };
+
+        // Without a filter cache, tables keep their filters from the start.
+        let counters = Arc::default();
+        let table = open(&options, &counters);
+        let pinned_open_reads = reads.load(Ordering::Relaxed);
+        let filter_size = table.filter_.as_ref().unwrap().size();
+        assert!(table.approximate_memory_usage() > filter_size);
+        let (file_reads, blocks_read) = lookup_missing(&table);
+        assert_eq!(blocks_read, file_reads);
+        assert!(blocks_read < 10, "{}", blocks_read);
+        assert_eq!(0, counters.hits() + counters.misses() + counters.loads());
+
+        // With one that holds the filters of two tables, only the filters
+        // of the last two tables looked up stay in memory.
+        let cache = new_lru_cache(2 * filter_size + filter_size / 2);
+        options.filter_cache = Some(cache.clone());
+        let counters = Arc::default();
+        let reads_before_open = reads.load(Ordering::Relaxed);
+        let tables: Vec<Arc<Table>> = (0..8).map(|_| open(&options, &counters)).collect();
+        let open_reads = reads.load(Ordering::Relaxed) - reads_before_open;
+        assert!(tables.iter().all(|table| table.filter_usable() && table.filter_.is_none()));
+        assert_eq!(table.approximate_memory_usage() - filter_size, tables[0].approximate_memory_usage());
+        assert_eq!(0, cache.total_charge());
+        assert_eq!(8 * (pinned_open_reads - 1), open_reads);   // Not the filter
+
+        for table in &tables {
+            let (file_reads, blocks_read) = lookup_missing(table);
+            assert_eq!(1 + blocks_read, file_reads);
+            assert!(cache.total_charge() <= 2 * filter_size + filter_size / 2);
+        }
+        assert_eq!(8, counters.loads());
+        assert_eq!(8, counters.misses());
+        assert_eq!(8 * 99, counters.hits());
+
+        // The first table's filter was evicted: it is read again once.
+        let (file_reads, blocks_read) = lookup_missing(&tables[0]);
+        assert_eq!(1 + blocks_read, file_reads);
+        assert_eq!(9, counters.loads());
+        assert_eq!((blocks_read, blocks_read), lookup_missing(&tables[0]));
+        assert_eq!(9, counters.loads());
+
+        // A lookup that may do no IO does not read an evicted filter.
+        let key = Slice::new(b"k00000x");
+        assert!(tables[1].internal_get(&ReadOptions::new(), None, &key, true, &mut 0).unwrap_err().is_incomplete());
+        assert_eq!(9, counters.loads());
+
+        // Filters never hide keys that are present
+        for i in 0..1000 {
+            let key = format!("k{:05}", i);
+            let (found, _) = tables[i % 8].internal_get(&ReadOptions::new(), None, &Slice::new(key.as_bytes()), false, &mut 0).unwrap().unwrap();
+            assert_eq!(key.as_bytes(), found.as_slice());
+        }
+    }
+
+    #[test]
+    fn compression_test() {
+        // Without snappy compiled in, snappy tables are stored raw.
+        let env = new_mem_env();
+        let mut options = small_block_options(&env);
+        let mut contents = Vec::new();
+        for compression in [CompressionType::NoCompression, CompressionType::SnappyCompression] {
+            options.compression = compression;
+            let table = build_table(&env, &options, 1000);
+            let mut iter = table.new_iterator(&ReadOptions::new(), None);
+            iter.seek_to_first();
+            assert!(iter.valid() && iter.key().data() == b"k00000");
+            let size = env.get_file_size("/table").unwrap();
+            contents.push(env.new_random_access_file("/table").unwrap().read(0, size as usize).unwrap());
+        }
+        assert_eq!(contents[0], contents[1]);
+    }
+}
@@ -0,0 +1,662 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{comparator::Comparator, iterator::{new_empty_iterator, new_error_iterator, Iterator, ValueLocation}, slice::Slice, status::Status, util::coding::{decode_fixed32, get_varint32_idx}};
+
+/// An immutable, parsed data or index block.  See block_builder.rs for
+/// the format.
+pub(crate) struct Block {
+    data_: Vec<u8>,
+    size_: usize,           // 0 if the contents are malformed
+    restart_offset_: usize, // Offset in data_ of restart array
+
+    // Masked crc from the block's trailer, for data blocks read from a
+    // table; lets the block be copied whole into another table.
+    crc_: Option<u32>,
+}
+
+impl Block {
+    /// Initialize the block with the specified contents.
+    pub(crate) fn new(contents: Vec<u8>) -> Self {
+        let mut block = Self { size_: contents.len(), data_: contents, restart_offset_: 0, crc_: None };
+        if block.size_ < 4 {
+            block.size_ = 0;    // Error marker
+        } else {
+            let max_restarts_allowed = (block.size_ - 4) / 4;
+            if block.num_restarts() as usize > max_restarts_allowed {
+                // The size is too small for num_restarts()
+                block.size_ = 0;
+            } else {
+                block.restart_offset_ = block.size_ - (1 + block.num_restarts() as usize) * 4;
+            }
+        }
+        block
+    }
+
+    /// Like new(), for a data block whose trailer held "crc".
+    pub(crate) fn with_crc(contents: Vec<u8>, crc: u32) -> Self {
+        Self { crc_: Some(crc), ..Self::new(contents) }
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size_
+    }
+
+    /// Return the "len" bytes at "offset" among the entries of the block,
+    /// or None if they are not all there.
+    pub(crate) fn value_at(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        let end = offset.checked_add(len).filter(|&end| end <= self.restart_offset_)?;
+        Some(&self.data_[offset..end])
+    }
+
+    fn num_restarts(&self) -> u32 {
+        debug_assert!(self.size_ >= 4);
+        decode_fixed32(self.data_[self.size_ - 4..self.size_].try_into().unwrap())
+    }
+
+    pub(crate) fn new_iterator(self: &Arc<Self>, comparator: Arc<dyn Comparator>) -> Box<dyn Iterator> {
+        if self.size_ < 4 {
+            return new_error_iterator(Status::corruption("bad block contents", ""));
+        }
+        let num_restarts = self.num_restarts();
+        if num_restarts == 0 {
+            new_empty_iterator()
+        } else {
+            Box::new(BlockIter {
+                comparator_: comparator,
+                block_: self.clone(),
+                restarts_: self.restart_offset_,
+                num_restarts_: num_restarts,
+                current_: self.restart_offset_,
+                restart_index_: num_restarts,
+                key_: Vec::new(),
+                value_offset_: 0,
+                value_len_: 0,
+                status_: Status::new_ok(),
+                prev_entries_: Vec::new(),
+                prev_keys_: Vec::new(),
+            })
+        }
+    }
+}
+
+/// A data block as stored in a table, which a TableBuilder can append
+/// to its own table without decoding and re-encoding the entries; see
+/// Iterator::raw_block().
+pub struct RawBlock {
+    pub(crate) block: Arc<Block>,
+    pub(crate) crc: u32,    // Masked, as stored in the trailer
+}
+
+impl RawBlock {
+    /// The contents of the block, without its trailer.
+    pub(crate) fn contents(&self) -> &[u8] {
+        &self.block.data_
+    }
+
+    /// Return an iterator over the entries of the block.
+    pub(crate) fn new_iterator(&self, comparator: Arc<dyn Comparator>) -> Box<dyn Iterator> {
+        self.block.new_iterator(comparator)
+    }
+
+    /// Return the last key of the block, or None if it has no entries
+    /// or is malformed.
+    pub(crate) fn last_key(&self, comparator: Arc<dyn Comparator>) -> Option<Vec<u8>> {
+        let mut iter = self.new_iterator(comparator);
+        iter.seek_to_last();
+        iter.valid().then(|| iter.key().data().to_vec())
+    }
+}
+
+/// Helper routine: decode the next block entry starting at "offset",
+/// storing the number of shared key bytes, non_shared key bytes,
+/// and the length of the value.  Will not dereference past "limit".
+///
+/// If any errors are detected, returns None.  Otherwise, returns the
+/// lengths and the offset of the key delta.
+fn decode_entry(data: &[u8], offset: usize, limit: usize) -> Option<(usize, usize, usize, usize)> {
+    // "offset" comes from the restart array and may point anywhere
+    if offset > limit || limit - offset < 3 {
+        return None;
+    }
+    let (shared, non_shared, value_length);
+    let mut p = offset;
+    if (data[p] | data[p + 1] | data[p + 2]) < 128 {
+        // Fast path: all three values are encoded in one byte each
+        shared = data[p] as usize;
+        non_shared = data[p + 1] as usize;
+        value_length = data[p + 2] as usize;
+        p += 3;
+    } else {
+        let mut values = [0usize; 3];
+        for value in values.iter_mut() {
+            let (next, v) = get_varint32_idx(&data[..limit], p as isize);
+            if next == -1 {
+                return None;
+            }
+            p = next as usize;
+            *value = v as usize;
+        }
+        [shared, non_shared, value_length] = values;
+    }
+
+    if limit - p < non_shared + value_length {
+        return None;
+    }
+    Some((shared, non_shared, value_length, p))
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of block entries decoded on this thread.
+    static ENTRY_DECODES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// An entry that prev() decoded on its way to the one it stopped at.
+struct PrevEntry {
+    offset: usize,
+    restart_index: u32,
+    key_end: usize,     // In prev_keys_, where the key before it ends
+    value_offset: usize,
+    value_len: usize,
+}
+
+struct BlockIter {
+    comparator_: Arc<dyn Comparator>,
+    block_: Arc<Block>,
+    restarts_: usize,       // Offset of restart array (list of fixed32)
+    num_restarts_: u32,     // Number of uint32_t entries in restart array
+
+    // current_ is offset in data_ of current entry.  >= restarts_ if !valid
+    current_: usize,
+    restart_index_: u32,    // Index of restart block in which current_ falls
+    key_: Vec<u8>,
+    value_offset_: usize,
+    value_len_: usize,
+    status_: Status,
+
+    // The entries before current_ in its restart run, in order, if prev()
+    // moved to it: walking back to an entry means decoding the run from
+    // its restart point, so the entries passed on the way are kept for
+    // the prev() calls that follow.  Cleared by every other move.
+    prev_entries_: Vec<PrevEntry>,
+    prev_keys_: Vec<u8>,    // The keys of prev_entries_, one after another
+}
+
+impl BlockIter {
+    fn compare(&self, a: &Slice, b: &Slice) -> Ordering {
+        self.comparator_.compare(a, b)
+    }
+
+    /// Return the offset in data_ just past the end of the current entry.
+    fn next_entry_offset(&self) -> usize {
+        self.value_offset_ + self.value_len_
+    }
+
+    fn get_restart_point(&self, index: u32) -> usize {
+        debug_assert!(index < self.num_restarts_);
+        let offset = self.restarts_ + index as usize * 4;
+        decode_fixed32(self.block_.data_[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    fn seek_to_restart_point(&mut self, index: u32) {
+        self.clear_prev_entries();
+        self.key_.clear();
+        self.restart_index_ = index;
+        // current_ will be fixed by parse_next_key();
+
+        // parse_next_key() starts at the end of value_, so set value_ accordingly
+        self.value_offset_ = self.get_restart_point(index);
+        self.value_len_ = 0;
+    }
+
+    /// Make the iterator invalid, past the last entry.
+    fn mark_end(&mut self) {
+        self.clear_prev_entries();
+        self.current_ = self.restarts_;
+        self.restart_index_ = self.num_restarts_;
+    }
+
+    fn corruption_error(&mut self) {
+        self.clear_prev_entries();
+        self.current_ = self.restarts_;
+        self.restart_index_ = self.num_restarts_;
+        self.status_ = Status::corruption("bad entry in block", "");
+        self.key_.clear();
+        self.value_offset_ = 0;
+        self.value_len_ = 0;
+    }
+
+    fn clear_prev_entries(&mut self) {
+        self.prev_entries_.clear();
+        self.prev_keys_.clear();
+    }
+
+    /// Remember the current entry for prev().
+    fn push_prev_entry(&mut self) {
+        self.prev_keys_.extend_from_slice(&self.key_);
+        self.prev_entries_.push(PrevEntry {
+            offset: self.current_,
+            restart_index: self.restart_index_,
+            key_end: self.prev_keys_.len(),
+            value_offset: self.value_offset_,
+            value_len: self.value_len_,
+        });
+    }
+
+    /// Move to the last entry prev() remembered, if any.
+    fn pop_prev_entry(&mut self) -> bool {
+        let Some(entry) = self.prev_entries_.pop() else {
+            return false;
+        };
+        let key_start = self.prev_entries_.last().map_or(0, |e| e.key_end);
+        self.key_.clear();
+        self.key_.extend_from_slice(&self.prev_keys_[key_start..entry.key_end]);
+        self.prev_keys_.truncate(key_start);
+        self.current_ = entry.offset;
+        self.restart_index_ = entry.restart_index;
+        self.value_offset_ = entry.value_offset;
+        self.value_len_ = entry.value_len;
+        true
+    }
+
+    fn parse_next_key(&mut self) -> bool {
+        #[cfg(test)]
+        ENTRY_DECODES.with(|n| n.set(n.get() + 1));
+        self.current_ = self.next_entry_offset();
+        let limit = self.restarts_;    // Restarts come right after data
+        if self.current_ > limit {
+            // Only a bad restart point leads past the entries
+            self.corruption_error();
+            return false;
+        }
+        if self.current_ == limit {
+            // No more entries to return.  Mark as invalid.
+            self.mark_end();
+            return false;
+        }
+
+        // Decode next entry
+        match decode_entry(&self.block_.data_, self.current_, limit) {
+            Some((shared, non_shared, value_length, p)) if self.key_.len() >= shared => {
+                self.key_.truncate(shared);
+                self.key_.extend_from_slice(&self.block_.data_[p..p + non_shared]);
+                self.value_offset_ = p + non_shared;
+                self.value_len_ = value_length;
+                while self.restart_index_ + 1 < self.num_restarts_ &&
+                    self.get_restart_point(self.restart_index_ + 1) < self.current_ {
+                    self.restart_index_ += 1;
+                }
+                true
+            },
+            _ => {
+                self.corruption_error();
+                false
+            },
+        }
+    }
+}
+
+impl Iterator for BlockIter {
+    fn valid(&self) -> bool {
+        self.current_ < self.restarts_
+    }
+
+    fn seek_to_first(&mut self) {
+        self.seek_to_restart_point(0);
+        self.parse_next_key();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.seek_to_restart_point(self.num_restarts_ - 1);
+        while self.parse_next_key() && self.next_entry_offset() < self.restarts_ {
+            // Keep skipping
+        }
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.clear_prev_entries();
+        // Binary search in restart array to find the last restart point
+        // with a key < target
+        let mut left = 0;
+        let mut right = self.num_restarts_ - 1;
+        let mut current_key_compare = Ordering::Equal;
+
+        if self.valid() {
+            // If we're already scanning, use the current position as a starting
+            // point. This is beneficial if the key we're seeking to is ahead of the
+            // current position.
+            current_key_compare = self.compare(&Slice::new(&self.key_), target);
+            match current_key_compare {
+                Ordering::Less => { left = self.restart_index_; },  // key_ is smaller than target
+                Ordering::Greater => { right = self.restart_index_; },
+                // We're seeking to the key we're already at.
+                Ordering::Equal => { return; },
+            }
+        }
+
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            let region_offset = self.get_restart_point(mid);
+            match decode_entry(&self.block_.data_, region_offset, self.restarts_) {
+                Some((0, non_shared, _, p)) => {
+                    let mid_key = Slice::new(&self.block_.data_[p..p + non_shared]);
+                    if self.compare(&mid_key, target) == Ordering::Less {
+                        // Key at "mid" is smaller than "target".  Therefore all
+                        // blocks before "mid" are uninteresting.
+                        left = mid;
+                    } else {
+                        // Key at "mid" is >= "target".  Therefore all blocks at or
+                        // after "mid" are uninteresting.
+                        right = mid - 1;
+                    }
+                },
+                _ => {
+                    self.corruption_error();
+                    return;
+                },
+            }
+        }
+
+        // We might be able to use our current position within the restart block.
+        // This is true if we determined the key we desire is in the current block
+        // and is after than the current key.
+        debug_assert!(current_key_compare == Ordering::Equal || self.valid());
+        let skip_seek = left == self.restart_index_ && current_key_compare == Ordering::Less;
+        if !skip_seek {
+            self.seek_to_restart_point(left);
+        }
+        // Linear search (within restart block) for first key >= target
+        loop {
+            if !self.parse_next_key() {
+                return;
+            }
+            if self.compare(&Slice::new(&self.key_), target) != Ordering::Less {
+                return;
+            }
+        }
+    }
+
+    fn next(&mut self) {
+        debug_assert!(self.valid());
+        self.clear_prev_entries();
+        self.parse_next_key();
+    }
+
+    fn prev(&mut self) {
+        debug_assert!(self.valid());
+        if self.pop_prev_entry() {
+            return;
+        }
+
+        // Scan backwards to a restart point before current_
+        let original = self.current_;
+        while self.get_restart_point(self.restart_index_) >= original {
+            if self.restart_index_ == 0 {
+                // No more entries
+                self.current_ = self.restarts_;
+                self.restart_index_ = self.num_restarts_;
+                return;
+            }
+            self.restart_index_ -= 1;
+        }
+
+        self.seek_to_restart_point(self.restart_index_);
+        // Loop until end of current entry hits the start of original entry
+        while self.parse_next_key() && self.next_entry_offset() < original {
+            self.push_prev_entry();
+        }
+    }
+
+    fn key(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        Slice::new(&self.key_)
+    }
+
+    fn value(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        Slice::new(&self.block_.data_[self.value_offset_..self.value_offset_ + self.value_len_])
+    }
+
+    fn status(&self) -> Status {
+        self.status_.clone()
+    }
+
+    fn raw_block(&self) -> Option<RawBlock> {
+        // At the first entry of a data block read from a table
+        let crc = self.block_.crc_?;
+        if !self.valid() || self.current_ != self.get_restart_point(0) {
+            return None;
+        }
+        Some(RawBlock { block: self.block_.clone(), crc })
+    }
+
+    fn skip_block(&mut self) {
+        self.mark_end();
+    }
+
+    fn value_location(&self) -> Option<ValueLocation> {
+        debug_assert!(self.valid());
+        Some(ValueLocation { file_number: 0, file_size: 0, block: None, offset: self.value_offset_, len: self.value_len_ })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{comparator::bytewise_comparator, table::block_builder::BlockBuilder, util::random::Random};
+
+    use super::*;
+
+    fn build(keys: &[String], restart_interval: usize) -> Arc<Block> {
+        let mut builder = BlockBuilder::new(bytewise_comparator(), restart_interval);
+        for k in keys {
+            builder.add(&Slice::new(k.as_bytes()), &Slice::new(format!("v_{}", k).as_bytes()));
+        }
+        Arc::new(Block::new(builder.finish().data().to_vec()))
+    }
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("key{:04}", i * 2)).collect()
+    }
+
+    #[test]
+    fn empty_block_test() {
+        let block = build(&[], 16);
+        let mut iter = block.new_iterator(bytewise_comparator());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek(&Slice::new(b"foo"));
+        assert!(!iter.valid());
+        assert!(iter.status().ok());
+    }
+
+    #[test]
+    fn iterate_test() {
+        for restart_interval in [1, 2, 16] {
+            let keys = keys(100);
+            let block = build(&keys, restart_interval);
+            let mut iter = block.new_iterator(bytewise_comparator());
+
+            iter.seek_to_first();
+            for k in &keys {
+                assert!(iter.valid());
+                assert_eq!(k.as_bytes(), iter.key().data());
+                assert_eq!(format!("v_{}", k).as_bytes(), iter.value().data());
+                iter.next();
+            }
+            assert!(!iter.valid());
+
+            iter.seek_to_last();
+            for k in keys.iter().rev() {
+                assert_eq!(k.as_bytes(), iter.key().data());
+                iter.prev();
+            }
+            assert!(!iter.valid());
+        }
+    }
+
+    #[test]
+    fn random_walk_test() {
+        // Any mix of moves lands where it would over a plain list of the
+        // keys, however prev() got to the entries it remembers.
+        let mut rnd = Random::new(301);
+        for restart_interval in [1, 3, 16] {
+            let keys = keys(200);
+            let block = build(&keys, restart_interval);
+            let mut iter = block.new_iterator(bytewise_comparator());
+            let mut pos: Option<usize> = None;
+            for _ in 0..5000 {
+                match rnd.uniform(10) {
+                    0 => {
+                        iter.seek_to_first();
+                        pos = Some(0);
+                    },
+                    1 => {
+                        iter.seek_to_last();
+                        pos = Some(keys.len() - 1);
+                    },
+                    2 => {
+                        let i = rnd.uniform(keys.len() as i32 * 2 + 2) as usize;
+                        let target = format!("key{:04}", i);
+                        iter.seek(&Slice::new(target.as_bytes()));
+                        pos = Some(i.div_ceil(2)).filter(|&p| p < keys.len());
+                    },
+                    3..=5 if pos.is_some() => {
+                        iter.next();
+                        pos = pos.map(|p| p + 1).filter(|&p| p < keys.len());
+                    },
+                    _ if pos.is_some() => {
+                        iter.prev();
+                        pos = pos.and_then(|p| p.checked_sub(1));
+                    },
+                    _ => {},
+                }
+                assert_eq!(pos.is_some(), iter.valid());
+                if let Some(p) = pos {
+                    assert_eq!(keys[p].as_bytes(), iter.key().data());
+                    assert_eq!(format!("v_{}", keys[p]).as_bytes(), iter.value().data());
+                }
+            }
+            assert!(iter.status().ok());
+        }
+    }
+
+    #[test]
+    fn reverse_scan_decodes_test() {
+        // Walking back through a restart run decodes it once, not once
+        // per entry.
+        let decodes = || ENTRY_DECODES.with(|n| n.get());
+        let keys = keys(160);
+        let block = build(&keys, 16);
+        let mut iter = block.new_iterator(bytewise_comparator());
+        let start = decodes();
+        iter.seek_to_first();
+        while iter.valid() {
+            iter.next();
+        }
+        let forward = decodes() - start;
+        assert_eq!(161, forward);
+
+        let start = decodes();
+        iter.seek_to_last();
+        let mut n = 0;
+        while iter.valid() {
+            n += 1;
+            iter.prev();
+        }
+        assert_eq!(160, n);
+        // seek_to_last() decodes the last run, and the walk back every
+        // run once more, the last one short of its last entry.  Decoding
+        // each entry's run up to it would take about 8 times as many.
+        assert_eq!(16 + 160 - 1, decodes() - start);
+
+        // Turning around forgets the remembered entries.
+        iter.seek(&Slice::new(b"key0100"));
+        iter.prev();
+        iter.next();
+        iter.prev();
+        assert_eq!(b"key0098", iter.key().data());
+    }
+
+    #[test]
+    fn seek_test() {
+        let keys = keys(100);
+        let block = build(&keys, 4);
+        let mut iter = block.new_iterator(bytewise_comparator());
+        iter.seek(&Slice::new(b"key0050"));
+        assert_eq!(b"key0050", iter.key().data());
+        iter.seek(&Slice::new(b"key0051"));
+        assert_eq!(b"key0052", iter.key().data());
+        // Seeking backwards from a valid position
+        iter.seek(&Slice::new(b"key0003"));
+        assert_eq!(b"key0004", iter.key().data());
+        iter.seek(&Slice::new(b"a"));
+        assert_eq!(b"key0000", iter.key().data());
+        iter.seek(&Slice::new(b"z"));
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn corrupted_block_test() {
+        let block = Arc::new(Block::new(vec![1, 2]));
+        assert_eq!(0, block.size());
+        let iter = block.new_iterator(bytewise_comparator());
+        assert!(iter.status().is_corruption());
+
+        // Restart array claims more entries than fit in the block
+        let block = Arc::new(Block::new(vec![0, 0, 0, 0, 9, 0, 0, 0]));
+        assert!(block.new_iterator(bytewise_comparator()).status().is_corruption());
+    }
+
+    #[test]
+    fn restart_offsets_past_end_test() {
+        let keys = keys(50);
+        let contents = build(&keys, 4).data_.clone();
+        let num_restarts = decode_fixed32(contents[contents.len() - 4..].try_into().unwrap()) as usize;
+        let restarts = contents.len() - (1 + num_restarts) * 4;
+
+        // Every restart point past the entries
+        let mut bad = contents.clone();
+        for i in 0..num_restarts {
+            bad[restarts + i * 4..restarts + i * 4 + 4].copy_from_slice(&(restarts as u32 + 1 + i as u32).to_le_bytes());
+        }
+        let block = Arc::new(Block::new(bad));
+        let mut iter = block.new_iterator(bytewise_comparator());
+        iter.seek_to_first();
+        assert!(!iter.valid() && iter.status().is_corruption());
+        let mut iter = block.new_iterator(bytewise_comparator());
+        iter.seek(&Slice::new(b"key0050"));
+        assert!(!iter.valid() && iter.status().is_corruption());
+
+        // Random restart offsets, anywhere up to u32::MAX, must never
+        // make the iterator read out of bounds or loop forever.
+        let mut rnd = Random::new(301);
+        for _ in 0..2000 {
+            let mut bad = contents.clone();
+            for _ in 0..1 + rnd.uniform(3) {
+                let i = rnd.uniform(num_restarts as i32) as usize;
+                let offset = match rnd.uniform(3) {
+                    0 => rnd.uniform(restarts as i32 + 8),
+                    1 => u32::MAX - rnd.uniform(8),
+                    _ => rnd.next(),
+                };
+                bad[restarts + i * 4..restarts + i * 4 + 4].copy_from_slice(&offset.to_le_bytes());
+            }
+            let block = Arc::new(Block::new(bad));
+            let mut iter = block.new_iterator(bytewise_comparator());
+            iter.seek_to_first();
+            for _ in 0..=keys.len() {
+                if !iter.valid() { break; }
+                iter.next();
+            }
+            iter.seek_to_last();
+            for _ in 0..=keys.len() {
+                if !iter.valid() { break; }
+                iter.prev();
+            }
+            iter.seek(&Slice::new(keys[rnd.uniform(keys.len() as i32) as usize].as_bytes()));
+            iter.seek(&Slice::new(b"key0001"));
+        }
+    }
+}
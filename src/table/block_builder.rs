@@ -0,0 +1,153 @@
+//! BlockBuilder generates blocks where keys are prefix-compressed:
+//!
+//! When we store a key, we drop the prefix shared with the previous
+//! string.  This helps reduce the space requirement significantly.
+//! Furthermore, once every K keys, we do not apply the prefix
+//! compression and store the entire key.  We call this a "restart
+//! point".  The tail end of the block stores the offsets of all of the
+//! restart points, and can be used to do a binary search when looking
+//! for a particular key.  Values are stored as-is (without compression)
+//! immediately following the corresponding key.
+//!
+//! An entry for a particular key-value pair has the form:
+//!     shared_bytes: varint32
+//!     unshared_bytes: varint32
+//!     value_length: varint32
+//!     key_delta: char[unshared_bytes]
+//!     value: char[value_length]
+//! shared_bytes == 0 for restart points.
+//!
+//! The trailer of the block has the form:
+//!     restarts: uint32[num_restarts]
+//!     num_restarts: uint32
+//! restarts[i] contains the offset within the block of the ith restart point.
+
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{comparator::Comparator, slice::Slice, util::coding::{put_fixed32, put_varint32}};
+
+use super::format::BLOCK_TRAILER_SIZE;
+
+/// Largest block a BlockBuilder produces.  Restart offsets and entry
+/// lengths are 32-bit, and a block plus its trailer must also be
+/// addressable on 32-bit targets.
+pub(crate) const MAX_BLOCK_SIZE: usize = u32::MAX as usize - BLOCK_TRAILER_SIZE;
+
+pub(crate) struct BlockBuilder {
+    comparator_: Arc<dyn Comparator>,
+    block_restart_interval_: usize,
+    max_size_: usize,       // Limit on the finished block, see fits()
+    buffer_: Vec<u8>,       // Destination buffer
+    restarts_: Vec<u32>,    // Restart points
+    counter_: usize,        // Number of entries emitted since restart
+    finished_: bool,        // Has finish() been called?
+    last_key_: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub(crate) fn new(comparator: Arc<dyn Comparator>, block_restart_interval: usize) -> Self {
+        debug_assert!(block_restart_interval >= 1);
+        Self {
+            comparator_: comparator,
+            block_restart_interval_: block_restart_interval,
+            max_size_: MAX_BLOCK_SIZE,
+            buffer_: Vec::new(),
+            restarts_: vec![0],     // First restart point is at offset 0
+            counter_: 0,
+            finished_: false,
+            last_key_: Vec::new(),
+        }
+    }
+
+    /// Lower the size limit, so tests can reach it with small entries.
+    #[cfg(test)]
+    pub(crate) fn set_max_size(&mut self, max_size: usize) {
+        self.max_size_ = max_size.min(MAX_BLOCK_SIZE);
+    }
+
+    /// Reset the contents as if the BlockBuilder was just constructed.
+    pub(crate) fn reset(&mut self) {
+        self.buffer_.clear();
+        self.restarts_.clear();
+        self.restarts_.push(0);     // First restart point is at offset 0
+        self.counter_ = 0;
+        self.finished_ = false;
+        self.last_key_.clear();
+    }
+
+    /// Returns an estimate of the current (uncompressed) size of the block
+    /// we are building.
+    pub(crate) fn current_size_estimate(&self) -> usize {
+        self.buffer_.len() +                    // Raw data buffer
+            self.restarts_.len() * 4 +          // Restart array
+            4                                   // Restart array length
+    }
+
+    /// Returns true iff adding "key","value" keeps the finished block
+    /// within MAX_BLOCK_SIZE.  Computed without overflow however large
+    /// the entry.
+    pub(crate) fn fits(&self, key: &Slice, value: &Slice) -> bool {
+        // At most three varint32 lengths and one more restart point
+        const MAX_ENTRY_OVERHEAD: usize = 3 * 5 + 4;
+        self.current_size_estimate()
+            .checked_add(MAX_ENTRY_OVERHEAD)
+            .and_then(|n| n.checked_add(key.size()))
+            .and_then(|n| n.checked_add(value.size()))
+            .is_some_and(|n| n <= self.max_size_)
+    }
+
+    /// Finish building the block and return a slice that refers to the
+    /// block contents.  The returned slice will remain valid for the
+    /// lifetime of this builder or until reset() is called.
+    pub(crate) fn finish(&mut self) -> Slice<'_> {
+        // Append restart array
+        for &restart in &self.restarts_ {
+            put_fixed32(&mut self.buffer_, restart);
+        }
+        put_fixed32(&mut self.buffer_, self.restarts_.len() as u32);
+        self.finished_ = true;
+        Slice::new(&self.buffer_)
+    }
+
+    /// REQUIRES: finish() has not been called since the last call to reset().
+    /// REQUIRES: key is larger than any previously added key
+    /// REQUIRES: fits(key, value)
+    pub(crate) fn add(&mut self, key: &Slice, value: &Slice) {
+        debug_assert!(!self.finished_);
+        assert!(self.fits(key, value), "block entry exceeds the block size limit");
+        debug_assert!(self.counter_ <= self.block_restart_interval_);
+        debug_assert!(self.buffer_.is_empty() ||    // No values yet?
+            self.comparator_.compare(key, &Slice::new(&self.last_key_)) == Ordering::Greater);
+        let key = key.data();
+        let mut shared = 0;
+        if self.counter_ < self.block_restart_interval_ {
+            // See how much sharing to do with previous string
+            shared = self.last_key_.iter().zip(key).take_while(|(a, b)| a == b).count();
+        } else {
+            // Restart compression
+            self.restarts_.push(self.buffer_.len() as u32);
+            self.counter_ = 0;
+        }
+        let non_shared = key.len() - shared;
+
+        // Add "<shared><non_shared><value_size>" to buffer_
+        put_varint32(&mut self.buffer_, shared as u32);
+        put_varint32(&mut self.buffer_, non_shared as u32);
+        put_varint32(&mut self.buffer_, value.size() as u32);
+
+        // Add string delta to buffer_ followed by value
+        self.buffer_.extend_from_slice(&key[shared..]);
+        self.buffer_.extend_from_slice(value.data());
+
+        // Update state
+        self.last_key_.truncate(shared);
+        self.last_key_.extend_from_slice(&key[shared..]);
+        debug_assert!(self.last_key_ == key);
+        self.counter_ += 1;
+    }
+
+    /// Return true iff no entries have been added since the last reset()
+    pub(crate) fn empty(&self) -> bool {
+        self.buffer_.is_empty()
+    }
+}
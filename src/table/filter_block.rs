@@ -0,0 +1,255 @@
+//! A filter block is stored near the end of a Table file.  It contains
+//! filters (e.g., bloom filters) for all data blocks in the table combined
+//! into a single filter block.
+
+use std::sync::Arc;
+
+use crate::{filter_policy::FilterPolicy, slice::Slice, util::coding::{decode_fixed32, put_fixed32}};
+
+/// Prefix of the metaindex entry that locates a table's filter block;
+/// the rest of the key is the name of the policy that built it.
+pub(crate) const FILTER_META_PREFIX: &str = "filter.";
+
+// Generate new filter every 2KB of data
+const FILTER_BASE_LG: u8 = 11;
+const FILTER_BASE: u64 = 1 << FILTER_BASE_LG;
+
+/// A FilterBlockBuilder is used to construct all of the filters for a
+/// particular Table.  It generates a single string which is stored as
+/// a special block in the Table.
+///
+/// The sequence of calls to FilterBlockBuilder must match the regexp:
+///      (start_block add_key*)* finish
+pub(crate) struct FilterBlockBuilder {
+    policy_: Arc<dyn FilterPolicy>,
+    keys_: Vec<u8>,             // Flattened key contents
+    start_: Vec<usize>,         // Starting index in keys_ of each key
+    result_: Vec<u8>,           // Filter data computed so far
+    filter_offsets_: Vec<u32>,
+}
+
+impl FilterBlockBuilder {
+    pub(crate) fn new(policy: Arc<dyn FilterPolicy>) -> Self {
+        Self { policy_: policy, keys_: Vec::new(), start_: Vec::new(), result_: Vec::new(), filter_offsets_: Vec::new() }
+    }
+
+    pub(crate) fn start_block(&mut self, block_offset: u64) {
+        let filter_index = (block_offset / FILTER_BASE) as usize;
+        debug_assert!(filter_index >= self.filter_offsets_.len());
+        while filter_index > self.filter_offsets_.len() {
+            self.generate_filter();
+        }
+    }
+
+    pub(crate) fn add_key(&mut self, key: &Slice) {
+        self.start_.push(self.keys_.len());
+        self.keys_.extend_from_slice(key.data());
+    }
+
+    pub(crate) fn finish(&mut self) -> Slice<'_> {
+        if !self.start_.is_empty() {
+            self.generate_filter();
+        }
+
+        // Append array of per-filter offsets
+        let array_offset = self.result_.len() as u32;
+        for &offset in &self.filter_offsets_ {
+            put_fixed32(&mut self.result_, offset);
+        }
+
+        put_fixed32(&mut self.result_, array_offset);
+        self.result_.push(FILTER_BASE_LG);  // Save encoding parameter in result
+        Slice::new(&self.result_)
+    }
+
+    fn generate_filter(&mut self) {
+        let num_keys = self.start_.len();
+        if num_keys == 0 {
+            // Fast path if there are no keys for this filter
+            self.filter_offsets_.push(self.result_.len() as u32);
+            return;
+        }
+
+        // Make list of keys from flattened key structure
+        self.start_.push(self.keys_.len());    // Simplify length computation
+        let tmp_keys: Vec<Slice> = self.start_.windows(2).map(|w| Slice::new(&self.keys_[w[0]..w[1]])).collect();
+
+        // Generate filter for current set of keys and append to result_.
+        self.filter_offsets_.push(self.result_.len() as u32);
+        self.policy_.create_filter(&tmp_keys, &mut self.result_);
+
+        self.keys_.clear();
+        self.start_.clear();
+    }
+}
+
+pub(crate) struct FilterBlockReader {
+    policy_: Arc<dyn FilterPolicy>,
+    data_: Vec<u8>,     // Filter data, followed by the offset array
+    offset_: usize,     // Beginning of offset array (at block-end)
+    num_: usize,        // Number of entries in offset array
+    base_lg_: u8,       // Encoding parameter (see FILTER_BASE_LG)
+}
+
+impl FilterBlockReader {
+    /// Parse "contents", a block produced by FilterBlockBuilder::finish().
+    /// Malformed contents yield a reader that matches every key.
+    pub(crate) fn new(policy: Arc<dyn FilterPolicy>, contents: Vec<u8>) -> Self {
+        let mut reader = Self { policy_: policy, data_: Vec::new(), offset_: 0, num_: 0, base_lg_: 0 };
+        let n = contents.len();
+        if n < 5 {
+            return reader;  // 1 byte for base_lg_ and 4 for start of offset array
+        }
+        reader.base_lg_ = contents[n - 1];
+        let last_word = decode_fixed32(contents[n - 5..n - 1].try_into().unwrap()) as usize;
+        if last_word > n - 5 {
+            return reader;
+        }
+        reader.offset_ = last_word;
+        reader.num_ = (n - 5 - last_word) / 4;
+        reader.data_ = contents;
+        reader
+    }
+
+    /// Bytes of filter data held in memory.
+    pub(crate) fn size(&self) -> usize {
+        self.data_.len()
+    }
+
+    pub(crate) fn key_may_match(&self, block_offset: u64, key: &Slice) -> bool {
+        let index = block_offset.checked_shr(self.base_lg_ as u32).unwrap_or(0) as usize;
+        if index < self.num_ {
+            let pos = self.offset_ + index * 4;
+            let start = decode_fixed32(self.data_[pos..pos + 4].try_into().unwrap()) as usize;
+            let limit = decode_fixed32(self.data_[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            if start <= limit && limit <= self.offset_ {
+                let filter = Slice::new(&self.data_[start..limit]);
+                return self.policy_.key_may_match(key, &filter);
+            } else if start == limit {
+                // Empty filters do not match any keys
+                return false;
+            }
+        }
+        true    // Errors are treated as potential matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::util::{coding::encode_fixed32, hash::leveldb_hash};
+
+    use super::*;
+
+    /// For testing: emit an array with one hash value per key
+    struct TestHashFilter;
+
+    impl FilterPolicy for TestHashFilter {
+        fn name(&self) -> &str {
+            "TestHashFilter"
+        }
+
+        fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>) {
+            for key in keys {
+                put_fixed32(dst, leveldb_hash(key.data(), 1));
+            }
+        }
+
+        fn key_may_match(&self, key: &Slice, filter: &Slice) -> bool {
+            let h = encode_fixed32(leveldb_hash(key.data(), 1));
+            filter.data().chunks_exact(4).any(|chunk| chunk == h)
+        }
+    }
+
+    fn policy() -> Arc<dyn FilterPolicy> {
+        Arc::new(TestHashFilter)
+    }
+
+    fn may_match(reader: &FilterBlockReader, block_offset: u64, key: &str) -> bool {
+        reader.key_may_match(block_offset, &Slice::new(key.as_bytes()))
+    }
+
+    #[test]
+    fn empty_builder_test() {
+        let mut builder = FilterBlockBuilder::new(policy());
+        let block = builder.finish().data().to_vec();
+        assert_eq!(vec![0, 0, 0, 0, 11], block);
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(may_match(&reader, 0, "foo"));
+        assert!(may_match(&reader, 100000, "foo"));
+    }
+
+    #[test]
+    fn single_chunk_test() {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(100);
+        builder.add_key(&Slice::new(b"foo"));
+        builder.add_key(&Slice::new(b"bar"));
+        builder.add_key(&Slice::new(b"box"));
+        builder.start_block(200);
+        builder.add_key(&Slice::new(b"box"));
+        builder.start_block(300);
+        builder.add_key(&Slice::new(b"hello"));
+        let block = builder.finish().data().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(may_match(&reader, 100, "foo"));
+        assert!(may_match(&reader, 100, "bar"));
+        assert!(may_match(&reader, 100, "box"));
+        assert!(may_match(&reader, 100, "hello"));
+        assert!(may_match(&reader, 100, "foo"));
+        assert!(!may_match(&reader, 100, "missing"));
+        assert!(!may_match(&reader, 100, "other"));
+    }
+
+    #[test]
+    fn multi_chunk_test() {
+        let builder = RefCell::new(FilterBlockBuilder::new(policy()));
+        let add = |offset: u64, keys: &[&str]| {
+            let mut builder = builder.borrow_mut();
+            builder.start_block(offset);
+            for key in keys {
+                builder.add_key(&Slice::new(key.as_bytes()));
+            }
+        };
+
+        // First filter
+        add(0, &["foo"]);
+        add(2000, &["bar"]);
+
+        // Second filter
+        add(3100, &["box"]);
+
+        // Third filter is empty
+
+        // Last filter
+        add(9000, &["box", "hello"]);
+
+        let block = builder.borrow_mut().finish().data().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+
+        // Check first filter
+        assert!(may_match(&reader, 0, "foo"));
+        assert!(may_match(&reader, 2000, "bar"));
+        assert!(!may_match(&reader, 0, "box"));
+        assert!(!may_match(&reader, 0, "hello"));
+
+        // Check second filter
+        assert!(may_match(&reader, 3100, "box"));
+        assert!(!may_match(&reader, 3100, "foo"));
+        assert!(!may_match(&reader, 3100, "bar"));
+        assert!(!may_match(&reader, 3100, "hello"));
+
+        // Check third filter (empty)
+        assert!(!may_match(&reader, 4100, "foo"));
+        assert!(!may_match(&reader, 4100, "bar"));
+        assert!(!may_match(&reader, 4100, "box"));
+        assert!(!may_match(&reader, 4100, "hello"));
+
+        // Check last filter
+        assert!(may_match(&reader, 9000, "box"));
+        assert!(may_match(&reader, 9000, "hello"));
+        assert!(!may_match(&reader, 9000, "foo"));
+        assert!(!may_match(&reader, 9000, "bar"));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{env::RandomAccessFile, options::ReadOptions, slice::Slice, status::Status, util::{coding::{decode_fixed32, get_varint64, put_fixed32, put_varint64}, crc32c}};
+
+use super::block_builder::MAX_BLOCK_SIZE;
+
+/// kTableMagicNumber was picked by running
+///    echo http://code.google.com/p/leveldb/ | sha1sum
+/// and taking the leading 64 bits.
+pub(crate) const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+
+/// 1-byte type + 32-bit crc
+pub(crate) const BLOCK_TRAILER_SIZE: usize = 5;
+
+/// Block types stored in the trailer of every block.  DO NOT CHANGE
+/// THESE VALUES: they are embedded in the on-disk data structures.
+pub(crate) const NO_COMPRESSION: u8 = 0x0;
+pub(crate) const SNAPPY_COMPRESSION: u8 = 0x1;
+
+/// BlockHandle is a pointer to the extent of a file that stores a data
+/// block or a meta block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BlockHandle {
+    offset_: u64,
+    size_: u64,
+}
+
+impl BlockHandle {
+    /// Maximum encoding length of a BlockHandle
+    pub(crate) const MAX_ENCODED_LENGTH: usize = 10 + 10;
+
+    pub(crate) fn new() -> Self {
+        Self { offset_: !0u64, size_: !0u64 }
+    }
+
+    /// The offset of the block in the file.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset_
+    }
+    pub(crate) fn set_offset(&mut self, offset: u64) {
+        self.offset_ = offset;
+    }
+
+    /// The size of the stored block
+    pub(crate) fn size(&self) -> u64 {
+        self.size_
+    }
+    pub(crate) fn set_size(&mut self, size: u64) {
+        self.size_ = size;
+    }
+
+    pub(crate) fn encode_to(&self, dst: &mut Vec<u8>) {
+        // Sanity check that all fields have been set
+        debug_assert!(self.offset_ != !0u64);
+        debug_assert!(self.size_ != !0u64);
+        put_varint64(dst, self.offset_);
+        put_varint64(dst, self.size_);
+    }
+
+    pub(crate) fn decode_from(input: &mut Slice) -> Result<Self, Status> {
+        match (get_varint64(input), get_varint64(input)) {
+            (Some(offset_), Some(size_)) => Ok(Self { offset_, size_ }),
+            _ => Err(Status::corruption("bad block handle", "")),
+        }
+    }
+}
+
+/// Footer encapsulates the fixed information stored at the tail
+/// end of every table file.
+pub(crate) struct Footer {
+    metaindex_handle_: BlockHandle,
+    index_handle_: BlockHandle,
+}
+
+impl Footer {
+    /// Encoded length of a Footer.  Note that the serialization of a
+    /// Footer will always occupy exactly this many bytes.  It consists
+    /// of two block handles and a magic number.
+    pub(crate) const ENCODED_LENGTH: usize = 2 * BlockHandle::MAX_ENCODED_LENGTH + 8;
+
+    pub(crate) fn new(metaindex_handle: BlockHandle, index_handle: BlockHandle) -> Self {
+        Self { metaindex_handle_: metaindex_handle, index_handle_: index_handle }
+    }
+
+    /// The block handle for the metaindex block of the table
+    pub(crate) fn metaindex_handle(&self) -> &BlockHandle {
+        &self.metaindex_handle_
+    }
+
+    /// The block handle for the index block of the table
+    pub(crate) fn index_handle(&self) -> &BlockHandle {
+        &self.index_handle_
+    }
+
+    pub(crate) fn encode_to(&self, dst: &mut Vec<u8>) {
+        let original_size = dst.len();
+        self.metaindex_handle_.encode_to(dst);
+        self.index_handle_.encode_to(dst);
+        dst.resize(original_size + 2 * BlockHandle::MAX_ENCODED_LENGTH, 0);  // Padding
+        put_fixed32(dst, (TABLE_MAGIC_NUMBER & 0xffffffff) as u32);
+        put_fixed32(dst, (TABLE_MAGIC_NUMBER >> 32) as u32);
+        debug_assert!(dst.len() == original_size + Self::ENCODED_LENGTH);
+    }
+
+    pub(crate) fn decode_from(input: &Slice) -> Result<Self, Status> {
+        let data = input.data();
+        if data.len() < Self::ENCODED_LENGTH {
+            return Err(Status::corruption("not an sstable (footer too short)", ""));
+        }
+
+        let magic = &data[Self::ENCODED_LENGTH - 8..Self::ENCODED_LENGTH];
+        let magic_lo = decode_fixed32(magic[..4].try_into().unwrap());
+        let magic_hi = decode_fixed32(magic[4..].try_into().unwrap());
+        let magic = ((magic_hi as u64) << 32) | (magic_lo as u64);
+        if magic != TABLE_MAGIC_NUMBER {
+            return Err(Status::corruption("not an sstable (bad magic number)", ""));
+        }
+
+        let mut handles = Slice::new(&data[..Self::ENCODED_LENGTH - 8]);
+        let metaindex_handle = BlockHandle::decode_from(&mut handles)?;
+        let index_handle = BlockHandle::decode_from(&mut handles)?;
+        Ok(Self::new(metaindex_handle, index_handle))
+    }
+}
+
+/// Read the block identified by "handle" from "file".  On failure
+/// return non-OK.  On success return the contents of the block
+/// (without its trailer).
+pub(crate) fn read_block(file: &dyn RandomAccessFile, options: &ReadOptions, handle: &BlockHandle) -> Result<Vec<u8>, Status> {
+    read_block_with_crc(file, options, handle).map(|(contents, _)| contents)
+}
+
+/// Like read_block(), but also return the masked crc stored in the
+/// block's trailer, which covers the contents and the block type.
+pub(crate) fn read_block_with_crc(file: &dyn RandomAccessFile, options: &ReadOptions, handle: &BlockHandle) -> Result<(Vec<u8>, u32), Status> {
+    // Read the block contents as well as the type/crc footer.
+    // See table_builder.rs for the code that built this structure.
+    // No builder writes blocks this large; do not try to allocate for a
+    // corrupt handle.
+    let n = match usize::try_from(handle.size()) {
+        Ok(n) if n <= MAX_BLOCK_SIZE => n,
+        _ => return Err(Status::corruption("block handle size too large", "")),
+    };
+    let mut contents = file.read(handle.offset(), n + BLOCK_TRAILER_SIZE)?;
+    if contents.len() != n + BLOCK_TRAILER_SIZE {
+        return Err(Status::corruption("truncated block read", ""));
+    }
+
+    // Check the crc of the type and the block contents
+    let masked_crc = decode_fixed32(contents[n + 1..n + 5].try_into().unwrap());
+    if options.verify_checksums {
+        let crc = crc32c::unmask(masked_crc);
+        let actual = crc32c::value(&contents[..n + 1]);
+        if actual != crc {
+            return Err(Status::corruption("block checksum mismatch", ""));
+        }
+    }
+
+    match contents[n] {
+        NO_COMPRESSION => {
+            contents.truncate(n);
+            Ok((contents, masked_crc))
+        },
+        SNAPPY_COMPRESSION => Err(Status::not_supported("snappy compressed blocks", "")),
+        _ => Err(Status::corruption("bad block type", "")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::helpers::memenv::new_mem_env;
+
+    use super::*;
+
+    #[test]
+    fn block_handle_test() {
+        let mut handle = BlockHandle::new();
+        handle.set_offset(1 << 40);
+        handle.set_size(4096);
+        let mut encoded = Vec::new();
+        handle.encode_to(&mut encoded);
+        assert!(encoded.len() <= BlockHandle::MAX_ENCODED_LENGTH);
+
+        let mut input = Slice::new(&encoded);
+        assert_eq!(handle, BlockHandle::decode_from(&mut input).unwrap());
+        assert!(input.is_empty());
+        assert!(BlockHandle::decode_from(&mut Slice::new(&encoded[..3])).is_err());
+    }
+
+    #[test]
+    fn footer_test() {
+        let mut metaindex = BlockHandle::new();
+        metaindex.set_offset(100);
+        metaindex.set_size(20);
+        let mut index = BlockHandle::new();
+        index.set_offset(125);
+        index.set_size(300);
+        let mut encoded = Vec::new();
+        Footer::new(metaindex, index).encode_to(&mut encoded);
+        assert_eq!(Footer::ENCODED_LENGTH, encoded.len());
+
+        let footer = Footer::decode_from(&Slice::new(&encoded)).unwrap();
+        assert_eq!(&metaindex, footer.metaindex_handle());
+        assert_eq!(&index, footer.index_handle());
+
+        encoded[Footer::ENCODED_LENGTH - 1] ^= 1;
+        let s = Footer::decode_from(&Slice::new(&encoded)).err().unwrap();
+        assert!(s.to_string().contains("bad magic number"));
+    }
+
+    #[test]
+    fn read_block_size_limit_test() {
+        let env = new_mem_env();
+        let file = env.new_writable_file("/block").unwrap();
+        assert!(file.append(&Slice::new(&[0u8; 64])).ok());
+        let file = env.new_random_access_file("/block").unwrap();
+        for size in [MAX_BLOCK_SIZE as u64 + 1, u32::MAX as u64, u64::MAX] {
+            let mut handle = BlockHandle::new();
+            handle.set_size(size);
+            let s = read_block(file.as_ref(), &ReadOptions::new(), &handle).err().unwrap();
+            assert!(s.is_corruption() && s.to_string().contains("too large"), "{}", s.to_string());
+        }
+    }
+}
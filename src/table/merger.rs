@@ -0,0 +1,451 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{comparator::Comparator, db::dbformat::{extract_tag, extract_user_key, InternalKeyComparator}, iterator::{new_empty_iterator, Iterator, RawBlock, ValueLocation}, slice::Slice, status::Status};
+
+#[cfg(test)]
+thread_local! {
+    /// Number of comparisons between children made on this thread.
+    static COMPARISONS: Cell<u64> = const { Cell::new(0) };
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+struct MergingIterator {
+    // We might want to use a heap in case there are lots of children.
+    // For now we use a simple array since we expect a very small number
+    // of children in leveldb.
+    comparator_: Arc<dyn Comparator>,
+    children_: Vec<Box<dyn Iterator>>,
+    current_: Option<usize>,
+    direction_: Direction,
+
+    // Set if the children yield internal keys.  The user key length and
+    // tag of each valid child's key are then decoded into tags_ once
+    // per move of the child, rather than on every comparison.
+    icmp_: Option<InternalKeyComparator>,
+    tags_: Vec<(usize, u64)>,
+}
+
+impl MergingIterator {
+    fn new(comparator: Arc<dyn Comparator>, icmp: Option<InternalKeyComparator>, children: Vec<Box<dyn Iterator>>) -> Self {
+        let tags = vec![(0, 0); children.len()];
+        Self { comparator_: comparator, children_: children, current_: None, direction_: Direction::Forward, icmp_: icmp, tags_: tags }
+    }
+
+    /// Note that child "i" moved.
+    fn refresh(&mut self, i: usize) {
+        if self.icmp_.is_some() && self.children_[i].valid() {
+            let key = self.children_[i].key();
+            self.tags_[i] = (key.size() - 8, extract_tag(key.data()));
+        }
+    }
+
+    fn refresh_all(&mut self) {
+        for i in 0..self.children_.len() {
+            self.refresh(i);
+        }
+    }
+
+    /// Compare the keys of the valid children "i" and "j".
+    fn compare_children(&self, i: usize, j: usize) -> Ordering {
+        #[cfg(test)]
+        COMPARISONS.with(|n| n.set(n.get() + 1));
+        let (a, b) = (self.children_[i].key(), self.children_[j].key());
+        match &self.icmp_ {
+            Some(icmp) => {
+                let ((a_len, a_tag), (b_len, b_tag)) = (self.tags_[i], self.tags_[j]);
+                icmp.compare_parsed(&a.data()[..a_len], a_tag, &b.data()[..b_len], b_tag)
+            },
+            None => self.comparator_.compare(&a, &b),
+        }
+    }
+
+    fn find_smallest(&mut self) {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children_.iter().enumerate() {
+            if child.valid() {
+                match smallest {
+                    Some(s) if self.compare_children(i, s) != Ordering::Less => {},
+                    _ => { smallest = Some(i); },
+                }
+            }
+        }
+        self.current_ = smallest;
+    }
+
+    fn find_largest(&mut self) {
+        let mut largest: Option<usize> = None;
+        for (i, child) in self.children_.iter().enumerate().rev() {
+            if child.valid() {
+                match largest {
+                    Some(l) if self.compare_children(i, l) != Ordering::Greater => {},
+                    _ => { largest = Some(i); },
+                }
+            }
+        }
+        self.current_ = largest;
+    }
+
+    fn current(&mut self) -> &mut Box<dyn Iterator> {
+        let current = self.current_.expect("require valid");
+        &mut self.children_[current]
+    }
+}
+
+impl Iterator for MergingIterator {
+    fn valid(&self) -> bool {
+        self.current_.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        for child in self.children_.iter_mut() {
+            child.seek_to_first();
+        }
+        self.refresh_all();
+        self.find_smallest();
+        self.direction_ = Direction::Forward;
+    }
+
+    fn seek_to_last(&mut self) {
+        for child in self.children_.iter_mut() {
+            child.seek_to_last();
+        }
+        self.refresh_all();
+        self.find_largest();
+        self.direction_ = Direction::Reverse;
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        for child in self.children_.iter_mut() {
+            child.seek(target);
+        }
+        self.refresh_all();
+        self.find_smallest();
+        self.direction_ = Direction::Forward;
+    }
+
+    fn next(&mut self) {
+        debug_assert!(self.valid());
+
+        // Ensure that all children are positioned after key().
+        // If we are moving in the forward direction, it is already
+        // true for all of the non-current_ children since current_ is
+        // the smallest child and key() == current_->key().  Otherwise,
+        // we explicitly position the non-current_ children.
+        if self.direction_ != Direction::Forward {
+            let key = self.key().data().to_vec();
+            let current = self.current_;
+            for (i, child) in self.children_.iter_mut().enumerate() {
+                if Some(i) != current {
+                    child.seek(&Slice::new(&key));
+                    if child.valid() && self.comparator_.compare(&Slice::new(&key), &child.key()) == Ordering::Equal {
+                        child.next();
+                    }
+                }
+            }
+            self.refresh_all();
+            self.direction_ = Direction::Forward;
+        }
+
+        self.current().next();
+        self.refresh(self.current_.unwrap());
+        self.find_smallest();
+    }
+
+    fn prev(&mut self) {
+        debug_assert!(self.valid());
+
+        // Ensure that all children are positioned before key().
+        // If we are moving in the reverse direction, it is already
+        // true for all of the non-current_ children since current_ is
+        // the largest child and key() == current_->key().  Otherwise,
+        // we explicitly position the non-current_ children.
+        if self.direction_ != Direction::Reverse {
+            let key = self.key().data().to_vec();
+            let current = self.current_;
+            for (i, child) in self.children_.iter_mut().enumerate() {
+                if Some(i) != current {
+                    child.seek(&Slice::new(&key));
+                    if child.valid() {
+                        // Child is at first entry >= key().  Step back one to be < key()
+                        child.prev();
+                    } else {
+                        // Child has no entries >= key().  Position at last entry.
+                        child.seek_to_last();
+                    }
+                }
+            }
+            self.refresh_all();
+            self.direction_ = Direction::Reverse;
+        }
+
+        self.current().prev();
+        self.refresh(self.current_.unwrap());
+        self.find_largest();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        self.children_[self.current_.expect("require valid")].key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        self.children_[self.current_.expect("require valid")].value()
+    }
+
+    fn value_location(&self) -> Option<ValueLocation> {
+        self.children_[self.current_.expect("require valid")].value_location()
+    }
+
+    fn status(&self) -> Status {
+        for child in &self.children_ {
+            let s = child.status();
+            if !s.ok() {
+                return s;
+            }
+        }
+        Status::new_ok()
+    }
+
+    fn raw_block(&self) -> Option<RawBlock> {
+        // Only whole user keys can be passed over, so the other children
+        // must all be past the last user key of the block.
+        let (icmp, current) = (self.icmp_.as_ref()?, self.current_?);
+        if self.direction_ != Direction::Forward {
+            return None;
+        }
+        let raw = self.children_[current].raw_block()?;
+        let last = raw.last_key(self.comparator_.clone()).filter(|key| key.len() >= 8)?;
+        let ucmp = icmp.user_comparator();
+        let limit = extract_user_key(&last);
+        let overlaps = self.children_.iter().enumerate().any(|(i, child)| {
+            let (len, _) = self.tags_[i];
+            i != current && child.valid() && ucmp.compare(&Slice::new(&child.key().data()[..len]), &limit) != Ordering::Greater
+        });
+        (!overlaps).then_some(raw)
+    }
+
+    fn skip_block(&mut self) {
+        debug_assert!(self.direction_ == Direction::Forward);
+        let current = self.current_.expect("require valid");
+        self.children_[current].skip_block();
+        self.refresh(current);
+        self.find_smallest();
+    }
+}
+
+/// Return an iterator that provided the union of the data in
+/// children.  For example, an iterator over [a, b, c] and one over
+/// [b, d] yields a, b, b, c, d.
+/// 
+/// The result does no duplicate suppression.  I.e., if a particular
+/// key is present in K child iterators, it will be yielded K times.
+/// 
+/// The DB merges internal keys only, with new_internal_merging_iterator().
+#[cfg(test)]
+pub(crate) fn new_merging_iterator(comparator: Arc<dyn Comparator>, mut children: Vec<Box<dyn Iterator>>) -> Box<dyn Iterator> {
+    match children.len() {
+        0 => new_empty_iterator(),
+        1 => children.pop().unwrap(),
+        _ => Box::new(MergingIterator::new(comparator, None, children)),
+    }
+}
+
+/// Like new_merging_iterator(), for children that yield internal keys
+/// ordered by "icmp".  Keys are compared without decoding their tags
+/// again each time.
+pub(crate) fn new_internal_merging_iterator(icmp: &InternalKeyComparator, mut children: Vec<Box<dyn Iterator>>) -> Box<dyn Iterator> {
+    match children.len() {
+        0 => new_empty_iterator(),
+        1 => children.pop().unwrap(),
+        _ => Box::new(MergingIterator::new(Arc::new(icmp.clone()), Some(icmp.clone()), children)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{comparator::bytewise_comparator, db::dbformat::{InternalKey, ValueType, TAG_DECODES}, iterator::new_error_iterator, util::random::Random};
+
+    use super::*;
+
+    /// Iterates over a sorted list of keys; values repeat the key.
+    struct VecIterator {
+        comparator_: Arc<dyn Comparator>,
+        keys_: Vec<Vec<u8>>,
+        pos_: usize,    // == keys_.len() when not valid
+    }
+
+    impl VecIterator {
+        fn with_keys(keys: &[&str]) -> Box<dyn Iterator> {
+            Self::sorted(bytewise_comparator(), keys.iter().map(|k| k.as_bytes().to_vec()).collect())
+        }
+
+        fn sorted(comparator: Arc<dyn Comparator>, mut keys: Vec<Vec<u8>>) -> Box<dyn Iterator> {
+            keys.sort_by(|a, b| comparator.compare(&Slice::new(a), &Slice::new(b)));
+            let pos_ = keys.len();
+            Box::new(Self { comparator_: comparator, keys_: keys, pos_ })
+        }
+    }
+
+    impl Iterator for VecIterator {
+        fn valid(&self) -> bool { self.pos_ < self.keys_.len() }
+        fn seek_to_first(&mut self) { self.pos_ = 0; }
+        fn seek_to_last(&mut self) { self.pos_ = if self.keys_.is_empty() { 0 } else { self.keys_.len() - 1 }; }
+        fn seek(&mut self, target: &Slice) {
+            self.pos_ = self.keys_.iter()
+                .position(|k| self.comparator_.compare(&Slice::new(k), target) != Ordering::Less)
+                .unwrap_or(self.keys_.len());
+        }
+        fn next(&mut self) { self.pos_ += 1; }
+        fn prev(&mut self) { self.pos_ = if self.pos_ == 0 { self.keys_.len() } else { self.pos_ - 1 }; }
+        fn key(&self) -> Slice<'_> { Slice::new(&self.keys_[self.pos_]) }
+        fn value(&self) -> Slice<'_> { Slice::new(&self.keys_[self.pos_]) }
+        fn status(&self) -> Status { Status::new_ok() }
+    }
+
+    fn merged() -> Box<dyn Iterator> {
+        new_merging_iterator(bytewise_comparator(),
+            vec![VecIterator::with_keys(&["a", "c", "e"]), VecIterator::with_keys(&[]), VecIterator::with_keys(&["b", "c", "d", "f"])])
+    }
+
+    fn collect(iter: &mut dyn Iterator, forward: bool) -> Vec<String> {
+        let mut result = Vec::new();
+        while iter.valid() {
+            result.push(iter.key().to_utf8_string().unwrap());
+            if forward { iter.next(); } else { iter.prev(); }
+        }
+        result
+    }
+
+    #[test]
+    fn merge_test() {
+        let mut iter = merged();
+        iter.seek_to_first();
+        assert_eq!(vec!["a", "b", "c", "c", "d", "e", "f"], collect(iter.as_mut(), true));
+        iter.seek_to_last();
+        assert_eq!(vec!["f", "e", "d", "c", "c", "b", "a"], collect(iter.as_mut(), false));
+        iter.seek(&Slice::new(b"cc"));
+        assert_eq!(vec!["d", "e", "f"], collect(iter.as_mut(), true));
+        assert!(iter.status().ok());
+    }
+
+    #[test]
+    fn direction_change_test() {
+        let mut iter = merged();
+        iter.seek(&Slice::new(b"d"));
+        assert_eq!("d", iter.key().to_utf8_string().unwrap());
+        iter.prev();
+        assert_eq!("c", iter.key().to_utf8_string().unwrap());
+        iter.prev();
+        assert_eq!("c", iter.key().to_utf8_string().unwrap());
+        iter.prev();
+        assert_eq!("b", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        assert_eq!("c", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        iter.next();
+        assert_eq!("d", iter.key().to_utf8_string().unwrap());
+        iter.seek_to_last();
+        iter.prev();
+        iter.next();
+        assert_eq!("f", iter.key().to_utf8_string().unwrap());
+        iter.next();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn special_cases_test() {
+        let mut empty = new_merging_iterator(bytewise_comparator(), Vec::new());
+        empty.seek_to_first();
+        assert!(!empty.valid());
+
+        let mut with_error = new_merging_iterator(bytewise_comparator(),
+            vec![VecIterator::with_keys(&["a"]), new_error_iterator(Status::corruption("bad", ""))]);
+        with_error.seek_to_first();
+        assert_eq!(vec!["a"], collect(with_error.as_mut(), true));
+        assert!(with_error.status().is_corruption());
+    }
+
+    /// Children holding random internal keys.  User keys share a long
+    /// prefix, and many appear in several children with different tags.
+    fn internal_children(rnd: &mut Random, icmp: &InternalKeyComparator, n: usize, user_keys: u32) -> Vec<Box<dyn Iterator>> {
+        (0..n).map(|_| {
+            let keys = (0..rnd.uniform(50)).map(|_| {
+                let user_key = format!("shared/prefix/{:03}", rnd.uniform(user_keys as i32));
+                let type_ = if rnd.one_in(4) { ValueType::type_deletion() } else { ValueType::type_value() };
+                InternalKey::new_from(&Slice::new(user_key.as_bytes()), rnd.uniform(20) as u64, type_).encode().data().to_vec()
+            }).collect();
+            VecIterator::sorted(Arc::new(icmp.clone()), keys)
+        }).collect()
+    }
+
+    #[test]
+    fn internal_merge_test() {
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        for seed in 0..20 {
+            // The same children, merged with and without decoded tags
+            let n = 1 + seed as usize % 8;
+            let mut parsed = new_internal_merging_iterator(&icmp, internal_children(&mut Random::new(seed), &icmp, n, 40));
+            let mut naive = new_merging_iterator(Arc::new(icmp.clone()), internal_children(&mut Random::new(seed), &icmp, n, 40));
+            let same = |a: &dyn Iterator, b: &dyn Iterator| {
+                assert_eq!(a.valid(), b.valid());
+                if a.valid() {
+                    assert_eq!(a.key().data(), b.key().data());
+                }
+            };
+
+            parsed.seek_to_first();
+            naive.seek_to_first();
+            let mut rnd = Random::new(seed + 1000);
+            for _ in 0..500 {
+                match rnd.uniform(6) {
+                    _ if !parsed.valid() || rnd.one_in(10) => {
+                        let target = InternalKey::new_from(&Slice::new(format!("shared/prefix/{:03}", rnd.uniform(42)).as_bytes()),
+                                                           rnd.uniform(20) as u64, ValueType::type_value());
+                        parsed.seek(&target.encode());
+                        naive.seek(&target.encode());
+                    },
+                    0 => {
+                        parsed.seek_to_last();
+                        naive.seek_to_last();
+                    },
+                    1 | 2 => {
+                        parsed.prev();
+                        naive.prev();
+                    },
+                    _ => {
+                        parsed.next();
+                        naive.next();
+                    },
+                }
+                same(parsed.as_ref(), naive.as_ref());
+            }
+        }
+    }
+
+    #[test]
+    fn tag_decodes_test() {
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        // Tag decodes per comparison over a full scan of a 16-child merge,
+        // where most comparisons are between equal user keys
+        let decodes_per_comparison = |iter: &mut dyn Iterator| {
+            iter.seek_to_first();
+            let (decodes, comparisons) = (TAG_DECODES.with(|n| n.get()), COMPARISONS.with(|n| n.get()));
+            while iter.valid() {
+                iter.next();
+            }
+            let comparisons = COMPARISONS.with(|n| n.get()) - comparisons;
+            assert!(comparisons > 1000);
+            (TAG_DECODES.with(|n| n.get()) - decodes) as f64 / comparisons as f64
+        };
+        let naive = decodes_per_comparison(new_merging_iterator(Arc::new(icmp.clone()), internal_children(&mut Random::new(7), &icmp, 16, 2)).as_mut());
+        let parsed = decodes_per_comparison(new_internal_merging_iterator(&icmp, internal_children(&mut Random::new(7), &icmp, 16, 2)).as_mut());
+        assert!(naive > 1.5, "{}", naive);
+        assert!(parsed < 0.1, "{}", parsed);
+    }
+}
@@ -0,0 +1,249 @@
+//! Table properties are statistics gathered by TableBuilder while a table
+//! is built, stored in a meta block so they can be read without scanning
+//! the table.  Tables written before the block existed have none.
+//!
+//! properties :=
+//!    key_sizes value_sizes [num_deletions: varint64]
+//! histogram :=
+//!    varint32 num_buckets (count: varint64, bytes: varint64)[num_buckets]
+
+use crate::{slice::Slice, status::Status, util::coding::{get_varint32, get_varint64, put_varint32, put_varint64}};
+
+/// Name of the metaindex entry that locates a table's properties block.
+pub(crate) const PROPERTIES_META_KEY: &str = "rucksdb.properties";
+
+/// Name of the metaindex entry whose value is the name of the comparator
+/// that ordered the table's user keys.  Only tables built outside a DB
+/// (by SstFileWriter) have it.
+pub(crate) const COMPARATOR_META_KEY: &str = "rucksdb.comparator";
+
+/// Number of buckets in a SizeHistogram.
+pub(crate) const SIZE_HISTOGRAM_BUCKETS: usize = 16;
+
+/// Counts sizes in log2 buckets: bucket 0 holds sizes 0 and 1, bucket i
+/// sizes in [2^i, 2^(i+1)), and the last bucket every size from 2^15 up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SizeHistogram {
+    counts_: [u64; SIZE_HISTOGRAM_BUCKETS],
+    bytes_: [u64; SIZE_HISTOGRAM_BUCKETS],
+}
+
+impl SizeHistogram {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the bucket that counts "size".
+    pub(crate) fn bucket_for(size: u64) -> usize {
+        (size.max(1).ilog2() as usize).min(SIZE_HISTOGRAM_BUCKETS - 1)
+    }
+
+    /// Return the smallest size counted by "bucket".
+    pub(crate) fn bucket_start(bucket: usize) -> u64 {
+        if bucket == 0 { 0 } else { 1 << bucket }
+    }
+
+    pub(crate) fn add(&mut self, size: u64) {
+        let bucket = Self::bucket_for(size);
+        self.counts_[bucket] += 1;
+        self.bytes_[bucket] += size;
+    }
+
+    pub(crate) fn merge(&mut self, other: &SizeHistogram) {
+        for i in 0..SIZE_HISTOGRAM_BUCKETS {
+            self.counts_[i] += other.counts_[i];
+            self.bytes_[i] += other.bytes_[i];
+        }
+    }
+
+    pub(crate) fn count(&self, bucket: usize) -> u64 {
+        self.counts_[bucket]
+    }
+
+    pub(crate) fn bytes(&self, bucket: usize) -> u64 {
+        self.bytes_[bucket]
+    }
+
+    /// Number of sizes added.
+    pub(crate) fn num(&self) -> u64 {
+        self.counts_.iter().sum()
+    }
+
+    /// Sum of the sizes added.
+    pub(crate) fn sum(&self) -> u64 {
+        self.bytes_.iter().sum()
+    }
+
+    fn encode_to(&self, dst: &mut Vec<u8>) {
+        put_varint32(dst, SIZE_HISTOGRAM_BUCKETS as u32);
+        for i in 0..SIZE_HISTOGRAM_BUCKETS {
+            put_varint64(dst, self.counts_[i]);
+            put_varint64(dst, self.bytes_[i]);
+        }
+    }
+
+    fn decode_from(input: &mut Slice) -> Result<Self, Status> {
+        let mut result = Self::new();
+        if get_varint32(input) != Some(SIZE_HISTOGRAM_BUCKETS as u32) {
+            return Err(Status::corruption("bad size histogram", ""));
+        }
+        for i in 0..SIZE_HISTOGRAM_BUCKETS {
+            match (get_varint64(input), get_varint64(input)) {
+                (Some(count), Some(bytes)) => {
+                    result.counts_[i] = count;
+                    result.bytes_[i] = bytes;
+                },
+                _ => return Err(Status::corruption("bad size histogram", "")),
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TableProperties {
+    pub(crate) key_sizes: SizeHistogram,    // Sizes of the keys as stored
+    pub(crate) value_sizes: SizeHistogram,
+
+    // Deletion markers among the entries.  Zero for tables written before
+    // they were counted.
+    pub(crate) num_deletions: u64,
+}
+
+impl TableProperties {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn encode_to(&self, dst: &mut Vec<u8>) {
+        self.key_sizes.encode_to(dst);
+        self.value_sizes.encode_to(dst);
+        put_varint64(dst, self.num_deletions);
+    }
+
+    pub(crate) fn decode_from(input: &Slice) -> Result<Self, Status> {
+        let mut input = input.clone();
+        let key_sizes = SizeHistogram::decode_from(&mut input)?;
+        let value_sizes = SizeHistogram::decode_from(&mut input)?;
+        let num_deletions = if input.is_empty() {
+            0
+        } else {
+            get_varint64(&mut input).ok_or_else(|| Status::corruption("bad deletion count", ""))?
+        };
+        Ok(Self { key_sizes, value_sizes, num_deletions })
+    }
+}
+
+/// Suggested value size above which values are worth treating as large,
+/// with the numbers it is based on (see DB::advise_value_threshold).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueThresholdAdvice {
+    /// Smallest histogram bucket boundary above which values make up more
+    /// than half of the value bytes but less than 5% of the values, or
+    /// None if no boundary does.
+    pub threshold: Option<u64>,
+
+    /// Values and value bytes covered by the histograms.
+    pub values: u64,
+    pub value_bytes: u64,
+
+    /// Values and value bytes at or above the threshold (zero without one).
+    pub large_values: u64,
+    pub large_value_bytes: u64,
+
+    /// Live tables without a value-size histogram.  They are left out of
+    /// the numbers above.
+    pub tables_without_histogram: u64,
+}
+
+impl ValueThresholdAdvice {
+    /// Analyze the value sizes of "histogram", which covers every live
+    /// table but "tables_without_histogram" of them.
+    pub(crate) fn from_histogram(histogram: &SizeHistogram, tables_without_histogram: u64) -> Self {
+        let (values, value_bytes) = (histogram.num(), histogram.sum());
+        let mut advice = Self {
+            threshold: None,
+            values,
+            value_bytes,
+            large_values: 0,
+            large_value_bytes: 0,
+            tables_without_histogram,
+        };
+        for bucket in 1..SIZE_HISTOGRAM_BUCKETS {
+            let large_values: u64 = (bucket..SIZE_HISTOGRAM_BUCKETS).map(|i| histogram.count(i)).sum();
+            let large_value_bytes: u64 = (bucket..SIZE_HISTOGRAM_BUCKETS).map(|i| histogram.bytes(i)).sum();
+            if large_value_bytes * 2 > value_bytes && large_values * 20 < values {
+                advice.threshold = Some(SizeHistogram::bucket_start(bucket));
+                advice.large_values = large_values;
+                advice.large_value_bytes = large_value_bytes;
+                break;
+            }
+        }
+        advice
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_test() {
+        assert_eq!(0, SizeHistogram::bucket_for(0));
+        assert_eq!(0, SizeHistogram::bucket_for(1));
+        assert_eq!(1, SizeHistogram::bucket_for(2));
+        assert_eq!(1, SizeHistogram::bucket_for(3));
+        assert_eq!(10, SizeHistogram::bucket_for(1024));
+        assert_eq!(14, SizeHistogram::bucket_for(32767));
+        assert_eq!(15, SizeHistogram::bucket_for(32768));
+        assert_eq!(15, SizeHistogram::bucket_for(u64::MAX));
+        for bucket in 0..SIZE_HISTOGRAM_BUCKETS {
+            assert_eq!(bucket, SizeHistogram::bucket_for(SizeHistogram::bucket_start(bucket)));
+        }
+    }
+
+    #[test]
+    fn encode_decode_test() {
+        let mut properties = TableProperties::new();
+        for size in [0, 5, 5, 100, 70000] {
+            properties.value_sizes.add(size);
+            properties.key_sizes.add(size / 2 + 8);
+        }
+        assert_eq!(5, properties.value_sizes.num());
+        assert_eq!(70110, properties.value_sizes.sum());
+        assert_eq!(2, properties.value_sizes.count(2));
+        assert_eq!(10, properties.value_sizes.bytes(2));
+        properties.num_deletions = 300;
+
+        let mut encoded = Vec::new();
+        properties.encode_to(&mut encoded);
+        assert_eq!(properties, TableProperties::decode_from(&Slice::new(&encoded)).unwrap());
+        assert!(TableProperties::decode_from(&Slice::new(&encoded[..encoded.len() - 1])).unwrap_err().is_corruption());
+
+        // Written before deletions were counted
+        let without_deletions = TableProperties::decode_from(&Slice::new(&encoded[..encoded.len() - 2])).unwrap();
+        assert_eq!(TableProperties { num_deletions: 0, ..properties.clone() }, without_deletions);
+        assert!(TableProperties::decode_from(&Slice::new(&encoded[..encoded.len() - 3])).unwrap_err().is_corruption());
+        encoded[0] = 3;
+        assert!(TableProperties::decode_from(&Slice::new(&encoded)).unwrap_err().is_corruption());
+    }
+
+    #[test]
+    fn advice_test() {
+        // Mostly small values, with a few large ones holding most bytes
+        let mut histogram = SizeHistogram::new();
+        (0..970).for_each(|_| histogram.add(20));
+        (0..30).for_each(|_| histogram.add(5000));
+        let advice = ValueThresholdAdvice::from_histogram(&histogram, 2);
+        assert_eq!(Some(32), advice.threshold);
+        assert_eq!((1000, 970 * 20 + 30 * 5000), (advice.values, advice.value_bytes));
+        assert_eq!((30, 30 * 5000), (advice.large_values, advice.large_value_bytes));
+        assert_eq!(2, advice.tables_without_histogram);
+
+        // Uniform sizes have no such boundary
+        let mut histogram = SizeHistogram::new();
+        (0..1000).for_each(|_| histogram.add(500));
+        assert_eq!(None, ValueThresholdAdvice::from_histogram(&histogram, 0).threshold);
+        assert_eq!(None, ValueThresholdAdvice::from_histogram(&SizeHistogram::new(), 0).threshold);
+    }
+}
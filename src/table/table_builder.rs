@@ -0,0 +1,407 @@
+//! TableBuilder provides the interface used to build a Table
+//! (an immutable and sorted map from keys to values).
+//!
+//! Multiple threads can invoke const methods on a TableBuilder without
+//! external synchronization, but if any of the threads may call a
+//! non-const method, all threads accessing the same TableBuilder must use
+//! external synchronization.
+
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{comparator::bytewise_comparator, env::WritableFile, iterator::RawBlock, options::{CompressionType, Options}, slice::Slice, status::Status, util::{coding::encode_fixed32, crc32c}};
+
+use super::{block_builder::BlockBuilder, filter_block::{FilterBlockBuilder, FILTER_META_PREFIX}, format::{BlockHandle, Footer, BLOCK_TRAILER_SIZE, NO_COMPRESSION}, properties::{TableProperties, COMPARATOR_META_KEY, PROPERTIES_META_KEY}};
+
+pub(crate) struct TableBuilder {
+    options_: Options,
+    file_: Arc<dyn WritableFile>,
+    offset_: u64,
+    file_crc_: u32,     // crc32c of the offset_ bytes written
+    status_: Status,
+    data_block_: BlockBuilder,
+    index_block_: BlockBuilder,
+    last_key_: Vec<u8>,
+    num_entries_: u64,
+    closed_: bool,  // Either finish() or abandon() has been called.
+    filter_block_: Option<FilterBlockBuilder>,
+    properties_: TableProperties,
+    // Tests turn this off to produce tables as written before the
+    // properties block existed.
+    write_properties_: bool,
+    comparator_name_: Option<String>,
+
+    // The keys are internal keys, whose deletion markers can be counted
+    // in properties_: true for the tables of a DB, which are ordered by
+    // the internal key comparator.
+    count_deletions_: bool,
+
+    // We do not emit the index entry for a block until we have seen the
+    // first key for the next data block.  This allows us to use shorter
+    // keys in the index block.  For example, consider a block boundary
+    // between the keys "the quick brown fox" and "the who".  We can use
+    // "the r" as the key for the index block entry since it is >= all
+    // entries in the first block and < all entries in subsequent
+    // blocks.
+    //
+    // Invariant: pending_index_entry_ is true only if data_block_ is empty.
+    pending_index_entry_: bool,
+    pending_handle_: BlockHandle,   // Handle to add to index block
+}
+
+impl TableBuilder {
+    /// Create a builder that will store the contents of the table it is
+    /// building in *file.  Does not close the file.  It is up to the
+    /// caller to close the file after calling finish().
+    pub(crate) fn new(options: &Options, file: Arc<dyn WritableFile>) -> Self {
+        let mut filter_block = options.filter_policy.clone().map(FilterBlockBuilder::new);
+        if let Some(filter_block) = filter_block.as_mut() {
+            filter_block.start_block(0);
+        }
+        Self {
+            options_: options.clone(),
+            file_: file,
+            offset_: 0,
+            file_crc_: 0,
+            status_: Status::new_ok(),
+            data_block_: BlockBuilder::new(options.comparator.clone(), options.block_restart_interval),
+            // Index blocks are searched with binary search only, so every
+            // entry is a restart point.
+            index_block_: BlockBuilder::new(options.comparator.clone(), 1),
+            last_key_: Vec::new(),
+            num_entries_: 0,
+            closed_: false,
+            filter_block_: filter_block,
+            properties_: TableProperties::new(),
+            write_properties_: true,
+            comparator_name_: None,
+            count_deletions_: options.comparator.name() == "leveldb.InternalKeyComparator",
+            pending_index_entry_: false,
+            pending_handle_: BlockHandle::new(),
+        }
+    }
+
+    /// Add key,value to the table being constructed.  Fails with
+    /// InvalidArgument, see status(), if the entry is too large for any
+    /// block (MAX_BLOCK_SIZE).
+    /// REQUIRES: key is after any previously added key according to comparator.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub(crate) fn add(&mut self, key: &Slice, value: &Slice) {
+        debug_assert!(!self.closed_);
+        if !self.ok() {
+            return;
+        }
+        if self.num_entries_ > 0 {
+            debug_assert!(self.options_.comparator.compare(key, &Slice::new(&self.last_key_)) == Ordering::Greater);
+        }
+
+        if !self.data_block_.fits(key, value) {
+            // Start a new block, unless the entry does not fit in one on
+            // its own.
+            self.flush();
+            if self.ok() && !self.data_block_.fits(key, value) {
+                self.status_ = Status::invalid_argument("entry too large for a table block",
+                                                        &format!("{} key bytes, {} value bytes", key.size(), value.size()));
+            }
+            if !self.ok() {
+                return;
+            }
+        }
+
+        if self.pending_index_entry_ {
+            debug_assert!(self.data_block_.empty());
+            self.options_.comparator.find_shortest_separator(&mut self.last_key_, key);
+            self.add_index_entry();
+            if !self.ok() {
+                return;
+            }
+        }
+
+        if let Some(filter_block) = self.filter_block_.as_mut() {
+            filter_block.add_key(key);
+        }
+
+        self.last_key_.clear();
+        self.last_key_.extend_from_slice(key.data());
+        self.add_to_properties(key, value);
+        self.data_block_.add(key, value);
+
+        let estimated_block_size = self.data_block_.current_size_estimate();
+        if estimated_block_size >= self.options_.block_size {
+            self.flush();
+        }
+    }
+
+    /// Append the data block "block" of another table as is, without
+    /// decoding and re-encoding its entries.  The buffered entries are
+    /// flushed into a block of their own first.
+    /// REQUIRES: the keys of "block" are after any previously added key.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub(crate) fn add_raw_block(&mut self, block: &RawBlock) {
+        debug_assert!(!self.closed_);
+        self.flush();
+        if !self.ok() {
+            return;
+        }
+
+        let mut iter = block.new_iterator(self.options_.comparator.clone());
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            if self.pending_index_entry_ {
+                self.options_.comparator.find_shortest_separator(&mut self.last_key_, &key);
+                self.add_index_entry();
+            }
+            if let Some(filter_block) = self.filter_block_.as_mut() {
+                filter_block.add_key(&key);
+            }
+            self.last_key_.clear();
+            self.last_key_.extend_from_slice(key.data());
+            self.add_to_properties(&key, &iter.value());
+            iter.next();
+        }
+        self.status_ = iter.status();
+        if !self.ok() {
+            return;
+        }
+
+        // The index entry is added once the next key is known, as for the
+        // blocks flush() writes.
+        self.pending_handle_ = self.write_block(block.contents(), NO_COMPRESSION, block.crc);
+        if self.ok() {
+            self.pending_index_entry_ = true;
+            self.status_ = self.file_.flush();
+        }
+        if let Some(filter_block) = self.filter_block_.as_mut() {
+            filter_block.start_block(self.offset_);
+        }
+    }
+
+    /// Advanced operation: flush any buffered key/value pairs to file.
+    /// Can be used to ensure that two adjacent entries never live in
+    /// the same data block.  Most clients should not need to use this method.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub(crate) fn flush(&mut self) {
+        debug_assert!(!self.closed_);
+        if !self.ok() {
+            return;
+        }
+        if self.data_block_.empty() {
+            return;
+        }
+        debug_assert!(!self.pending_index_entry_);
+        let raw = self.data_block_.finish().data().to_vec();
+        self.data_block_.reset();
+        self.pending_handle_ = self.write_raw_block(&raw, self.block_type());
+        if self.ok() {
+            self.pending_index_entry_ = true;
+            self.status_ = self.file_.flush();
+        }
+        if let Some(filter_block) = self.filter_block_.as_mut() {
+            filter_block.start_block(self.offset_);
+        }
+    }
+
+    /// Return non-ok iff some error has been detected.
+    pub(crate) fn status(&self) -> Status {
+        self.status_.clone()
+    }
+
+    /// Finish building the table.  Stops using the file passed to the
+    /// constructor after this function returns.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub(crate) fn finish(&mut self) -> Status {
+        self.flush();
+        debug_assert!(!self.closed_);
+        self.closed_ = true;
+
+        // Write filter block
+        let mut filter_block_handle = BlockHandle::new();
+        if self.ok() {
+            if let Some(filter_block) = self.filter_block_.as_mut() {
+                let raw = filter_block.finish().data().to_vec();
+                filter_block_handle = self.write_raw_block(&raw, NO_COMPRESSION);
+            }
+        }
+
+        // Write properties block
+        let mut properties_block_handle = BlockHandle::new();
+        if self.ok() && self.write_properties_ {
+            let mut raw = Vec::new();
+            self.properties_.encode_to(&mut raw);
+            properties_block_handle = self.write_raw_block(&raw, NO_COMPRESSION);
+        }
+
+        // Write metaindex block
+        let mut metaindex_block_handle = BlockHandle::new();
+        if self.ok() {
+            // Meta block names are compared bytewise, whatever the table's
+            // comparator.
+            let mut meta_index_block = BlockBuilder::new(bytewise_comparator(), self.options_.block_restart_interval);
+            if let Some(policy) = &self.options_.filter_policy {
+                // Add mapping from "filter.Name" to location of filter data
+                let key = format!("{}{}", FILTER_META_PREFIX, policy.name());
+                let mut handle_encoding = Vec::new();
+                filter_block_handle.encode_to(&mut handle_encoding);
+                meta_index_block.add(&Slice::new(key.as_bytes()), &Slice::new(&handle_encoding));
+            }
+            if let Some(name) = &self.comparator_name_ {
+                meta_index_block.add(&Slice::new(COMPARATOR_META_KEY.as_bytes()), &Slice::new(name.as_bytes()));
+            }
+            if self.write_properties_ {
+                let mut handle_encoding = Vec::new();
+                properties_block_handle.encode_to(&mut handle_encoding);
+                meta_index_block.add(&Slice::new(PROPERTIES_META_KEY.as_bytes()), &Slice::new(&handle_encoding));
+            }
+            let raw = meta_index_block.finish().data().to_vec();
+            metaindex_block_handle = self.write_raw_block(&raw, self.block_type());
+        }
+
+        // Write index block
+        let mut index_block_handle = BlockHandle::new();
+        if self.ok() && self.pending_index_entry_ {
+            self.options_.comparator.find_short_successor(&mut self.last_key_);
+            self.add_index_entry();
+        }
+        if self.ok() {
+            let raw = self.index_block_.finish().data().to_vec();
+            self.index_block_.reset();
+            index_block_handle = self.write_raw_block(&raw, self.block_type());
+        }
+
+        // Write footer
+        if self.ok() {
+            let mut footer_encoding = Vec::new();
+            Footer::new(metaindex_block_handle, index_block_handle).encode_to(&mut footer_encoding);
+            self.status_ = self.file_.append(&Slice::new(&footer_encoding));
+            if self.ok() {
+                self.offset_ += footer_encoding.len() as u64;
+                self.file_crc_ = crc32c::extend(self.file_crc_, &footer_encoding);
+            }
+        }
+        self.status_.clone()
+    }
+
+    /// Indicate that the contents of this builder should be abandoned.  Stops
+    /// using the file passed to the constructor after this function returns.
+    /// If the caller is not going to call finish(), it must call abandon()
+    /// before destroying this builder.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub(crate) fn abandon(&mut self) {
+        debug_assert!(!self.closed_);
+        self.closed_ = true;
+    }
+
+    /// Number of calls to add() so far.
+    pub(crate) fn num_entries(&self) -> u64 {
+        self.num_entries_
+    }
+
+    /// Record "name" as the name of the comparator that orders the user
+    /// keys of the table, for a DB to check before it takes the table in.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub(crate) fn set_comparator_name(&mut self, name: &str) {
+        self.comparator_name_ = Some(name.to_string());
+    }
+
+    /// Leave the properties block out of the table, as older versions did.
+    #[cfg(test)]
+    pub(crate) fn skip_properties(&mut self) {
+        self.write_properties_ = false;
+    }
+
+    /// Lower the block size limit, so tests can reach it with small
+    /// entries.
+    #[cfg(test)]
+    pub(crate) fn set_max_block_size(&mut self, max_size: usize) {
+        self.data_block_.set_max_size(max_size);
+        self.index_block_.set_max_size(max_size);
+    }
+
+    /// Size of the file generated so far.  If invoked after a successful
+    /// finish() call, returns the size of the final generated file.
+    pub(crate) fn file_size(&self) -> u64 {
+        self.offset_
+    }
+
+    /// crc32c of the bytes of the file generated so far.  If invoked
+    /// after a successful finish() call, covers the whole file, so that
+    /// a file cut short or grown later no longer matches it even where
+    /// its blocks are intact.
+    pub(crate) fn file_checksum(&self) -> u32 {
+        self.file_crc_
+    }
+
+    fn ok(&self) -> bool {
+        self.status_.ok()
+    }
+
+    /// Add the index entry for the last data block written, keyed by
+    /// last_key_.
+    fn add_index_entry(&mut self) {
+        let mut handle_encoding = Vec::new();
+        self.pending_handle_.encode_to(&mut handle_encoding);
+        let (key, value) = (Slice::new(&self.last_key_), Slice::new(&handle_encoding));
+        if self.index_block_.fits(&key, &value) {
+            self.index_block_.add(&key, &value);
+        } else {
+            self.status_ = Status::invalid_argument("index block too large", "");
+        }
+        self.pending_index_entry_ = false;
+    }
+
+    /// Count the entry "key","value" in the table's entries and properties.
+    fn add_to_properties(&mut self, key: &Slice, value: &Slice) {
+        self.num_entries_ += 1;
+        self.properties_.key_sizes.add(key.size() as u64);
+        self.properties_.value_sizes.add(value.size() as u64);
+        // The low byte of an internal key's 8-byte trailer is its type;
+        // deletions are type zero.
+        if self.count_deletions_ && key.size() >= 8 && key.data()[key.size() - 8] == 0 {
+            self.properties_.num_deletions += 1;
+        }
+    }
+
+    /// The type data, index and metaindex blocks are stored with, for
+    /// options_.compression.
+    fn block_type(&self) -> u8 {
+        match self.options_.compression {
+            CompressionType::NoCompression => NO_COMPRESSION,
+            // Snappy is not compiled in: store the block raw, as LevelDB
+            // does when its Snappy_Compress() is not supported.
+            CompressionType::SnappyCompression => NO_COMPRESSION,
+        }
+    }
+
+    /// Append "contents" followed by its trailer, and return the handle
+    /// that locates it in the file.
+    fn write_raw_block(&mut self, contents: &[u8], type_: u8) -> BlockHandle {
+        let crc = crc32c::extend(crc32c::value(contents), &[type_]);  // Extend crc to cover block type
+        self.write_block(contents, type_, crc32c::mask(crc))
+    }
+
+    /// Like write_raw_block(), with the masked crc of the contents and
+    /// type already computed.
+    fn write_block(&mut self, contents: &[u8], type_: u8, masked_crc: u32) -> BlockHandle {
+        let mut handle = BlockHandle::new();
+        handle.set_offset(self.offset_);
+        handle.set_size(contents.len() as u64);
+        self.status_ = self.file_.append(&Slice::new(contents));
+        if self.ok() {
+            let mut trailer = [0u8; BLOCK_TRAILER_SIZE];
+            trailer[0] = type_;
+            trailer[1..].copy_from_slice(&encode_fixed32(masked_crc));
+            self.status_ = self.file_.append(&Slice::new(&trailer));
+            if self.ok() {
+                self.offset_ += (contents.len() + BLOCK_TRAILER_SIZE) as u64;
+                self.file_crc_ = crc32c::extend(crc32c::extend(self.file_crc_, contents), &trailer);
+            }
+        }
+        handle
+    }
+}
+
+impl Drop for TableBuilder {
+    fn drop(&mut self) {
+        debug_assert!(self.closed_ || std::thread::panicking());    // Catch errors where caller forgot to call finish()
+    }
+}
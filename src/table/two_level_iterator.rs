@@ -0,0 +1,211 @@
+use crate::{iterator::{Iterator, RawBlock, ValueLocation}, options::ReadOptions, slice::Slice, status::Status};
+
+/// Maps the value of an index entry to an iterator over the contents of
+/// the corresponding block.
+pub(crate) type BlockFunction = Box<dyn Fn(&ReadOptions, &Slice) -> Box<dyn Iterator> + Send>;
+
+/// Completes the location of a value in a block with the value of the
+/// index entry that points to the block.
+pub(crate) type ValueLocator = Box<dyn Fn(&Slice, ValueLocation) -> Option<ValueLocation> + Send>;
+
+struct TwoLevelIterator {
+    block_function_: BlockFunction,
+    options_: ReadOptions,
+    status_: Status,
+    index_iter_: Box<dyn Iterator>,
+    data_iter_: Option<Box<dyn Iterator>>,  // May be None
+    // If data_iter_ is Some, then "data_block_handle_" holds the
+    // "index_value" passed to block_function_ to create the data_iter_.
+    data_block_handle_: Vec<u8>,
+    value_locator_: Option<ValueLocator>,
+}
+
+impl TwoLevelIterator {
+    fn save_error(&mut self, s: Status) {
+        if self.status_.ok() && !s.ok() {
+            self.status_ = s;
+        }
+    }
+
+    fn data_valid(&self) -> bool {
+        self.data_iter_.as_ref().is_some_and(|iter| iter.valid())
+    }
+
+    fn skip_empty_data_blocks_forward(&mut self) {
+        while !self.data_valid() {
+            // Move to next block
+            if !self.index_iter_.valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            self.index_iter_.next();
+            self.init_data_block();
+            if let Some(iter) = self.data_iter_.as_mut() {
+                iter.seek_to_first();
+            }
+        }
+    }
+
+    fn skip_empty_data_blocks_backward(&mut self) {
+        while !self.data_valid() {
+            // Move to previous block
+            if !self.index_iter_.valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            self.index_iter_.prev();
+            self.init_data_block();
+            if let Some(iter) = self.data_iter_.as_mut() {
+                iter.seek_to_last();
+            }
+        }
+    }
+
+    fn set_data_iterator(&mut self, data_iter: Option<Box<dyn Iterator>>) {
+        if let Some(iter) = self.data_iter_.take() {
+            self.save_error(iter.status());
+        }
+        self.data_iter_ = data_iter;
+    }
+
+    fn init_data_block(&mut self) {
+        if !self.index_iter_.valid() {
+            self.set_data_iterator(None);
+        } else {
+            let handle = self.index_iter_.value();
+            if self.data_iter_.is_some() && handle.data() == self.data_block_handle_.as_slice() {
+                // data_iter_ is already constructed with this iterator, so
+                // no need to change anything
+            } else {
+                let iter = (self.block_function_)(&self.options_, &handle);
+                self.data_block_handle_ = handle.data().to_vec();
+                self.set_data_iterator(Some(iter));
+            }
+        }
+    }
+
+    fn data_iter(&mut self) -> &mut Box<dyn Iterator> {
+        self.data_iter_.as_mut().expect("require valid")
+    }
+}
+
+impl Iterator for TwoLevelIterator {
+    fn valid(&self) -> bool {
+        self.data_valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index_iter_.seek_to_first();
+        self.init_data_block();
+        if let Some(iter) = self.data_iter_.as_mut() {
+            iter.seek_to_first();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index_iter_.seek_to_last();
+        self.init_data_block();
+        if let Some(iter) = self.data_iter_.as_mut() {
+            iter.seek_to_last();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.index_iter_.seek(target);
+        self.init_data_block();
+        if let Some(iter) = self.data_iter_.as_mut() {
+            iter.seek(target);
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn next(&mut self) {
+        debug_assert!(self.valid());
+        self.data_iter().next();
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn prev(&mut self) {
+        debug_assert!(self.valid());
+        self.data_iter().prev();
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        self.data_iter_.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        debug_assert!(self.valid());
+        self.data_iter_.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Status {
+        // It'd be nice if status() returned a const Status& instead of a Status
+        let s = self.index_iter_.status();
+        if !s.ok() {
+            return s;
+        }
+        match &self.data_iter_ {
+            Some(iter) if !iter.status().ok() => iter.status(),
+            _ => self.status_.clone(),
+        }
+    }
+
+    fn raw_block(&self) -> Option<RawBlock> {
+        self.data_iter_.as_ref().filter(|iter| iter.valid())?.raw_block()
+    }
+
+    fn skip_block(&mut self) {
+        self.data_iter().skip_block();
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn value_location(&self) -> Option<ValueLocation> {
+        debug_assert!(self.valid());
+        let location = self.data_iter_.as_ref()?.value_location()?;
+        match &self.value_locator_ {
+            Some(locator) => locator(&Slice::new(&self.data_block_handle_), location),
+            None => Some(location),
+        }
+    }
+}
+
+/// Return a new two level iterator.  A two-level iterator contains an
+/// index iterator whose values point to a sequence of blocks where
+/// each block is itself a sequence of key,value pairs.  The returned
+/// two-level iterator yields the concatenation of all key/value pairs
+/// in the sequence of blocks.  Takes ownership of "index_iter".
+///
+/// Uses a supplied function to convert an index_iter value into
+/// an iterator over the contents of the corresponding block.
+pub(crate) fn new_two_level_iterator(index_iter: Box<dyn Iterator>, block_function: BlockFunction,
+                                     options: &ReadOptions) -> Box<dyn Iterator> {
+    Box::new(TwoLevelIterator {
+        block_function_: block_function,
+        options_: options.clone(),
+        status_: Status::new_ok(),
+        index_iter_: index_iter,
+        data_iter_: None,
+        data_block_handle_: Vec::new(),
+        value_locator_: None,
+    })
+}
+
+/// Like new_two_level_iterator(), with "value_locator" completing the
+/// value locations of the block iterators.
+pub(crate) fn new_located_two_level_iterator(index_iter: Box<dyn Iterator>, block_function: BlockFunction,
+                                             value_locator: ValueLocator, options: &ReadOptions) -> Box<dyn Iterator> {
+    Box::new(TwoLevelIterator {
+        block_function_: block_function,
+        options_: options.clone(),
+        status_: Status::new_ok(),
+        index_iter_: index_iter,
+        data_iter_: None,
+        data_block_handle_: Vec::new(),
+        value_locator_: Some(value_locator),
+    })
+}
@@ -1,8 +1,14 @@
 pub(crate) mod crc32c;
 pub(crate) mod coding;
 pub(crate) mod env;
+pub(crate) mod env_posix;
 pub(crate) mod comparator;
 pub(crate) mod arena;
+pub(crate) mod bloom;
 pub(crate) mod random;
+pub(crate) mod rate_limiter;
 pub(crate) mod hash;
+pub(crate) mod interval_map;
+pub(crate) mod redact;
 pub(crate) mod testutil;
+mod unsafe_impl;
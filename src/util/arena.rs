@@ -1,14 +1,26 @@
-use std::{alloc::{Allocator, Global}, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
+use std::{alloc::Global, sync::{atomic::{AtomicUsize, Ordering}, Arc}};
 
+#[cfg(feature = "arena-canaries")]
+use super::unsafe_impl::canary;
+
+/// Tracks the memory of the allocations made through it; implements
+/// Allocator (in unsafe_impl.rs) by forwarding to the global allocator.
 #[derive(Clone)]
 pub(crate) struct Arena {
-    global_: Arc<Global>,
-    allocated_: Arc<AtomicUsize>,
+    pub(super) global_: Arc<Global>,
+    pub(super) allocated_: Arc<AtomicUsize>,
+    #[cfg(feature = "arena-canaries")]
+    pub(super) canaries_: Arc<canary::Registry>,
 }
 
 impl Arena {
     pub(crate) fn new() -> Self {
-        Self { global_: Arc::new(Global), allocated_: Arc::new(AtomicUsize::new(0)) }
+        Self {
+            global_: Arc::new(Global),
+            allocated_: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "arena-canaries")]
+            canaries_: Arc::new(canary::Registry::new()),
+        }
     }
 
     /// Returns an estimate of the total memory usage of data allocated
@@ -16,21 +28,28 @@ impl Arena {
     pub(crate) fn memory_usage(&self) -> usize {
         self.allocated_.load(Ordering::Relaxed)
     }
-}
 
-unsafe impl Allocator for Arena {
-    fn allocate(&self, layout: std::alloc::Layout) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
-        let ret = self.global_.allocate(layout)?;
-        self.allocated_.fetch_add(layout.size(), Ordering::Relaxed);
-        Ok(ret)
+    /// Verify the canaries around every live allocation.  Returns a
+    /// description of the first damaged allocation, if any.
+    #[cfg(feature = "arena-canaries")]
+    pub(crate) fn check_canaries(&self) -> Result<(), String> {
+        self.canaries_.check_all()
     }
 
-    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: std::alloc::Layout) {
-        self.global_.deallocate(ptr, layout);
-        self.allocated_.fetch_sub(layout.size(), Ordering::Relaxed);
+    /// Panics unless "ptr" points into a live allocation of this arena.
+    #[cfg(feature = "arena-canaries")]
+    pub(crate) fn assert_live<T: ?Sized>(&self, ptr: *const T) {
+        self.canaries_.assert_live(ptr as *const u8 as usize);
     }
 }
 
+/// Returns true if "bytes" looks like memory that was freed by an arena
+/// built with the "arena-canaries" feature.
+#[cfg(feature = "arena-canaries")]
+pub(crate) fn is_poisoned(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|&b| b == canary::POISON_BYTE)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,7 +68,7 @@ mod tests {
             assert_eq!(1, arena.memory_usage());
         }
         assert_eq!(0, arena.memory_usage());
-        
+
         {
             let _1 = Box::new_in(0u8, arena.clone());
             let _2 = Box::new_in(0u8, arena.clone());
@@ -59,4 +78,29 @@ mod tests {
         }
         assert_eq!(0, arena.memory_usage());
     }
+
+    #[cfg(feature = "arena-canaries")]
+    #[test]
+    fn canaries_test() {
+        let arena = Arena::new();
+        let small = Box::new_in(7u8, arena.clone());
+        let wide = Box::new_in(9u128, arena.clone());
+        assert_eq!(0, &*wide as *const u128 as usize % std::mem::align_of::<u128>());
+        assert_eq!(17, arena.memory_usage());
+        assert!(arena.check_canaries().is_ok());
+        arena.assert_live(&*small);
+        assert_eq!(7, *small);
+        assert_eq!(9, *wide);
+    }
+
+    #[cfg(feature = "arena-canaries")]
+    #[test]
+    #[should_panic(expected = "not a live allocation")]
+    fn use_after_free_test() {
+        let arena = Arena::new();
+        let boxed = Box::new_in(0u64, arena.clone());
+        let ptr = &*boxed as *const u64;
+        drop(boxed);
+        arena.assert_live(ptr);
+    }
 }
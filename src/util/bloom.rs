@@ -0,0 +1,200 @@
+use crate::{filter_policy::FilterPolicy, slice::Slice};
+
+use super::hash::leveldb_hash;
+
+fn bloom_hash(key: &Slice) -> u32 {
+    leveldb_hash(key.data(), 0xbc9f1d34)
+}
+
+pub(crate) struct BloomFilterPolicy {
+    bits_per_key_: usize,
+    k_: usize,
+}
+
+impl BloomFilterPolicy {
+    pub(crate) fn new(bits_per_key: usize) -> Self {
+        // We intentionally round down to reduce probing cost a little bit
+        let k = (bits_per_key as f64 * 0.69) as usize;  // 0.69 =~ ln(2)
+        Self { bits_per_key_: bits_per_key, k_: k.clamp(1, 30) }
+    }
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+    fn name(&self) -> &str {
+        "leveldb.BuiltinBloomFilter2"
+    }
+
+    fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>) {
+        // Compute bloom filter size (in both bits and bytes)
+        // For small n, we can see a very high false positive rate.  Fix it
+        // by enforcing a minimum bloom filter length.
+        let bits = (keys.len() * self.bits_per_key_).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let init_size = dst.len();
+        dst.resize(init_size + bytes, 0);
+        dst.push(self.k_ as u8);    // Remember # of probes in filter
+        let array = &mut dst[init_size..init_size + bytes];
+        for key in keys {
+            // Use double-hashing to generate a sequence of hash values.
+            // See analysis in [Kirsch,Mitzenmacher 2006].
+            let mut h = bloom_hash(key);
+            let delta = h.rotate_right(17);  // Rotate right 17 bits
+            for _ in 0..self.k_ {
+                let bitpos = h as usize % bits;
+                array[bitpos / 8] |= 1 << (bitpos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+    }
+
+    fn key_may_match(&self, key: &Slice, bloom_filter: &Slice) -> bool {
+        let array = bloom_filter.data();
+        if array.len() < 2 {
+            return false;
+        }
+        let bits = (array.len() - 1) * 8;
+
+        // Use the encoded k so that we can read filters generated by
+        // bloom filters created using different parameters.
+        let k = array[array.len() - 1];
+        if k > 30 {
+            // Reserved for potentially new encodings for short bloom filters.
+            // Consider it a match.
+            return true;
+        }
+
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_right(17);  // Rotate right 17 bits
+        for _ in 0..k {
+            let bitpos = h as usize % bits;
+            if array[bitpos / 8] & (1 << (bitpos % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::coding::encode_fixed32;
+
+    use super::*;
+
+    struct BloomTest {
+        policy_: BloomFilterPolicy,
+        filter_: Vec<u8>,
+        keys_: Vec<Vec<u8>>,
+    }
+
+    impl BloomTest {
+        fn new() -> Self {
+            Self { policy_: BloomFilterPolicy::new(10), filter_: Vec::new(), keys_: Vec::new() }
+        }
+
+        fn reset(&mut self) {
+            self.keys_.clear();
+            self.filter_.clear();
+        }
+
+        fn add(&mut self, s: &[u8]) {
+            self.keys_.push(s.to_vec());
+        }
+
+        fn build(&mut self) {
+            let key_slices: Vec<Slice> = self.keys_.iter().map(|k| Slice::new(k)).collect();
+            self.filter_.clear();
+            self.policy_.create_filter(&key_slices, &mut self.filter_);
+            self.keys_.clear();
+        }
+
+        fn filter_size(&self) -> usize {
+            self.filter_.len()
+        }
+
+        fn matches(&mut self, s: &[u8]) -> bool {
+            if !self.keys_.is_empty() {
+                self.build();
+            }
+            self.policy_.key_may_match(&Slice::new(s), &Slice::new(&self.filter_))
+        }
+
+        fn false_positive_rate(&mut self) -> f64 {
+            let mut result = 0;
+            for i in 0..10000u32 {
+                if self.matches(&encode_fixed32(i + 1_000_000_000)) {
+                    result += 1;
+                }
+            }
+            result as f64 / 10000.0
+        }
+    }
+
+    fn next_length(length: u32) -> u32 {
+        if length < 10 {
+            length + 1
+        } else if length < 100 {
+            length + 10
+        } else if length < 1000 {
+            length + 100
+        } else {
+            length + 1000
+        }
+    }
+
+    #[test]
+    fn empty_filter_test() {
+        let mut t = BloomTest::new();
+        assert!(!t.matches(b"hello"));
+        assert!(!t.matches(b"world"));
+    }
+
+    #[test]
+    fn small_test() {
+        let mut t = BloomTest::new();
+        t.add(b"hello");
+        t.add(b"world");
+        assert!(t.matches(b"hello"));
+        assert!(t.matches(b"world"));
+        assert!(!t.matches(b"x"));
+        assert!(!t.matches(b"foo"));
+    }
+
+    #[test]
+    fn varying_lengths_test() {
+        let mut t = BloomTest::new();
+        // Count number of filters that significantly exceed the false positive rate
+        let mut mediocre_filters = 0;
+        let mut good_filters = 0;
+
+        let mut length = 1;
+        while length <= 10000 {
+            t.reset();
+            for i in 0..length {
+                t.add(&encode_fixed32(i));
+            }
+            t.build();
+
+            assert!(t.filter_size() <= (length as usize * 10 / 8) + 40, "{}", length);
+
+            // All added keys must match
+            for i in 0..length {
+                assert!(t.matches(&encode_fixed32(i)), "Length {}; key {}", length, i);
+            }
+
+            // Check false positive rate
+            let rate = t.false_positive_rate();
+            assert!(rate <= 0.02, "{}", rate);   // Must not be over 2%
+            if rate > 0.0125 {
+                mediocre_filters += 1;  // Allowed, but not too often
+            } else {
+                good_filters += 1;
+            }
+            length = next_length(length);
+        }
+        assert!(mediocre_filters <= good_filters / 5);
+    }
+}
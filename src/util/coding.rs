@@ -31,7 +31,7 @@ pub(crate) fn put_length_prefixed_slice(dst: &mut Vec<u8>, value: &Slice) {
     dst.extend(value.data());
 }
 
-pub(crate) fn get_length_prefixed_slice<'a>(input: &'a mut Slice) -> Option<Slice<'a>> {
+pub(crate) fn get_length_prefixed_slice<'a>(input: &mut Slice<'a>) -> Option<Slice<'a>> {
     match get_varint64(input) {
         Some(len) => {
             if input.size() >= len as usize {
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use once_cell::sync::Lazy;
 
-use crate::comparator::Comparator;
+use crate::{comparator::Comparator, slice::Slice};
 
 pub(crate) static BYTEWISE_COMPARATOR: Lazy<Arc<dyn Comparator + Sync + Send>> = Lazy::new(|| {
     Arc::new(BytewiseComparator) as Arc<dyn Comparator + Sync + Send>
@@ -16,4 +16,63 @@ impl Comparator for BytewiseComparator {
     fn compare(&self, a: &crate::slice::Slice, b: &crate::slice::Slice) -> std::cmp::Ordering {
         a.compare(b)
     }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &Slice) {
+        // Find length of common prefix
+        let limit = limit.data();
+        let min_length = start.len().min(limit.len());
+        let diff_index = start.iter().zip(limit).take_while(|(a, b)| a == b).count();
+
+        if diff_index >= min_length {
+            // Do not shorten if one string is a prefix of the other
+        } else {
+            let diff_byte = start[diff_index];
+            if diff_byte < 0xff && diff_byte + 1 < limit[diff_index] {
+                start[diff_index] += 1;
+                start.truncate(diff_index + 1);
+                debug_assert!(self.compare(&Slice::new(start), &Slice::new(limit)) == std::cmp::Ordering::Less);
+            }
+        }
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>) {
+        // Find first character that can be incremented
+        if let Some(i) = key.iter().position(|&byte| byte != 0xff) {
+            key[i] += 1;
+            key.truncate(i + 1);
+        }
+        // *key is a run of 0xffs.  Leave it alone.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn separator(start: &str, limit: &str) -> Vec<u8> {
+        let mut start = start.as_bytes().to_vec();
+        BytewiseComparator.find_shortest_separator(&mut start, &Slice::new(limit.as_bytes()));
+        start
+    }
+
+    fn successor(key: &[u8]) -> Vec<u8> {
+        let mut key = key.to_vec();
+        BytewiseComparator.find_short_successor(&mut key);
+        key
+    }
+
+    #[test]
+    fn shortest_separator_test() {
+        assert_eq!(b"b".to_vec(), separator("abcd", "c"));
+        assert_eq!(b"abcd".to_vec(), separator("abcd", "abce"));   // Adjacent bytes
+        assert_eq!(b"abc".to_vec(), separator("abc", "abcd"));     // Prefix
+        assert_eq!(b"foo".to_vec(), separator("foo", "foo"));
+    }
+
+    #[test]
+    fn short_successor_test() {
+        assert_eq!(b"b".to_vec(), successor(b"abc"));
+        assert_eq!(vec![0xff, 0xff, 0x01], successor(&[0xff, 0xff, 0x00, 0x10]));
+        assert_eq!(vec![0xff, 0xff], successor(&[0xff, 0xff]));
+    }
 }
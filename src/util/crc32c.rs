@@ -24,13 +24,13 @@ pub(crate) fn value(data: &[u8]) -> u32 {
 #[inline]
 pub(crate) fn mask(crc: u32) -> u32 {
     // Rotate right by 15 bits and add a constant.
-    (crc >> 15 | crc << 17) + MASK_DELTA
+    (crc >> 15 | crc << 17).wrapping_add(MASK_DELTA)
 }
 
 /// Return the crc whose masked representation is masked_crc.
 #[inline]
 pub(crate) fn unmask(masked_crc: u32) -> u32 {
-    let rot = masked_crc - MASK_DELTA;
+    let rot = masked_crc.wrapping_sub(MASK_DELTA);
     rot >> 17 | rot << 15
 }
 
@@ -1,12 +1,121 @@
-use std::rc::Rc;
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex};
 
-use crate::{env::Env, slice::Slice, status::Status};
+use crate::{env::{Env, WritableFile}, slice::Slice, status::Status, util::crc32c};
 
-pub(crate) fn write_string_to_file_sync(env: Rc<dyn Env>, data: &Slice, fname: &str) -> Status {
+/// Counts a file as open in a counter of its Env until dropped, for
+/// Env::open_file_limit().
+pub(crate) struct OpenFileGuard(Arc<AtomicU64>);
+
+impl OpenFileGuard {
+    pub(crate) fn new(counter: &Arc<AtomicU64>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for OpenFileGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A WritableFile that keeps the first error sync() or close() of the
+/// file it wraps returns, and fails every later call with it, as the
+/// WritableFile contract asks.  Env implementations whose files may
+/// report a failed sync as successful when retried wrap them in this.
+pub(crate) struct StickyErrorFile {
+    file_: Arc<dyn WritableFile>,
+    error_: Mutex<Option<Status>>,
+}
+
+impl StickyErrorFile {
+    pub(crate) fn wrap(file: Arc<dyn WritableFile>) -> Arc<dyn WritableFile> {
+        Arc::new(Self { file_: file, error_: Mutex::new(None) })
+    }
+
+    /// Run "op" on the file unless an earlier sync or close failed, and
+    /// keep its error if it is one of those ("sticky").
+    fn run(&self, sticky: bool, op: impl FnOnce(&dyn WritableFile) -> Status) -> Status {
+        let mut error = self.error_.lock().unwrap();
+        if let Some(s) = error.as_ref() {
+            return s.clone();
+        }
+        let s = op(self.file_.as_ref());
+        if sticky && !s.ok() {
+            *error = Some(s.clone());
+        }
+        s
+    }
+}
+
+impl WritableFile for StickyErrorFile {
+    fn append(&self, data: &Slice) -> Status { self.run(false, |file| file.append(data)) }
+    fn close(&self) -> Status { self.run(true, |file| file.close()) }
+    fn flush(&self) -> Status { self.run(false, |file| file.flush()) }
+    fn sync(&self) -> Status { self.run(true, |file| file.sync()) }
+    fn preallocate(&self, offset: u64, len: u64) -> Status { self.run(false, |file| file.preallocate(offset, len)) }
+}
+
+pub(crate) fn write_string_to_file_sync(env: Arc<dyn Env>, data: &Slice, fname: &str) -> Status {
     do_write_string_to_file(env, data, fname, true)
 }
 
-fn do_write_string_to_file(env: Rc<dyn Env>, data: &Slice, fname: &str, should_sync: bool) -> Status {
+/// A utility routine: read contents of named file into a string.
+pub(crate) fn read_file_to_string(env: Arc<dyn Env>, fname: &str) -> Result<String, Status> {
+    let mut file = env.new_sequential_file(fname)?;
+    const BUFFER_SIZE: usize = 8192;
+    let mut data = Vec::new();
+    loop {
+        let fragment = file.read(BUFFER_SIZE)?;
+        if fragment.is_empty() {
+            break;
+        }
+        data.extend_from_slice(&fragment);
+    }
+    String::from_utf8(data).map_err(|_| Status::corruption(fname, "file contents are not valid UTF-8"))
+}
+
+/// A utility routine: return the crc32c of the whole contents of the
+/// named file, as TableBuilder::file_checksum() computes it, and the
+/// number of bytes read.
+pub(crate) fn checksum_file(env: Arc<dyn Env>, fname: &str) -> Result<(u32, u64), Status> {
+    let mut file = env.new_sequential_file(fname)?;
+    const BUFFER_SIZE: usize = 8192;
+    let mut crc = 0;
+    let mut size = 0;
+    loop {
+        let fragment = file.read(BUFFER_SIZE)?;
+        if fragment.is_empty() {
+            break;
+        }
+        crc = crc32c::extend(crc, &fragment);
+        size += fragment.len() as u64;
+    }
+    Ok((crc, size))
+}
+
+/// A utility routine: write the first "size" bytes of the named file
+/// "src" to a new file "target", and sync it.
+pub(crate) fn copy_file(env: Arc<dyn Env>, src: &str, target: &str, size: u64) -> Status {
+    let mut file = match env.new_sequential_file(src) {
+        Ok(file) => file,
+        Err(s) => { return s; },
+    };
+    const BUFFER_SIZE: u64 = 8192;
+    let mut data = Vec::new();
+    while (data.len() as u64) < size {
+        match file.read(BUFFER_SIZE.min(size - data.len() as u64) as usize) {
+            Ok(fragment) if fragment.is_empty() => {
+                return Status::io_error(src, "file is shorter than expected");
+            },
+            Ok(fragment) => data.extend_from_slice(&fragment),
+            Err(s) => { return s; },
+        }
+    }
+    write_string_to_file_sync(env, &Slice::new(&data), target)
+}
+
+fn do_write_string_to_file(env: Arc<dyn Env>, data: &Slice, fname: &str, should_sync: bool) -> Status {
     let mut s = Status::new_ok();
     match env.new_writable_file(fname) {
         Ok(file) => {
@@ -0,0 +1,562 @@
+use std::{collections::{HashMap, VecDeque}, fs::{self, File, OpenOptions, TryLockError}, io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write}, os::unix::fs::FileExt, sync::{atomic::{AtomicU64, Ordering}, Arc, Condvar, Mutex, PoisonError}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{env::{Env, FileLock, RandomAccessFile, SequentialFile, WritableFile}, slice::Slice, status::{Status, SubCode}};
+
+use super::env::{OpenFileGuard, StickyErrorFile};
+#[cfg(target_os = "linux")]
+use super::unsafe_impl::{fallocate_keep_size, soft_open_file_limit};
+
+const WRITABLE_FILE_BUFFER_SIZE: usize = 65536;
+
+// errno of an open() that failed because the process has as many files
+// open as it may; the same on Linux, macOS and the BSDs.
+const EMFILE: i32 = 24;
+
+pub(crate) fn default_env() -> Arc<dyn Env> {
+    Arc::new(PosixEnv::new())
+}
+
+/// The Status for "err", from an operation on the file "fname".
+fn posix_error(fname: &str, err: &io::Error) -> Status {
+    let s = if err.kind() == ErrorKind::NotFound {
+        Status::not_found(fname, &err.to_string())
+    } else if err.raw_os_error() == Some(EMFILE) {
+        Status::io_error(fname, &err.to_string()).with_subcode(SubCode::TooManyOpenFiles)
+    } else {
+        Status::io_error(fname, &err.to_string())
+    };
+    s.with_filename(fname)
+}
+
+/// Implements sequential read access in a file using read().
+struct PosixSequentialFile {
+    file_: File,
+    filename_: String,
+    _open_: OpenFileGuard,
+}
+
+impl SequentialFile for PosixSequentialFile {
+    fn read(&mut self, n: usize) -> Result<Vec<u8>, Status> {
+        let mut result = Vec::with_capacity(n);
+        match (&mut self.file_).take(n as u64).read_to_end(&mut result) {
+            Ok(_) => Ok(result),
+            Err(e) => Err(posix_error(&self.filename_, &e)),
+        }
+    }
+
+    fn skip(&mut self, n: u64) -> Status {
+        match self.file_.seek(SeekFrom::Current(n as i64)) {
+            Ok(_) => Status::new_ok(),
+            Err(e) => posix_error(&self.filename_, &e),
+        }
+    }
+}
+
+/// Implements random read access in a file using pread().
+struct PosixRandomAccessFile {
+    file_: File,
+    filename_: String,
+    _open_: OpenFileGuard,
+}
+
+impl RandomAccessFile for PosixRandomAccessFile {
+    fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+        let mut result = vec![0u8; n];
+        let mut read = 0;
+        while read < n {
+            match self.file_.read_at(&mut result[read..], offset + read as u64) {
+                Ok(0) => { break; },
+                Ok(r) => { read += r; },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => { return Err(posix_error(&self.filename_, &e)); },
+            }
+        }
+        result.truncate(read);
+        Ok(result)
+    }
+}
+
+struct PosixWritableFile {
+    // None once the file has been closed.
+    file_: Mutex<Option<BufWriter<File>>>,
+    filename_: String,
+    _open_: OpenFileGuard,
+}
+
+impl PosixWritableFile {
+    fn with_file(&self, f: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> Status {
+        match self.file_.lock().unwrap().as_mut() {
+            Some(file) => match f(file) {
+                Ok(()) => Status::new_ok(),
+                Err(e) => posix_error(&self.filename_, &e),
+            },
+            None => Status::io_error(&self.filename_, "file is closed"),
+        }
+    }
+}
+
+impl WritableFile for PosixWritableFile {
+    fn append(&self, data: &Slice) -> Status {
+        self.with_file(|file| file.write_all(data.data()))
+    }
+
+    fn close(&self) -> Status {
+        let s = self.with_file(|file| file.flush());
+        self.file_.lock().unwrap().take();
+        s
+    }
+
+    fn flush(&self) -> Status {
+        self.with_file(|file| file.flush())
+    }
+
+    fn sync(&self) -> Status {
+        self.with_file(|file| {
+            file.flush()?;
+            file.get_ref().sync_data()
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn preallocate(&self, offset: u64, len: u64) -> Status {
+        // FALLOC_FL_KEEP_SIZE reserves the blocks without extending the
+        // file, so readers never see the preallocated space.
+        self.with_file(|file| fallocate_keep_size(file.get_ref(), offset, len))
+    }
+}
+
+impl Drop for PosixWritableFile {
+    fn drop(&mut self) {
+        // Ignoring any potential errors
+        let _ = self.close();
+    }
+}
+
+struct PosixEnv {
+    // Files locked through this Env, with the open descriptor that holds
+    // the lock.  The lock is released when the descriptor is closed.
+    locks_: Mutex<HashMap<String, File>>,
+
+    // Files opened through this Env and not dropped yet, locks aside.
+    open_files_: Arc<AtomicU64>,
+
+    background_: Arc<BackgroundQueue>,
+}
+
+/// Work handed to Env::schedule(), run in FIFO order per label.
+/// Unlabelled work runs on a single background thread that is started
+/// on first use; each label gets a thread of its own, started when work
+/// for it arrives and exiting once that work has drained.
+struct BackgroundQueue {
+    state_: Mutex<BackgroundState>,
+    signal_: Condvar,   // Signalled when unlabelled work is queued or the Env is dropped
+}
+
+struct BackgroundState {
+    queue_: VecDeque<Box<dyn FnOnce() + Send>>,
+    started_: bool,
+    shutting_down_: bool,
+    labelled_: HashMap<String, VecDeque<Box<dyn FnOnce() + Send>>>,   // Only labels with a running thread
+}
+
+impl PosixEnv {
+    fn new() -> Self {
+        Self {
+            locks_: Mutex::new(HashMap::new()),
+            open_files_: Arc::new(AtomicU64::new(0)),
+            background_: Arc::new(BackgroundQueue {
+                state_: Mutex::new(BackgroundState { queue_: VecDeque::new(), started_: false, shutting_down_: false, labelled_: HashMap::new() }),
+                signal_: Condvar::new(),
+            }),
+        }
+    }
+
+    /// A file opened for writing.  A failed fsync() may clear the error
+    /// so that the next one succeeds without the data, so failures of
+    /// sync and close are made sticky.
+    fn writable_file(&self, fname: &str, file: File) -> Arc<dyn WritableFile> {
+        StickyErrorFile::wrap(Arc::new(PosixWritableFile {
+            file_: Mutex::new(Some(BufWriter::with_capacity(WRITABLE_FILE_BUFFER_SIZE, file))),
+            filename_: fname.to_string(),
+            _open_: OpenFileGuard::new(&self.open_files_),
+        }))
+    }
+}
+
+impl BackgroundQueue {
+    /// Run queued work until the queue is empty and the Env is gone.
+    fn background_thread_main(&self) {
+        loop {
+            let work = {
+                let mut state = self.state_.lock().unwrap_or_else(PoisonError::into_inner);
+                while state.queue_.is_empty() && !state.shutting_down_ {
+                    state = self.signal_.wait(state).unwrap_or_else(PoisonError::into_inner);
+                }
+                match state.queue_.pop_front() {
+                    Some(work) => work,
+                    None => return,     // Shutting down and nothing left to run
+                }
+            };
+            work();
+        }
+    }
+
+    /// Run work queued under "label" until there is none left.
+    fn labelled_thread_main(&self, label: &str) {
+        loop {
+            let work = {
+                let mut state = self.state_.lock().unwrap_or_else(PoisonError::into_inner);
+                match state.labelled_.get_mut(label).and_then(VecDeque::pop_front) {
+                    Some(work) => work,
+                    None => {
+                        // Later work for this label starts a new thread.
+                        state.labelled_.remove(label);
+                        return;
+                    },
+                }
+            };
+            work();
+        }
+    }
+}
+
+impl Drop for PosixEnv {
+    fn drop(&mut self) {
+        // Work already scheduled still runs; the thread exits afterwards.
+        let mut state = self.background_.state_.lock().unwrap_or_else(PoisonError::into_inner);
+        state.shutting_down_ = true;
+        self.background_.signal_.notify_all();
+    }
+}
+
+impl Env for PosixEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> {
+        match File::open(fname) {
+            Ok(file) => Ok(Box::new(PosixSequentialFile { file_: file, filename_: fname.to_string(), _open_: OpenFileGuard::new(&self.open_files_) })),
+            Err(e) => Err(posix_error(fname, &e)),
+        }
+    }
+
+    fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+        match File::open(fname) {
+            Ok(file) => Ok(Arc::new(PosixRandomAccessFile { file_: file, filename_: fname.to_string(), _open_: OpenFileGuard::new(&self.open_files_) })),
+            Err(e) => Err(posix_error(fname, &e)),
+        }
+    }
+
+    fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        match File::create(fname) {
+            Ok(file) => Ok(self.writable_file(fname, file)),
+            Err(e) => Err(posix_error(fname, &e)),
+        }
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        match OpenOptions::new().append(true).create(true).open(fname) {
+            Ok(file) => Ok(self.writable_file(fname, file)),
+            Err(e) => Err(posix_error(fname, &e)),
+        }
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        fs::metadata(fname).is_ok()
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> {
+        let entries = fs::read_dir(dir).map_err(|e| posix_error(dir, &e))?;
+        let mut result = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| posix_error(dir, &e))?;
+            result.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(result)
+    }
+
+    fn remove_file(&self, fname: &str) -> Status {
+        match fs::remove_file(fname) {
+            Ok(()) => Status::new_ok(),
+            Err(e) => posix_error(fname, &e),
+        }
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Status> {
+        match fs::metadata(fname) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) => Err(posix_error(fname, &e)),
+        }
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), Status> {
+        fs::create_dir(dirname).map_err(|e| posix_error(dirname, &e))
+    }
+
+    fn remove_dir(&self, dirname: &str) -> Status {
+        match fs::remove_dir(dirname) {
+            Ok(()) => Status::new_ok(),
+            Err(e) => posix_error(dirname, &e),
+        }
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Status {
+        match fs::rename(src, target) {
+            Ok(()) => Status::new_ok(),
+            Err(e) => posix_error(src, &e),
+        }
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Status {
+        match fs::hard_link(src, target) {
+            Ok(()) => Status::new_ok(),
+            Err(e) => posix_error(src, &e),
+        }
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<FileLock, Status> {
+        let mut locks = self.locks_.lock().unwrap();
+        if locks.contains_key(fname) {
+            return Err(Status::busy(&format!("lock {}", fname), "already held by process").with_subcode(SubCode::LockHeld).with_filename(fname));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(fname).map_err(|e| posix_error(fname, &e))?;
+        // flock() locks belong to the open file description, so this also
+        // fails if another Env in the same process holds the lock.
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                return Err(Status::busy(&format!("lock {}", fname), "held by another process").with_subcode(SubCode::LockHeld).with_filename(fname));
+            },
+            Err(TryLockError::Error(e)) => {
+                return Err(Status::io_error(&format!("lock {}", fname), &e.to_string()).with_filename(fname));
+            },
+        }
+        locks.insert(fname.to_string(), file);
+        Ok(FileLock::new(fname))
+    }
+
+    fn unlock_file(&self, lock: FileLock) -> Status {
+        match self.locks_.lock().unwrap().remove(lock.name()) {
+            Some(file) => match file.unlock() {
+                Ok(()) => Status::new_ok(),
+                Err(e) => Status::io_error(&format!("unlock {}", lock.name()), &e.to_string()),
+            },
+            None => Status::io_error(&format!("unlock {}", lock.name()), "not locked by this Env"),
+        }
+    }
+
+    fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) {
+        let mut state = self.background_.state_.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(label) = label {
+            // Queue behind the label's thread, or start one.
+            if let Some(queue) = state.labelled_.get_mut(label) {
+                queue.push_back(work);
+                return;
+            }
+            let background = self.background_.clone();
+            let owned = label.to_string();
+            match thread::Builder::new().name(format!("rucksdb-bg-{}", label)).spawn(move || background.labelled_thread_main(&owned)) {
+                Ok(_) => { state.labelled_.insert(label.to_string(), VecDeque::from([work])); },
+                Err(_) => {
+                    drop(state);
+                    work();
+                },
+            }
+            return;
+        }
+
+        // Start the background thread, if we haven't done so already.
+        if !state.started_ {
+            let background = self.background_.clone();
+            match thread::Builder::new().name("rucksdb-bg".to_string()).spawn(move || background.background_thread_main()) {
+                Ok(_) => state.started_ = true,
+                Err(_) => {
+                    // Without a background thread, run the work here.
+                    drop(state);
+                    work();
+                    return;
+                },
+            }
+        }
+        state.queue_.push_back(work);
+        self.background_.signal_.notify_one();
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_file_limit(&self) -> Option<(u64, u64)> {
+        let open = self.open_files_.load(Ordering::Relaxed) + self.locks_.lock().unwrap().len() as u64;
+        Some((open, soft_open_file_limit().ok()?))
+    }
+
+    fn now_micros(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        std::thread::sleep(Duration::from_micros(micros));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rucksdb-env_posix-{}-{}-{}",
+            name, std::process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn read_write_test() {
+        let env = default_env();
+        let dir = test_dir("read_write");
+        let fname = format!("{}/f", dir);
+
+        let file = env.new_writable_file(&fname).unwrap();
+        assert!(file.append(&Slice::new(b"hello ")).ok());
+        assert!(file.append(&Slice::new(b"world")).ok());
+        assert!(file.sync().ok());
+        assert!(file.close().ok());
+        assert!(!file.append(&Slice::new(b"x")).ok());
+        assert_eq!(11, env.get_file_size(&fname).unwrap());
+
+        let mut seq = env.new_sequential_file(&fname).unwrap();
+        assert_eq!(b"hello".to_vec(), seq.read(5).unwrap());
+        assert!(seq.skip(1).ok());
+        assert_eq!(b"world".to_vec(), seq.read(1000).unwrap());
+        assert!(seq.read(1000).unwrap().is_empty());
+
+        let rand = env.new_random_access_file(&fname).unwrap();
+        assert_eq!(b"world".to_vec(), rand.read(6, 5).unwrap());
+        assert_eq!(b"d".to_vec(), rand.read(10, 100).unwrap());
+        assert!(rand.read(100, 5).unwrap().is_empty());
+
+        let file = env.new_appendable_file(&fname).unwrap();
+        assert!(file.append(&Slice::new(b"!")).ok());
+        assert!(file.close().ok());
+        assert_eq!(b"world!".to_vec(), rand.read(6, 100).unwrap());
+
+        let link = format!("{}/link", dir);
+        assert!(env.link_file(&fname, &link).ok());
+        assert!(!env.link_file(&fname, &link).ok());
+        assert!(env.remove_file(&fname).ok());
+        assert_eq!(12, env.get_file_size(&link).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_operations_test() {
+        let env = default_env();
+        let dir = test_dir("file_operations");
+        let f = format!("{}/f", dir);
+        let g = format!("{}/g", dir);
+
+        assert!(env.create_dir(&dir).is_err());
+        assert!(!env.file_exists(&f));
+        assert!(env.get_file_size(&f).unwrap_err().is_not_found());
+        assert!(env.new_sequential_file(&f).err().unwrap().is_not_found());
+        assert!(env.get_children(&dir).unwrap().is_empty());
+
+        env.new_writable_file(&f).unwrap();
+        assert!(env.file_exists(&f));
+        assert_eq!(vec!["f".to_string()], env.get_children(&dir).unwrap());
+
+        assert!(env.rename_file(&f, &g).ok());
+        assert!(!env.file_exists(&f));
+        assert!(env.file_exists(&g));
+
+        assert!(env.remove_file(&g).ok());
+        assert!(!env.remove_file(&g).ok());
+        assert!(env.get_children(&dir).unwrap().is_empty());
+
+        assert!(env.remove_dir(&dir).ok());
+        assert!(!env.file_exists(&dir));
+        assert!(!env.remove_dir(&dir).ok());
+    }
+
+    #[test]
+    fn schedule_test() {
+        let env = default_env();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let main_thread = thread::current().id();
+        for i in 0..100 {
+            let sender = sender.clone();
+            env.schedule(None, Box::new(move || {
+                sender.send((i, thread::current().id())).unwrap();
+            }));
+        }
+
+        // Work runs in order, on one thread that is not the caller's.
+        let ran: Vec<_> = (0..100).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!((0..100).collect::<Vec<_>>(), ran.iter().map(|&(i, _)| i).collect::<Vec<_>>());
+        assert!(ran.iter().all(|&(_, id)| id == ran[0].1 && id != main_thread));
+
+        // Work scheduled before the Env is dropped still runs.
+        let sender2 = sender.clone();
+        env.schedule(None, Box::new(move || {
+            thread::sleep(Duration::from_millis(10));
+            sender2.send((100, thread::current().id())).unwrap();
+        }));
+        drop(env);
+        assert_eq!(100, receiver.recv().unwrap().0);
+    }
+
+    #[test]
+    fn schedule_labelled_test() {
+        let env = default_env();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for i in 0..100 {
+            let sender = sender.clone();
+            env.schedule(Some("schedule_test"), Box::new(move || {
+                sender.send((i, thread::current().name().map(str::to_string))).unwrap();
+            }));
+        }
+
+        // A label's work runs in order, on a thread named after it.
+        let ran: Vec<_> = (0..100).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!((0..100).collect::<Vec<_>>(), ran.iter().map(|(i, _)| *i).collect::<Vec<_>>());
+        assert!(ran.iter().all(|(_, name)| name.as_deref() == Some("rucksdb-bg-schedule_test")));
+
+        // Labels do not wait on each other's work.
+        let (blocked_sender, blocked) = std::sync::mpsc::channel::<()>();
+        env.schedule(Some("blocked"), Box::new(move || { blocked.recv().unwrap(); }));
+        let sender2 = sender.clone();
+        env.schedule(Some("other"), Box::new(move || {
+            sender2.send((100, thread::current().name().map(str::to_string))).unwrap();
+        }));
+        assert_eq!((100, Some("rucksdb-bg-other".to_string())), receiver.recv().unwrap());
+        blocked_sender.send(()).unwrap();
+
+        // Once a label's thread has exited, new work for it starts another.
+        thread::sleep(Duration::from_millis(10));
+        env.schedule(Some("other"), Box::new(move || {
+            sender.send((101, thread::current().name().map(str::to_string))).unwrap();
+        }));
+        assert_eq!((101, Some("rucksdb-bg-other".to_string())), receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn lock_test() {
+        let env = default_env();
+        let dir = test_dir("lock");
+        let fname = format!("{}/LOCK", dir);
+
+        let lock = env.lock_file(&fname).unwrap();
+        // The same process cannot take the lock twice, even through another Env.
+        assert!(env.lock_file(&fname).err().unwrap().is_busy());
+        assert!(default_env().lock_file(&fname).err().unwrap().is_busy());
+
+        assert!(env.unlock_file(lock).ok());
+        let lock = env.lock_file(&fname).unwrap();
+        assert!(env.unlock_file(lock).ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-//! The wrapper of murmur3 hash function.
+//! Hash functions: a wrapper of murmur3, and a port of leveldb's own.
 
 use std::io::Cursor;
 use murmur3::murmur3_32;
@@ -6,3 +6,57 @@ use murmur3::murmur3_32;
 pub(crate) fn hash(data: &[u8], seed: u32) -> u32 {
     murmur3_32(&mut Cursor::new(data), seed).unwrap()
 }
+
+/// The hash function of leveldb (similar to murmur hash).  Anything
+/// persisted in a leveldb-compatible format, such as the builtin bloom
+/// filters, must be computed with this one.
+pub(crate) fn leveldb_hash(data: &[u8], seed: u32) -> u32 {
+    const M: u32 = 0xc6a4a793;
+    const R: u32 = 24;
+    let mut h = seed ^ (data.len() as u32).wrapping_mul(M);
+
+    // Pick up four bytes at a time
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let w = u32::from_le_bytes(chunk.try_into().unwrap());
+        h = h.wrapping_add(w).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    // Pick up remaining bytes
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate() {
+            h = h.wrapping_add((b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_unsigned_issue_test() {
+        let data1 = [0x62];
+        let data2 = [0xc3, 0x97];
+        let data3 = [0xe2, 0x99, 0xa5];
+        let data4 = [0xe1, 0x80, 0xb9, 0x32];
+        let data5 = [
+            0x01, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00,
+            0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x18, 0x28, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        assert_eq!(leveldb_hash(&[], 0xbc9f1d34), 0xbc9f1d34);
+        assert_eq!(leveldb_hash(&data1, 0xbc9f1d34), 0xef1345c4);
+        assert_eq!(leveldb_hash(&data2, 0xbc9f1d34), 0x5b663814);
+        assert_eq!(leveldb_hash(&data3, 0xbc9f1d34), 0x323c078f);
+        assert_eq!(leveldb_hash(&data4, 0xbc9f1d34), 0xed21633a);
+        assert_eq!(leveldb_hash(&data5, 0x12345678), 0xf333dabb);
+    }
+}
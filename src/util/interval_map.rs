@@ -0,0 +1,165 @@
+//! IntervalMap maps disjoint, half-open key ranges [begin, end) to values.
+//! Ranges are ordered by a user supplied comparator and kept sorted by
+//! their start key, so lookups are a binary search.
+//!
+//! Not thread-safe: callers must provide their own synchronization.
+
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{comparator::Comparator, slice::Slice};
+
+struct Interval<V> {
+    begin_: Vec<u8>,
+    end_: Vec<u8>,
+    value_: V,
+}
+
+pub(crate) struct IntervalMap<V> {
+    comparator_: Arc<dyn Comparator>,
+    // Sorted by begin_.  Since the intervals are disjoint, they are
+    // sorted by end_ as well.
+    intervals_: Vec<Interval<V>>,
+}
+
+impl<V> IntervalMap<V> {
+    pub(crate) fn new(comparator: Arc<dyn Comparator>) -> Self {
+        Self { comparator_: comparator, intervals_: Vec::new() }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.intervals_.is_empty()
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.comparator_.compare(&Slice::new(a), &Slice::new(b))
+    }
+
+    /// Return the value of the interval containing "key", if any.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&V> {
+        // Intervals in [0, idx) start at or before key; only the last of
+        // them can contain it.
+        let idx = self.intervals_.partition_point(|iv| self.compare(&iv.begin_, key) != Ordering::Greater);
+        match idx.checked_sub(1).map(|i| &self.intervals_[i]) {
+            Some(iv) if self.compare(key, &iv.end_) == Ordering::Less => Some(&iv.value_),
+            _ => None,
+        }
+    }
+
+    /// Return the value of some interval overlapping [begin, end), if any.
+    /// REQUIRES: begin < end
+    pub(crate) fn find_overlap(&self, begin: &[u8], end: &[u8]) -> Option<&V> {
+        let idx = self.intervals_.partition_point(|iv| self.compare(&iv.begin_, end) == Ordering::Less);
+        match idx.checked_sub(1).map(|i| &self.intervals_[i]) {
+            Some(iv) if self.compare(&iv.end_, begin) == Ordering::Greater => Some(&iv.value_),
+            _ => None,
+        }
+    }
+
+    /// Return the values of the intervals overlapping [begin, end), in
+    /// key order.  Nothing overlaps an empty range.
+    pub(crate) fn overlapping<'a>(&'a self, begin: &[u8], end: &[u8]) -> impl std::iter::Iterator<Item = &'a V> + 'a {
+        // Intervals are sorted by both begin_ and end_.
+        let (first, last) = if self.compare(begin, end) == Ordering::Less {
+            (self.intervals_.partition_point(|iv| self.compare(&iv.end_, begin) != Ordering::Greater),
+             self.intervals_.partition_point(|iv| self.compare(&iv.begin_, end) == Ordering::Less))
+        } else {
+            (0, 0)
+        };
+        self.intervals_[first..last.max(first)].iter().map(|iv| &iv.value_)
+    }
+
+    /// Add [begin, end) -> value unless it overlaps an existing interval.
+    /// Returns true iff the interval was added.
+    /// REQUIRES: begin < end
+    pub(crate) fn insert(&mut self, begin: &[u8], end: &[u8], value: V) -> bool {
+        debug_assert!(self.compare(begin, end) == Ordering::Less);
+        if self.find_overlap(begin, end).is_some() {
+            return false;
+        }
+        let idx = self.intervals_.partition_point(|iv| self.compare(&iv.begin_, begin) == Ordering::Less);
+        self.intervals_.insert(idx, Interval { begin_: begin.to_vec(), end_: end.to_vec(), value_: value });
+        true
+    }
+
+    /// Remove the interval starting at "begin" and return its value.
+    pub(crate) fn remove(&mut self, begin: &[u8]) -> Option<V> {
+        let idx = self.intervals_.partition_point(|iv| self.compare(&iv.begin_, begin) == Ordering::Less);
+        if idx < self.intervals_.len() && self.compare(&self.intervals_[idx].begin_, begin) == Ordering::Equal {
+            Some(self.intervals_.remove(idx).value_)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{comparator::bytewise_comparator, util::random::Random};
+
+    use super::*;
+
+    #[test]
+    fn get_test() {
+        let mut map = IntervalMap::new(bytewise_comparator());
+        assert!(map.is_empty());
+        assert!(map.insert(b"c", b"f", 1));
+        assert!(map.insert(b"m", b"p", 2));
+        assert!(!map.is_empty());
+
+        assert_eq!(None, map.get(b"a"));
+        assert_eq!(Some(&1), map.get(b"c"));
+        assert_eq!(Some(&1), map.get(b"ezzz"));
+        assert_eq!(None, map.get(b"f"));     // end is exclusive
+        assert_eq!(None, map.get(b"g"));
+        assert_eq!(Some(&2), map.get(b"m"));
+        assert_eq!(None, map.get(b"z"));
+    }
+
+    #[test]
+    fn overlap_test() {
+        let mut map = IntervalMap::new(bytewise_comparator());
+        assert!(map.insert(b"c", b"f", 1));
+        assert!(!map.insert(b"a", b"d", 2));
+        assert!(!map.insert(b"e", b"g", 2));
+        assert!(!map.insert(b"a", b"z", 2));
+        assert!(!map.insert(b"d", b"e", 2));
+        // Touching intervals do not overlap
+        assert!(map.insert(b"a", b"c", 3));
+        assert!(map.insert(b"f", b"g", 4));
+        assert_eq!(Some(&1), map.find_overlap(b"e", b"f"));
+        assert_eq!(None, map.find_overlap(b"g", b"z"));
+        assert_eq!(vec![&3, &1], map.overlapping(b"b", b"f").collect::<Vec<_>>());
+        assert_eq!(vec![&3, &1, &4], map.overlapping(b"", b"z").collect::<Vec<_>>());
+        assert_eq!(0, map.overlapping(b"g", b"z").count());
+        assert_eq!(0, map.overlapping(b"d", b"d").count());
+
+        assert_eq!(Some(1), map.remove(b"c"));
+        assert_eq!(None, map.remove(b"c"));
+        assert!(map.insert(b"d", b"e", 5));
+        assert_eq!(Some(&5), map.get(b"d"));
+    }
+
+    #[test]
+    fn random_test() {
+        // Compare against a brute force check over single byte keys.
+        let mut rnd = Random::new(301);
+        let mut map = IntervalMap::new(bytewise_comparator());
+        let mut locked = [false; 64];
+        for _ in 0..2000 {
+            let a = rnd.uniform(64) as u8;
+            let b = a + 1 + rnd.uniform(64 - a as i32) as u8;
+            if rnd.one_in(2) {
+                let free = (a..b).all(|k| !locked[k as usize]);
+                assert_eq!(free, map.insert(&[a], &[b], b));
+                if free {
+                    (a..b).for_each(|k| locked[k as usize] = true);
+                }
+            } else if let Some(end) = map.remove(&[a]) {
+                (a..end).for_each(|k| locked[k as usize] = false);
+            }
+            for k in 0..64u8 {
+                assert_eq!(locked[k as usize], map.get(&[k]).is_some());
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Mutex};
+
+/// Paces writes to a number of bytes per second shared by every thread
+/// that writes through it.  The rate may be changed at any time; zero
+/// means no limit.
+pub(crate) struct RateLimiter {
+    bytes_per_second_: AtomicU64,
+
+    // When the bytes reserved so far will have been paid for, in
+    // Env::now_micros() time.
+    paid_until_micros_: Mutex<u64>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        Self { bytes_per_second_: AtomicU64::new(bytes_per_second), paid_until_micros_: Mutex::new(0) }
+    }
+
+    pub(crate) fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        self.bytes_per_second_.store(bytes_per_second, Ordering::Release);
+    }
+
+    /// Reserve "bytes" at "now_micros", and return how many microseconds
+    /// to wait before they are paid for.  Time spent under the rate is
+    /// not saved up for later bursts.
+    pub(crate) fn reserve(&self, bytes: u64, now_micros: u64) -> u64 {
+        let rate = self.bytes_per_second_.load(Ordering::Acquire);
+        if rate == 0 || bytes == 0 {
+            return 0;
+        }
+        let cost = (bytes as u128 * 1_000_000 / rate as u128).min(u64::MAX as u128) as u64;
+        let mut paid_until = self.paid_until_micros_.lock().unwrap();
+        *paid_until = (*paid_until).max(now_micros).saturating_add(cost);
+        *paid_until - now_micros
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_test() {
+        let limiter = RateLimiter::new(0);
+        assert_eq!(0, limiter.reserve(1 << 20, 1000));
+
+        // 1MB/s: each 1KB costs about a millisecond, and reservations
+        // made together queue up behind each other.
+        limiter.set_bytes_per_second(1 << 20);
+        assert_eq!(976, limiter.reserve(1 << 10, 1000));
+        assert_eq!(1952, limiter.reserve(1 << 10, 1000));
+        assert_eq!(1928, limiter.reserve(1 << 10, 2000));
+        assert_eq!(0, limiter.reserve(0, 2000));
+
+        // Idle time does not buy a burst.
+        assert_eq!(976, limiter.reserve(1 << 10, 1_000_000));
+
+        limiter.set_bytes_per_second(0);
+        assert_eq!(0, limiter.reserve(1 << 20, 1_000_000));
+    }
+}
@@ -0,0 +1,49 @@
+//! Rendering of user keys and values in diagnostic output, as
+//! Options::diagnostics_redaction asks.
+
+use crate::options::DiagnosticRedaction;
+
+use super::hash::hash;
+
+/// Render "bytes" (a user key or value) for a log line or an error
+/// message under "policy".
+pub(crate) fn redact(bytes: &[u8], policy: DiagnosticRedaction) -> String {
+    match policy {
+        DiagnosticRedaction::Full => String::from_utf8_lossy(bytes).into_owned(),
+        DiagnosticRedaction::TruncateTo(n) => {
+            let mut rendered = String::from_utf8_lossy(bytes).into_owned();
+            if rendered.len() > n {
+                let mut end = n;
+                while !rendered.is_char_boundary(end) {
+                    end -= 1;
+                }
+                rendered.truncate(end);
+                rendered.push_str("...");
+            }
+            rendered
+        },
+        DiagnosticRedaction::HashOnly => format!("<{} bytes, hash {:08x}>", bytes.len(), hash(bytes, 0xbc9f1d34)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_test() {
+        let key = b"user@example.com";
+        assert_eq!("user@example.com", redact(key, DiagnosticRedaction::Full));
+        assert_eq!("user...", redact(key, DiagnosticRedaction::TruncateTo(4)));
+        assert_eq!("user@example.com", redact(key, DiagnosticRedaction::TruncateTo(16)));
+        assert_eq!("...", redact(key, DiagnosticRedaction::TruncateTo(0)));
+        // Never more than n bytes, even if that cuts a character short
+        assert_eq!("ab...", redact("abé".as_bytes(), DiagnosticRedaction::TruncateTo(3)));
+        assert_eq!("\u{fffd}...", redact(&[0xff, 0xfe], DiagnosticRedaction::TruncateTo(3)));
+
+        let hashed = redact(key, DiagnosticRedaction::HashOnly);
+        assert!(!hashed.contains("user") && hashed.starts_with("<16 bytes, hash "), "{}", hashed);
+        assert_eq!(hashed, redact(key, DiagnosticRedaction::HashOnly));
+        assert_ne!(hashed, redact(b"user@example.org", DiagnosticRedaction::HashOnly));
+    }
+}
@@ -4,3 +4,27 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub(crate) fn random_seed() -> u32 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
 }
+
+#[cfg(test)]
+thread_local! {
+    /// Number of heap allocations made on this thread.
+    static ALLOCATIONS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: super::unsafe_impl::CountingAllocator = super::unsafe_impl::CountingAllocator;
+
+/// Note a heap allocation made on the current thread.
+#[cfg(test)]
+pub(super) fn count_allocation() {
+    // try_with: the thread may be tearing down its thread locals
+    let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+}
+
+/// Returns the number of heap allocations (and reallocations) made on
+/// the current thread so far.
+#[cfg(test)]
+pub(crate) fn allocations() -> u64 {
+    ALLOCATIONS.with(|n| n.get())
+}
@@ -0,0 +1,387 @@
+//! The only code of the crate allowed to use unsafe.  The crate root
+//! denies unsafe_code; everything here is wrapped in a safe interface
+//! whose invariants are spelled out next to it, and is covered by the
+//! tests at the bottom, which are kept small enough to run under miri:
+//!
+//!   cargo +nightly miri test --lib util::unsafe_impl
+//!
+//! (The "arena-canaries" feature adds the canary tests.)
+//!
+//! What lives here:
+//!
+//! - The Allocator impl of Arena, and the canary registry behind the
+//!   "arena-canaries" feature.
+//! - fallocate(2) for PosixWritableFile::preallocate.
+//! - getrlimit(2) for PosixEnv::open_file_limit.
+//! - The counting global allocator of the tests.
+
+#![allow(unsafe_code)]
+
+use std::{alloc::{AllocError, Allocator, Layout}, ptr::NonNull, sync::atomic::Ordering};
+
+use super::arena::Arena;
+
+// Invariants: every block handed out is a block of the global allocator
+// (or of the canary registry) with exactly "layout", and memory_usage()
+// counts the requested size of each live block.  Clones of an arena
+// share the global allocator and the counter, so a block may be freed
+// through any clone of the arena it came from.
+unsafe impl Allocator for Arena {
+    #[cfg(not(feature = "arena-canaries"))]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ret = self.global_.allocate(layout)?;
+        self.allocated_.fetch_add(layout.size(), Ordering::Relaxed);
+        Ok(ret)
+    }
+
+    #[cfg(not(feature = "arena-canaries"))]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // The caller guarantees "ptr" is a live block of this arena with
+        // "layout", so it is a live block of the global allocator.
+        unsafe { self.global_.deallocate(ptr, layout) };
+        self.allocated_.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "arena-canaries")]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ret = self.canaries_.allocate(&self.global_, layout)?;
+        self.allocated_.fetch_add(layout.size(), Ordering::Relaxed);
+        Ok(ret)
+    }
+
+    #[cfg(feature = "arena-canaries")]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.canaries_.deallocate(ptr, layout);
+        self.allocated_.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Debugging support for the "arena-canaries" feature.
+///
+/// Every allocation is surrounded by a header and a trailer canary.
+/// Deallocation verifies both, fills the memory with POISON_BYTE and
+/// keeps the block quarantined (never reused) until the last clone of
+/// the arena is dropped, so stale readers observe the poison pattern
+/// instead of somebody else's data.
+#[cfg(feature = "arena-canaries")]
+pub(super) mod canary {
+    use std::{alloc::{AllocError, Allocator, Global, Layout}, collections::BTreeMap, ptr::NonNull, sync::Mutex};
+
+    pub(in crate::util) const POISON_BYTE: u8 = 0xdd;
+    const CANARY_SIZE: usize = 8;
+    const HEAD_CANARY: u64 = 0xca11_ab1e_5afe_c0de;
+    const TAIL_CANARY: u64 = 0x0ddb_a11f_ee1d_beef;
+
+    /// Bookkeeping for one block handed out by the arena.
+    #[derive(Clone, Copy)]
+    struct Block {
+        start_: usize,  // address of the underlying allocation
+        full_: Layout,  // layout of the underlying allocation
+        size_: usize,   // size requested by the caller
+    }
+
+    // Invariant: every block in live_ or quarantine_ is a live block of
+    // the global allocator with layout full_, owned by the registry, and
+    // its canaries lie inside it.
+    pub(in crate::util) struct Registry {
+        // Live allocations keyed by the address handed out to callers.
+        live_: Mutex<BTreeMap<usize, Block>>,
+        // Freed (and poisoned) allocations, keyed the same way.
+        quarantine_: Mutex<BTreeMap<usize, Block>>,
+    }
+
+    fn header_size(layout: Layout) -> usize {
+        // A multiple of the alignment, so the caller's pointer stays aligned.
+        CANARY_SIZE.max(layout.align())
+    }
+
+    impl Registry {
+        pub(in crate::util) fn new() -> Self {
+            Self { live_: Mutex::new(BTreeMap::new()), quarantine_: Mutex::new(BTreeMap::new()) }
+        }
+
+        pub(in crate::util) fn allocate(&self, global: &Global, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            let header = header_size(layout);
+            let full = Layout::from_size_align(header + layout.size() + CANARY_SIZE, layout.align())
+                .map_err(|_| AllocError)?;
+            let start = global.allocate(full)?.cast::<u8>();
+            // The header and trailer canaries, and the caller's block
+            // between them, all lie inside "full".
+            unsafe {
+                let data = start.as_ptr().add(header);
+                data.sub(CANARY_SIZE).cast::<u64>().write_unaligned(HEAD_CANARY);
+                data.add(layout.size()).cast::<u64>().write_unaligned(TAIL_CANARY);
+                let block = Block { start_: start.as_ptr() as usize, full_: full, size_: layout.size() };
+                self.live_.lock().unwrap().insert(data as usize, block);
+                Ok(NonNull::slice_from_raw_parts(NonNull::new_unchecked(data), layout.size()))
+            }
+        }
+
+        /// Check and poison the block at "ptr", which must be live, and
+        /// quarantine it.  Panics on a double free, a free of memory this
+        /// registry did not hand out, or damaged canaries.
+        pub(in crate::util) fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let addr = ptr.as_ptr() as usize;
+            let block = match self.live_.lock().unwrap().remove(&addr) {
+                Some(block) => block,
+                None => {
+                    if self.quarantine_.lock().unwrap().contains_key(&addr) {
+                        panic!("arena: double free of {:#x}", addr);
+                    }
+                    panic!("arena: free of {:#x} which was not allocated by this arena", addr);
+                },
+            };
+            assert_eq!(block.size_, layout.size(), "arena: free of {:#x} with the wrong size", addr);
+            if let Err(msg) = Self::check(addr, &block) {
+                panic!("{}", msg);
+            }
+            // The block was live, so the registry still owns its memory.
+            unsafe { std::ptr::write_bytes(ptr.as_ptr(), POISON_BYTE, block.size_) };
+            self.quarantine_.lock().unwrap().insert(addr, block);
+        }
+
+        pub(in crate::util) fn check_all(&self) -> Result<(), String> {
+            for (&addr, block) in self.live_.lock().unwrap().iter() {
+                Self::check(addr, block)?;
+            }
+            Ok(())
+        }
+
+        pub(in crate::util) fn assert_live(&self, addr: usize) {
+            let live = self.live_.lock().unwrap();
+            match live.range(..=addr).next_back() {
+                Some((&start, block)) if addr < start + block.size_.max(1) => {},
+                _ => panic!("arena: access to {:#x} which is not a live allocation", addr),
+            }
+        }
+
+        fn check(addr: usize, block: &Block) -> Result<(), String> {
+            let data = addr as *const u8;
+            // The canaries of a registered block lie inside its memory.
+            let (head, tail) = unsafe {
+                (data.sub(CANARY_SIZE).cast::<u64>().read_unaligned(),
+                 data.add(block.size_).cast::<u64>().read_unaligned())
+            };
+            if head != HEAD_CANARY {
+                return Err(format!("arena: header canary of {:#x} overwritten", addr));
+            }
+            if tail != TAIL_CANARY {
+                return Err(format!("arena: trailer canary of {:#x} ({} bytes) overwritten", addr, block.size_));
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Registry {
+        fn drop(&mut self) {
+            // Live blocks are owned by their allocations, which all hold a
+            // clone of the arena, so only quarantined blocks remain here.
+            for block in self.quarantine_.get_mut().unwrap().values() {
+                unsafe {
+                    Global.deallocate(NonNull::new_unchecked(block.start_ as *mut u8), block.full_);
+                }
+            }
+        }
+    }
+}
+
+/// Reserve the blocks of "file" for the "len" bytes at "offset" without
+/// changing its size, as fallocate(2) with FALLOC_FL_KEEP_SIZE does.
+#[cfg(target_os = "linux")]
+pub(super) fn fallocate_keep_size(file: &std::fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // "file" keeps the descriptor open for the duration of the call.
+    match unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset as libc::off_t, len as libc::off_t) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// The soft limit on the number of files the process may have open, as
+/// getrlimit(2) reports it for RLIMIT_NOFILE.
+#[cfg(target_os = "linux")]
+pub(super) fn soft_open_file_limit() -> std::io::Result<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // "limit" is a valid rlimit for the call to fill in.
+    match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 => Ok(limit.rlim_cur),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Counts the allocations of each thread on their way to the system
+/// allocator, for tests of how many an operation makes; see
+/// testutil::allocations().
+#[cfg(test)]
+pub(super) struct CountingAllocator;
+
+// Forwards every call to the system allocator unchanged.
+#[cfg(test)]
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::testutil::count_allocation();
+        unsafe { std::alloc::System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { std::alloc::System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        super::testutil::count_allocation();
+        unsafe { std::alloc::System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::util::random::Random;
+
+    use super::*;
+
+    #[cfg(not(feature = "arena-canaries"))]
+    #[test]
+    fn default_layout_test() {
+        // Without the feature the arena is two shared pointers and forwards
+        // allocations to the global allocator unchanged.
+        assert_eq!(2 * std::mem::size_of::<usize>(), std::mem::size_of::<Arena>());
+        let arena = Arena::new();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = arena.allocate(layout).unwrap();
+        assert_eq!(24, ptr.len());
+        assert_eq!(0, ptr.cast::<u8>().as_ptr() as usize % 8);
+        assert_eq!(24, arena.memory_usage());
+        unsafe { arena.deallocate(ptr.cast(), layout) };
+        assert_eq!(0, arena.memory_usage());
+    }
+
+    #[test]
+    fn allocate_deallocate_cycles_test() {
+        let arena = Arena::new();
+        let mut rnd = Random::new(301);
+        let mut live: Vec<(NonNull<u8>, Layout, u8)> = Vec::new();
+        let mut expected = 0;
+        for i in 0..200 {
+            if live.is_empty() || rnd.uniform(3) != 0 {
+                let layout = Layout::from_size_align(1 + rnd.uniform(64) as usize, 1 << rnd.uniform(5)).unwrap();
+                let ptr = arena.allocate(layout).unwrap().cast::<u8>();
+                assert_eq!(0, ptr.as_ptr() as usize % layout.align());
+                // Fill the block, to check later that no other allocation
+                // overlapped it.
+                let fill = i as u8;
+                unsafe { std::ptr::write_bytes(ptr.as_ptr(), fill, layout.size()) };
+                live.push((ptr, layout, fill));
+                expected += layout.size();
+            } else {
+                let (ptr, layout, fill) = live.swap_remove(rnd.uniform(live.len() as i32) as usize);
+                let block = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+                assert!(block.iter().all(|&b| b == fill));
+                // Free through a clone: clones share the blocks.
+                unsafe { arena.clone().deallocate(ptr, layout) };
+                expected -= layout.size();
+            }
+            assert_eq!(expected, arena.memory_usage());
+        }
+        for (ptr, layout, _) in live {
+            unsafe { arena.deallocate(ptr, layout) };
+        }
+        assert_eq!(0, arena.memory_usage());
+        #[cfg(feature = "arena-canaries")]
+        assert!(arena.check_canaries().is_ok());
+    }
+
+    #[test]
+    fn concurrent_test() {
+        // Threads build and drop linked nodes in clones of one arena, and
+        // hand some of them to the main thread to drop.
+        struct Node {
+            value: u64,
+            next: Option<Box<Node, Arena>>,
+        }
+
+        let arena = Arena::new();
+        let workers: Vec<_> = (0..4).map(|t| {
+            let arena = arena.clone();
+            thread::spawn(move || {
+                let mut kept = None;
+                for round in 0..10u64 {
+                    let mut head: Option<Box<Node, Arena>> = None;
+                    for i in 0..20 {
+                        head = Some(Box::new_in(Node { value: t * 1000 + round * 20 + i, next: head }, arena.clone()));
+                    }
+                    let mut sum = 0;
+                    let mut node = head.as_deref();
+                    while let Some(n) = node {
+                        sum += n.value;
+                        node = n.next.as_deref();
+                    }
+                    assert_eq!(20 * (t * 1000 + round * 20) + 190, sum);
+                    if round == 9 {
+                        kept = head;
+                    }
+                }
+                kept
+            })
+        }).collect();
+        let kept: Vec<_> = workers.into_iter().map(|w| w.join().unwrap().unwrap()).collect();
+        assert_eq!(4 * 20 * std::mem::size_of::<Node>(), arena.memory_usage());
+        drop(kept);
+        assert_eq!(0, arena.memory_usage());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(miri, ignore)]   // Calls into libc
+    #[test]
+    fn fallocate_keep_size_test() {
+        let path = std::env::temp_dir().join(format!("rucksdb-fallocate-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        // Not every file system supports it, but none may change the size.
+        let _ = fallocate_keep_size(&file, 0, 1 << 16);
+        assert_eq!(0, file.metadata().unwrap().len());
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "arena-canaries")]
+    #[test]
+    fn overflow_detected_test() {
+        let arena = Arena::new();
+        let layout = Layout::from_size_align(4, 1).unwrap();
+        let ptr = arena.allocate(layout).unwrap().cast::<u8>();
+        // Write one byte past the end of the allocation.
+        unsafe { ptr.as_ptr().add(4).write(0) };
+        let err = arena.check_canaries().unwrap_err();
+        assert!(err.contains("trailer canary"), "{}", err);
+    }
+
+    #[cfg(feature = "arena-canaries")]
+    #[test]
+    fn freed_memory_is_poisoned_test() {
+        let arena = Arena::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = arena.allocate(layout).unwrap().cast::<u8>();
+        unsafe {
+            std::ptr::write_bytes(ptr.as_ptr(), 1, 16);
+            arena.deallocate(ptr, layout);
+            // The block is quarantined, so reading it is still sound.
+            let stale = std::slice::from_raw_parts(ptr.as_ptr(), 16);
+            assert!(crate::util::arena::is_poisoned(stale));
+        }
+        assert!(arena.check_canaries().is_ok());
+    }
+
+    #[cfg(feature = "arena-canaries")]
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_test() {
+        let arena = Arena::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptr = arena.allocate(layout).unwrap().cast::<u8>();
+        unsafe {
+            arena.deallocate(ptr, layout);
+            arena.deallocate(ptr, layout);
+        }
+    }
+}
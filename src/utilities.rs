@@ -0,0 +1,3 @@
+pub mod encrypted_env;
+pub mod multi_db;
+pub mod ttl;
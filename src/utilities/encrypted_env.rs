@@ -0,0 +1,484 @@
+//! An Env wrapper that encrypts the contents of every file it creates.
+//! 
+//! Each file starts with a small header: an 8 byte magic number followed
+//! by a random per-file nonce of one cipher block.  The rest of the file
+//! is the plaintext XOR-ed with a counter mode key stream derived from
+//! the nonce and the position in the file.  Readers skip the header
+//! transparently and file sizes reported to callers exclude it, so the
+//! wrapped files behave exactly like plain ones: they may be appended
+//! to, renamed, and read from arbitrary offsets.
+//! 
+//! The block cipher itself is supplied by the user through the
+//! BlockCipherProvider trait (e.g. backed by an AES implementation).
+
+use std::{cmp::min, collections::hash_map::RandomState, hash::{BuildHasher, Hasher}, sync::{Arc, Mutex}};
+
+use crate::{env::{Env, FileLock, RandomAccessFile, SequentialFile, WritableFile}, slice::Slice, status::Status};
+
+const MAGIC: &[u8; 8] = b"rucksENC";
+
+/// A block cipher used to generate the key stream of encrypted files.
+/// Implementations must be deterministic: encrypting the same block
+/// twice must yield the same output.
+pub trait BlockCipherProvider: Send + Sync {
+    /// The name of the cipher, used in diagnostic messages.
+    fn name(&self) -> &'static str;
+
+    /// Size in bytes of one cipher block, e.g. 16 for AES.  The per-file
+    /// nonce has the same size.
+    /// 
+    /// REQUIRES: at least 8
+    fn block_size(&self) -> usize;
+
+    /// Encrypt one block in place.
+    /// REQUIRES: block.len() == block_size()
+    fn encrypt_block(&self, block: &mut [u8]);
+}
+
+/// A trivial cipher that XORs each block with a fixed key.
+/// 
+/// It is NOT secure and only exists so tests (and examples) can exercise
+/// the encrypted Env without pulling in a real cipher implementation.
+pub struct XorBlockCipher {
+    key_: [u8; 16],
+}
+
+impl XorBlockCipher {
+    pub fn new(key: [u8; 16]) -> Self {
+        Self { key_: key }
+    }
+}
+
+impl BlockCipherProvider for XorBlockCipher {
+    fn name(&self) -> &'static str { "XorBlockCipher" }
+
+    fn block_size(&self) -> usize { self.key_.len() }
+
+    fn encrypt_block(&self, block: &mut [u8]) {
+        debug_assert!(block.len() == self.key_.len());
+        for (b, k) in block.iter_mut().zip(self.key_.iter()) {
+            *b = (*b ^ *k).rotate_left(3);
+        }
+    }
+}
+
+/// Returns a new environment that encrypts the files it creates with
+/// "provider" and stores them through "base".  Files that were not
+/// written through an encrypted Env cannot be opened with it.
+pub fn new_encrypted_env(base: Arc<dyn Env>, provider: Arc<dyn BlockCipherProvider>) -> Arc<dyn Env> {
+    assert!(provider.block_size() >= 8, "cipher block size must be at least 8 bytes");
+    Arc::new(EncryptedEnv { base_: base, cipher_: provider })
+}
+
+/// Counter mode key stream of one file.
+struct CipherStream {
+    cipher_: Arc<dyn BlockCipherProvider>,
+    nonce_: Vec<u8>,
+}
+
+impl CipherStream {
+    /// XOR "data", located at "offset" in the plaintext of the file, with
+    /// the key stream.  Encryption and decryption are the same operation.
+    fn apply(&self, offset: u64, data: &mut [u8]) {
+        let block_size = self.cipher_.block_size();
+        let mut block = vec![0u8; block_size];
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let in_block = (pos % block_size as u64) as usize;
+            self.counter_block(pos / block_size as u64, &mut block);
+            self.cipher_.encrypt_block(&mut block);
+            let n = min(block_size - in_block, data.len() - done);
+            for i in 0..n {
+                data[done + i] ^= block[in_block + i];
+            }
+            done += n;
+        }
+    }
+
+    fn counter_block(&self, index: u64, block: &mut [u8]) {
+        block.copy_from_slice(&self.nonce_);
+        let start = block.len() - 8;
+        for (b, c) in block[start..].iter_mut().zip(index.to_be_bytes()) {
+            *b ^= c;
+        }
+    }
+}
+
+fn header_size(cipher: &Arc<dyn BlockCipherProvider>) -> usize {
+    MAGIC.len() + cipher.block_size()
+}
+
+fn new_nonce(size: usize) -> Vec<u8> {
+    let mut nonce = Vec::with_capacity(size + 8);
+    while nonce.len() < size {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(nonce.len());
+        nonce.extend(hasher.finish().to_le_bytes());
+    }
+    nonce.truncate(size);
+    nonce
+}
+
+/// Parse the header of an encrypted file.  An empty file has no header
+/// (it may have been created by a process that crashed right away) and
+/// yields None.
+fn parse_header(fname: &str, cipher: &Arc<dyn BlockCipherProvider>, header: &[u8]) -> Result<Option<CipherStream>, Status> {
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if header.len() < header_size(cipher) || &header[..MAGIC.len()] != MAGIC {
+        return Err(Status::corruption(fname, "not a file encrypted by this Env"));
+    }
+    Ok(Some(CipherStream { cipher_: cipher.clone(), nonce_: header[MAGIC.len()..].to_vec() }))
+}
+
+struct EncryptedSequentialFile {
+    file_: Box<dyn SequentialFile>,
+    stream_: Option<CipherStream>,
+    offset_: u64,
+}
+
+impl SequentialFile for EncryptedSequentialFile {
+    fn read(&mut self, n: usize) -> Result<Vec<u8>, Status> {
+        let mut result = self.file_.read(n)?;
+        if let Some(stream) = self.stream_.as_ref() {
+            stream.apply(self.offset_, &mut result);
+        }
+        self.offset_ += result.len() as u64;
+        Ok(result)
+    }
+
+    fn skip(&mut self, n: u64) -> Status {
+        let s = self.file_.skip(n);
+        if s.ok() {
+            self.offset_ += n;
+        }
+        s
+    }
+}
+
+struct EncryptedRandomAccessFile {
+    file_: Arc<dyn RandomAccessFile>,
+    stream_: Option<CipherStream>,
+    header_size_: u64,
+}
+
+impl RandomAccessFile for EncryptedRandomAccessFile {
+    fn read(&self, offset: u64, n: usize) -> Result<Vec<u8>, Status> {
+        match self.stream_.as_ref() {
+            Some(stream) => {
+                let mut result = self.file_.read(offset + self.header_size_, n)?;
+                stream.apply(offset, &mut result);
+                Ok(result)
+            },
+            None => self.file_.read(offset, n),
+        }
+    }
+}
+
+struct EncryptedWritableFile {
+    file_: Arc<dyn WritableFile>,
+    stream_: CipherStream,
+    offset_: Mutex<u64>,
+}
+
+impl WritableFile for EncryptedWritableFile {
+    fn append(&self, data: &Slice) -> Status {
+        let mut offset = self.offset_.lock().unwrap();
+        let mut buf = data.data().to_vec();
+        self.stream_.apply(*offset, &mut buf);
+        let s = self.file_.append(&Slice::new(&buf));
+        if s.ok() {
+            *offset += buf.len() as u64;
+        }
+        s
+    }
+
+    fn close(&self) -> Status { self.file_.close() }
+    fn flush(&self) -> Status { self.file_.flush() }
+    fn sync(&self) -> Status { self.file_.sync() }
+    // Off by the header length, which does not matter for a hint.
+    fn preallocate(&self, offset: u64, len: u64) -> Status { self.file_.preallocate(offset, len) }
+}
+
+struct EncryptedEnv {
+    base_: Arc<dyn Env>,
+    cipher_: Arc<dyn BlockCipherProvider>,
+}
+
+impl Env for EncryptedEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> {
+        let mut file = self.base_.new_sequential_file(fname)?;
+        let header = file.read(header_size(&self.cipher_))?;
+        let stream = parse_header(fname, &self.cipher_, &header)?;
+        Ok(Box::new(EncryptedSequentialFile { file_: file, stream_: stream, offset_: 0 }))
+    }
+
+    fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> {
+        let file = self.base_.new_random_access_file(fname)?;
+        let header = file.read(0, header_size(&self.cipher_))?;
+        let stream = parse_header(fname, &self.cipher_, &header)?;
+        Ok(Arc::new(EncryptedRandomAccessFile { 
+            file_: file, stream_: stream, header_size_: header_size(&self.cipher_) as u64,
+        }))
+    }
+
+    fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        let file = self.base_.new_writable_file(fname)?;
+        let nonce = new_nonce(self.cipher_.block_size());
+        let mut header = MAGIC.to_vec();
+        header.extend(&nonce);
+        let s = file.append(&Slice::new(&header));
+        if !s.ok() {
+            return Err(s);
+        }
+        Ok(Arc::new(EncryptedWritableFile {
+            file_: file,
+            stream_: CipherStream { cipher_: self.cipher_.clone(), nonce_: nonce },
+            offset_: Mutex::new(0),
+        }))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> {
+        // A missing or empty file gets a header, as a new one would
+        let header = if self.base_.file_exists(fname) {
+            self.base_.new_random_access_file(fname)?.read(0, header_size(&self.cipher_))?
+        } else {
+            Vec::new()
+        };
+        let Some(stream) = parse_header(fname, &self.cipher_, &header)? else {
+            return self.new_writable_file(fname);
+        };
+        let size = self.get_file_size(fname)?;
+        Ok(Arc::new(EncryptedWritableFile {
+            file_: self.base_.new_appendable_file(fname)?,
+            stream_: stream,
+            offset_: Mutex::new(size),
+        }))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        self.base_.file_exists(fname)
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> {
+        self.base_.get_children(dir)
+    }
+
+    fn remove_file(&self, fname: &str) -> Status {
+        self.base_.remove_file(fname)
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Status> {
+        let size = self.base_.get_file_size(fname)?;
+        Ok(size.saturating_sub(header_size(&self.cipher_) as u64))
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), Status> {
+        self.base_.create_dir(dirname)
+    }
+
+    fn remove_dir(&self, dirname: &str) -> Status {
+        self.base_.remove_dir(dirname)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Status {
+        self.base_.rename_file(src, target)
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Status {
+        // The header travels with the contents, so the link decrypts alike.
+        self.base_.link_file(src, target)
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<FileLock, Status> {
+        self.base_.lock_file(fname)
+    }
+
+    fn open_file_limit(&self) -> Option<(u64, u64)> {
+        self.base_.open_file_limit()
+    }
+
+    fn unlock_file(&self, lock: FileLock) -> Status {
+        self.base_.unlock_file(lock)
+    }
+
+    fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) {
+        self.base_.schedule(label, work)
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.base_.now_micros()
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        self.base_.sleep_for_microseconds(micros)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::{filename::{current_file_name, set_current_file}, log_writer::Writer, DB}, helpers::memenv::new_mem_env, options::{CompressionType, Options, ReadOptions, WriteOptions}};
+
+    use super::*;
+
+    fn test_cipher() -> Arc<dyn BlockCipherProvider> {
+        Arc::new(XorBlockCipher::new(*b"0123456789abcdef"))
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    fn read_all(env: &Arc<dyn Env>, fname: &str) -> Vec<u8> {
+        let size = env.get_file_size(fname).unwrap();
+        env.new_random_access_file(fname).unwrap().read(0, size as usize).unwrap()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let env = new_encrypted_env(new_mem_env(), test_cipher());
+        let file = env.new_writable_file("/dir/f").unwrap();
+        let mut expected: Vec<u8> = Vec::new();
+        for i in 0..100 {
+            let chunk = format!("chunk-{}|", i);
+            assert!(file.append(&Slice::new(chunk.as_bytes())).ok());
+            expected.extend(chunk.as_bytes());
+        }
+        assert_eq!(expected.len() as u64, env.get_file_size("/dir/f").unwrap());
+
+        // Sequential reads with odd sizes and skips.
+        let mut seq_file = env.new_sequential_file("/dir/f").unwrap();
+        assert_eq!(&expected[0..7], &seq_file.read(7).unwrap()[..]);
+        assert!(seq_file.skip(13).ok());
+        assert_eq!(&expected[20..53], &seq_file.read(33).unwrap()[..]);
+        assert_eq!(&expected[53..], &seq_file.read(100000).unwrap()[..]);
+        assert!(seq_file.read(10).unwrap().is_empty());
+
+        // Random reads at arbitrary offsets.
+        let rand_file = env.new_random_access_file("/dir/f").unwrap();
+        for (offset, n) in [(0, 1), (5, 17), (15, 16), (16, 16), (31, 100), (expected.len() - 3, 10)] {
+            let end = min(offset + n, expected.len());
+            assert_eq!(&expected[offset..end], &rand_file.read(offset as u64, n).unwrap()[..]);
+        }
+
+        // Renaming keeps the file readable.
+        assert!(env.rename_file("/dir/f", "/dir/g").ok());
+        assert_eq!(expected, read_all(&env, "/dir/g"));
+
+        // Appending continues the key stream; a new file gets a header.
+        for fname in ["/dir/g", "/dir/h"] {
+            let file = env.new_appendable_file(fname).unwrap();
+            assert!(file.append(&Slice::new(b"appended")).ok());
+        }
+        expected.extend(b"appended");
+        assert_eq!(expected, read_all(&env, "/dir/g"));
+        assert_eq!(b"appended".to_vec(), read_all(&env, "/dir/h"));
+    }
+
+    #[test]
+    fn no_plaintext_on_disk_test() {
+        let base = new_mem_env();
+        let env = new_encrypted_env(base.clone(), test_cipher());
+
+        // A WAL-like file written through the log writer.
+        let mut writer = Writer::new(env.new_writable_file("/db/000003.log").unwrap());
+        for i in 0..1000 {
+            let record = format!("key{:06}=value{:06}", i, i);
+            assert!(writer.add_record(&Slice::new(record.as_bytes())).ok());
+        }
+        let raw = read_all(&base, "/db/000003.log");
+        let plain = read_all(&env, "/db/000003.log");
+        for i in (0..1000).step_by(37) {
+            assert!(!contains(&raw, format!("key{:06}", i).as_bytes()));
+            assert!(!contains(&raw, format!("value{:06}", i).as_bytes()));
+            assert!(contains(&plain, format!("key{:06}=value{:06}", i, i).as_bytes()));
+        }
+
+        // CURRENT goes through the same path.
+        assert!(set_current_file(env.clone(), "/db", 1).ok());
+        assert!(!contains(&read_all(&base, &current_file_name("/db")), b"MANIFEST"));
+        assert_eq!(b"MANIFEST-000001\n".to_vec(), read_all(&env, &current_file_name("/db")));
+    }
+
+    #[test]
+    fn db_over_encrypted_env_test() {
+        let base = new_mem_env();
+        let options = Options {
+            env: new_encrypted_env(base.clone(), test_cipher()),
+            create_if_missing: true,
+            compression: CompressionType::NoCompression,
+            write_buffer_size: 64 << 10,
+            ..Options::new()
+        };
+        let key = |i: usize| format!("secret-key-{:05}", i);
+        let value = |i: usize| format!("secret-value-{:05}", i);
+        let db = DB::open(&options, "/db").unwrap();
+        for i in 0..3000 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+        }
+        assert!(db.flush().ok());
+        for i in (0..3000).step_by(3) {
+            assert!(db.delete(&WriteOptions::default(), &Slice::new(key(i).as_bytes())).ok());
+        }
+        assert!(db.compact_range(None, None).ok());
+        // Some writes are left in the log only.
+        for i in 3000..3100 {
+            assert!(db.put(&WriteOptions::default(), &Slice::new(key(i).as_bytes()), &Slice::new(value(i).as_bytes())).ok());
+        }
+        drop(db);
+
+        let db = DB::open(&options, "/db").unwrap();
+        for i in 0..3100 {
+            match db.get(&ReadOptions::new(), &Slice::new(key(i).as_bytes())) {
+                Ok(v) => assert_eq!(value(i).into_bytes(), v),
+                Err(s) => assert!(i % 3 == 0 && i < 3000 && s.is_not_found(), "{}: {}", i, s.to_string()),
+            }
+        }
+        drop(db);
+
+        // Tables, logs, the MANIFEST and the info log all went through
+        // the wrapper.
+        let files = base.get_children("/db").unwrap();
+        assert!(files.iter().any(|f| f.ends_with(".ldb")) && files.iter().any(|f| f.ends_with(".log")), "{:?}", files);
+        for fname in files {
+            let raw = read_all(&base, &format!("/db/{}", fname));
+            assert!(!contains(&raw, b"secret-"), "{}", fname);
+            assert!(!contains(&raw, b"MANIFEST") && !contains(&raw, b"leveldb"), "{}", fname);
+        }
+    }
+
+    #[test]
+    fn nonce_per_file_test() {
+        let base = new_mem_env();
+        let env = new_encrypted_env(base.clone(), test_cipher());
+        for fname in ["/dir/a", "/dir/b"] {
+            let file = env.new_writable_file(fname).unwrap();
+            assert!(file.append(&Slice::new(&[0u8; 64])).ok());
+        }
+        // Same plaintext, different ciphertext.
+        assert_ne!(read_all(&base, "/dir/a"), read_all(&base, "/dir/b"));
+    }
+
+    #[test]
+    fn plain_file_rejected_test() {
+        let base = new_mem_env();
+        let env = new_encrypted_env(base.clone(), test_cipher());
+        let file = base.new_writable_file("/dir/plain").unwrap();
+        assert!(file.append(&Slice::new(b"this file was written without encryption")).ok());
+        match env.new_sequential_file("/dir/plain") {
+            Err(s) => assert!(s.is_corruption()),
+            Ok(_) => panic!("opened a plain file"),
+        }
+        match env.new_random_access_file("/dir/plain") {
+            Err(s) => assert!(s.is_corruption()),
+            Ok(_) => panic!("opened a plain file"),
+        }
+
+        // And the plain Env does not see the plaintext of an encrypted file.
+        let file = env.new_writable_file("/dir/secret").unwrap();
+        assert!(file.append(&Slice::new(b"attack at dawn")).ok());
+        assert!(!contains(&read_all(&base, "/dir/secret"), b"attack at dawn"));
+    }
+}
@@ -0,0 +1,362 @@
+//! Atomic writes across several databases.
+//!
+//! A MultiDbWriteBatch holds one batch per database, and commit() applies
+//! either all of them or none, with a two-phase commit: each database
+//! first logs and syncs its batch as prepared, without applying it, and
+//! only once every one has does each log it again as committed and apply
+//! it.  If a prepare fails, the batches prepared so far are rolled back.
+//!
+//! A crash in the middle leaves some databases with a prepared batch in
+//! their log that is neither committed nor rolled back.  DB::open() fails
+//! on such a database with InvalidArgument unless
+//! Options::prepared_batch_resolver says what to do with it, and the
+//! answer depends on the other databases: open them all together with
+//! open_participants(), which reads their logs first and decides each
+//! transaction the same way everywhere.  Opening one of them alone with
+//! DB::open() throws away what its logs say about the transactions of the
+//! others.
+//!
+//! The prepared batches make a database unreadable by builds without
+//! two-phase commit support, which fail to open it with NotSupported.
+//! The batches are not run through Options::batch_transformer, do not
+//! wait for range locks (see DB::lock_range()), and are always synced.
+
+use std::{collections::{BTreeMap, BTreeSet}, process, sync::{atomic::{AtomicU32, Ordering}, Arc}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{db::{two_phase::logged_transaction_records, DB}, options::Options, slice::escape_bytes, status::Status, write_batch::{TransactionId, TransactionRecord, WriteBatch}};
+
+/// Decides the outcome of the transactions across several DBs that a DB
+/// finds prepared but neither committed nor rolled back when it is
+/// opened.  See Options::prepared_batch_resolver.
+pub trait PreparedBatchResolver: Send + Sync {
+    /// Return true to commit transaction "txn", of which the database
+    /// "dbname" holds the share of participant "participant" (of
+    /// "participants"), or false to roll it back.  An error fails
+    /// DB::open() with it.
+    fn resolve(&self, dbname: &str, txn: &TransactionId, participant: u32, participants: u32) -> Result<bool, Status>;
+}
+
+/// One WriteBatch per database, committed atomically across them.
+pub struct MultiDbWriteBatch {
+    batches_: Vec<WriteBatch>,
+}
+
+impl MultiDbWriteBatch {
+    /// A batch for "participants" databases, with nothing to write to any.
+    pub fn new(participants: usize) -> Self {
+        Self { batches_: (0..participants).map(|_| WriteBatch::new()).collect() }
+    }
+
+    /// Return the batch for the database at index "participant" of the
+    /// slice given to commit().
+    pub fn batch(&mut self, participant: usize) -> &mut WriteBatch {
+        &mut self.batches_[participant]
+    }
+
+    /// Apply each batch to the database at its index in "dbs", all of
+    /// them or none.  "dbs" must hold distinct databases, one per batch.
+    ///
+    /// If a database fails to prepare, every batch is rolled back and the
+    /// error returned: no database gets its batch.  Once all have
+    /// prepared, the transaction is committed, and an error committing on
+    /// a database leaves it to apply its batch when it is reopened, with
+    /// the others, by open_participants().  Until then, the others keep
+    /// the logs that say the transaction committed.  A crash at any point
+    /// is resolved the same way.
+    pub fn commit(&self, dbs: &[&DB]) -> Status {
+        if dbs.len() != self.batches_.len() {
+            return Status::invalid_argument("MultiDbWriteBatch",
+                &format!("has {} batches for {} databases", self.batches_.len(), dbs.len()));
+        }
+        let txn = new_transaction_id();
+        let participants = dbs.len() as u32;
+        let mut s;
+        for (i, (db, batch)) in dbs.iter().zip(&self.batches_).enumerate() {
+            s = db.prepare_transaction(&txn, i as u32, participants, batch);
+            sync_point!("multi-db:commit:after-prepare", s);
+            if !s.ok() {
+                // This one's prepare too may have reached its log.
+                for db in &dbs[..=i] {
+                    let _ = db.rollback_transaction(&txn);
+                }
+                return s;
+            }
+        }
+
+        let mut result = Status::new_ok();
+        for (db, batch) in dbs.iter().zip(&self.batches_) {
+            s = db.commit_transaction(&txn, batch);
+            sync_point!("multi-db:commit:after-commit", s);
+            if !s.ok() && result.ok() {
+                result = s.annotate("transaction committed, but not applied; reopen with open_participants()");
+            }
+        }
+        if result.ok() {
+            for db in dbs {
+                db.release_transaction(&txn);
+            }
+        }
+        result
+    }
+}
+
+/// Return an id that no other transaction gets.
+fn new_transaction_id() -> TransactionId {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let micros = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+    let mut txn = [0; 16];
+    txn[..8].copy_from_slice(&micros.to_le_bytes());
+    txn[8..12].copy_from_slice(&process::id().to_le_bytes());
+    txn[12..].copy_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    txn
+}
+
+/// What the logs of the participants say about a transaction.
+#[derive(Default)]
+struct Evidence {
+    prepared: BTreeSet<u32>,    // Participants that logged their share
+    participants: u32,
+    committed: bool,
+    rolled_back: bool,
+}
+
+impl Evidence {
+    /// A transaction commits if a participant committed it, or if every
+    /// one prepared and none rolled it back.
+    fn commits(&self) -> bool {
+        self.committed || (!self.rolled_back && self.prepared.len() == self.participants as usize)
+    }
+}
+
+/// Resolves the transactions open_participants() decided.
+struct Decisions(BTreeMap<TransactionId, bool>);
+
+impl PreparedBatchResolver for Decisions {
+    fn resolve(&self, dbname: &str, txn: &TransactionId, _participant: u32, _participants: u32) -> Result<bool, Status> {
+        self.0.get(txn).copied()
+            .ok_or_else(|| Status::invalid_argument(dbname, &format!("transaction {} is not in the logs read", escape_bytes(txn))))
+    }
+}
+
+/// Open the databases "participants", each with its options and name as
+/// DB::open() does, deciding the transactions a crash left prepared in
+/// them the same way in each (see MultiDbWriteBatch::commit()).  Returns
+/// the databases in the order given.
+///
+/// The databases must be all those that took part in the transactions
+/// left prepared, and must not be open.  Their logs are read before any
+/// of them is opened, and those with transactions to resolve are opened
+/// first, so that a crash in the middle still leaves the outcome in
+/// their logs for the next call.  If a database fails to open, the ones
+/// already opened are closed again and the error returned.
+pub fn open_participants(participants: &[(&Options, &str)]) -> Result<Vec<Box<DB>>, Status> {
+    let mut evidence: BTreeMap<TransactionId, Evidence> = BTreeMap::new();
+    let mut pending = Vec::with_capacity(participants.len());
+    for (options, dbname) in participants {
+        let mut prepared = BTreeSet::new();
+        for record in logged_transaction_records(&options.env, dbname)? {
+            match record {
+                TransactionRecord::Prepare { txn, participant, participants } => {
+                    let e = evidence.entry(txn).or_default();
+                    e.prepared.insert(participant);
+                    e.participants = participants;
+                    prepared.insert(txn);
+                },
+                TransactionRecord::Commit(txn) => {
+                    evidence.entry(txn).or_default().committed = true;
+                    prepared.remove(&txn);
+                },
+                TransactionRecord::Rollback(txn) => {
+                    evidence.entry(txn).or_default().rolled_back = true;
+                    prepared.remove(&txn);
+                },
+            }
+        }
+        pending.push(!prepared.is_empty());
+    }
+    let resolver: Arc<dyn PreparedBatchResolver> = Arc::new(Decisions(
+        evidence.into_iter().map(|(txn, e)| (txn, e.commits())).collect()));
+
+    let order = (0..participants.len()).filter(|&i| pending[i]).chain((0..participants.len()).filter(|&i| !pending[i]));
+    let mut dbs: Vec<Option<Box<DB>>> = participants.iter().map(|_| None).collect();
+    for i in order {
+        let (options, dbname) = participants[i];
+        let options = Options { prepared_batch_resolver: Some(resolver.clone()), ..options.clone() };
+        dbs[i] = Some(DB::open(&options, dbname)?);
+    }
+    Ok(dbs.into_iter().map(|db| db.unwrap()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic::{self, AssertUnwindSafe}, sync::atomic::AtomicUsize};
+
+    use crate::{db::features::{self, SUPPORTED_OVERRIDE}, env::Env, helpers::memenv::new_mem_env, options::{ReadOptions, WriteOptions}, slice::Slice, sync_point};
+
+    use super::*;
+
+    fn options(env: &Arc<dyn Env>) -> Options {
+        Options { env: env.clone(), create_if_missing: true, ..Options::new() }
+    }
+
+    fn get(db: &DB, key: &str) -> Option<String> {
+        db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).ok().map(|v| String::from_utf8(v).unwrap())
+    }
+
+    fn two_db_batch() -> MultiDbWriteBatch {
+        let mut batch = MultiDbWriteBatch::new(2);
+        batch.batch(0).put(&Slice::new(b"a"), &Slice::new(b"1"));
+        batch.batch(1).put(&Slice::new(b"b"), &Slice::new(b"2"));
+        batch.batch(1).delete(&Slice::new(b"c"));
+        batch
+    }
+
+    /// Check that both DBs have the writes of two_db_batch(), or neither.
+    fn check_all_or_nothing(dbs: &[Box<DB>], committed: bool) {
+        let expected = |v: &str| committed.then(|| v.to_string());
+        assert_eq!(expected("1"), get(&dbs[0], "a"));
+        assert_eq!(expected("2"), get(&dbs[1], "b"));
+        assert_eq!(if committed { None } else { Some("old".to_string()) }, get(&dbs[1], "c"));
+    }
+
+    fn open_two(env: &Arc<dyn Env>) -> (Box<DB>, Box<DB>) {
+        let options = options(env);
+        let db1 = DB::open(&options, "/db1").unwrap();
+        assert!(db1.put(&WriteOptions::default(), &Slice::new(b"c"), &Slice::new(b"old")).ok());
+        (DB::open(&options, "/db0").unwrap(), db1)
+    }
+
+    #[test]
+    fn commit_test() {
+        let env = new_mem_env();
+        let (db0, db1) = open_two(&env);
+        assert!(two_db_batch().commit(&[&db0, &db1]).ok());
+        let dbs = [db0, db1];
+        check_all_or_nothing(&dbs, true);
+        drop(dbs);
+
+        // Committed transactions need no resolver.
+        let options = options(&env);
+        let dbs = [DB::open(&options, "/db0").unwrap(), DB::open(&options, "/db1").unwrap()];
+        check_all_or_nothing(&dbs, true);
+
+        let s = two_db_batch().commit(&[&dbs[0]]);
+        assert!(s.is_invalid_argument(), "{}", s.to_string());
+    }
+
+    #[test]
+    fn crash_test() {
+        // The point to crash at, the participant to crash after, and
+        // whether the transaction then commits.
+        let cases = [
+            ("multi-db:commit:after-prepare", 0, false),
+            ("multi-db:commit:after-prepare", 1, true),
+            ("multi-db:commit:after-commit", 0, true),
+            ("multi-db:commit:after-commit", 1, true),
+        ];
+        for (point, participant, committed) in cases {
+            let env = new_mem_env();
+            let (db0, db1) = open_two(&env);
+            let hits = AtomicUsize::new(0);
+            let _crash = sync_point::activate(point, move || {
+                if hits.fetch_add(1, Ordering::Relaxed) == participant {
+                    panic!("crash");
+                }
+                None
+            });
+            let batch = two_db_batch();
+            assert!(panic::catch_unwind(AssertUnwindSafe(|| batch.commit(&[&db0, &db1]))).is_err());
+            drop((db0, db1));
+
+            // Alone, the DBs left with a prepared batch cannot be opened.
+            let options = options(&env);
+            for (i, name) in ["/db0", "/db1"].into_iter().enumerate() {
+                let prepared = match point {
+                    "multi-db:commit:after-prepare" => i <= participant,
+                    _ => i > participant,
+                };
+                if prepared {
+                    let s = DB::open(&options, name).err().unwrap();
+                    assert!(s.is_invalid_argument(), "{}: {}", point, s.to_string());
+                }
+            }
+
+            let dbs = open_participants(&[(&options, "/db0"), (&options, "/db1")]).unwrap();
+            check_all_or_nothing(&dbs, committed);
+            drop(dbs);
+
+            // The outcome was recorded, and stays once the logs are gone.
+            let dbs = [DB::open(&options, "/db0").unwrap(), DB::open(&options, "/db1").unwrap()];
+            check_all_or_nothing(&dbs, committed);
+            for db in &dbs {
+                assert!(db.flush().ok());
+            }
+            drop(dbs);
+            let dbs = open_participants(&[(&options, "/db0"), (&options, "/db1")]).unwrap();
+            check_all_or_nothing(&dbs, committed);
+        }
+    }
+
+    #[test]
+    fn prepare_failure_test() {
+        let env = new_mem_env();
+        let (db0, db1) = open_two(&env);
+        let hits = AtomicUsize::new(0);
+        let _fail = sync_point::activate("multi-db:commit:after-prepare", move || {
+            (hits.fetch_add(1, Ordering::Relaxed) == 1).then(|| Status::io_error("injected", ""))
+        });
+        let s = two_db_batch().commit(&[&db0, &db1]);
+        assert_eq!("IO error: injected", s.to_string());
+        let dbs = [db0, db1];
+        check_all_or_nothing(&dbs, false);
+        drop(dbs);
+
+        // Both were rolled back in the logs.
+        let options = options(&env);
+        let dbs = [DB::open(&options, "/db0").unwrap(), DB::open(&options, "/db1").unwrap()];
+        check_all_or_nothing(&dbs, false);
+    }
+
+    #[test]
+    fn prepared_log_kept_test() {
+        let env = new_mem_env();
+        let (db0, db1) = open_two(&env);
+        let (db0, db1): (Arc<DB>, Arc<DB>) = (db0.into(), db1.into());
+        let hits = AtomicUsize::new(0);
+        let flushed = db0.clone();
+        let crash = sync_point::activate("multi-db:commit:after-prepare", move || {
+            if hits.fetch_add(1, Ordering::Relaxed) == 0 {
+                // Flushing switches the log, but the one with the prepare
+                // record must stay.
+                assert!(flushed.put(&WriteOptions::default(), &Slice::new(b"z"), &Slice::new(b"3")).ok());
+                assert!(flushed.flush().ok());
+            } else {
+                panic!("crash");
+            }
+            None
+        });
+        let batch = two_db_batch();
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| batch.commit(&[&db0, &db1]))).is_err());
+        drop(crash);
+        drop((db0, db1));
+
+        let options = options(&env);
+        let dbs = open_participants(&[(&options, "/db0"), (&options, "/db1")]).unwrap();
+        check_all_or_nothing(&dbs, true);
+        assert_eq!(Some("3".to_string()), get(&dbs[0], "z"));
+    }
+
+    #[test]
+    fn required_feature_test() {
+        let env = new_mem_env();
+        let (db0, db1) = open_two(&env);
+        assert!(two_db_batch().commit(&[&db0, &db1]).ok());
+        drop((db0, db1));
+
+        SUPPORTED_OVERRIDE.with(|o| o.set(Some(!features::TWO_PHASE_COMMIT)));
+        let s = DB::open(&options(&env), "/db0").err().unwrap();
+        SUPPORTED_OVERRIDE.with(|o| o.set(None));
+        assert!(s.is_not_supported_error());
+        assert!(s.to_string().contains("two-phase commit support"), "{}", s.to_string());
+    }
+}
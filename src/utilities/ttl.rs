@@ -0,0 +1,263 @@
+//! A database whose entries expire some time after they were written.
+//!
+//! DBWithTTL::put() appends the time of the write, in seconds of
+//! Env::now_micros(), to the value as a 4 byte little-endian suffix.
+//! get() strips the suffix again and reports entries older than the TTL
+//! as not found, and compaction drops them so that their space is
+//! reclaimed.  Expired entries may be reported as not found well before
+//! compaction gets to them.
+//!
+//! Every value of a database used through DBWithTTL must have been
+//! written by it: a value without the suffix is misread, and one shorter
+//! than the suffix is reported as corrupted.
+
+use std::sync::Arc;
+
+use crate::{compaction_filter::CompactionFilter, db::DB, env::Env, options::{Options, ReadOptions, WriteOptions}, slice::Slice, status::Status, util::coding::{decode_fixed32, encode_fixed32}};
+
+const TIMESTAMP_SIZE: usize = 4;
+
+pub struct DBWithTTL {
+    db_: Box<DB>,
+    env_: Arc<dyn Env>,
+    ttl_: u64,
+}
+
+impl DBWithTTL {
+    /// Open the database "dbname" as DB::open() does, with entries
+    /// expiring "ttl" seconds after they were written.  Zero means they
+    /// never expire.  Compaction runs the values through
+    /// options.compaction_filter, if set, with the suffix stripped, after
+    /// dropping the expired ones.
+    pub fn open(options: &Options, dbname: &str, ttl: u64) -> Result<Self, Status> {
+        let filter = TtlCompactionFilter { env_: options.env.clone(), ttl_: ttl, user_filter_: options.compaction_filter.clone() };
+        let options = Options { compaction_filter: Some(Arc::new(filter)), ..options.clone() };
+        let db = DB::open(&options, dbname)?;
+        Ok(Self { db_: db, env_: options.env.clone(), ttl_: ttl })
+    }
+
+    /// Set "key" to "value", which expires "ttl" seconds from now.
+    pub fn put(&self, options: &WriteOptions, key: &Slice, value: &Slice) -> Status {
+        let mut stamped = Vec::with_capacity(value.size() + TIMESTAMP_SIZE);
+        stamped.extend_from_slice(value.data());
+        stamped.extend_from_slice(&encode_fixed32(now_secs(&self.env_)));
+        self.db_.put(options, key, &Slice::new(&stamped))
+    }
+
+    pub fn delete(&self, options: &WriteOptions, key: &Slice) -> Status {
+        self.db_.delete(options, key)
+    }
+
+    /// Return the value of "key", or a NotFound status if there is none
+    /// or it has expired.
+    pub fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Vec<u8>, Status> {
+        let mut value = self.db_.get(options, key)?;
+        let Some(timestamp) = timestamp(&value) else {
+            return Err(Status::corruption("TTL value is shorter than its timestamp", &format!("{} bytes", value.len())));
+        };
+        if is_stale(timestamp, self.ttl_, now_secs(&self.env_)) {
+            return Err(Status::not_found("", ""));
+        }
+        value.truncate(value.len() - TIMESTAMP_SIZE);
+        Ok(value)
+    }
+
+    pub fn compact_range(&self, begin: Option<&Slice>, end: Option<&Slice>) -> Status {
+        self.db_.compact_range(begin, end)
+    }
+
+    /// The underlying database, whose values carry the timestamp suffix.
+    pub fn db(&self) -> &DB {
+        &self.db_
+    }
+}
+
+/// Drops the entries that have expired, and hands the others to the
+/// user's filter, if any.
+struct TtlCompactionFilter {
+    env_: Arc<dyn Env>,
+    ttl_: u64,
+    user_filter_: Option<Arc<dyn CompactionFilter>>,
+}
+
+impl CompactionFilter for TtlCompactionFilter {
+    fn name(&self) -> &str {
+        "rucksdb.TtlCompactionFilter"
+    }
+
+    fn filter(&self, level: i32, key: &Slice, value: &Slice) -> bool {
+        // Values without a timestamp are kept, and reported by get().
+        let Some(timestamp) = timestamp(value.data()) else {
+            return false;
+        };
+        if is_stale(timestamp, self.ttl_, now_secs(&self.env_)) {
+            return true;
+        }
+        let stripped = Slice::new(&value.data()[..value.size() - TIMESTAMP_SIZE]);
+        self.user_filter_.as_ref().is_some_and(|f| f.filter(level, key, &stripped))
+    }
+}
+
+fn now_secs(env: &Arc<dyn Env>) -> u32 {
+    (env.now_micros() / 1_000_000) as u32
+}
+
+/// The write time stored at the end of "value", if it is long enough.
+fn timestamp(value: &[u8]) -> Option<u32> {
+    let suffix = value.len().checked_sub(TIMESTAMP_SIZE).map(|start| &value[start..])?;
+    Some(decode_fixed32(suffix.try_into().unwrap()))
+}
+
+fn is_stale(timestamp: u32, ttl: u64, now: u32) -> bool {
+    ttl > 0 && timestamp as u64 + ttl < now as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::{env::{FileLock, RandomAccessFile, SequentialFile, WritableFile}, helpers::memenv::new_mem_env};
+
+    use super::*;
+
+    const DBNAME: &str = "/ttl";
+
+    /// An Env whose clock only moves when the test advances it.
+    struct MockClockEnv {
+        base_: Arc<dyn Env>,
+        micros_: AtomicU64,
+    }
+
+    impl MockClockEnv {
+        fn advance_secs(&self, secs: u64) {
+            self.micros_.fetch_add(secs * 1_000_000, Ordering::SeqCst);
+        }
+    }
+
+    impl Env for MockClockEnv {
+        fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, Status> { self.base_.new_sequential_file(fname) }
+        fn new_random_access_file(&self, fname: &str) -> Result<Arc<dyn RandomAccessFile>, Status> { self.base_.new_random_access_file(fname) }
+        fn new_writable_file(&self, fname: &str) -> Result<Arc<dyn WritableFile>, Status> { self.base_.new_writable_file(fname) }
+        fn file_exists(&self, fname: &str) -> bool { self.base_.file_exists(fname) }
+        fn get_children(&self, dir: &str) -> Result<Vec<String>, Status> { self.base_.get_children(dir) }
+        fn remove_file(&self, fname: &str) -> Status { self.base_.remove_file(fname) }
+        fn get_file_size(&self, fname: &str) -> Result<u64, Status> { self.base_.get_file_size(fname) }
+        fn create_dir(&self, dirname: &str) -> Result<(), Status> { self.base_.create_dir(dirname) }
+        fn remove_dir(&self, dirname: &str) -> Status { self.base_.remove_dir(dirname) }
+        fn rename_file(&self, src: &str, target: &str) -> Status { self.base_.rename_file(src, target) }
+        fn lock_file(&self, fname: &str) -> Result<FileLock, Status> { self.base_.lock_file(fname) }
+        fn unlock_file(&self, lock: FileLock) -> Status { self.base_.unlock_file(lock) }
+        fn schedule(&self, label: Option<&str>, work: Box<dyn FnOnce() + Send>) { self.base_.schedule(label, work) }
+        fn now_micros(&self) -> u64 { self.micros_.load(Ordering::SeqCst) }
+        fn sleep_for_microseconds(&self, micros: u64) { self.micros_.fetch_add(micros, Ordering::SeqCst); }
+    }
+
+    fn new_env() -> Arc<MockClockEnv> {
+        Arc::new(MockClockEnv { base_: new_mem_env(), micros_: AtomicU64::new(1_000_000_000) })
+    }
+
+    fn options_with_env(env: Arc<dyn Env>) -> Options {
+        let mut options = Options::new();
+        options.env = env;
+        options.create_if_missing = true;
+        options
+    }
+
+    /// Shorthand for getting "key" as a string.
+    fn get(db: &DBWithTTL, key: &str) -> Result<String, Status> {
+        db.get(&ReadOptions::new(), &Slice::new(key.as_bytes())).map(|v| String::from_utf8(v).unwrap())
+    }
+
+    #[test]
+    fn timestamp_test() {
+        assert_eq!(None, timestamp(b"abc"));
+        assert_eq!(Some(0x04030201), timestamp(&[1, 2, 3, 4]));
+        assert_eq!(Some(0x04030201), timestamp(b"value\x01\x02\x03\x04"));
+        assert!(!is_stale(100, 10, 110));
+        assert!(is_stale(100, 10, 111));
+        assert!(!is_stale(0, 0, u32::MAX));
+    }
+
+    #[test]
+    fn expire_test() {
+        let env = new_env();
+        let db = DBWithTTL::open(&options_with_env(env.clone()), DBNAME, 10).unwrap();
+        let wo = WriteOptions::default();
+        assert!(db.put(&wo, &Slice::new(b"old"), &Slice::new(b"v1")).ok());
+        assert_eq!("v1", get(&db, "old").unwrap());
+        assert_eq!(6, db.db().get(&ReadOptions::new(), &Slice::new(b"old")).unwrap().len());
+
+        env.advance_secs(5);
+        assert!(db.put(&wo, &Slice::new(b"new"), &Slice::new(b"v2")).ok());
+        env.advance_secs(5);
+        assert_eq!("v1", get(&db, "old").unwrap());
+
+        // "old" has expired, but is still stored until compacted.
+        env.advance_secs(1);
+        assert!(get(&db, "old").unwrap_err().is_not_found());
+        assert_eq!("v2", get(&db, "new").unwrap());
+        assert!(db.db().get(&ReadOptions::new(), &Slice::new(b"old")).is_ok());
+        // (Only compactions filter, not memtable flushes.)
+        assert!(db.db().flush().ok());
+        assert!(db.compact_range(None, None).ok());
+        assert!(db.db().get(&ReadOptions::new(), &Slice::new(b"old")).unwrap_err().is_not_found());
+        assert_eq!("v2", get(&db, "new").unwrap());
+
+        // Overwriting an entry restarts its time to live.
+        assert!(db.put(&wo, &Slice::new(b"new"), &Slice::new(b"v3")).ok());
+        env.advance_secs(10);
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!("v3", get(&db, "new").unwrap());
+        assert!(db.delete(&wo, &Slice::new(b"new")).ok());
+        assert!(get(&db, "new").unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn no_expiry_test() {
+        let env = new_env();
+        let db = DBWithTTL::open(&options_with_env(env.clone()), DBNAME, 0).unwrap();
+        assert!(db.put(&WriteOptions::default(), &Slice::new(b"k"), &Slice::new(b"")).ok());
+        env.advance_secs(1 << 30);
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!("", get(&db, "k").unwrap());
+    }
+
+    #[test]
+    fn non_ttl_values_test() {
+        let env = new_env();
+        let options = options_with_env(env.clone());
+        {
+            let db = DB::open(&options, DBNAME).unwrap();
+            assert!(db.put(&WriteOptions::default(), &Slice::new(b"short"), &Slice::new(b"ab")).ok());
+        }
+        let db = DBWithTTL::open(&options, DBNAME, 10).unwrap();
+        assert!(get(&db, "short").unwrap_err().is_corruption());
+
+        // Compaction keeps what it can not tell the age of.
+        env.advance_secs(100);
+        assert!(db.compact_range(None, None).ok());
+        assert_eq!(b"ab".to_vec(), db.db().get(&ReadOptions::new(), &Slice::new(b"short")).unwrap());
+    }
+
+    /// Removes the values equal to "drop".
+    struct DropFilter;
+
+    impl CompactionFilter for DropFilter {
+        fn name(&self) -> &str { "DropFilter" }
+        fn filter(&self, _level: i32, _key: &Slice, value: &Slice) -> bool { value.data() == b"drop" }
+    }
+
+    #[test]
+    fn user_filter_test() {
+        let env = new_env();
+        let options = Options { compaction_filter: Some(Arc::new(DropFilter)), ..options_with_env(env.clone()) };
+        let db = DBWithTTL::open(&options, DBNAME, 10).unwrap();
+        let wo = WriteOptions::default();
+        assert!(db.put(&wo, &Slice::new(b"a"), &Slice::new(b"drop")).ok());
+        assert!(db.put(&wo, &Slice::new(b"b"), &Slice::new(b"keep")).ok());
+        assert!(db.db().flush().ok());
+        assert!(db.compact_range(None, None).ok());
+        assert!(get(&db, "a").unwrap_err().is_not_found());
+        assert_eq!("keep", get(&db, "b").unwrap());
+    }
+}
@@ -0,0 +1,756 @@
+//! WriteBatch holds a collection of updates to apply atomically to a DB.
+//!
+//! The updates are applied in the order in which they are added
+//! to the WriteBatch.  For example, the value of "key" will be "v3"
+//! after the following batch is written:
+//!
+//!    batch.put("key", "v1");
+//!    batch.delete("key");
+//!    batch.put("key", "v2");
+//!    batch.put("key", "v3");
+//!
+//! Multiple threads can invoke const methods on a WriteBatch without
+//! external synchronization, but if any of the threads may call a
+//! non-const method, all threads accessing the same WriteBatch must use
+//! external synchronization.
+//!
+//! WriteBatch::rep_ :=
+//!    sequence: fixed64
+//!    count: fixed32
+//!    data: record[count]
+//! record :=
+//!    kTypeValue varstring varstring         |
+//!    kTypeDeletion varstring                |
+//!    kTypeRangeDeletion varstring varstring |
+//!    kTypeIdempotencyToken uint8[16]        |
+//!    kTypePrepare uint8[16] fixed32 fixed32 |
+//!    kTypeCommit uint8[16]                  |
+//!    kTypeRollback uint8[16]                |
+//!    kTypeSkippable varstring
+//! The token record, if any, is the first one and is not included in
+//! count; it records WriteOptions::idempotency_token in the log.
+//! Likewise for the transaction record of a batch logged by a
+//! MultiDbWriteBatch: the transaction id, and for a prepared batch the
+//! participant's index and the number of participants.  Older readers
+//! fail on these, as a prepared batch must not be applied as it is.
+//! Records with a tag of kTypeSkippable (0x80) or above are not included
+//! in count either, and readers skip the ones they do not know, so that
+//! new kinds of records can be logged without breaking older readers.
+//! The only one so far is the WAL trailer (see new_wal_trailer()).
+//! varstring :=
+//!    len: varint32
+//!    data: uint8[len]
+
+use crate::{db::{dbformat::ValueType, memtable::MemTable, version_edit::SequenceNumber}, slice::Slice, status::Status, util::{coding::{decode_fixed32, decode_fixed64_bytes, encode_fixed32, encode_fixed64, get_length_prefixed_slice, put_fixed32, put_fixed64, put_length_prefixed_slice}, crc32c}};
+
+/// WriteBatch header has an 8-byte sequence number followed by a 4-byte count.
+pub(crate) const HEADER: usize = 12;
+
+// Tag of the record holding the batch's idempotency token.
+const TYPE_IDEMPOTENCY_TOKEN: u8 = 0x7f;
+const IDEMPOTENCY_TOKEN_SIZE: usize = 16;
+
+// Tags of the transaction records; see TransactionRecord.
+const TYPE_PREPARE: u8 = 0x7e;
+const TYPE_COMMIT: u8 = 0x7d;
+const TYPE_ROLLBACK: u8 = 0x7c;
+const PREPARE_SIZE: usize = TRANSACTION_ID_SIZE + 8;
+
+/// Size of the id of a transaction across several DBs.
+pub(crate) const TRANSACTION_ID_SIZE: usize = 16;
+
+/// Identifies a transaction across several DBs; see MultiDbWriteBatch.
+pub type TransactionId = [u8; TRANSACTION_ID_SIZE];
+
+// Tags of records that readers skip unless they know them.
+const TYPE_SKIPPABLE: u8 = 0x80;
+const TYPE_WAL_TRAILER: u8 = 0x80;
+
+// WAL trailer payload: last sequence (fixed64), record count (fixed64)
+// and the masked crc32c of the two (fixed32).
+const WAL_TRAILER_SIZE: usize = 20;
+
+/// What a log held when the DB writing it was closed cleanly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct WalTrailer {
+    /// Sequence number of the last write in the log, or of the last
+    /// write before it if it has none.
+    pub(crate) last_sequence: SequenceNumber,
+
+    /// Number of records in the log before the trailer.
+    pub(crate) records: u64,
+}
+
+/// What a batch logged by a MultiDbWriteBatch does with its transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TransactionRecord {
+    /// The batch is the share of participant "participant" (of
+    /// "participants") in the transaction.  It is logged, but applied
+    /// only once the transaction commits.
+    Prepare { txn: TransactionId, participant: u32, participants: u32 },
+
+    /// The transaction commits, and the batch holds the participant's
+    /// share of it.
+    Commit(TransactionId),
+
+    /// The transaction is abandoned.  The batch holds nothing.
+    Rollback(TransactionId),
+}
+
+/// Support for iterating over the contents of a batch.
+pub trait Handler {
+    fn put(&mut self, key: &Slice, value: &Slice);
+    fn delete(&mut self, key: &Slice);
+    fn delete_range(&mut self, begin: &Slice, end: &Slice);
+}
+
+#[derive(Clone)]
+pub struct WriteBatch {
+    rep_: Vec<u8>,
+    range_deletions_: bool,     // Whether rep_ holds a kTypeRangeDeletion record
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        let mut batch = Self { rep_: Vec::new(), range_deletions_: false };
+        batch.clear();
+        batch
+    }
+
+    /// Store the mapping "key->value" in the database.
+    pub fn put(&mut self, key: &Slice, value: &Slice) {
+        self.set_count(self.count() + 1);
+        self.rep_.push(ValueType::type_value().value());
+        put_length_prefixed_slice(&mut self.rep_, key);
+        put_length_prefixed_slice(&mut self.rep_, value);
+    }
+
+    /// If the database contains a mapping for "key", erase it.  Else do nothing.
+    pub fn delete(&mut self, key: &Slice) {
+        self.set_count(self.count() + 1);
+        self.rep_.push(ValueType::type_deletion().value());
+        put_length_prefixed_slice(&mut self.rep_, key);
+    }
+
+    /// Erase the database entries (if any) for the keys in [begin, end),
+    /// as ordered by the database's comparator.  Keys written after this
+    /// in the batch, or in later writes, are not affected.  Does nothing
+    /// if "begin" does not come before "end".
+    pub fn delete_range(&mut self, begin: &Slice, end: &Slice) {
+        self.set_count(self.count() + 1);
+        self.rep_.push(ValueType::type_range_deletion().value());
+        put_length_prefixed_slice(&mut self.rep_, begin);
+        put_length_prefixed_slice(&mut self.rep_, end);
+        self.range_deletions_ = true;
+    }
+
+    /// Clear all updates buffered in this batch.
+    pub fn clear(&mut self) {
+        self.rep_.clear();
+        self.rep_.resize(HEADER, 0);
+        self.range_deletions_ = false;
+    }
+
+    /// The size of the database changes caused by this batch.
+    ///
+    /// This number is tied to implementation details, and may change across
+    /// releases. It is intended for LevelDB usage metrics.
+    pub fn approximate_size(&self) -> usize {
+        self.rep_.len()
+    }
+
+    /// Copies the operations in "source" to this batch.
+    ///
+    /// This runs in O(source size) time. However, the constant factor is better
+    /// than calling iterate() over the source batch with a Handler that replicates
+    /// the operations into this batch.
+    pub fn append(&mut self, source: &WriteBatch) {
+        self.set_count(self.count() + source.count());
+        debug_assert!(source.rep_.len() >= HEADER);
+        self.rep_.extend_from_slice(&source.rep_[HEADER..]);
+        self.range_deletions_ |= source.range_deletions_;
+    }
+
+    /// Call the handler for every record in the batch, in order.  Returns
+    /// a Corruption status if the batch is malformed.
+    pub fn iterate(&self, handler: &mut dyn Handler) -> Status {
+        let mut input = Slice::new(&self.rep_);
+        if input.size() < HEADER {
+            return Status::corruption("malformed WriteBatch (too small)", "");
+        }
+
+        input.advance(HEADER);
+        let mut found = 0;
+        while !input.is_empty() {
+            let tag = input.advance(1).data()[0];
+            if tag == TYPE_IDEMPOTENCY_TOKEN {
+                if input.size() < IDEMPOTENCY_TOKEN_SIZE {
+                    return Status::corruption("bad WriteBatch idempotency token", "");
+                }
+                input.advance(IDEMPOTENCY_TOKEN_SIZE);
+                continue;
+            }
+            if let Some(size) = transaction_record_size(tag) {
+                if input.size() < size {
+                    return Status::corruption("bad WriteBatch transaction record", "");
+                }
+                input.advance(size);
+                continue;
+            }
+            if tag >= TYPE_SKIPPABLE {
+                if get_length_prefixed_slice(&mut input).is_none() {
+                    return Status::corruption("bad WriteBatch skippable record", "");
+                }
+                continue;
+            }
+            found += 1;
+            if tag == ValueType::type_value().value() {
+                match (get_length_prefixed_slice(&mut input), get_length_prefixed_slice(&mut input)) {
+                    (Some(key), Some(value)) => { handler.put(&key, &value); },
+                    _ => { return Status::corruption("bad WriteBatch Put", ""); },
+                }
+            } else if tag == ValueType::type_deletion().value() {
+                match get_length_prefixed_slice(&mut input) {
+                    Some(key) => { handler.delete(&key); },
+                    None => { return Status::corruption("bad WriteBatch Delete", ""); },
+                }
+            } else if tag == ValueType::type_range_deletion().value() {
+                match (get_length_prefixed_slice(&mut input), get_length_prefixed_slice(&mut input)) {
+                    (Some(begin), Some(end)) => { handler.delete_range(&begin, &end); },
+                    _ => { return Status::corruption("bad WriteBatch DeleteRange", ""); },
+                }
+            } else {
+                return Status::corruption("unknown WriteBatch tag", "");
+            }
+        }
+        if found != self.count() {
+            Status::corruption("WriteBatch has wrong count", "")
+        } else {
+            Status::new_ok()
+        }
+    }
+
+    /// Return the number of entries in the batch.
+    pub(crate) fn count(&self) -> u32 {
+        u32::from_le_bytes(self.rep_[8..HEADER].try_into().unwrap())
+    }
+
+    /// Set the count for the number of entries in the batch.
+    pub(crate) fn set_count(&mut self, n: u32) {
+        self.rep_[8..HEADER].copy_from_slice(&encode_fixed32(n));
+    }
+
+    /// Return the sequence number for the start of this batch.
+    pub(crate) fn sequence(&self) -> SequenceNumber {
+        decode_fixed64_bytes(&self.rep_[..8])
+    }
+
+    /// Store the specified number as the sequence number for the start of
+    /// this batch.
+    pub(crate) fn set_sequence(&mut self, seq: SequenceNumber) {
+        self.rep_[..8].copy_from_slice(&encode_fixed64(seq));
+    }
+
+    pub(crate) fn contents(&self) -> Slice<'_> {
+        Slice::new(&self.rep_)
+    }
+
+    pub(crate) fn byte_size(&self) -> usize {
+        self.rep_.len()
+    }
+
+    pub(crate) fn set_contents(&mut self, contents: &Slice) {
+        debug_assert!(contents.size() >= HEADER);
+        self.rep_.clear();
+        self.rep_.extend_from_slice(contents.data());
+        let mut finder = RangeDeletionFinder { found: false };
+        // A malformed batch is reported when it is applied.
+        let _ = self.iterate(&mut finder);
+        self.range_deletions_ = finder.found;
+    }
+
+    /// Returns true iff the batch holds a range deletion.
+    pub(crate) fn has_range_deletions(&self) -> bool {
+        self.range_deletions_
+    }
+
+    /// Record "token" at the start of the batch, replacing any token
+    /// already there.
+    pub(crate) fn set_idempotency_token(&mut self, token: &[u8; IDEMPOTENCY_TOKEN_SIZE]) {
+        let mut record = vec![TYPE_IDEMPOTENCY_TOKEN];
+        record.extend_from_slice(token);
+        let end = if self.idempotency_token().is_some() { HEADER + record.len() } else { HEADER };
+        self.rep_.splice(HEADER..end, record);
+    }
+
+    /// Return the token recorded by set_idempotency_token(), if any.
+    pub(crate) fn idempotency_token(&self) -> Option<[u8; IDEMPOTENCY_TOKEN_SIZE]> {
+        match self.rep_.get(HEADER..HEADER + 1 + IDEMPOTENCY_TOKEN_SIZE) {
+            Some(record) if record[0] == TYPE_IDEMPOTENCY_TOKEN => Some(record[1..].try_into().unwrap()),
+            _ => None,
+        }
+    }
+
+    /// Record "record" at the start of the batch, in place of the
+    /// transaction record it holds, if any.
+    /// REQUIRES: the batch has no idempotency token
+    pub(crate) fn set_transaction_record(&mut self, record: &TransactionRecord) {
+        debug_assert!(self.idempotency_token().is_none());
+        if let Some(size) = self.rep_.get(HEADER).and_then(|&tag| transaction_record_size(tag)) {
+            self.rep_.drain(HEADER..HEADER + 1 + size);
+        }
+        let mut encoded = Vec::with_capacity(1 + PREPARE_SIZE);
+        match record {
+            TransactionRecord::Prepare { txn, participant, participants } => {
+                encoded.push(TYPE_PREPARE);
+                encoded.extend_from_slice(txn);
+                put_fixed32(&mut encoded, *participant);
+                put_fixed32(&mut encoded, *participants);
+            },
+            TransactionRecord::Commit(txn) => {
+                encoded.push(TYPE_COMMIT);
+                encoded.extend_from_slice(txn);
+            },
+            TransactionRecord::Rollback(txn) => {
+                encoded.push(TYPE_ROLLBACK);
+                encoded.extend_from_slice(txn);
+            },
+        }
+        self.rep_.splice(HEADER..HEADER, encoded);
+    }
+
+    /// Return the record set by set_transaction_record(), if any.
+    pub(crate) fn transaction_record(&self) -> Option<TransactionRecord> {
+        let tag = *self.rep_.get(HEADER)?;
+        let record = self.rep_.get(HEADER + 1..HEADER + 1 + transaction_record_size(tag)?)?;
+        let txn = record[..TRANSACTION_ID_SIZE].try_into().unwrap();
+        Some(match tag {
+            TYPE_PREPARE => TransactionRecord::Prepare {
+                txn,
+                participant: decode_fixed32(record[TRANSACTION_ID_SIZE..TRANSACTION_ID_SIZE + 4].try_into().unwrap()),
+                participants: decode_fixed32(record[TRANSACTION_ID_SIZE + 4..].try_into().unwrap()),
+            },
+            TYPE_COMMIT => TransactionRecord::Commit(txn),
+            _ => TransactionRecord::Rollback(txn),
+        })
+    }
+
+    /// A batch holding nothing but "trailer", logged on a clean shutdown
+    /// after the last write.  Replaying it changes nothing.
+    pub(crate) fn new_wal_trailer(trailer: &WalTrailer) -> WriteBatch {
+        let mut payload = Vec::with_capacity(WAL_TRAILER_SIZE);
+        put_fixed64(&mut payload, trailer.last_sequence);
+        put_fixed64(&mut payload, trailer.records);
+        let crc = crc32c::mask(crc32c::value(&payload));
+        put_fixed32(&mut payload, crc);
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(trailer.last_sequence + 1);
+        batch.rep_.push(TYPE_WAL_TRAILER);
+        put_length_prefixed_slice(&mut batch.rep_, &Slice::new(&payload));
+        batch
+    }
+
+    /// Return the trailer if the batch is one made by new_wal_trailer(),
+    /// or a Corruption status if it is a damaged one.
+    pub(crate) fn wal_trailer(&self) -> Result<Option<WalTrailer>, Status> {
+        if self.count() != 0 || self.rep_.get(HEADER) != Some(&TYPE_WAL_TRAILER) {
+            return Ok(None);
+        }
+        let mut input = Slice::new(&self.rep_[HEADER + 1..]);
+        let payload = match get_length_prefixed_slice(&mut input) {
+            Some(payload) if payload.size() == WAL_TRAILER_SIZE && input.is_empty() => payload,
+            _ => { return Err(Status::corruption("malformed WAL trailer", "")); },
+        };
+        let payload = payload.data();
+        let crc = u32::from_le_bytes(payload[16..].try_into().unwrap());
+        if crc32c::unmask(crc) != crc32c::value(&payload[..16]) {
+            return Err(Status::corruption("WAL trailer checksum mismatch", ""));
+        }
+        Ok(Some(WalTrailer { last_sequence: decode_fixed64_bytes(&payload[..8]), records: decode_fixed64_bytes(&payload[8..16]) }))
+    }
+
+    /// Apply the batch to the memtable, numbering the entries from the
+    /// batch's sequence number.
+    pub(crate) fn insert_into(&self, memtable: &MemTable) -> Status {
+        let mut inserter = MemTableInserter::new(self.sequence(), memtable);
+        self.iterate(&mut inserter)
+    }
+}
+
+/// Return the size of the transaction record with tag "tag", or None if
+/// "tag" is not that of a transaction record.
+fn transaction_record_size(tag: u8) -> Option<usize> {
+    match tag {
+        TYPE_PREPARE => Some(PREPARE_SIZE),
+        TYPE_COMMIT | TYPE_ROLLBACK => Some(TRANSACTION_ID_SIZE),
+        _ => None,
+    }
+}
+
+/// Applies every record of a batch to a memtable.  Each record consumes
+/// one sequence number, starting at the batch's base sequence.
+pub(crate) struct MemTableInserter<'a> {
+    sequence_: SequenceNumber,
+    mem_: &'a MemTable,
+}
+
+impl<'a> MemTableInserter<'a> {
+    pub(crate) fn new(sequence: SequenceNumber, mem: &'a MemTable) -> Self {
+        Self { sequence_: sequence, mem_: mem }
+    }
+}
+
+impl Handler for MemTableInserter<'_> {
+    fn put(&mut self, key: &Slice, value: &Slice) {
+        self.mem_.add(self.sequence_, ValueType::type_value(), key, value);
+        self.sequence_ += 1;
+    }
+
+    fn delete(&mut self, key: &Slice) {
+        self.mem_.add(self.sequence_, ValueType::type_deletion(), key, &Slice::new(b""));
+        self.sequence_ += 1;
+    }
+
+    fn delete_range(&mut self, begin: &Slice, end: &Slice) {
+        self.mem_.add_range_tombstone(self.sequence_, begin, end);
+        self.sequence_ += 1;
+    }
+}
+
+struct RangeDeletionFinder {
+    found: bool,
+}
+
+impl Handler for RangeDeletionFinder {
+    fn put(&mut self, _key: &Slice, _value: &Slice) {}
+
+    fn delete(&mut self, _key: &Slice) {}
+
+    fn delete_range(&mut self, _begin: &Slice, _end: &Slice) {
+        self.found = true;
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{comparator::bytewise_comparator, db::dbformat::{InternalKeyComparator, LookupKey}};
+
+    use super::*;
+
+    // Hand-encoded batch: sequence 100, then Put("foo", "bar"),
+    // Delete("box"), Put("baz", "boo").
+    const ENCODED: &[u8] = &[
+        100, 0, 0, 0, 0, 0, 0, 0,   // sequence
+        3, 0, 0, 0,                 // count
+        1, 3, b'f', b'o', b'o', 3, b'b', b'a', b'r',
+        0, 3, b'b', b'o', b'x',
+        1, 3, b'b', b'a', b'z', 3, b'b', b'o', b'o',
+    ];
+
+    #[derive(Default)]
+    struct Recorder {
+        ops_: Vec<String>,
+    }
+
+    impl Handler for Recorder {
+        fn put(&mut self, key: &Slice, value: &Slice) {
+            self.ops_.push(format!("Put({}, {})", key.to_utf8_string().unwrap(), value.to_utf8_string().unwrap()));
+        }
+
+        fn delete(&mut self, key: &Slice) {
+            self.ops_.push(format!("Delete({})", key.to_utf8_string().unwrap()));
+        }
+
+        fn delete_range(&mut self, begin: &Slice, end: &Slice) {
+            self.ops_.push(format!("DeleteRange({}, {})", begin.to_utf8_string().unwrap(), end.to_utf8_string().unwrap()));
+        }
+    }
+
+    fn print_contents(batch: &WriteBatch) -> Result<Vec<String>, Status> {
+        let mut recorder = Recorder::default();
+        let s = batch.iterate(&mut recorder);
+        if s.ok() { Ok(recorder.ops_) } else { Err(s) }
+    }
+
+    #[test]
+    fn empty_test() {
+        let batch = WriteBatch::new();
+        assert_eq!(0, batch.count());
+        assert_eq!(0, batch.sequence());
+        assert_eq!(HEADER, batch.approximate_size());
+        assert_eq!(vec![0u8; HEADER], batch.contents().data().to_vec());
+    }
+
+    #[test]
+    fn multiple_test() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::new(b"foo"), &Slice::new(b"bar"));
+        batch.delete(&Slice::new(b"box"));
+        batch.put(&Slice::new(b"baz"), &Slice::new(b"boo"));
+        batch.set_sequence(100);
+        assert_eq!(100, batch.sequence());
+        assert_eq!(3, batch.count());
+        assert_eq!(ENCODED.to_vec(), batch.contents().data().to_vec());
+    }
+
+    #[test]
+    fn set_contents_test() {
+        let mut batch = WriteBatch::new();
+        batch.set_contents(&Slice::new(ENCODED));
+        assert_eq!(100, batch.sequence());
+        assert_eq!(3, batch.count());
+        assert_eq!(ENCODED.len(), batch.byte_size());
+
+        // Appending to a decoded batch keeps the header consistent.
+        batch.delete(&Slice::new(b"foo"));
+        assert_eq!(4, batch.count());
+        assert_eq!(100, batch.sequence());
+        assert_eq!(&[0, 3, b'f', b'o', b'o'], &batch.contents().data()[ENCODED.len()..]);
+
+        batch.clear();
+        assert_eq!(0, batch.count());
+        assert_eq!(HEADER, batch.approximate_size());
+    }
+
+    #[test]
+    fn append_test() {
+        let mut b1 = WriteBatch::new();
+        let mut b2 = WriteBatch::new();
+        b1.set_sequence(200);
+        b2.set_sequence(300);
+        b1.append(&b2);
+        assert_eq!(0, b1.count());
+        assert_eq!(HEADER, b1.byte_size());
+
+        b2.put(&Slice::new(b"a"), &Slice::new(b"va"));
+        b1.append(&b2);
+        assert_eq!(1, b1.count());
+        assert_eq!(200, b1.sequence());
+        assert_eq!(&b2.contents().data()[HEADER..], &b1.contents().data()[HEADER..]);
+
+        b2.clear();
+        b2.put(&Slice::new(b"b"), &Slice::new(b"vb"));
+        b1.append(&b2);
+        b2.delete(&Slice::new(b"foo"));
+        b1.append(&b2);
+        assert_eq!(4, b1.count());
+        assert_eq!(&[1, 1, b'a', 2, b'v', b'a', 1, 1, b'b', 2, b'v', b'b',
+                     1, 1, b'b', 2, b'v', b'b', 0, 3, b'f', b'o', b'o'],
+                   &b1.contents().data()[HEADER..]);
+    }
+
+    #[test]
+    fn approximate_size_test() {
+        let mut batch = WriteBatch::new();
+        let empty_size = batch.approximate_size();
+
+        batch.put(&Slice::new(b"foo"), &Slice::new(b"bar"));
+        let one_key_size = batch.approximate_size();
+        assert!(empty_size < one_key_size);
+
+        batch.put(&Slice::new(b"baz"), &Slice::new(b"boo"));
+        let two_keys_size = batch.approximate_size();
+        assert!(one_key_size < two_keys_size);
+
+        batch.delete(&Slice::new(b"box"));
+        let post_delete_size = batch.approximate_size();
+        assert!(two_keys_size < post_delete_size);
+    }
+
+    #[test]
+    fn idempotency_token_test() {
+        let mut batch = WriteBatch::new();
+        batch.set_contents(&Slice::new(ENCODED));
+        assert_eq!(None, batch.idempotency_token());
+        batch.set_idempotency_token(&[7; 16]);
+        batch.set_idempotency_token(&[9; 16]);
+        assert_eq!(Some([9; 16]), batch.idempotency_token());
+        assert_eq!(ENCODED.len() + 17, batch.byte_size());
+
+        // The token is not an update: it is neither counted nor iterated.
+        assert_eq!(3, batch.count());
+        assert_eq!(100, batch.sequence());
+        assert_eq!(vec!["Put(foo, bar)", "Delete(box)", "Put(baz, boo)"], print_contents(&batch).unwrap());
+
+        let mut truncated = WriteBatch::new();
+        truncated.set_contents(&Slice::new(&batch.contents().data()[..HEADER + 10]));
+        assert!(print_contents(&truncated).unwrap_err().is_corruption());
+    }
+
+    #[test]
+    fn transaction_record_test() {
+        let records = [
+            TransactionRecord::Prepare { txn: [3; 16], participant: 1, participants: 2 },
+            TransactionRecord::Commit([4; 16]),
+            TransactionRecord::Rollback([5; 16]),
+        ];
+        for record in records {
+            let mut batch = WriteBatch::new();
+            batch.set_contents(&Slice::new(ENCODED));
+            assert_eq!(None, batch.transaction_record());
+            batch.set_transaction_record(&record);
+            assert_eq!(Some(record), batch.transaction_record());
+            batch.set_transaction_record(&TransactionRecord::Commit([6; 16]));
+            assert_eq!(Some(TransactionRecord::Commit([6; 16])), batch.transaction_record());
+            batch.set_transaction_record(&record);
+
+            // Like a token, the record is neither counted nor iterated.
+            assert_eq!(3, batch.count());
+            assert_eq!(None, batch.idempotency_token());
+            assert_eq!(vec!["Put(foo, bar)", "Delete(box)", "Put(baz, boo)"], print_contents(&batch).unwrap());
+
+            let mut truncated = WriteBatch::new();
+            truncated.set_contents(&Slice::new(&batch.contents().data()[..HEADER + 10]));
+            assert_eq!(None, truncated.transaction_record());
+            assert!(print_contents(&truncated).unwrap_err().is_corruption());
+        }
+    }
+
+    #[test]
+    fn wal_trailer_test() {
+        let trailer = WalTrailer { last_sequence: 41, records: 7 };
+        let batch = WriteBatch::new_wal_trailer(&trailer);
+        assert_eq!(0, batch.count());
+        assert_eq!(42, batch.sequence());
+        assert_eq!(Some(trailer), batch.wal_trailer().unwrap());
+        assert!(print_contents(&batch).unwrap().is_empty());
+
+        // Ordinary batches are not trailers
+        let mut decoded = WriteBatch::new();
+        decoded.set_contents(&Slice::new(ENCODED));
+        assert_eq!(None, decoded.wal_trailer().unwrap());
+        assert_eq!(None, WriteBatch::new().wal_trailer().unwrap());
+
+        // Skippable records, known or not, are skipped between updates
+        let mut rep = ENCODED.to_vec();
+        rep.extend_from_slice(&batch.contents().data()[HEADER..]);
+        rep.extend_from_slice(&[0xfe, 2, b'x', b'y']);
+        decoded.set_contents(&Slice::new(&rep));
+        assert_eq!(vec!["Put(foo, bar)", "Delete(box)", "Put(baz, boo)"], print_contents(&decoded).unwrap());
+        rep.truncate(rep.len() - 1);
+        decoded.set_contents(&Slice::new(&rep));
+        assert!(print_contents(&decoded).unwrap_err().to_string().contains("bad WriteBatch skippable record"));
+
+        let mut damaged = batch.contents().data().to_vec();
+        damaged[HEADER + 2] ^= 1;
+        decoded.set_contents(&Slice::new(&damaged));
+        assert!(decoded.wal_trailer().unwrap_err().to_string().contains("WAL trailer checksum mismatch"));
+        decoded.set_contents(&Slice::new(&batch.contents().data()[..HEADER + 10]));
+        assert!(decoded.wal_trailer().unwrap_err().is_corruption());
+    }
+
+    #[test]
+    fn iterate_test() {
+        let mut batch = WriteBatch::new();
+        batch.set_contents(&Slice::new(ENCODED));
+        assert_eq!(vec!["Put(foo, bar)", "Delete(box)", "Put(baz, boo)"], print_contents(&batch).unwrap());
+        assert!(print_contents(&WriteBatch::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn insert_into_test() {
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let mem = MemTable::new(&icmp);
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::new(b"foo"), &Slice::new(b"v1"));
+        batch.put(&Slice::new(b"bar"), &Slice::new(b"v2"));
+        batch.delete(&Slice::new(b"bar"));
+        batch.put(&Slice::new(b"foo"), &Slice::new(b"v3"));
+        batch.set_sequence(10);
+        assert!(batch.insert_into(&mem).ok());
+
+        // Entries are numbered 10, 11, 12, 13.
+        let get = |key: &[u8], seq| mem.get(&LookupKey::new(&Slice::new(key), seq));
+        assert_eq!(Some(b"v1".to_vec()), get(b"foo", 10).0);
+        assert_eq!(Some(b"v1".to_vec()), get(b"foo", 12).0);
+        assert_eq!(Some(b"v3".to_vec()), get(b"foo", 13).0);
+        assert!(!get(b"foo", 9).2);
+        assert_eq!(Some(b"v2".to_vec()), get(b"bar", 11).0);
+        assert!(get(b"bar", 12).1.unwrap().is_not_found());
+    }
+
+    #[test]
+    fn delete_range_test() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::new(b"b"), &Slice::new(b"v1"));
+        assert!(!batch.has_range_deletions());
+        batch.delete_range(&Slice::new(b"a"), &Slice::new(b"c"));
+        batch.put(&Slice::new(b"b2"), &Slice::new(b"v2"));
+        assert!(batch.has_range_deletions());
+        assert_eq!(3, batch.count());
+        assert_eq!(vec!["Put(b, v1)", "DeleteRange(a, c)", "Put(b2, v2)"], print_contents(&batch).unwrap());
+
+        // Decoded and appended batches know they hold a range deletion
+        let mut decoded = WriteBatch::new();
+        decoded.set_contents(&batch.contents());
+        assert!(decoded.has_range_deletions());
+        let mut appended = WriteBatch::new();
+        appended.append(&batch);
+        assert!(appended.has_range_deletions());
+        decoded.clear();
+        assert!(!decoded.has_range_deletions());
+        decoded.set_contents(&Slice::new(ENCODED));
+        assert!(!decoded.has_range_deletions());
+
+        // Entries are numbered 10, 11, 12: the deletion hides the older
+        // entry only.
+        let icmp = InternalKeyComparator::new(bytewise_comparator());
+        let mem = MemTable::new(&icmp);
+        batch.set_sequence(10);
+        assert!(batch.insert_into(&mem).ok());
+        let get = |key: &[u8], seq| mem.get(&LookupKey::new(&Slice::new(key), seq));
+        assert_eq!(Some(b"v1".to_vec()), get(b"b", 10).0);
+        assert!(get(b"b", 11).1.unwrap().is_not_found());
+        assert!(get(b"b2", 11).1.unwrap().is_not_found());
+        assert_eq!(Some(b"v2".to_vec()), get(b"b2", 12).0);
+        assert!(get(b"a", 11).2);
+        assert!(!get(b"a", 10).2);
+        assert!(!get(b"c", 12).2);
+
+        let mut truncated = WriteBatch::new();
+        truncated.set_contents(&Slice::new(&batch.contents().data()[..batch.byte_size() - 8]));
+        assert!(print_contents(&truncated).unwrap_err().to_string().contains("bad WriteBatch DeleteRange"));
+    }
+
+    #[test]
+    fn corruption_test() {
+        let mut batch = WriteBatch::new();
+        batch.set_contents(&Slice::new(ENCODED));
+        batch.put(&Slice::new(&[b'k'; 200]), &Slice::new(b"v"));
+        let full = batch.contents().data().to_vec();
+        let boundaries = [HEADER, 21, 26, 35];
+
+        // Every truncation either splits a record or drops whole records,
+        // and neither may panic.
+        for len in HEADER..full.len() {
+            batch.rep_ = full[..len].to_vec();
+            let s = print_contents(&batch).unwrap_err();
+            assert!(s.is_corruption());
+            if boundaries.contains(&len) {
+                assert!(s.to_string().contains("wrong count"), "{}: {}", len, s.to_string());
+            } else {
+                assert!(s.to_string().contains("bad WriteBatch"), "{}: {}", len, s.to_string());
+            }
+        }
+
+        batch.rep_ = full[..HEADER - 1].to_vec();
+        assert!(print_contents(&batch).unwrap_err().to_string().contains("too small"));
+
+        let mut bad_tag = ENCODED.to_vec();
+        bad_tag[21] = 7;
+        batch.rep_ = bad_tag;
+        assert!(print_contents(&batch).unwrap_err().to_string().contains("unknown WriteBatch tag"));
+
+        // A length prefix running past the end of the batch.
+        let mut bad_len = ENCODED.to_vec();
+        bad_len[22] = 100;
+        batch.rep_ = bad_len;
+        assert!(print_contents(&batch).unwrap_err().to_string().contains("bad WriteBatch Delete"));
+    }
+}
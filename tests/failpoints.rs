@@ -0,0 +1,31 @@
+//! Checks that sync points cost nothing unless the "failpoints" feature is
+//! on: the names of the points must not even make it into the binary.
+
+use rucksdb::{db::DB, helpers::memenv::new_mem_env, options::{Options, WriteOptions}, slice::Slice};
+
+/// Returns true iff the running test binary contains "needle".
+fn binary_contains(needle: &[u8]) -> bool {
+    let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+    binary.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn compiled_out_without_feature_test() {
+    // Use the code paths that hold points, so they are linked in.
+    let mut options = Options::new();
+    options.env = new_mem_env();
+    options.create_if_missing = true;
+    options.write_buffer_size = 1000;
+    let db = DB::open(&options, "/db").unwrap();
+    for i in 0..10 {
+        assert!(db.put(&WriteOptions::default(), &Slice::new(format!("key{}", i).as_bytes()), &Slice::new(&[b'x'; 500])).ok());
+    }
+    assert!(db.compact_range(None, None).ok());
+
+    // Built up at run time so this file does not put the names in the
+    // binary itself.
+    for point in [["versionset", "before-current-rename"], ["db:flush", "after-build"], ["compaction", "after-output-sync"]] {
+        let name = point.join(":");
+        assert_eq!(cfg!(feature = "failpoints"), binary_contains(name.as_bytes()), "{}", name);
+    }
+}